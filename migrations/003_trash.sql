@@ -0,0 +1,14 @@
+-- Create trash table for soft-deleted files and folders
+CREATE TABLE IF NOT EXISTS trash (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    original_path TEXT NOT NULL,
+    name TEXT NOT NULL,
+    is_directory BOOLEAN NOT NULL DEFAULT 0,
+    size INTEGER NOT NULL,
+    deleted_at TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_trash_user ON trash (user_id);
+CREATE INDEX IF NOT EXISTS idx_trash_deleted_at ON trash (deleted_at);
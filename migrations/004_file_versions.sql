@@ -0,0 +1,17 @@
+-- Create file_versions table holding previous contents of overwritten files
+CREATE TABLE IF NOT EXISTS file_versions (
+    id TEXT PRIMARY KEY,
+    file_id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    checksum TEXT NOT NULL, -- Content is stored under .versions/<checksum>
+    modified_at TEXT NOT NULL,
+    author_id TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    FOREIGN KEY (file_id) REFERENCES file_metadata (id),
+    FOREIGN KEY (author_id) REFERENCES users (id),
+    UNIQUE(file_id, version)
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_versions_file ON file_versions (file_id);
+CREATE INDEX IF NOT EXISTS idx_file_versions_checksum ON file_versions (checksum);
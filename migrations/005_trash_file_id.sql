@@ -0,0 +1,3 @@
+-- Remember which metadata row a trash entry came from, so purging it can
+-- remove the row and its descendants
+ALTER TABLE trash ADD COLUMN file_id TEXT REFERENCES file_metadata (id);
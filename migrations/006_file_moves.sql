@@ -0,0 +1,13 @@
+-- Record moves and renames so the sync feed can report the previous path
+CREATE TABLE IF NOT EXISTS file_moves (
+    id TEXT PRIMARY KEY,
+    file_id TEXT NOT NULL,
+    owner_id TEXT NOT NULL,
+    old_path TEXT NOT NULL,
+    new_path TEXT NOT NULL,
+    moved_at TEXT NOT NULL,
+    FOREIGN KEY (file_id) REFERENCES file_metadata (id),
+    FOREIGN KEY (owner_id) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_moves_owner ON file_moves (owner_id, moved_at);
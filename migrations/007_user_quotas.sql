@@ -0,0 +1,2 @@
+-- Per-user storage quota in bytes; NULL falls back to the server default
+ALTER TABLE users ADD COLUMN quota_bytes INTEGER;
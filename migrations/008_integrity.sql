@@ -0,0 +1,14 @@
+-- When the background scrub last confirmed a file's checksum
+ALTER TABLE file_metadata ADD COLUMN last_verified_at TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_file_metadata_verified ON file_metadata (last_verified_at);
+
+-- Files whose content no longer matches the stored checksum
+CREATE TABLE IF NOT EXISTS integrity_issues (
+    id TEXT PRIMARY KEY,
+    file_id TEXT NOT NULL UNIQUE,
+    path TEXT NOT NULL,
+    expected_checksum TEXT NOT NULL,
+    actual_checksum TEXT NOT NULL,
+    detected_at TEXT NOT NULL
+);
@@ -0,0 +1,19 @@
+-- General log of changes the sync feed can't derive from file_metadata alone
+-- (moves and deletions). Entries outlive the rows they refer to.
+CREATE TABLE IF NOT EXISTS change_log (
+    id TEXT PRIMARY KEY,
+    owner_id TEXT NOT NULL,
+    file_id TEXT NOT NULL,
+    change_type TEXT NOT NULL, -- 'Moved' or 'Deleted'
+    path TEXT NOT NULL,
+    old_path TEXT,
+    changed_at TEXT NOT NULL,
+    FOREIGN KEY (owner_id) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_change_log_owner ON change_log (owner_id, changed_at);
+
+INSERT INTO change_log (id, owner_id, file_id, change_type, path, old_path, changed_at)
+SELECT id, owner_id, file_id, 'Moved', new_path, old_path, moved_at FROM file_moves;
+
+DROP TABLE file_moves;
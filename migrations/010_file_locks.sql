@@ -0,0 +1,13 @@
+-- Advisory locks taken by sync clients while they edit a file
+CREATE TABLE IF NOT EXISTS file_locks (
+    id TEXT PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    owner_id TEXT NOT NULL,
+    device_id TEXT,
+    token TEXT NOT NULL UNIQUE,
+    acquired_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    FOREIGN KEY (owner_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_locks_expires ON file_locks (expires_at);
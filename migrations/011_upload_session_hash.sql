@@ -0,0 +1,3 @@
+-- Running SHA-256 of the chunks received in order, so completing a session
+-- doesn't have to read the assembled file back
+ALTER TABLE upload_sessions ADD COLUMN hash_state BLOB;
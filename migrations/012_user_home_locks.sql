@@ -0,0 +1,19 @@
+-- With user homes, the same path in two homes is two files, so locks are
+-- keyed by the home as well. Locks are short-lived, so the table is
+-- recreated rather than copied; the shared tree uses the nil UUID.
+DROP TABLE IF EXISTS file_locks;
+
+CREATE TABLE file_locks (
+    id TEXT PRIMARY KEY,
+    home_id TEXT NOT NULL,
+    path TEXT NOT NULL,
+    owner_id TEXT NOT NULL,
+    device_id TEXT,
+    token TEXT NOT NULL UNIQUE,
+    acquired_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    FOREIGN KEY (owner_id) REFERENCES users (id) ON DELETE CASCADE,
+    UNIQUE(home_id, path)
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_locks_expires ON file_locks (expires_at);
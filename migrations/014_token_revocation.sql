@@ -0,0 +1,13 @@
+-- Access tokens revoked before they expire, e.g. on logout. Rows can go
+-- once the token would have expired anyway.
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+    jti TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_revoked_tokens_expires ON revoked_tokens (expires_at);
+
+-- Tokens issued at or before this time are rejected
+ALTER TABLE users ADD COLUMN tokens_valid_after TEXT;
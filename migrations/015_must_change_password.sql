@@ -0,0 +1,2 @@
+-- Set when an admin resets a password, cleared once the user picks their own
+ALTER TABLE users ADD COLUMN must_change_password BOOLEAN NOT NULL DEFAULT 0;
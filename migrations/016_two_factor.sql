@@ -0,0 +1,14 @@
+-- TOTP secret, encrypted, and whether logins must supply a code. A secret
+-- without enforcement is a setup that hasn't been confirmed yet.
+ALTER TABLE users ADD COLUMN totp_secret TEXT;
+ALTER TABLE users ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT 0;
+
+-- Single-use codes for when the authenticator is lost; only hashes are kept
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    code_hash TEXT NOT NULL,
+    used_at TEXT,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    UNIQUE (user_id, code_hash)
+);
@@ -0,0 +1,13 @@
+-- Every login attempt, for lockouts and for admins auditing brute-force
+-- activity. Outcome is success, failure, locked or rate_limited; only
+-- failures count towards a lockout.
+CREATE TABLE IF NOT EXISTS login_attempts (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    ip_address TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    attempted_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_login_attempts_username ON login_attempts (username, attempted_at);
+CREATE INDEX IF NOT EXISTS idx_login_attempts_time ON login_attempts (attempted_at);
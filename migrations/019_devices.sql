@@ -0,0 +1,15 @@
+-- Devices users have logged in from. Revoking one rejects every token
+-- issued to it up to tokens_valid_after; logging in again starts afresh.
+CREATE TABLE IF NOT EXISTS devices (
+    user_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    name TEXT,
+    client_version TEXT,
+    ip_address TEXT,
+    created_at TEXT NOT NULL,
+    last_seen_at TEXT NOT NULL,
+    revoked_at TEXT,
+    tokens_valid_after TEXT,
+    PRIMARY KEY (user_id, device_id),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,11 @@
+-- Deleting and sharing used to need only "write". Now that each route checks
+-- its own permission, users who could write keep being able to do both.
+UPDATE users
+SET permissions = json_insert(permissions, '$[#]', 'delete')
+WHERE EXISTS (SELECT 1 FROM json_each(users.permissions) WHERE value = 'write')
+  AND NOT EXISTS (SELECT 1 FROM json_each(users.permissions) WHERE value = 'delete');
+
+UPDATE users
+SET permissions = json_insert(permissions, '$[#]', 'share')
+WHERE EXISTS (SELECT 1 FROM json_each(users.permissions) WHERE value = 'write')
+  AND NOT EXISTS (SELECT 1 FROM json_each(users.permissions) WHERE value = 'share');
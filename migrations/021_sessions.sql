@@ -0,0 +1,23 @@
+-- One row per login, followed through token refreshes until it expires
+-- or is ended. jti is that of the newest access token, so ending the
+-- session can revoke it.
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    device_id TEXT,
+    user_agent TEXT,
+    ip_address TEXT,
+    jti TEXT NOT NULL,
+    token_expires_at TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    last_active_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_user ON sessions (user_id);
+CREATE INDEX IF NOT EXISTS idx_sessions_jti ON sessions (jti);
+CREATE INDEX IF NOT EXISTS idx_sessions_expires ON sessions (expires_at);
+
+-- The session a refresh token keeps alive
+ALTER TABLE refresh_tokens ADD COLUMN session_id TEXT;
@@ -0,0 +1,3 @@
+-- Users created on their first login through MyCloud. Only these may be
+-- vouched for by MyCloud once their local password stops matching.
+ALTER TABLE users ADD COLUMN mycloud_sourced BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,12 @@
+-- Accounts at an OpenID Connect provider and the local users they log in
+-- as. provisioned marks users created on their first OIDC login, whose
+-- permissions follow their groups at the provider.
+CREATE TABLE IF NOT EXISTS oidc_identities (
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    provisioned BOOLEAN NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (issuer, subject),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
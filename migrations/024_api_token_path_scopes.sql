@@ -0,0 +1,3 @@
+-- Scoped tokens are limited to some folders, per action. Stored as a JSON
+-- list of {action, path_prefix}; empty for tokens that reach every file.
+ALTER TABLE api_tokens ADD COLUMN path_scopes TEXT NOT NULL DEFAULT '[]';
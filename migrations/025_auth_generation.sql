@@ -0,0 +1,3 @@
+-- Bumped when a user is deactivated or their password changes. Tokens carry
+-- the generation they were issued under and older ones are refused.
+ALTER TABLE users ADD COLUMN auth_generation INTEGER NOT NULL DEFAULT 0;
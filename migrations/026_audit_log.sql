@@ -0,0 +1,19 @@
+-- Security-relevant events for admins to review: logins, token refreshes,
+-- failed authentication, permission denials, share links and admin actions.
+-- user_id is set when the user is known; username holds the name a login
+-- was attempted with otherwise. Outcome is success, failure or denied.
+CREATE TABLE IF NOT EXISTS audit_log (
+    id TEXT PRIMARY KEY,
+    occurred_at TEXT NOT NULL,
+    user_id TEXT,
+    username TEXT,
+    event TEXT NOT NULL,
+    ip_address TEXT,
+    user_agent TEXT,
+    target TEXT,
+    outcome TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_log_time ON audit_log (occurred_at);
+CREATE INDEX IF NOT EXISTS idx_audit_log_username ON audit_log (username, occurred_at);
+CREATE INDEX IF NOT EXISTS idx_audit_log_event ON audit_log (event, occurred_at);
@@ -0,0 +1,15 @@
+-- App passwords for clients that only speak HTTP Basic auth, such as DAV
+-- clients. Stored bcrypt-hashed; path_prefix limits one to a folder.
+CREATE TABLE IF NOT EXISTS app_passwords (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    label TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+    path_prefix TEXT,
+    created_at TEXT NOT NULL,
+    expires_at TEXT,
+    last_used_at TEXT,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_app_passwords_user ON app_passwords (user_id);
@@ -0,0 +1,3 @@
+-- Requests made with an impersonation token are attributed to the admin in
+-- user_id and username; this holds the user they acted as.
+ALTER TABLE audit_log ADD COLUMN impersonating TEXT;
@@ -0,0 +1,7 @@
+-- Revoked share links keep their row, so the audit trail still points at
+-- something; the public endpoint answers 410 for them. password_hash is set
+-- for links that need a password to download.
+ALTER TABLE share_links ADD COLUMN revoked_at TEXT;
+ALTER TABLE share_links ADD COLUMN password_hash TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_share_links_created_by ON share_links (created_by);
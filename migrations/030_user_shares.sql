@@ -0,0 +1,16 @@
+-- Files and folders shared with other local users. A folder share covers
+-- everything under it. permissions is read or read_write. Shares of files
+-- that are gone are skipped when read rather than deleted with them.
+CREATE TABLE IF NOT EXISTS user_shares (
+    id TEXT PRIMARY KEY,
+    file_id TEXT NOT NULL,
+    grantor TEXT NOT NULL,
+    grantee TEXT NOT NULL,
+    permissions TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    UNIQUE (file_id, grantee),
+    FOREIGN KEY (grantor) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (grantee) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_shares_grantee ON user_shares (grantee);
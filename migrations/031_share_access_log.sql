@@ -0,0 +1,15 @@
+-- Every redemption of a share link, for its owner. ip_address is truncated
+-- to its network, or NULL when capturing addresses is turned off.
+CREATE TABLE IF NOT EXISTS share_access_log (
+    id TEXT PRIMARY KEY,
+    share_id TEXT NOT NULL,
+    accessed_at TEXT NOT NULL,
+    ip_address TEXT,
+    user_agent TEXT,
+    bytes_served INTEGER NOT NULL DEFAULT 0,
+    outcome TEXT NOT NULL,
+    FOREIGN KEY (share_id) REFERENCES share_links (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_share_access_log_share ON share_access_log (share_id, accessed_at);
+CREATE INDEX IF NOT EXISTS idx_share_access_log_accessed_at ON share_access_log (accessed_at);
@@ -0,0 +1,8 @@
+-- Short random codes and optional custom aliases for share links. Both
+-- resolve to the same share as its signed token. Links made before have
+-- neither.
+ALTER TABLE share_links ADD COLUMN short_code TEXT;
+ALTER TABLE share_links ADD COLUMN alias TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_share_links_short_code ON share_links (short_code);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_share_links_alias ON share_links (alias);
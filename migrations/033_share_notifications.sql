@@ -0,0 +1,3 @@
+-- Notes on share activity beyond a visit, such as who an email went to
+-- and why it failed
+ALTER TABLE share_access_log ADD COLUMN detail TEXT;
@@ -0,0 +1,7 @@
+-- How far back the change log still reaches once old entries are pruned.
+-- Clients that last synced before it have missed deletions and must start
+-- over. A single row, missing until the first prune.
+CREATE TABLE IF NOT EXISTS change_log_horizon (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    pruned_before TEXT NOT NULL
+);
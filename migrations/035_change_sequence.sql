@@ -0,0 +1,26 @@
+-- Number every change so clients can sync from a cursor instead of a time.
+-- AUTOINCREMENT keeps numbers from being reused once old entries are pruned.
+-- The log now also records creations and edits, not just moves and deletions.
+CREATE TABLE IF NOT EXISTS change_log_sequenced (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    owner_id TEXT NOT NULL,
+    file_id TEXT NOT NULL,
+    change_type TEXT NOT NULL, -- 'Created', 'Modified', 'Moved' or 'Deleted'
+    path TEXT NOT NULL,
+    old_path TEXT,
+    changed_at TEXT NOT NULL,
+    FOREIGN KEY (owner_id) REFERENCES users (id)
+);
+
+INSERT INTO change_log_sequenced (id, owner_id, file_id, change_type, path, old_path, changed_at)
+SELECT id, owner_id, file_id, change_type, path, old_path, changed_at FROM change_log ORDER BY changed_at;
+
+DROP TABLE change_log;
+ALTER TABLE change_log_sequenced RENAME TO change_log;
+
+CREATE INDEX IF NOT EXISTS idx_change_log_owner ON change_log (owner_id, changed_at);
+CREATE INDEX IF NOT EXISTS idx_change_log_owner_seq ON change_log (owner_id, seq);
+
+-- Highest sequence number pruned so far; cursors below it have missed changes
+ALTER TABLE change_log_horizon ADD COLUMN pruned_through INTEGER NOT NULL DEFAULT 0;
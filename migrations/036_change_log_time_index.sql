@@ -0,0 +1,2 @@
+-- Syncs from a time start at the first change after it
+CREATE INDEX IF NOT EXISTS idx_change_log_changed_at ON change_log (changed_at);
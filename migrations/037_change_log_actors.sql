@@ -0,0 +1,15 @@
+-- Who made each change: the user and device of the request, or neither for
+-- changes picked up from disk
+ALTER TABLE change_log ADD COLUMN actor_id TEXT;
+ALTER TABLE change_log ADD COLUMN device_id TEXT;
+
+-- Compaction looks at each file's history in order
+CREATE INDEX IF NOT EXISTS idx_change_log_file_seq ON change_log (file_id, seq);
+
+-- Files tracked before creations were logged have no history at all; give
+-- each one so syncs from a time before it was last modified still see it
+INSERT INTO change_log (id, owner_id, file_id, change_type, path, changed_at)
+SELECT randomblob(16), owner_id, id, 'Created', path, modified_at
+FROM file_metadata
+WHERE id NOT IN (SELECT file_id FROM change_log)
+ORDER BY modified_at;
@@ -0,0 +1,2 @@
+-- Sync snapshots walk one owner's tree in path order, a page at a time
+CREATE INDEX IF NOT EXISTS idx_file_metadata_owner_path ON file_metadata (owner_id, path);
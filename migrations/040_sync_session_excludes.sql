@@ -0,0 +1,2 @@
+-- Paths a device leaves out of the folders it syncs
+ALTER TABLE sync_sessions ADD COLUMN exclude_patterns TEXT NOT NULL DEFAULT '[]'; -- JSON array of gitignore-style patterns
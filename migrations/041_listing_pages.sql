@@ -0,0 +1,3 @@
+-- Keyset pagination of the trash and share link listings, newest first
+CREATE INDEX IF NOT EXISTS idx_trash_user_deleted ON trash (user_id, deleted_at, id);
+CREATE INDEX IF NOT EXISTS idx_share_links_created_by_created ON share_links (created_by, created_at, id);
@@ -0,0 +1,24 @@
+-- Full-text index over file names for search, kept in step by triggers
+CREATE VIRTUAL TABLE IF NOT EXISTS file_search USING fts5 (
+    name,
+    content = 'file_metadata',
+    content_rowid = 'rowid',
+    tokenize = 'unicode61 remove_diacritics 2',
+    prefix = '2 3'
+);
+
+CREATE TRIGGER IF NOT EXISTS file_search_insert AFTER INSERT ON file_metadata BEGIN
+    INSERT INTO file_search (rowid, name) VALUES (new.rowid, new.name);
+END;
+
+CREATE TRIGGER IF NOT EXISTS file_search_delete AFTER DELETE ON file_metadata BEGIN
+    INSERT INTO file_search (file_search, rowid, name) VALUES ('delete', old.rowid, old.name);
+END;
+
+CREATE TRIGGER IF NOT EXISTS file_search_rename AFTER UPDATE OF name ON file_metadata BEGIN
+    INSERT INTO file_search (file_search, rowid, name) VALUES ('delete', old.rowid, old.name);
+    INSERT INTO file_search (rowid, name) VALUES (new.rowid, new.name);
+END;
+
+-- Index what is already there
+INSERT INTO file_search (file_search) VALUES ('rebuild');
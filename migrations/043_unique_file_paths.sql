@@ -0,0 +1,105 @@
+-- One row per owner and path.
+--
+-- Trashed rows move under the trash along with their content, out of the
+-- way of whatever was stored at their paths since. As when trashing, an
+-- entry takes the rows at and below its original path, so rows that never
+-- had a parent_id go too, as do those of entries from before file_id was
+-- kept. Rows an entry doesn't name count as trashed only if they weren't
+-- modified after it was, and go with the deepest such entry, so rows
+-- trashed inside a trashed folder keep their own entry's location.
+-- Ids are stored as 16-byte blobs; paths spell them out like Uuid does
+CREATE TEMP TABLE trash_locations AS
+SELECT user_id, file_id, original_path, deleted_at, '/.trash/'
+    || lower(substr(hex(user_id), 1, 8) || '-' || substr(hex(user_id), 9, 4) || '-' || substr(hex(user_id), 13, 4)
+        || '-' || substr(hex(user_id), 17, 4) || '-' || substr(hex(user_id), 21))
+    || '/'
+    || lower(substr(hex(id), 1, 8) || '-' || substr(hex(id), 9, 4) || '-' || substr(hex(id), 13, 4)
+        || '-' || substr(hex(id), 17, 4) || '-' || substr(hex(id), 21)) AS location
+FROM trash;
+
+-- Compared against bounds rather than with LIKE, so '%' and '_' in names
+-- match literally
+CREATE TEMP TABLE trashed_rows AS
+SELECT id, (
+    SELECT CASE
+        WHEN entry.file_id = file_metadata.id THEN entry.location
+        ELSE entry.location || substr(file_metadata.path, length(rtrim(entry.original_path, '/')) + 1)
+    END
+    FROM trash_locations AS entry
+    WHERE entry.user_id = file_metadata.owner_id
+      AND (
+        entry.file_id = file_metadata.id
+        OR (
+            (file_metadata.path = entry.original_path
+                OR (file_metadata.path >= rtrim(entry.original_path, '/') || '/'
+                    AND file_metadata.path < rtrim(entry.original_path, '/') || '0'))
+            AND file_metadata.id NOT IN (SELECT file_id FROM trash_locations WHERE file_id IS NOT NULL)
+            AND file_metadata.modified_at <= entry.deleted_at
+        )
+      )
+    ORDER BY entry.file_id = file_metadata.id DESC, length(entry.original_path) DESC, entry.deleted_at
+    LIMIT 1
+) AS location
+FROM file_metadata
+WHERE location IS NOT NULL;
+
+UPDATE file_metadata
+SET path = (SELECT location FROM trashed_rows WHERE trashed_rows.id = file_metadata.id)
+WHERE id IN (SELECT id FROM trashed_rows);
+
+DROP TABLE trashed_rows;
+DROP TABLE trash_locations;
+
+-- Overwrites used to insert a fresh row each time, so fold every set of
+-- duplicates left into its newest row and point whatever referred to the
+-- others at it
+CREATE TEMP TABLE duplicate_file_metadata AS
+SELECT id, (
+    SELECT newest.id FROM file_metadata AS newest
+    WHERE newest.owner_id = file_metadata.owner_id AND newest.path = file_metadata.path
+    ORDER BY newest.modified_at DESC, newest.rowid DESC
+    LIMIT 1
+) AS survivor_id
+FROM file_metadata;
+
+DELETE FROM duplicate_file_metadata WHERE id = survivor_id;
+
+UPDATE file_metadata
+SET parent_id = (SELECT survivor_id FROM duplicate_file_metadata WHERE id = file_metadata.parent_id)
+WHERE parent_id IN (SELECT id FROM duplicate_file_metadata);
+
+UPDATE share_links
+SET file_id = (SELECT survivor_id FROM duplicate_file_metadata WHERE id = share_links.file_id)
+WHERE file_id IN (SELECT id FROM duplicate_file_metadata);
+
+UPDATE trash
+SET file_id = (SELECT survivor_id FROM duplicate_file_metadata WHERE id = trash.file_id)
+WHERE file_id IN (SELECT id FROM duplicate_file_metadata);
+
+UPDATE change_log
+SET file_id = (SELECT survivor_id FROM duplicate_file_metadata WHERE id = change_log.file_id)
+WHERE file_id IN (SELECT id FROM duplicate_file_metadata);
+
+-- Where the newest row already has the same version, share or issue, the
+-- duplicate's is dropped
+UPDATE OR IGNORE file_versions
+SET file_id = (SELECT survivor_id FROM duplicate_file_metadata WHERE id = file_versions.file_id)
+WHERE file_id IN (SELECT id FROM duplicate_file_metadata);
+DELETE FROM file_versions WHERE file_id IN (SELECT id FROM duplicate_file_metadata);
+
+UPDATE OR IGNORE user_shares
+SET file_id = (SELECT survivor_id FROM duplicate_file_metadata WHERE id = user_shares.file_id)
+WHERE file_id IN (SELECT id FROM duplicate_file_metadata);
+DELETE FROM user_shares WHERE file_id IN (SELECT id FROM duplicate_file_metadata);
+
+UPDATE OR IGNORE integrity_issues
+SET file_id = (SELECT survivor_id FROM duplicate_file_metadata WHERE id = integrity_issues.file_id)
+WHERE file_id IN (SELECT id FROM duplicate_file_metadata);
+DELETE FROM integrity_issues WHERE file_id IN (SELECT id FROM duplicate_file_metadata);
+
+DELETE FROM file_metadata WHERE id IN (SELECT id FROM duplicate_file_metadata);
+DROP TABLE duplicate_file_metadata;
+
+-- Replaces the plain index sync snapshots walked
+DROP INDEX IF EXISTS idx_file_metadata_owner_path;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_file_metadata_owner_path ON file_metadata (owner_id, path);
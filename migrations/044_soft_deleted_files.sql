@@ -0,0 +1,17 @@
+-- Trashed rows are flagged instead of being told apart by their path.
+-- They keep moving under the trash too, so the unique (owner_id, path)
+-- index never sees them at a path a live file can take.
+ALTER TABLE file_metadata ADD COLUMN deleted_at DATETIME;
+
+-- Rows already in the trash take the time of their entry, the third
+-- component of their path
+UPDATE file_metadata
+SET deleted_at = COALESCE(
+    (
+        SELECT trash.deleted_at FROM trash
+        WHERE lower(substr(hex(trash.id), 1, 8) || '-' || substr(hex(trash.id), 9, 4) || '-' || substr(hex(trash.id), 13, 4)
+            || '-' || substr(hex(trash.id), 17, 4) || '-' || substr(hex(trash.id), 21)) = substr(file_metadata.path, 46, 36)
+    ),
+    strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+)
+WHERE substr(path, 1, 8) = '/.trash/';
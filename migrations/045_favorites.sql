@@ -0,0 +1,11 @@
+-- Files and folders users starred, their own or ones shared with them.
+-- Rows of files that are gone are removed the next time favorites are read.
+CREATE TABLE IF NOT EXISTS favorites (
+    user_id TEXT NOT NULL,
+    file_id TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (user_id, file_id),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_favorites_file ON favorites (file_id);
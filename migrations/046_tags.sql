@@ -0,0 +1,19 @@
+-- Tags users put on files, each user's own. Renaming a tag renames it on
+-- every file it is on.
+CREATE TABLE IF NOT EXISTS tags (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    UNIQUE (user_id, name),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS file_tags (
+    tag_id TEXT NOT NULL,
+    file_id TEXT NOT NULL,
+    PRIMARY KEY (tag_id, file_id),
+    FOREIGN KEY (tag_id) REFERENCES tags (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_tags_file ON file_tags (file_id);
@@ -0,0 +1,14 @@
+-- Notes left on files, readable by everyone who can read the file. Kept by
+-- file id, so they follow the file through renames and moves, and deleted
+-- with it when it is purged. Comments of removed users stay, unsigned.
+CREATE TABLE IF NOT EXISTS comments (
+    id TEXT PRIMARY KEY,
+    file_id TEXT NOT NULL,
+    author_id TEXT,
+    body TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    edited_at TEXT,
+    FOREIGN KEY (author_id) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_comments_file ON comments (file_id, created_at);
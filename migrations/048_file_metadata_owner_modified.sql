@@ -0,0 +1,5 @@
+-- Searches for files modified after a date filter on the owner and
+-- modified_at. Lookups by path already have (owner_id, path) from 043, and
+-- either index serves queries on the owner alone.
+CREATE INDEX IF NOT EXISTS idx_file_metadata_owner_modified ON file_metadata (owner_id, modified_at);
+DROP INDEX IF EXISTS idx_file_metadata_owner;
@@ -0,0 +1,3 @@
+-- Shares made for MyCloud shares carry the MyCloud share's name, so they
+-- are revoked when it goes away. Shares users made themselves have none.
+ALTER TABLE user_shares ADD COLUMN mycloud_share TEXT;
@@ -0,0 +1,29 @@
+-- Visits outlive their share link: deleting the link only clears share_id,
+-- and each row keeps the link's token so it can still be told apart.
+-- SQLite can't change a foreign key in place, so the table is rebuilt.
+CREATE TABLE share_access_log_new (
+    id TEXT PRIMARY KEY,
+    share_id TEXT,
+    share_token TEXT,
+    accessed_at TEXT NOT NULL,
+    ip_address TEXT,
+    user_agent TEXT,
+    bytes_served INTEGER NOT NULL DEFAULT 0,
+    outcome TEXT NOT NULL,
+    detail TEXT,
+    FOREIGN KEY (share_id) REFERENCES share_links (id) ON DELETE SET NULL
+);
+
+INSERT INTO share_access_log_new
+    (id, share_id, share_token, accessed_at, ip_address, user_agent, bytes_served, outcome, detail)
+SELECT share_access_log.id, share_access_log.share_id, share_links.share_token, share_access_log.accessed_at,
+       share_access_log.ip_address, share_access_log.user_agent, share_access_log.bytes_served,
+       share_access_log.outcome, share_access_log.detail
+FROM share_access_log
+LEFT JOIN share_links ON share_links.id = share_access_log.share_id;
+
+DROP TABLE share_access_log;
+ALTER TABLE share_access_log_new RENAME TO share_access_log;
+
+CREATE INDEX IF NOT EXISTS idx_share_access_log_share ON share_access_log (share_id, accessed_at);
+CREATE INDEX IF NOT EXISTS idx_share_access_log_accessed_at ON share_access_log (accessed_at);
@@ -0,0 +1,3 @@
+-- When a pending delivery is tried again, so a backlog of retries to a
+-- hook that is down can be told apart from deliveries still on their way
+ALTER TABLE webhook_deliveries ADD COLUMN next_attempt_at TEXT;
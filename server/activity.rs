@@ -0,0 +1,176 @@
+use chrono::{DateTime, Duration, Utc};
+use uuid::Uuid;
+
+use crate::database::ActivityEvent;
+use crate::types::{ActivityGroup, ActivityKind};
+
+/// Folds activity, fed newest first, into groups. Consecutive events of the
+/// same kind, by the same actor and device, in the same folder and the same
+/// stretch of `bucket` share one; anything in between starts a new one.
+pub struct ActivityGrouper {
+    bucket_seconds: i64,
+    groups: Vec<Grouped>,
+}
+
+struct Grouped {
+    group: ActivityGroup,
+    bucket: i64,
+    /// Time and id of the oldest event taken in, where the feed resumes
+    oldest: (DateTime<Utc>, Uuid),
+}
+
+impl ActivityGrouper {
+    pub fn new(bucket: Duration) -> Self {
+        Self {
+            bucket_seconds: bucket.num_seconds().max(1),
+            groups: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, event: ActivityEvent) {
+        let bucket = event.occurred_at.timestamp().div_euclid(self.bucket_seconds);
+        let directory = directory(&event.path);
+
+        if let Some(last) = self.groups.last_mut() {
+            let group = &mut last.group;
+            if last.bucket == bucket
+                && group.kind == event.kind
+                && group.directory == directory
+                && group.actor_id == event.actor_id
+                && group.device_id == event.device_id
+            {
+                if !group.file_ids.contains(&event.file_id) {
+                    group.file_ids.push(event.file_id);
+                }
+                group.events += 1;
+                group.first_at = event.occurred_at;
+                last.oldest = (event.occurred_at, event.id);
+                return;
+            }
+        }
+
+        self.groups.push(Grouped {
+            group: ActivityGroup {
+                kind: event.kind,
+                directory,
+                actor_id: event.actor_id,
+                device_id: event.device_id,
+                file_ids: vec![event.file_id],
+                events: 1,
+                first_at: event.occurred_at,
+                last_at: event.occurred_at,
+            },
+            bucket,
+            oldest: (event.occurred_at, event.id),
+        });
+    }
+
+    /// Whether a group past the first `limit` has begun, so those are complete
+    pub fn has_more_than(&self, limit: usize) -> bool {
+        self.groups.len() > limit
+    }
+
+    /// The first `limit` groups, and when more follow, the time and id of
+    /// the oldest event in the last of them to carry on from
+    pub fn finish(mut self, limit: usize) -> (Vec<ActivityGroup>, Option<(DateTime<Utc>, Uuid)>) {
+        let resume = self.has_more_than(limit)
+            .then(|| {
+                self.groups.truncate(limit);
+                self.groups.last().map(|last| last.oldest)
+            })
+            .flatten();
+        (self.groups.into_iter().map(|grouped| grouped.group).collect(), resume)
+    }
+}
+
+/// The folder `path` is in
+fn directory(path: &str) -> String {
+    match path.trim_end_matches('/').rsplit_once('/') {
+        Some((parent, _)) if !parent.is_empty() => parent.to_string(),
+        _ => "/".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn event(kind: ActivityKind, path: &str, minute: i64, device: Option<&str>) -> ActivityEvent {
+        ActivityEvent {
+            id: Uuid::new_v4(),
+            kind,
+            file_id: Uuid::new_v4(),
+            path: path.to_string(),
+            occurred_at: Utc.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap() + Duration::minutes(minute),
+            actor_id: None,
+            device_id: device.map(str::to_string),
+        }
+    }
+
+    fn summary(groups: &[ActivityGroup]) -> Vec<(ActivityKind, &str, usize)> {
+        groups.iter().map(|group| (group.kind, group.directory.as_str(), group.file_ids.len())).collect()
+    }
+
+    #[test]
+    fn test_runs_of_alike_events_make_one_group() {
+        let mut grouper = ActivityGrouper::new(Duration::hours(1));
+        // Newest first, as the feed is read
+        for minute in (10..24).rev() {
+            grouper.push(event(ActivityKind::Created, &format!("/scans/{}.pdf", minute), minute, Some("scanner")));
+        }
+        grouper.push(event(ActivityKind::Modified, "/notes.txt", 8, Some("laptop")));
+        grouper.push(event(ActivityKind::Created, "/scans/old.pdf", 5, Some("scanner")));
+        grouper.push(event(ActivityKind::Created, "/scans/other-device.pdf", 4, Some("laptop")));
+        grouper.push(event(ActivityKind::Created, "/scans/sub/deeper.pdf", 3, Some("scanner")));
+        // The hour before is another bucket
+        grouper.push(event(ActivityKind::Created, "/scans/earlier.pdf", -1, Some("scanner")));
+
+        let (groups, resume) = grouper.finish(10);
+        assert!(resume.is_none());
+        assert_eq!(summary(&groups), vec![
+            (ActivityKind::Created, "/scans", 14),
+            (ActivityKind::Modified, "/", 1),
+            (ActivityKind::Created, "/scans", 1),
+            (ActivityKind::Created, "/scans", 1),
+            (ActivityKind::Created, "/scans/sub", 1),
+            (ActivityKind::Created, "/scans", 1),
+        ]);
+        assert_eq!(groups[0].events, 14);
+        assert!(groups[0].first_at < groups[0].last_at);
+    }
+
+    #[test]
+    fn test_files_changed_repeatedly_count_once() {
+        let mut grouper = ActivityGrouper::new(Duration::hours(1));
+        let mut edit = event(ActivityKind::Modified, "/docs/plan.txt", 30, None);
+        let file_id = edit.file_id;
+        for minute in [30, 20, 10] {
+            edit.id = Uuid::new_v4();
+            edit.occurred_at = Utc.with_ymd_and_hms(2024, 5, 1, 9, minute, 0).unwrap();
+            grouper.push(edit.clone());
+        }
+
+        let (groups, _) = grouper.finish(10);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].file_ids, vec![file_id]);
+        assert_eq!(groups[0].events, 3);
+    }
+
+    #[test]
+    fn test_pages_resume_after_the_last_whole_group() {
+        let mut grouper = ActivityGrouper::new(Duration::hours(1));
+        let first = event(ActivityKind::Created, "/a/1.txt", 50, None);
+        let last_of_first = event(ActivityKind::Created, "/a/2.txt", 40, None);
+        let resume_at = (last_of_first.occurred_at, last_of_first.id);
+        grouper.push(first);
+        grouper.push(last_of_first);
+        assert!(!grouper.has_more_than(1));
+        grouper.push(event(ActivityKind::Deleted, "/a/3.txt", 30, None));
+        assert!(grouper.has_more_than(1));
+
+        let (groups, resume) = grouper.finish(1);
+        assert_eq!(summary(&groups), vec![(ActivityKind::Created, "/a", 2)]);
+        assert_eq!(resume, Some(resume_at));
+    }
+}
@@ -0,0 +1,180 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header::USER_AGENT, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::auth::Claims;
+use crate::database::Database;
+use crate::types::{AuditEntry, AuditEvent, ShareAccess, ShareAccessOutcome};
+
+/// Write an entry to the audit log. A failure to write one is logged and
+/// must never fail the request it describes.
+pub async fn record(database: &Database, entry: AuditEntry) {
+    if let Err(e) = database.record_audit_event(&entry).await {
+        tracing::warn!("Failed to record {} audit event: {}", entry.event.as_str(), e);
+    }
+}
+
+/// The same without holding up the request
+pub fn record_in_background(database: &Database, entry: AuditEntry) {
+    let database = database.clone();
+    tokio::spawn(async move { record(&database, entry).await });
+}
+
+pub fn user_agent(headers: &HeaderMap) -> Option<String> {
+    headers.get(USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Address and user agent of the client that sent a request
+pub fn request_origin(request: &Request) -> (Option<String>, Option<String>) {
+    let ip = request.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    (ip, user_agent(request.headers()))
+}
+
+/// Method and path of a request, as the target of its audit entry
+pub fn request_target(request: &Request) -> String {
+    format!("{} {}", request.method(), request.uri().path())
+}
+
+/// Attribute an entry to whoever made the request: the admin behind an
+/// impersonation token, noting the user they acted as, or else the user the
+/// token belongs to. Anonymous visitors are left unnamed.
+pub fn with_caller(entry: AuditEntry, claims: &Claims) -> AuditEntry {
+    if claims.is_anonymous() {
+        return entry;
+    }
+    match &claims.impersonator {
+        Some(impersonator) => entry
+            .with_user(Some(impersonator.id), &impersonator.username)
+            .with_impersonating(&claims.username),
+        None => entry.with_user(Uuid::parse_str(&claims.sub).ok(), &claims.username),
+    }
+}
+
+/// success for 2xx, denied for 401 and 403, failure otherwise
+pub fn outcome_of(status: StatusCode) -> &'static str {
+    match status {
+        status if status.is_success() => "success",
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => "denied",
+        _ => "failure",
+    }
+}
+
+/// What `audit_changes` records requests as
+#[derive(Clone)]
+pub struct AuditRoutes {
+    pub database: Database,
+    pub event: AuditEvent,
+}
+
+/// Records every request that changes something under the routes it is
+/// layered on, with the response's outcome. Layered inside `auth_middleware`
+/// and the permission check, so requests turned away there are recorded as
+/// denials rather than here.
+pub async fn audit_changes(
+    State(routes): State<AuditRoutes>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.method() == Method::GET || request.method() == Method::HEAD {
+        return next.run(request).await;
+    }
+
+    let (ip, user_agent) = request_origin(&request);
+    let target = request_target(&request);
+    let claims = request.extensions().get::<Claims>().cloned();
+
+    let response = next.run(request).await;
+
+    let mut entry = AuditEntry::new(routes.event, outcome_of(response.status()))
+        .with_origin(ip, user_agent)
+        .with_target(target);
+    if let Some(claims) = &claims {
+        entry = with_caller(entry, claims);
+    }
+    record_in_background(&routes.database, entry);
+
+    response
+}
+
+/// Records visits to share links for their owners, in the background so
+/// public downloads don't wait on the database
+#[derive(Clone)]
+pub struct ShareAccessLog {
+    database: Database,
+    capture_ips: bool,
+}
+
+impl ShareAccessLog {
+    pub fn new(database: Database) -> Self {
+        Self { database, capture_ips: true }
+    }
+
+    /// Whether visitors' networks are recorded at all
+    pub fn with_ip_capture(mut self, capture_ips: bool) -> Self {
+        self.capture_ips = capture_ips;
+        self
+    }
+
+    /// A visit from `client`, to be recorded once its outcome is known
+    pub fn visit(&self, client: SocketAddr, headers: &HeaderMap) -> ShareVisit {
+        ShareVisit {
+            log: self.clone(),
+            ip_address: self.capture_ips.then(|| truncate_ip(client.ip())),
+            user_agent: user_agent(headers),
+        }
+    }
+}
+
+pub struct ShareVisit {
+    log: ShareAccessLog,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl ShareVisit {
+    pub fn record(&self, share_id: Uuid, outcome: ShareAccessOutcome, bytes_served: u64) {
+        let access = ShareAccess {
+            id: Uuid::new_v4(),
+            share_id,
+            accessed_at: Utc::now(),
+            ip_address: self.ip_address.clone(),
+            user_agent: self.user_agent.clone(),
+            bytes_served,
+            outcome,
+            detail: None,
+        };
+        let database = self.log.database.clone();
+        tokio::spawn(async move {
+            if let Err(e) = database.record_share_access(&access).await {
+                tracing::warn!("Failed to record a visit to share {}: {}", access.share_id, e);
+            }
+        });
+    }
+}
+
+/// The network an address belongs to: the /24 of IPv4 addresses and the
+/// /48 of IPv6 ones, enough to tell visitors apart without naming them
+pub fn truncate_ip(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            std::net::Ipv4Addr::new(a, b, c, 0).to_string()
+        }
+        IpAddr::V6(ip) => {
+            let mut segments = ip.segments();
+            segments[3..].fill(0);
+            std::net::Ipv6Addr::from(segments).to_string()
+        }
+    }
+}
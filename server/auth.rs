@@ -1,46 +1,388 @@
-use jsonwebtoken::{encode, decode, Header, Algorithm, Validation, EncodingKey, DecodingKey};
+use jsonwebtoken::{encode, decode, decode_header, Header, Algorithm, Validation, EncodingKey, DecodingKey};
+use jsonwebtoken::errors::ErrorKind;
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc, Duration};
 use uuid::Uuid;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
 use anyhow::{Result, anyhow};
 use bcrypt::{hash, verify, DEFAULT_COST};
-use crate::types::User;
+use argon2::{Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use argon2::password_hash::{rand_core::{OsRng, RngCore}, SaltString};
+use sha2::{Digest, Sha256};
+use crate::types::{AppPassword, Impersonator, User, Permission, PathScope, PolicyViolation, TokenScope};
+use crate::config::{AuthSettings, PasswordPolicy, PasswordScheme};
+use crate::database::{ChangeActor, Database};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Stored in place of a hash for users with no password here yet, like
+/// those provisioned from MyCloud before their first login. Nothing matches it.
+pub const NO_PASSWORD: &str = "!";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,  // User ID
     pub username: String,
     pub exp: i64,     // Expiration time
     pub iat: i64,     // Issued at
     pub device_id: Option<String>,
+    #[serde(default)]
+    pub jti: String,  // Token ID, for revocation
+    /// Set for personal access tokens, which only get part of the owner's rights
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<TokenScope>,
+    /// What the user held when the token was issued, narrowed by the scope
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
+    /// Folders a scoped token is limited to, per action. Empty for every
+    /// other token, which reaches all of its owner's files.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<PathScope>,
+    /// The user's `auth_generation` when the token was issued
+    #[serde(default)]
+    pub auth_generation: i64,
+    /// Set when an admin is acting as the user; requests are audited as theirs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<Impersonator>,
 }
 
+impl Claims {
+    pub fn has(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    /// Personal access tokens can't manage the account they belong to
+    pub fn is_api_token(&self) -> bool {
+        self.scope.is_some()
+    }
+
+    pub fn is_impersonation(&self) -> bool {
+        self.impersonator.is_some()
+    }
+
+    /// What a visitor without a login holds when anonymous reads are on.
+    /// It belongs to no user and, being read-only scoped, can't manage one.
+    pub fn anonymous() -> Self {
+        Self {
+            sub: Uuid::nil().to_string(),
+            username: String::new(),
+            exp: 0,
+            iat: 0,
+            device_id: None,
+            jti: String::new(),
+            scope: Some(TokenScope::ReadOnly),
+            permissions: vec![Permission::Read],
+            scopes: Vec::new(),
+            auth_generation: 0,
+            impersonator: None,
+        }
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.sub == Uuid::nil().to_string()
+    }
+}
+
+/// Read-only access without a login, limited to `root` in the shared tree.
+/// Put in the request next to `Claims::anonymous()` by `auth_middleware`.
+#[derive(Debug, Clone)]
+pub struct AnonymousAccess {
+    pub root: String,
+}
+
+/// What is actually signed: the claims plus who issued the token and for whom
+#[derive(Serialize, Deserialize)]
+struct SignedClaims {
+    #[serde(flatten)]
+    claims: Claims,
+    iss: String,
+    aud: String,
+}
+
+/// Cookie holding the access token of browsers that logged in through OIDC
+pub const TOKEN_COOKIE: &str = "synker_token";
+
+/// Prefix that tells personal access tokens apart from JWTs
+pub const API_TOKEN_PREFIX: &str = "synker_pat_";
+
+/// Revocations checked on every request, mirrored from the database so the
+/// check never has to leave memory
+#[derive(Debug, Default)]
+struct Revocations {
+    /// Revoked token IDs and when each token expires
+    jtis: HashMap<String, i64>,
+    /// Per user: tokens issued at or before this timestamp are rejected
+    valid_after: HashMap<String, i64>,
+    /// The same per device, keyed by user and device ID
+    device_valid_after: HashMap<(String, String), i64>,
+}
+
+#[derive(Clone)]
 pub struct AuthService {
     encoding_key: EncodingKey,
-    decoding_key: DecodingKey,
+    /// The current secret's key first, then those of previous secrets
+    decoding_keys: Vec<DecodingKey>,
+    issuer: String,
+    audience: String,
+    leeway_seconds: u64,
+    access_token_ttl: Duration,
+    refresh_token_ttl: Duration,
+    password_scheme: PasswordScheme,
+    bcrypt_cost: u32,
+    argon2_params: Params,
+    password_policy: PasswordPolicy,
+    revocations: Arc<RwLock<Revocations>>,
+    /// Whether each user was active and their auth generation, or None if
+    /// they were gone, and when that was read
+    user_status: Arc<RwLock<HashMap<String, (Option<(bool, i64)>, Instant)>>>,
+    user_status_ttl: std::time::Duration,
+    /// App password each recently checked username and password matched,
+    /// keyed by their hash, so clients sending Basic auth with every request
+    /// don't pay for bcrypt each time
+    app_password_matches: Arc<RwLock<HashMap<String, (Uuid, Instant)>>>,
 }
 
+// How long a user's status is trusted before the database is asked again
+const USER_STATUS_TTL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// How long a matched app password is remembered. The app password itself is
+// still looked up on every request, so revoking it takes effect at once.
+const APP_PASSWORD_MATCH_TTL: std::time::Duration = std::time::Duration::from_secs(600);
+
+// Longest an impersonation token lives, however long access tokens do
+const IMPERSONATION_TTL_MINUTES: i64 = 15;
+
+// Kinds of character `require_mixed_classes` asks a password to have
+const MIXED_CLASSES_REQUIRED: usize = 3;
+
+/// Characters app passwords are made of, leaving out look-alikes
+const APP_PASSWORD_ALPHABET: &[u8; 32] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+/// Leading hex digits of its id an app password starts with
+const APP_PASSWORD_ID_LEN: usize = 8;
+
 impl AuthService {
     pub fn new(secret: &str) -> Self {
         Self {
             encoding_key: EncodingKey::from_secret(secret.as_ref()),
-            decoding_key: DecodingKey::from_secret(secret.as_ref()),
+            decoding_keys: vec![DecodingKey::from_secret(secret.as_ref())],
+            issuer: "synker".to_string(),
+            audience: "synker".to_string(),
+            leeway_seconds: 60,
+            access_token_ttl: Duration::hours(1),
+            refresh_token_ttl: Duration::days(30),
+            password_scheme: PasswordScheme::Bcrypt,
+            bcrypt_cost: DEFAULT_COST,
+            argon2_params: Params::default(),
+            password_policy: PasswordPolicy::default(),
+            revocations: Arc::new(RwLock::new(Revocations::default())),
+            user_status: Arc::new(RwLock::new(HashMap::new())),
+            user_status_ttl: USER_STATUS_TTL,
+            app_password_matches: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    pub fn from_settings(settings: &AuthSettings) -> Self {
+        Self::new(&settings.jwt_secret)
+            .with_previous_secrets(&settings.jwt_previous_secrets)
+            .with_issuer(&settings.jwt_issuer, &settings.jwt_audience)
+            .with_leeway(settings.jwt_leeway_seconds)
+            .with_token_expiry(
+                Duration::hours(settings.token_expiry_hours),
+                Duration::days(settings.refresh_token_expiry_days),
+            )
+            .with_password_scheme(settings.password_scheme)
+            .with_bcrypt_cost(settings.bcrypt_cost)
+            // validate() has already turned down parameters Argon2 won't take
+            .with_argon2_params(
+                Params::new(
+                    settings.argon2_memory_kib,
+                    settings.argon2_iterations,
+                    settings.argon2_parallelism,
+                    None,
+                )
+                .unwrap_or_default(),
+            )
+            .with_password_policy(settings.password_policy.clone())
+    }
+
+    /// Secrets tokens may still be signed with. New tokens are always signed
+    /// with the current one.
+    pub fn with_previous_secrets(mut self, secrets: &[String]) -> Self {
+        self.decoding_keys.truncate(1);
+        self.decoding_keys.extend(secrets.iter().map(|secret| DecodingKey::from_secret(secret.as_ref())));
+        self
+    }
+
+    /// `iss` and `aud` put in new tokens and required of presented ones
+    pub fn with_issuer(mut self, issuer: &str, audience: &str) -> Self {
+        self.issuer = issuer.to_string();
+        self.audience = audience.to_string();
+        self
+    }
+
+    /// Clock difference tolerated when checking `exp`
+    pub fn with_leeway(mut self, seconds: u64) -> Self {
+        self.leeway_seconds = seconds;
+        self
+    }
+
+    /// How long access tokens and refresh tokens stay valid
+    pub fn with_token_expiry(mut self, access: Duration, refresh: Duration) -> Self {
+        self.access_token_ttl = access;
+        self.refresh_token_ttl = refresh;
+        self
+    }
+
+    /// Scheme new password hashes are made with
+    pub fn with_password_scheme(mut self, scheme: PasswordScheme) -> Self {
+        self.password_scheme = scheme;
+        self
+    }
+
+    pub fn with_bcrypt_cost(mut self, cost: u32) -> Self {
+        self.bcrypt_cost = cost;
+        self
+    }
+
+    pub fn with_argon2_params(mut self, params: Params) -> Self {
+        self.argon2_params = params;
+        self
+    }
+
+    pub fn with_password_policy(mut self, policy: PasswordPolicy) -> Self {
+        self.password_policy = policy;
+        self
+    }
+
+    /// How long a user's status is cached by `is_user_current`
+    pub fn with_user_status_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.user_status_ttl = ttl;
+        self
+    }
+
+    /// Every rule of the password policy a new password breaks
+    pub fn validate_password(&self, password: &str) -> std::result::Result<(), Vec<PolicyViolation>> {
+        let policy = &self.password_policy;
+        if !policy.enabled {
+            return Ok(());
+        }
+
+        let mut violations = Vec::new();
+        if password.chars().count() < policy.min_length {
+            violations.push(PolicyViolation::TooShort { min_length: policy.min_length });
+        }
+        if let Some(max_length) = policy.max_length.filter(|max_length| password.len() > *max_length) {
+            violations.push(PolicyViolation::TooLong { max_length });
+        }
+        if policy.require_mixed_classes {
+            let found = [
+                password.chars().any(|c| c.is_lowercase()),
+                password.chars().any(|c| c.is_uppercase()),
+                password.chars().any(|c| c.is_numeric()),
+                password.chars().any(|c| !c.is_alphanumeric()),
+            ]
+            .into_iter()
+            .filter(|present| *present)
+            .count();
+            if found < MIXED_CLASSES_REQUIRED {
+                violations.push(PolicyViolation::MissingCharacterClasses { required: MIXED_CLASSES_REQUIRED, found });
+            }
+        }
+        if policy.deny_list.iter().any(|common| common.eq_ignore_ascii_case(password)) {
+            violations.push(PolicyViolation::Common);
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    pub fn refresh_token_ttl(&self) -> Duration {
+        self.refresh_token_ttl
+    }
+
     pub fn hash_password(&self, password: &str) -> Result<String> {
-        let hashed = hash(password, DEFAULT_COST)?;
-        Ok(hashed)
+        match self.password_scheme {
+            PasswordScheme::Bcrypt => Ok(hash(password, self.bcrypt_cost)?),
+            PasswordScheme::Argon2id => {
+                let salt = SaltString::generate(&mut OsRng);
+                let hashed = self.argon2()
+                    .hash_password(password.as_bytes(), &salt)
+                    .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
+                Ok(hashed.to_string())
+            }
+        }
     }
 
+    /// Check a password against a stored hash of either scheme, telling
+    /// them apart by the hash's prefix
     pub fn verify_password(&self, password: &str, hash: &str) -> Result<bool> {
-        let is_valid = verify(password, hash)?;
-        Ok(is_valid)
+        if hash == NO_PASSWORD {
+            return Ok(false);
+        }
+        if !hash.starts_with("$argon2") {
+            return Ok(verify(password, hash)?);
+        }
+
+        let parsed = PasswordHash::new(hash)
+            .map_err(|e| anyhow!("Malformed Argon2 hash: {}", e))?;
+        // The hash names its own variant and parameters
+        match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(anyhow!("Failed to verify password: {}", e)),
+        }
+    }
+
+    /// Whether a stored hash was made with another scheme or other parameters
+    /// than new ones would be, so it should be replaced once the password is known
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        match self.password_scheme {
+            // $2b$12$..., the cost being the two digits after the version
+            PasswordScheme::Bcrypt => {
+                !hash.starts_with("$2")
+                    || hash.get(4..6).and_then(|cost| cost.parse::<u32>().ok()) != Some(self.bcrypt_cost)
+            }
+            PasswordScheme::Argon2id => {
+                let parsed = match PasswordHash::new(hash) {
+                    Ok(parsed) => parsed,
+                    Err(_) => return true,
+                };
+                if parsed.algorithm != argon2::Algorithm::Argon2id.ident() {
+                    return true;
+                }
+
+                match Params::try_from(&parsed) {
+                    Ok(params) => {
+                        params.m_cost() != self.argon2_params.m_cost()
+                            || params.t_cost() != self.argon2_params.t_cost()
+                            || params.p_cost() != self.argon2_params.p_cost()
+                    }
+                    Err(_) => true,
+                }
+            }
+        }
     }
 
-    pub fn generate_token(&self, user: &User, device_id: Option<String>) -> Result<String> {
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, self.argon2_params.clone())
+    }
+
+    /// A signed access token and the moment it expires
+    pub fn generate_token(&self, user: &User, device_id: Option<String>) -> Result<(String, DateTime<Utc>)> {
+        let (token, claims) = self.issue_token(user, device_id)?;
+        let expires_at = DateTime::from_timestamp(claims.exp, 0)
+            .ok_or_else(|| anyhow!("Token expiry out of range"))?;
+        Ok((token, expires_at))
+    }
+
+    /// A signed access token and the claims it carries
+    pub fn issue_token(&self, user: &User, device_id: Option<String>) -> Result<(String, Claims)> {
         let now = Utc::now();
-        let expiration = now + Duration::hours(24); // Token expires in 24 hours
+        let expiration = now + self.access_token_ttl;
 
         let claims = Claims {
             sub: user.id.to_string(),
@@ -48,23 +390,346 @@ impl AuthService {
             exp: expiration.timestamp(),
             iat: now.timestamp(),
             device_id,
+            jti: Uuid::new_v4().to_string(),
+            scope: None,
+            permissions: user.granted_permissions(),
+            scopes: Vec::new(),
+            auth_generation: user.auth_generation,
+            impersonator: None,
+        };
+
+        self.sign(claims)
+    }
+
+    /// A short-lived access token for an admin to act as `user`. It never
+    /// carries the admin permission, so it can't manage users or start
+    /// another impersonation, and it can't be refreshed.
+    pub fn issue_impersonation_token(&self, user: &User, impersonator: Impersonator) -> Result<(String, Claims)> {
+        let now = Utc::now();
+        let expiration = now + self.access_token_ttl.min(Duration::minutes(IMPERSONATION_TTL_MINUTES));
+
+        let claims = Claims {
+            sub: user.id.to_string(),
+            username: user.username.clone(),
+            exp: expiration.timestamp(),
+            iat: now.timestamp(),
+            device_id: None,
+            jti: Uuid::new_v4().to_string(),
+            scope: None,
+            permissions: user.granted_permissions().into_iter()
+                .filter(|permission| *permission != Permission::Admin)
+                .collect(),
+            scopes: Vec::new(),
+            auth_generation: user.auth_generation,
+            impersonator: Some(impersonator),
         };
 
-        let token = encode(&Header::default(), &claims, &self.encoding_key)?;
-        Ok(token)
+        self.sign(claims)
+    }
+
+    fn sign(&self, claims: Claims) -> Result<(String, Claims)> {
+        let signed = SignedClaims {
+            claims,
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+        };
+        let token = encode(&Header::new(Algorithm::HS256), &signed, &self.encoding_key)?;
+        Ok((token, signed.claims))
     }
 
     pub fn verify_token(&self, token: &str) -> Result<Claims> {
-        let validation = Validation::new(Algorithm::HS256);
-        let token_data = decode::<Claims>(token, &self.decoding_key, &validation)?;
-        
-        // Check if token is expired
+        // Turned away before any key is tried: `none` doesn't even parse as
+        // an algorithm, and only HS256 is ever issued
+        let header = decode_header(token)?;
+        if header.alg != Algorithm::HS256 {
+            return Err(anyhow!("Unexpected token algorithm {:?}", header.alg));
+        }
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = self.leeway_seconds;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+
+        // Only a signature mismatch is worth retrying with an older secret
+        let mut signed = None;
+        for key in &self.decoding_keys {
+            match decode::<SignedClaims>(token, key, &validation) {
+                Ok(token_data) => {
+                    signed = Some(token_data.claims);
+                    break;
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::InvalidSignature) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let claims = signed.ok_or_else(|| anyhow!("Token signature is invalid"))?.claims;
+
+        if self.is_revoked(&claims) {
+            return Err(anyhow!("Token has been revoked"));
+        }
+
+        Ok(claims)
+    }
+
+    /// Resolve a personal access token to its owner's claims, narrowed to
+    /// the token's scope
+    pub async fn verify_api_token(&self, database: &Database, token: &str) -> Result<Claims> {
+        let (api_token, owner) = database.get_api_token(&Self::hash_token(token)).await?
+            .ok_or_else(|| anyhow!("Unknown or expired access token"))?;
+
+        // Looked up on every use, so a change to the owner's rights applies at once
+        let permissions = owner.granted_permissions()
+            .into_iter()
+            .filter(|permission| api_token.scope.allows(*permission))
+            .collect();
+
+        let claims = Claims {
+            sub: api_token.user_id.to_string(),
+            username: owner.username,
+            exp: api_token.expires_at.map_or(i64::MAX, |at| at.timestamp()),
+            iat: api_token.created_at.timestamp(),
+            device_id: None,
+            jti: String::new(),
+            scope: Some(api_token.scope),
+            permissions,
+            scopes: api_token.path_scopes,
+            auth_generation: owner.auth_generation,
+            impersonator: None,
+        };
+
+        // An admin revoking the owner's tokens takes access tokens too
+        if self.is_revoked(&claims) {
+            return Err(anyhow!("Token has been revoked"));
+        }
+
+        database.touch_api_token(api_token.id, Utc::now()).await?;
+        Ok(claims)
+    }
+
+    /// Claims for an app password this username matched lately, without
+    /// checking its hash again; None if it didn't
+    pub async fn remembered_app_password(&self, database: &Database, username: &str, password: &str) -> Result<Option<Claims>> {
+        let key = Self::hash_token(&format!("{}:{}", username, password));
+        let remembered = self.app_password_matches.read().unwrap()
+            .get(&key)
+            .filter(|(_, matched_at)| matched_at.elapsed() < APP_PASSWORD_MATCH_TTL)
+            .map(|(id, _)| *id);
+
+        let found = match remembered {
+            Some(id) => database.get_app_password(id).await?
+                .filter(|(_, owner)| owner.username == username),
+            None => None,
+        };
+        match found {
+            Some((app_password, owner)) => Ok(Some(self.app_password_claims(database, &app_password, &owner).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Claims for HTTP Basic auth with an app password, which only gets what
+    /// a read-write access token would, limited to its folder if it has one.
+    /// The id the password starts with picks the one app password whose
+    /// hash is checked, off the async runtime.
+    pub async fn verify_app_password(&self, database: &Database, username: &str, password: &str) -> Result<Claims> {
+        let unknown = || anyhow!("Unknown username or app password");
+        let prefix = password.split_once('-')
+            .map(|(prefix, _)| prefix)
+            .filter(|prefix| prefix.len() == APP_PASSWORD_ID_LEN)
+            .ok_or_else(unknown)?;
+
+        let owner = database.get_user_by_username(username).await?
+            .filter(|owner| owner.is_active)
+            .ok_or_else(unknown)?;
+        let candidates = database.list_app_passwords(owner.id, false).await?
+            .into_iter()
+            .filter(|app_password| app_password.id.simple().to_string().starts_with(prefix));
+
+        for app_password in candidates {
+            let (candidate, password_hash) = (password.to_string(), app_password.password_hash.clone());
+            let matches = tokio::task::spawn_blocking(move || verify(candidate, &password_hash).unwrap_or(false)).await?;
+            if !matches {
+                continue;
+            }
+
+            let now = Instant::now();
+            let key = Self::hash_token(&format!("{}:{}", username, password));
+            let mut matches = self.app_password_matches.write().unwrap();
+            if matches.len() > 10_000 {
+                matches.retain(|_, (_, matched_at)| now.duration_since(*matched_at) < APP_PASSWORD_MATCH_TTL);
+            }
+            matches.insert(key, (app_password.id, now));
+            drop(matches);
+
+            return self.app_password_claims(database, &app_password, &owner).await;
+        }
+
+        Err(unknown())
+    }
+
+    async fn app_password_claims(&self, database: &Database, app_password: &AppPassword, owner: &User) -> Result<Claims> {
+        let scope = TokenScope::ReadWrite;
+        let claims = Claims {
+            sub: owner.id.to_string(),
+            username: owner.username.clone(),
+            exp: app_password.expires_at.map_or(i64::MAX, |at| at.timestamp()),
+            iat: app_password.created_at.timestamp(),
+            device_id: None,
+            jti: String::new(),
+            scope: Some(scope),
+            permissions: owner.granted_permissions()
+                .into_iter()
+                .filter(|permission| scope.allows(*permission))
+                .collect(),
+            scopes: app_password.path_scopes(),
+            auth_generation: owner.auth_generation,
+            impersonator: None,
+        };
+
+        // An admin revoking the owner's tokens takes app passwords too
+        if self.is_revoked(&claims) {
+            return Err(anyhow!("Token has been revoked"));
+        }
+
+        database.touch_app_password(app_password.id, Utc::now()).await?;
+        Ok(claims)
+    }
+
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        let revocations = self.revocations.read().unwrap();
+
+        if !claims.jti.is_empty() && revocations.jtis.contains_key(&claims.jti) {
+            return true;
+        }
+        // iat has one-second resolution, so a token issued in the same second
+        // as the cutoff counts as issued before it
+        if revocations.valid_after.get(&claims.sub)
+            .is_some_and(|valid_after| claims.iat <= *valid_after) {
+            return true;
+        }
+
+        claims.device_id.as_ref().is_some_and(|device_id| {
+            revocations.device_valid_after
+                .get(&(claims.sub.clone(), device_id.clone()))
+                .is_some_and(|valid_after| claims.iat <= *valid_after)
+        })
+    }
+
+    /// Whether the token's user still exists, is active and hasn't moved to
+    /// a newer auth generation since it was issued. The answer is cached
+    /// briefly, so a change made straight in the database or by another
+    /// server takes up to `user_status_ttl` to be seen.
+    pub async fn is_user_current(&self, database: &Database, claims: &Claims) -> Result<bool> {
+        let cached = self.user_status.read().unwrap()
+            .get(&claims.sub)
+            .filter(|(_, read_at)| read_at.elapsed() < self.user_status_ttl)
+            .map(|(status, _)| *status);
+
+        let status = match cached {
+            Some(status) => status,
+            None => {
+                let Ok(user_id) = Uuid::parse_str(&claims.sub) else {
+                    return Ok(false);
+                };
+                let status = database.get_user_status(user_id).await?;
+                let now = Instant::now();
+                let mut cache = self.user_status.write().unwrap();
+                if cache.len() > 10_000 {
+                    cache.retain(|_, (_, read_at)| now.duration_since(*read_at) < self.user_status_ttl);
+                }
+                cache.insert(claims.sub.clone(), (status, now));
+                status
+            }
+        };
+
+        Ok(status.is_some_and(|(is_active, generation)| is_active && claims.auth_generation >= generation))
+    }
+
+    /// Reject one token from now on. The caller records it in the database.
+    pub fn revoke_token(&self, jti: &str, expires_at: i64) {
+        self.revocations.write().unwrap().jtis.insert(jti.to_string(), expires_at);
+    }
+
+    /// Reject every token issued to the user up to `before`. Their cached
+    /// status is dropped too, so a deactivation is seen on the next request.
+    pub fn revoke_user_tokens(&self, user_id: Uuid, before: DateTime<Utc>) {
+        self.revocations.write().unwrap()
+            .valid_after
+            .insert(user_id.to_string(), before.timestamp());
+        self.user_status.write().unwrap().remove(&user_id.to_string());
+    }
+
+    /// Reject every token issued to one of the user's devices up to `before`
+    pub fn revoke_device_tokens(&self, user_id: Uuid, device_id: &str, before: DateTime<Utc>) {
+        self.revocations.write().unwrap()
+            .device_valid_after
+            .insert((user_id.to_string(), device_id.to_string()), before.timestamp());
+    }
+
+    /// Merge in revocations read from the database and forget tokens that
+    /// have expired anyway. Merging rather than replacing keeps a revocation
+    /// made while the database was being read.
+    pub fn load_revocations(
+        &self,
+        jtis: Vec<(String, i64)>,
+        valid_after: Vec<(Uuid, DateTime<Utc>)>,
+        device_valid_after: Vec<(Uuid, String, DateTime<Utc>)>,
+    ) {
         let now = Utc::now().timestamp();
-        if token_data.claims.exp < now {
-            return Err(anyhow!("Token has expired"));
+        let mut revocations = self.revocations.write().unwrap();
+
+        revocations.jtis.extend(jtis);
+        revocations.jtis.retain(|_, expires_at| *expires_at >= now);
+
+        for (user_id, at) in valid_after {
+            let at = at.timestamp();
+            let entry = revocations.valid_after.entry(user_id.to_string()).or_insert(at);
+            *entry = (*entry).max(at);
+        }
+
+        for (user_id, device_id, at) in device_valid_after {
+            let at = at.timestamp();
+            let entry = revocations.device_valid_after.entry((user_id.to_string(), device_id)).or_insert(at);
+            *entry = (*entry).max(at);
         }
+    }
 
-        Ok(token_data.claims)
+    /// A new opaque refresh token and the hash to store for it
+    pub fn generate_refresh_token(&self) -> (String, String) {
+        let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let token_hash = Self::hash_token(&token);
+        (token, token_hash)
+    }
+
+    /// A new personal access token and the hash to store for it
+    pub fn generate_api_token(&self) -> (String, String) {
+        let token = format!("{}{}{}", API_TOKEN_PREFIX, Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let token_hash = Self::hash_token(&token);
+        (token, token_hash)
+    }
+
+    /// A new app password, in groups of four so it can be typed on a phone,
+    /// and its bcrypt hash
+    pub fn generate_app_password(&self, id: Uuid) -> Result<(String, String)> {
+        let mut bytes = [0u8; 24];
+        OsRng.fill_bytes(&mut bytes);
+
+        // 256 is a multiple of the alphabet's 32 characters, so none is favoured
+        let groups = bytes
+            .chunks(4)
+            .map(|group| group.iter().map(|byte| APP_PASSWORD_ALPHABET[(*byte % 32) as usize] as char).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("-");
+        // The id lets a login find the one hash to check
+        let password = format!("{}-{}", &id.simple().to_string()[..APP_PASSWORD_ID_LEN], groups);
+        let password_hash = hash(&password, self.bcrypt_cost)?;
+        Ok((password, password_hash))
+    }
+
+    /// Refresh and access tokens are random, so a plain SHA-256 is enough to
+    /// keep the stored form useless to whoever reads the database
+    pub fn hash_token(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
     }
 
     pub fn extract_user_id(&self, token: &str) -> Result<Uuid> {
@@ -75,37 +740,331 @@ impl AuthService {
 }
 
 // Middleware for token validation
+use std::net::SocketAddr;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use axum::{
-    extract::{Request, State},
-    http::{header::AUTHORIZATION, StatusCode},
+    extract::{ConnectInfo, Request, State},
+    http::{header::{AUTHORIZATION, COOKIE}, Method, StatusCode},
     middleware::Next,
     response::Response,
 };
+use crate::audit;
+use crate::handlers::{ApiError, missing_permission};
+use crate::rate_limit::LoginLimiter;
+use crate::types::{AuditEntry, AuditEvent, LoginAttempt};
+
+/// What `auth_middleware` needs: JWTs are checked in memory, personal
+/// access tokens against the database
+#[derive(Clone)]
+pub struct AuthState {
+    pub auth_service: AuthService,
+    pub database: Database,
+    pub activity: ActivityThrottle,
+    /// App passwords not matched lately are checked like password logins
+    pub login_limiter: LoginLimiter,
+    /// Set when anonymous reads are on
+    pub anonymous: Option<AnonymousAccess>,
+}
+
+// A device's last-seen or a session's last-active time is written at most
+// this often, as is an audit entry for failed authentication from one address
+const ACTIVITY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum ActivityKey {
+    /// User and device ID
+    Device(String, String),
+    /// jti of the session's current access token
+    Session(String),
+    /// Address a request that failed authentication came from
+    AuthFailure(String),
+}
+
+/// Throttles last-seen and last-active updates and the auditing of failed
+/// authentication, so a syncing device doesn't turn every request into
+/// database writes
+#[derive(Clone, Default)]
+pub struct ActivityThrottle {
+    last_written: Arc<std::sync::Mutex<HashMap<ActivityKey, std::time::Instant>>>,
+}
+
+impl ActivityThrottle {
+    /// Whether this request should update the device's last-seen time
+    pub fn should_record_device(&self, user_id: &str, device_id: &str) -> bool {
+        self.should_record(ActivityKey::Device(user_id.to_string(), device_id.to_string()))
+    }
+
+    /// Whether this request should update its session's last-active time
+    pub fn should_record_session(&self, jti: &str) -> bool {
+        self.should_record(ActivityKey::Session(jti.to_string()))
+    }
+
+    /// Whether a failed authentication from this address should go to the
+    /// audit log
+    pub fn should_record_auth_failure(&self, ip: &str) -> bool {
+        self.should_record(ActivityKey::AuthFailure(ip.to_string()))
+    }
+
+    fn should_record(&self, key: ActivityKey) -> bool {
+        let now = std::time::Instant::now();
+        let mut last_written = self.last_written.lock().unwrap();
+
+        match last_written.get(&key) {
+            Some(at) if now.duration_since(*at) < ACTIVITY_INTERVAL => false,
+            _ => {
+                if last_written.len() > 10_000 {
+                    last_written.retain(|_, at| now.duration_since(*at) < ACTIVITY_INTERVAL);
+                }
+                last_written.insert(key, now);
+                true
+            }
+        }
+    }
+}
 
 pub async fn auth_middleware(
-    State(auth_service): State<AuthService>,
-    mut request: Request,
+    State(state): State<AuthState>,
+    request: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    let auth_header = request
+    let (ip, user_agent) = audit::request_origin(&request);
+    let target = audit::request_target(&request);
+
+    let (claims, request) = match authenticate(&state, request).await {
+        Ok(authenticated) => authenticated,
+        Err(status) => {
+            // Sampled per address, so a client stuck on a bad token or a
+            // scan of the API doesn't flood the log
+            let address = ip.clone().unwrap_or_default();
+            if status == StatusCode::UNAUTHORIZED && state.activity.should_record_auth_failure(&address) {
+                let entry = AuditEntry::new(AuditEvent::AuthFailure, "failure")
+                    .with_origin(ip, user_agent)
+                    .with_target(target);
+                audit::record_in_background(&state.database, entry);
+            }
+            return Err(status);
+        }
+    };
+
+    // Changes the request makes to the sync feed are attributed to its caller
+    let response = match Uuid::parse_str(&claims.sub) {
+        Ok(user_id) => {
+            let actor = ChangeActor { user_id, device_id: claims.device_id.clone() };
+            actor.scope(next.run(request)).await
+        }
+        Err(_) => next.run(request).await,
+    };
+
+    // Whichever check turned it away, the permission layers, scopes or the
+    // handler itself. Everything an admin does as someone else is recorded,
+    // reads included.
+    let event = if response.status() == StatusCode::FORBIDDEN {
+        Some(AuditEvent::PermissionDenied)
+    } else if claims.is_impersonation() {
+        Some(AuditEvent::Impersonation)
+    } else {
+        None
+    };
+    if let Some(event) = event {
+        let entry = AuditEntry::new(event, audit::outcome_of(response.status()))
+            .with_origin(ip, user_agent)
+            .with_target(target);
+        audit::record_in_background(&state.database, audit::with_caller(entry, &claims));
+    }
+
+    Ok(response)
+}
+
+/// Claims for HTTP Basic auth. An app password matched lately is let
+/// through as it is; any other costs a bcrypt check, so like a password
+/// login it is rate limited, subject to the username's lockout and recorded.
+async fn basic_auth_claims(state: &AuthState, ip: &str, username: &str, password: &str) -> Result<Claims, StatusCode> {
+    let remembered = state.auth_service.remembered_app_password(&state.database, username, password).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(claims) = remembered {
+        return Ok(claims);
+    }
+
+    let limiter = &state.login_limiter;
+    if limiter.check(ip, Some(username)).is_err() {
+        record_app_password_attempt(&state.database, username, ip, "rate_limited").await;
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+    let (failures, last_failure) = state.database.get_consecutive_login_failures(username, limiter.failures_since()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if limiter.locked_until(failures, last_failure).is_some() {
+        record_app_password_attempt(&state.database, username, ip, "locked").await;
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    match state.auth_service.verify_app_password(&state.database, username, password).await {
+        Ok(claims) => {
+            record_app_password_attempt(&state.database, username, ip, "success").await;
+            Ok(claims)
+        }
+        Err(_) => {
+            record_app_password_attempt(&state.database, username, ip, "failure").await;
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+/// Keep an app password attempt for the username's lockout. A failure to
+/// record one must not decide the request.
+async fn record_app_password_attempt(database: &Database, username: &str, ip: &str, outcome: &str) {
+    let attempt = LoginAttempt {
+        id: Uuid::new_v4(),
+        username: username.to_string(),
+        ip_address: ip.to_string(),
+        outcome: outcome.to_string(),
+        attempted_at: Utc::now(),
+    };
+    if let Err(e) = database.record_login_attempt(&attempt).await {
+        tracing::warn!("Failed to record app password attempt for {}: {}", username, e);
+    }
+}
+
+/// Find who is calling and put their claims in the request. Returns the
+/// claims too, for auditing what happens to the request.
+async fn authenticate(state: &AuthState, mut request: Request) -> Result<(Claims, Request), StatusCode> {
+    let authorization = request
         .headers()
         .get(AUTHORIZATION)
-        .and_then(|header| header.to_str().ok())
-        .and_then(|header| header.strip_prefix("Bearer "));
+        .and_then(|header| header.to_str().ok());
 
-    let token = match auth_header {
-        Some(token) => token,
-        None => return Err(StatusCode::UNAUTHORIZED),
+    // Clients that only speak HTTP Basic auth log in with an app password
+    let basic = authorization
+        .and_then(|header| header.strip_prefix("Basic "))
+        .and_then(basic_credentials);
+
+    let claims = match basic {
+        Some((username, password)) => {
+            let ip = audit::request_origin(&request).0.unwrap_or_default();
+            Ok(basic_auth_claims(state, &ip, &username, &password).await?)
+        }
+        None => {
+            let bearer = authorization.and_then(|header| header.strip_prefix("Bearer "));
+            let token = match bearer.or_else(|| token_cookie(&request)) {
+                Some(token) => token.to_string(),
+                None => match &state.anonymous {
+                    // Only requests without any credentials browse anonymously;
+                    // a bad token is still a 401
+                    Some(access) if request.method() == Method::GET => {
+                        request.extensions_mut().insert(Claims::anonymous());
+                        request.extensions_mut().insert(access.clone());
+                        return Ok((Claims::anonymous(), request));
+                    }
+                    _ => return Err(StatusCode::UNAUTHORIZED),
+                },
+            };
+
+            if token.starts_with(API_TOKEN_PREFIX) {
+                state.auth_service.verify_api_token(&state.database, &token).await
+            } else {
+                state.auth_service.verify_token(&token)
+            }
+        }
     };
+    let claims = claims.map_err(|_| StatusCode::UNAUTHORIZED)?;
 
-    match auth_service.verify_token(token) {
-        Ok(claims) => {
-            // Add user info to request extensions
-            request.extensions_mut().insert(claims);
-            Ok(next.run(request).await)
+    // Deleted and deactivated users, and tokens from before a password
+    // change or deactivation, are turned away
+    let current = state.auth_service.is_user_current(&state.database, &claims).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !current {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    if let Some(device_id) = &claims.device_id {
+        if state.activity.should_record_device(&claims.sub, device_id) {
+            record_device_seen(&state.database, &claims, device_id, &request);
         }
-        Err(_) => Err(StatusCode::UNAUTHORIZED),
     }
+    // Personal access tokens have no jti and no session
+    if !claims.jti.is_empty() && state.activity.should_record_session(&claims.jti) {
+        record_session_active(&state.database, &claims.jti);
+    }
+
+    // Add user info to request extensions
+    request.extensions_mut().insert(claims.clone());
+    Ok((claims, request))
+}
+
+/// Username and password of an HTTP Basic `Authorization` header
+fn basic_credentials(encoded: &str) -> Option<(String, String)> {
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// The access token a browser holds in its cookie
+fn token_cookie(request: &Request) -> Option<&str> {
+    request.headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(TOKEN_COOKIE)?.strip_prefix('='))
+}
+
+/// Per-route check that the caller holds a permission, layered inside
+/// `auth_middleware` with `middleware::from_fn_with_state(permission, require_permission)`.
+/// The 403 names what is missing, so clients can explain it.
+pub async fn require_permission(
+    State(permission): State<Permission>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let allowed = request.extensions()
+        .get::<Claims>()
+        .is_some_and(|claims| claims.has(permission));
+
+    if !allowed {
+        return Err(missing_permission(permission));
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Keeps anonymous visitors out of routes that aren't limited to the
+/// anonymous root, layered inside `auth_middleware`
+pub async fn reject_anonymous(request: Request, next: Next) -> Result<Response, StatusCode> {
+    if request.extensions().get::<Claims>().is_some_and(Claims::is_anonymous) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Update a session's last-active time without holding up the request
+fn record_session_active(database: &Database, jti: &str) {
+    let database = database.clone();
+    let jti = jti.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = database.touch_session(&jti, Utc::now()).await {
+            tracing::warn!("Failed to update last activity of session: {}", e);
+        }
+    });
+}
+
+/// Update a device's last-seen time without holding up the request
+fn record_device_seen(database: &Database, claims: &Claims, device_id: &str, request: &Request) {
+    let user_id = match Uuid::parse_str(&claims.sub) {
+        Ok(user_id) => user_id,
+        Err(_) => return,
+    };
+    let ip = request.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+
+    let database = database.clone();
+    let device_id = device_id.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = database.touch_device(user_id, &device_id, ip.as_deref(), Utc::now()).await {
+            tracing::warn!("Failed to update last seen of device {}: {}", device_id, e);
+        }
+    });
 }
 
 #[cfg(test)]
@@ -113,6 +1072,7 @@ mod tests {
     use super::*;
     use uuid::Uuid;
     use chrono::Utc;
+    use crate::types::ApiToken;
 
     #[test]
     fn test_password_hashing() {
@@ -124,6 +1084,35 @@ mod tests {
         assert!(!auth_service.verify_password("wrong_password", &hash).unwrap());
     }
 
+    #[test]
+    fn test_passwords_verify_across_schemes() {
+        let bcrypt = AuthService::new("test_secret").with_bcrypt_cost(4);
+        let argon2 = AuthService::new("test_secret")
+            .with_password_scheme(PasswordScheme::Argon2id)
+            .with_argon2_params(Params::new(1024, 1, 1, None).unwrap());
+
+        let bcrypt_hash = bcrypt.hash_password("test_password").unwrap();
+        let argon2_hash = argon2.hash_password("test_password").unwrap();
+        assert!(bcrypt_hash.starts_with("$2b$04$"));
+        assert!(argon2_hash.starts_with("$argon2id$"));
+
+        for service in [&bcrypt, &argon2] {
+            for hash in [&bcrypt_hash, &argon2_hash] {
+                assert!(service.verify_password("test_password", hash).unwrap());
+                assert!(!service.verify_password("wrong_password", hash).unwrap());
+            }
+        }
+
+        // Hashes of the other scheme, or with other parameters, are due for replacing
+        assert!(argon2.needs_rehash(&bcrypt_hash));
+        assert!(!argon2.needs_rehash(&argon2_hash));
+        assert!(bcrypt.needs_rehash(&argon2_hash));
+        assert!(!bcrypt.needs_rehash(&bcrypt_hash));
+        assert!(bcrypt.clone().with_bcrypt_cost(5).needs_rehash(&bcrypt_hash));
+        let stronger = argon2.clone().with_argon2_params(Params::new(2048, 1, 1, None).unwrap());
+        assert!(stronger.needs_rehash(&argon2_hash));
+    }
+
     #[test]
     fn test_token_generation_and_verification() {
         let auth_service = AuthService::new("test_secret");
@@ -135,13 +1124,548 @@ mod tests {
             created_at: Utc::now(),
             last_login: None,
             is_active: true,
+            auth_generation: 0,
             permissions: vec!["read".to_string(), "write".to_string()],
         };
 
-        let token = auth_service.generate_token(&user, Some("device123".to_string())).unwrap();
+        let (token, _) = auth_service.generate_token(&user, Some("device123".to_string())).unwrap();
         let claims = auth_service.verify_token(&token).unwrap();
         
         assert_eq!(claims.username, user.username);
         assert_eq!(claims.device_id, Some("device123".to_string()));
     }
+
+    fn token_user() -> User {
+        User {
+            id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_tokens_are_checked_for_issuer_audience_and_algorithm() {
+        let auth_service = AuthService::new("test_secret").with_issuer("synker", "nas");
+        let (token, _) = auth_service.generate_token(&token_user(), None).unwrap();
+        assert!(auth_service.verify_token(&token).is_ok());
+
+        // Same secret, but meant for someone else
+        let other_audience = AuthService::new("test_secret").with_issuer("synker", "elsewhere");
+        assert!(other_audience.verify_token(&token).is_err());
+        let other_issuer = AuthService::new("test_secret").with_issuer("other", "nas");
+        assert!(other_issuer.verify_token(&token).is_err());
+
+        // Valid claims, signed with another algorithm or not at all
+        let claims = SignedClaims {
+            claims: auth_service.verify_token(&token).unwrap(),
+            iss: "synker".to_string(),
+            aud: "nas".to_string(),
+        };
+        let hs512 = encode(&Header::new(Algorithm::HS512), &claims, &EncodingKey::from_secret(b"test_secret")).unwrap();
+        assert!(auth_service.verify_token(&hs512).is_err());
+
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+        let unsigned = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"none","typ":"JWT"}"#),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap()),
+        );
+        assert!(auth_service.verify_token(&unsigned).is_err());
+    }
+
+    #[test]
+    fn test_token_expiry_allows_for_clock_skew() {
+        // Expired half a minute ago
+        let issuer = AuthService::new("test_secret")
+            .with_token_expiry(Duration::seconds(-30), Duration::days(1));
+        let (token, _) = issuer.generate_token(&token_user(), None).unwrap();
+
+        assert!(AuthService::new("test_secret").with_leeway(60).verify_token(&token).is_ok());
+        assert!(AuthService::new("test_secret").with_leeway(0).verify_token(&token).is_err());
+    }
+
+    #[test]
+    fn test_previous_secrets_keep_tokens_valid_across_rotation() {
+        let before = AuthService::new("old_secret");
+        let after = AuthService::new("new_secret").with_previous_secrets(&["old_secret".to_string()]);
+        let (old_token, _) = before.generate_token(&token_user(), None).unwrap();
+        let (new_token, _) = after.generate_token(&token_user(), None).unwrap();
+
+        assert!(after.verify_token(&old_token).is_ok());
+        assert!(after.verify_token(&new_token).is_ok());
+        // New tokens are signed with the new secret only
+        assert!(before.verify_token(&new_token).is_err());
+        // Dropping the old secret ends its tokens
+        assert!(AuthService::new("new_secret").verify_token(&old_token).is_err());
+    }
+
+    #[test]
+    fn test_impersonation_tokens_are_short_lived_and_never_admin() {
+        let auth_service = AuthService::new("test_secret")
+            .with_token_expiry(Duration::hours(8), Duration::days(30));
+        let mut user = token_user();
+        user.permissions = vec!["read".to_string(), "admin".to_string()];
+        let impersonator = Impersonator { id: Uuid::new_v4(), username: "admin".to_string() };
+
+        let (token, _) = auth_service.issue_impersonation_token(&user, impersonator.clone()).unwrap();
+        let claims = auth_service.verify_token(&token).unwrap();
+        assert_eq!(claims.sub, user.id.to_string());
+        assert_eq!(claims.impersonator, Some(impersonator));
+        assert!(claims.exp - claims.iat <= 15 * 60);
+        assert_eq!(claims.permissions, vec![Permission::Read]);
+
+        // Shorter access tokens stay shorter
+        let brief = AuthService::new("test_secret").with_token_expiry(Duration::minutes(5), Duration::days(30));
+        let (_, claims) = brief.issue_impersonation_token(&user, claims.impersonator.unwrap()).unwrap();
+        assert_eq!(claims.exp - claims.iat, 5 * 60);
+
+        // Ordinary tokens carry no impersonator
+        let (token, _) = auth_service.generate_token(&user, None).unwrap();
+        assert!(!auth_service.verify_token(&token).unwrap().is_impersonation());
+    }
+
+    #[test]
+    fn test_refresh_tokens_are_unique_and_hashed() {
+        let auth_service = AuthService::new("test_secret");
+
+        let (token, token_hash) = auth_service.generate_refresh_token();
+        let (other, _) = auth_service.generate_refresh_token();
+
+        assert_ne!(token, other);
+        assert_ne!(token, token_hash);
+        assert_eq!(AuthService::hash_token(&token), token_hash);
+    }
+
+    async fn test_state(dir: &std::path::Path) -> (AuthState, User) {
+        let url = format!("sqlite://{}?mode=rwc", dir.join("test.db").display());
+        let database = Database::new(&url).await.unwrap();
+
+        let user = User {
+            id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string(), "write".to_string()],
+        };
+        database.create_user(&user).await.unwrap();
+
+        let mut settings = crate::config::ServerConfig::default().auth;
+        settings.login_attempts_per_minute_per_user = 100;
+        let state = AuthState {
+            auth_service: AuthService::new("test_secret"),
+            database,
+            activity: ActivityThrottle::default(),
+            login_limiter: LoginLimiter::new(&settings),
+            anonymous: None,
+        };
+        (state, user)
+    }
+
+    #[tokio::test]
+    async fn test_revoked_tokens_are_rejected_by_middleware() {
+        use axum::{body::Body, middleware, routing::get, Router};
+        use tower::ServiceExt;
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let (state, user) = test_state(db_dir.path()).await;
+        let auth_service = state.auth_service.clone();
+        let router = Router::new()
+            .route("/protected", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(state, auth_middleware));
+
+        let status = |token: String| {
+            let router = router.clone();
+            async move {
+                let request = axum::http::Request::builder()
+                    .uri("/protected")
+                    .header(AUTHORIZATION, format!("Bearer {}", token))
+                    .body(Body::empty())
+                    .unwrap();
+                router.oneshot(request).await.unwrap().status()
+            }
+        };
+
+        let (token, _) = auth_service.generate_token(&user, None).unwrap();
+        let (other, _) = auth_service.generate_token(&user, None).unwrap();
+        assert_eq!(status(token.clone()).await, StatusCode::OK);
+        assert_eq!(status(String::new()).await, StatusCode::UNAUTHORIZED);
+
+        // Logging out revokes only that token
+        let claims = auth_service.verify_token(&token).unwrap();
+        auth_service.revoke_token(&claims.jti, claims.exp);
+        assert_eq!(status(token).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(other.clone()).await, StatusCode::OK);
+
+        // Revoking the user's tokens takes the rest
+        auth_service.revoke_user_tokens(user.id, Utc::now());
+        assert_eq!(status(other.clone()).await, StatusCode::UNAUTHORIZED);
+
+        // A reload from the database keeps what is revoked in memory
+        auth_service.load_revocations(Vec::new(), Vec::new(), Vec::new());
+        assert_eq!(status(other).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_browsers_can_send_the_token_as_a_cookie() {
+        use axum::{body::Body, middleware, routing::get, Router};
+        use tower::ServiceExt;
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let (state, user) = test_state(db_dir.path()).await;
+        let (token, _) = state.auth_service.generate_token(&user, None).unwrap();
+        let router = Router::new()
+            .route("/protected", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(state, auth_middleware));
+
+        let status = |cookie: String| {
+            let router = router.clone();
+            async move {
+                let request = axum::http::Request::builder()
+                    .uri("/protected")
+                    .header(COOKIE, cookie)
+                    .body(Body::empty())
+                    .unwrap();
+                router.oneshot(request).await.unwrap().status()
+            }
+        };
+
+        assert_eq!(status(format!("theme=dark; {}={}", TOKEN_COOKIE, token)).await, StatusCode::OK);
+        assert_eq!(status(format!("{}x={}", TOKEN_COOKIE, token)).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(format!("{}=forged", TOKEN_COOKIE)).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_tokens_of_deactivated_users_are_rejected() {
+        use axum::{body::Body, middleware, routing::get, Router};
+        use crate::types::UpdateUserRequest;
+        use tower::ServiceExt;
+
+        let ttl = std::time::Duration::from_millis(200);
+        let db_dir = tempfile::tempdir().unwrap();
+        let (mut state, user) = test_state(db_dir.path()).await;
+        state.auth_service = state.auth_service.with_user_status_ttl(ttl);
+        let database = state.database.clone();
+        let auth_service = state.auth_service.clone();
+        let router = Router::new()
+            .route("/protected", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(state, auth_middleware));
+
+        let status = |token: String| {
+            let router = router.clone();
+            async move {
+                let request = axum::http::Request::builder()
+                    .uri("/protected")
+                    .header(AUTHORIZATION, format!("Bearer {}", token))
+                    .body(Body::empty())
+                    .unwrap();
+                router.oneshot(request).await.unwrap().status()
+            }
+        };
+        let fresh_token = || {
+            let database = database.clone();
+            let auth_service = auth_service.clone();
+            async move {
+                let user = database.get_user_by_id(user.id).await.unwrap().unwrap();
+                auth_service.generate_token(&user, None).unwrap().0
+            }
+        };
+
+        let (token, _) = auth_service.generate_token(&user, None).unwrap();
+        assert_eq!(status(token.clone()).await, StatusCode::OK);
+
+        // A password changed elsewhere moves the user to a new generation,
+        // which is seen once the cached status runs out
+        database.update_password_hash(user.id, "new-hash", false, Utc::now()).await.unwrap();
+        tokio::time::sleep(ttl).await;
+        assert_eq!(status(token).await, StatusCode::UNAUTHORIZED);
+        let token = fresh_token().await;
+        assert_eq!(status(token.clone()).await, StatusCode::OK);
+
+        // Deactivating refuses every token, and those issued before stay
+        // refused once the user is active again
+        let deactivate = UpdateUserRequest { is_active: Some(false), permissions: None, quota_bytes: None };
+        database.update_user(user.id, &deactivate).await.unwrap();
+        tokio::time::sleep(ttl).await;
+        assert_eq!(status(token.clone()).await, StatusCode::UNAUTHORIZED);
+
+        let activate = UpdateUserRequest { is_active: Some(true), permissions: None, quota_bytes: None };
+        database.update_user(user.id, &activate).await.unwrap();
+        tokio::time::sleep(ttl).await;
+        assert_eq!(status(token).await, StatusCode::UNAUTHORIZED);
+        let token = fresh_token().await;
+        assert_eq!(status(token.clone()).await, StatusCode::OK);
+
+        // So are those of users who were deleted
+        assert!(database.delete_user(user.id, Uuid::new_v4(), None).await.unwrap().is_some());
+        tokio::time::sleep(ttl).await;
+        assert_eq!(status(token).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_token_expiry_and_cost_come_from_settings() {
+        let settings = AuthSettings {
+            jwt_secret: "test_secret".to_string(),
+            token_expiry_hours: 1,
+            refresh_token_expiry_days: 7,
+            jwt_previous_secrets: Vec::new(),
+            jwt_issuer: "synker".to_string(),
+            jwt_audience: "synker".to_string(),
+            jwt_leeway_seconds: 60,
+            share_secret: "share_secret".to_string(),
+            share_previous_secrets: Vec::new(),
+            accept_legacy_share_tokens: true,
+            share_code_length: 10,
+            share_cleanup_interval_minutes: 60,
+            password_scheme: PasswordScheme::Bcrypt,
+            bcrypt_cost: 8,
+            argon2_memory_kib: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+            password_policy: PasswordPolicy { min_length: 10, ..PasswordPolicy::default() },
+            login_attempts_per_minute_per_ip: 20,
+            login_attempts_per_minute_per_user: 5,
+            lockout_threshold: 5,
+            lockout_base_seconds: 30,
+            lockout_max_seconds: 900,
+            lockout_window_seconds: 3600,
+            audit_retention_days: 90,
+            share_access_log_ips: true,
+            allow_anonymous_read: false,
+            anonymous_root: None,
+            oidc: None,
+        };
+        let auth_service = AuthService::from_settings(&settings);
+        let user = User {
+            id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string()],
+        };
+
+        let (token, expires_at) = auth_service.generate_token(&user, None).unwrap();
+        let claims = auth_service.verify_token(&token).unwrap();
+        assert_eq!(claims.exp, expires_at.timestamp());
+        assert!((claims.exp - Utc::now().timestamp() - 3600).abs() <= 5);
+        assert_eq!(auth_service.refresh_token_ttl(), Duration::days(7));
+
+        let hash = auth_service.hash_password("password").unwrap();
+        assert!(hash.starts_with("$2b$08$"));
+
+        assert!(auth_service.validate_password("too-short").is_err());
+        assert!(auth_service.validate_password("long-enough").is_ok());
+    }
+
+    #[test]
+    fn test_password_policy_reports_every_broken_rule() {
+        let policy = PasswordPolicy {
+            enabled: true,
+            min_length: 10,
+            max_length: Some(16),
+            require_mixed_classes: true,
+            deny_list: vec!["Password".to_string()],
+        };
+        let auth_service = AuthService::new("test_secret").with_password_policy(policy.clone());
+
+        assert_eq!(
+            auth_service.validate_password("short"),
+            Err(vec![
+                PolicyViolation::TooShort { min_length: 10 },
+                PolicyViolation::MissingCharacterClasses { required: 3, found: 1 },
+            ])
+        );
+        assert_eq!(
+            auth_service.validate_password("Much-Too-Long-Passw0rd"),
+            Err(vec![PolicyViolation::TooLong { max_length: 16 }])
+        );
+        // The maximum counts bytes, as that is what bcrypt truncates
+        assert_eq!(
+            auth_service.validate_password("Ünïcödé-Pässwörd"),
+            Err(vec![PolicyViolation::TooLong { max_length: 16 }])
+        );
+        assert_eq!(
+            auth_service.validate_password("alllowercase"),
+            Err(vec![PolicyViolation::MissingCharacterClasses { required: 3, found: 1 }])
+        );
+        assert_eq!(
+            AuthService::new("test_secret")
+                .with_password_policy(PasswordPolicy { min_length: 8, ..policy.clone() })
+                .validate_password("PASSWORD"),
+            Err(vec![
+                PolicyViolation::MissingCharacterClasses { required: 3, found: 1 },
+                PolicyViolation::Common,
+            ])
+        );
+        assert_eq!(auth_service.validate_password("Sturdy-Pass-42"), Ok(()));
+
+        // Any one rule can be relaxed on its own
+        let relaxed = AuthService::new("test_secret").with_password_policy(PasswordPolicy {
+            max_length: None,
+            require_mixed_classes: false,
+            deny_list: Vec::new(),
+            ..policy.clone()
+        });
+        assert_eq!(relaxed.validate_password("password-without-any-upper-limit"), Ok(()));
+        assert!(relaxed.validate_password("short").is_err());
+
+        // A disabled policy accepts anything
+        let disabled = AuthService::new("test_secret")
+            .with_password_policy(PasswordPolicy { enabled: false, ..policy });
+        assert_eq!(disabled.validate_password(""), Ok(()));
+        assert_eq!(disabled.validate_password("password"), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_api_tokens_resolve_to_scoped_claims() {
+        use axum::{body::Body, middleware, routing::get, Extension, Router};
+        use tower::ServiceExt;
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let (state, user) = test_state(db_dir.path()).await;
+        let database = state.database.clone();
+        let auth_service = state.auth_service.clone();
+        let router = Router::new()
+            .route("/protected", get(|Extension(claims): Extension<Claims>| async move {
+                format!("{}:{}", claims.has(Permission::Read), claims.has(Permission::Write))
+            }))
+            .layer(middleware::from_fn_with_state(state, auth_middleware));
+
+        let call = |token: String| {
+            let router = router.clone();
+            async move {
+                let request = axum::http::Request::builder()
+                    .uri("/protected")
+                    .header(AUTHORIZATION, format!("Bearer {}", token))
+                    .body(Body::empty())
+                    .unwrap();
+                let response = router.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        let (secret, token_hash) = auth_service.generate_api_token();
+        let api_token = ApiToken {
+            id: Uuid::new_v4(),
+            user_id: user.id,
+            name: "backups".to_string(),
+            token_hash,
+            scope: TokenScope::ReadOnly,
+            path_scopes: Vec::new(),
+            created_at: Utc::now(),
+            expires_at: None,
+            last_used_at: None,
+        };
+        database.create_api_token(&api_token).await.unwrap();
+
+        assert_eq!(call(secret.clone()).await, (StatusCode::OK, "true:false".to_string()));
+        let listed = database.list_api_tokens(user.id).await.unwrap();
+        assert!(listed[0].last_used_at.is_some());
+
+        let (unknown, _) = auth_service.generate_api_token();
+        assert_eq!(call(unknown).await.0, StatusCode::UNAUTHORIZED);
+
+        assert!(database.delete_api_token(user.id, api_token.id).await.unwrap());
+        assert_eq!(call(secret).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_app_passwords_log_in_with_basic_auth() {
+        use axum::{body::Body, middleware, routing::get, Extension, Router};
+        use crate::types::AppPassword;
+        use tower::ServiceExt;
+
+        let db_dir = tempfile::tempdir().unwrap();
+        let (mut state, user) = test_state(db_dir.path()).await;
+        state.auth_service = state.auth_service.with_bcrypt_cost(4);
+        let database = state.database.clone();
+        let auth_service = state.auth_service.clone();
+        let router = Router::new()
+            .route("/protected", get(|Extension(claims): Extension<Claims>| async move {
+                format!("{}:{}:{}", claims.username, claims.has(Permission::Write), claims.scopes.len())
+            }))
+            .layer(middleware::from_fn_with_state(state, auth_middleware));
+
+        let call = |username: &str, password: &str| {
+            let router = router.clone();
+            let credentials = STANDARD.encode(format!("{}:{}", username, password));
+            async move {
+                let request = axum::http::Request::builder()
+                    .uri("/protected")
+                    .header(AUTHORIZATION, format!("Basic {}", credentials))
+                    .body(Body::empty())
+                    .unwrap();
+                let response = router.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+        let create = |path_prefix: Option<&str>, expires_at: Option<DateTime<Utc>>| {
+            let id = Uuid::new_v4();
+            let (password, password_hash) = auth_service.generate_app_password(id).unwrap();
+            let app_password = AppPassword {
+                id,
+                user_id: user.id,
+                label: "phone".to_string(),
+                password_hash,
+                path_prefix: path_prefix.map(str::to_string),
+                created_at: Utc::now(),
+                expires_at,
+                last_used_at: None,
+            };
+            (app_password, password)
+        };
+
+        let (phone, phone_password) = create(None, None);
+        database.create_app_password(&phone).await.unwrap();
+        let (camera, camera_password) = create(Some("/photos"), None);
+        database.create_app_password(&camera).await.unwrap();
+        assert!(phone_password.len() >= 20);
+        assert_ne!(phone_password, camera_password);
+
+        // Any of the user's app passwords works, the first time and once remembered
+        for _ in 0..2 {
+            assert_eq!(call("testuser", &phone_password).await, (StatusCode::OK, "testuser:true:0".to_string()));
+            assert_eq!(call("testuser", &camera_password).await, (StatusCode::OK, "testuser:true:3".to_string()));
+        }
+        assert!(database.list_app_passwords(user.id, false).await.unwrap()[0].last_used_at.is_some());
+
+        // Not with another name or password, nor the account password
+        assert_eq!(call("someone", &phone_password).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call("testuser", "wrong").await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call("testuser", "").await.0, StatusCode::UNAUTHORIZED);
+
+        // Expired ones are turned away
+        let (old, old_password) = create(None, Some(Utc::now() - Duration::days(1)));
+        database.create_app_password(&old).await.unwrap();
+        assert_eq!(call("testuser", &old_password).await.0, StatusCode::UNAUTHORIZED);
+
+        // Revoking one takes effect at once, even though it was remembered
+        assert!(database.delete_app_password(user.id, phone.id).await.unwrap());
+        assert_eq!(call("testuser", &phone_password).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call("testuser", &camera_password).await.0, StatusCode::OK);
+
+        // Guesses lock the username like failed logins; remembered passwords still work
+        assert_eq!(call("testuser", "wrong").await.0, StatusCode::UNAUTHORIZED);
+        let (tablet, tablet_password) = create(None, None);
+        database.create_app_password(&tablet).await.unwrap();
+        assert_eq!(call("testuser", &tablet_password).await.0, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(call("testuser", &camera_password).await.0, StatusCode::OK);
+    }
 }
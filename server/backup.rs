@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use crate::config::BackupSettings;
+use crate::database::Database;
+use crate::types::BackupInfo;
+
+/// Backups are named `synker-<time>.db`, with `.gz` added when compressed,
+/// so they sort oldest first
+const FILE_PREFIX: &str = "synker-";
+
+/// Takes consistent snapshots of the database while the server runs, keeping
+/// them in the backup directory or handing them out for download
+#[derive(Clone)]
+pub struct Backups {
+    database: Database,
+    directory: Option<PathBuf>,
+    keep: usize,
+    compress: bool,
+    temp_directory: PathBuf,
+}
+
+impl Backups {
+    pub fn new(database: Database, settings: &BackupSettings) -> Self {
+        Self {
+            database,
+            directory: settings.directory.clone(),
+            keep: settings.keep.max(1),
+            compress: settings.compress,
+            temp_directory: std::env::temp_dir(),
+        }
+    }
+
+    /// Where snapshots for download are written before they are sent
+    pub fn with_temp_directory(mut self, temp_directory: impl AsRef<Path>) -> Self {
+        self.temp_directory = temp_directory.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn has_directory(&self) -> bool {
+        self.directory.is_some()
+    }
+
+    /// Whether backups are compressed unless asked otherwise
+    pub fn compresses(&self) -> bool {
+        self.compress
+    }
+
+    /// Back up into the backup directory, then delete the oldest backups
+    /// there past `keep`
+    pub async fn save(&self, compress: bool) -> Result<BackupInfo> {
+        let directory = self.directory.as_ref().context("No backup directory is configured")?;
+        tokio::fs::create_dir_all(directory).await?;
+
+        let name = file_name(Utc::now(), compress);
+        let info = write_backup(&self.database, &directory.join(&name), compress).await?;
+        tracing::info!("Backup {} written to {:?} ({} bytes, sha256 {})", info.file_name, directory, info.size, info.checksum);
+
+        if let Err(e) = self.rotate(directory).await {
+            tracing::error!("Failed to remove old backups from {:?}: {}", directory, e);
+        }
+        Ok(info)
+    }
+
+    /// Back up into the temp directory for sending. The file is removed
+    /// once the returned `Snapshot` is dropped.
+    pub async fn snapshot(&self, compress: bool) -> Result<(Snapshot, BackupInfo)> {
+        tokio::fs::create_dir_all(&self.temp_directory).await?;
+
+        let name = file_name(Utc::now(), compress);
+        let path = self.temp_directory.join(format!("backup-{}-{}", Uuid::new_v4(), name));
+        let mut info = write_backup(&self.database, &path, compress).await?;
+        info.file_name = name;
+        tracing::info!("Backup {} taken for download ({} bytes, sha256 {})", info.file_name, info.size, info.checksum);
+
+        Ok((Snapshot(path), info))
+    }
+
+    async fn rotate(&self, directory: &Path) -> Result<()> {
+        let mut names = Vec::new();
+        let mut entries = tokio::fs::read_dir(directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(FILE_PREFIX) && (name.ends_with(".db") || name.ends_with(".db.gz")) {
+                names.push(name);
+            }
+        }
+
+        names.sort();
+        let excess = names.len().saturating_sub(self.keep);
+        for name in &names[..excess] {
+            tokio::fs::remove_file(directory.join(name)).await?;
+            tracing::info!("Removed old backup {}", name);
+        }
+        Ok(())
+    }
+}
+
+/// A backup taken for download, removed when dropped, so a download that is
+/// cut off leaves nothing behind either
+pub struct Snapshot(PathBuf);
+
+impl Snapshot {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            tracing::warn!("Failed to remove backup snapshot {:?}: {}", self.0, e);
+        }
+    }
+}
+
+fn file_name(at: DateTime<Utc>, compress: bool) -> String {
+    format!("{}{}.db{}", FILE_PREFIX, at.format("%Y%m%d-%H%M%S-%3f"), if compress { ".gz" } else { "" })
+}
+
+/// Write a snapshot of the database to `target`, which must not exist yet,
+/// gzipped when `compress`
+pub async fn write_backup(database: &Database, target: &Path, compress: bool) -> Result<BackupInfo> {
+    if tokio::fs::try_exists(target).await? {
+        anyhow::bail!("{:?} already exists", target);
+    }
+
+    let created_at = Utc::now();
+    // SQLite writes the copy itself; compressing reads it back
+    let snapshot = if compress {
+        let mut partial = target.as_os_str().to_owned();
+        partial.push(".partial");
+        PathBuf::from(partial)
+    } else {
+        target.to_path_buf()
+    };
+    database.backup_into(&snapshot).await?;
+
+    let finished = {
+        let (snapshot, target) = (snapshot.clone(), target.to_path_buf());
+        tokio::task::spawn_blocking(move || finish(&snapshot, &target, compress)).await?
+    };
+    if compress {
+        let _ = tokio::fs::remove_file(&snapshot).await;
+    }
+    let (size, checksum) = match finished {
+        Ok(finished) => finished,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(target).await;
+            return Err(e);
+        }
+    };
+
+    Ok(BackupInfo {
+        file_name: target.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+        size,
+        checksum,
+        compressed: compress,
+        created_at,
+    })
+}
+
+/// Compress the snapshot into `target` when asked, then measure and hash
+/// what ended up there
+fn finish(snapshot: &Path, target: &Path, compress: bool) -> Result<(u64, String)> {
+    if compress {
+        let mut encoder = GzEncoder::new(BufWriter::new(File::create(target)?), Compression::default());
+        std::io::copy(&mut BufReader::new(File::open(snapshot)?), &mut encoder)?;
+        let file = encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+    }
+
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut BufReader::new(File::open(target)?), &mut hasher)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tempfile::tempdir;
+
+    async fn test_database(dir: &Path) -> Database {
+        let url = format!("sqlite://{}?mode=rwc", dir.join("test.db").display());
+        Database::new(&url).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_backups_are_whole_databases() {
+        let db_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let database = test_database(db_dir.path()).await;
+
+        let plain = backup_dir.path().join("plain.db");
+        let info = write_backup(&database, &plain, false).await.unwrap();
+        let content = std::fs::read(&plain).unwrap();
+        assert!(content.starts_with(b"SQLite format 3\0"));
+        assert_eq!(info.size, content.len() as u64);
+        assert_eq!(info.checksum, format!("{:x}", Sha256::digest(&content)));
+
+        // The copy opens as a database of its own
+        let copy = Database::new(&format!("sqlite://{}", plain.display())).await.unwrap();
+        assert_eq!(copy.count_users().await.unwrap(), 0);
+
+        let compressed = backup_dir.path().join("plain.db.gz");
+        let info = write_backup(&database, &compressed, true).await.unwrap();
+        assert!(info.compressed);
+        let mut unpacked = Vec::new();
+        GzDecoder::new(File::open(&compressed).unwrap()).read_to_end(&mut unpacked).unwrap();
+        assert!(unpacked.starts_with(b"SQLite format 3\0"));
+        assert!(!backup_dir.path().join("plain.db.gz.partial").exists());
+
+        // Nothing is overwritten
+        assert!(write_backup(&database, &plain, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_only_the_newest_backups_are_kept() {
+        let db_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let database = test_database(db_dir.path()).await;
+        std::fs::write(backup_dir.path().join("notes.txt"), b"not a backup").unwrap();
+
+        let settings = BackupSettings {
+            directory: Some(backup_dir.path().to_path_buf()),
+            interval_hours: 0,
+            keep: 2,
+            compress: true,
+        };
+        let backups = Backups::new(database, &settings);
+        let mut names = Vec::new();
+        for compress in [true, false, true] {
+            names.push(backups.save(compress).await.unwrap().file_name);
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        let mut left: Vec<String> = std::fs::read_dir(backup_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["notes.txt".to_string(), names[1].clone(), names[2].clone()]);
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use futures_util::{Stream, StreamExt};
+
+use crate::auth::Claims;
+use crate::config::ServerSettings;
+use crate::types::BandwidthLimits;
+
+const BYTES_PER_MEGABIT: f64 = 125_000.0;
+
+/// Slowest limit accepted, 10 kbit/s; anything lower is a typo for 0
+const MIN_MBPS: f64 = 0.01;
+
+/// Longest a single take waits. A huge chunk on a slow limit leaves the
+/// bucket in debt for longer, and the next take waits the rest.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// A token bucket holding at most one second of traffic. Takers may drive it
+/// into debt and then wait until that is paid off, so concurrent transfers
+/// queue up behind each other instead of each getting the full rate.
+#[derive(Debug)]
+struct Bucket {
+    mbps: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// None for 0, which means unlimited
+    fn new(mbps: f64) -> Option<Self> {
+        (mbps > 0.0).then(|| Self { mbps, tokens: mbps * BYTES_PER_MEGABIT, updated: Instant::now() })
+    }
+
+    /// Take `bytes` and return how long the taker has to wait for them
+    fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let rate = self.mbps * BYTES_PER_MEGABIT;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        self.updated = now;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::try_from_secs_f64(-self.tokens / rate).map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT))
+        }
+    }
+}
+
+struct Limits {
+    global: Option<Bucket>,
+    /// Users limited by their own bucket instead of the global one; None
+    /// leaves them unlimited
+    users: HashMap<String, Option<Bucket>>,
+}
+
+impl Limits {
+    fn new(max_mbps: f64, user_mbps: &HashMap<String, f64>) -> Self {
+        Self {
+            global: Bucket::new(max_mbps),
+            users: user_mbps.iter()
+                .map(|(username, mbps)| (username.to_lowercase(), Bucket::new(*mbps)))
+                .collect(),
+        }
+    }
+}
+
+/// An IPv4 or IPv6 network in CIDR notation; a bare address is a network of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn parse(network: &str) -> Option<Self> {
+        let (address, prefix) = match network.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (network.trim(), None),
+        };
+        let address: IpAddr = address.parse().ok()?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= bits)?,
+            None => bits,
+        };
+        Some(Self { address, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual-stack socket show up as ::ffff:a.b.c.d
+        let (network, ip, bits) = match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (u32::from(network) as u128, u32::from(ip) as u128, 32),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        self.prefix == 0 || (network ^ ip) >> (bits - self.prefix) == 0
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+/// Caps how fast downloads and uploads move. The buckets are shared by every
+/// connection, and admins can change the limits while the server runs;
+/// transfers already under way pick them up with their next chunk.
+#[derive(Clone)]
+pub struct Bandwidth {
+    limits: Arc<Mutex<Limits>>,
+    exempt: Arc<Vec<Network>>,
+}
+
+impl Bandwidth {
+    pub fn new(settings: &ServerSettings) -> anyhow::Result<Self> {
+        check_rates(settings.max_bandwidth_mbps, &settings.user_bandwidth_mbps)?;
+
+        let exempt = settings.bandwidth_exempt_networks.iter()
+            .map(|network| Network::parse(network)
+                .ok_or_else(|| anyhow::anyhow!("Invalid network '{}' in bandwidth_exempt_networks", network)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            limits: Arc::new(Mutex::new(Limits::new(settings.max_bandwidth_mbps, &settings.user_bandwidth_mbps))),
+            exempt: Arc::new(exempt),
+        })
+    }
+
+    pub fn limits(&self) -> BandwidthLimits {
+        let limits = self.limits.lock().unwrap();
+        BandwidthLimits {
+            max_mbps: limits.global.as_ref().map_or(0.0, |bucket| bucket.mbps),
+            user_mbps: limits.users.iter()
+                .map(|(username, bucket)| (username.clone(), bucket.as_ref().map_or(0.0, |bucket| bucket.mbps)))
+                .collect(),
+            exempt_networks: self.exempt.iter().map(Network::to_string).collect(),
+        }
+    }
+
+    /// Replace the global cap and the per-user overrides. They last until the
+    /// server restarts, which goes back to the configured ones.
+    pub fn set_limits(&self, max_mbps: f64, user_mbps: &HashMap<String, f64>) -> anyhow::Result<BandwidthLimits> {
+        check_rates(max_mbps, user_mbps)?;
+        *self.limits.lock().unwrap() = Limits::new(max_mbps, user_mbps);
+        Ok(self.limits())
+    }
+
+    /// The throttle for a transfer from `ip` by `username`; None for clients
+    /// on an exempt network
+    pub fn throttle(&self, ip: Option<IpAddr>, username: Option<&str>) -> Option<Throttle> {
+        if ip.is_some_and(|ip| self.exempt.iter().any(|network| network.contains(ip))) {
+            return None;
+        }
+        Some(Throttle {
+            bandwidth: self.clone(),
+            username: username.map(str::to_lowercase),
+        })
+    }
+
+    fn take(&self, username: Option<&str>, bytes: usize, now: Instant) -> Duration {
+        let mut limits = self.limits.lock().unwrap();
+        let Limits { global, users } = &mut *limits;
+        let bucket = match username.and_then(|username| users.get_mut(username)) {
+            Some(bucket) => bucket.as_mut(),
+            None => global.as_mut(),
+        };
+        bucket.map_or(Duration::ZERO, |bucket| bucket.take(bytes, now))
+    }
+}
+
+fn check_rates(max_mbps: f64, user_mbps: &HashMap<String, f64>) -> anyhow::Result<()> {
+    let valid = |mbps: f64| mbps == 0.0 || (mbps.is_finite() && mbps >= MIN_MBPS);
+    if !valid(max_mbps) || !user_mbps.values().all(|mbps| valid(*mbps)) {
+        return Err(anyhow::anyhow!("Bandwidth limits must be 0 (unlimited) or at least {} Mbit/s", MIN_MBPS));
+    }
+    Ok(())
+}
+
+/// One transfer's share of the limits
+#[derive(Clone)]
+pub struct Throttle {
+    bandwidth: Bandwidth,
+    username: Option<String>,
+}
+
+impl Throttle {
+    pub async fn take(&self, bytes: usize) {
+        let wait = self.bandwidth.take(self.username.as_deref(), bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Hold each chunk of `stream` back until the limits allow it through
+    pub fn wrap<S, E>(self, stream: S) -> impl Stream<Item = Result<Bytes, E>>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+    {
+        stream.then(move |chunk| {
+            let throttle = self.clone();
+            async move {
+                if let Ok(bytes) = &chunk {
+                    throttle.take(bytes.len()).await;
+                }
+                chunk
+            }
+        })
+    }
+}
+
+/// Throttles the request and response bodies of the routes it is layered
+/// on, by the caller's limit and the client's address
+pub async fn throttle_transfers(
+    State(bandwidth): State<Bandwidth>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = request.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let username = request.extensions()
+        .get::<Claims>()
+        .filter(|claims| !claims.is_anonymous())
+        .map(|claims| claims.username.clone());
+
+    let Some(throttle) = bandwidth.throttle(ip, username.as_deref()) else {
+        return next.run(request).await;
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = Body::from_stream(throttle.clone().wrap(body.into_data_stream()));
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    let (parts, body) = response.into_parts();
+    Response::from_parts(parts, Body::from_stream(throttle.wrap(body.into_data_stream())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(max_mbps: f64, user_mbps: &[(&str, f64)], exempt: &[&str]) -> ServerSettings {
+        ServerSettings {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            max_connections: 100,
+            request_timeout_seconds: 30,
+            max_request_size: 1024 * 1024,
+            max_bandwidth_mbps: max_mbps,
+            user_bandwidth_mbps: user_mbps.iter().map(|(name, mbps)| (name.to_string(), *mbps)).collect(),
+            bandwidth_exempt_networks: exempt.iter().map(|network| network.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_networks_match_by_prefix() {
+        let lan = Network::parse("192.168.1.0/24").unwrap();
+        assert!(lan.contains("192.168.1.77".parse().unwrap()));
+        assert!(lan.contains("::ffff:192.168.1.77".parse().unwrap()));
+        assert!(!lan.contains("192.168.2.1".parse().unwrap()));
+        assert!(!lan.contains("fd00::1".parse().unwrap()));
+
+        let ula = Network::parse("fd00::/8").unwrap();
+        assert!(ula.contains("fd12:3456::1".parse().unwrap()));
+        assert!(!ula.contains("2001:db8::1".parse().unwrap()));
+
+        assert!(Network::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert_eq!(Network::parse("10.0.0.5").unwrap().to_string(), "10.0.0.5/32");
+        assert!(Network::parse("10.0.0.0/33").is_none());
+        assert!(Network::parse("lan").is_none());
+        assert!(Bandwidth::new(&settings(0.0, &[], &["10.0.0.0/8", "nas.local"])).is_err());
+    }
+
+    #[test]
+    fn test_transfers_share_the_global_bucket() {
+        // 8 Mbit/s is a million bytes a second
+        let bandwidth = Bandwidth::new(&settings(8.0, &[("Alice", 80.0), ("bob", 0.0)], &["192.168.0.0/16"])).unwrap();
+        let now = Instant::now();
+
+        // A second's worth goes straight through, then two connections
+        // queue up behind each other
+        assert_eq!(bandwidth.take(None, 1_000_000, now), Duration::ZERO);
+        assert_eq!(bandwidth.take(Some("carol"), 500_000, now), Duration::from_millis(500));
+        assert_eq!(bandwidth.take(None, 500_000, now), Duration::from_secs(1));
+        assert_eq!(bandwidth.take(None, 0, now + Duration::from_secs(1)), Duration::ZERO);
+
+        // Overrides get their own bucket, or none at all
+        assert_eq!(bandwidth.take(Some("alice"), 10_000_000, now), Duration::ZERO);
+        assert_eq!(bandwidth.take(Some("alice"), 1_000_000, now), Duration::from_millis(100));
+        assert_eq!(bandwidth.take(Some("bob"), 100_000_000, now), Duration::ZERO);
+
+        assert!(bandwidth.throttle(Some("192.168.1.20".parse().unwrap()), Some("carol")).is_none());
+        assert!(bandwidth.throttle(Some("203.0.113.9".parse().unwrap()), Some("carol")).is_some());
+    }
+
+    #[test]
+    fn test_slow_limits_wait_a_bounded_time() {
+        // 10 kbit/s is 1250 bytes a second
+        let bandwidth = Bandwidth::new(&settings(MIN_MBPS, &[], &[])).unwrap();
+        let now = Instant::now();
+
+        assert_eq!(bandwidth.take(None, 1250 + 2500, now), Duration::from_secs(2));
+        assert_eq!(bandwidth.take(None, 64 * 1024 * 1024, now), MAX_WAIT);
+        // The debt is still owed by whoever comes next
+        assert_eq!(bandwidth.take(None, 0, now + MAX_WAIT), MAX_WAIT);
+
+        let mut bucket = Bucket { mbps: f64::MIN_POSITIVE, tokens: 0.0, updated: now };
+        assert_eq!(bucket.take(1, now), MAX_WAIT);
+    }
+
+    #[test]
+    fn test_limits_change_at_runtime() {
+        let bandwidth = Bandwidth::new(&settings(0.0, &[], &[])).unwrap();
+        let now = Instant::now();
+        assert_eq!(bandwidth.take(None, 100_000_000, now), Duration::ZERO);
+
+        let limits = bandwidth.set_limits(16.0, &HashMap::from([("Dave".to_string(), 0.0)])).unwrap();
+        assert_eq!(limits.max_mbps, 16.0);
+        assert_eq!(limits.user_mbps.get("dave"), Some(&0.0));
+
+        let now = Instant::now();
+        assert_eq!(bandwidth.take(None, 4_000_000, now), Duration::from_secs(1));
+        assert_eq!(bandwidth.take(Some("dave"), 4_000_000, now), Duration::ZERO);
+
+        assert!(bandwidth.set_limits(-1.0, &HashMap::new()).is_err());
+        assert!(bandwidth.set_limits(f64::NAN, &HashMap::new()).is_err());
+        assert!(bandwidth.set_limits(1e-300, &HashMap::new()).is_err());
+        assert!(bandwidth.set_limits(16.0, &HashMap::from([("dave".to_string(), f64::MIN_POSITIVE)])).is_err());
+        assert_eq!(bandwidth.limits().max_mbps, 16.0);
+    }
+}
@@ -1,13 +1,26 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
+use crate::types::{ConflictPolicy, Permission, SymlinkPolicy, WebhookEvent};
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ServerConfig {
     pub server: ServerSettings,
     pub database: DatabaseSettings,
     pub filesystem: FilesystemSettings,
+    #[serde(default)]
+    pub storage: StorageSettings,
     pub auth: AuthSettings,
     pub mycloud: MyCloudSettings,
+    #[serde(default)]
+    pub notifications: NotificationSettings,
+    #[serde(default)]
+    pub sync: SyncSettings,
+    #[serde(default)]
+    pub webhooks: WebhookSettings,
+    #[serde(default)]
+    pub backup: BackupSettings,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -17,6 +30,19 @@ pub struct ServerSettings {
     pub max_connections: usize,
     pub request_timeout_seconds: u64,
     pub max_request_size: usize,
+    /// Entries per page of a listing when the client doesn't ask for a `limit`
+    pub default_page_size: u32,
+    /// Most entries one page of a listing may carry, whatever the client asks for
+    pub max_page_size: u32,
+    /// Combined rate of all downloads and uploads in Mbit/s; 0 means unlimited
+    pub max_bandwidth_mbps: f64,
+    /// Clients on these networks (CIDR, e.g. 192.168.0.0/16) are never throttled
+    pub bandwidth_exempt_networks: Vec<String>,
+    /// Per-username limits in Mbit/s that replace `max_bandwidth_mbps`; 0 means unlimited
+    #[serde(default)]
+    pub user_bandwidth_mbps: HashMap<String, f64>,
+    /// Seconds the admin statistics are reused before being counted again
+    pub stats_cache_seconds: u64,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -30,15 +56,352 @@ pub struct DatabaseSettings {
 pub struct FilesystemSettings {
     pub base_path: PathBuf,
     pub max_file_size_mb: u64,
+    /// Upload allowlist, compared case-insensitively; empty allows everything
     pub allowed_extensions: Vec<String>,
+    /// Whether files without any extension pass the allowlist
+    pub allow_no_extension: bool,
     pub temp_directory: PathBuf,
+    /// Downloads larger than this many bytes are streamed instead of buffered
+    pub stream_threshold_bytes: u64,
+    /// Resumable upload sessions idle for longer than this are discarded
+    pub upload_session_ttl_hours: u64,
+    /// Smallest chunk an upload session may use, in KB
+    pub upload_min_chunk_kb: u64,
+    /// Largest chunk an upload session may use, in MB
+    pub upload_max_chunk_mb: u64,
+    /// Most chunks one upload session may be split into
+    pub upload_max_chunks: u32,
+    /// Deleted files are kept in the trash for this many days before being purged
+    pub trash_retention_days: u64,
+    /// Previous versions kept per file on overwrite; 0 disables versioning
+    pub max_versions_per_file: u32,
+    /// Total space the version store may use; 0 means unlimited
+    pub max_version_storage_mb: u64,
+    /// Images wider or taller than this are not decoded for thumbnails
+    pub thumbnail_max_source_dimension: u32,
+    /// Largest total size a single server-side copy may duplicate
+    pub max_copy_size_mb: u64,
+    /// Store identical file contents once and hard link every path to it (unix only)
+    pub deduplicate_files: bool,
+    /// How symbolic links inside `base_path` are treated: deny, skip or follow_within_base
+    pub symlink_policy: SymlinkPolicy,
+    /// Storage quota for users without their own; 0 means unlimited
+    pub default_quota_mb: u64,
+    /// Hours between background integrity scrub cycles; 0 disables the scrub
+    pub integrity_scan_interval_hours: u64,
+    /// Files re-hashed per scrub cycle
+    pub integrity_scan_files_per_cycle: u32,
+    /// Read rate cap for the scrub in MB/s; 0 means unthrottled
+    pub integrity_scan_rate_mb_per_sec: u64,
+    /// Record changes made directly on disk (e.g. over SMB) in the sync feed
+    pub watch_external_changes: bool,
+    /// Days changes stay in the sync feed; clients that last
+    /// synced longer ago have to resync in full. 0 keeps them forever.
+    pub change_log_retention_days: u64,
+    /// Days after which edits and other changes later ones supersede are
+    /// dropped from the sync feed, which clients never notice. 0 keeps them.
+    pub change_log_compact_after_days: u64,
+    /// Days a device can go without syncing before its sync session is
+    /// marked inactive and it has to register again. 0 keeps them active.
+    pub sync_session_idle_days: u64,
+    /// Most changes one sync response may carry, whatever the client asks for
+    pub sync_max_page_size: u32,
+    /// Most files a client may list in one `/sync/plan` manifest
+    pub sync_plan_max_entries: u32,
+    /// Block size of the signatures delta uploads are computed against
+    pub delta_block_size: u32,
+    /// Files smaller than this many KB are always uploaded in full
+    pub delta_min_file_size_kb: u64,
+    /// Most operations one `/sync/push` batch may carry
+    pub push_max_operations: usize,
+    /// Most inline content one push batch may carry in total, in MB
+    pub push_max_payload_mb: u64,
+    /// Largest file a push may carry inline, in KB; bigger ones go through an upload session
+    pub push_max_inline_kb: u64,
+    /// Gitignore-style patterns for entries hidden everywhere, on top of `.synkerignore` files
+    pub ignore_patterns: Vec<String>,
+    /// Root each user's files in their own home, `base_path/users/<user_id>`
+    pub user_homes: bool,
+    /// Username that reconciliation gives untracked top-level files and folders to
+    pub reconcile_owner: String,
+    /// Files or rows reconciliation handles before pausing for other work
+    pub reconcile_batch_size: u32,
+    /// Extra top-level folders served from other locations, e.g. a second drive
+    #[serde(default)]
+    pub mounts: Vec<MountSettings>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MountSettings {
+    /// Top-level folder name the mount appears under
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackendKind {
+    /// Files under `base_path` and the mounts
+    #[default]
+    Local,
+    /// Files in the bucket described by `[storage.s3]`
+    S3,
+}
+
+/// How new password hashes are made. Stored hashes of either kind keep working.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordScheme {
+    Bcrypt,
+    #[default]
+    Argon2id,
+}
+
+/// Rules for new passwords. Passwords already set keep working.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PasswordPolicy {
+    /// Off accepts any password
+    pub enabled: bool,
+    pub min_length: usize,
+    /// Longest password in bytes. bcrypt ignores anything past 72.
+    pub max_length: Option<usize>,
+    /// Need three of lowercase letters, uppercase letters, digits and symbols
+    pub require_mixed_classes: bool,
+    /// Refused outright, whatever the case
+    pub deny_list: Vec<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_length: 8,
+            max_length: Some(72),
+            require_mixed_classes: false,
+            deny_list: [
+                "password", "password1", "12345678", "123456789", "1234567890",
+                "qwertyuiop", "iloveyou", "sunshine", "football", "letmein1",
+            ]
+            .iter()
+            .map(|password| password.to_string())
+            .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct StorageSettings {
+    /// Where file contents are kept; metadata always stays in the database
+    pub backend: StorageBackendKind,
+    pub s3: Option<S3Settings>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct S3Settings {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prepended to every object key, so one bucket can hold several servers
+    #[serde(default)]
+    pub path_prefix: String,
+    /// Address the bucket as `endpoint/bucket`, which MinIO needs
+    pub path_style: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AuthSettings {
     pub jwt_secret: String,
+    /// Secrets used before `jwt_secret`, still accepted for tokens and 2FA
+    /// secrets made with them, so rotating it logs no one out
+    pub jwt_previous_secrets: Vec<String>,
+    /// `iss` and `aud` of issued tokens; tokens with others are rejected
+    pub jwt_issuer: String,
+    pub jwt_audience: String,
+    /// Clock difference between hosts tolerated when checking expiry
+    pub jwt_leeway_seconds: u64,
+    /// Signs share link tokens; kept apart from `jwt_secret`
+    pub share_secret: String,
+    /// Secrets used before `share_secret`, still accepted so rotating it
+    /// breaks no links
+    pub share_previous_secrets: Vec<String>,
+    /// Accept the plain UUID tokens of share links made before tokens were
+    /// signed. Turn off once those links are no longer needed.
+    pub accept_legacy_share_tokens: bool,
+    /// Characters in the short codes of new share links, 8 to 32
+    pub share_code_length: usize,
+    /// How often share links that expired or used up their downloads are
+    /// deleted, 0 to keep them
+    pub share_cleanup_interval_minutes: u64,
+    /// Lifetime of access tokens; clients renew them with a refresh token
     pub token_expiry_hours: i64,
+    /// Lifetime of refresh tokens, after which the user has to log in again
+    pub refresh_token_expiry_days: i64,
+    /// Scheme for new hashes; logins upgrade hashes made with the other one
+    pub password_scheme: PasswordScheme,
     pub bcrypt_cost: u32,
+    /// Memory each Argon2id hash uses, in KiB
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    /// Rules passwords set by users and admins have to follow
+    pub password_policy: PasswordPolicy,
+    /// Login attempts allowed from one address per minute, whatever the username
+    pub login_attempts_per_minute_per_ip: u32,
+    /// Login attempts allowed for one username per minute, whatever the address
+    pub login_attempts_per_minute_per_user: u32,
+    /// Consecutive failures after which a username is locked
+    pub lockout_threshold: u32,
+    /// First lockout; each further failure doubles it
+    pub lockout_base_seconds: u64,
+    /// Longest a lockout can get
+    pub lockout_max_seconds: u64,
+    /// Failures older than this no longer count towards a lockout
+    pub lockout_window_seconds: u64,
+    /// Days audit log entries are kept, 0 to keep them forever
+    pub audit_retention_days: u64,
+    /// Record the network share link visitors come from in each link's
+    /// activity log. Off records no address at all.
+    pub share_access_log_ips: bool,
+    /// Let visitors without a login list and download `anonymous_root`
+    pub allow_anonymous_read: bool,
+    /// Folder of the shared tree anonymous visitors see, e.g. `/public`
+    pub anonymous_root: Option<String>,
+    /// Login through an OpenID Connect provider, next to password login
+    pub oidc: Option<OidcSettings>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OidcSettings {
+    /// Discovered through `<issuer_url>/.well-known/openid-configuration`
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// `/api/v1/auth/oidc/callback` as the browser reaches it
+    pub redirect_url: String,
+    /// Create a user on their first login. Otherwise only users linked
+    /// before, or matched by email, get in.
+    pub auto_provision: bool,
+    /// Link a login to the only user with the same verified email, unless
+    /// that user is an admin or uses two-factor authentication
+    #[serde(default)]
+    pub link_by_email: bool,
+    /// ID token claim listing the user's groups
+    pub groups_claim: String,
+    /// Permissions of users created through OIDC, before their groups add any
+    pub default_permissions: Vec<Permission>,
+    /// What each group adds, e.g. `synker-admin = ["admin"]`
+    #[serde(default)]
+    pub group_permissions: HashMap<String, Vec<Permission>>,
+    /// Where browsers are sent after logging in, with the token in a cookie.
+    /// Without it the callback answers like password login.
+    pub post_login_redirect: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct NotificationSettings {
+    /// Address users reach the server at, e.g. `https://nas.example.com`,
+    /// for links in emails
+    pub public_url: Option<String>,
+    /// Emails are off without it
+    pub smtp: Option<SmtpSettings>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: u16,
+    /// Upgrade the connection with STARTTLS. Off sends in the clear, for a
+    /// relay on the same host or network.
+    pub starttls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender of every email, e.g. `Synker <synker@example.com>`
+    pub from: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SyncSettings {
+    /// What happens when a client changed a file someone else changed first:
+    /// keep_both, server_wins, client_wins or newest_wins
+    pub conflict_policy: ConflictPolicy,
+    /// Seconds apart two modification times may be and still count as the
+    /// same under newest_wins, which then keeps both
+    pub newest_wins_tolerance_seconds: u64,
+    /// Policies for paths matching gitignore-style patterns, e.g.
+    /// `"/photos" = "keep_both"`; the longest matching pattern wins
+    pub folder_policies: HashMap<String, ConflictPolicy>,
+}
+
+impl Default for SyncSettings {
+    fn default() -> Self {
+        Self {
+            conflict_policy: ConflictPolicy::KeepBoth,
+            newest_wins_tolerance_seconds: 2,
+            folder_policies: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WebhookSettings {
+    /// Tries per event before its delivery is marked failed
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubling after each
+    pub retry_delay_seconds: u64,
+    pub endpoints: Vec<WebhookEndpoint>,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            retry_delay_seconds: 10,
+            endpoints: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BackupSettings {
+    /// Where backups are kept, both scheduled ones and those asked for
+    /// through the admin API; without one they can only be downloaded
+    pub directory: Option<PathBuf>,
+    /// Hours between scheduled backups; 0 leaves backups to admins
+    pub interval_hours: u64,
+    /// Backups kept in `directory`; older ones are deleted after each new one
+    pub keep: usize,
+    /// Gzip backups unless the request says otherwise
+    pub compress: bool,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            interval_hours: 0,
+            keep: 7,
+            compress: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebhookEndpoint {
+    /// Names the hook in the admin API and its deliveries
+    pub name: String,
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature sent with every event
+    pub secret: String,
+    /// Only events at or under this path, e.g. `/scans`
+    pub path_prefix: Option<String>,
+    /// created, modified, deleted, moved and share_created; empty sends all of them
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -48,6 +411,41 @@ pub struct MyCloudSettings {
     pub admin_password: String,
     pub verify_ssl: bool,
     pub sync_interval_seconds: u64,
+    /// Check logins the local database turns down against MyCloud, creating
+    /// NAS users here on their first login
+    pub auth_fallback: bool,
+    /// How long MyCloud keeps the admin session; it is renewed shortly
+    /// before then. 0 renews it only once MyCloud turns it down.
+    #[serde(default = "default_session_ttl_seconds")]
+    pub session_ttl_seconds: u64,
+    /// Create, update and deactivate users here from MyCloud's user list on
+    /// every sync
+    #[serde(default)]
+    pub sync_users: bool,
+    /// Make each MyCloud share's folder shared in Synker with the users who
+    /// can open it on the NAS, on every sync
+    #[serde(default)]
+    pub import_shares: bool,
+    /// Folder on the NAS that shares are in, such as /mnt/HD/HD_a2
+    #[serde(default = "default_share_root")]
+    pub share_root: String,
+    /// Folder in Synker that `share_root` corresponds to
+    #[serde(default = "default_share_folder")]
+    pub share_folder: String,
+}
+
+/// MyCloud's own session lifetime
+fn default_session_ttl_seconds() -> u64 {
+    3600
+}
+
+/// Where a My Cloud keeps its shares
+fn default_share_root() -> String {
+    "/mnt/HD/HD_a2".to_string()
+}
+
+fn default_share_folder() -> String {
+    "/".to_string()
 }
 
 impl Default for ServerConfig {
@@ -59,6 +457,12 @@ impl Default for ServerConfig {
                 max_connections: 1000,
                 request_timeout_seconds: 30,
                 max_request_size: 100 * 1024 * 1024, // 100MB
+                default_page_size: 100,
+                max_page_size: 1000,
+                max_bandwidth_mbps: 0.0,
+                bandwidth_exempt_networks: Vec::new(),
+                user_bandwidth_mbps: HashMap::new(),
+                stats_cache_seconds: 30,
             },
             database: DatabaseSettings {
                 url: "sqlite:./synker.db".to_string(),
@@ -85,12 +489,76 @@ impl Default for ServerConfig {
                     "zip".to_string(), "rar".to_string(), "7z".to_string(), "tar".to_string(),
                     "gz".to_string(), "bz2".to_string(),
                 ],
+                allow_no_extension: false,
                 temp_directory: PathBuf::from("./temp"),
+                stream_threshold_bytes: 8 * 1024 * 1024, // 8MB
+                upload_session_ttl_hours: 24,
+                upload_min_chunk_kb: 64,
+                upload_max_chunk_mb: 64,
+                upload_max_chunks: 100000,
+                trash_retention_days: 30,
+                max_versions_per_file: 10,
+                max_version_storage_mb: 10240, // 10GB
+                thumbnail_max_source_dimension: 12000,
+                max_copy_size_mb: 10240, // 10GB
+                deduplicate_files: false,
+                symlink_policy: SymlinkPolicy::Skip,
+                default_quota_mb: 0,
+                integrity_scan_interval_hours: 24,
+                integrity_scan_files_per_cycle: 1000,
+                integrity_scan_rate_mb_per_sec: 20,
+                watch_external_changes: true,
+                change_log_retention_days: 90,
+                change_log_compact_after_days: 7,
+                sync_session_idle_days: 30,
+                sync_max_page_size: 5000,
+                sync_plan_max_entries: 100000,
+                delta_block_size: 8192,
+                delta_min_file_size_kb: 1024,
+                push_max_operations: 1000,
+                push_max_payload_mb: 32,
+                push_max_inline_kb: 1024,
+                ignore_patterns: vec![
+                    "node_modules".to_string(),
+                    ".DS_Store".to_string(),
+                    "Thumbs.db".to_string(),
+                ],
+                user_homes: false,
+                reconcile_owner: "admin".to_string(),
+                reconcile_batch_size: 200,
+                mounts: Vec::new(),
             },
+            storage: StorageSettings::default(),
             auth: AuthSettings {
                 jwt_secret: "your-super-secret-jwt-key-change-this-in-production".to_string(),
-                token_expiry_hours: 24,
+                jwt_previous_secrets: Vec::new(),
+                jwt_issuer: "synker".to_string(),
+                jwt_audience: "synker".to_string(),
+                jwt_leeway_seconds: 60,
+                share_secret: "your-super-secret-share-key-change-this-in-production".to_string(),
+                share_previous_secrets: Vec::new(),
+                accept_legacy_share_tokens: true,
+                share_code_length: 10,
+                share_cleanup_interval_minutes: 60,
+                token_expiry_hours: 1,
+                refresh_token_expiry_days: 30,
+                password_scheme: PasswordScheme::Argon2id,
                 bcrypt_cost: 12,
+                argon2_memory_kib: 19 * 1024,
+                argon2_iterations: 2,
+                argon2_parallelism: 1,
+                password_policy: PasswordPolicy::default(),
+                login_attempts_per_minute_per_ip: 20,
+                login_attempts_per_minute_per_user: 5,
+                lockout_threshold: 5,
+                lockout_base_seconds: 30,
+                lockout_max_seconds: 900,
+                lockout_window_seconds: 3600,
+                audit_retention_days: 90,
+                share_access_log_ips: true,
+                allow_anonymous_read: false,
+                anonymous_root: None,
+                oidc: None,
             },
             mycloud: MyCloudSettings {
                 api_endpoint: "http://192.168.1.100".to_string(),
@@ -98,7 +566,17 @@ impl Default for ServerConfig {
                 admin_password: "".to_string(),
                 verify_ssl: false,
                 sync_interval_seconds: 300, // 5 minutes
+                auth_fallback: false,
+                session_ttl_seconds: default_session_ttl_seconds(),
+                sync_users: false,
+                import_shares: false,
+                share_root: default_share_root(),
+                share_folder: default_share_folder(),
             },
+            notifications: NotificationSettings::default(),
+            sync: SyncSettings::default(),
+            webhooks: WebhookSettings::default(),
+            backup: BackupSettings::default(),
         }
     }
 }
@@ -131,16 +609,200 @@ impl ServerConfig {
             return Err(anyhow::anyhow!("Server port cannot be 0"));
         }
 
+        if self.server.default_page_size == 0 || self.server.default_page_size > self.server.max_page_size {
+            return Err(anyhow::anyhow!("default_page_size must be positive and at most max_page_size"));
+        }
+
+        if self.database.max_connections == 0 || self.database.connection_timeout_seconds == 0 {
+            return Err(anyhow::anyhow!("Database max_connections and connection_timeout_seconds must be positive"));
+        }
+
         // Validate auth settings
         if self.auth.jwt_secret.len() < 32 {
             return Err(anyhow::anyhow!("JWT secret must be at least 32 characters long"));
         }
 
+        if self.auth.jwt_previous_secrets.iter().any(|secret| secret.len() < 32) {
+            return Err(anyhow::anyhow!("Previous JWT secrets must be at least 32 characters long"));
+        }
+
+        if self.auth.share_secret.len() < 32 || self.auth.share_previous_secrets.iter().any(|secret| secret.len() < 32) {
+            return Err(anyhow::anyhow!("Share secrets must be at least 32 characters long"));
+        }
+
+        if !(8..=32).contains(&self.auth.share_code_length) {
+            return Err(anyhow::anyhow!("share_code_length must be between 8 and 32"));
+        }
+
+        if self.auth.share_secret == self.auth.jwt_secret {
+            return Err(anyhow::anyhow!("share_secret must differ from jwt_secret"));
+        }
+
+        if self.auth.jwt_issuer.is_empty() || self.auth.jwt_audience.is_empty() {
+            return Err(anyhow::anyhow!("jwt_issuer and jwt_audience cannot be empty"));
+        }
+
+        // More than a few minutes hides a broken clock rather than skew
+        if self.auth.jwt_leeway_seconds > 300 {
+            return Err(anyhow::anyhow!("jwt_leeway_seconds must be at most 300"));
+        }
+
+        if self.auth.token_expiry_hours <= 0 || self.auth.refresh_token_expiry_days <= 0 {
+            return Err(anyhow::anyhow!("Token expiry times must be positive"));
+        }
+
+        if self.auth.login_attempts_per_minute_per_ip == 0 || self.auth.login_attempts_per_minute_per_user == 0 {
+            return Err(anyhow::anyhow!("Login rate limits must allow at least one attempt per minute"));
+        }
+
+        if self.auth.lockout_threshold == 0 || self.auth.lockout_base_seconds > self.auth.lockout_max_seconds {
+            return Err(anyhow::anyhow!("lockout_threshold must be positive and lockout_base_seconds at most lockout_max_seconds"));
+        }
+        if self.auth.lockout_window_seconds < self.auth.lockout_base_seconds {
+            return Err(anyhow::anyhow!("lockout_window_seconds must be at least lockout_base_seconds"));
+        }
+
+        let policy = &self.auth.password_policy;
+        if policy.max_length.is_some_and(|max_length| max_length < policy.min_length) {
+            return Err(anyhow::anyhow!("password_policy.max_length must be at least min_length"));
+        }
+
+        // Below 8 hashes are cheap to brute force, above 16 a login takes seconds
+        if !(8..=16).contains(&self.auth.bcrypt_cost) {
+            return Err(anyhow::anyhow!("bcrypt_cost must be between 8 and 16"));
+        }
+
+        argon2::Params::new(
+            self.auth.argon2_memory_kib,
+            self.auth.argon2_iterations,
+            self.auth.argon2_parallelism,
+            None,
+        )
+        .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {}", e))?;
+
+        if self.auth.allow_anonymous_read {
+            match &self.auth.anonymous_root {
+                Some(root) if root.starts_with('/') && !root.split('/').any(|part| part == "..") => {}
+                _ => return Err(anyhow::anyhow!("allow_anonymous_read needs an absolute anonymous_root without '..'")),
+            }
+        }
+
+        if let Some(oidc) = &self.auth.oidc {
+            for url in [&oidc.issuer_url, &oidc.redirect_url] {
+                reqwest::Url::parse(url).map_err(|e| anyhow::anyhow!("Invalid OIDC URL '{}': {}", url, e))?;
+            }
+            if oidc.client_id.is_empty() {
+                return Err(anyhow::anyhow!("OIDC client_id cannot be empty"));
+            }
+        }
+
         // Validate filesystem settings
         if !self.filesystem.base_path.is_absolute() {
             return Err(anyhow::anyhow!("Filesystem base path must be absolute"));
         }
 
+        for mount in &self.filesystem.mounts {
+            if !mount.path.is_absolute() {
+                return Err(anyhow::anyhow!("Path of mount '{}' must be absolute", mount.name));
+            }
+        }
+
+        if self.filesystem.sync_max_page_size == 0 {
+            return Err(anyhow::anyhow!("sync_max_page_size must be positive"));
+        }
+
+        if self.filesystem.sync_plan_max_entries == 0 {
+            return Err(anyhow::anyhow!("sync_plan_max_entries must be positive"));
+        }
+
+        // Tiny blocks make huge signatures, huge ones find few matches
+        if !(512..=1024 * 1024).contains(&self.filesystem.delta_block_size) {
+            return Err(anyhow::anyhow!("delta_block_size must be between 512 and 1048576"));
+        }
+
+        if self.filesystem.upload_min_chunk_kb == 0
+            || self.filesystem.upload_min_chunk_kb > self.filesystem.upload_max_chunk_mb * 1024
+            || self.filesystem.upload_max_chunks == 0
+        {
+            return Err(anyhow::anyhow!("upload_min_chunk_kb must be positive and at most upload_max_chunk_mb, and upload_max_chunks positive"));
+        }
+
+        if self.filesystem.push_max_operations == 0
+            || self.filesystem.push_max_payload_mb == 0
+            || self.filesystem.push_max_inline_kb == 0
+        {
+            return Err(anyhow::anyhow!("push_max_operations, push_max_payload_mb and push_max_inline_kb must be positive"));
+        }
+
+        if self.webhooks.max_attempts == 0 {
+            return Err(anyhow::anyhow!("webhooks.max_attempts must be positive"));
+        }
+
+        for (index, endpoint) in self.webhooks.endpoints.iter().enumerate() {
+            if endpoint.name.is_empty() || self.webhooks.endpoints[..index].iter().any(|other| other.name == endpoint.name) {
+                return Err(anyhow::anyhow!("Webhook names must be set and unique"));
+            }
+            match reqwest::Url::parse(&endpoint.url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                _ => return Err(anyhow::anyhow!("Webhook '{}' needs an http or https URL", endpoint.name)),
+            }
+            if endpoint.secret.len() < 16 {
+                return Err(anyhow::anyhow!("Secret of webhook '{}' must be at least 16 characters long", endpoint.name));
+            }
+            if endpoint.events.contains(&WebhookEvent::Test) {
+                return Err(anyhow::anyhow!("Webhook '{}' can't subscribe to test events; they are sent on request", endpoint.name));
+            }
+        }
+
+        if self.backup.keep == 0 {
+            return Err(anyhow::anyhow!("backup.keep must be positive"));
+        }
+
+        if self.backup.interval_hours > 0 && self.backup.directory.is_none() {
+            return Err(anyhow::anyhow!("Scheduled backups need backup.directory"));
+        }
+
+        // The watcher maps paths on disk to rows without knowing about homes
+        if self.filesystem.user_homes && self.filesystem.watch_external_changes {
+            return Err(anyhow::anyhow!("With user_homes, watch_external_changes must be turned off"));
+        }
+
+        if self.storage.backend == StorageBackendKind::S3 {
+            if self.storage.s3.is_none() {
+                return Err(anyhow::anyhow!("The s3 storage backend needs a [storage.s3] section"));
+            }
+
+            // These work on files in base_path directly
+            let filesystem = &self.filesystem;
+            if filesystem.deduplicate_files
+                || !filesystem.mounts.is_empty()
+                || filesystem.watch_external_changes
+                || filesystem.max_versions_per_file > 0
+                || filesystem.integrity_scan_interval_hours > 0
+            {
+                return Err(anyhow::anyhow!(
+                    "With the s3 storage backend, deduplicate_files, mounts, watch_external_changes, \
+                     max_versions_per_file and integrity_scan_interval_hours must be turned off"
+                ));
+            }
+        }
+
+        // Validate notification settings
+        if let Some(smtp) = &self.notifications.smtp {
+            match &self.notifications.public_url {
+                Some(url) => {
+                    reqwest::Url::parse(url).map_err(|e| anyhow::anyhow!("Invalid public_url '{}': {}", url, e))?;
+                }
+                None => return Err(anyhow::anyhow!("[notifications.smtp] needs notifications.public_url for links")),
+            }
+            if smtp.host.is_empty() || smtp.from.is_empty() {
+                return Err(anyhow::anyhow!("SMTP host and from cannot be empty"));
+            }
+            if smtp.username.is_some() != smtp.password.is_some() {
+                return Err(anyhow::anyhow!("SMTP username and password go together"));
+            }
+        }
+
         // Validate MyCloud settings
         if self.mycloud.admin_username.is_empty() {
             return Err(anyhow::anyhow!("MyCloud admin username cannot be empty"));
@@ -1,21 +1,386 @@
-use sqlx::{SqlitePool, Row};
+use sqlx::{QueryBuilder, Sqlite, SqliteConnection, SqlitePool, Row, Transaction};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
 use uuid::Uuid;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex, MutexGuard};
 use chrono::{DateTime, Utc};
 use anyhow::Result;
+use crate::config::DatabaseSettings;
+use crate::filesystem::{FileSystemService, parent_path};
 use crate::types::*;
 
+/// Share links deleted per statement when purging, so a large backlog
+/// doesn't hold the write lock for long
+const SHARE_PURGE_BATCH: i64 = 500;
+
+/// Rows per statement when recording many files at once. At twelve values a
+/// row that stays well below SQLite's limit of 32766 bound parameters.
+const METADATA_INSERT_BATCH: usize = 500;
+
+/// Values bound per `IN (...)` list when looking up many rows at once, well
+/// below SQLite's limit of 32766 bound parameters
+const MAX_BIND_PARAMS: usize = 500;
+
+/// Whether a write failed on a UNIQUE constraint
+pub fn is_unique_violation(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<sqlx::Error>()
+        .and_then(|error| error.as_database_error())
+        .is_some_and(|error| error.is_unique_violation())
+}
+
+/// Whether a query gave up waiting for a free connection or its turn to write
+pub fn is_pool_timeout(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref::<sqlx::Error>(), Some(sqlx::Error::PoolTimedOut))
+}
+
+/// What deleting metadata rows took along
+#[derive(Debug, Default)]
+pub struct DeletedFiles {
+    pub rows: u64,
+    /// Checksums of stored versions nothing refers to any more; the content
+    /// is the caller's to remove
+    pub unused_versions: Vec<String>,
+}
+
+/// A metadata write deferred so that several can share one transaction
+pub enum MetadataWrite {
+    Insert(FileMetadata),
+    Move { metadata: FileMetadata, old_path: String },
+    Trash(TrashEntry),
+}
+
+/// One entry of the change log
+#[derive(Debug, Clone)]
+pub struct LoggedChange {
+    pub seq: i64,
+    pub owner_id: Uuid,
+    pub file_id: Uuid,
+    pub change_type: ChangeType,
+    pub path: String,
+    pub old_path: Option<String>,
+    pub changed_at: DateTime<Utc>,
+}
+
+/// A change to a user's files or a download through one of their share
+/// links, as the activity feed shows it
+#[derive(Debug, Clone)]
+pub struct ActivityEvent {
+    pub id: Uuid,
+    pub kind: ActivityKind,
+    pub file_id: Uuid,
+    pub path: String,
+    pub occurred_at: DateTime<Utc>,
+    pub actor_id: Option<Uuid>,
+    pub device_id: Option<String>,
+}
+
+/// Who a change to the sync feed is attributed to
+#[derive(Debug, Clone)]
+pub struct ChangeActor {
+    pub user_id: Uuid,
+    pub device_id: Option<String>,
+}
+
+tokio::task_local! {
+    // Set around each authenticated request; changes made outside one, such
+    // as the watcher's, have no actor
+    static CHANGE_ACTOR: ChangeActor;
+}
+
+impl ChangeActor {
+    /// Run `future` with the changes it records attributed to this actor
+    pub async fn scope<F: std::future::Future>(self, future: F) -> F::Output {
+        CHANGE_ACTOR.scope(self, future).await
+    }
+
+    fn current() -> Option<Self> {
+        CHANGE_ACTOR.try_with(Clone::clone).ok()
+    }
+}
+
+#[derive(Clone)]
 pub struct Database {
     pool: SqlitePool,
+    /// Bumped whenever changes for the sync feed are committed
+    changes: Arc<watch::Sender<()>>,
+    /// Taken for the whole of every write transaction, see `begin_write`
+    writes: Arc<Mutex<()>>,
+}
+
+/// A write transaction that holds the write turn until it is committed or
+/// dropped
+struct WriteTransaction<'a> {
+    tx: Transaction<'static, Sqlite>,
+    _turn: MutexGuard<'a, ()>,
+}
+
+impl WriteTransaction<'_> {
+    async fn commit(self) -> Result<()> {
+        self.tx.commit().await?;
+        Ok(())
+    }
+}
+
+impl Deref for WriteTransaction<'_> {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &SqliteConnection {
+        &self.tx
+    }
+}
+
+impl DerefMut for WriteTransaction<'_> {
+    fn deref_mut(&mut self) -> &mut SqliteConnection {
+        &mut self.tx
+    }
+}
+
+/// Metadata writes that land together or not at all, for operations that
+/// touch many rows. Reads through it see its own writes. It holds the write
+/// turn until it is committed, and dropping it uncommitted rolls everything
+/// back. Its methods do what the `Database` ones of the same name do.
+pub struct DatabaseTransaction<'a> {
+    database: &'a Database,
+    tx: WriteTransaction<'a>,
+}
+
+impl<'a> DatabaseTransaction<'a> {
+    /// The database outside the transaction, for reads that needn't see
+    /// its writes. Writing through it waits for the transaction to end.
+    pub fn database(&self) -> &'a Database {
+        self.database
+    }
+
+    pub async fn get_file_metadata_by_path(&mut self, owner_id: Uuid, path: &str) -> Result<Option<FileMetadata>> {
+        Database::fetch_file_metadata_by_path(&mut self.tx, owner_id, path).await
+    }
+
+    pub async fn create_file_metadata(&mut self, metadata: &FileMetadata) -> Result<()> {
+        Database::insert_file_metadata(&mut self.tx, metadata).await
+    }
+
+    pub async fn create_trash_entry(&mut self, entry: &TrashEntry) -> Result<()> {
+        Database::insert_trash_entry(&mut self.tx, entry).await
+    }
+
+    pub async fn upsert_file_metadata(&mut self, metadata: &mut FileMetadata) -> Result<()> {
+        Database::store_file_metadata(&mut self.tx, metadata).await
+    }
+
+    /// Swap the checksum and size of the row at `path` for new ones, only
+    /// while it is still the version with `base_checksum` and
+    /// `base_modified_at`, whichever are given. Returns the row as it was,
+    /// or None when it has changed or is gone.
+    pub async fn claim_file_version(
+        &mut self,
+        owner_id: Uuid,
+        path: &str,
+        base_checksum: Option<&str>,
+        base_modified_at: Option<DateTime<Utc>>,
+        checksum: &str,
+        size: u64,
+    ) -> Result<Option<FileMetadata>> {
+        let Some(existing) = Database::fetch_file_metadata_by_path(&mut self.tx, owner_id, path).await? else {
+            return Ok(None);
+        };
+
+        let size = size as i64;
+        let result = sqlx::query!(
+            r#"
+            UPDATE file_metadata SET checksum = ?4, size = ?5
+            WHERE id = ?1 AND deleted_at IS NULL
+              AND (?2 IS NULL OR lower(checksum) = lower(?2))
+              AND (?3 IS NULL OR modified_at = ?3)
+            "#,
+            existing.id,
+            base_checksum,
+            base_modified_at,
+            checksum,
+            size
+        )
+        .execute(&mut *self.tx)
+        .await?;
+
+        Ok((result.rows_affected() > 0).then_some(existing))
+    }
+
+    pub async fn apply_metadata_writes(&mut self, writes: &[MetadataWrite]) -> Result<()> {
+        // Runs of inserts, like the rows of a copied folder, go in together
+        let mut inserts = Vec::new();
+        for write in writes {
+            match write {
+                MetadataWrite::Insert(metadata) => inserts.push(metadata),
+                MetadataWrite::Move { metadata, old_path } => {
+                    Database::insert_file_metadata_batch(&mut self.tx, &inserts).await?;
+                    inserts.clear();
+                    Database::update_moved_metadata(&mut self.tx, metadata, old_path).await?
+                }
+                MetadataWrite::Trash(entry) => {
+                    Database::insert_file_metadata_batch(&mut self.tx, &inserts).await?;
+                    inserts.clear();
+                    Database::insert_trash_entry(&mut self.tx, entry).await?
+                }
+            }
+        }
+
+        Database::insert_file_metadata_batch(&mut self.tx, &inserts).await
+    }
+
+    pub async fn delete_file_metadata(&mut self, file_id: Uuid) -> Result<DeletedFiles> {
+        Database::delete_subtree(&mut self.tx, file_id).await
+    }
+
+    pub async fn delete_trash_entry(&mut self, entry_id: Uuid) -> Result<()> {
+        sqlx::query!("DELETE FROM trash WHERE id = ?1", entry_id)
+            .execute(&mut *self.tx)
+            .await?;
+
+        Ok(())
+    }
+
+    /// `Database::ensure_parent_directories` inside the transaction, so the
+    /// rows go with the rest of its writes
+    pub async fn ensure_parent_rows(&mut self, user_id: Uuid, path: &str) -> Result<Option<Uuid>> {
+        let parent = match parent_path(path) {
+            Some(parent) => parent,
+            None => return Ok(None),
+        };
+
+        let mut parent_id = None;
+        let mut current = String::new();
+
+        for component in parent.split('/').filter(|c| !c.is_empty()) {
+            current.push('/');
+            current.push_str(component);
+
+            parent_id = match self.get_file_metadata_by_path(user_id, &current).await? {
+                Some(existing) => Some(existing.id),
+                None => {
+                    let now = Utc::now();
+                    let directory = FileMetadata {
+                        id: Uuid::new_v4(),
+                        name: component.to_string(),
+                        path: current.clone(),
+                        size: 0,
+                        mime_type: "inode/directory".to_string(),
+                        checksum: String::new(),
+                        created_at: now,
+                        modified_at: now,
+                        owner_id: user_id,
+                        is_directory: true,
+                        is_symlink: false,
+                        parent_id,
+                        permissions: FilePermissions {
+                            read: true,
+                            write: true,
+                            delete: true,
+                            share: true,
+                        },
+                    };
+                    self.create_file_metadata(&directory).await?;
+                    Some(directory.id)
+                }
+            };
+        }
+
+        Ok(parent_id)
+    }
+
+    pub async fn commit(self) -> Result<()> {
+        self.tx.commit().await?;
+        self.database.changes_recorded();
+        Ok(())
+    }
+
+    /// Undo everything, as dropping it does, but waiting for it to be done
+    pub async fn rollback(self) -> Result<()> {
+        self.tx.tx.rollback().await?;
+        Ok(())
+    }
 }
 
 impl Database {
+    /// Connect with the default pool limits
     pub async fn new(database_url: &str) -> Result<Self> {
-        let pool = SqlitePool::connect(database_url).await?;
+        Self::connect(&DatabaseSettings {
+            url: database_url.to_string(),
+            max_connections: 10,
+            connection_timeout_seconds: 30,
+        })
+        .await
+    }
+
+    /// Connect with the configured pool limits and timeout
+    pub async fn connect(settings: &DatabaseSettings) -> Result<Self> {
+        let timeout = Duration::from_secs(settings.connection_timeout_seconds);
+        // WAL lets readers carry on while a write commits; writers wait for
+        // each other for up to the timeout instead of failing as locked
+        let options = SqliteConnectOptions::from_str(&settings.url)?
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(timeout);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(settings.max_connections)
+            .acquire_timeout(timeout)
+            .connect_with(options)
+            .await?;
         
         // Run migrations
         sqlx::migrate!("./migrations").run(&pool).await?;
         
-        Ok(Self { pool })
+        Ok(Self {
+            pool,
+            changes: Arc::new(watch::channel(()).0),
+            writes: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Fails when no connection frees up in time or SQLite can't answer
+    pub async fn health(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Write a consistent copy of the whole database to `path`, which must
+    /// not exist yet. Other readers and writers carry on meanwhile.
+    pub async fn backup_into(&self, path: &std::path::Path) -> Result<()> {
+        let path = path.to_str()
+            .ok_or_else(|| anyhow::anyhow!("Backup path {:?} is not valid UTF-8", path))?;
+        sqlx::query("VACUUM INTO ?1").bind(path).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Start a transaction that writes. SQLite fails, rather than waits, when
+    /// a transaction that has read tries to write after another one committed,
+    /// so write transactions take turns here before they begin. Waiting for a
+    /// turn is bounded like waiting for a connection.
+    async fn begin_write(&self) -> Result<WriteTransaction<'_>> {
+        let turn = match tokio::time::timeout(self.pool.options().get_acquire_timeout(), self.writes.lock()).await {
+            Ok(turn) => turn,
+            Err(_) => return Err(sqlx::Error::PoolTimedOut.into()),
+        };
+        let tx = self.pool.begin().await?;
+        Ok(WriteTransaction { tx, _turn: turn })
+    }
+
+    /// Start a transaction for writes that must land together
+    pub async fn begin(&self) -> Result<DatabaseTransaction<'_>> {
+        let tx = self.begin_write().await?;
+        Ok(DatabaseTransaction { database: self, tx })
+    }
+
+    /// Wakes on every change committed to the sync feed after subscribing,
+    /// for anyone's files
+    pub fn subscribe_changes(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    /// Called once changes are committed, so waiters find them when they look
+    fn changes_recorded(&self) {
+        self.changes.send_replace(());
     }
 
     pub async fn create_user(&self, user: &User) -> Result<()> {
@@ -39,18 +404,52 @@ impl Database {
         Ok(())
     }
 
-    pub async fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
+    /// Create a user provisioned from their MyCloud account
+    pub async fn create_mycloud_user(&self, user: &User) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO users (id, username, email, password_hash, created_at, last_login, is_active, permissions, mycloud_sourced)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 1)
+            "#,
+            user.id,
+            user.username,
+            user.email,
+            user.password_hash,
+            user.created_at,
+            user.last_login,
+            user.is_active,
+            serde_json::to_string(&user.permissions)?
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Whether the user was provisioned from MyCloud, so MyCloud may vouch
+    /// for their password
+    pub async fn is_mycloud_user(&self, user_id: Uuid) -> Result<bool> {
         let row = sqlx::query!(
-            "SELECT * FROM users WHERE username = ?1",
-            username
+            r#"SELECT mycloud_sourced as "mycloud_sourced!: bool" FROM users WHERE id = ?1"#,
+            user_id
         )
         .fetch_optional(&self.pool)
         .await?;
 
-        if let Some(row) = row {
+        Ok(row.is_some_and(|row| row.mycloud_sourced))
+    }
+
+    /// Every user provisioned from MyCloud
+    pub async fn get_mycloud_users(&self) -> Result<Vec<User>> {
+        let rows = sqlx::query!("SELECT * FROM users WHERE mycloud_sourced = 1 ORDER BY username")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut users = Vec::new();
+        for row in rows {
             let permissions: Vec<String> = serde_json::from_str(&row.permissions)?;
-            
-            Ok(Some(User {
+
+            users.push(User {
                 id: row.id,
                 username: row.username,
                 email: row.email,
@@ -58,44 +457,80 @@ impl Database {
                 created_at: row.created_at,
                 last_login: row.last_login,
                 is_active: row.is_active,
+                auth_generation: row.auth_generation,
                 permissions,
-            }))
-        } else {
-            Ok(None)
+            });
         }
+
+        Ok(users)
     }
 
-    pub async fn update_last_login(&self, user_id: Uuid, last_login: DateTime<Utc>) -> Result<()> {
-        sqlx::query!(
-            "UPDATE users SET last_login = ?1 WHERE id = ?2",
-            last_login,
+    /// Bring a user provisioned from MyCloud in line with their account
+    /// there. Deactivating moves them to a new auth generation like
+    /// `update_user` does. Users created here are left alone.
+    pub async fn update_mycloud_user(&self, user_id: Uuid, email: Option<&str>, is_active: bool, permissions: &[String]) -> Result<bool> {
+        let permissions = serde_json::to_string(permissions)?;
+        let result = sqlx::query!(
+            r#"
+            UPDATE users
+            SET email = ?1, permissions = ?2, is_active = ?3,
+                auth_generation = auth_generation + (CASE WHEN is_active AND NOT ?3 THEN 1 ELSE 0 END)
+            WHERE id = ?4 AND mycloud_sourced = 1
+            "#,
+            email,
+            permissions,
+            is_active,
             user_id
         )
         .execute(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 
-    pub async fn create_file_metadata(&self, metadata: &FileMetadata) -> Result<()> {
+    /// Create a user on their first OIDC login, linked to their identity
+    pub async fn create_oidc_user(&self, user: &User, issuer: &str, subject: &str) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+
         sqlx::query!(
             r#"
-            INSERT INTO file_metadata 
-            (id, name, path, size, mime_type, checksum, created_at, modified_at, owner_id, is_directory, parent_id, permissions)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+            INSERT INTO users (id, username, email, password_hash, created_at, last_login, is_active, permissions)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
             "#,
-            metadata.id,
-            metadata.name,
-            metadata.path,
-            metadata.size as i64,
-            metadata.mime_type,
-            metadata.checksum,
-            metadata.created_at,
-            metadata.modified_at,
-            metadata.owner_id,
-            metadata.is_directory,
-            metadata.parent_id,
-            serde_json::to_string(&metadata.permissions)?
+            user.id,
+            user.username,
+            user.email,
+            user.password_hash,
+            user.created_at,
+            user.last_login,
+            user.is_active,
+            serde_json::to_string(&user.permissions)?
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO oidc_identities (issuer, subject, user_id, provisioned, created_at) VALUES (?1, ?2, ?3, 1, ?4)",
+            issuer,
+            subject,
+            user.id,
+            user.created_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Let an OIDC identity log in as an existing user
+    pub async fn link_oidc_identity(&self, issuer: &str, subject: &str, user_id: Uuid, at: DateTime<Utc>) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO oidc_identities (issuer, subject, user_id, provisioned, created_at) VALUES (?1, ?2, ?3, 0, ?4)",
+            issuer,
+            subject,
+            user_id,
+            at
         )
         .execute(&self.pool)
         .await?;
@@ -103,29 +538,51 @@ impl Database {
         Ok(())
     }
 
-    pub async fn get_file_metadata(&self, file_id: Uuid) -> Result<Option<FileMetadata>> {
+    /// The user an OIDC identity logs in as, and whether it was created for it
+    pub async fn get_oidc_identity(&self, issuer: &str, subject: &str) -> Result<Option<(Uuid, bool)>> {
         let row = sqlx::query!(
-            "SELECT * FROM file_metadata WHERE id = ?1",
-            file_id
+            r#"
+            SELECT user_id, provisioned as "provisioned!: bool"
+            FROM oidc_identities WHERE issuer = ?1 AND subject = ?2
+            "#,
+            issuer,
+            subject
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| (row.user_id, row.provisioned)))
+    }
+
+    /// Users with this email address, ignoring case
+    pub async fn get_user_ids_by_email(&self, email: &str) -> Result<Vec<Uuid>> {
+        let rows = sqlx::query!("SELECT id FROM users WHERE email = ?1 COLLATE NOCASE", email)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|row| row.id).collect())
+    }
+
+    pub async fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
+        let row = sqlx::query!(
+            "SELECT * FROM users WHERE username = ?1",
+            username
         )
         .fetch_optional(&self.pool)
         .await?;
 
         if let Some(row) = row {
-            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+            let permissions: Vec<String> = serde_json::from_str(&row.permissions)?;
             
-            Ok(Some(FileMetadata {
+            Ok(Some(User {
                 id: row.id,
-                name: row.name,
-                path: row.path,
-                size: row.size as u64,
-                mime_type: row.mime_type,
-                checksum: row.checksum,
+                username: row.username,
+                email: row.email,
+                password_hash: row.password_hash,
                 created_at: row.created_at,
-                modified_at: row.modified_at,
-                owner_id: row.owner_id,
-                is_directory: row.is_directory,
-                parent_id: row.parent_id,
+                last_login: row.last_login,
+                is_active: row.is_active,
+                auth_generation: row.auth_generation,
                 permissions,
             }))
         } else {
@@ -133,91 +590,116 @@ impl Database {
         }
     }
 
-    pub async fn list_files_in_directory(&self, parent_id: Option<Uuid>, owner_id: Uuid) -> Result<Vec<FileMetadata>> {
-        let rows = sqlx::query!(
-            "SELECT * FROM file_metadata WHERE parent_id = ?1 AND owner_id = ?2 ORDER BY name",
-            parent_id,
-            owner_id
+    pub async fn get_user_by_id(&self, user_id: Uuid) -> Result<Option<User>> {
+        let row = sqlx::query!(
+            "SELECT * FROM users WHERE id = ?1",
+            user_id
         )
-        .fetch_all(&self.pool)
+        .fetch_optional(&self.pool)
         .await?;
 
-        let mut files = Vec::new();
-        for row in rows {
-            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
-            
-            files.push(FileMetadata {
+        match row {
+            Some(row) => Ok(Some(User {
                 id: row.id,
-                name: row.name,
-                path: row.path,
-                size: row.size as u64,
-                mime_type: row.mime_type,
-                checksum: row.checksum,
+                username: row.username,
+                email: row.email,
+                password_hash: row.password_hash,
                 created_at: row.created_at,
-                modified_at: row.modified_at,
-                owner_id: row.owner_id,
-                is_directory: row.is_directory,
-                parent_id: row.parent_id,
-                permissions,
-            });
+                last_login: row.last_login,
+                is_active: row.is_active,
+                auth_generation: row.auth_generation,
+                permissions: serde_json::from_str(&row.permissions)?,
+            })),
+            None => Ok(None),
         }
-
-        Ok(files)
     }
 
-    pub async fn create_sync_session(&self, session: &SyncSession) -> Result<()> {
-        sqlx::query!(
+    /// Set a new password hash and whether the user has to replace it at
+    /// their next login. Every token issued up to `at` stops working, as
+    /// does every token of an earlier auth generation.
+    /// Returns false if there is no such user.
+    pub async fn update_password_hash(
+        &self,
+        user_id: Uuid,
+        password_hash: &str,
+        must_change_password: bool,
+        at: DateTime<Utc>,
+    ) -> Result<bool> {
+        let mut tx = self.begin_write().await?;
+
+        let updated = sqlx::query!(
             r#"
-            INSERT INTO sync_sessions (id, user_id, device_id, device_name, last_sync, sync_folders, is_active)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            UPDATE users
+            SET password_hash = ?1, must_change_password = ?2, tokens_valid_after = ?3,
+                auth_generation = auth_generation + 1
+            WHERE id = ?4
             "#,
-            session.id,
-            session.user_id,
-            session.device_id,
-            session.device_name,
-            session.last_sync,
-            serde_json::to_string(&session.sync_folders)?,
-            session.is_active
+            password_hash,
+            must_change_password,
+            at,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!("DELETE FROM refresh_tokens WHERE user_id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("DELETE FROM sessions WHERE user_id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(updated.rows_affected() > 0)
+    }
+
+    /// Swap a hash for one of the same password made another way, unless
+    /// the password changed since `old_hash` was read
+    pub async fn rehash_password(&self, user_id: Uuid, old_hash: &str, new_hash: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE users SET password_hash = ?1 WHERE id = ?2 AND password_hash = ?3",
+            new_hash,
+            user_id,
+            old_hash
         )
         .execute(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 
-    pub async fn get_sync_session(&self, user_id: Uuid, device_id: &str) -> Result<Option<SyncSession>> {
+    pub async fn must_change_password(&self, user_id: Uuid) -> Result<bool> {
         let row = sqlx::query!(
-            "SELECT * FROM sync_sessions WHERE user_id = ?1 AND device_id = ?2",
-            user_id,
-            device_id
+            r#"SELECT must_change_password as "must_change_password!: bool" FROM users WHERE id = ?1"#,
+            user_id
         )
         .fetch_optional(&self.pool)
         .await?;
 
-        if let Some(row) = row {
-            let sync_folders: Vec<String> = serde_json::from_str(&row.sync_folders)?;
-            
-            Ok(Some(SyncSession {
-                id: row.id,
-                user_id: row.user_id,
-                device_id: row.device_id,
-                device_name: row.device_name,
-                last_sync: row.last_sync,
-                sync_folders,
-                is_active: row.is_active,
-            }))
-        } else {
-            Ok(None)
-        }
+        Ok(row.is_some_and(|row| row.must_change_password))
     }
 
-    pub async fn update_sync_session(&self, session: &SyncSession) -> Result<()> {
+    pub async fn get_two_factor(&self, user_id: Uuid) -> Result<TwoFactorState> {
+        let row = sqlx::query!(
+            r#"SELECT totp_secret, totp_enabled as "totp_enabled!: bool" FROM users WHERE id = ?1"#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(match row {
+            Some(row) => TwoFactorState { secret: row.totp_secret, enabled: row.totp_enabled },
+            None => TwoFactorState { secret: None, enabled: false },
+        })
+    }
+
+    /// Store a secret awaiting confirmation, replacing any earlier unconfirmed one
+    pub async fn set_pending_totp_secret(&self, user_id: Uuid, encrypted_secret: &str) -> Result<()> {
         sqlx::query!(
-            "UPDATE sync_sessions SET last_sync = ?1, sync_folders = ?2, is_active = ?3 WHERE id = ?4",
-            session.last_sync,
-            serde_json::to_string(&session.sync_folders)?,
-            session.is_active,
-            session.id
+            "UPDATE users SET totp_secret = ?1 WHERE id = ?2 AND totp_enabled = 0",
+            encrypted_secret,
+            user_id
         )
         .execute(&self.pool)
         .await?;
@@ -225,22 +707,76 @@ impl Database {
         Ok(())
     }
 
-    pub async fn create_share_link(&self, share_link: &ShareLink) -> Result<()> {
-        sqlx::query!(
+    /// Start requiring codes at login, with a fresh set of recovery codes
+    pub async fn enable_two_factor(&self, user_id: Uuid, recovery_code_hashes: &[String]) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+
+        sqlx::query!("UPDATE users SET totp_enabled = 1 WHERE id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        for code_hash in recovery_code_hashes {
+            let id = Uuid::new_v4();
+            sqlx::query!(
+                "INSERT INTO recovery_codes (id, user_id, code_hash) VALUES (?1, ?2, ?3)",
+                id,
+                user_id,
+                code_hash
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Drop the secret and recovery codes. Returns false if there is no such user.
+    pub async fn disable_two_factor(&self, user_id: Uuid) -> Result<bool> {
+        let mut tx = self.begin_write().await?;
+
+        let updated = sqlx::query!(
+            "UPDATE users SET totp_secret = NULL, totp_enabled = 0 WHERE id = ?1",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(updated.rows_affected() > 0)
+    }
+
+    /// Spend a recovery code. Returns false if it is unknown or already used.
+    pub async fn use_recovery_code(&self, user_id: Uuid, code_hash: &str) -> Result<bool> {
+        let now = Utc::now();
+        let result = sqlx::query!(
             r#"
-            INSERT INTO share_links 
-            (id, file_id, created_by, share_token, expires_at, password_protected, download_count, max_downloads, created_at)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            UPDATE recovery_codes SET used_at = ?1
+            WHERE user_id = ?2 AND code_hash = ?3 AND used_at IS NULL
             "#,
-            share_link.id,
-            share_link.file_id,
-            share_link.created_by,
-            share_link.share_token,
-            share_link.expires_at,
-            share_link.password_protected,
-            share_link.download_count as i32,
-            share_link.max_downloads.map(|x| x as i32),
-            share_link.created_at
+            now,
+            user_id,
+            code_hash
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn update_last_login(&self, user_id: Uuid, last_login: DateTime<Utc>) -> Result<()> {
+        sqlx::query!(
+            "UPDATE users SET last_login = ?1 WHERE id = ?2",
+            last_login,
+            user_id
         )
         .execute(&self.pool)
         .await?;
@@ -248,53 +784,336 @@ impl Database {
         Ok(())
     }
 
-    pub async fn get_share_link_by_token(&self, token: &str) -> Result<Option<ShareLink>> {
+    /// The user's own quota in bytes, `None` when they use the server default
+    pub async fn get_user_quota(&self, user_id: Uuid) -> Result<Option<u64>> {
         let row = sqlx::query!(
-            "SELECT * FROM share_links WHERE share_token = ?1",
-            token
+            "SELECT quota_bytes FROM users WHERE id = ?1",
+            user_id
         )
         .fetch_optional(&self.pool)
         .await?;
 
-        if let Some(row) = row {
-            Ok(Some(ShareLink {
+        Ok(row.and_then(|row| row.quota_bytes).map(|quota| quota as u64))
+    }
+
+    /// Set or clear a user's quota. Returns false if the user doesn't exist.
+    pub async fn set_user_quota(&self, user_id: Uuid, quota_bytes: Option<u64>) -> Result<bool> {
+        let quota_bytes = quota_bytes.map(|quota| quota as i64);
+        let result = sqlx::query!(
+            "UPDATE users SET quota_bytes = ?1 WHERE id = ?2",
+            quota_bytes,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Whether a user is active and their auth generation, or None if they
+    /// are gone. Checked against every token, so it reads as little as it can.
+    pub async fn get_user_status(&self, user_id: Uuid) -> Result<Option<(bool, i64)>> {
+        let row = sqlx::query!(
+            r#"SELECT is_active as "is_active!: bool", auth_generation FROM users WHERE id = ?1"#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| (row.is_active, row.auth_generation)))
+    }
+
+    /// Users by name, a page at a time
+    /// Accounts by username, from after `after` when given
+    pub async fn list_users(&self, after: Option<&str>, limit: i64, offset: i64) -> Result<Vec<UserAccount>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, username, email, created_at, last_login, is_active, permissions, quota_bytes
+            FROM users
+            WHERE username > COALESCE(?1, '')
+            ORDER BY username
+            LIMIT ?2 OFFSET ?3
+            "#,
+            after,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut users = Vec::new();
+        for row in rows {
+            users.push(UserAccount {
                 id: row.id,
-                file_id: row.file_id,
-                created_by: row.created_by,
-                share_token: row.share_token,
-                expires_at: row.expires_at,
-                password_protected: row.password_protected,
-                download_count: row.download_count as u32,
-                max_downloads: row.max_downloads.map(|x| x as u32),
+                username: row.username,
+                email: row.email,
                 created_at: row.created_at,
-            }))
-        } else {
-            Ok(None)
+                last_login: row.last_login,
+                is_active: row.is_active,
+                permissions: serde_json::from_str(&row.permissions)?,
+                quota_bytes: row.quota_bytes.map(|quota| quota as u64),
+            });
         }
+
+        Ok(users)
     }
 
-    pub async fn get_files_changed_since(&self, user_id: Uuid, since: DateTime<Utc>) -> Result<Vec<FileChange>> {
-        let rows = sqlx::query!(
+    pub async fn count_users(&self) -> Result<u64> {
+        let row = sqlx::query!(r#"SELECT COUNT(*) as "count!: i64" FROM users"#)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.count as u64)
+    }
+
+    /// Totals for the admin status page, in one pass. Users count as active
+    /// when seen since `active_since`; uploads and syncs since `recent_since`
+    /// are counted.
+    pub async fn get_global_stats(&self, active_since: DateTime<Utc>, recent_since: DateTime<Utc>) -> Result<GlobalStats> {
+        let now = Utc::now();
+        let row = sqlx::query!(
+            r#"
+            SELECT
+                (SELECT COUNT(*) FROM users) AS "users!: i64",
+                (SELECT COUNT(*) FROM users
+                 WHERE last_login >= ?1
+                    OR id IN (SELECT user_id FROM sessions WHERE last_active_at >= ?1)
+                    OR id IN (SELECT user_id FROM devices WHERE last_seen_at >= ?1)) AS "active_users!: i64",
+                (SELECT COUNT(*) FROM file_metadata
+                 WHERE is_directory = 0 AND deleted_at IS NULL) AS "files!: i64",
+                (SELECT COALESCE(SUM(size), 0) FROM file_metadata
+                 WHERE is_directory = 0 AND deleted_at IS NULL) AS "logical_bytes!: i64",
+                (SELECT COUNT(*) FROM change_log
+                 WHERE changed_at >= ?2 AND change_type IN ('Created', 'Modified')
+                   AND NOT EXISTS (SELECT 1 FROM file_metadata
+                                   WHERE file_metadata.id = change_log.file_id AND is_directory = 1)) AS "uploads!: i64",
+                (SELECT COUNT(*) FROM share_links
+                 WHERE revoked_at IS NULL
+                   AND (expires_at IS NULL OR expires_at > ?3)
+                   AND (max_downloads IS NULL OR download_count < max_downloads)
+                   AND file_id IN (SELECT id FROM file_metadata WHERE deleted_at IS NULL)) AS "share_links!: i64",
+                (SELECT COUNT(*) FROM user_shares
+                 WHERE file_id IN (SELECT id FROM file_metadata WHERE deleted_at IS NULL)) AS "user_shares!: i64",
+                (SELECT COUNT(*) FROM sync_sessions
+                 WHERE is_active = 1 AND last_sync >= ?2) AS "sync_sessions!: i64"
+            "#,
+            active_since,
+            recent_since,
+            now
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(GlobalStats {
+            users: row.users as u64,
+            active_users: row.active_users as u64,
+            files: row.files as u64,
+            logical_bytes: row.logical_bytes as u64,
+            uploads_last_day: row.uploads as u64,
+            active_share_links: row.share_links as u64,
+            user_shares: row.user_shares as u64,
+            recent_sync_sessions: row.sync_sessions as u64,
+        })
+    }
+
+    pub async fn get_user_account(&self, user_id: Uuid) -> Result<Option<UserAccount>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT id, username, email, created_at, last_login, is_active, permissions, quota_bytes
+            FROM users WHERE id = ?1
+            "#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => Ok(Some(UserAccount {
+                id: row.id,
+                username: row.username,
+                email: row.email,
+                created_at: row.created_at,
+                last_login: row.last_login,
+                is_active: row.is_active,
+                permissions: serde_json::from_str(&row.permissions)?,
+                quota_bytes: row.quota_bytes.map(|quota| quota as u64),
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Apply what an admin changed about a user. Returns false if there is no such user.
+    pub async fn update_user(&self, user_id: Uuid, update: &UpdateUserRequest) -> Result<bool> {
+        let mut tx = self.begin_write().await?;
+
+        let exists = sqlx::query!("SELECT id FROM users WHERE id = ?1", user_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+        if !exists {
+            return Ok(false);
+        }
+
+        if let Some(is_active) = update.is_active {
+            // Deactivating moves the user to a new auth generation, so tokens
+            // issued before stay refused if they are turned back on
+            sqlx::query!(
+                r#"
+                UPDATE users
+                SET is_active = ?1, auth_generation = auth_generation + (CASE WHEN ?1 THEN 0 ELSE 1 END)
+                WHERE id = ?2
+                "#,
+                is_active,
+                user_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        if let Some(permissions) = &update.permissions {
+            let names: Vec<&str> = permissions.iter().map(|permission| permission.as_str()).collect();
+            let permissions = serde_json::to_string(&names)?;
+            sqlx::query!("UPDATE users SET permissions = ?1 WHERE id = ?2", permissions, user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        if let Some(quota_bytes) = update.quota_bytes {
+            let quota_bytes = quota_bytes.map(|quota| quota as i64);
+            sqlx::query!("UPDATE users SET quota_bytes = ?1 WHERE id = ?2", quota_bytes, user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Remove a user. Files still on record for them, and the versions and
+    /// share links they made, go to `heir`, with `path_prefix` put in front
+    /// of the paths when they move to another home. Where `heir` already
+    /// has a row at a path, theirs is kept and the user's dropped. Their
+    /// trash, sync history, sessions and tokens go with them. Returns None
+    /// if there is no such user.
+    pub async fn delete_user(&self, user_id: Uuid, heir: Uuid, path_prefix: Option<&str>) -> Result<Option<DeletedFiles>> {
+        let mut tx = self.begin_write().await?;
+        let path_prefix = path_prefix.unwrap_or("");
+        let mut deleted = DeletedFiles::default();
+
+        let trashed = sqlx::query!(
+            r#"SELECT file_id as "file_id!: Uuid" FROM trash WHERE user_id = ?1 AND file_id IS NOT NULL"#,
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for row in trashed {
+            let subtree = Self::delete_subtree(&mut tx, row.file_id).await?;
+            deleted.rows += subtree.rows;
+            deleted.unused_versions.extend(subtree.unused_versions);
+        }
+
+        // Rows the heir already has stand for the same entries; whatever
+        // hangs below the user's copies moves under the heir's
+        let clashes = sqlx::query!(
             r#"
-            SELECT fm.*, 'Modified' as change_type 
-            FROM file_metadata fm 
-            WHERE fm.owner_id = ?1 AND fm.modified_at > ?2
-            ORDER BY fm.modified_at
+            SELECT id FROM file_metadata
+            WHERE owner_id = ?1 AND ?2 || path IN (SELECT path FROM file_metadata WHERE owner_id = ?3)
+            ORDER BY path DESC
             "#,
             user_id,
-            since
+            path_prefix,
+            heir
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        if !clashes.is_empty() {
+            sqlx::query!(
+                r#"
+                UPDATE file_metadata AS child SET parent_id = (
+                    SELECT kept.id FROM file_metadata parent
+                    JOIN file_metadata kept ON kept.owner_id = ?3 AND kept.path = ?2 || parent.path
+                    WHERE parent.id = child.parent_id
+                )
+                WHERE child.owner_id = ?1 AND child.parent_id IN (
+                    SELECT id FROM file_metadata
+                    WHERE owner_id = ?1 AND ?2 || path IN (SELECT path FROM file_metadata WHERE owner_id = ?3)
+                )
+                "#,
+                user_id,
+                path_prefix,
+                heir
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            let ids: Vec<Uuid> = clashes.into_iter().map(|row| row.id).collect();
+            let dropped = Self::delete_rows(&mut tx, &ids).await?;
+            deleted.rows += dropped.rows;
+            deleted.unused_versions.extend(dropped.unused_versions);
+        }
+
+        sqlx::query!(
+            "UPDATE file_metadata SET owner_id = ?1, path = ?2 || path WHERE owner_id = ?3",
+            heir,
+            path_prefix,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!("UPDATE file_versions SET author_id = ?1 WHERE author_id = ?2", heir, user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("UPDATE share_links SET created_by = ?1 WHERE created_by = ?2", heir, user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("DELETE FROM change_log WHERE owner_id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("DELETE FROM trash WHERE user_id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("DELETE FROM upload_sessions WHERE user_id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("DELETE FROM sync_sessions WHERE user_id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        // Tokens, devices, locks, stars and tags cascade
+        let result = sqlx::query!("DELETE FROM users WHERE id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        tx.commit().await?;
+        Ok(Some(deleted))
+    }
+
+    /// Every file and directory on record as the user's, parents before children
+    pub async fn get_files_owned_by(&self, user_id: Uuid) -> Result<Vec<FileMetadata>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM file_metadata WHERE owner_id = ?1 AND deleted_at IS NULL ORDER BY path",
+            user_id
         )
         .fetch_all(&self.pool)
         .await?;
 
-        let mut changes = Vec::new();
+        let mut files = Vec::new();
         for row in rows {
             let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
-            
-            let metadata = FileMetadata {
+
+            files.push(FileMetadata {
                 id: row.id,
                 name: row.name,
-                path: row.path.clone(),
+                path: row.path,
                 size: row.size as u64,
                 mime_type: row.mime_type,
                 checksum: row.checksum,
@@ -302,19 +1121,4626 @@ impl Database {
                 modified_at: row.modified_at,
                 owner_id: row.owner_id,
                 is_directory: row.is_directory,
+                is_symlink: false,
                 parent_id: row.parent_id,
                 permissions,
-            };
+            });
+        }
 
-            changes.push(FileChange {
-                file_id: row.id,
-                change_type: ChangeType::Modified,
+        Ok(files)
+    }
+
+    /// Like `get_files_owned_by`, with the rows of trashed entries as well,
+    /// at their places in the trash
+    pub async fn get_files_owned_by_including_deleted(&self, user_id: Uuid) -> Result<Vec<FileMetadata>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM file_metadata WHERE owner_id = ?1 ORDER BY path",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
                 path: row.path,
-                metadata: Some(metadata),
-                timestamp: row.modified_at,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
             });
         }
 
-        Ok(changes)
+        Ok(files)
+    }
+
+    /// Total size of the files a user owns, including ones sitting in the trash
+    pub async fn get_user_storage_usage(&self, user_id: Uuid) -> Result<u64> {
+        let row = sqlx::query!(
+            r#"SELECT COALESCE(SUM(size), 0) as "used!: i64" FROM file_metadata WHERE owner_id = ?1 AND is_directory = 0"#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.used as u64)
+    }
+
+    pub async fn create_file_metadata(&self, metadata: &FileMetadata) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.create_file_metadata(metadata).await?;
+        tx.commit().await
+    }
+
+    /// Record `metadata` at its path, or update the size, type, checksum and
+    /// modification time of the row already there. An existing row keeps its
+    /// id and creation time, which are copied into `metadata`, and the sync
+    /// feed sees an edit instead of a new file.
+    pub async fn upsert_file_metadata(&self, metadata: &mut FileMetadata) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+        Self::store_file_metadata(&mut tx, metadata).await?;
+        tx.commit().await?;
+        self.changes_recorded();
+        Ok(())
+    }
+
+    async fn store_file_metadata(conn: &mut SqliteConnection, metadata: &mut FileMetadata) -> Result<()> {
+        let existing = sqlx::query!(
+            "SELECT id, created_at FROM file_metadata WHERE owner_id = ?1 AND path = ?2",
+            metadata.owner_id,
+            metadata.path
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some(existing) = &existing {
+            metadata.id = existing.id;
+            metadata.created_at = existing.created_at;
+        }
+
+        sqlx::query!(
+            r#"
+            INSERT INTO file_metadata
+            (id, name, path, size, mime_type, checksum, created_at, modified_at, owner_id, is_directory, parent_id, permissions)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+            ON CONFLICT (owner_id, path) DO UPDATE SET
+                size = excluded.size,
+                mime_type = excluded.mime_type,
+                checksum = excluded.checksum,
+                modified_at = excluded.modified_at
+            "#,
+            metadata.id,
+            metadata.name,
+            metadata.path,
+            metadata.size as i64,
+            metadata.mime_type,
+            metadata.checksum,
+            metadata.created_at,
+            metadata.modified_at,
+            metadata.owner_id,
+            metadata.is_directory,
+            metadata.parent_id,
+            serde_json::to_string(&metadata.permissions)?
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        let change_type = match existing {
+            Some(_) => ChangeType::Modified,
+            None => ChangeType::Created,
+        };
+        Self::insert_change(conn, metadata.owner_id, metadata.id, change_type, &metadata.path, None, Utc::now()).await
+    }
+
+    /// Insert several rows in one transaction, so either all are recorded or
+    /// none. Rows go in hundreds to a statement, which is what makes
+    /// importing a large library bearable.
+    pub async fn create_file_metadata_batch(&self, entries: &[FileMetadata]) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+        let entries: Vec<&FileMetadata> = entries.iter().collect();
+        Self::insert_file_metadata_batch(&mut tx, &entries).await?;
+        tx.commit().await?;
+        self.changes_recorded();
+        Ok(())
+    }
+
+    /// Apply deferred writes in order inside a single transaction
+    pub async fn apply_metadata_writes(&self, writes: &[MetadataWrite]) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.apply_metadata_writes(writes).await?;
+        tx.commit().await
+    }
+
+    /// Insert a row and record its creation for the sync feed
+    async fn insert_file_metadata(conn: &mut SqliteConnection, metadata: &FileMetadata) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO file_metadata
+            (id, name, path, size, mime_type, checksum, created_at, modified_at, owner_id, is_directory, parent_id, permissions)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+            "#,
+            metadata.id,
+            metadata.name,
+            metadata.path,
+            metadata.size as i64,
+            metadata.mime_type,
+            metadata.checksum,
+            metadata.created_at,
+            metadata.modified_at,
+            metadata.owner_id,
+            metadata.is_directory,
+            metadata.parent_id,
+            serde_json::to_string(&metadata.permissions)?
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Self::insert_change(conn, metadata.owner_id, metadata.id, ChangeType::Created, &metadata.path, None, Utc::now()).await
+    }
+
+    /// Insert rows and record their creation like `insert_file_metadata`,
+    /// `METADATA_INSERT_BATCH` to a statement
+    async fn insert_file_metadata_batch(conn: &mut SqliteConnection, entries: &[&FileMetadata]) -> Result<()> {
+        let change_type = format!("{:?}", ChangeType::Created);
+        let actor = ChangeActor::current();
+        let (actor_id, device_id) = (actor.as_ref().map(|a| a.user_id), actor.and_then(|a| a.device_id));
+        let changed_at = Utc::now();
+
+        for chunk in entries.chunks(METADATA_INSERT_BATCH) {
+            let permissions = chunk.iter()
+                .map(|metadata| serde_json::to_string(&metadata.permissions))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut query = QueryBuilder::<Sqlite>::new(
+                "INSERT INTO file_metadata \
+                (id, name, path, size, mime_type, checksum, created_at, modified_at, owner_id, is_directory, parent_id, permissions) ",
+            );
+            query.push_values(chunk.iter().zip(permissions), |mut row, (metadata, permissions)| {
+                row.push_bind(metadata.id)
+                    .push_bind(metadata.name.clone())
+                    .push_bind(metadata.path.clone())
+                    .push_bind(metadata.size as i64)
+                    .push_bind(metadata.mime_type.clone())
+                    .push_bind(metadata.checksum.clone())
+                    .push_bind(metadata.created_at)
+                    .push_bind(metadata.modified_at)
+                    .push_bind(metadata.owner_id)
+                    .push_bind(metadata.is_directory)
+                    .push_bind(metadata.parent_id)
+                    .push_bind(permissions);
+            });
+            query.build().execute(&mut *conn).await?;
+
+            let mut query = QueryBuilder::<Sqlite>::new(
+                "INSERT INTO change_log (id, owner_id, file_id, change_type, path, old_path, changed_at, actor_id, device_id) ",
+            );
+            query.push_values(chunk, |mut row, metadata| {
+                row.push_bind(Uuid::new_v4())
+                    .push_bind(metadata.owner_id)
+                    .push_bind(metadata.id)
+                    .push_bind(change_type.clone())
+                    .push_bind(metadata.path.clone())
+                    .push_bind(None::<String>)
+                    .push_bind(changed_at)
+                    .push_bind(actor_id)
+                    .push_bind(device_id.clone());
+            });
+            query.build().execute(&mut *conn).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_file_metadata(&self, file_id: Uuid) -> Result<Option<FileMetadata>> {
+        let row = sqlx::query!(
+            "SELECT * FROM file_metadata WHERE id = ?1 AND deleted_at IS NULL",
+            file_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if let Some(row) = row {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+            
+            Ok(Some(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The row recorded for `path`; each owner has at most one per path
+    pub async fn get_file_metadata_by_path(&self, owner_id: Uuid, path: &str) -> Result<Option<FileMetadata>> {
+        let mut conn = self.pool.acquire().await?;
+        Self::fetch_file_metadata_by_path(&mut conn, owner_id, path).await
+    }
+
+    async fn fetch_file_metadata_by_path(
+        conn: &mut SqliteConnection,
+        owner_id: Uuid,
+        path: &str,
+    ) -> Result<Option<FileMetadata>> {
+        let row = sqlx::query!(
+            "SELECT * FROM file_metadata WHERE owner_id = ?1 AND path = ?2 AND deleted_at IS NULL",
+            owner_id,
+            path
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        if let Some(row) = row {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            Ok(Some(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Whether anyone but `user_id` has a row at `path`. Only tells something
+    /// in a flat tree, where all users' paths are the same files.
+    pub async fn is_path_owned_by_other(&self, user_id: Uuid, path: &str) -> Result<bool> {
+        let row = sqlx::query!(
+            r#"SELECT EXISTS(SELECT 1 FROM file_metadata WHERE path = ?1 AND owner_id != ?2 AND deleted_at IS NULL) as "owned!: bool""#,
+            path,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.owned)
+    }
+
+    /// Make sure every directory above `path` has a metadata row, creating rows
+    /// for intermediate directories as needed, and return the immediate parent's id.
+    pub async fn ensure_parent_directories(&self, user_id: Uuid, path: &str) -> Result<Option<Uuid>> {
+        let mut tx = self.begin().await?;
+        let parent_id = tx.ensure_parent_rows(user_id, path).await?;
+        tx.commit().await?;
+        Ok(parent_id)
+    }
+
+    /// Replace the throwaway ids that the filesystem layer generates with the ids
+    /// recorded in `file_metadata`, so the same file keeps its id across calls.
+    /// Entries seen for the first time are recorded with a freshly minted id.
+    pub async fn assign_stable_ids(&self, user_id: Uuid, files: &mut [FileMetadata]) -> Result<()> {
+        let paths: Vec<String> = files.iter().map(|f| f.path.clone()).collect();
+        let known = self.get_file_ids_by_paths(user_id, &paths).await?;
+
+        // Entries of one listing usually share a parent, so resolve each parent once
+        let mut parent_ids: HashMap<String, Option<Uuid>> = HashMap::new();
+
+        for file in files.iter_mut() {
+            file.owner_id = user_id;
+
+            let parent = parent_path(&file.path).unwrap_or("").to_string();
+            file.parent_id = match parent_ids.get(&parent) {
+                Some(parent_id) => *parent_id,
+                None => {
+                    let parent_id = self.ensure_parent_directories(user_id, &file.path).await?;
+                    parent_ids.insert(parent, parent_id);
+                    parent_id
+                }
+            };
+
+            match known.get(&file.path) {
+                Some((id, checksum)) => {
+                    file.id = *id;
+                    // Fast listings skip hashing; fall back to the checksum stored at upload
+                    if file.checksum.is_empty() {
+                        file.checksum = checksum.clone();
+                    }
+                }
+                None => self.create_file_metadata(file).await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Filesystem metadata for `path` carrying its stable id
+    pub async fn resolve_file_metadata(
+        &self,
+        filesystem: &FileSystemService,
+        user_id: Uuid,
+        path: &str,
+    ) -> Result<FileMetadata> {
+        let mut metadata = filesystem.get_file_metadata(path).await?;
+        self.assign_stable_ids(user_id, std::slice::from_mut(&mut metadata)).await?;
+        Ok(metadata)
+    }
+
+    /// Ids and stored checksums of tracked entries at `paths`, keyed by path
+    pub async fn get_file_ids_by_paths(
+        &self,
+        owner_id: Uuid,
+        paths: &[String],
+    ) -> Result<HashMap<String, (Uuid, String)>> {
+        let mut found = HashMap::new();
+
+        for chunk in paths.chunks(MAX_BIND_PARAMS) {
+            let mut query = QueryBuilder::<Sqlite>::new(
+                "SELECT id, path, checksum FROM file_metadata WHERE owner_id = ",
+            );
+            query.push_bind(owner_id);
+            query.push(" AND deleted_at IS NULL AND path IN (");
+            let mut separated = query.separated(", ");
+            for path in chunk {
+                separated.push_bind(path);
+            }
+            separated.push_unseparated(")");
+
+            for row in query.build().fetch_all(&self.pool).await? {
+                let id: Uuid = row.try_get("id")?;
+                let path: String = row.try_get("path")?;
+                let checksum: String = row.try_get("checksum")?;
+                found.insert(path, (id, checksum));
+            }
+        }
+
+        Ok(found)
+    }
+
+    pub async fn update_file_metadata(&self, metadata: &FileMetadata) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE file_metadata
+            SET name = ?1, path = ?2, size = ?3, mime_type = ?4, checksum = ?5, modified_at = ?6, parent_id = ?7
+            WHERE id = ?8
+            "#,
+            metadata.name,
+            metadata.path,
+            metadata.size as i64,
+            metadata.mime_type,
+            metadata.checksum,
+            metadata.modified_at,
+            metadata.parent_id,
+            metadata.id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        Self::insert_row_change(&mut tx, metadata.id, ChangeType::Modified).await?;
+
+        tx.commit().await?;
+        self.changes_recorded();
+        Ok(())
+    }
+
+    pub async fn list_files_in_directory(&self, parent_id: Option<Uuid>, owner_id: Uuid) -> Result<Vec<FileMetadata>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM file_metadata WHERE parent_id = ?1 AND owner_id = ?2 AND deleted_at IS NULL ORDER BY name",
+            parent_id,
+            owner_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+            
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        Ok(files)
+    }
+
+    pub async fn get_children(&self, parent_id: Uuid) -> Result<Vec<FileMetadata>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM file_metadata WHERE parent_id = ?1 AND deleted_at IS NULL ORDER BY is_directory DESC, name",
+            parent_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// Point `metadata.id` at its new location, rewrite the paths of every
+    /// row below it and record the move for the sync feed
+    pub async fn move_file_metadata(&self, metadata: &FileMetadata, old_path: &str) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+        Self::update_moved_metadata(&mut tx, metadata, old_path).await?;
+        tx.commit().await?;
+        self.changes_recorded();
+        Ok(())
+    }
+
+    async fn update_moved_metadata(
+        conn: &mut SqliteConnection,
+        metadata: &FileMetadata,
+        old_path: &str,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE file_metadata SET name = ?1, path = ?2, parent_id = ?3, modified_at = ?4 WHERE id = ?5",
+            metadata.name,
+            metadata.path,
+            metadata.parent_id,
+            metadata.modified_at,
+            metadata.id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        // Descendants move along without entries of their own; clients
+        // rename the folder and what it holds comes with it
+        if metadata.is_directory {
+            Self::rename_path_prefix(conn, metadata.owner_id, old_path, &metadata.path).await?;
+        }
+
+        Self::insert_change(
+            conn,
+            metadata.owner_id,
+            metadata.id,
+            ChangeType::Moved,
+            &metadata.path,
+            Some(old_path),
+            metadata.modified_at,
+        )
+        .await
+    }
+
+    /// Rewrite the paths of `owner_id`'s rows below `old_prefix` to sit
+    /// below `new_prefix` instead. Only whole components match, so moving
+    /// `/photos` leaves `/photos-raw` alone.
+    async fn rename_path_prefix(conn: &mut SqliteConnection, owner_id: Uuid, old_prefix: &str, new_prefix: &str) -> Result<()> {
+        let (lower, upper) = descendant_range(old_prefix);
+        let prefix_len = lower.chars().count() as i64;
+        let new_prefix = format!("{}/", new_prefix.trim_end_matches('/'));
+
+        sqlx::query!(
+            r#"
+            UPDATE file_metadata
+            SET path = ?1 || substr(path, ?2 + 1)
+            WHERE owner_id = ?3 AND path >= ?4 AND path < ?5
+            "#,
+            new_prefix,
+            prefix_len,
+            owner_id,
+            lower,
+            upper
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record that a tracked file came back from the trash
+    pub async fn record_file_restored(&self, file_id: Uuid) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        Self::insert_row_change(&mut conn, file_id, ChangeType::Created).await?;
+        self.changes_recorded();
+        Ok(())
+    }
+
+    async fn insert_change(
+        conn: &mut SqliteConnection,
+        owner_id: Uuid,
+        file_id: Uuid,
+        change_type: ChangeType,
+        path: &str,
+        old_path: Option<&str>,
+        changed_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let change_type = format!("{:?}", change_type);
+        let actor = ChangeActor::current();
+        let (actor_id, device_id) = (actor.as_ref().map(|a| a.user_id), actor.and_then(|a| a.device_id));
+        sqlx::query!(
+            r#"
+            INSERT INTO change_log (id, owner_id, file_id, change_type, path, old_path, changed_at, actor_id, device_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            "#,
+            Uuid::new_v4(),
+            owner_id,
+            file_id,
+            change_type,
+            path,
+            old_path,
+            changed_at,
+            actor_id,
+            device_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record a change to a tracked row at its current path
+    async fn insert_row_change(conn: &mut SqliteConnection, file_id: Uuid, change_type: ChangeType) -> Result<()> {
+        let id = Uuid::new_v4();
+        let change_type = format!("{:?}", change_type);
+        let changed_at = Utc::now();
+        let actor = ChangeActor::current();
+        let (actor_id, device_id) = (actor.as_ref().map(|a| a.user_id), actor.and_then(|a| a.device_id));
+        sqlx::query!(
+            r#"
+            INSERT INTO change_log (id, owner_id, file_id, change_type, path, changed_at, actor_id, device_id)
+            SELECT ?1, owner_id, id, ?2, path, ?3, ?5, ?6 FROM file_metadata WHERE id = ?4
+            "#,
+            id,
+            change_type,
+            changed_at,
+            file_id,
+            actor_id,
+            device_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Drop changes older than `before` that later ones make redundant: an
+    /// edit followed by any other change to the file, and everything before
+    /// a file's deletion. Syncing from any cursor gives the same result
+    /// afterwards, so the horizon stays where it is.
+    pub async fn compact_change_log_before(&self, before: DateTime<Utc>) -> Result<u64> {
+        let mut tx = self.begin_write().await?;
+
+        let through = sqlx::query!(
+            r#"SELECT COALESCE(MAX(seq), 0) as "seq!: i64" FROM change_log WHERE changed_at < ?1"#,
+            before
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM change_log
+            WHERE seq < (
+                SELECT MAX(later.seq) FROM change_log AS later
+                WHERE later.file_id = change_log.file_id AND later.seq <= ?1
+            )
+            AND (
+                change_type = 'Modified'
+                OR (
+                    SELECT latest.change_type FROM change_log AS latest
+                    WHERE latest.file_id = change_log.file_id AND latest.seq <= ?1
+                    ORDER BY latest.seq DESC LIMIT 1
+                ) = 'Deleted'
+            )
+            "#,
+            through.seq
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Forget changes older than `before`, moving the horizon clients have
+    /// to have synced after
+    pub async fn purge_change_log_before(&self, before: DateTime<Utc>) -> Result<u64> {
+        let mut tx = self.begin_write().await?;
+
+        let pruned = sqlx::query!(
+            r#"SELECT COALESCE(MAX(seq), 0) as "seq!: i64" FROM change_log WHERE changed_at < ?1"#,
+            before
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let result = sqlx::query!("DELETE FROM change_log WHERE changed_at < ?1", before)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO change_log_horizon (id, pruned_before, pruned_through) VALUES (1, ?1, ?2)
+            ON CONFLICT (id) DO UPDATE SET
+                pruned_before = MAX(pruned_before, excluded.pruned_before),
+                pruned_through = MAX(pruned_through, excluded.pruned_through)
+            "#,
+            before,
+            pruned.seq
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    /// How far back the feed still reaches; none until the change log is
+    /// first pruned
+    pub async fn get_change_log_horizon(&self) -> Result<Option<ChangeLogHorizon>> {
+        let row = sqlx::query!("SELECT pruned_before, pruned_through FROM change_log_horizon WHERE id = 1")
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|row| ChangeLogHorizon {
+            pruned_before: row.pruned_before,
+            pruned_through: row.pruned_through,
+        }))
+    }
+
+    /// Number of the latest change recorded, for anyone. Pruning never
+    /// lowers it.
+    pub async fn get_latest_change_seq(&self) -> Result<i64> {
+        let row = sqlx::query!(
+            r#"SELECT COALESCE((SELECT seq FROM sqlite_sequence WHERE name = 'change_log'), 0) as "seq!: i64""#
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.seq)
+    }
+
+    /// Number of the latest change to `owners`' files, up to `through`
+    pub async fn get_latest_change_seq_for(&self, owners: &[Uuid], through: i64) -> Result<i64> {
+        if owners.is_empty() {
+            return Ok(0);
+        }
+
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT COALESCE(MAX(seq), 0) AS seq FROM change_log WHERE seq <= ");
+        query.push_bind(through);
+        query.push(" AND owner_id IN (");
+        let mut separated = query.separated(", ");
+        for owner_id in owners {
+            separated.push_bind(owner_id);
+        }
+        separated.push_unseparated(")");
+
+        let row = query.build().fetch_one(&self.pool).await?;
+        Ok(row.try_get("seq")?)
+    }
+
+    /// Number of the first change recorded after `since`, for anyone
+    pub async fn get_first_change_after(&self, since: DateTime<Utc>) -> Result<Option<i64>> {
+        let row = sqlx::query!(
+            r#"SELECT MIN(seq) as "seq: i64" FROM change_log WHERE changed_at > ?1"#,
+            since
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.seq)
+    }
+
+    /// Where a page of at most `limit` changes to `owners`' files, starting
+    /// after `after`, ends: `through` when the rest fits
+    pub async fn get_change_page_end(&self, owners: &[Uuid], after: i64, through: i64, limit: u32) -> Result<i64> {
+        if owners.is_empty() || limit == 0 {
+            return Ok(through);
+        }
+
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT seq FROM change_log WHERE seq > ");
+        query.push_bind(after);
+        query.push(" AND seq <= ");
+        query.push_bind(through);
+        query.push(" AND owner_id IN (");
+        let mut separated = query.separated(", ");
+        for owner_id in owners {
+            separated.push_bind(owner_id);
+        }
+        separated.push_unseparated(") ORDER BY seq LIMIT 1 OFFSET ");
+        query.push_bind(limit as i64 - 1);
+
+        let row = query.build().fetch_optional(&self.pool).await?;
+        match row {
+            Some(row) => Ok(row.try_get("seq")?),
+            None => Ok(through),
+        }
+    }
+
+    /// Delete a metadata row together with every row below it in the tree,
+    /// and the share links, shares, versions and integrity issues of each
+    pub async fn delete_file_metadata(&self, file_id: Uuid) -> Result<DeletedFiles> {
+        let mut tx = self.begin().await?;
+        let deleted = tx.delete_file_metadata(file_id).await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn delete_subtree(conn: &mut SqliteConnection, file_id: Uuid) -> Result<DeletedFiles> {
+        let rows = sqlx::query!(
+            r#"
+            WITH RECURSIVE subtree(id) AS (
+                SELECT id FROM file_metadata WHERE id = ?1
+                UNION ALL
+                SELECT fm.id FROM file_metadata fm JOIN subtree ON fm.parent_id = subtree.id
+            )
+            SELECT file_metadata.id FROM file_metadata JOIN subtree ON file_metadata.id = subtree.id
+            ORDER BY file_metadata.path DESC
+            "#,
+            file_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let ids: Vec<Uuid> = rows.into_iter().map(|row| row.id).collect();
+        Self::delete_rows(conn, &ids).await
+    }
+
+    /// Delete `owner_id`'s row at `path` and every row below it by path,
+    /// like `delete_file_metadata`, and record the deletion for the sync
+    /// feed. Rows whose parent link went astray go too.
+    pub async fn delete_metadata_under_path(&self, owner_id: Uuid, path: &str) -> Result<DeletedFiles> {
+        let mut tx = self.begin_write().await?;
+
+        let (lower, upper) = descendant_range(path);
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, path FROM file_metadata
+            WHERE owner_id = ?1 AND (path = ?2 OR (path >= ?3 AND path < ?4))
+            ORDER BY path DESC
+            "#,
+            owner_id,
+            path,
+            lower,
+            upper
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let top = rows.iter().find(|row| row.path == path).map(|row| row.id);
+        let ids: Vec<Uuid> = rows.into_iter().map(|row| row.id).collect();
+        let deleted = Self::delete_rows(&mut tx, &ids).await?;
+
+        // Like moves, a folder's deletion is recorded once, for the folder
+        if let Some(file_id) = top {
+            Self::insert_change(&mut tx, owner_id, file_id, ChangeType::Deleted, path, None, Utc::now()).await?;
+        }
+
+        tx.commit().await?;
+        self.changes_recorded();
+        Ok(deleted)
+    }
+
+    /// Delete the rows `ids`, given children before their parents, and
+    /// whatever refers to them. Trash entries of the rows are kept, without
+    /// a row to purge.
+    async fn delete_rows(conn: &mut SqliteConnection, ids: &[Uuid]) -> Result<DeletedFiles> {
+        let mut deleted = DeletedFiles::default();
+        let mut checksums = Vec::new();
+
+        for chunk in ids.chunks(MAX_BIND_PARAMS) {
+            let mut query = QueryBuilder::<Sqlite>::new("SELECT DISTINCT checksum FROM file_versions WHERE file_id IN ");
+            push_ids(&mut query, chunk);
+            for row in query.build().fetch_all(&mut *conn).await? {
+                checksums.push(row.try_get::<String, _>("checksum")?);
+            }
+
+            // Visits of the share links stay on record, by token
+            for statement in [
+                "DELETE FROM file_versions WHERE file_id IN ",
+                "DELETE FROM share_links WHERE file_id IN ",
+                "DELETE FROM user_shares WHERE file_id IN ",
+                "DELETE FROM favorites WHERE file_id IN ",
+                "DELETE FROM file_tags WHERE file_id IN ",
+                "DELETE FROM comments WHERE file_id IN ",
+                "DELETE FROM integrity_issues WHERE file_id IN ",
+                "UPDATE trash SET file_id = NULL WHERE file_id IN ",
+            ] {
+                let mut query = QueryBuilder::<Sqlite>::new(statement);
+                push_ids(&mut query, chunk);
+                query.build().execute(&mut *conn).await?;
+            }
+
+            let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM file_metadata WHERE id IN ");
+            push_ids(&mut query, chunk);
+            deleted.rows += query.build().execute(&mut *conn).await?.rows_affected();
+        }
+
+        // Version content can be shared with other files' versions
+        checksums.sort();
+        checksums.dedup();
+        for checksum in checksums {
+            let row = sqlx::query!(
+                r#"SELECT EXISTS(SELECT 1 FROM file_versions WHERE checksum = ?1) as "used!: bool""#,
+                checksum
+            )
+            .fetch_one(&mut *conn)
+            .await?;
+            if !row.used {
+                deleted.unused_versions.push(checksum);
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    /// Record a login from a device, bringing a revoked one back
+    pub async fn upsert_device(&self, device: &Device) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO devices (user_id, device_id, name, client_version, ip_address, created_at, last_seen_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT (user_id, device_id) DO UPDATE SET
+                name = COALESCE(excluded.name, devices.name),
+                client_version = COALESCE(excluded.client_version, devices.client_version),
+                ip_address = excluded.ip_address,
+                last_seen_at = excluded.last_seen_at,
+                revoked_at = NULL
+            "#,
+            device.user_id,
+            device.device_id,
+            device.name,
+            device.client_version,
+            device.ip_address,
+            device.created_at,
+            device.last_seen_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn touch_device(&self, user_id: Uuid, device_id: &str, ip_address: Option<&str>, at: DateTime<Utc>) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE devices SET last_seen_at = ?1, ip_address = COALESCE(?2, ip_address)
+            WHERE user_id = ?3 AND device_id = ?4 AND revoked_at IS NULL
+            "#,
+            at,
+            ip_address,
+            user_id,
+            device_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn list_devices(&self, user_id: Uuid) -> Result<Vec<Device>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM devices WHERE user_id = ?1 ORDER BY last_seen_at DESC",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| Device {
+            user_id: row.user_id,
+            device_id: row.device_id,
+            name: row.name,
+            client_version: row.client_version,
+            ip_address: row.ip_address,
+            created_at: row.created_at,
+            last_seen_at: row.last_seen_at,
+            revoked_at: row.revoked_at,
+        }).collect())
+    }
+
+    /// Reject the device's tokens issued up to `at`, drop its refresh tokens
+    /// and stop its sync session. Returns false if there is no such device.
+    pub async fn revoke_device(&self, user_id: Uuid, device_id: &str, at: DateTime<Utc>) -> Result<bool> {
+        let mut tx = self.begin_write().await?;
+
+        let updated = sqlx::query!(
+            "UPDATE devices SET revoked_at = ?1, tokens_valid_after = ?1 WHERE user_id = ?2 AND device_id = ?3",
+            at,
+            user_id,
+            device_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if updated.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            "DELETE FROM refresh_tokens WHERE user_id = ?1 AND device_id = ?2",
+            user_id,
+            device_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE sync_sessions SET is_active = 0 WHERE user_id = ?1 AND device_id = ?2",
+            user_id,
+            device_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "DELETE FROM sessions WHERE user_id = ?1 AND device_id = ?2",
+            user_id,
+            device_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Per device: tokens issued at or before this time are rejected
+    pub async fn get_device_revocations(&self) -> Result<Vec<(Uuid, String, DateTime<Utc>)>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT user_id, device_id, tokens_valid_after as "tokens_valid_after!: DateTime<Utc>"
+            FROM devices WHERE tokens_valid_after IS NOT NULL
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.user_id, row.device_id, row.tokens_valid_after)).collect())
+    }
+
+    pub async fn create_session(&self, session: &Session) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO sessions
+            (id, user_id, device_id, user_agent, ip_address, jti, token_expires_at, created_at, last_active_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+            "#,
+            session.id,
+            session.user_id,
+            session.device_id,
+            session.user_agent,
+            session.ip_address,
+            session.jti,
+            session.token_expires_at,
+            session.created_at,
+            session.last_active_at,
+            session.expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The user's sessions that haven't expired, most recently active first
+    pub async fn list_sessions(&self, user_id: Uuid) -> Result<Vec<Session>> {
+        let now = Utc::now();
+        let rows = sqlx::query!(
+            "SELECT * FROM sessions WHERE user_id = ?1 AND expires_at > ?2 ORDER BY last_active_at DESC",
+            user_id,
+            now
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| Session {
+            id: row.id,
+            user_id: row.user_id,
+            device_id: row.device_id,
+            user_agent: row.user_agent,
+            ip_address: row.ip_address,
+            jti: row.jti,
+            token_expires_at: row.token_expires_at,
+            created_at: row.created_at,
+            last_active_at: row.last_active_at,
+            expires_at: row.expires_at,
+            current: false,
+        }).collect())
+    }
+
+    /// Follow a session to the access token a refresh just issued
+    pub async fn refresh_session(
+        &self,
+        session_id: Uuid,
+        jti: &str,
+        token_expires_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+        at: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE sessions SET jti = ?1, token_expires_at = ?2, expires_at = ?3, last_active_at = ?4
+            WHERE id = ?5
+            "#,
+            jti,
+            token_expires_at,
+            expires_at,
+            at,
+            session_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn touch_session(&self, jti: &str, at: DateTime<Utc>) -> Result<()> {
+        sqlx::query!("UPDATE sessions SET last_active_at = ?1 WHERE jti = ?2", at, jti)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// End one of the user's sessions: drop its refresh tokens and record its
+    /// current access token as revoked. Returns the session so the caller can
+    /// revoke the token in memory too, or None if there is no such session.
+    pub async fn end_session(&self, user_id: Uuid, session_id: Uuid) -> Result<Option<Session>> {
+        let mut tx = self.begin_write().await?;
+
+        let row = sqlx::query!(
+            "SELECT * FROM sessions WHERE id = ?1 AND user_id = ?2",
+            session_id,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let session = match row {
+            Some(row) => Session {
+                id: row.id,
+                user_id: row.user_id,
+                device_id: row.device_id,
+                user_agent: row.user_agent,
+                ip_address: row.ip_address,
+                jti: row.jti,
+                token_expires_at: row.token_expires_at,
+                created_at: row.created_at,
+                last_active_at: row.last_active_at,
+                expires_at: row.expires_at,
+                current: false,
+            },
+            None => return Ok(None),
+        };
+
+        sqlx::query!("DELETE FROM refresh_tokens WHERE session_id = ?1", session_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!(
+            "INSERT OR IGNORE INTO revoked_tokens (jti, user_id, expires_at) VALUES (?1, ?2, ?3)",
+            session.jti,
+            user_id,
+            session.token_expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!("DELETE FROM sessions WHERE id = ?1", session_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(Some(session))
+    }
+
+    /// End the session whose current access token this is, along with its
+    /// refresh tokens. The token itself is revoked by the caller.
+    pub async fn delete_session_by_jti(&self, user_id: Uuid, jti: &str) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM refresh_tokens
+            WHERE session_id IN (SELECT id FROM sessions WHERE user_id = ?1 AND jti = ?2)
+            "#,
+            user_id,
+            jti
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!("DELETE FROM sessions WHERE user_id = ?1 AND jti = ?2", user_id, jti)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Forget sessions that can't be refreshed any more
+    pub async fn purge_expired_sessions(&self, now: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query!("DELETE FROM sessions WHERE expires_at <= ?1", now)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn create_sync_session(&self, session: &SyncSession) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO sync_sessions (id, user_id, device_id, device_name, last_sync, sync_folders, exclude_patterns, is_active)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            "#,
+            session.id,
+            session.user_id,
+            session.device_id,
+            session.device_name,
+            session.last_sync,
+            serde_json::to_string(&session.sync_folders)?,
+            serde_json::to_string(&session.exclude_patterns)?,
+            session.is_active
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_sync_session(&self, user_id: Uuid, device_id: &str) -> Result<Option<SyncSession>> {
+        let row = sqlx::query!(
+            "SELECT * FROM sync_sessions WHERE user_id = ?1 AND device_id = ?2",
+            user_id,
+            device_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if let Some(row) = row {
+            let sync_folders: Vec<String> = serde_json::from_str(&row.sync_folders)?;
+            let exclude_patterns: Vec<String> = serde_json::from_str(&row.exclude_patterns)?;
+            
+            Ok(Some(SyncSession {
+                id: row.id,
+                user_id: row.user_id,
+                device_id: row.device_id,
+                device_name: row.device_name,
+                last_sync: row.last_sync,
+                sync_folders,
+                exclude_patterns,
+                is_active: row.is_active,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub async fn update_sync_session(&self, session: &SyncSession) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE sync_sessions SET device_name = ?1, last_sync = ?2, sync_folders = ?3, exclude_patterns = ?4, is_active = ?5
+            WHERE id = ?6
+            "#,
+            session.device_name,
+            session.last_sync,
+            serde_json::to_string(&session.sync_folders)?,
+            serde_json::to_string(&session.exclude_patterns)?,
+            session.is_active,
+            session.id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Note that a session's device synced at `last_sync`, leaving the rest
+    /// of the session as it is
+    pub async fn record_sync_session_sync(&self, session_id: Uuid, last_sync: DateTime<Utc>) -> Result<()> {
+        sqlx::query!("UPDATE sync_sessions SET last_sync = ?1 WHERE id = ?2", last_sync, session_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// A user's sync sessions, most recently synced first
+    pub async fn list_sync_sessions(&self, user_id: Uuid) -> Result<Vec<SyncSession>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM sync_sessions WHERE user_id = ?1 ORDER BY last_sync DESC",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut sessions = Vec::new();
+        for row in rows {
+            sessions.push(SyncSession {
+                id: row.id,
+                user_id: row.user_id,
+                device_id: row.device_id,
+                device_name: row.device_name,
+                last_sync: row.last_sync,
+                sync_folders: serde_json::from_str(&row.sync_folders)?,
+                exclude_patterns: serde_json::from_str(&row.exclude_patterns)?,
+                is_active: row.is_active,
+            });
+        }
+
+        Ok(sessions)
+    }
+
+    /// Mark sessions that haven't synced since `before` inactive, so their
+    /// devices have to register again
+    pub async fn deactivate_idle_sync_sessions(&self, before: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE sync_sessions SET is_active = 0 WHERE is_active = 1 AND last_sync < ?1",
+            before
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn create_share_link(&self, share_link: &ShareLink) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO share_links 
+            (id, file_id, created_by, share_token, expires_at, password_protected, download_count, max_downloads,
+             created_at, revoked_at, password_hash, short_code, alias)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+            "#,
+            share_link.id,
+            share_link.file_id,
+            share_link.created_by,
+            share_link.share_token,
+            share_link.expires_at,
+            share_link.password_protected,
+            share_link.download_count as i32,
+            share_link.max_downloads.map(|x| x as i32),
+            share_link.created_at,
+            share_link.revoked_at,
+            share_link.password_hash,
+            share_link.short_code,
+            share_link.alias
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_share_link(&self, id: Uuid) -> Result<Option<ShareLink>> {
+        let row = sqlx::query!(
+            "SELECT * FROM share_links WHERE id = ?1",
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| ShareLink {
+            id: row.id,
+            file_id: row.file_id,
+            created_by: row.created_by,
+            share_token: row.share_token,
+            expires_at: row.expires_at,
+            password_protected: row.password_protected,
+            download_count: row.download_count as u32,
+            max_downloads: row.max_downloads.map(|x| x as u32),
+            created_at: row.created_at,
+            revoked_at: row.revoked_at,
+            password_hash: row.password_hash,
+            short_code: row.short_code,
+            alias: row.alias,
+        }))
+    }
+
+    /// The share link with this short code or alias
+    pub async fn get_share_link_by_code(&self, code: &str) -> Result<Option<ShareLink>> {
+        let row = sqlx::query!(
+            "SELECT * FROM share_links WHERE short_code = ?1 OR alias = ?1",
+            code
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| ShareLink {
+            id: row.id,
+            file_id: row.file_id,
+            created_by: row.created_by,
+            share_token: row.share_token,
+            expires_at: row.expires_at,
+            password_protected: row.password_protected,
+            download_count: row.download_count as u32,
+            max_downloads: row.max_downloads.map(|x| x as u32),
+            created_at: row.created_at,
+            revoked_at: row.revoked_at,
+            password_hash: row.password_hash,
+            short_code: row.short_code,
+            alias: row.alias,
+        }))
+    }
+
+    /// Count a download of a share link. False when its downloads are used up.
+    pub async fn record_share_download(&self, id: Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE share_links SET download_count = download_count + 1
+            WHERE id = ?1 AND (max_downloads IS NULL OR download_count < max_downloads)
+            "#,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Delete share links that expired by `before` or used up their
+    /// downloads, a batch at a time. Returns how many were deleted.
+    pub async fn purge_expired_share_links(&self, before: DateTime<Utc>) -> Result<u64> {
+        let mut purged = 0;
+        loop {
+            let result = sqlx::query!(
+                r#"
+                DELETE FROM share_links WHERE id IN (
+                    SELECT id FROM share_links
+                    WHERE expires_at <= ?1
+                       OR (max_downloads IS NOT NULL AND download_count >= max_downloads)
+                    LIMIT ?2
+                )
+                "#,
+                before,
+                SHARE_PURGE_BATCH
+            )
+            .execute(&self.pool)
+            .await?;
+
+            purged += result.rows_affected();
+            if result.rows_affected() < SHARE_PURGE_BATCH as u64 {
+                return Ok(purged);
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    pub async fn get_share_link_by_token(&self, token: &str) -> Result<Option<ShareLink>> {
+        let row = sqlx::query!(
+            "SELECT * FROM share_links WHERE share_token = ?1",
+            token
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if let Some(row) = row {
+            Ok(Some(ShareLink {
+                id: row.id,
+                file_id: row.file_id,
+                created_by: row.created_by,
+                share_token: row.share_token,
+                expires_at: row.expires_at,
+                password_protected: row.password_protected,
+                download_count: row.download_count as u32,
+                max_downloads: row.max_downloads.map(|x| x as u32),
+                created_at: row.created_at,
+                revoked_at: row.revoked_at,
+                password_hash: row.password_hash,
+                short_code: row.short_code,
+                alias: row.alias,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The user's share links that are neither revoked nor expired by `now`,
+    /// newest first, with the file each points to, from after the link
+    /// created at and with the id in `after` when given. Expiry is judged as
+    /// by `ShareLink::is_expired`. Links whose downloads are used up are
+    /// listed until the cleanup deletes them, since raising the limit
+    /// revives them.
+    pub async fn get_share_links_by_user(
+        &self,
+        user_id: Uuid,
+        now: DateTime<Utc>,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<ShareSummary>> {
+        let (after_created, after_id) = after.unzip();
+        // '9999' sorts after every stored time, so no cursor starts at the top
+        let rows = sqlx::query!(
+            r#"
+            SELECT share_links.*, file_metadata.name AS file_name, file_metadata.path AS file_path
+            FROM share_links
+            JOIN file_metadata ON file_metadata.id = share_links.file_id
+                AND file_metadata.deleted_at IS NULL
+            WHERE share_links.created_by = ?1
+              AND share_links.revoked_at IS NULL
+              AND (share_links.expires_at IS NULL OR share_links.expires_at > ?2)
+              AND (share_links.created_at, share_links.id) < (COALESCE(?3, '9999'), COALESCE(?4, ''))
+            ORDER BY share_links.created_at DESC, share_links.id DESC
+            LIMIT ?5 OFFSET ?6
+            "#,
+            user_id,
+            now,
+            after_created,
+            after_id,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| ShareSummary {
+            share: ShareLink {
+                id: row.id,
+                file_id: row.file_id,
+                created_by: row.created_by,
+                share_token: row.share_token,
+                expires_at: row.expires_at,
+                password_protected: row.password_protected,
+                download_count: row.download_count as u32,
+                max_downloads: row.max_downloads.map(|x| x as u32),
+                created_at: row.created_at,
+                revoked_at: row.revoked_at,
+                password_hash: row.password_hash,
+                short_code: row.short_code,
+                alias: row.alias,
+            },
+            file_name: row.file_name,
+            file_path: row.file_path,
+        }).collect())
+    }
+
+    /// How many share links `get_share_links_by_user` lists in all
+    pub async fn count_share_links_by_user(&self, user_id: Uuid, now: DateTime<Utc>) -> Result<u64> {
+        let row = sqlx::query!(
+            r#"
+            SELECT COUNT(*) as "count!: i64"
+            FROM share_links
+            JOIN file_metadata ON file_metadata.id = share_links.file_id
+                AND file_metadata.deleted_at IS NULL
+            WHERE share_links.created_by = ?1
+              AND share_links.revoked_at IS NULL
+              AND (share_links.expires_at IS NULL OR share_links.expires_at > ?2)
+            "#,
+            user_id,
+            now
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.count as u64)
+    }
+
+    /// Revoke one of the user's share links. The row is kept, flagged, so
+    /// the audit trail survives. False when there is no such live link.
+    pub async fn delete_share_link(&self, id: Uuid, user_id: Uuid, now: DateTime<Utc>) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE share_links SET revoked_at = ?3 WHERE id = ?1 AND created_by = ?2 AND revoked_at IS NULL",
+            id,
+            user_id,
+            now
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Store a share link's token, expiry, download limit and password.
+    /// False when it has been revoked in the meantime.
+    pub async fn update_share_link(&self, share_link: &ShareLink) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE share_links
+            SET share_token = ?2, expires_at = ?3, max_downloads = ?4, password_protected = ?5, password_hash = ?6
+            WHERE id = ?1 AND revoked_at IS NULL
+            "#,
+            share_link.id,
+            share_link.share_token,
+            share_link.expires_at,
+            share_link.max_downloads.map(|x| x as i32),
+            share_link.password_protected,
+            share_link.password_hash
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Share a file or folder with another user, or change the permissions
+    /// of an existing share between them. A share made for a MyCloud share
+    /// becomes the grantor's own, and stays when the MyCloud share goes.
+    pub async fn create_user_share(&self, share: &UserShare) -> Result<()> {
+        let permissions = share.permissions.as_str();
+        sqlx::query!(
+            r#"
+            INSERT INTO user_shares (id, file_id, grantor, grantee, permissions, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT (file_id, grantee) DO UPDATE SET permissions = excluded.permissions, mycloud_share = NULL
+            "#,
+            share.id,
+            share.file_id,
+            share.grantor,
+            share.grantee,
+            permissions,
+            share.created_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Make `grants` the shares of the folder `file_id` for the MyCloud share
+    /// `share_name`, dropping the ones made for it before that aren't among
+    /// them. Shares users made themselves are left as they are.
+    pub async fn set_mycloud_share_grants(
+        &self,
+        share_name: &str,
+        file_id: Uuid,
+        grantor: Uuid,
+        grants: &[(Uuid, SharePermissions)],
+    ) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+
+        let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM user_shares WHERE mycloud_share = ");
+        query.push_bind(share_name.to_string());
+        query.push(" AND (file_id != ");
+        query.push_bind(file_id);
+        if !grants.is_empty() {
+            query.push(" OR grantee NOT IN ");
+            let grantees: Vec<Uuid> = grants.iter().map(|(grantee, _)| *grantee).collect();
+            push_ids(&mut query, &grantees);
+        }
+        query.push(")");
+        query.build().execute(&mut *tx).await?;
+
+        let now = Utc::now();
+        for (grantee, permissions) in grants {
+            let id = Uuid::new_v4();
+            let permissions = permissions.as_str();
+            sqlx::query!(
+                r#"
+                INSERT INTO user_shares (id, file_id, grantor, grantee, permissions, created_at, mycloud_share)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT (file_id, grantee) DO UPDATE SET permissions = excluded.permissions
+                WHERE user_shares.mycloud_share = excluded.mycloud_share
+                "#,
+                id,
+                file_id,
+                grantor,
+                grantee,
+                permissions,
+                now,
+                share_name
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Drop the shares made for MyCloud shares other than `kept`. Returns
+    /// how many went.
+    pub async fn revoke_mycloud_share_grants(&self, kept: &[String]) -> Result<u64> {
+        let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM user_shares WHERE mycloud_share IS NOT NULL");
+        if !kept.is_empty() {
+            query.push(" AND mycloud_share NOT IN (");
+            let mut separated = query.separated(", ");
+            for name in kept {
+                separated.push_bind(name.clone());
+            }
+            separated.push_unseparated(")");
+        }
+        let result = query.build().execute(&self.pool).await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn delete_user_share(&self, file_id: Uuid, grantee: Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM user_shares WHERE file_id = ?1 AND grantee = ?2",
+            file_id,
+            grantee
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// What has been shared with a user, with the files' current paths.
+    /// Shares of files that are gone or in the trash are left out.
+    pub async fn get_incoming_shares(&self, grantee: Uuid) -> Result<Vec<IncomingShare>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT user_shares.*, users.username AS owner, file_metadata.name, file_metadata.path,
+                   file_metadata.is_directory
+            FROM user_shares
+            JOIN users ON users.id = user_shares.grantor
+            JOIN file_metadata ON file_metadata.id = user_shares.file_id
+                AND file_metadata.owner_id = user_shares.grantor
+                AND file_metadata.deleted_at IS NULL
+            WHERE user_shares.grantee = ?1
+            ORDER BY users.username, file_metadata.path
+            "#,
+            grantee
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        // Permissions this version doesn't know are left out
+        Ok(rows.into_iter()
+            .filter_map(|row| Some(IncomingShare {
+                share: UserShare {
+                    id: row.id,
+                    file_id: row.file_id,
+                    grantor: row.grantor,
+                    grantee: row.grantee,
+                    permissions: SharePermissions::parse(&row.permissions)?,
+                    created_at: row.created_at,
+                },
+                owner: row.owner,
+                name: row.name,
+                path: row.path,
+                is_directory: row.is_directory,
+            }))
+            .collect())
+    }
+
+    /// Star a file or folder for `user_id`; starring it again changes nothing
+    pub async fn add_favorite(&self, user_id: Uuid, file_id: Uuid) -> Result<()> {
+        let now = Utc::now();
+        sqlx::query!(
+            r#"
+            INSERT INTO favorites (user_id, file_id, created_at) VALUES (?1, ?2, ?3)
+            ON CONFLICT (user_id, file_id) DO NOTHING
+            "#,
+            user_id,
+            file_id,
+            now
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_favorite(&self, user_id: Uuid, file_id: Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM favorites WHERE user_id = ?1 AND file_id = ?2",
+            user_id,
+            file_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// What `user_id` starred, by path. Stars of files that are gone are
+    /// removed on the way; those of trashed files stay for when they are
+    /// restored, but are left out.
+    pub async fn get_favorites(&self, user_id: Uuid) -> Result<Vec<FileMetadata>> {
+        sqlx::query!(
+            r#"
+            DELETE FROM favorites
+            WHERE user_id = ?1 AND NOT EXISTS (SELECT 1 FROM file_metadata WHERE file_metadata.id = favorites.file_id)
+            "#,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM file_metadata
+            WHERE deleted_at IS NULL AND id IN (SELECT file_id FROM favorites WHERE user_id = ?1)
+            ORDER BY path
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// Which of `file_ids` `user_id` starred
+    pub async fn get_favorite_ids(&self, user_id: Uuid, file_ids: &[Uuid]) -> Result<HashSet<Uuid>> {
+        let mut starred = HashSet::new();
+
+        for chunk in file_ids.chunks(MAX_BIND_PARAMS) {
+            let mut query = QueryBuilder::<Sqlite>::new("SELECT file_id FROM favorites WHERE user_id = ");
+            query.push_bind(user_id);
+            query.push(" AND file_id IN ");
+            push_ids(&mut query, chunk);
+            for row in query.build().fetch_all(&self.pool).await? {
+                starred.insert(row.try_get::<Uuid, _>("file_id")?);
+            }
+        }
+
+        Ok(starred)
+    }
+
+    /// Put `user_id`'s tag `name` on a file, making the tag if it's new
+    pub async fn add_file_tag(&self, user_id: Uuid, file_id: Uuid, name: &str) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+
+        let id = Uuid::new_v4();
+        let now = Utc::now();
+        sqlx::query!(
+            r#"
+            INSERT INTO tags (id, user_id, name, created_at) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (user_id, name) DO NOTHING
+            "#,
+            id,
+            user_id,
+            name,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO file_tags (tag_id, file_id)
+            SELECT id, ?3 FROM tags WHERE user_id = ?1 AND name = ?2
+            ON CONFLICT (tag_id, file_id) DO NOTHING
+            "#,
+            user_id,
+            name,
+            file_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Take `user_id`'s tag `name` off a file. A tag left on no file goes.
+    pub async fn remove_file_tag(&self, user_id: Uuid, file_id: Uuid, name: &str) -> Result<bool> {
+        let mut tx = self.begin_write().await?;
+
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM file_tags
+            WHERE file_id = ?3 AND tag_id = (SELECT id FROM tags WHERE user_id = ?1 AND name = ?2)
+            "#,
+            user_id,
+            name,
+            file_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM tags
+            WHERE user_id = ?1 AND name = ?2 AND NOT EXISTS (SELECT 1 FROM file_tags WHERE file_tags.tag_id = tags.id)
+            "#,
+            user_id,
+            name
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// `user_id`'s tags by name, with how many files outside the trash
+    /// carry each. Tags only on trashed files are left out.
+    pub async fn get_tags(&self, user_id: Uuid) -> Result<Vec<TagUsage>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT tags.name, COUNT(*) AS "files!: i64"
+            FROM tags
+            JOIN file_tags ON file_tags.tag_id = tags.id
+            JOIN file_metadata ON file_metadata.id = file_tags.file_id AND file_metadata.deleted_at IS NULL
+            WHERE tags.user_id = ?1
+            GROUP BY tags.id
+            ORDER BY tags.name
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| TagUsage { name: row.name, files: row.files as u64 }).collect())
+    }
+
+    /// Rename `user_id`'s tag `from` to `to` on all their files at once. If
+    /// they already have a tag `to`, the two become one. Returns false if
+    /// there is no tag `from`.
+    pub async fn rename_tag(&self, user_id: Uuid, from: &str, to: &str) -> Result<bool> {
+        let mut tx = self.begin_write().await?;
+
+        let tag = sqlx::query!("SELECT id FROM tags WHERE user_id = ?1 AND name = ?2", user_id, from)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(tag) = tag else {
+            return Ok(false);
+        };
+        let existing = sqlx::query!("SELECT id FROM tags WHERE user_id = ?1 AND name = ?2", user_id, to)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        match existing {
+            Some(existing) if existing.id != tag.id => {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO file_tags (tag_id, file_id)
+                    SELECT ?1, file_id FROM file_tags WHERE tag_id = ?2
+                    ON CONFLICT (tag_id, file_id) DO NOTHING
+                    "#,
+                    existing.id,
+                    tag.id
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query!("DELETE FROM file_tags WHERE tag_id = ?1", tag.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM tags WHERE id = ?1", tag.id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            Some(_) => {}
+            None => {
+                sqlx::query!("UPDATE tags SET name = ?1 WHERE id = ?2", to, tag.id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// `user_id`'s tags on each of `file_ids` that has any, by name
+    pub async fn get_file_tags(&self, user_id: Uuid, file_ids: &[Uuid]) -> Result<HashMap<Uuid, Vec<String>>> {
+        let mut tags: HashMap<Uuid, Vec<String>> = HashMap::new();
+
+        for chunk in file_ids.chunks(MAX_BIND_PARAMS) {
+            let mut query = QueryBuilder::<Sqlite>::new(
+                "SELECT file_tags.file_id, tags.name FROM file_tags JOIN tags ON tags.id = file_tags.tag_id \
+                WHERE tags.user_id = ",
+            );
+            query.push_bind(user_id);
+            query.push(" AND file_tags.file_id IN ");
+            push_ids(&mut query, chunk);
+            query.push(" ORDER BY tags.name");
+            for row in query.build().fetch_all(&self.pool).await? {
+                tags.entry(row.try_get("file_id")?).or_default().push(row.try_get("name")?);
+            }
+        }
+
+        Ok(tags)
+    }
+
+    pub async fn create_comment(&self, comment: &Comment) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO comments (id, file_id, author_id, body, created_at, edited_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            "#,
+            comment.id,
+            comment.file_id,
+            comment.author_id,
+            comment.body,
+            comment.created_at,
+            comment.edited_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_comment(&self, comment_id: Uuid) -> Result<Option<Comment>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT comments.*, users.username AS "author?"
+            FROM comments LEFT JOIN users ON users.id = comments.author_id
+            WHERE comments.id = ?1
+            "#,
+            comment_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| Comment {
+            id: row.id,
+            file_id: row.file_id,
+            author_id: row.author_id,
+            author: row.author,
+            body: row.body,
+            created_at: row.created_at,
+            edited_at: row.edited_at,
+        }))
+    }
+
+    /// The comments on a file, oldest first
+    pub async fn get_comments(&self, file_id: Uuid) -> Result<Vec<Comment>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT comments.*, users.username AS "author?"
+            FROM comments LEFT JOIN users ON users.id = comments.author_id
+            WHERE comments.file_id = ?1
+            ORDER BY comments.created_at, comments.id
+            "#,
+            file_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| Comment {
+            id: row.id,
+            file_id: row.file_id,
+            author_id: row.author_id,
+            author: row.author,
+            body: row.body,
+            created_at: row.created_at,
+            edited_at: row.edited_at,
+        }).collect())
+    }
+
+    /// Returns false if there is no such comment
+    pub async fn update_comment(&self, comment_id: Uuid, body: &str, edited_at: DateTime<Utc>) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE comments SET body = ?1, edited_at = ?2 WHERE id = ?3",
+            body,
+            edited_at,
+            comment_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete_comment(&self, comment_id: Uuid) -> Result<bool> {
+        let result = sqlx::query!("DELETE FROM comments WHERE id = ?1", comment_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// What happened to `user_id`'s files in changes `after` (exclusive) to
+    /// `through` (inclusive), one entry per file in the order of its latest
+    /// change
+    pub async fn get_changes_after(&self, user_id: Uuid, after: i64, through: i64) -> Result<Vec<FileChange>> {
+        let logged = sqlx::query!(
+            "SELECT * FROM change_log WHERE owner_id = ?1 AND seq > ?2 AND seq <= ?3 ORDER BY seq",
+            user_id,
+            after,
+            through
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM file_metadata
+            WHERE id IN (SELECT file_id FROM change_log WHERE owner_id = ?1 AND seq > ?2 AND seq <= ?3)
+              AND deleted_at IS NULL
+            "#,
+            user_id,
+            after,
+            through
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut current = HashMap::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            current.insert(row.id, FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        #[derive(Default)]
+        struct Window {
+            last_seq: i64,
+            created: bool,
+            moved_from: Option<String>,
+            deleted: Option<(String, DateTime<Utc>)>,
+        }
+
+        let mut windows: HashMap<Uuid, Window> = HashMap::new();
+        for row in logged {
+            let window = windows.entry(row.file_id).or_default();
+            window.last_seq = row.seq;
+            match row.change_type.as_str() {
+                "Deleted" => window.deleted = Some((row.path, row.changed_at)),
+                // Anything after a deletion means the file came back from the trash
+                change_type => {
+                    window.deleted = None;
+                    window.created |= change_type == "Created";
+                    if let (None, Some(old_path)) = (&window.moved_from, row.old_path) {
+                        window.moved_from = Some(old_path);
+                    }
+                }
+            }
+        }
+
+        let mut windows: Vec<(Uuid, Window)> = windows.into_iter().collect();
+        windows.sort_by_key(|(_, window)| window.last_seq);
+
+        let mut changes = Vec::new();
+        for (file_id, window) in windows {
+            if let Some((path, deleted_at)) = window.deleted {
+                changes.push(FileChange {
+                    file_id,
+                    change_type: ChangeType::Deleted,
+                    path,
+                    old_path: None,
+                    metadata: None,
+                    timestamp: deleted_at,
+                    shared_by: None,
+                });
+                continue;
+            }
+
+            // Rows removed without a recorded deletion have nothing to report,
+            // nor do the contents of a trashed folder, gone along with it
+            let Some(metadata) = current.remove(&file_id) else {
+                continue;
+            };
+            // Clients never saw where a file created in the window used to be
+            let (change_type, old_path) = match (window.created, window.moved_from) {
+                (true, _) => (ChangeType::Created, None),
+                (false, Some(old_path)) => (ChangeType::Moved, Some(old_path)),
+                (false, None) => (ChangeType::Modified, None),
+            };
+
+            changes.push(FileChange {
+                file_id,
+                change_type,
+                path: metadata.path.clone(),
+                old_path,
+                timestamp: metadata.modified_at,
+                metadata: Some(metadata),
+                shared_by: None,
+            });
+        }
+
+        Ok(changes)
+    }
+
+    /// Everyone's change log entries after `after`, oldest first, as
+    /// recorded rather than folded together like `get_changes_after`
+    pub async fn get_logged_changes_after(&self, after: i64, limit: i64) -> Result<Vec<LoggedChange>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM change_log WHERE seq > ?1 ORDER BY seq LIMIT ?2",
+            after,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().filter_map(|row| {
+            let change_type = match row.change_type.as_str() {
+                "Created" => ChangeType::Created,
+                "Modified" => ChangeType::Modified,
+                "Moved" => ChangeType::Moved,
+                "Deleted" => ChangeType::Deleted,
+                _ => return None,
+            };
+            Some(LoggedChange {
+                seq: row.seq,
+                owner_id: row.owner_id,
+                file_id: row.file_id,
+                change_type,
+                path: row.path,
+                old_path: row.old_path,
+                changed_at: row.changed_at,
+            })
+        }).collect())
+    }
+
+    /// Up to `limit` events of `user_id`'s activity feed, newest first, from
+    /// before `before`: changes to their files still in the change log,
+    /// downloads through share links they made of files still there, and
+    /// with `include_comments`, comments on their files. With `within`, only
+    /// events at or under one of those folders.
+    pub async fn get_activity_before(
+        &self,
+        user_id: Uuid,
+        before: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+        include_comments: bool,
+        within: &[String],
+    ) -> Result<Vec<ActivityEvent>> {
+        let (before_time, before_id) = before.unzip();
+        let mut query = QueryBuilder::<Sqlite>::new(
+            r#"
+            SELECT * FROM (
+                SELECT id, change_type AS kind, file_id, path, changed_at AS occurred_at, actor_id, device_id
+                FROM change_log
+                WHERE owner_id = "#,
+        );
+        query.push_bind(user_id);
+        query.push(
+            r#"
+                UNION ALL
+                SELECT share_access_log.id, 'SharedDownload', share_links.file_id, file_metadata.path,
+                       share_access_log.accessed_at, NULL, NULL
+                FROM share_access_log
+                JOIN share_links ON share_links.id = share_access_log.share_id
+                JOIN file_metadata ON file_metadata.id = share_links.file_id
+                WHERE share_links.created_by = "#,
+        );
+        query.push_bind(user_id);
+        query.push(
+            r#"
+                  AND share_access_log.outcome = 'success'
+                  AND share_access_log.bytes_served > 0
+                UNION ALL
+                SELECT comments.id, 'Commented', comments.file_id, file_metadata.path,
+                       comments.created_at, comments.author_id, NULL
+                FROM comments
+                JOIN file_metadata ON file_metadata.id = comments.file_id
+                WHERE "#,
+        );
+        query.push_bind(include_comments);
+        query.push(" AND file_metadata.owner_id = ");
+        query.push_bind(user_id);
+        // '9999' sorts after every stored time, so no cursor starts at the top
+        query.push(
+            r#"
+            )
+            WHERE (occurred_at, id) < (COALESCE("#,
+        );
+        query.push_bind(before_time);
+        query.push(", '9999'), COALESCE(");
+        query.push_bind(before_id);
+        query.push(", ''))");
+        if !within.is_empty() {
+            query.push(" AND (");
+            let mut folders = query.separated(" OR ");
+            for folder in within {
+                let (lower, upper) = descendant_range(folder);
+                folders.push("path = ");
+                folders.push_bind_unseparated(folder.clone());
+                folders.push_unseparated(" OR (path >= ");
+                folders.push_bind_unseparated(lower);
+                folders.push_unseparated(" AND path < ");
+                folders.push_bind_unseparated(upper);
+                folders.push_unseparated(")");
+            }
+            query.push(")");
+        }
+        query.push(" ORDER BY occurred_at DESC, id DESC LIMIT ");
+        query.push_bind(limit);
+
+        let rows = query.build().fetch_all(&self.pool).await?;
+
+        let mut events = Vec::new();
+        for row in rows {
+            let kind: String = row.try_get("kind")?;
+            // Kinds this version doesn't know are left out
+            let kind = match kind.as_str() {
+                "Created" => ActivityKind::Created,
+                "Modified" => ActivityKind::Modified,
+                "Moved" => ActivityKind::Moved,
+                "Deleted" => ActivityKind::Deleted,
+                "SharedDownload" => ActivityKind::SharedDownload,
+                "Commented" => ActivityKind::Commented,
+                _ => continue,
+            };
+            events.push(ActivityEvent {
+                id: row.try_get("id")?,
+                kind,
+                file_id: row.try_get("file_id")?,
+                path: row.try_get("path")?,
+                occurred_at: row.try_get("occurred_at")?,
+                actor_id: row.try_get("actor_id")?,
+                device_id: row.try_get("device_id")?,
+            });
+        }
+
+        Ok(events)
+    }
+
+    /// Files whose checksum was verified longest ago, never-verified ones first
+    pub async fn get_files_due_for_verification(&self, limit: u32) -> Result<Vec<FileMetadata>> {
+        let limit = limit as i64;
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM file_metadata
+            WHERE is_directory = 0 AND deleted_at IS NULL
+            ORDER BY last_verified_at IS NOT NULL, last_verified_at
+            LIMIT ?1
+            "#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// Files at `path` or anywhere below it, across all owners
+    pub async fn get_files_under_path(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        let path = path.trim_end_matches('/');
+        let prefix = format!("{}/", path);
+        let prefix_len = prefix.chars().count() as i64;
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM file_metadata
+            WHERE is_directory = 0 AND deleted_at IS NULL AND (path = ?1 OR substr(path, 1, ?2) = ?3)
+            ORDER BY path
+            "#,
+            path,
+            prefix_len,
+            prefix
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// The owner's rows strictly below `path_prefix`, in path order. Rows in
+    /// the trash are left out.
+    pub async fn get_descendants(&self, owner_id: Uuid, path_prefix: &str) -> Result<Vec<FileMetadata>> {
+        let (lower, upper) = descendant_range(path_prefix);
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM file_metadata
+            WHERE owner_id = ?1 AND path >= ?2 AND path < ?3 AND deleted_at IS NULL
+            ORDER BY path
+            "#,
+            owner_id,
+            lower,
+            upper
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// One page of the owner's rows strictly below `path_prefix`, in path
+    /// order from after the path `after` when given. Rows in the trash are
+    /// left out.
+    pub async fn get_descendants_page(
+        &self,
+        owner_id: Uuid,
+        path_prefix: &str,
+        after: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<FileMetadata>> {
+        let (lower, upper) = descendant_range(path_prefix);
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM file_metadata
+            WHERE owner_id = ?1 AND path >= ?2 AND path < ?3
+              AND path > COALESCE(?4, '')
+              AND deleted_at IS NULL
+            ORDER BY path
+            LIMIT ?5 OFFSET ?6
+            "#,
+            owner_id,
+            lower,
+            upper,
+            after,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// How many rows `get_descendants_page` lists in all
+    pub async fn count_descendants(&self, owner_id: Uuid, path_prefix: &str) -> Result<u64> {
+        let (lower, upper) = descendant_range(path_prefix);
+
+        let row = sqlx::query!(
+            r#"
+            SELECT COUNT(*) as "count!: i64" FROM file_metadata
+            WHERE owner_id = ?1 AND path >= ?2 AND path < ?3 AND deleted_at IS NULL
+            "#,
+            owner_id,
+            lower,
+            upper
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.count as u64)
+    }
+
+    /// One page of the entries right inside the owner's `folder`, by name,
+    /// from after the one named `after` when given. Entries in the trash are
+    /// left out.
+    pub async fn get_children_page(
+        &self,
+        owner_id: Uuid,
+        folder: &str,
+        after: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<FileMetadata>> {
+        let (lower, upper) = descendant_range(folder);
+        // Names share the folder's prefix, so the path orders them too
+        let after = after.map(|name| format!("{}{}", lower, name));
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM file_metadata
+            WHERE owner_id = ?1 AND path >= ?2 AND path < ?3
+              AND instr(substr(path, length(?2) + 1), '/') = 0
+              AND path > COALESCE(?4, '')
+              AND deleted_at IS NULL
+            ORDER BY path
+            LIMIT ?5 OFFSET ?6
+            "#,
+            owner_id,
+            lower,
+            upper,
+            after,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// How many entries `get_children_page` lists in all
+    pub async fn count_children(&self, owner_id: Uuid, folder: &str) -> Result<u64> {
+        let (lower, upper) = descendant_range(folder);
+
+        let row = sqlx::query!(
+            r#"
+            SELECT COUNT(*) as "count!: i64" FROM file_metadata
+            WHERE owner_id = ?1 AND path >= ?2 AND path < ?3
+              AND instr(substr(path, length(?2) + 1), '/') = 0
+              AND deleted_at IS NULL
+            "#,
+            owner_id,
+            lower,
+            upper
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.count as u64)
+    }
+
+    /// Up to `limit` of the owner's entries at or under `folder`, in path
+    /// order after `after`, for walking a tree a page at a time. Entries in
+    /// the trash are left out.
+    pub async fn get_folder_entries_page(
+        &self,
+        owner_id: Uuid,
+        folder: &str,
+        after: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<FileMetadata>> {
+        let folder = folder.trim_end_matches('/');
+        let (lower, upper) = descendant_range(folder);
+        let limit = limit as i64;
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM file_metadata
+            WHERE owner_id = ?1
+              AND (path = ?2 OR (path >= ?3 AND path < ?4))
+              AND (?5 IS NULL OR path > ?5)
+              AND deleted_at IS NULL
+            ORDER BY path
+            LIMIT ?6
+            "#,
+            owner_id,
+            folder,
+            lower,
+            upper,
+            after,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// One page of `owner_id`'s files matching `search`, outside the trash,
+    /// with how many match in all. Name matches come best first, and
+    /// otherwise the most recently modified do.
+    pub async fn search_files(
+        &self,
+        owner_id: Uuid,
+        search: &FileSearch,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<FileMetadata>, u64)> {
+        let words = search.query.as_deref().and_then(fts_query);
+
+        let mut count = QueryBuilder::<Sqlite>::new("");
+        push_file_search(&mut count, "COUNT(*) AS count", owner_id, search, words.as_deref());
+        let total: i64 = count.build().fetch_one(&self.pool).await?.try_get("count")?;
+
+        let mut query = QueryBuilder::<Sqlite>::new("");
+        push_file_search(&mut query, "file_metadata.*", owner_id, search, words.as_deref());
+        query.push(if words.is_some() { " ORDER BY file_search.rank, " } else { " ORDER BY " });
+        query.push("file_metadata.modified_at DESC LIMIT ");
+        query.push_bind(limit);
+        query.push(" OFFSET ");
+        query.push_bind(offset);
+
+        let mut files = Vec::new();
+        for row in query.build().fetch_all(&self.pool).await? {
+            let permissions: String = row.try_get("permissions")?;
+            let size: i64 = row.try_get("size")?;
+            files.push(FileMetadata {
+                id: row.try_get("id")?,
+                name: row.try_get("name")?,
+                path: row.try_get("path")?,
+                size: size as u64,
+                mime_type: row.try_get("mime_type")?,
+                checksum: row.try_get("checksum")?,
+                created_at: row.try_get("created_at")?,
+                modified_at: row.try_get("modified_at")?,
+                owner_id: row.try_get("owner_id")?,
+                is_directory: row.try_get("is_directory")?,
+                is_symlink: false,
+                parent_id: row.try_get("parent_id")?,
+                permissions: serde_json::from_str(&permissions)?,
+            });
+        }
+
+        Ok((files, total as u64))
+    }
+
+    /// Index the names of every file for search afresh, for databases whose
+    /// index has drifted from `file_metadata`
+    pub async fn rebuild_search_index(&self) -> Result<()> {
+        sqlx::query!("INSERT INTO file_search (file_search) VALUES ('rebuild')")
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Owner and path of every row, trashed ones included
+    pub async fn list_file_paths(&self) -> Result<Vec<(Uuid, String)>> {
+        let rows = sqlx::query!("SELECT owner_id, path FROM file_metadata")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|row| (row.owner_id, row.path)).collect())
+    }
+
+    /// Rows recorded at exactly `path`, across all owners
+    pub async fn get_file_metadata_for_path(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM file_metadata WHERE path = ?1 AND deleted_at IS NULL",
+            path
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// Whether any row is recorded at each of `paths`, keyed by path, with
+    /// `true` for directories
+    pub async fn get_tracked_kinds(&self, paths: &[String]) -> Result<HashMap<String, bool>> {
+        let mut found = HashMap::new();
+
+        for chunk in paths.chunks(MAX_BIND_PARAMS) {
+            let mut query = QueryBuilder::<Sqlite>::new(
+                "SELECT path, is_directory FROM file_metadata WHERE deleted_at IS NULL AND path IN (",
+            );
+            let mut separated = query.separated(", ");
+            for path in chunk {
+                separated.push_bind(path);
+            }
+            separated.push_unseparated(")");
+
+            for row in query.build().fetch_all(&self.pool).await? {
+                let path: String = row.try_get("path")?;
+                let is_directory: bool = row.try_get("is_directory")?;
+                found.insert(path, is_directory);
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Up to `limit` rows outside the trash ordered by id, starting after
+    /// `after`, for walking the whole table in batches
+    pub async fn get_file_metadata_page(&self, after: Option<Uuid>, limit: u32) -> Result<Vec<FileMetadata>> {
+        let limit = limit as i64;
+        let rows = sqlx::query!(
+            "SELECT * FROM file_metadata WHERE (?1 IS NULL OR id > ?1) AND deleted_at IS NULL ORDER BY id LIMIT ?2",
+            after,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            let permissions: FilePermissions = serde_json::from_str(&row.permissions)?;
+
+            files.push(FileMetadata {
+                id: row.id,
+                name: row.name,
+                path: row.path,
+                size: row.size as u64,
+                mime_type: row.mime_type,
+                checksum: row.checksum,
+                created_at: row.created_at,
+                modified_at: row.modified_at,
+                owner_id: row.owner_id,
+                is_directory: row.is_directory,
+                is_symlink: false,
+                parent_id: row.parent_id,
+                permissions,
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// Give `owner_id` the rows of other users, for content moved into their
+    /// home. Paths they already have a row for, all but one row of paths
+    /// several others share, and trashed entries (whose content stays with
+    /// their user) are left as they are. Returns the rows reassigned.
+    pub async fn assign_files_to_owner(&self, owner_id: Uuid) -> Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE file_metadata SET owner_id = ?1
+            WHERE id IN (
+                SELECT MIN(id) FROM file_metadata
+                WHERE owner_id != ?1
+                AND path NOT IN (SELECT path FROM file_metadata WHERE owner_id = ?1)
+                AND deleted_at IS NULL
+                GROUP BY path
+            )
+            "#,
+            owner_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn mark_file_verified(&self, file_id: Uuid, verified_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query!(
+            "UPDATE file_metadata SET last_verified_at = ?1 WHERE id = ?2",
+            verified_at,
+            file_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Take the current on-disk state as the new reference for a file
+    pub async fn restamp_file(
+        &self,
+        file_id: Uuid,
+        checksum: &str,
+        size: u64,
+        modified_at: DateTime<Utc>,
+        verified_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let size = size as i64;
+        let mut tx = self.begin_write().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE file_metadata
+            SET checksum = ?1, size = ?2, modified_at = ?3, last_verified_at = ?4
+            WHERE id = ?5
+            "#,
+            checksum,
+            size,
+            modified_at,
+            verified_at,
+            file_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        Self::insert_row_change(&mut tx, file_id, ChangeType::Modified).await?;
+
+        tx.commit().await?;
+        self.changes_recorded();
+        Ok(())
+    }
+
+    /// Record a mismatch, replacing any earlier issue for the same file
+    pub async fn record_integrity_issue(&self, issue: &IntegrityIssue) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO integrity_issues (id, file_id, path, expected_checksum, actual_checksum, detected_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT (file_id) DO UPDATE SET
+                path = excluded.path,
+                expected_checksum = excluded.expected_checksum,
+                actual_checksum = excluded.actual_checksum,
+                detected_at = excluded.detected_at
+            "#,
+            issue.id,
+            issue.file_id,
+            issue.path,
+            issue.expected_checksum,
+            issue.actual_checksum,
+            issue.detected_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn clear_integrity_issue(&self, file_id: Uuid) -> Result<()> {
+        sqlx::query!("DELETE FROM integrity_issues WHERE file_id = ?1", file_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn list_integrity_issues(&self) -> Result<Vec<IntegrityIssue>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM integrity_issues ORDER BY detected_at DESC"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| IntegrityIssue {
+                id: row.id,
+                file_id: row.file_id,
+                path: row.path,
+                expected_checksum: row.expected_checksum,
+                actual_checksum: row.actual_checksum,
+                detected_at: row.detected_at,
+            })
+            .collect())
+    }
+
+    /// The unexpired lock on `path` in the home `home_id`, if any
+    pub async fn get_file_lock(&self, home_id: Uuid, path: &str) -> Result<Option<FileLock>> {
+        let now = Utc::now();
+        let row = sqlx::query!(
+            r#"
+            SELECT fl.*, u.username FROM file_locks fl
+            JOIN users u ON u.id = fl.owner_id
+            WHERE fl.home_id = ?1 AND fl.path = ?2 AND fl.expires_at > ?3
+            "#,
+            home_id,
+            path,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| FileLock {
+            id: row.id,
+            home_id: row.home_id,
+            path: row.path,
+            owner_id: row.owner_id,
+            owner_name: row.username,
+            device_id: row.device_id,
+            token: row.token,
+            acquired_at: row.acquired_at,
+            expires_at: row.expires_at,
+        }))
+    }
+
+    /// Take the lock unless someone holds an unexpired one. Returns whether it was taken.
+    pub async fn acquire_file_lock(&self, lock: &FileLock) -> Result<bool> {
+        let mut tx = self.begin_write().await?;
+
+        sqlx::query!(
+            "DELETE FROM file_locks WHERE home_id = ?1 AND path = ?2 AND expires_at <= ?3",
+            lock.home_id,
+            lock.path,
+            lock.acquired_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let result = sqlx::query!(
+            r#"
+            INSERT OR IGNORE INTO file_locks (id, home_id, path, owner_id, device_id, token, acquired_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            "#,
+            lock.id,
+            lock.home_id,
+            lock.path,
+            lock.owner_id,
+            lock.device_id,
+            lock.token,
+            lock.acquired_at,
+            lock.expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn refresh_file_lock(&self, lock_id: Uuid, expires_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query!(
+            "UPDATE file_locks SET expires_at = ?1 WHERE id = ?2",
+            expires_at,
+            lock_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_file_lock(&self, lock_id: Uuid) -> Result<()> {
+        sqlx::query!("DELETE FROM file_locks WHERE id = ?1", lock_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Drop the locks on `path` and below in the home `home_id`, once what
+    /// they lock is gone
+    pub async fn delete_file_locks_under(&self, home_id: Uuid, path: &str) -> Result<()> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        let prefix_len = prefix.chars().count() as i64;
+        sqlx::query!(
+            "DELETE FROM file_locks WHERE home_id = ?1 AND (path = ?2 OR substr(path, 1, ?3) = ?4)",
+            home_id,
+            path,
+            prefix_len,
+            prefix
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record a refresh token, dropping the user's expired ones on the way
+    pub async fn create_refresh_token(&self, token: &RefreshToken) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+
+        sqlx::query!(
+            "DELETE FROM refresh_tokens WHERE user_id = ?1 AND expires_at <= ?2",
+            token.user_id,
+            token.created_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO refresh_tokens (id, user_id, token_hash, device_id, session_id, created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            "#,
+            token.id,
+            token.user_id,
+            token.token_hash,
+            token.device_id,
+            token.session_id,
+            token.created_at,
+            token.expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// The unexpired refresh token with this hash, if any
+    pub async fn get_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshToken>> {
+        let now = Utc::now();
+        let row = sqlx::query!(
+            "SELECT * FROM refresh_tokens WHERE token_hash = ?1 AND expires_at > ?2",
+            token_hash,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| RefreshToken {
+            id: row.id,
+            user_id: row.user_id,
+            token_hash: row.token_hash,
+            device_id: row.device_id,
+            session_id: row.session_id,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+        }))
+    }
+
+    /// Swap a refresh token for a new one. Returns false if the old one was
+    /// already used, so two clients can't both rotate the same token.
+    pub async fn rotate_refresh_token(&self, old_id: Uuid, new: &RefreshToken) -> Result<bool> {
+        let mut tx = self.begin_write().await?;
+
+        let deleted = sqlx::query!("DELETE FROM refresh_tokens WHERE id = ?1", old_id)
+            .execute(&mut *tx)
+            .await?;
+        if deleted.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            r#"
+            INSERT INTO refresh_tokens (id, user_id, token_hash, device_id, session_id, created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            "#,
+            new.id,
+            new.user_id,
+            new.token_hash,
+            new.device_id,
+            new.session_id,
+            new.created_at,
+            new.expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Revoke the user's refresh tokens issued to one device. Returns how many there were.
+    pub async fn delete_device_refresh_tokens(&self, user_id: Uuid, device_id: &str) -> Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM refresh_tokens WHERE user_id = ?1 AND device_id = ?2",
+            user_id,
+            device_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Record a revoked access token until it would have expired
+    pub async fn revoke_token(&self, jti: &str, user_id: Uuid, expires_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query!(
+            "INSERT OR IGNORE INTO revoked_tokens (jti, user_id, expires_at) VALUES (?1, ?2, ?3)",
+            jti,
+            user_id,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revoked tokens that haven't expired yet, with their expiry as a
+    /// timestamp. Expired ones are dropped first.
+    pub async fn get_revoked_tokens(&self) -> Result<Vec<(String, i64)>> {
+        let now = Utc::now();
+        sqlx::query!("DELETE FROM revoked_tokens WHERE expires_at <= ?1", now)
+            .execute(&self.pool)
+            .await?;
+
+        let rows = sqlx::query!("SELECT jti, expires_at FROM revoked_tokens")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|row| (row.jti, row.expires_at.timestamp())).collect())
+    }
+
+    /// Invalidate every token issued to the user up to `at`, refresh tokens
+    /// included. Returns false if there is no such user.
+    pub async fn set_tokens_valid_after(&self, user_id: Uuid, at: DateTime<Utc>) -> Result<bool> {
+        let mut tx = self.begin_write().await?;
+
+        let updated = sqlx::query!(
+            "UPDATE users SET tokens_valid_after = ?1 WHERE id = ?2",
+            at,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!("DELETE FROM refresh_tokens WHERE user_id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("DELETE FROM sessions WHERE user_id = ?1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(updated.rows_affected() > 0)
+    }
+
+    pub async fn get_tokens_valid_after(&self) -> Result<Vec<(Uuid, DateTime<Utc>)>> {
+        let rows = sqlx::query!(
+            r#"SELECT id, tokens_valid_after as "tokens_valid_after!: DateTime<Utc>" FROM users WHERE tokens_valid_after IS NOT NULL"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.id, row.tokens_valid_after)).collect())
+    }
+
+    pub async fn create_api_token(&self, token: &ApiToken) -> Result<()> {
+        let scope = token.scope.as_str();
+        let path_scopes = serde_json::to_string(&token.path_scopes)?;
+        sqlx::query!(
+            r#"
+            INSERT INTO api_tokens (id, user_id, name, token_hash, scope, path_scopes, created_at, expires_at, last_used_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            "#,
+            token.id,
+            token.user_id,
+            token.name,
+            token.token_hash,
+            scope,
+            path_scopes,
+            token.created_at,
+            token.expires_at,
+            token.last_used_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The unexpired token with this hash and its owner, as long
+    /// as the owner is still active
+    pub async fn get_api_token(&self, token_hash: &str) -> Result<Option<(ApiToken, User)>> {
+        let now = Utc::now();
+        let row = sqlx::query!(
+            r#"
+            SELECT t.*
+            FROM api_tokens t
+            JOIN users u ON u.id = t.user_id
+            WHERE t.token_hash = ?1 AND u.is_active = 1
+              AND (t.expires_at IS NULL OR t.expires_at > ?2)
+            "#,
+            token_hash,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let scope = TokenScope::parse(&row.scope)
+            .ok_or_else(|| anyhow::anyhow!("Unknown token scope '{}'", row.scope))?;
+
+        let token = ApiToken {
+            id: row.id,
+            user_id: row.user_id,
+            name: row.name,
+            token_hash: row.token_hash,
+            scope,
+            path_scopes: serde_json::from_str(&row.path_scopes)?,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+        };
+        Ok(self.get_user_by_id(token.user_id).await?.map(|owner| (token, owner)))
+    }
+
+    pub async fn list_api_tokens(&self, user_id: Uuid) -> Result<Vec<ApiToken>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM api_tokens WHERE user_id = ?1 ORDER BY created_at",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut tokens = Vec::with_capacity(rows.len());
+        for row in rows {
+            let scope = TokenScope::parse(&row.scope)
+                .ok_or_else(|| anyhow::anyhow!("Unknown token scope '{}'", row.scope))?;
+            tokens.push(ApiToken {
+                id: row.id,
+                user_id: row.user_id,
+                name: row.name,
+                token_hash: row.token_hash,
+                scope,
+                path_scopes: serde_json::from_str(&row.path_scopes)?,
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+                last_used_at: row.last_used_at,
+            });
+        }
+
+        Ok(tokens)
+    }
+
+    /// Note a token's use. Only written once a minute per token, so busy
+    /// scripts don't turn every request into a database write.
+    pub async fn touch_api_token(&self, token_id: Uuid, at: DateTime<Utc>) -> Result<()> {
+        let stale_before = at - chrono::Duration::minutes(1);
+        sqlx::query!(
+            r#"
+            UPDATE api_tokens SET last_used_at = ?1
+            WHERE id = ?2 AND (last_used_at IS NULL OR last_used_at < ?3)
+            "#,
+            at,
+            token_id,
+            stale_before
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns false if the user has no such token
+    pub async fn delete_api_token(&self, user_id: Uuid, token_id: Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM api_tokens WHERE id = ?1 AND user_id = ?2",
+            token_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn create_app_password(&self, app_password: &AppPassword) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO app_passwords (id, user_id, label, password_hash, path_prefix, created_at, expires_at, last_used_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            "#,
+            app_password.id,
+            app_password.user_id,
+            app_password.label,
+            app_password.password_hash,
+            app_password.path_prefix,
+            app_password.created_at,
+            app_password.expires_at,
+            app_password.last_used_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The unexpired app password with this ID and its owner, as long as
+    /// the owner is still active
+    pub async fn get_app_password(&self, id: Uuid) -> Result<Option<(AppPassword, User)>> {
+        let now = Utc::now();
+        let row = sqlx::query!(
+            r#"
+            SELECT a.*
+            FROM app_passwords a
+            JOIN users u ON u.id = a.user_id
+            WHERE a.id = ?1 AND u.is_active = 1
+              AND (a.expires_at IS NULL OR a.expires_at > ?2)
+            "#,
+            id,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let app_password = match row {
+            Some(row) => AppPassword {
+                id: row.id,
+                user_id: row.user_id,
+                label: row.label,
+                password_hash: row.password_hash,
+                path_prefix: row.path_prefix,
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+                last_used_at: row.last_used_at,
+            },
+            None => return Ok(None),
+        };
+        Ok(self.get_user_by_id(app_password.user_id).await?.map(|owner| (app_password, owner)))
+    }
+
+    /// The user's app passwords, oldest first. Expired ones are left out
+    /// unless `include_expired` is set.
+    pub async fn list_app_passwords(&self, user_id: Uuid, include_expired: bool) -> Result<Vec<AppPassword>> {
+        let now = Utc::now();
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM app_passwords
+            WHERE user_id = ?1 AND (?2 OR expires_at IS NULL OR expires_at > ?3)
+            ORDER BY created_at
+            "#,
+            user_id,
+            include_expired,
+            now
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| AppPassword {
+            id: row.id,
+            user_id: row.user_id,
+            label: row.label,
+            password_hash: row.password_hash,
+            path_prefix: row.path_prefix,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+        }).collect())
+    }
+
+    /// Note an app password's use, at most once a minute like `touch_api_token`
+    pub async fn touch_app_password(&self, id: Uuid, at: DateTime<Utc>) -> Result<()> {
+        let stale_before = at - chrono::Duration::minutes(1);
+        sqlx::query!(
+            r#"
+            UPDATE app_passwords SET last_used_at = ?1
+            WHERE id = ?2 AND (last_used_at IS NULL OR last_used_at < ?3)
+            "#,
+            at,
+            id,
+            stale_before
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns false if the user has no such app password
+    pub async fn delete_app_password(&self, user_id: Uuid, id: Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM app_passwords WHERE id = ?1 AND user_id = ?2",
+            id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn record_login_attempt(&self, attempt: &LoginAttempt) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO login_attempts (id, username, ip_address, outcome, attempted_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+            attempt.id,
+            attempt.username,
+            attempt.ip_address,
+            attempt.outcome,
+            attempt.attempted_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Failures for a username after `since` and its last successful login,
+    /// and when the latest one happened
+    pub async fn get_consecutive_login_failures(
+        &self,
+        username: &str,
+        since: DateTime<Utc>,
+    ) -> Result<(u32, Option<DateTime<Utc>>)> {
+        let row = sqlx::query!(
+            r#"
+            SELECT COUNT(*) as "failures!: i64", MAX(attempted_at) as "last_failure: DateTime<Utc>"
+            FROM login_attempts
+            WHERE username = ?1 AND outcome = 'failure'
+              AND attempted_at > ?2
+              AND attempted_at > COALESCE(
+                  (SELECT MAX(attempted_at) FROM login_attempts WHERE username = ?1 AND outcome = 'success'),
+                  ''
+              )
+            "#,
+            username,
+            since
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((row.failures as u32, row.last_failure))
+    }
+
+    /// Most recent attempts first, optionally for one username, from after
+    /// the attempt made at and with the id in `after` when given
+    pub async fn list_login_attempts(
+        &self,
+        username: Option<&str>,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<LoginAttempt>> {
+        let (after_attempted, after_id) = after.unzip();
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM login_attempts
+            WHERE (?1 IS NULL OR username = ?1)
+              AND (attempted_at, id) < (COALESCE(?2, '9999'), COALESCE(?3, ''))
+            ORDER BY attempted_at DESC, id DESC
+            LIMIT ?4 OFFSET ?5
+            "#,
+            username,
+            after_attempted,
+            after_id,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| LoginAttempt {
+            id: row.id,
+            username: row.username,
+            ip_address: row.ip_address,
+            outcome: row.outcome,
+            attempted_at: row.attempted_at,
+        }).collect())
+    }
+
+    pub async fn count_login_attempts(&self, username: Option<&str>) -> Result<u64> {
+        let row = sqlx::query!(
+            r#"SELECT COUNT(*) as "count!: i64" FROM login_attempts WHERE ?1 IS NULL OR username = ?1"#,
+            username
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.count as u64)
+    }
+
+    pub async fn purge_login_attempts_before(&self, before: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query!("DELETE FROM login_attempts WHERE attempted_at < ?1", before)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn record_audit_event(&self, entry: &AuditEntry) -> Result<()> {
+        let event = entry.event.as_str();
+        sqlx::query!(
+            r#"
+            INSERT INTO audit_log
+            (id, occurred_at, user_id, username, impersonating, event, ip_address, user_agent, target, outcome)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+            "#,
+            entry.id,
+            entry.occurred_at,
+            entry.user_id,
+            entry.username,
+            entry.impersonating,
+            event,
+            entry.ip_address,
+            entry.user_agent,
+            entry.target,
+            entry.outcome
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Most recent entries first, `limit` at a time from after the entry
+    /// in `after` and then `offset`, optionally only those since a time, for
+    /// one username or of one kind
+    pub async fn list_audit_events(
+        &self,
+        since: Option<DateTime<Utc>>,
+        username: Option<&str>,
+        event: Option<AuditEvent>,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<AuditEntry>> {
+        let event = event.map(AuditEvent::as_str);
+        let (after_occurred, after_id) = after.unzip();
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM audit_log
+            WHERE (?1 IS NULL OR occurred_at >= ?1)
+              AND (?2 IS NULL OR username = ?2)
+              AND (?3 IS NULL OR event = ?3)
+              AND (occurred_at, id) < (COALESCE(?4, '9999'), COALESCE(?5, ''))
+            ORDER BY occurred_at DESC, id DESC
+            LIMIT ?6 OFFSET ?7
+            "#,
+            since,
+            username,
+            event,
+            after_occurred,
+            after_id,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        // Kinds this version doesn't know are left out
+        Ok(rows.into_iter().filter_map(|row| Some(AuditEntry {
+            id: row.id,
+            occurred_at: row.occurred_at,
+            user_id: row.user_id,
+            username: row.username,
+            impersonating: row.impersonating,
+            event: AuditEvent::parse(&row.event)?,
+            ip_address: row.ip_address,
+            user_agent: row.user_agent,
+            target: row.target,
+            outcome: row.outcome,
+        })).collect())
+    }
+
+    pub async fn count_audit_events(
+        &self,
+        since: Option<DateTime<Utc>>,
+        username: Option<&str>,
+        event: Option<AuditEvent>,
+    ) -> Result<u64> {
+        let event = event.map(AuditEvent::as_str);
+        let row = sqlx::query!(
+            r#"
+            SELECT COUNT(*) as "count!: i64" FROM audit_log
+            WHERE (?1 IS NULL OR occurred_at >= ?1)
+              AND (?2 IS NULL OR username = ?2)
+              AND (?3 IS NULL OR event = ?3)
+            "#,
+            since,
+            username,
+            event
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.count as u64)
+    }
+
+    /// Log a visit, with the link's token so it stays readable once the
+    /// link is deleted
+    pub async fn record_share_access(&self, access: &ShareAccess) -> Result<()> {
+        let outcome = access.outcome.as_str();
+        sqlx::query!(
+            r#"
+            INSERT INTO share_access_log (id, share_id, share_token, accessed_at, ip_address, user_agent, bytes_served, outcome, detail)
+            VALUES (?1, ?2, (SELECT share_token FROM share_links WHERE id = ?2), ?3, ?4, ?5, ?6, ?7, ?8)
+            "#,
+            access.id,
+            access.share_id,
+            access.accessed_at,
+            access.ip_address,
+            access.user_agent,
+            access.bytes_served as i64,
+            outcome,
+            access.detail
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Visits to a share link, newest first, from after the visit made at
+    /// and with the id in `after` when given
+    pub async fn list_share_access(
+        &self,
+        share_id: Uuid,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<ShareAccess>> {
+        let (after_accessed, after_id) = after.unzip();
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, share_id as "share_id!: Uuid", accessed_at, ip_address, user_agent, bytes_served, outcome, detail
+            FROM share_access_log
+            WHERE share_id = ?1 AND (accessed_at, id) < (COALESCE(?2, '9999'), COALESCE(?3, ''))
+            ORDER BY accessed_at DESC, id DESC
+            LIMIT ?4 OFFSET ?5
+            "#,
+            share_id,
+            after_accessed,
+            after_id,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        // Outcomes this version doesn't know are left out
+        Ok(rows.into_iter().filter_map(|row| Some(ShareAccess {
+            id: row.id,
+            share_id: row.share_id,
+            accessed_at: row.accessed_at,
+            ip_address: row.ip_address,
+            user_agent: row.user_agent,
+            bytes_served: row.bytes_served as u64,
+            outcome: ShareAccessOutcome::parse(&row.outcome)?,
+            detail: row.detail,
+        })).collect())
+    }
+
+    pub async fn count_share_access(&self, share_id: Uuid) -> Result<u64> {
+        let row = sqlx::query!(
+            r#"SELECT COUNT(*) as "count!: i64" FROM share_access_log WHERE share_id = ?1"#,
+            share_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.count as u64)
+    }
+
+    pub async fn purge_share_access_before(&self, before: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query!("DELETE FROM share_access_log WHERE accessed_at < ?1", before)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn purge_audit_events_before(&self, before: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query!("DELETE FROM audit_log WHERE occurred_at < ?1", before)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn create_webhook_delivery(&self, delivery: &WebhookDelivery) -> Result<()> {
+        let event = delivery.event.as_str();
+        let status = delivery.status.as_str();
+        let attempts = delivery.attempts as i64;
+        let response_status = delivery.response_status.map(|status| status as i64);
+        sqlx::query!(
+            r#"
+            INSERT INTO webhook_deliveries (id, webhook, event, path, status, attempts, response_status, error, next_attempt_at, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+            "#,
+            delivery.id,
+            delivery.webhook,
+            event,
+            delivery.path,
+            status,
+            attempts,
+            response_status,
+            delivery.error,
+            delivery.next_attempt_at,
+            delivery.created_at,
+            delivery.updated_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record how the latest attempt at a delivery went
+    pub async fn update_webhook_delivery(&self, delivery: &WebhookDelivery) -> Result<()> {
+        let status = delivery.status.as_str();
+        let attempts = delivery.attempts as i64;
+        let response_status = delivery.response_status.map(|status| status as i64);
+        sqlx::query!(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = ?1, attempts = ?2, response_status = ?3, error = ?4, next_attempt_at = ?5, updated_at = ?6
+            WHERE id = ?7
+            "#,
+            status,
+            attempts,
+            response_status,
+            delivery.error,
+            delivery.next_attempt_at,
+            delivery.updated_at,
+            delivery.id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Mark every delivery still pending as failed with `error`, for those a
+    /// shutdown cut short. Returns how many there were.
+    pub async fn fail_pending_webhook_deliveries(&self, error: &str, at: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE webhook_deliveries SET status = 'failed', error = ?1, next_attempt_at = NULL, updated_at = ?2 WHERE status = 'pending'",
+            error,
+            at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Deliveries to one webhook, or to all of them, newest first, from
+    /// after the delivery created at and with the id in `after` when given
+    pub async fn list_webhook_deliveries(
+        &self,
+        webhook: Option<&str>,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<WebhookDelivery>> {
+        let (after_created, after_id) = after.unzip();
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM webhook_deliveries
+            WHERE (?1 IS NULL OR webhook = ?1)
+              AND (created_at, id) < (COALESCE(?2, '9999'), COALESCE(?3, ''))
+            ORDER BY created_at DESC, id DESC
+            LIMIT ?4 OFFSET ?5
+            "#,
+            webhook,
+            after_created,
+            after_id,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        // Events and statuses this version doesn't know are left out
+        Ok(rows.into_iter().filter_map(|row| Some(WebhookDelivery {
+            id: row.id,
+            webhook: row.webhook,
+            event: WebhookEvent::parse(&row.event)?,
+            path: row.path,
+            status: WebhookDeliveryStatus::parse(&row.status)?,
+            attempts: row.attempts as u32,
+            response_status: row.response_status.map(|status| status as u16),
+            error: row.error,
+            next_attempt_at: row.next_attempt_at,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        })).collect())
+    }
+
+    pub async fn count_webhook_deliveries(&self, webhook: Option<&str>) -> Result<u64> {
+        let row = sqlx::query!(
+            r#"SELECT COUNT(*) as "count!: i64" FROM webhook_deliveries WHERE ?1 IS NULL OR webhook = ?1"#,
+            webhook
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.count as u64)
+    }
+
+    pub async fn purge_webhook_deliveries_before(&self, before: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query!("DELETE FROM webhook_deliveries WHERE created_at < ?1", before)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn create_upload_session(&self, session: &UploadSession) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO upload_sessions
+            (id, user_id, target_path, total_size, chunk_size, checksum, overwrite, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            "#,
+            session.id,
+            session.user_id,
+            session.target_path,
+            session.total_size as i64,
+            session.chunk_size as i64,
+            session.checksum,
+            session.overwrite,
+            session.created_at,
+            session.updated_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_upload_session(&self, session_id: Uuid) -> Result<Option<UploadSession>> {
+        let row = sqlx::query!(
+            "SELECT * FROM upload_sessions WHERE id = ?1",
+            session_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| UploadSession {
+            id: row.id,
+            user_id: row.user_id,
+            target_path: row.target_path,
+            total_size: row.total_size as u64,
+            chunk_size: row.chunk_size as u64,
+            checksum: row.checksum,
+            overwrite: row.overwrite,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            hash_state: row.hash_state,
+        }))
+    }
+
+    pub async fn update_upload_session_hash(&self, session_id: Uuid, hash_state: &[u8]) -> Result<()> {
+        sqlx::query!(
+            "UPDATE upload_sessions SET hash_state = ?1 WHERE id = ?2",
+            hash_state,
+            session_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn mark_upload_chunk_received(&self, session_id: Uuid, chunk_index: u32) -> Result<()> {
+        let now = Utc::now();
+        let mut tx = self.begin_write().await?;
+
+        sqlx::query!(
+            "INSERT OR IGNORE INTO upload_session_chunks (session_id, chunk_index) VALUES (?1, ?2)",
+            session_id,
+            chunk_index as i64
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE upload_sessions SET updated_at = ?1 WHERE id = ?2",
+            now,
+            session_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn get_received_chunks(&self, session_id: Uuid) -> Result<Vec<u32>> {
+        let rows = sqlx::query!(
+            "SELECT chunk_index FROM upload_session_chunks WHERE session_id = ?1 ORDER BY chunk_index",
+            session_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| row.chunk_index as u32).collect())
+    }
+
+    pub async fn delete_upload_session(&self, session_id: Uuid) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+
+        sqlx::query!("DELETE FROM upload_session_chunks WHERE session_id = ?1", session_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("DELETE FROM upload_sessions WHERE id = ?1", session_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Sessions that have not received a chunk since `before`
+    pub async fn get_user_upload_sessions(&self, user_id: Uuid) -> Result<Vec<Uuid>> {
+        let rows = sqlx::query!(
+            "SELECT id FROM upload_sessions WHERE user_id = ?1",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| row.id).collect())
+    }
+
+    pub async fn get_stale_upload_sessions(&self, before: DateTime<Utc>) -> Result<Vec<Uuid>> {
+        let rows = sqlx::query!(
+            "SELECT id FROM upload_sessions WHERE updated_at < ?1",
+            before
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| row.id).collect())
+    }
+
+    pub async fn create_trash_entry(&self, entry: &TrashEntry) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.create_trash_entry(entry).await?;
+        tx.commit().await
+    }
+
+    /// Insert a trash entry and, for tracked files, a deletion for the sync feed
+    async fn insert_trash_entry(conn: &mut SqliteConnection, entry: &TrashEntry) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO trash (id, user_id, file_id, original_path, name, is_directory, size, deleted_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            "#,
+            entry.id,
+            entry.user_id,
+            entry.file_id,
+            entry.original_path,
+            entry.name,
+            entry.is_directory,
+            entry.size as i64,
+            entry.deleted_at
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        if let Some(file_id) = entry.file_id {
+            // The rows stay, flagged as deleted, until the entry is purged. They
+            // move along with the content so the path is free for whatever is
+            // stored there next.
+            let location = FileSystemService::trash_location(entry.user_id, entry.id);
+            Self::relocate_rows(conn, entry.user_id, file_id, &entry.original_path, &location, Some(entry.deleted_at)).await?;
+
+            Self::insert_change(
+                conn,
+                entry.user_id,
+                file_id,
+                ChangeType::Deleted,
+                &entry.original_path,
+                None,
+                entry.deleted_at,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Put the rows of a trashed entry back at its original path and drop
+    /// the entry
+    pub async fn restore_trash_entry(&self, entry: &TrashEntry) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+
+        if let Some(file_id) = entry.file_id {
+            let location = FileSystemService::trash_location(entry.user_id, entry.id);
+            Self::relocate_rows(&mut tx, entry.user_id, file_id, &location, &entry.original_path, None).await?;
+        }
+
+        sqlx::query!("DELETE FROM trash WHERE id = ?1", entry.id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Move the row `file_id` from `from` to `to`, and the rows below it with
+    /// it, stamping them all with `deleted_at`
+    async fn relocate_rows(
+        conn: &mut SqliteConnection,
+        owner_id: Uuid,
+        file_id: Uuid,
+        from: &str,
+        to: &str,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        sqlx::query!("UPDATE file_metadata SET path = ?1, deleted_at = ?2 WHERE id = ?3", to, deleted_at, file_id)
+            .execute(&mut *conn)
+            .await?;
+
+        Self::rename_path_prefix(conn, owner_id, from, to).await?;
+
+        let (lower, upper) = descendant_range(to);
+        sqlx::query!(
+            "UPDATE file_metadata SET deleted_at = ?1 WHERE owner_id = ?2 AND path >= ?3 AND path < ?4",
+            deleted_at,
+            owner_id,
+            lower,
+            upper
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_trash_entry(&self, entry_id: Uuid) -> Result<Option<TrashEntry>> {
+        let row = sqlx::query!(
+            "SELECT * FROM trash WHERE id = ?1",
+            entry_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| TrashEntry {
+            id: row.id,
+            user_id: row.user_id,
+            file_id: row.file_id,
+            original_path: row.original_path,
+            name: row.name,
+            is_directory: row.is_directory,
+            size: row.size as u64,
+            deleted_at: row.deleted_at,
+        }))
+    }
+
+    pub async fn list_trash_entries(&self, user_id: Uuid) -> Result<Vec<TrashEntry>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM trash WHERE user_id = ?1 ORDER BY deleted_at DESC",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| TrashEntry {
+                id: row.id,
+                user_id: row.user_id,
+                file_id: row.file_id,
+                original_path: row.original_path,
+                name: row.name,
+                is_directory: row.is_directory,
+                size: row.size as u64,
+                deleted_at: row.deleted_at,
+            })
+            .collect())
+    }
+
+    /// One page of the user's trash, newest first, from after the entry
+    /// deleted at and with the id in `after` when given
+    pub async fn list_trash_page(
+        &self,
+        user_id: Uuid,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<TrashEntry>> {
+        let (after_deleted, after_id) = after.unzip();
+        // '9999' sorts after every stored time, so no cursor starts at the top
+        let rows = sqlx::query!(
+            r#"
+            SELECT * FROM trash
+            WHERE user_id = ?1 AND (deleted_at, id) < (COALESCE(?2, '9999'), COALESCE(?3, ''))
+            ORDER BY deleted_at DESC, id DESC
+            LIMIT ?4 OFFSET ?5
+            "#,
+            user_id,
+            after_deleted,
+            after_id,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| TrashEntry {
+                id: row.id,
+                user_id: row.user_id,
+                file_id: row.file_id,
+                original_path: row.original_path,
+                name: row.name,
+                is_directory: row.is_directory,
+                size: row.size as u64,
+                deleted_at: row.deleted_at,
+            })
+            .collect())
+    }
+
+    pub async fn count_trash_entries(&self, user_id: Uuid) -> Result<u64> {
+        let row = sqlx::query!(r#"SELECT COUNT(*) as "count!: i64" FROM trash WHERE user_id = ?1"#, user_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.count as u64)
+    }
+
+    /// Trash entries deleted before `before`, due for permanent removal
+    pub async fn get_expired_trash_entries(&self, before: DateTime<Utc>) -> Result<Vec<TrashEntry>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM trash WHERE deleted_at < ?1",
+            before
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| TrashEntry {
+                id: row.id,
+                user_id: row.user_id,
+                file_id: row.file_id,
+                original_path: row.original_path,
+                name: row.name,
+                is_directory: row.is_directory,
+                size: row.size as u64,
+                deleted_at: row.deleted_at,
+            })
+            .collect())
+    }
+
+    pub async fn delete_trash_entry(&self, entry_id: Uuid) -> Result<()> {
+        sqlx::query!("DELETE FROM trash WHERE id = ?1", entry_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn create_file_version(&self, version: &FileVersion) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO file_versions (id, file_id, version, size, checksum, modified_at, author_id, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            "#,
+            version.id,
+            version.file_id,
+            version.version as i64,
+            version.size as i64,
+            version.checksum,
+            version.modified_at,
+            version.author_id,
+            version.created_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Versions of a file, newest first
+    pub async fn list_file_versions(&self, file_id: Uuid) -> Result<Vec<FileVersion>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM file_versions WHERE file_id = ?1 ORDER BY version DESC",
+            file_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| FileVersion {
+                id: row.id,
+                file_id: row.file_id,
+                version: row.version as u32,
+                size: row.size as u64,
+                checksum: row.checksum,
+                modified_at: row.modified_at,
+                author_id: row.author_id,
+                created_at: row.created_at,
+            })
+            .collect())
+    }
+
+    pub async fn get_file_version(&self, file_id: Uuid, version: u32) -> Result<Option<FileVersion>> {
+        let row = sqlx::query!(
+            "SELECT * FROM file_versions WHERE file_id = ?1 AND version = ?2",
+            file_id,
+            version as i64
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| FileVersion {
+            id: row.id,
+            file_id: row.file_id,
+            version: row.version as u32,
+            size: row.size as u64,
+            checksum: row.checksum,
+            modified_at: row.modified_at,
+            author_id: row.author_id,
+            created_at: row.created_at,
+        }))
+    }
+
+    pub async fn get_latest_version_number(&self, file_id: Uuid) -> Result<u32> {
+        let row = sqlx::query!(
+            "SELECT MAX(version) as latest FROM file_versions WHERE file_id = ?1",
+            file_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.latest.unwrap_or(0) as u32)
+    }
+
+    /// Delete a version row. Returns true when no other version still refers to
+    /// the same content, meaning the stored object can be removed.
+    pub async fn delete_file_version(&self, version_id: Uuid, checksum: &str) -> Result<bool> {
+        sqlx::query!("DELETE FROM file_versions WHERE id = ?1", version_id)
+            .execute(&self.pool)
+            .await?;
+
+        let row = sqlx::query!(
+            "SELECT COUNT(*) as count FROM file_versions WHERE checksum = ?1",
+            checksum
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.count == 0)
+    }
+
+    /// Total bytes held by stored versions, counting shared content once
+    pub async fn get_total_version_size(&self) -> Result<u64> {
+        let row = sqlx::query!(
+            "SELECT COALESCE(SUM(size), 0) as total FROM (SELECT DISTINCT checksum, size FROM file_versions)"
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.total as u64)
+    }
+
+    pub async fn get_oldest_file_versions(&self, limit: u32) -> Result<Vec<FileVersion>> {
+        let rows = sqlx::query!(
+            "SELECT * FROM file_versions ORDER BY created_at LIMIT ?1",
+            limit as i64
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| FileVersion {
+                id: row.id,
+                file_id: row.file_id,
+                version: row.version as u32,
+                size: row.size as u64,
+                checksum: row.checksum,
+                modified_at: row.modified_at,
+                author_id: row.author_id,
+                created_at: row.created_at,
+            })
+            .collect())
+    }
+}
+
+/// `SELECT columns` of the files `search_files` finds, with `words` the
+/// full-text query made of its `query`
+fn push_file_search(
+    query: &mut QueryBuilder<'_, Sqlite>,
+    columns: &str,
+    owner_id: Uuid,
+    search: &FileSearch,
+    words: Option<&str>,
+) {
+    query.push("SELECT ");
+    query.push(columns);
+    match words {
+        Some(words) => {
+            query.push(
+                " FROM file_search JOIN file_metadata ON file_metadata.rowid = file_search.rowid \
+                WHERE file_search MATCH ",
+            );
+            query.push_bind(words.to_string());
+            query.push(" AND ");
+        }
+        None => {
+            query.push(" FROM file_metadata WHERE ");
+        }
+    }
+    query.push("file_metadata.owner_id = ");
+    query.push_bind(owner_id);
+    query.push(" AND file_metadata.deleted_at IS NULL");
+
+    if let Some(mime_type) = &search.mime_type {
+        match mime_type.strip_suffix('*') {
+            Some(family) => {
+                query.push(" AND substr(file_metadata.mime_type, 1, ");
+                query.push_bind(family.chars().count() as i64);
+                query.push(") = ");
+                query.push_bind(family.to_string());
+            }
+            None => {
+                query.push(" AND file_metadata.mime_type = ");
+                query.push_bind(mime_type.clone());
+            }
+        }
+    }
+    if let Some(min_size) = search.min_size {
+        query.push(" AND file_metadata.size >= ");
+        query.push_bind(min_size as i64);
+    }
+    if let Some(max_size) = search.max_size {
+        query.push(" AND file_metadata.size <= ");
+        query.push_bind(max_size as i64);
+    }
+    if let Some(modified_after) = search.modified_after {
+        query.push(" AND file_metadata.modified_at > ");
+        query.push_bind(modified_after);
+    }
+    if let Some(folder) = &search.path_prefix {
+        let (lower, upper) = descendant_range(folder);
+        query.push(" AND file_metadata.path >= ");
+        query.push_bind(lower);
+        query.push(" AND file_metadata.path < ");
+        query.push_bind(upper);
+    }
+    if let Some(tag) = &search.tag {
+        query.push(
+            " AND file_metadata.id IN (SELECT file_tags.file_id FROM file_tags \
+            JOIN tags ON tags.id = file_tags.tag_id WHERE tags.user_id = ",
+        );
+        query.push_bind(owner_id);
+        query.push(" AND tags.name = ");
+        query.push_bind(tag.clone());
+        query.push(")");
+    }
+}
+
+/// An FTS5 query matching names with words that begin with each of the
+/// words in `text`, or none when it has none. Each is quoted, so nothing in
+/// it is taken for query syntax.
+fn fts_query(text: &str) -> Option<String> {
+    let words: Vec<String> = text.split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect();
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+/// Bounds on the paths strictly below `path`: every one of them is at least
+/// `"{path}/"` and sorts before `"{path}0"`, '0' coming right after '/'.
+/// Comparing against these rather than with substr() or LIKE lets SQLite
+/// search the (owner_id, path) index, and '%' and '_' in names match
+/// literally.
+fn descendant_range(path: &str) -> (String, String) {
+    let path = path.trim_end_matches('/');
+    (format!("{}/", path), format!("{}0", path))
+}
+
+/// `(id, ...)` for an `IN` test
+fn push_ids(query: &mut QueryBuilder<'_, Sqlite>, ids: &[Uuid]) {
+    query.push("(");
+    let mut separated = query.separated(", ");
+    for id in ids {
+        separated.push_bind(*id);
+    }
+    separated.push_unseparated(")");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// How SQLite would run `sql` for `owner_id` and `paths`
+    async fn query_plan(database: &Database, sql: &str, owner_id: Uuid, paths: &[&str]) -> String {
+        let explain = format!("EXPLAIN QUERY PLAN {}", sql);
+        let mut query = sqlx::query(&explain).bind(owner_id);
+        for path in paths {
+            query = query.bind(*path);
+        }
+        let rows = query
+            .fetch_all(&database.pool)
+            .await
+            .unwrap();
+        rows.iter().map(|row| row.get::<String, _>("detail")).collect::<Vec<_>>().join("\n")
+    }
+
+    async fn test_database(dir: &std::path::Path) -> (Database, Uuid) {
+        let url = format!("sqlite://{}?mode=rwc", dir.join("test.db").display());
+        let database = Database::new(&url).await.unwrap();
+        let user = User {
+            id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string()],
+        };
+        database.create_user(&user).await.unwrap();
+        (database, user.id)
+    }
+
+    fn photo(owner_id: Uuid, path: String) -> FileMetadata {
+        FileMetadata {
+            id: Uuid::new_v4(),
+            name: path.rsplit('/').next().unwrap_or_default().to_string(),
+            path,
+            size: 4,
+            mime_type: "image/jpeg".to_string(),
+            checksum: String::new(),
+            created_at: Utc::now(),
+            modified_at: Utc::now(),
+            owner_id,
+            is_directory: false,
+            is_symlink: false,
+            parent_id: None,
+            permissions: FilePermissions { read: true, write: true, delete: true, share: true },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_inserts_match_one_at_a_time() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let one_by_one: Vec<FileMetadata> = (0..100).map(|i| photo(user_id, format!("/one/{}.jpg", i))).collect();
+        let batched: Vec<FileMetadata> = (0..10_000).map(|i| photo(user_id, format!("/batch/{}.jpg", i))).collect();
+
+        let cursor = database.get_latest_change_seq().await.unwrap();
+        for metadata in &one_by_one {
+            database.create_file_metadata(metadata).await.unwrap();
+        }
+        assert_eq!(database.get_latest_change_seq().await.unwrap() - cursor, 100);
+
+        let cursor = database.get_latest_change_seq().await.unwrap();
+        database.create_file_metadata_batch(&batched).await.unwrap();
+
+        // Rows and their sync feed entries come out the same either way
+        let single = database.get_file_metadata_by_path(user_id, "/one/99.jpg").await.unwrap().unwrap();
+        let stored = database.get_file_metadata_by_path(user_id, "/batch/9999.jpg").await.unwrap().unwrap();
+        assert_eq!(single.id, one_by_one[99].id);
+        assert_eq!(stored.id, batched[9999].id);
+        assert_eq!((stored.size, stored.mime_type.as_str()), (single.size, single.mime_type.as_str()));
+        assert!(stored.permissions.share);
+        assert_eq!(database.get_descendants(user_id, "/batch").await.unwrap().len(), 10_000);
+        assert_eq!(database.get_latest_change_seq().await.unwrap() - cursor, 10_000);
+
+        // A row already on record stops the whole batch
+        let clash = [photo(user_id, "/late/a.jpg".to_string()), photo(user_id, "/batch/0.jpg".to_string())];
+        assert!(database.create_file_metadata_batch(&clash).await.is_err());
+        assert!(database.get_file_metadata_by_path(user_id, "/late/a.jpg").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_share_visits_outlive_their_link() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let file = photo(user_id, "/shared.jpg".to_string());
+        database.create_file_metadata(&file).await.unwrap();
+
+        let now = Utc::now();
+        let link = ShareLink {
+            id: Uuid::new_v4(),
+            file_id: file.id,
+            created_by: user_id,
+            share_token: "token-of-the-link".to_string(),
+            expires_at: Some(now - chrono::Duration::minutes(1)),
+            password_protected: false,
+            download_count: 0,
+            max_downloads: None,
+            created_at: now,
+            revoked_at: None,
+            password_hash: None,
+            short_code: None,
+            alias: None,
+        };
+        database.create_share_link(&link).await.unwrap();
+        database.record_share_access(&ShareAccess {
+            id: Uuid::new_v4(),
+            share_id: link.id,
+            accessed_at: now,
+            ip_address: None,
+            user_agent: None,
+            bytes_served: 4,
+            outcome: ShareAccessOutcome::Success,
+            detail: None,
+        }).await.unwrap();
+
+        assert_eq!(database.purge_expired_share_links(now).await.unwrap(), 1);
+        let kept = sqlx::query("SELECT share_id, share_token FROM share_access_log")
+            .fetch_all(&database.pool)
+            .await
+            .unwrap();
+        assert_eq!(kept.len(), 1);
+        assert!(kept[0].get::<Option<Uuid>, _>("share_id").is_none());
+        assert_eq!(kept[0].get::<Option<String>, _>("share_token").as_deref(), Some("token-of-the-link"));
+    }
+
+    #[tokio::test]
+    async fn test_path_lookups_search_the_owner_path_index() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+
+        // 100k files spread over 100 folders
+        sqlx::query(
+            r#"
+            WITH RECURSIVE seq (n) AS (SELECT 0 UNION ALL SELECT n + 1 FROM seq WHERE n < 99999)
+            INSERT INTO file_metadata
+                (id, name, path, size, mime_type, checksum, created_at, modified_at, owner_id, is_directory, permissions)
+            SELECT randomblob(16), 'file-' || n, '/folder-' || (n % 100) || '/file-' || n, n,
+                'text/plain', '', ?2, ?2, ?1, 0, '{"read":true,"write":true,"delete":true,"share":true}'
+            FROM seq
+            "#,
+        )
+        .bind(user_id)
+        .bind(Utc::now())
+        .execute(&database.pool)
+        .await
+        .unwrap();
+
+        let by_path = query_plan(
+            &database,
+            "SELECT * FROM file_metadata WHERE owner_id = ?1 AND path = ?2 AND deleted_at IS NULL",
+            user_id,
+            &["/folder-7/file-507"],
+        ).await;
+        let (lower, upper) = descendant_range("/folder-7");
+        let descendants = query_plan(
+            &database,
+            "SELECT * FROM file_metadata WHERE owner_id = ?1 AND path >= ?2 AND path < ?3 AND deleted_at IS NULL ORDER BY path",
+            user_id,
+            &[&lower, &upper],
+        ).await;
+        for plan in [&by_path, &descendants] {
+            assert!(plan.contains("SEARCH file_metadata USING INDEX idx_file_metadata_owner_path"), "{}", plan);
+            assert!(!plan.contains("SCAN file_metadata"), "{}", plan);
+        }
+
+        let found = database.get_file_metadata_by_path(user_id, "/folder-7/file-507").await.unwrap().unwrap();
+        assert_eq!(found.size, 507);
+        let below = database.get_descendants(user_id, "/folder-7").await.unwrap();
+        assert_eq!(below.len(), 1000);
+        assert!(below.iter().all(|file| file.path.starts_with("/folder-7/")));
+
+        // Pages pick up after the last path of the one before
+        let page = database.get_descendants_page(user_id, "/folder-7", Some(&below[9].path), 5, 0).await.unwrap();
+        let paths: Vec<&str> = page.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, below[10..15].iter().map(|file| file.path.as_str()).collect::<Vec<_>>());
+        assert_eq!(database.count_descendants(user_id, "/folder-7").await.unwrap(), 1000);
+
+        // Siblings sharing the name's start aren't below it
+        assert!(database.get_descendants(user_id, "/folder-1").await.unwrap()
+            .iter()
+            .all(|file| file.path.starts_with("/folder-1/")));
     }
 }
@@ -0,0 +1,218 @@
+use fast_rsync::{Signature, SignatureOptions};
+use sha2::{Digest, Sha256};
+
+// librsync's delta format: a magic number, then commands until END. Numbers
+// are big-endian; the opcode says how wide they are.
+const DELTA_MAGIC: u32 = 0x7273_0236;
+const OP_END: u8 = 0x00;
+const OP_LITERAL_N1: u8 = 0x41;
+const OP_LITERAL_N8: u8 = 0x44;
+const OP_COPY_N1_N1: u8 = 0x45;
+const OP_COPY_N8_N8: u8 = 0x54;
+const WIDTHS: [usize; 4] = [1, 2, 4, 8];
+
+// Bytes of the strong hash kept per block
+const STRONG_HASH_SIZE: u32 = 8;
+// Bytes before the first block in a signature: magic, block size, hash size
+const SIGNATURE_HEADER: usize = 12;
+// Blocks hashed at a time while a file streams past
+const BLOCKS_PER_BATCH: usize = 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeltaError {
+    #[error("Not a delta")]
+    BadMagic,
+    #[error("Delta ends in the middle of a command")]
+    Truncated,
+    #[error("Unknown delta command 0x{0:02x}")]
+    UnknownCommand(u8),
+    #[error("Delta copies bytes {offset}..{end} of a {size} byte file")]
+    CopyOutOfRange { offset: u64, end: u64, size: u64 },
+}
+
+/// Builds the block signature of a file as it is read, along with its
+/// SHA-256, without holding more than a batch of blocks
+pub struct SignatureBuilder {
+    block_size: usize,
+    pending: Vec<u8>,
+    signature: Vec<u8>,
+    hasher: Sha256,
+}
+
+impl SignatureBuilder {
+    pub fn new(block_size: u32) -> Self {
+        Self {
+            block_size: block_size as usize,
+            pending: Vec::new(),
+            signature: Vec::new(),
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.pending.extend_from_slice(data);
+
+        let batch = self.block_size * BLOCKS_PER_BATCH;
+        if self.pending.len() >= batch {
+            let whole = self.pending.len() - self.pending.len() % batch;
+            let blocks: Vec<u8> = self.pending.drain(..whole).collect();
+            self.sign(&blocks);
+        }
+    }
+
+    /// The signature in librsync's format and the file's SHA-256
+    pub fn finish(mut self) -> (Vec<u8>, String) {
+        if !self.pending.is_empty() || self.signature.is_empty() {
+            let rest = std::mem::take(&mut self.pending);
+            self.sign(&rest);
+        }
+        (self.signature, format!("{:x}", self.hasher.finalize()))
+    }
+
+    // Batches are whole blocks, so their signatures line up end to end
+    fn sign(&mut self, data: &[u8]) {
+        let options = SignatureOptions {
+            block_size: self.block_size as u32,
+            crypto_hash_size: STRONG_HASH_SIZE,
+        };
+        let signature = Signature::calculate(data, options);
+        let serialized = signature.serialized();
+        if self.signature.is_empty() {
+            self.signature.extend_from_slice(serialized);
+        } else {
+            self.signature.extend_from_slice(&serialized[SIGNATURE_HEADER..]);
+        }
+    }
+}
+
+/// One step of rebuilding a file from its old version and a delta
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaCommand {
+    /// Bytes carried in the delta itself, as a range of it
+    Literal(std::ops::Range<usize>),
+    /// Bytes taken from the old version
+    Copy { offset: u64, length: u64 },
+}
+
+/// The commands of a delta against a base of `base_size` bytes, checked so
+/// that every copy stays inside the base
+pub fn parse_delta(delta: &[u8], base_size: u64) -> Result<Vec<DeltaCommand>, DeltaError> {
+    let mut reader = Reader { data: delta, position: 0 };
+    if reader.number(4)? != DELTA_MAGIC as u64 {
+        return Err(DeltaError::BadMagic);
+    }
+
+    let mut commands = Vec::new();
+    loop {
+        let op = reader.number(1)? as u8;
+        match op {
+            OP_END => return Ok(commands),
+            // Short literals carry their length in the opcode
+            length @ 0x01..=0x40 => commands.push(DeltaCommand::Literal(reader.skip(length as u64)?)),
+            OP_LITERAL_N1..=OP_LITERAL_N8 => {
+                let length = reader.number(WIDTHS[(op - OP_LITERAL_N1) as usize])?;
+                commands.push(DeltaCommand::Literal(reader.skip(length)?));
+            }
+            OP_COPY_N1_N1..=OP_COPY_N8_N8 => {
+                let widths = op - OP_COPY_N1_N1;
+                let offset = reader.number(WIDTHS[(widths / 4) as usize])?;
+                let length = reader.number(WIDTHS[(widths % 4) as usize])?;
+                let end = offset.checked_add(length).filter(|end| *end <= base_size)
+                    .ok_or(DeltaError::CopyOutOfRange { offset, end: offset.saturating_add(length), size: base_size })?;
+                // Neighbouring copies are read in one go
+                match commands.last_mut() {
+                    Some(DeltaCommand::Copy { offset: previous, length: previous_length })
+                        if *previous + *previous_length == offset => *previous_length += length,
+                    _ => commands.push(DeltaCommand::Copy { offset, length: end - offset }),
+                }
+            }
+            op => return Err(DeltaError::UnknownCommand(op)),
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn number(&mut self, width: usize) -> Result<u64, DeltaError> {
+        let bytes = self.take(width)?;
+        Ok(bytes.iter().fold(0, |number, byte| number << 8 | *byte as u64))
+    }
+
+    fn skip(&mut self, length: u64) -> Result<std::ops::Range<usize>, DeltaError> {
+        let start = self.position;
+        self.take(usize::try_from(length).map_err(|_| DeltaError::Truncated)?)?;
+        Ok(start..self.position)
+    }
+
+    fn take(&mut self, length: usize) -> Result<&[u8], DeltaError> {
+        let end = self.position.checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(DeltaError::Truncated)?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rebuild(base: &[u8], delta: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for command in parse_delta(delta, base.len() as u64).unwrap() {
+            match command {
+                DeltaCommand::Literal(range) => out.extend_from_slice(&delta[range]),
+                DeltaCommand::Copy { offset, length } => {
+                    out.extend_from_slice(&base[offset as usize..(offset + length) as usize])
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_streamed_signatures_rebuild_the_new_version() {
+        let base: Vec<u8> = (0..200_000u32).flat_map(|i| (i % 251).to_le_bytes()).collect();
+        let mut new = base.clone();
+        new[5000..5100].fill(7);
+        new.splice(300_000..300_000, b"inserted".iter().copied());
+        new.truncate(700_000);
+
+        // Fed in odd-sized pieces across several batches
+        let mut builder = SignatureBuilder::new(64);
+        for piece in base.chunks(10_007) {
+            builder.update(piece);
+        }
+        let (signature, checksum) = builder.finish();
+        assert_eq!(signature, Signature::calculate(&base, SignatureOptions {
+            block_size: 64,
+            crypto_hash_size: STRONG_HASH_SIZE,
+        }).serialized());
+        assert_eq!(checksum, format!("{:x}", Sha256::digest(&base)));
+
+        let signature = Signature::deserialize(signature).unwrap();
+        let mut delta = Vec::new();
+        fast_rsync::diff(&signature.index(), &new, &mut delta).unwrap();
+        assert!(delta.len() < new.len() / 10);
+        assert_eq!(rebuild(&base, &delta), new);
+    }
+
+    #[test]
+    fn test_deltas_stay_inside_the_base() {
+        // COPY_N1_N1 of 16 bytes at 8 from an 8 byte file
+        let delta = [0x72, 0x73, 0x02, 0x36, OP_COPY_N1_N1, 8, 16, OP_END];
+        assert!(matches!(parse_delta(&delta, 8), Err(DeltaError::CopyOutOfRange { .. })));
+        assert!(parse_delta(&delta, 24).is_ok());
+
+        assert!(matches!(parse_delta(&[0, 0, 0, 0], 8), Err(DeltaError::BadMagic)));
+        // A literal promising more than is there
+        let truncated = [0x72, 0x73, 0x02, 0x36, 0x05, b'a', b'b'];
+        assert!(matches!(parse_delta(&truncated, 8), Err(DeltaError::Truncated)));
+    }
+}
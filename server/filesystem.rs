@@ -1,22 +1,313 @@
-use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
 use std::fs::{self, Metadata};
 use std::io::{self, Read, Write};
+use std::ops::Range;
 use tokio::fs as async_fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom, Take};
+use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use sha2::{Sha256, Digest};
 use anyhow::{Result, anyhow};
 use walkdir::WalkDir;
 use mime_guess::from_path;
-use notify::{Watcher, RecursiveMode, watcher, DebouncedEvent};
+use notify::{Watcher, RecommendedWatcher, RecursiveMode, watcher, DebouncedEvent};
 use std::sync::mpsc;
-use std::time::Duration;
-use crate::types::{FileMetadata, FilePermissions, FileChange, ChangeType};
+use std::time::{Duration, SystemTime};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use lru::LruCache;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use unicode_normalization::UnicodeNormalization;
+use crate::hashing::ResumableSha256;
+use crate::storage::StagedUpload;
+use crate::types::{FileMetadata, FilePermissions, FileChange, ChangeType, DiskSpace, MountDiskSpace, StorageUsage, SymlinkPolicy};
 
+#[derive(Debug, thiserror::Error)]
+pub enum FileSystemError {
+    #[error("File size exceeds maximum allowed size")]
+    FileTooLarge,
+    #[error("Checksum mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("Files with extension '.{0}' are not allowed")]
+    ExtensionNotAllowed(String),
+    #[error("Files without an extension are not allowed")]
+    MissingExtension,
+    #[error("Copy exceeds the limit of {limit} bytes per request")]
+    CopyTooLarge { limit: u64 },
+    #[error("Symbolic links are not allowed: {0}")]
+    SymlinkDenied(String),
+    #[error("Symbolic link points outside the storage directory: {0}")]
+    SymlinkOutsideBase(String),
+    #[error("Invalid file name '{0}': control characters and trailing dots or spaces are not allowed")]
+    InvalidFileName(String),
+    #[error("'{0}' matches an ignore pattern and can't be stored")]
+    Ignored(String),
+}
+
+/// Paths are stored in Unicode NFC, so "café" typed on macOS (NFD) and on
+/// Linux (NFC) name the same file
+pub fn normalize_path(path: &str) -> String {
+    path.nfc().collect()
+}
+
+/// The normalized components of a path, or `None` when it climbs with `..`.
+/// Lets paths be compared by folder, so `/public` never matches `/public-old`.
+pub fn path_components(path: &str) -> Option<Vec<String>> {
+    let mut components = Vec::new();
+    for part in normalize_path(path).split('/') {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            part => components.push(part.to_string()),
+        }
+    }
+    Some(components)
+}
+
+/// Whether `path` is `folder` or lies beneath it, by component
+pub fn is_within(path: &str, folder: &str) -> bool {
+    match (path_components(path), path_components(folder)) {
+        (Some(path), Some(folder)) => path.starts_with(&folder),
+        _ => false,
+    }
+}
+
+/// Parent directory of a stored path, or None for entries at the root
+pub fn parent_path(path: &str) -> Option<&str> {
+    let trimmed = path.trim_end_matches('/');
+    match trimmed.rfind('/') {
+        Some(0) | None => None,
+        Some(index) => Some(&trimmed[..index]),
+    }
+}
+
+// Read buffer size used when streaming file contents to clients
+pub(crate) const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// Per-user trash area under base_path, hidden from listings
+const TRASH_DIR: &str = ".trash";
+
+// Content-addressed store of previous file versions, hidden from listings
+const VERSIONS_DIR: &str = ".versions";
+
+// Number of file checksums remembered between listings
+const CHECKSUM_CACHE_SIZE: usize = 50_000;
+
+// Content-addressed store of deduplicated file contents, hidden from listings
+const OBJECTS_DIR: &str = ".objects";
+
+// Internal directories under base_path that users never see
+const RESERVED_DIRS: &[&str] = &[TRASH_DIR, VERSIONS_DIR, OBJECTS_DIR];
+
+// Per-directory file of gitignore-style patterns for entries to hide
+const IGNORE_FILE: &str = ".synkerignore";
+
+// Parent of the per-user home directories when user homes are on
+const USERS_DIR: &str = "users";
+
+/// A directory served as the top-level folder `/name`, typically on another drive
+#[derive(Clone)]
+struct Mount {
+    name: String,
+    path: PathBuf,
+    canonical_path: PathBuf,
+}
+
+#[derive(Clone)]
 pub struct FileSystemService {
     base_path: PathBuf,
+    canonical_base: PathBuf,
+    /// The configured `base_path`; differs from `base_path` once scoped to a home
+    root_path: PathBuf,
+    user_homes: bool,
+    /// User whose home this service is rooted at
+    home: Option<Uuid>,
+    mounts: Vec<Mount>,
+    temp_directory: PathBuf,
     max_file_size: u64,
+    stream_threshold: u64,
+    max_versions_per_file: u32,
+    max_version_storage: u64,
+    allowed_extensions: Vec<String>,
+    allow_no_extension: bool,
+    max_copy_size: u64,
+    deduplicate: bool,
+    symlink_policy: SymlinkPolicy,
+    default_quota: Option<u64>,
+    ignore_patterns: Arc<Gitignore>,
+    apply_ignore_rules: bool,
+    checksum_cache: Arc<Mutex<LruCache<PathBuf, CachedChecksum>>>,
+    checksum_reads: Arc<AtomicU64>,
+    /// Test hook: when set, renames fail as if crossing filesystems and the
+    /// copy fallback fails after copying this many files
+    #[cfg(test)]
+    simulated_cross_device: Option<usize>,
+}
+
+/// Matches paths against the configured patterns and `.synkerignore` files,
+/// reading each directory's file at most once
+struct IgnoreMatcher<'a> {
+    filesystem: &'a FileSystemService,
+    directories: HashMap<String, Option<Gitignore>>,
+}
+
+impl IgnoreMatcher<'_> {
+    /// Whether `relative_path` or any directory above it is ignored. As with
+    /// git, nothing inside an ignored directory can be re-included.
+    fn is_ignored(&mut self, relative_path: &str, is_dir: bool) -> bool {
+        if !self.filesystem.apply_ignore_rules {
+            return false;
+        }
+
+        let components: Vec<&str> = relative_path.split('/').filter(|c| !c.is_empty()).collect();
+        let mut directory = "/".to_string();
+
+        for (i, component) in components.iter().enumerate() {
+            let path = if directory == "/" {
+                format!("/{}", component)
+            } else {
+                format!("{}/{}", directory, component)
+            };
+            let entry_is_dir = is_dir || i + 1 < components.len();
+
+            if self.matches(&directory, &path, entry_is_dir) {
+                return true;
+            }
+            directory = path;
+        }
+
+        false
+    }
+
+    /// The nearest `.synkerignore` with a rule for `path` decides, and the
+    /// configured patterns apply when none has one
+    fn matches(&mut self, directory: &str, path: &str, is_dir: bool) -> bool {
+        let mut current = Some(directory.to_string());
+
+        while let Some(directory) = current {
+            let filesystem = self.filesystem;
+            let rules = self.directories
+                .entry(directory.clone())
+                .or_insert_with(|| filesystem.load_ignore_file(&directory));
+
+            if let Some(rules) = rules {
+                match rules.matched(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+
+            current = match directory.rfind('/') {
+                _ if directory == "/" => None,
+                Some(0) => Some("/".to_string()),
+                Some(i) => Some(directory[..i].to_string()),
+                None => None,
+            };
+        }
+
+        self.filesystem.ignore_patterns.matched(path, is_dir).is_ignore()
+    }
+}
+
+/// A checksum is reused as long as the file's size and mtime are unchanged
+#[derive(Clone)]
+struct CachedChecksum {
+    size: u64,
+    modified: Option<SystemTime>,
+    checksum: String,
+}
+
+/// An upload being written to a temp file chunk by chunk. The checksum and
+/// size are tracked as data arrives so the file never has to be re-read.
+/// Dropping it unfinished, as when a client goes away mid-upload, removes
+/// the temp file.
+pub struct UploadWriter {
+    temp_path: PathBuf,
+    file: async_fs::File,
+    hasher: Sha256,
+    size: u64,
+    max_size: u64,
+}
+
+impl UploadWriter {
+    pub async fn write_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        if self.size + chunk.len() as u64 > self.max_size {
+            return Err(FileSystemError::FileTooLarge.into());
+        }
+
+        self.file.write_all(chunk).await?;
+        self.hasher.update(chunk);
+        self.size += chunk.len() as u64;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// SHA-256 of the data written so far
+    pub fn checksum(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+
+    /// Flush the temp file to disk and hand it over for storing
+    pub async fn finish(mut self) -> Result<StagedUpload> {
+        let result = async {
+            self.file.flush().await?;
+            self.file.sync_all().await
+        }
+        .await;
+
+        if let Err(e) = result {
+            self.abort().await;
+            return Err(e.into());
+        }
+
+        Ok(StagedUpload {
+            temp_path: std::mem::take(&mut self.temp_path),
+            size: self.size,
+            checksum: self.checksum(),
+        })
+    }
+
+    /// Discard the upload and remove its temp file
+    pub async fn abort(mut self) {
+        let temp_path = std::mem::take(&mut self.temp_path);
+        // Closes the file first, which Windows needs to remove it
+        drop(self);
+        if let Err(e) = async_fs::remove_file(&temp_path).await {
+            tracing::warn!("Failed to remove temp file {:?}: {}", temp_path, e);
+        }
+    }
+}
+
+impl Drop for UploadWriter {
+    fn drop(&mut self) {
+        // Empty once finished or aborted
+        if !self.temp_path.as_os_str().is_empty() {
+            if let Err(e) = std::fs::remove_file(&self.temp_path) {
+                tracing::warn!("Failed to remove temp file {:?}: {}", self.temp_path, e);
+            }
+        }
+    }
+}
+
+/// A live recursive watch; dropping it stops the watcher
+pub struct DirectoryWatch {
+    _watcher: RecommendedWatcher,
+    events: mpsc::Receiver<DebouncedEvent>,
+}
+
+impl DirectoryWatch {
+    /// Wait up to `timeout` for the next debounced event
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<DebouncedEvent, mpsc::RecvTimeoutError> {
+        self.events.recv_timeout(timeout)
+    }
 }
 
 impl FileSystemService {
@@ -28,44 +319,667 @@ impl FileSystemService {
             fs::create_dir_all(&base_path)?;
         }
 
+        // Symlink targets are compared against the fully resolved base
+        let canonical_base = fs::canonicalize(&base_path)?;
+
         Ok(Self {
+            root_path: base_path.clone(),
             base_path,
+            canonical_base,
+            user_homes: false,
+            home: None,
+            mounts: Vec::new(),
+            temp_directory: std::env::temp_dir(),
             max_file_size,
+            stream_threshold: u64::MAX,
+            max_versions_per_file: 0,
+            max_version_storage: 0,
+            allowed_extensions: Vec::new(),
+            allow_no_extension: true,
+            max_copy_size: u64::MAX,
+            deduplicate: false,
+            symlink_policy: SymlinkPolicy::Skip,
+            default_quota: None,
+            ignore_patterns: Arc::new(Gitignore::empty()),
+            apply_ignore_rules: true,
+            checksum_cache: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(CHECKSUM_CACHE_SIZE).unwrap(),
+            ))),
+            checksum_reads: Arc::new(AtomicU64::new(0)),
+            #[cfg(test)]
+            simulated_cross_device: None,
         })
     }
 
+    /// Directory where in-flight uploads are staged before being moved into place
+    pub fn with_temp_directory(mut self, temp_directory: impl AsRef<Path>) -> Self {
+        self.temp_directory = temp_directory.as_ref().to_path_buf();
+        self
+    }
+
+    /// Files larger than `threshold` bytes are streamed in chunks instead of
+    /// being read into memory in one go.
+    pub fn with_stream_threshold(mut self, threshold: u64) -> Self {
+        self.stream_threshold = threshold;
+        self
+    }
+
+    /// Limits for version history; a zero storage limit means unlimited
+    pub fn with_version_retention(mut self, max_versions_per_file: u32, max_version_storage: u64) -> Self {
+        self.max_versions_per_file = max_versions_per_file;
+        self.max_version_storage = max_version_storage;
+        self
+    }
+
+    /// Restrict uploads to these extensions; an empty list allows everything
+    pub fn with_allowed_extensions(mut self, allowed_extensions: Vec<String>, allow_no_extension: bool) -> Self {
+        self.allowed_extensions = allowed_extensions
+            .into_iter()
+            .map(|ext| ext.trim_start_matches('.').to_lowercase())
+            .collect();
+        self.allow_no_extension = allow_no_extension;
+        self
+    }
+
+    /// Cap on the total bytes a single `copy_tree` call may copy
+    pub fn with_max_copy_size(mut self, max_copy_size: u64) -> Self {
+        self.max_copy_size = max_copy_size;
+        self
+    }
+
+    /// Store identical file contents once under `.objects` and hard link every
+    /// path to it. Only honoured on unix, where link counts tell us when the
+    /// last reference to an object is gone.
+    pub fn with_deduplication(mut self, enabled: bool) -> Self {
+        self.deduplicate = enabled && cfg!(unix);
+        self
+    }
+
+    pub fn with_symlink_policy(mut self, symlink_policy: SymlinkPolicy) -> Self {
+        self.symlink_policy = symlink_policy;
+        self
+    }
+
+    /// Quota applied to users without one of their own; `None` is unlimited
+    pub fn with_default_quota(mut self, default_quota: Option<u64>) -> Self {
+        self.default_quota = default_quota;
+        self
+    }
+
+    /// Serve each `(name, path)` as the top-level folder `/name`. Trash,
+    /// versions and deduplicated objects stay under `base_path`.
+    pub fn with_mounts(mut self, mounts: Vec<(String, PathBuf)>) -> Result<Self> {
+        for (name, path) in mounts {
+            let name = normalize_path(&name);
+            if name.is_empty() || name.contains('/') || RESERVED_DIRS.contains(&name.as_str()) {
+                return Err(anyhow!("Invalid mount name '{}'", name));
+            }
+            if self.mounts.iter().any(|mount| mount.name == name) {
+                return Err(anyhow!("Mount '{}' is configured twice", name));
+            }
+
+            if !path.exists() {
+                fs::create_dir_all(&path)?;
+            }
+            let canonical_path = fs::canonicalize(&path)?;
+
+            self.mounts.push(Mount { name, path, canonical_path });
+        }
+
+        Ok(self)
+    }
+
+    /// Hide entries matching any of `patterns` (gitignore syntax) everywhere,
+    /// on top of the rules in `.synkerignore` files
+    pub fn with_ignore_patterns(mut self, patterns: Vec<String>) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new("/");
+        for pattern in &patterns {
+            builder.add_line(None, pattern)?;
+        }
+
+        self.ignore_patterns = Arc::new(builder.build()?);
+        Ok(self)
+    }
+
+    /// Give every user their own tree under `base_path/users/<user_id>`, see `for_user`
+    pub fn with_user_homes(mut self, enabled: bool) -> Self {
+        self.user_homes = enabled;
+        self
+    }
+
+    pub fn user_homes(&self) -> bool {
+        self.user_homes
+    }
+
+    /// The storage as `user_id` sees it: rooted at their home, which is created
+    /// on first use. Without user homes every user shares `base_path`. Mounts
+    /// are shared folders and appear in every home; versions and deduplicated
+    /// objects stay in one store under `base_path`.
+    pub fn for_user(&self, user_id: Uuid) -> Result<Self> {
+        let mut filesystem = self.clone();
+        if !self.user_homes {
+            return Ok(filesystem);
+        }
+
+        let home = self.root_path.join(USERS_DIR).join(user_id.to_string());
+        if !home.exists() {
+            fs::create_dir_all(&home)?;
+        }
+
+        filesystem.canonical_base = fs::canonicalize(&home)?;
+        filesystem.base_path = home;
+        filesystem.home = Some(user_id);
+        Ok(filesystem)
+    }
+
+    /// User whose home this service is rooted at, if it is scoped to one
+    pub fn home(&self) -> Option<Uuid> {
+        self.home
+    }
+
+    /// Top-level entries of a flat deployment that belong in a home: everything
+    /// under `base_path` except the homes and the shared stores
+    fn flat_entries(&self) -> Result<Vec<PathBuf>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.root_path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name == USERS_DIR || name == VERSIONS_DIR || name == OBJECTS_DIR {
+                continue;
+            }
+            entries.push(entry.path());
+        }
+
+        Ok(entries)
+    }
+
+    /// Whether files from before user homes were turned on still sit directly
+    /// under `base_path`, out of every user's reach
+    pub fn has_flat_content(&self) -> Result<bool> {
+        Ok(!self.flat_entries()?.is_empty())
+    }
+
+    /// Move the content of a flat deployment into `owner_id`'s home. Each
+    /// user's trash goes to their own home so their trash entries still
+    /// restore. Returns the number of top-level entries moved.
+    pub async fn migrate_to_user_homes(&self, owner_id: Uuid) -> Result<usize> {
+        if !self.user_homes {
+            return Err(anyhow!("User homes are not enabled"));
+        }
+
+        let home = self.for_user(owner_id)?;
+        let mut moved = 0;
+
+        for entry in self.flat_entries()? {
+            if entry.file_name().map_or(false, |name| name == TRASH_DIR) {
+                for user_trash in fs::read_dir(&entry)? {
+                    let user_trash = user_trash?;
+                    let user_id = match Uuid::parse_str(&user_trash.file_name().to_string_lossy()) {
+                        Ok(user_id) => user_id,
+                        Err(_) => continue,
+                    };
+                    let user_home = self.for_user(user_id)?;
+                    let target = user_home.base_path.join(TRASH_DIR).join(user_id.to_string());
+                    self.move_into(&user_trash.path(), &target).await?;
+                }
+                // Left alone if anything unexpected was in it
+                let _ = async_fs::remove_dir(&entry).await;
+                moved += 1;
+                continue;
+            }
+
+            let target = home.base_path.join(entry.file_name().unwrap_or_default());
+            self.move_into(&entry, &target).await?;
+            moved += 1;
+        }
+
+        Ok(moved)
+    }
+
+    /// Rename `from` to `to`, refusing to replace anything already there
+    async fn move_into(&self, from: &Path, to: &Path) -> Result<()> {
+        if async_fs::symlink_metadata(to).await.is_ok() {
+            return Err(anyhow!("{:?} already exists", to));
+        }
+        if let Some(parent) = to.parent() {
+            async_fs::create_dir_all(parent).await?;
+        }
+
+        self.rename_or_copy(from, to).await
+    }
+
+    /// The same storage with ignore rules switched off, for admins
+    pub fn including_ignored(&self) -> Self {
+        let mut filesystem = self.clone();
+        filesystem.apply_ignore_rules = false;
+        filesystem
+    }
+
+    pub fn default_quota(&self) -> Option<u64> {
+        self.default_quota
+    }
+
+    pub fn deduplicates(&self) -> bool {
+        self.deduplicate
+    }
+
+    pub fn allowed_extensions(&self) -> &[String] {
+        &self.allowed_extensions
+    }
+
+    pub fn allows_no_extension(&self) -> bool {
+        self.allowed_extensions.is_empty() || self.allow_no_extension
+    }
+
+    pub fn check_extension(&self, relative_path: &str) -> Result<(), FileSystemError> {
+        if self.allowed_extensions.is_empty() {
+            return Ok(());
+        }
+
+        match Path::new(relative_path).extension().and_then(|e| e.to_str()) {
+            Some(ext) => {
+                let ext = ext.to_lowercase();
+                if self.allowed_extensions.contains(&ext) {
+                    Ok(())
+                } else {
+                    Err(FileSystemError::ExtensionNotAllowed(ext))
+                }
+            }
+            None if self.allow_no_extension => Ok(()),
+            None => Err(FileSystemError::MissingExtension),
+        }
+    }
+
+    pub fn max_versions_per_file(&self) -> u32 {
+        self.max_versions_per_file
+    }
+
+    pub fn max_version_storage(&self) -> u64 {
+        self.max_version_storage
+    }
+
+    /// Number of files whose contents have been read to compute a checksum
+    pub fn checksum_reads(&self) -> u64 {
+        self.checksum_reads.load(Ordering::Relaxed)
+    }
+
+    pub fn should_stream(&self, size: u64) -> bool {
+        size > self.stream_threshold
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+
+    pub fn max_copy_size(&self) -> u64 {
+        self.max_copy_size
+    }
+
     pub fn get_absolute_path(&self, relative_path: &str) -> PathBuf {
-        let cleaned_path = relative_path.trim_start_matches('/');
-        self.base_path.join(cleaned_path)
+        let cleaned_path = normalize_path(relative_path.trim_start_matches('/'));
+        let (first, rest) = cleaned_path.split_once('/').unwrap_or((&cleaned_path, ""));
+
+        match self.mounts.iter().find(|mount| mount.name == first) {
+            Some(mount) if rest.is_empty() => mount.path.clone(),
+            Some(mount) => mount.path.join(rest),
+            None => self.base_path.join(&cleaned_path),
+        }
+    }
+
+    /// The storage root `absolute_path` lies in, with its canonical form:
+    /// the mount containing it, or else `base_path`
+    fn root_of(&self, absolute_path: &Path) -> (&Path, &Path) {
+        self.mounts
+            .iter()
+            .find(|mount| absolute_path.starts_with(&mount.path))
+            .map_or((&self.base_path, &self.canonical_base), |mount| (&mount.path, &mount.canonical_path))
+    }
+
+    /// Whether the storage root holding `relative_path`, `base_path` or a
+    /// mount, is present, so an unplugged drive isn't mistaken for deleted files
+    pub fn storage_root_exists(&self, relative_path: &str) -> bool {
+        let (root, _) = self.root_of(&self.get_absolute_path(relative_path));
+        root.is_dir()
+    }
+
+    /// Absolute path for `relative_path` after applying the symlink policy to
+    /// every component that already exists. Use this rather than
+    /// `get_absolute_path` before touching the filesystem.
+    pub fn resolve_path(&self, relative_path: &str) -> Result<PathBuf> {
+        let absolute_path = self.get_absolute_path(relative_path);
+        let (root, _) = self.root_of(&absolute_path);
+        let mut current = root.to_path_buf();
+
+        for component in absolute_path.strip_prefix(root)?.components() {
+            // `..` could climb out of the storage root or mount
+            if component == Component::ParentDir {
+                return Err(anyhow!("Path escapes the storage directory: {}", relative_path));
+            }
+
+            current.push(component);
+            match fs::symlink_metadata(&current) {
+                Ok(metadata) if metadata.file_type().is_symlink() => self.check_symlink(&current)?,
+                Ok(_) => {}
+                // Nothing further down exists yet, e.g. the target of a write
+                Err(_) => break,
+            }
+        }
+
+        Ok(absolute_path)
+    }
+
+    fn check_symlink(&self, link: &Path) -> Result<()> {
+        let display = self.get_relative_path(link)?;
+
+        match self.symlink_policy {
+            SymlinkPolicy::Deny => Err(FileSystemError::SymlinkDenied(display).into()),
+            SymlinkPolicy::Skip => Err(anyhow!("File not found")),
+            SymlinkPolicy::FollowWithinBase => {
+                // Fails for dangling and cyclic links too
+                let target = fs::canonicalize(link)
+                    .map_err(|_| anyhow!("Broken symbolic link: {}", display))?;
+                let (_, canonical_root) = self.root_of(link);
+                if target.starts_with(canonical_root) {
+                    Ok(())
+                } else {
+                    Err(FileSystemError::SymlinkOutsideBase(display).into())
+                }
+            }
+        }
+    }
+
+    /// Reject names that Windows clients can't represent: control characters
+    /// anywhere, or a trailing dot or space on any component
+    pub fn check_file_name(&self, relative_path: &str) -> Result<(), FileSystemError> {
+        for component in relative_path.split('/').filter(|c| !c.is_empty()) {
+            if component.chars().any(char::is_control)
+                || component.ends_with('.')
+                || component.ends_with(' ')
+            {
+                return Err(FileSystemError::InvalidFileName(component.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `relative_path`, or a directory above it, is hidden by the
+    /// configured patterns or a `.synkerignore` file
+    pub fn is_ignored(&self, relative_path: &str, is_dir: bool) -> bool {
+        self.ignore_matcher().is_ignored(relative_path, is_dir)
+    }
+
+    pub fn check_ignored(&self, relative_path: &str) -> Result<(), FileSystemError> {
+        if self.is_ignored(relative_path, false) {
+            return Err(FileSystemError::Ignored(relative_path.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Drop listed entries hidden by ignore rules
+    pub fn filter_ignored(&self, entries: &mut Vec<FileMetadata>) {
+        let mut ignore_matcher = self.ignore_matcher();
+        entries.retain(|entry| !ignore_matcher.is_ignored(&entry.path, entry.is_directory));
+    }
+
+    fn ignore_matcher(&self) -> IgnoreMatcher<'_> {
+        IgnoreMatcher {
+            filesystem: self,
+            directories: HashMap::new(),
+        }
+    }
+
+    /// Rules from the `.synkerignore` in `directory`, if there is one
+    fn load_ignore_file(&self, directory: &str) -> Option<Gitignore> {
+        let file = self.get_absolute_path(directory).join(IGNORE_FILE);
+        if !file.is_file() {
+            return None;
+        }
+
+        let mut builder = GitignoreBuilder::new(directory);
+        if let Some(e) = builder.add(&file) {
+            tracing::warn!("Skipping invalid lines in {:?}: {}", file, e);
+        }
+        builder.build().ok()
+    }
+
+    /// Whether `relative_path` points inside one of the internal directories
+    pub fn is_reserved_path(&self, relative_path: &str) -> bool {
+        relative_path
+            .trim_start_matches('/')
+            .split('/')
+            .next()
+            .map_or(false, |first| RESERVED_DIRS.contains(&first))
     }
 
     pub fn get_relative_path(&self, absolute_path: &Path) -> Result<String> {
+        for mount in &self.mounts {
+            if let Ok(relative) = absolute_path.strip_prefix(&mount.path) {
+                let mut path = format!("/{}", mount.name);
+                if !relative.as_os_str().is_empty() {
+                    path.push('/');
+                    path.push_str(&relative.to_string_lossy());
+                }
+                return Ok(normalize_path(&path));
+            }
+        }
+
         let relative = absolute_path.strip_prefix(&self.base_path)?;
-        Ok(format!("/{}", relative.to_string_lossy()))
+        Ok(normalize_path(&format!("/{}", relative.to_string_lossy())))
     }
 
     pub async fn save_file(&self, relative_path: &str, data: &[u8]) -> Result<FileMetadata> {
         if data.len() as u64 > self.max_file_size {
-            return Err(anyhow!("File size exceeds maximum allowed size"));
+            return Err(FileSystemError::FileTooLarge.into());
         }
 
-        let absolute_path = self.get_absolute_path(relative_path);
-        
+        self.save_file_from_reader(relative_path, data).await
+    }
+
+    /// Write `reader` to `relative_path` atomically: the data goes to a temp file
+    /// next to the destination, is fsynced, and only then renamed over it. A failed
+    /// write leaves any previous version of the file untouched.
+    pub async fn save_file_from_reader<R: AsyncRead + Unpin>(
+        &self,
+        relative_path: &str,
+        mut reader: R,
+    ) -> Result<FileMetadata> {
+        let absolute_path = self.resolve_path(relative_path)?;
+
         // Create parent directories if they don't exist
         if let Some(parent) = absolute_path.parent() {
             async_fs::create_dir_all(parent).await?;
         }
 
-        // Write file
-        async_fs::write(&absolute_path, data).await?;
+        let temp_path = sibling_temp_path(&absolute_path);
+        let result = async {
+            let mut file = async_fs::File::create(&temp_path).await?;
+            let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+            let mut hasher = Sha256::new();
+            let mut written = 0u64;
+
+            loop {
+                let bytes_read = reader.read(&mut buffer).await?;
+                if bytes_read == 0 {
+                    break;
+                }
+
+                written += bytes_read as u64;
+                if written > self.max_file_size {
+                    return Err(FileSystemError::FileTooLarge.into());
+                }
+
+                hasher.update(&buffer[..bytes_read]);
+                file.write_all(&buffer[..bytes_read]).await?;
+            }
+
+            file.flush().await?;
+            file.sync_all().await?;
+
+            let checksum = format!("{:x}", hasher.finalize());
+            self.commit_file(&temp_path, &absolute_path, &checksum, written).await?;
+            Ok::<_, anyhow::Error>(checksum)
+        }
+        .await;
+
+        let checksum = match result {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                let _ = async_fs::remove_file(&temp_path).await;
+                return Err(e);
+            }
+        };
 
         // Generate metadata
-        let metadata = self.generate_file_metadata(&absolute_path, Uuid::new_v4()).await?;
+        let metadata = self
+            .generate_file_metadata_with_checksum(&absolute_path, Uuid::new_v4(), Some(checksum))
+            .await?;
+        Ok(metadata)
+    }
+
+    pub async fn begin_upload(&self) -> Result<UploadWriter> {
+        async_fs::create_dir_all(&self.temp_directory).await?;
+
+        let temp_path = self.temp_directory.join(format!("upload-{}.part", Uuid::new_v4()));
+        let file = async_fs::File::create(&temp_path).await?;
+
+        Ok(UploadWriter {
+            temp_path,
+            file,
+            hasher: Sha256::new(),
+            size: 0,
+            max_size: self.max_file_size,
+        })
+    }
+
+    /// Move a staged upload into place at `relative_path`
+    pub async fn commit_upload(&self, relative_path: &str, upload: StagedUpload) -> Result<FileMetadata> {
+        let result = async {
+            let absolute_path = self.resolve_path(relative_path)?;
+            self.commit_file(&upload.temp_path, &absolute_path, &upload.checksum, upload.size).await?;
+            Ok::<_, anyhow::Error>(absolute_path)
+        }
+        .await;
+
+        let absolute_path = match result {
+            Ok(absolute_path) => absolute_path,
+            Err(e) => {
+                upload.discard().await;
+                return Err(e);
+            }
+        };
+
+        let metadata = self
+            .generate_file_metadata_with_checksum(&absolute_path, Uuid::new_v4(), Some(upload.checksum))
+            .await?;
         Ok(metadata)
     }
 
+    fn session_temp_path(&self, session_id: Uuid) -> PathBuf {
+        self.temp_directory.join(format!("session-{}.part", session_id))
+    }
+
+    /// Write one chunk of a resumable upload at its offset in the session's temp file.
+    /// Chunks may arrive in any order and re-sending a chunk simply overwrites it.
+    pub async fn write_session_chunk(&self, session_id: Uuid, offset: u64, data: &[u8]) -> Result<()> {
+        async_fs::create_dir_all(&self.temp_directory).await?;
+
+        let mut file = async_fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(self.session_temp_path(session_id))
+            .await?;
+
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(data).await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+
+    /// Bring a session's running checksum up to `end` bytes. `chunk`, just
+    /// written at `offset`, is hashed from memory when it continues the hashed
+    /// prefix; chunks that arrived early are read back from the temp file. A
+    /// chunk rewriting already hashed data starts the hash over.
+    pub async fn extend_session_hash(
+        &self,
+        session_id: Uuid,
+        hash: &mut ResumableSha256,
+        offset: u64,
+        chunk: &[u8],
+        end: u64,
+    ) -> Result<()> {
+        if offset < hash.len() {
+            *hash = ResumableSha256::new();
+        }
+        if offset == hash.len() {
+            hash.update(chunk);
+        }
+        if hash.len() >= end {
+            return Ok(());
+        }
+
+        self.checksum_reads.fetch_add(1, Ordering::Relaxed);
+        let mut file = async_fs::File::open(self.session_temp_path(session_id)).await?;
+        file.seek(SeekFrom::Start(hash.len())).await?;
+        let mut reader = file.take(end - hash.len());
+        let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+
+        loop {
+            let bytes_read = reader.read(&mut buffer).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            hash.update(&buffer[..bytes_read]);
+        }
+
+        Ok(())
+    }
+
+    /// Verify the assembled session file and hand it over for storing. The
+    /// file is only read again if `hash` doesn't already cover all of it.
+    pub async fn stage_upload_session(
+        &self,
+        session_id: Uuid,
+        hash: Option<ResumableSha256>,
+        expected_checksum: Option<&str>,
+    ) -> Result<StagedUpload> {
+        let temp_path = self.session_temp_path(session_id);
+
+        if !temp_path.exists() {
+            return Err(anyhow!("Upload session data not found"));
+        }
+
+        let size = async_fs::metadata(&temp_path).await?.len();
+        let checksum = match hash {
+            Some(hash) if hash.len() == size => hash.finalize(),
+            _ => self.calculate_checksum(&temp_path).await?,
+        };
+        if let Some(expected) = expected_checksum {
+            if !expected.eq_ignore_ascii_case(&checksum) {
+                return Err(FileSystemError::ChecksumMismatch {
+                    expected: expected.to_string(),
+                    actual: checksum,
+                }
+                .into());
+            }
+        }
+
+        Ok(StagedUpload { temp_path, size, checksum })
+    }
+
+    pub async fn discard_upload_session(&self, session_id: Uuid) -> Result<()> {
+        let temp_path = self.session_temp_path(session_id);
+
+        if temp_path.exists() {
+            async_fs::remove_file(temp_path).await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn read_file(&self, relative_path: &str) -> Result<Vec<u8>> {
-        let absolute_path = self.get_absolute_path(relative_path);
+        let absolute_path = self.resolve_path(relative_path)?;
         
         if !absolute_path.exists() {
             return Err(anyhow!("File not found"));
@@ -75,24 +989,233 @@ impl FileSystemService {
         Ok(data)
     }
 
+    /// Stream a file's contents, or only the bytes in `range`
+    pub async fn open_file_stream(
+        &self,
+        relative_path: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<ReaderStream<Take<async_fs::File>>> {
+        let absolute_path = self.resolve_path(relative_path)?;
+
+        if !absolute_path.is_file() {
+            return Err(anyhow!("File not found"));
+        }
+
+        let mut file = async_fs::File::open(absolute_path).await?;
+        let length = match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                range.end.saturating_sub(range.start)
+            }
+            None => u64::MAX,
+        };
+
+        Ok(ReaderStream::with_capacity(file.take(length), STREAM_CHUNK_SIZE))
+    }
+
     pub async fn delete_file(&self, relative_path: &str) -> Result<()> {
-        let absolute_path = self.get_absolute_path(relative_path);
+        let absolute_path = self.resolve_path(relative_path)?;
         
         if !absolute_path.exists() {
             return Err(anyhow!("File not found"));
         }
 
+        self.remove_path(&absolute_path).await
+    }
+
+    /// Where a trashed entry is kept, as a path in the same storage
+    pub fn trash_location(user_id: Uuid, entry_id: Uuid) -> String {
+        format!("/{}/{}/{}", TRASH_DIR, user_id, entry_id)
+    }
+
+    /// Where a user's home is, as a path in the storage before `for_user`
+    pub fn home_location(user_id: Uuid) -> String {
+        format!("/{}/{}", USERS_DIR, user_id)
+    }
+
+    fn version_path(&self, checksum: &str) -> PathBuf {
+        self.root_path.join(VERSIONS_DIR).join(checksum)
+    }
+
+    /// Preserve the current content of a file in the version store, returning
+    /// its checksum and size. Identical content is only stored once.
+    pub async fn store_version(&self, relative_path: &str) -> Result<(String, u64)> {
+        let absolute_path = self.resolve_path(relative_path)?;
+
+        if !absolute_path.is_file() {
+            return Err(anyhow!("File not found"));
+        }
+
+        let checksum = self.calculate_checksum(&absolute_path).await?;
+        let size = async_fs::metadata(&absolute_path).await?.len();
+        let version_path = self.version_path(&checksum);
+
+        if !version_path.exists() {
+            if let Some(parent) = version_path.parent() {
+                async_fs::create_dir_all(parent).await?;
+            }
+
+            // A hard link is free and survives the original being replaced by
+            // rename; fall back to a copy when the filesystem can't link
+            if async_fs::hard_link(&absolute_path, &version_path).await.is_err() {
+                async_fs::copy(&absolute_path, &version_path).await?;
+            }
+        }
+
+        Ok((checksum, size))
+    }
+
+    pub async fn open_version_stream(&self, checksum: &str) -> Result<ReaderStream<async_fs::File>> {
+        let file = async_fs::File::open(self.version_path(checksum)).await
+            .map_err(|_| anyhow!("Version not found"))?;
+        Ok(ReaderStream::with_capacity(file, STREAM_CHUNK_SIZE))
+    }
+
+    /// Atomically replace the file at `relative_path` with a stored version
+    pub async fn restore_version(&self, checksum: &str, relative_path: &str) -> Result<FileMetadata> {
+        let file = async_fs::File::open(self.version_path(checksum)).await
+            .map_err(|_| anyhow!("Version not found"))?;
+        self.save_file_from_reader(relative_path, file).await
+    }
+
+    pub async fn delete_version(&self, checksum: &str) -> Result<()> {
+        let version_path = self.version_path(checksum);
+
+        if version_path.exists() {
+            self.remove_path(&version_path).await?;
+        }
+
+        Ok(())
+    }
+
+    fn object_path(&self, checksum: &str) -> PathBuf {
+        self.root_path.join(OBJECTS_DIR).join(checksum)
+    }
+
+    /// Move a fully written temp file to `absolute_path`. With deduplication on,
+    /// content already in the object store is linked into place and the temp
+    /// file dropped; new content is added to the store.
+    async fn commit_file(&self, temp_path: &Path, absolute_path: &Path, checksum: &str, size: u64) -> Result<()> {
+        if let Some(parent) = absolute_path.parent() {
+            async_fs::create_dir_all(parent).await?;
+        }
+
+        // Objects live under base_path and can't be hard linked into a mount on another drive
+        if !self.deduplicate || self.root_of(absolute_path).0 != self.base_path {
+            self.rename_or_copy(temp_path, absolute_path).await?;
+            return Ok(());
+        }
+
+        // Whatever is being overwritten may hold the last link to its object
+        let replaced = self.linked_object(absolute_path).await?;
+        let object = self.object_path(checksum);
+
+        match async_fs::metadata(&object).await {
+            Ok(existing) if existing.len() == size => {
+                let link_path = sibling_temp_path(absolute_path);
+                async_fs::hard_link(&object, &link_path).await?;
+                if let Err(e) = async_fs::rename(&link_path, absolute_path).await {
+                    let _ = async_fs::remove_file(&link_path).await;
+                    return Err(e.into());
+                }
+                async_fs::remove_file(temp_path).await?;
+            }
+            _ => {
+                self.rename_or_copy(temp_path, absolute_path).await?;
+                self.add_object(absolute_path, &object).await?;
+            }
+        }
+
+        if let Some(replaced) = replaced {
+            self.release_object(&replaced).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn add_object(&self, absolute_path: &Path, object: &Path) -> Result<()> {
+        if let Some(parent) = object.parent() {
+            async_fs::create_dir_all(parent).await?;
+        }
+
+        match async_fs::hard_link(absolute_path, object).await {
+            Ok(()) => Ok(()),
+            // Another upload of the same content got there first
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            // The file is still stored, just not shared
+            Err(e) => {
+                tracing::warn!("Failed to add {:?} to the object store: {}", absolute_path, e);
+                Ok(())
+            }
+        }
+    }
+
+    /// The object `path` is linked to, if any
+    async fn linked_object(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let metadata = match async_fs::symlink_metadata(path).await {
+            Ok(metadata) if metadata.is_file() && link_count(&metadata) > 1 => metadata,
+            _ => return Ok(None),
+        };
+
+        let checksum = match self.cached_checksum(path, metadata.len(), metadata.modified().ok()) {
+            Some(checksum) => checksum,
+            None => self.calculate_checksum(path).await?,
+        };
+
+        let object = self.object_path(&checksum);
+        match async_fs::metadata(&object).await {
+            Ok(object_metadata) if same_file(&metadata, &object_metadata) => Ok(Some(object)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Drop an object once the store holds the only remaining link to it
+    async fn release_object(&self, object: &Path) -> Result<()> {
+        if let Ok(metadata) = async_fs::metadata(object).await {
+            if link_count(&metadata) == 1 {
+                async_fs::remove_file(object).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove a file or directory, releasing objects that lose their last link
+    async fn remove_path(&self, absolute_path: &Path) -> Result<()> {
+        let mut objects = Vec::new();
+
+        if self.deduplicate {
+            let files: Vec<PathBuf> = WalkDir::new(absolute_path)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_file())
+                .map(|entry| entry.into_path())
+                .collect();
+
+            for file in files {
+                if let Some(object) = self.linked_object(&file).await? {
+                    objects.push(object);
+                }
+            }
+        }
+
         if absolute_path.is_dir() {
             async_fs::remove_dir_all(absolute_path).await?;
         } else {
             async_fs::remove_file(absolute_path).await?;
         }
 
+        objects.sort();
+        objects.dedup();
+        for object in objects {
+            self.release_object(&object).await?;
+        }
+
         Ok(())
     }
 
     pub async fn create_directory(&self, relative_path: &str) -> Result<FileMetadata> {
-        let absolute_path = self.get_absolute_path(relative_path);
+        let absolute_path = self.resolve_path(relative_path)?;
         
         async_fs::create_dir_all(&absolute_path).await?;
         
@@ -101,8 +1224,8 @@ impl FileSystemService {
     }
 
     pub async fn move_file(&self, old_path: &str, new_path: &str) -> Result<()> {
-        let old_absolute = self.get_absolute_path(old_path);
-        let new_absolute = self.get_absolute_path(new_path);
+        let old_absolute = self.resolve_path(old_path)?;
+        let new_absolute = self.resolve_path(new_path)?;
         
         if !old_absolute.exists() {
             return Err(anyhow!("Source file not found"));
@@ -113,12 +1236,81 @@ impl FileSystemService {
             async_fs::create_dir_all(parent).await?;
         }
 
-        async_fs::rename(old_absolute, new_absolute).await?;
-        Ok(())
+        self.rename_or_copy(&old_absolute, &new_absolute).await
+    }
+
+    /// Rename, falling back to copy and delete when `from` and `to` are on
+    /// different filesystems, e.g. when moving between mounts or out of a
+    /// temp directory on another disk. The copy keeps modification times and
+    /// is fsynced before the source is removed.
+    async fn rename_or_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        match self.rename(from, to).await {
+            Ok(()) => return Ok(()),
+            Err(e) if !is_cross_device(&e) => return Err(e.into()),
+            Err(_) => {}
+        }
+
+        // The copy goes next to the destination and is renamed into place once
+        // complete, so a failure never leaves a partial tree at `to`
+        let staging = sibling_temp_path(to);
+        let (source, target) = (from.to_path_buf(), staging.clone());
+        let max_files = self.copy_fallback_limit();
+        let copied = tokio::task::spawn_blocking(move || copy_recursive(&source, &target, max_files)).await?;
+
+        let placed = match copied {
+            Ok(()) => async_fs::rename(&staging, to).await.map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = placed {
+            if staging.is_dir() {
+                let _ = async_fs::remove_dir_all(&staging).await;
+            } else {
+                let _ = async_fs::remove_file(&staging).await;
+            }
+            return Err(e);
+        }
+
+        if let Some(parent) = to.parent() {
+            sync_directory(parent)?;
+        }
+        self.remove_path(from).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        #[cfg(test)]
+        if self.simulated_cross_device.is_some() {
+            return Err(io::Error::from_raw_os_error(CROSS_DEVICE_ERROR));
+        }
+
+        async_fs::rename(from, to).await
+    }
+
+    /// Files the copy fallback may copy before failing
+    fn copy_fallback_limit(&self) -> usize {
+        #[cfg(test)]
+        if let Some(limit) = self.simulated_cross_device {
+            return limit;
+        }
+
+        usize::MAX
     }
 
     pub async fn list_directory(&self, relative_path: &str) -> Result<Vec<FileMetadata>> {
-        let absolute_path = self.get_absolute_path(relative_path);
+        self.list_directory_with_checksums(relative_path, true).await
+    }
+
+    /// List a directory without reading file contents. Checksums are only
+    /// filled in when a cached value is still valid, otherwise left empty.
+    pub async fn list_directory_fast(&self, relative_path: &str) -> Result<Vec<FileMetadata>> {
+        self.list_directory_with_checksums(relative_path, false).await
+    }
+
+    async fn list_directory_with_checksums(
+        &self,
+        relative_path: &str,
+        compute_checksum: bool,
+    ) -> Result<Vec<FileMetadata>> {
+        let absolute_path = self.resolve_path(relative_path)?;
         
         if !absolute_path.exists() || !absolute_path.is_dir() {
             return Err(anyhow!("Directory not found"));
@@ -127,11 +1319,53 @@ impl FileSystemService {
         let mut entries = Vec::new();
         let mut dir_entries = async_fs::read_dir(absolute_path).await?;
         
+        let is_root = absolute_path == self.base_path;
+        let mut ignore_matcher = self.ignore_matcher();
+
         while let Some(entry) = dir_entries.next_entry().await? {
-            let metadata = self.generate_file_metadata(&entry.path(), Uuid::new_v4()).await?;
+            if RESERVED_DIRS.iter().any(|name| entry.file_name() == *name) {
+                continue;
+            }
+
+            // A mount hides any directory of the same name under base_path
+            if is_root && self.mounts.iter().any(|mount| entry.file_name() == mount.name.as_str()) {
+                continue;
+            }
+
+            let file_type = entry.file_type().await?;
+            if ignore_matcher.is_ignored(&self.get_relative_path(&entry.path())?, file_type.is_dir()) {
+                continue;
+            }
+
+            if file_type.is_symlink() {
+                match self.symlink_policy {
+                    SymlinkPolicy::Skip => continue,
+                    SymlinkPolicy::Deny => self.check_symlink(&entry.path())?,
+                    // Dangling links and links leaving base_path are left out
+                    SymlinkPolicy::FollowWithinBase => {
+                        if self.check_symlink(&entry.path()).is_err() {
+                            continue;
+                        }
+                    }
+                }
+            }
+
+            let metadata = self
+                .build_file_metadata(&entry.path(), Uuid::new_v4(), None, compute_checksum)
+                .await?;
             entries.push(metadata);
         }
 
+        // Mounts appear as directories at the top level
+        if is_root {
+            for mount in &self.mounts {
+                let metadata = self
+                    .build_file_metadata(&mount.path, Uuid::new_v4(), None, compute_checksum)
+                    .await?;
+                entries.push(metadata);
+            }
+        }
+
         entries.sort_by(|a, b| {
             // Sort directories first, then by name
             match (a.is_directory, b.is_directory) {
@@ -145,7 +1379,7 @@ impl FileSystemService {
     }
 
     pub async fn get_file_metadata(&self, relative_path: &str) -> Result<FileMetadata> {
-        let absolute_path = self.get_absolute_path(relative_path);
+        let absolute_path = self.resolve_path(relative_path)?;
         
         if !absolute_path.exists() {
             return Err(anyhow!("File not found"));
@@ -156,14 +1390,36 @@ impl FileSystemService {
     }
 
     async fn generate_file_metadata(&self, path: &Path, owner_id: Uuid) -> Result<FileMetadata> {
+        self.build_file_metadata(path, owner_id, None, true).await
+    }
+
+    /// Like `generate_file_metadata`, but reuses a checksum computed while the
+    /// file was written instead of reading the contents again.
+    async fn generate_file_metadata_with_checksum(
+        &self,
+        path: &Path,
+        owner_id: Uuid,
+        known_checksum: Option<String>,
+    ) -> Result<FileMetadata> {
+        self.build_file_metadata(path, owner_id, known_checksum, true).await
+    }
+
+    async fn build_file_metadata(
+        &self,
+        path: &Path,
+        owner_id: Uuid,
+        known_checksum: Option<String>,
+        compute_checksum: bool,
+    ) -> Result<FileMetadata> {
         let std_metadata = async_fs::metadata(path).await?;
         let relative_path = self.get_relative_path(path)?;
+        let is_symlink = async_fs::symlink_metadata(path)
+            .await
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
         
-        let name = path
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("")
-            .to_string();
+        // Taken from the relative path so a mount shows under its configured name
+        let name = relative_path.rsplit('/').next().unwrap_or("").to_string();
 
         let is_directory = std_metadata.is_dir();
         let size = if is_directory { 0 } else { std_metadata.len() };
@@ -174,10 +1430,20 @@ impl FileSystemService {
             from_path(path).first_or_octet_stream().to_string()
         };
 
+        let modified = std_metadata.modified().ok();
         let checksum = if is_directory {
             String::new()
+        } else if let Some(checksum) = known_checksum {
+            self.cache_checksum(path, size, modified, &checksum);
+            checksum
+        } else if let Some(checksum) = self.cached_checksum(path, size, modified) {
+            checksum
+        } else if compute_checksum {
+            let checksum = self.calculate_checksum(path).await?;
+            self.cache_checksum(path, size, modified, &checksum);
+            checksum
         } else {
-            self.calculate_checksum(path).await?
+            String::new()
         };
 
         let created_at = std_metadata
@@ -201,6 +1467,7 @@ impl FileSystemService {
             modified_at,
             owner_id,
             is_directory,
+            is_symlink,
             parent_id: None, // This would need to be set by the caller
             permissions: FilePermissions {
                 read: true,
@@ -211,10 +1478,61 @@ impl FileSystemService {
         })
     }
 
-    async fn calculate_checksum(&self, path: &Path) -> Result<String> {
-        let mut file = async_fs::File::open(path).await?;
+    fn cached_checksum(&self, path: &Path, size: u64, modified: Option<SystemTime>) -> Option<String> {
+        let mut cache = self.checksum_cache.lock().unwrap();
+        match cache.get(path) {
+            Some(cached) if cached.size == size && cached.modified == modified => {
+                Some(cached.checksum.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn cache_checksum(&self, path: &Path, size: u64, modified: Option<SystemTime>, checksum: &str) {
+        let mut cache = self.checksum_cache.lock().unwrap();
+        cache.put(
+            path.to_path_buf(),
+            CachedChecksum {
+                size,
+                modified,
+                checksum: checksum.to_string(),
+            },
+        );
+    }
+
+    async fn calculate_checksum(&self, path: &Path) -> Result<String> {
+        self.checksum_reads.fetch_add(1, Ordering::Relaxed);
+
+        let mut file = async_fs::File::open(path).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 8192];
+
+        loop {
+            let bytes_read = file.read(&mut buffer).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes_read]);
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Filesystem metadata of `relative_path` without reading its contents
+    pub async fn stat(&self, relative_path: &str) -> Result<Metadata> {
+        let absolute_path = self.resolve_path(relative_path)?;
+        Ok(async_fs::metadata(absolute_path).await?)
+    }
+
+    /// Hash a file's current contents, reading no faster than `max_bytes_per_sec`
+    /// so background work leaves disk bandwidth for users. Zero means unthrottled.
+    pub async fn calculate_checksum_throttled(&self, relative_path: &str, max_bytes_per_sec: u64) -> Result<String> {
+        let absolute_path = self.resolve_path(relative_path)?;
+        let mut file = async_fs::File::open(absolute_path).await?;
         let mut hasher = Sha256::new();
-        let mut buffer = vec![0; 8192];
+        let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+        let started = std::time::Instant::now();
+        let mut total_read = 0u64;
 
         loop {
             let bytes_read = file.read(&mut buffer).await?;
@@ -222,39 +1540,60 @@ impl FileSystemService {
                 break;
             }
             hasher.update(&buffer[..bytes_read]);
+            total_read += bytes_read as u64;
+
+            if max_bytes_per_sec > 0 {
+                let due = Duration::from_secs_f64(total_read as f64 / max_bytes_per_sec as f64);
+                let elapsed = started.elapsed();
+                if due > elapsed {
+                    tokio::time::sleep(due - elapsed).await;
+                }
+            }
         }
 
         Ok(format!("{:x}", hasher.finalize()))
     }
 
-    pub fn watch_directory(&self, relative_path: &str) -> Result<mpsc::Receiver<DebouncedEvent>> {
-        let absolute_path = self.get_absolute_path(relative_path);
+    /// Watch `relative_path` recursively; watching `/` covers the mounts too.
+    /// Events arrive until the returned handle is dropped.
+    pub fn watch_directory(&self, relative_path: &str) -> Result<DirectoryWatch> {
+        let absolute_path = self.resolve_path(relative_path)?;
         let (tx, rx) = mpsc::channel();
-        
+
         let mut watcher = watcher(tx, Duration::from_secs(1))?;
         watcher.watch(&absolute_path, RecursiveMode::Recursive)?;
-        
-        // Keep watcher alive by moving it into a thread
-        std::thread::spawn(move || {
-            loop {
-                std::thread::sleep(Duration::from_secs(1));
+        if absolute_path == self.base_path {
+            for mount in &self.mounts {
+                watcher.watch(&mount.path, RecursiveMode::Recursive)?;
             }
-        });
+        }
 
-        Ok(rx)
+        Ok(DirectoryWatch {
+            _watcher: watcher,
+            events: rx,
+        })
     }
 
     pub async fn get_directory_size(&self, relative_path: &str) -> Result<u64> {
-        let absolute_path = self.get_absolute_path(relative_path);
+        let absolute_path = self.resolve_path(relative_path)?;
         
         if !absolute_path.exists() {
             return Err(anyhow!("Directory not found"));
         }
 
         let mut total_size = 0u64;
+        let mut ignore_matcher = self.ignore_matcher();
+        let walk = WalkDir::new(&absolute_path).into_iter().filter_entry(|entry| {
+            self.get_relative_path(entry.path())
+                .map_or(true, |path| !ignore_matcher.is_ignored(&path, entry.file_type().is_dir()))
+        });
         
-        for entry in WalkDir::new(&absolute_path) {
+        // Walks never descend through symlinks, so cyclic links can't loop
+        for entry in walk {
             let entry = entry?;
+            if entry.path_is_symlink() && self.symlink_policy == SymlinkPolicy::Deny {
+                self.check_symlink(entry.path())?;
+            }
             if entry.file_type().is_file() {
                 total_size += entry.metadata()?.len();
             }
@@ -264,8 +1603,8 @@ impl FileSystemService {
     }
 
     pub async fn copy_file(&self, source_path: &str, dest_path: &str) -> Result<FileMetadata> {
-        let source_absolute = self.get_absolute_path(source_path);
-        let dest_absolute = self.get_absolute_path(dest_path);
+        let source_absolute = self.resolve_path(source_path)?;
+        let dest_absolute = self.resolve_path(dest_path)?;
         
         if !source_absolute.exists() {
             return Err(anyhow!("Source file not found"));
@@ -282,31 +1621,499 @@ impl FileSystemService {
         Ok(metadata)
     }
 
-    pub fn get_available_space(&self) -> Result<u64> {
-        // This is a simplified implementation
-        // In a real implementation, you'd use platform-specific APIs
-        // to get actual disk space information
-        
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::MetadataExt;
-            let metadata = fs::metadata(&self.base_path)?;
-            // This is not accurate - you'd need to use statvfs or similar
-            Ok(u64::MAX) // Placeholder
+    /// Copy a file or a whole directory tree to `dest_path`, which must not exist.
+    /// Directories are walked depth-first and the returned metadata lists every
+    /// created entry with parents before their children. The total size is checked
+    /// against the copy limit up front, and if anything fails midway the partial
+    /// copy is removed.
+    pub async fn copy_tree(&self, source_path: &str, dest_path: &str) -> Result<Vec<FileMetadata>> {
+        let source_absolute = self.resolve_path(source_path)?;
+        let dest_absolute = self.resolve_path(dest_path)?;
+
+        if !source_absolute.exists() {
+            return Err(anyhow!("Source file not found"));
         }
-        
-        #[cfg(windows)]
-        {
-            // Use GetDiskFreeSpaceEx on Windows
-            Ok(u64::MAX) // Placeholder
+        if dest_absolute.exists() {
+            return Err(anyhow!("Destination already exists"));
+        }
+
+        let total_size = if source_absolute.is_dir() {
+            self.get_directory_size(source_path).await?
+        } else {
+            async_fs::metadata(&source_absolute).await?.len()
+        };
+
+        if total_size > self.max_copy_size {
+            return Err(FileSystemError::CopyTooLarge { limit: self.max_copy_size }.into());
+        }
+
+        if let Some(parent) = dest_absolute.parent() {
+            async_fs::create_dir_all(parent).await?;
+        }
+
+        let mut copied = Vec::new();
+        let result = self.copy_entries(&source_absolute, &dest_absolute, &mut copied).await;
+
+        if let Err(e) = result {
+            if dest_absolute.is_dir() {
+                let _ = async_fs::remove_dir_all(&dest_absolute).await;
+            } else {
+                let _ = async_fs::remove_file(&dest_absolute).await;
+            }
+            return Err(e);
+        }
+
+        Ok(copied)
+    }
+
+    async fn copy_entries(
+        &self,
+        source: &Path,
+        dest: &Path,
+        copied: &mut Vec<FileMetadata>,
+    ) -> Result<()> {
+        let mut pending = vec![(source.to_path_buf(), dest.to_path_buf())];
+        let mut ignore_matcher = self.ignore_matcher();
+
+        while let Some((source, dest)) = pending.pop() {
+            let source_metadata = async_fs::symlink_metadata(&source).await?;
+
+            if source_metadata.is_dir() {
+                async_fs::create_dir(&dest).await?;
+                copied.push(self.build_file_metadata(&dest, Uuid::new_v4(), None, false).await?);
+
+                let mut children = Vec::new();
+                let mut dir_entries = async_fs::read_dir(&source).await?;
+                while let Some(entry) = dir_entries.next_entry().await? {
+                    let is_dir = entry.file_type().await?.is_dir();
+                    if !ignore_matcher.is_ignored(&self.get_relative_path(&entry.path())?, is_dir) {
+                        children.push(entry.file_name());
+                    }
+                }
+
+                // Reverse so the stack pops children in name order
+                children.sort();
+                for name in children.into_iter().rev() {
+                    pending.push((source.join(&name), dest.join(&name)));
+                }
+            } else if source_metadata.is_file() {
+                // Deduplicated content only needs another link, not a copy
+                let object = if self.deduplicate { self.linked_object(&source).await? } else { None };
+                let linked = match object {
+                    Some(object) => async_fs::hard_link(&object, &dest).await.is_ok(),
+                    None => false,
+                };
+                // Copies into a mount on another drive can't share the object
+                if !linked {
+                    async_fs::copy(&source, &dest).await?;
+                }
+
+                // Same bytes as the source, so a valid cached checksum carries over
+                let known_checksum = self.cached_checksum(
+                    &source,
+                    source_metadata.len(),
+                    source_metadata.modified().ok(),
+                );
+                copied.push(self.build_file_metadata(&dest, Uuid::new_v4(), known_checksum, true).await?);
+            } else if source_metadata.file_type().is_symlink() && self.symlink_policy == SymlinkPolicy::Deny {
+                self.check_symlink(&source)?;
+            }
+            // Otherwise symlinks and other special files are not copied
+        }
+
+        Ok(())
+    }
+
+    /// Space on the volume containing `base_path`. This is a blocking syscall;
+    /// async callers should use `get_disk_space_async`.
+    pub fn get_disk_space(&self) -> Result<DiskSpace> {
+        disk_space_at(&self.base_path)
+    }
+
+    pub async fn get_disk_space_async(&self) -> Result<DiskSpace> {
+        let service = self.clone();
+        tokio::task::spawn_blocking(move || service.get_disk_space()).await?
+    }
+
+    /// Space on the volume of each mount
+    pub async fn get_mount_disk_space(&self) -> Result<Vec<MountDiskSpace>> {
+        let mounts = self.mounts.clone();
+
+        tokio::task::spawn_blocking(move || {
+            mounts
+                .iter()
+                .map(|mount| {
+                    Ok(MountDiskSpace {
+                        name: mount.name.clone(),
+                        disk: disk_space_at(&mount.path)?,
+                    })
+                })
+                .collect()
+        })
+        .await?
+    }
+
+    /// Size of all user-visible files (logical) against the space actually
+    /// used under `base_path` and the mounts, where hard-linked content counts
+    /// once (physical). Walks the whole tree, so it runs on the blocking pool.
+    pub async fn get_storage_usage(&self) -> Result<StorageUsage> {
+        let base_path = self.base_path.clone();
+        let user_homes = self.user_homes && self.home.is_none();
+        let roots: Vec<PathBuf> = std::iter::once(base_path.clone())
+            .chain(self.mounts.iter().map(|mount| mount.path.clone()))
+            .collect();
+
+        tokio::task::spawn_blocking(move || {
+            let mut logical = 0u64;
+            let mut physical = 0u64;
+            let mut seen = std::collections::HashSet::new();
+
+            for entry in roots.iter().flat_map(WalkDir::new) {
+                let entry = entry?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+
+                let metadata = entry.metadata()?;
+                // Inside a home, the reserved directories sit below `users/<id>`
+                let reserved = entry
+                    .path()
+                    .strip_prefix(&base_path)
+                    .ok()
+                    .and_then(|relative| {
+                        let mut components = relative.components();
+                        match components.next() {
+                            Some(first) if user_homes && first.as_os_str() == USERS_DIR => components.nth(1),
+                            first => first,
+                        }
+                    })
+                    .map_or(false, |first| RESERVED_DIRS.iter().any(|name| first.as_os_str() == *name));
+
+                if !reserved {
+                    logical += metadata.len();
+                }
+                if link_count(&metadata) == 1 || seen.insert(file_identity(&metadata)) {
+                    physical += metadata.len();
+                }
+            }
+
+            Ok(StorageUsage { logical, physical })
+        })
+        .await?
+    }
+}
+
+/// OS error code for a rename that would cross filesystems
+#[cfg(unix)]
+const CROSS_DEVICE_ERROR: i32 = libc::EXDEV;
+
+// ERROR_NOT_SAME_DEVICE
+#[cfg(not(unix))]
+const CROSS_DEVICE_ERROR: i32 = 17;
+
+/// Whether a rename failed only because it would cross filesystems
+fn is_cross_device(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(CROSS_DEVICE_ERROR)
+}
+
+/// Copy a file or directory tree, parents before children, keeping
+/// modification times and syncing everything to disk. Symlinks and other
+/// special files are not copied. Fails once more than `max_files` files
+/// would be copied.
+fn copy_recursive(source: &Path, dest: &Path, max_files: usize) -> Result<()> {
+    let mut directories = Vec::new();
+    let mut copied = 0;
+
+    for entry in WalkDir::new(source) {
+        let entry = entry?;
+        // Joining an empty path would add a trailing slash when copying a single file
+        let relative = entry.path().strip_prefix(source)?;
+        let target = if relative.as_os_str().is_empty() { dest.to_path_buf() } else { dest.join(relative) };
+        let modified = entry.metadata()?.modified()?;
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+            directories.push((target, modified));
+        } else if entry.file_type().is_file() {
+            if copied == max_files {
+                return Err(anyhow!("Copy of {:?} stopped after {} files", source, copied));
+            }
+
+            fs::copy(entry.path(), &target)?;
+            let file = fs::File::options().write(true).open(&target)?;
+            file.set_modified(modified)?;
+            file.sync_all()?;
+            copied += 1;
+        }
+    }
+
+    // Creating children bumps a directory's mtime, so restore the deepest ones first
+    for (target, modified) in directories.iter().rev() {
+        finish_directory(target, *modified)?;
+    }
+
+    Ok(())
+}
+
+/// Give a copied directory its source's mtime and sync its entries. Windows
+/// can't open directories as files, so there this does nothing.
+#[cfg(unix)]
+fn finish_directory(path: &Path, modified: SystemTime) -> io::Result<()> {
+    let directory = fs::File::open(path)?;
+    directory.set_modified(modified)?;
+    directory.sync_all()
+}
+
+#[cfg(not(unix))]
+fn finish_directory(_path: &Path, _modified: SystemTime) -> io::Result<()> {
+    Ok(())
+}
+
+/// Sync a directory's entries, e.g. after renaming a file into it
+#[cfg(unix)]
+fn sync_directory(path: &Path) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Space on the volume containing `path`. This is a blocking syscall.
+fn disk_space_at(path: &Path) -> Result<DiskSpace> {
+    #[cfg(unix)]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let block_size = stat.f_frsize as u64;
+        let total = stat.f_blocks as u64 * block_size;
+        let free = stat.f_bfree as u64 * block_size;
+        let available = stat.f_bavail as u64 * block_size;
+
+        Ok(DiskSpace {
+            total,
+            available,
+            used: total.saturating_sub(free),
+        })
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+        let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        let (mut available, mut total, mut free) = (0u64, 0u64, 0u64);
+        if unsafe { GetDiskFreeSpaceExW(path.as_ptr(), &mut available, &mut total, &mut free) } == 0 {
+            return Err(io::Error::last_os_error().into());
         }
+
+        Ok(DiskSpace {
+            total,
+            available,
+            used: total.saturating_sub(free),
+        })
     }
 }
 
+/// Temp file in the same directory as `path`, so the final rename stays on one
+/// filesystem and is atomic.
+fn sibling_temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("file");
+    path.with_file_name(format!(".{}.synker-tmp-{}", name, Uuid::new_v4()))
+}
+
+#[cfg(unix)]
+fn link_count(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn link_count(_metadata: &Metadata) -> u64 {
+    1
+}
+
+#[cfg(unix)]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Metadata, _b: &Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn file_identity(metadata: &Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn file_identity(_metadata: &Metadata) -> (u64, u64) {
+    (0, 0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use tempfile::tempdir;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    /// Yields `data` up to `fail_at` bytes and then errors, like a dropped client
+    struct FailingReader {
+        data: Vec<u8>,
+        pos: usize,
+        fail_at: usize,
+    }
+
+    impl AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.pos >= self.fail_at {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionReset, "client went away")));
+            }
+
+            let end = (self.pos + buf.remaining()).min(self.fail_at);
+            buf.put_slice(&self.data[self.pos..end]);
+            self.pos = end;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Counts the bytes read through it
+    struct CountingReader<'a> {
+        data: &'a [u8],
+        read: Arc<AtomicU64>,
+    }
+
+    impl AsyncRead for CountingReader<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let len = buf.remaining().min(self.data.len());
+            buf.put_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            self.read.fetch_add(len as u64, Ordering::Relaxed);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_uploads_are_hashed_while_written() {
+        let temp_dir = tempdir().unwrap();
+        let fs_service = FileSystemService::new(temp_dir.path(), 1024 * 1024)
+            .unwrap()
+            .with_temp_directory(temp_dir.path().join("temp"));
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let expected = format!("{:x}", Sha256::digest(&data));
+
+        let read = Arc::new(AtomicU64::new(0));
+        let reader = CountingReader { data: &data, read: read.clone() };
+        let metadata = fs_service.save_file_from_reader("/single.bin", reader).await.unwrap();
+        assert_eq!(metadata.checksum, expected);
+        assert_eq!(read.load(Ordering::Relaxed), data.len() as u64);
+        assert_eq!(fs_service.checksum_reads(), 0);
+
+        // Chunks arriving in order are hashed from memory, and the saved state carries over
+        let session_id = Uuid::new_v4();
+        let chunk_size = 65_536;
+        let mut saved = ResumableSha256::new().to_bytes();
+        for (index, chunk) in data.chunks(chunk_size).enumerate() {
+            let offset = (index * chunk_size) as u64;
+            fs_service.write_session_chunk(session_id, offset, chunk).await.unwrap();
+            let mut hash = ResumableSha256::from_bytes(&saved).unwrap();
+            fs_service
+                .extend_session_hash(session_id, &mut hash, offset, chunk, offset + chunk.len() as u64)
+                .await
+                .unwrap();
+            saved = hash.to_bytes();
+        }
+
+        let hash = ResumableSha256::from_bytes(&saved);
+        let staged = fs_service.stage_upload_session(session_id, hash, Some(&expected)).await.unwrap();
+        assert_eq!(staged.checksum, expected);
+        assert_eq!(fs_service.checksum_reads(), 0);
+        staged.discard().await;
+
+        // A chunk arriving early is read back once the gap before it is filled
+        let session_id = Uuid::new_v4();
+        let (first, second) = data.split_at(chunk_size);
+        fs_service.write_session_chunk(session_id, chunk_size as u64, second).await.unwrap();
+        let mut hash = ResumableSha256::new();
+        fs_service
+            .extend_session_hash(session_id, &mut hash, chunk_size as u64, second, 0)
+            .await
+            .unwrap();
+        assert_eq!(hash.len(), 0);
+
+        fs_service.write_session_chunk(session_id, 0, first).await.unwrap();
+        fs_service
+            .extend_session_hash(session_id, &mut hash, 0, first, data.len() as u64)
+            .await
+            .unwrap();
+        assert_eq!(fs_service.checksum_reads(), 1);
+
+        let staged = fs_service.stage_upload_session(session_id, Some(hash), None).await.unwrap();
+        assert_eq!(staged.checksum, expected);
+        assert_eq!(fs_service.checksum_reads(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_uploads_store_their_checksum_and_clean_up() {
+        let temp_dir = tempdir().unwrap();
+        let staging = temp_dir.path().join("temp");
+        let fs_service = FileSystemService::new(temp_dir.path(), 100_000)
+            .unwrap()
+            .with_temp_directory(&staging);
+        let data: Vec<u8> = (0..90_000u32).map(|i| (i % 251) as u8).collect();
+        let staged_files = || std::fs::read_dir(&staging).unwrap().count();
+
+        let mut upload = fs_service.begin_upload().await.unwrap();
+        for chunk in data.chunks(7_000) {
+            upload.write_chunk(chunk).await.unwrap();
+        }
+        let staged = upload.finish().await.unwrap();
+        let metadata = fs_service.commit_upload("/streamed.bin", staged).await.unwrap();
+        assert_eq!(metadata.checksum, format!("{:x}", Sha256::digest(&data)));
+        assert_eq!(fs_service.read_file("/streamed.bin").await.unwrap(), data);
+        assert_eq!(staged_files(), 0);
+
+        // Going over max_file_size fails the chunk; the caller aborts
+        let mut upload = fs_service.begin_upload().await.unwrap();
+        upload.write_chunk(&data).await.unwrap();
+        let err = upload.write_chunk(&data).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<FileSystemError>(), Some(FileSystemError::FileTooLarge)));
+        upload.abort().await;
+        assert_eq!(staged_files(), 0);
+
+        // An upload abandoned halfway, as when the request is dropped
+        let mut upload = fs_service.begin_upload().await.unwrap();
+        upload.write_chunk(&data[..1_000]).await.unwrap();
+        assert_eq!(staged_files(), 1);
+        drop(upload);
+        assert_eq!(staged_files(), 0);
+    }
 
     #[tokio::test]
     async fn test_file_operations() {
@@ -331,4 +2138,330 @@ mod tests {
         let entries = fs_service.list_directory("/").await.unwrap();
         assert!(entries.len() >= 2); // test.txt and testdir
     }
+
+    #[tokio::test]
+    async fn test_failed_write_keeps_original_file() {
+        let temp_dir = tempdir().unwrap();
+        let fs_service = FileSystemService::new(temp_dir.path(), 1024 * 1024).unwrap();
+
+        let original = b"good version";
+        fs_service.save_file("/doc.txt", original).await.unwrap();
+
+        let reader = FailingReader {
+            data: vec![b'x'; 200 * 1024],
+            pos: 0,
+            fail_at: 100 * 1024,
+        };
+        assert!(fs_service.save_file_from_reader("/doc.txt", reader).await.is_err());
+
+        // The original is intact and no temp file was left behind
+        let read_data = fs_service.read_file("/doc.txt").await.unwrap();
+        assert_eq!(read_data, original);
+
+        let entries: Vec<_> = fs::read_dir(temp_dir.path()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_disk_space() {
+        let temp_dir = tempdir().unwrap();
+        let fs_service = FileSystemService::new(temp_dir.path(), 1024 * 1024).unwrap();
+
+        let space = fs_service.get_disk_space().unwrap();
+        assert!(space.total > 0);
+        assert!(space.available <= space.total);
+        assert!(space.used <= space.total);
+    }
+
+    #[tokio::test]
+    async fn test_listing_does_not_reread_files() {
+        let temp_dir = tempdir().unwrap();
+        let fs_service = FileSystemService::new(temp_dir.path(), 1024 * 1024).unwrap();
+
+        for i in 0..1000 {
+            fs::write(temp_dir.path().join(format!("photo-{:04}.jpg", i)), b"not really a jpeg").unwrap();
+        }
+
+        // The fast listing never touches file contents
+        let entries = fs_service.list_directory_fast("/").await.unwrap();
+        assert_eq!(entries.len(), 1000);
+        assert_eq!(fs_service.checksum_reads(), 0);
+
+        // A full listing hashes each file once, then serves checksums from the cache
+        fs_service.list_directory("/").await.unwrap();
+        assert_eq!(fs_service.checksum_reads(), 1000);
+
+        let entries = fs_service.list_directory("/").await.unwrap();
+        assert_eq!(fs_service.checksum_reads(), 1000);
+        assert!(entries.iter().all(|e| !e.checksum.is_empty()));
+    }
+
+    #[test]
+    fn test_extension_allowlist() {
+        let temp_dir = tempdir().unwrap();
+        let fs_service = FileSystemService::new(temp_dir.path(), 1024).unwrap();
+
+        // No allowlist means anything goes
+        assert!(fs_service.check_extension("/setup.exe").is_ok());
+        assert!(fs_service.check_extension("/Makefile").is_ok());
+
+        let fs_service = fs_service.with_allowed_extensions(vec!["jpg".to_string(), "PDF".to_string()], false);
+        assert!(fs_service.check_extension("/photos/IMG_001.JPG").is_ok());
+        assert!(fs_service.check_extension("/docs/report.pdf").is_ok());
+        assert!(matches!(
+            fs_service.check_extension("/setup.exe"),
+            Err(FileSystemError::ExtensionNotAllowed(ext)) if ext == "exe"
+        ));
+        assert!(matches!(fs_service.check_extension("/Makefile"), Err(FileSystemError::MissingExtension)));
+
+        let fs_service = fs_service.with_allowed_extensions(vec!["jpg".to_string()], true);
+        assert!(fs_service.check_extension("/Makefile").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_paths_are_normalized_to_nfc() {
+        let temp_dir = tempdir().unwrap();
+        let fs_service = FileSystemService::new(temp_dir.path(), 1024).unwrap();
+
+        let nfd = "/cafe\u{301}.txt";
+        let nfc = "/caf\u{e9}.txt";
+        fs_service.save_file(nfd, b"latte").await.unwrap();
+
+        assert_eq!(fs_service.get_absolute_path(nfd), fs_service.get_absolute_path(nfc));
+        let listing = fs_service.list_directory("/").await.unwrap();
+        assert_eq!(listing.len(), 1);
+        assert_eq!(listing[0].path, nfc);
+        assert_eq!(listing[0].name, "caf\u{e9}.txt");
+
+        assert!(fs_service.check_file_name("/docs/report.txt").is_ok());
+        for bad in ["/docs./a.txt", "/a.txt ", "/tab\there.txt"] {
+            assert!(matches!(fs_service.check_file_name(bad), Err(FileSystemError::InvalidFileName(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_tree() {
+        let temp_dir = tempdir().unwrap();
+        let fs_service = FileSystemService::new(temp_dir.path(), 1024).unwrap();
+
+        fs_service.save_file("/src/a.txt", b"aaaa").await.unwrap();
+        fs_service.save_file("/src/nested/b.txt", b"bbbb").await.unwrap();
+
+        let copied = fs_service.copy_tree("/src", "/dst").await.unwrap();
+        let paths: Vec<&str> = copied.iter().map(|m| m.path.as_str()).collect();
+        assert_eq!(paths, vec!["/dst", "/dst/a.txt", "/dst/nested", "/dst/nested/b.txt"]);
+        assert_eq!(fs_service.read_file("/dst/nested/b.txt").await.unwrap(), b"bbbb");
+        assert!(copied.iter().all(|m| m.is_directory || !m.checksum.is_empty()));
+
+        // Refuses to overwrite and leaves the existing copy alone
+        assert!(fs_service.copy_tree("/src", "/dst").await.is_err());
+        assert!(fs_service.get_absolute_path("/dst/a.txt").exists());
+
+        let limited = fs_service.with_max_copy_size(6);
+        let err = limited.copy_tree("/src", "/too-big").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FileSystemError>(),
+            Some(FileSystemError::CopyTooLarge { limit: 6 })
+        ));
+        assert!(!limited.get_absolute_path("/too-big").exists());
+    }
+
+    #[tokio::test]
+    async fn test_ignored_entries_are_hidden() {
+        let temp_dir = tempdir().unwrap();
+        let fs_service = FileSystemService::new(temp_dir.path(), 1024)
+            .unwrap()
+            .with_ignore_patterns(vec!["node_modules".to_string(), "*.tmp".to_string()])
+            .unwrap();
+
+        fs_service.save_file("/app/index.js", b"main").await.unwrap();
+        fs_service.save_file("/app/node_modules/dep/lib.js", b"dependency").await.unwrap();
+        fs_service.save_file("/app/scratch.tmp", b"scratch").await.unwrap();
+        fs_service.save_file("/app/keep.tmp", b"keep").await.unwrap();
+        fs_service.save_file("/app/build/out.js", b"output").await.unwrap();
+        fs_service.save_file("/app/.synkerignore", b"build/\n!keep.tmp\n").await.unwrap();
+
+        let names: Vec<String> = fs_service.list_directory("/app").await.unwrap()
+            .into_iter()
+            .map(|m| m.name)
+            .collect();
+        assert_eq!(names, vec![".synkerignore", "index.js", "keep.tmp"]);
+
+        // index.js, keep.tmp and the ignore file itself
+        let rules_size = "build/\n!keep.tmp\n".len() as u64;
+        assert_eq!(fs_service.get_directory_size("/app").await.unwrap(), 8 + rules_size);
+
+        assert!(fs_service.is_ignored("/app/node_modules/dep/lib.js", false));
+        assert!(matches!(fs_service.check_ignored("/app/build/new.js"), Err(FileSystemError::Ignored(_))));
+        assert!(fs_service.check_ignored("/app/src/new.js").is_ok());
+
+        let everything = fs_service.including_ignored();
+        assert_eq!(everything.list_directory("/app").await.unwrap().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_mounts_serve_top_level_folders() {
+        let temp_dir = tempdir().unwrap();
+        let mount_dir = tempdir().unwrap();
+        let fs_service = FileSystemService::new(temp_dir.path(), 1024)
+            .unwrap()
+            .with_mounts(vec![("media".to_string(), mount_dir.path().to_path_buf())])
+            .unwrap();
+
+        fs_service.save_file("/media/song.mp3", b"tune").await.unwrap();
+        assert!(mount_dir.path().join("song.mp3").exists());
+        assert!(!temp_dir.path().join("media").exists());
+        assert_eq!(fs_service.get_relative_path(&mount_dir.path().join("song.mp3")).unwrap(), "/media/song.mp3");
+
+        let root: Vec<String> = fs_service.list_directory("/").await.unwrap()
+            .into_iter()
+            .map(|m| m.path)
+            .collect();
+        assert!(root.contains(&"/media".to_string()));
+
+        fs_service.move_file("/media/song.mp3", "/song.mp3").await.unwrap();
+        assert!(temp_dir.path().join("song.mp3").exists());
+        assert!(!mount_dir.path().join("song.mp3").exists());
+
+        assert!(fs_service.resolve_path("/media/../song.mp3").is_err());
+        assert!(FileSystemService::new(temp_dir.path(), 1024)
+            .unwrap()
+            .with_mounts(vec![(".trash".to_string(), mount_dir.path().to_path_buf())])
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_deduplicated_content_is_stored_once() {
+        let temp_dir = tempdir().unwrap();
+        let fs_service = FileSystemService::new(temp_dir.path(), 1024)
+            .unwrap()
+            .with_deduplication(true);
+
+        let first = fs_service.save_file("/a/setup.bin", b"installer").await.unwrap();
+        let second = fs_service.save_file("/b/setup.bin", b"installer").await.unwrap();
+        assert_eq!(first.checksum, second.checksum);
+
+        let object = fs_service.object_path(&first.checksum);
+        assert_eq!(link_count(&fs::metadata(&object).unwrap()), 3);
+
+        let usage = fs_service.get_storage_usage().await.unwrap();
+        assert_eq!(usage.logical, 18);
+        assert_eq!(usage.physical, 9);
+
+        // The object outlives the first path and goes with the last
+        fs_service.delete_file("/a/setup.bin").await.unwrap();
+        assert!(object.exists());
+        assert_eq!(fs_service.read_file("/b/setup.bin").await.unwrap(), b"installer");
+
+        fs_service.delete_file("/b").await.unwrap();
+        assert!(!object.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlink_policy() {
+        let temp_dir = tempdir().unwrap();
+        let outside_dir = tempdir().unwrap();
+        let base = temp_dir.path().join("storage");
+        let fs_service = FileSystemService::new(&base, 1024).unwrap();
+
+        fs_service.save_file("/docs/inside.txt", b"inside").await.unwrap();
+        fs::write(outside_dir.path().join("secret.txt"), b"secret").unwrap();
+        std::os::unix::fs::symlink(base.join("docs"), base.join("docs-link")).unwrap();
+        std::os::unix::fs::symlink(outside_dir.path(), base.join("escape")).unwrap();
+
+        // Skip: links are invisible
+        let listing = fs_service.list_directory_fast("/").await.unwrap();
+        assert_eq!(listing.len(), 1);
+        assert!(fs_service.read_file("/docs-link/inside.txt").await.is_err());
+
+        // Deny: touching a link is an error
+        let deny = fs_service.clone().with_symlink_policy(SymlinkPolicy::Deny);
+        let err = deny.read_file("/escape/secret.txt").await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<FileSystemError>(), Some(FileSystemError::SymlinkDenied(_))));
+        assert!(deny.list_directory_fast("/").await.is_err());
+
+        // Follow within base: the internal link works, the escaping one doesn't
+        let follow = fs_service.with_symlink_policy(SymlinkPolicy::FollowWithinBase);
+        assert_eq!(follow.read_file("/docs-link/inside.txt").await.unwrap(), b"inside");
+        let err = follow.read_file("/escape/secret.txt").await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<FileSystemError>(), Some(FileSystemError::SymlinkOutsideBase(_))));
+
+        let listing = follow.list_directory_fast("/").await.unwrap();
+        let names: Vec<(&str, bool)> = listing.iter().map(|m| (m.name.as_str(), m.is_symlink)).collect();
+        assert_eq!(names, vec![("docs", false), ("docs-link", true)]);
+    }
+
+    #[tokio::test]
+    async fn test_move_falls_back_to_copy_across_devices() {
+        let temp_dir = tempdir().unwrap();
+        let mut fs_service = FileSystemService::new(temp_dir.path(), 1024 * 1024).unwrap();
+        fs_service.simulated_cross_device = Some(usize::MAX);
+
+        fs_service.save_file("/photos/a.jpg", b"first").await.unwrap();
+        fs_service.save_file("/photos/trip/b.jpg", b"second").await.unwrap();
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs::File::options()
+            .write(true)
+            .open(fs_service.get_absolute_path("/photos/a.jpg"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let before = fs_service.get_file_metadata("/photos/a.jpg").await.unwrap();
+
+        fs_service.move_file("/photos", "/archive/photos").await.unwrap();
+        assert!(!fs_service.get_absolute_path("/photos").exists());
+        assert_eq!(fs_service.read_file("/archive/photos/trip/b.jpg").await.unwrap(), b"second");
+        let after = fs_service.get_file_metadata("/archive/photos/a.jpg").await.unwrap();
+        assert_eq!(after.modified_at, before.modified_at);
+        assert_eq!(after.checksum, before.checksum);
+
+        fs_service.move_file("/archive/photos/a.jpg", "/a.jpg").await.unwrap();
+        assert_eq!(fs_service.read_file("/a.jpg").await.unwrap(), b"first");
+
+        // A copy failing partway leaves the source alone and nothing at the destination
+        fs_service.simulated_cross_device = Some(1);
+        fs_service.save_file("/docs/one.txt", b"one").await.unwrap();
+        fs_service.save_file("/docs/two.txt", b"two").await.unwrap();
+        fs_service.save_file("/elsewhere/keep.txt", b"keep").await.unwrap();
+
+        assert!(fs_service.move_file("/docs", "/elsewhere/docs").await.is_err());
+        assert_eq!(fs_service.read_file("/docs/one.txt").await.unwrap(), b"one");
+        assert_eq!(fs_service.read_file("/docs/two.txt").await.unwrap(), b"two");
+        let left: Vec<_> = fs::read_dir(fs_service.get_absolute_path("/elsewhere"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(left, vec!["keep.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_user_homes_are_isolated() {
+        let temp_dir = tempdir().unwrap();
+        let flat = FileSystemService::new(temp_dir.path(), 1024).unwrap();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // A flat deployment, with a trashed entry of bob's
+        flat.save_file("/docs/report.txt", b"report").await.unwrap();
+        flat.save_file(&FileSystemService::trash_location(bob, Uuid::new_v4()), b"old").await.unwrap();
+
+        let fs_service = flat.with_user_homes(true);
+        assert!(fs_service.has_flat_content().unwrap());
+        assert_eq!(fs_service.migrate_to_user_homes(alice).await.unwrap(), 2);
+        assert!(!fs_service.has_flat_content().unwrap());
+
+        let alice_home = fs_service.for_user(alice).unwrap();
+        let bob_home = fs_service.for_user(bob).unwrap();
+        assert_eq!(alice_home.read_file("/docs/report.txt").await.unwrap(), b"report");
+        assert!(bob_home.read_file("/docs/report.txt").await.is_err());
+        assert_eq!(bob_home.list_directory_fast("/").await.unwrap().len(), 0);
+        assert!(bob_home.get_absolute_path(&format!("/.trash/{}", bob)).is_dir());
+
+        // The same path in two homes is two files
+        bob_home.save_file("/docs/report.txt", b"bob's").await.unwrap();
+        assert_eq!(alice_home.read_file("/docs/report.txt").await.unwrap(), b"report");
+        assert!(bob_home.read_file(&format!("/../{}/docs/report.txt", alice)).await.is_err());
+    }
 }
@@ -1,315 +1,12588 @@
 use axum::{
-    extract::{Path, Query, State, Multipart},
+    extract::{ConnectInfo, Path, Query, State, Multipart},
     http::{StatusCode, HeaderMap, header},
-    response::{Response, Json},
+    response::{IntoResponse, Redirect, Response, Json},
     Extension,
 };
 use serde_json::json;
+use futures_util::{StreamExt, TryStreamExt};
 use uuid::Uuid;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::Arc;
 use chrono::Utc;
 use anyhow::Result;
+use lettre::message::Mailbox;
 
 use crate::types::*;
-use crate::auth::{Claims, AuthService};
-use crate::database::Database;
-use crate::filesystem::FileSystemService;
+use crate::auth::{AnonymousAccess, Claims, AuthService, TOKEN_COOKIE};
+use crate::two_factor::{self, TwoFactorService};
+use crate::rate_limit::LoginLimiter;
+use crate::database::{Database, DatabaseTransaction, DeletedFiles, MetadataWrite, is_pool_timeout};
+use crate::filesystem::{FileSystemService, FileSystemError, UploadWriter, is_within, normalize_path, parent_path, path_components};
+use crate::storage::{StagedUpload, StorageBackend, stored_size};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use crate::thumbnails::{ThumbnailService, ThumbnailError};
+use crate::hashing::ResumableSha256;
+use crate::delta::{DeltaCommand, SignatureBuilder, parse_delta};
+use crate::integrity::IntegrityScanner;
+use crate::reconcile::Reconciler;
+use crate::mycloud::{user_from_mycloud, MyCloudApi};
+use crate::oidc::{OidcIdentity, OidcService};
+use crate::share_tokens::{self, ShareTokens};
+use crate::audit::{self, ShareAccessLog, ShareVisit};
+use crate::notifications::{self, Mailer};
+use crate::config::SyncSettings;
+use crate::webhooks::Webhooks;
+use crate::bandwidth::Bandwidth;
+use crate::stats::{DownloadCounter, StatsCache};
+use crate::backup::Backups;
+use crate::scheduler::Scheduler;
+use crate::sync::planner::{self, ServerChange};
+use crate::activity::ActivityGrouper;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// Error for handlers that need to pair a status code with an explanation.
+/// A bare `StatusCode` converts into one with an empty body, so `?` on
+/// `Result<_, StatusCode>` keeps working.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: Option<String>,
+    data: Option<serde_json::Value>,
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: Some(message.into()),
+            data: None,
+            retry_after: None,
+        }
+    }
+
+    /// Tell the client how many seconds to wait before trying again
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    /// Attach structured details, returned in the `data` field of the error body
+    pub fn with_data(mut self, data: impl serde::Serialize) -> Self {
+        self.data = serde_json::to_value(data).ok();
+        self
+    }
+
+    /// Explanation for the client, falling back to the status reason
+    pub fn message(&self) -> String {
+        self.message.clone().unwrap_or_else(|| {
+            self.status.canonical_reason().unwrap_or("Request failed").to_string()
+        })
+    }
+}
+
+/// 403 naming the permission the caller lacks, in the `data` field
+pub fn missing_permission(permission: Permission) -> ApiError {
+    ApiError::new(
+        StatusCode::FORBIDDEN,
+        format!("This requires the '{}' permission", permission.as_str()),
+    )
+    .with_data(serde_json::json!({ "missing_permission": permission }))
+}
+
+/// 400 for a password the policy rejects, listing every rule it breaks in
+/// the `data` field
+pub fn password_rejected(violations: Vec<PolicyViolation>) -> ApiError {
+    let message = violations.iter().map(PolicyViolation::message).collect::<Vec<_>>().join("; ");
+    ApiError::new(StatusCode::BAD_REQUEST, message)
+        .with_data(serde_json::json!({ "violations": violations }))
+}
+
+/// Seconds a client is asked to wait when every database connection is busy
+const DATABASE_BUSY_RETRY_SECONDS: u64 = 5;
+
+/// What a failed database call answers with: 503 when it gave up waiting
+/// for a connection or its turn to write, which a busy server worth
+/// retrying causes rather than a failure, and 500 otherwise
+fn database_error(error: anyhow::Error) -> StatusCode {
+    if is_pool_timeout(&error) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl From<StatusCode> for ApiError {
+    fn from(status: StatusCode) -> Self {
+        // Handlers only answer a bare 503 when the database is too busy
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            return ApiError::new(status, "The server is too busy to reach its database; try again shortly")
+                .with_retry_after(DATABASE_BUSY_RETRY_SECONDS);
+        }
+        Self {
+            status,
+            message: None,
+            data: None,
+            retry_after: None,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = match self.message {
+            Some(message) => {
+                let mut body = ApiResponse::<serde_json::Value>::error(message);
+                body.data = self.data;
+                (self.status, Json(body)).into_response()
+            }
+            None => self.status.into_response(),
+        };
+
+        if let Some(seconds) = self.retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, seconds.into());
+        }
+        response
+    }
+}
 
 pub async fn login(
     State(auth_service): State<AuthService>,
+    State(two_factor_service): State<TwoFactorService>,
+    State(login_limiter): State<LoginLimiter>,
     State(database): State<Database>,
+    State(mycloud): State<Arc<dyn MyCloudApi>>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(request): Json<LoginRequest>,
-) -> Result<Json<ApiResponse<LoginResponse>>, StatusCode> {
+) -> Result<Json<ApiResponse<LoginResponse>>, ApiError> {
+    let ip = remote.ip().to_string();
+
     // Get user from database
     let user = match database.get_user_by_username(&request.username).await {
-        Ok(Some(user)) => user,
-        Ok(None) => {
-            return Ok(Json(ApiResponse::error("Invalid credentials".to_string())));
-        }
+        Ok(user) => user,
         Err(_) => {
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        }
+    };
+    let two_factor = match &user {
+        Some(user) => database.get_two_factor(user.id).await
+            .map_err(database_error)?,
+        None => TwoFactorState { secret: None, enabled: false },
+    };
+
+    // Someone who also holds the authenticator gets past the username's
+    // limit and lock, so bad guesses from elsewhere can't lock its owner out
+    let exempt = match (&user, &two_factor.secret, &request.totp_code) {
+        (Some(user), Some(secret), Some(code)) if two_factor.enabled && two_factor::is_totp_code(code) => {
+            two_factor_service.verify_code(secret, &user.username, code).unwrap_or(false)
         }
+        _ => false,
     };
 
+    let limited_username = (!exempt).then_some(request.username.as_str());
+    if let Err(retry_after) = login_limiter.check(&ip, limited_username) {
+        record_login_attempt(&database, &request.username, &ip, &headers, "rate_limited").await;
+        return Err(ApiError::new(StatusCode::TOO_MANY_REQUESTS, "Too many login attempts")
+            .with_retry_after(retry_after));
+    }
+
+    let (failures, last_failure) = database
+        .get_consecutive_login_failures(&request.username, login_limiter.failures_since())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(locked_until) = login_limiter.locked_until(failures, last_failure) {
+        if !exempt {
+            record_login_attempt(&database, &request.username, &ip, &headers, "locked").await;
+            let retry_after = (locked_until - Utc::now()).num_seconds().max(1) as u64;
+            return Err(ApiError::new(StatusCode::TOO_MANY_REQUESTS, "Too many failed logins; try again later")
+                .with_retry_after(retry_after));
+        }
+    }
+
     // Verify password
-    if !auth_service.verify_password(&request.password, &user.password_hash)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
-        return Ok(Json(ApiResponse::error("Invalid credentials".to_string())));
+    let password_matches = match &user {
+        Some(user) => auth_service.verify_password(&request.password, &user.password_hash)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        None => false,
+    };
+    let user = if password_matches {
+        user
+    } else {
+        mycloud_login(mycloud.as_ref(), &auth_service, &database, user, &request.username, &request.password).await?
+    };
+
+    let mut user = match user {
+        Some(user) => user,
+        None => {
+            record_login_attempt(&database, &request.username, &ip, &headers, "failure").await;
+            return Ok(Json(ApiResponse::error("Invalid credentials".to_string())));
+        }
+    };
+
+    if !user.is_active {
+        record_login_attempt(&database, &request.username, &ip, &headers, "failure").await;
+        return Ok(Json(ApiResponse::error("Account is disabled".to_string())));
+    }
+
+    if two_factor.enabled {
+        if let Err(err) = check_second_factor(&two_factor_service, &database, &user, &two_factor, request.totp_code.as_deref()).await {
+            // Only a wrong code counts; being asked for one is not a failure
+            if request.totp_code.is_some() {
+                record_login_attempt(&database, &request.username, &ip, &headers, "failure").await;
+            }
+            return Err(err);
+        }
+    }
+
+    record_login_attempt(&database, &request.username, &ip, &headers, "success").await;
+
+    // The password is at hand only now, so this is when an old hash can be replaced
+    if auth_service.needs_rehash(&user.password_hash) {
+        match auth_service.hash_password(&request.password) {
+            Ok(new_hash) => match database.rehash_password(user.id, &user.password_hash, &new_hash).await {
+                Ok(true) => user.password_hash = new_hash,
+                // Changed in the meantime; nothing left to upgrade
+                Ok(false) => {}
+                Err(e) => tracing::warn!("Failed to store new password hash for {}: {}", user.username, e),
+            },
+            Err(e) => tracing::warn!("Failed to re-hash password of {}: {}", user.username, e),
+        }
+    }
+
+    if let Some(device_id) = &request.device_id {
+        let now = Utc::now();
+        let device = Device {
+            user_id: user.id,
+            device_id: device_id.clone(),
+            name: request.device_name.clone(),
+            client_version: request.client_version.clone(),
+            ip_address: Some(ip.clone()),
+            created_at: now,
+            last_seen_at: now,
+            revoked_at: None,
+        };
+        database.upsert_device(&device).await
+            .map_err(database_error)?;
     }
 
+    let response = start_session(&auth_service, &database, user, request.device_id, ip, &headers).await?;
+    Ok(Json(ApiResponse::success(response)))
+}
+
+/// Issue the tokens for a login that has passed its checks and record the
+/// session they belong to
+async fn start_session(
+    auth_service: &AuthService,
+    database: &Database,
+    user: User,
+    device_id: Option<String>,
+    ip: String,
+    headers: &HeaderMap,
+) -> Result<LoginResponse, StatusCode> {
     // Update last login
     if let Err(_) = database.update_last_login(user.id, Utc::now()).await {
         // Log error but don't fail the login
     }
 
     // Generate JWT token
-    let token = auth_service.generate_token(&user, request.device_id)
+    let (token, claims) = auth_service.issue_token(&user, device_id.clone())
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let expires_at = chrono::DateTime::from_timestamp(claims.exp, 0)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let response = LoginResponse {
-        token: token.clone(),
-        user: user.clone(),
-        expires_at: Utc::now() + chrono::Duration::hours(24),
+    let (refresh_token, mut refresh_row) = new_refresh_token(auth_service, user.id, device_id.clone());
+    let now = Utc::now();
+    let session = Session {
+        id: Uuid::new_v4(),
+        user_id: user.id,
+        device_id,
+        user_agent: headers.get(header::USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .map(str::to_string),
+        ip_address: Some(ip),
+        jti: claims.jti,
+        token_expires_at: expires_at,
+        created_at: now,
+        last_active_at: now,
+        expires_at: refresh_row.expires_at,
+        current: false,
     };
+    database.create_session(&session).await
+        .map_err(database_error)?;
+    refresh_row.session_id = Some(session.id);
+    database.create_refresh_token(&refresh_row).await
+        .map_err(database_error)?;
 
-    Ok(Json(ApiResponse::success(response)))
+    let must_change_password = database.must_change_password(user.id).await
+        .map_err(database_error)?;
+
+    Ok(LoginResponse {
+        token,
+        user,
+        expires_at,
+        refresh_token,
+        refresh_expires_at: refresh_row.expires_at,
+        must_change_password,
+    })
 }
 
-pub async fn upload_file(
-    State(filesystem): State<FileSystemService>,
+/// Start logging in through the OpenID Connect provider, by sending the
+/// browser there
+pub async fn oidc_login(
+    State(oidc): State<Option<OidcService>>,
+) -> Result<Redirect, ApiError> {
+    let oidc = oidc.ok_or(StatusCode::NOT_FOUND)?;
+
+    let url = oidc.authorize_url().await.map_err(|e| {
+        tracing::error!("Failed to start OIDC login: {}", e);
+        ApiError::new(StatusCode::BAD_GATEWAY, "Identity provider is unavailable")
+    })?;
+
+    Ok(Redirect::to(&url))
+}
+
+/// Where the provider sends the browser back. Answers like password login,
+/// or sets the token as a cookie and redirects if `post_login_redirect` is set.
+pub async fn oidc_callback(
+    State(auth_service): State<AuthService>,
+    State(oidc): State<Option<OidcService>>,
     State(database): State<Database>,
-    Extension(claims): Extension<Claims>,
-    Query(params): Query<HashMap<String, String>>,
-    mut multipart: Multipart,
-) -> Result<Json<ApiResponse<UploadResponse>>, StatusCode> {
-    let path = params.get("path").unwrap_or(&"/".to_string()).clone();
-    let overwrite = params.get("overwrite")
-        .and_then(|s| s.parse::<bool>().ok())
-        .unwrap_or(false);
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<OidcCallbackQuery>,
+) -> Result<Response, ApiError> {
+    let oidc = oidc.ok_or(StatusCode::NOT_FOUND)?;
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap_or("file").to_string();
-        let filename = field.file_name().unwrap_or("unnamed").to_string();
-        let data = field.bytes().await.unwrap();
+    let (state, code) = match (query.state, query.code) {
+        (Some(state), Some(code)) => (state, code),
+        _ => {
+            let reason = query.error.unwrap_or_else(|| "missing code".to_string());
+            return Err(ApiError::new(StatusCode::UNAUTHORIZED, format!("Login was not completed: {}", reason)));
+        }
+    };
 
-        let file_path = if path.ends_with('/') {
-            format!("{}{}", path, filename)
-        } else {
-            format!("{}/{}", path, filename)
-        };
+    let identity = oidc.complete_login(&state, &code).await.map_err(|e| {
+        tracing::warn!("OIDC login failed: {}", e);
+        ApiError::new(StatusCode::UNAUTHORIZED, "Login could not be verified")
+    })?;
 
-        // Check if file exists and overwrite is not allowed
-        if !overwrite {
-            if let Ok(_) = filesystem.get_file_metadata(&file_path).await {
-                return Ok(Json(ApiResponse::error("File already exists".to_string())));
-            }
+    let ip = remote.ip().to_string();
+    let user = oidc_user(&oidc, &auth_service, &database, &identity).await?;
+    if !user.is_active {
+        record_login_attempt(&database, &user.username, &ip, &headers, "failure").await;
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "Account is disabled"));
+    }
+    record_login_attempt(&database, &user.username, &ip, &headers, "success").await;
+
+    let response = start_session(&auth_service, &database, user, None, ip, &headers).await?;
+
+    match &oidc.settings().post_login_redirect {
+        Some(target) => {
+            let max_age = (response.expires_at - Utc::now()).num_seconds().max(0);
+            let cookie = format!(
+                "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+                TOKEN_COOKIE, response.token, max_age
+            );
+            Ok(([(header::SET_COOKIE, cookie)], Redirect::to(target)).into_response())
         }
+        None => Ok(Json(ApiResponse::success(response)).into_response()),
+    }
+}
 
-        // Save file to filesystem
-        let mut metadata = filesystem.save_file(&file_path, &data).await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+/// The local user an OIDC login is for: the one its identity is linked to,
+/// else the only one with the same verified email, who gets linked if
+/// `link_by_email` allows, else a new one if `auto_provision` allows. Users
+/// created this way take their permissions from their groups at every login.
+/// Admins and users with two-factor authentication are never linked by
+/// email, since the provider's login would then stand in for their second
+/// factor.
+async fn oidc_user(
+    oidc: &OidcService,
+    auth_service: &AuthService,
+    database: &Database,
+    identity: &OidcIdentity,
+) -> Result<User, ApiError> {
+    let permissions: Vec<String> = oidc.permissions_for(&identity.groups)
+        .iter()
+        .map(|permission| permission.as_str().to_string())
+        .collect();
 
-        // Update owner ID
-        let user_id = Uuid::parse_str(&claims.sub)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        metadata.owner_id = user_id;
+    let linked = database.get_oidc_identity(&identity.issuer, &identity.subject).await
+        .map_err(database_error)?;
+    if let Some((user_id, provisioned)) = linked {
+        let mut user = database.get_user_by_id(user_id).await
+            .map_err(database_error)?
+            .ok_or(StatusCode::UNAUTHORIZED)?;
 
-        // Save metadata to database
-        database.create_file_metadata(&metadata).await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if provisioned && user.permissions != permissions {
+            let update = UpdateUserRequest {
+                permissions: Some(oidc.permissions_for(&identity.groups)),
+                ..Default::default()
+            };
+            database.update_user(user.id, &update).await
+                .map_err(database_error)?;
+            user.permissions = permissions;
+        }
+        return Ok(user);
+    }
 
-        let response = UploadResponse {
-            file_id: metadata.id,
-            path: metadata.path,
-            size: metadata.size,
-            checksum: metadata.checksum,
-        };
+    // An unverified address could be anyone's
+    if let (Some(email), true, true) = (&identity.email, identity.email_verified, oidc.settings().link_by_email) {
+        let matches = database.get_user_ids_by_email(email).await
+            .map_err(database_error)?;
+        if let [user_id] = matches[..] {
+            let user = database.get_user_by_id(user_id).await
+                .map_err(database_error)?
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+            let two_factor = database.get_two_factor(user_id).await
+                .map_err(database_error)?;
 
-        return Ok(Json(ApiResponse::success(response)));
+            if user.has_permission(Permission::Admin) || two_factor.enabled {
+                tracing::warn!("Not linking an OIDC login to {} by email; link it by hand", user.username);
+                return Err(ApiError::new(StatusCode::FORBIDDEN, "This account can't be linked by email"));
+            }
+
+            database.link_oidc_identity(&identity.issuer, &identity.subject, user_id, Utc::now()).await
+                .map_err(database_error)?;
+            return Ok(user);
+        }
     }
 
-    Ok(Json(ApiResponse::error("No file uploaded".to_string())))
-}
+    if !oidc.settings().auto_provision {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "No user here matches this login"));
+    }
 
-pub async fn download_file(
-    State(filesystem): State<FileSystemService>,
-    State(database): State<Database>,
-    Extension(claims): Extension<Claims>,
-    Path(file_path): Path<String>,
-) -> Result<Response, StatusCode> {
-    let user_id = Uuid::parse_str(&claims.sub)
+    let username = identity.preferred_username.clone()
+        .or_else(|| identity.email.as_ref().and_then(|email| email.split('@').next()).map(str::to_string))
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| identity.subject.clone());
+    let existing = database.get_user_by_username(&username).await
+        .map_err(database_error)?;
+    if existing.is_some() {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!("User '{}' already exists", username)));
+    }
+
+    // Never told to anyone: these users log in through the provider
+    let password_hash = auth_service.hash_password(&Uuid::new_v4().to_string())
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = User {
+        id: Uuid::new_v4(),
+        username,
+        email: identity.email.clone(),
+        password_hash,
+        created_at: Utc::now(),
+        last_login: None,
+        is_active: true,
+        auth_generation: 0,
+        permissions,
+    };
+    database.create_oidc_user(&user, &identity.issuer, &identity.subject).await
+        .map_err(database_error)?;
+    tracing::info!("Created user {} on their first OIDC login", user.username);
 
-    // Decode the file path (it might be URL encoded)
-    let file_path = urlencoding::decode(&file_path)
-        .map_err(|_| StatusCode::BAD_REQUEST)?
-        .into_owned();
+    Ok(user)
+}
 
-    // Check if user has access to the file
-    // This is a simplified check - in production you'd want more granular permissions
-    let file_data = filesystem.read_file(&file_path).await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
+/// Check credentials the local database turned down against MyCloud, if the
+/// fallback is on. Someone unknown here is created from their MyCloud
+/// account, and a MyCloud user whose password changed on the NAS gets the
+/// new one's hash. Either way the password then also works while MyCloud is
+/// unreachable.
+async fn mycloud_login(
+    mycloud: &dyn MyCloudApi,
+    auth_service: &AuthService,
+    database: &Database,
+    existing: Option<User>,
+    username: &str,
+    password: &str,
+) -> Result<Option<User>, StatusCode> {
+    if !mycloud.auth_fallback() {
+        return Ok(None);
+    }
 
-    let file_metadata = filesystem.get_file_metadata(&file_path).await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
+    // Local accounts never defer to MyCloud, so a NAS account of the same
+    // name can't take one over
+    if let Some(user) = &existing {
+        if !database.is_mycloud_user(user.id).await.map_err(database_error)? {
+            return Ok(None);
+        }
+    }
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        header::CONTENT_TYPE,
-        file_metadata.mime_type.parse().unwrap(),
-    );
-    headers.insert(
-        header::CONTENT_DISPOSITION,
-        format!("attachment; filename=\"{}\"", file_metadata.name).parse().unwrap(),
-    );
+    let mycloud_user = match mycloud.verify_user_credentials(username, password).await {
+        Ok(Some(mycloud_user)) => mycloud_user,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            tracing::warn!("Failed to check login of {} with MyCloud: {}", username, e);
+            return Ok(None);
+        }
+    };
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .headers(headers)
-        .body(axum::body::Body::from(file_data))
-        .unwrap())
+    let password_hash = auth_service.hash_password(password)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    match existing {
+        Some(mut user) => {
+            database.rehash_password(user.id, &user.password_hash, &password_hash).await
+                .map_err(database_error)?;
+            user.password_hash = password_hash;
+            Ok(Some(user))
+        }
+        None => {
+            let mut user = user_from_mycloud(&mycloud_user, &password_hash);
+            // Found by the name it logged in with next time
+            user.username = username.to_string();
+            database.create_mycloud_user(&user).await
+                .map_err(database_error)?;
+            tracing::info!("Created user {} from their MyCloud account", username);
+            Ok(Some(user))
+        }
+    }
 }
 
-pub async fn list_files(
-    State(filesystem): State<FileSystemService>,
-    State(database): State<Database>,
-    Extension(claims): Extension<Claims>,
-    Query(params): Query<HashMap<String, String>>,
-) -> Result<Json<ApiResponse<Vec<FileMetadata>>>, StatusCode> {
-    let path = params.get("path").unwrap_or(&"/".to_string()).clone();
-    let user_id = Uuid::parse_str(&claims.sub)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+/// Keep a login attempt for lockouts and in the audit log. A failure to
+/// record one must not decide the login.
+async fn record_login_attempt(database: &Database, username: &str, ip: &str, headers: &HeaderMap, outcome: &str) {
+    let attempt = LoginAttempt {
+        id: Uuid::new_v4(),
+        username: username.to_string(),
+        ip_address: ip.to_string(),
+        outcome: outcome.to_string(),
+        attempted_at: Utc::now(),
+    };
 
-    let files = filesystem.list_directory(&path).await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
+    if let Err(e) = database.record_login_attempt(&attempt).await {
+        tracing::warn!("Failed to record login attempt for {}: {}", username, e);
+    }
 
-    // Filter files by user ownership (simplified - you might want more complex permissions)
-    let user_files: Vec<FileMetadata> = files.into_iter()
-        .map(|mut file| {
-            file.owner_id = user_id; // Set correct owner
-            file
-        })
-        .collect();
+    // Locked and rate limited logins never had their password checked
+    let outcome = match outcome {
+        "success" | "failure" => outcome,
+        _ => "denied",
+    };
+    let entry = AuditEntry::new(AuditEvent::Login, outcome)
+        .with_user(None, username)
+        .with_origin(Some(ip.to_string()), audit::user_agent(headers));
+    audit::record(database, entry).await;
+}
+
+/// Accept either the current authenticator code or an unused recovery code
+async fn check_second_factor(
+    two_factor_service: &TwoFactorService,
+    database: &Database,
+    user: &User,
+    two_factor: &TwoFactorState,
+    code: Option<&str>,
+) -> Result<(), ApiError> {
+    let challenge = |message: &str| {
+        ApiError::new(StatusCode::UNAUTHORIZED, message).with_data(json!({ "requires_2fa": true }))
+    };
+
+    let code = match code {
+        Some(code) if !code.trim().is_empty() => code,
+        _ => return Err(challenge("Two-factor code required")),
+    };
 
-    Ok(Json(ApiResponse::success(user_files)))
+    // The code's shape decides how it is checked; anything else is wrong
+    let accepted = if two_factor::is_totp_code(code) {
+        let secret = two_factor.secret.as_deref().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        two_factor_service.verify_code(secret, &user.username, code)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    } else if two_factor::is_recovery_code(code) {
+        database.use_recovery_code(user.id, &two_factor::hash_recovery_code(code)).await
+            .map_err(database_error)?
+    } else {
+        false
+    };
+
+    if !accepted {
+        return Err(challenge("Invalid two-factor code"));
+    }
+    Ok(())
 }
 
-pub async fn create_folder(
-    State(filesystem): State<FileSystemService>,
+/// A new refresh token for the user, not yet stored, along with its row
+fn new_refresh_token(
+    auth_service: &AuthService,
+    user_id: Uuid,
+    device_id: Option<String>,
+) -> (String, RefreshToken) {
+    let (token, token_hash) = auth_service.generate_refresh_token();
+    let now = Utc::now();
+
+    let row = RefreshToken {
+        id: Uuid::new_v4(),
+        user_id,
+        token_hash,
+        device_id,
+        session_id: None,
+        created_at: now,
+        expires_at: now + auth_service.refresh_token_ttl(),
+    };
+
+    (token, row)
+}
+
+/// Trade a refresh token for a new access token, and optionally a new
+/// refresh token in place of the old one
+pub async fn refresh_access_token(
+    State(auth_service): State<AuthService>,
     State(database): State<Database>,
-    Extension(claims): Extension<Claims>,
-    Json(request): Json<CreateFolderRequest>,
-) -> Result<Json<ApiResponse<FileMetadata>>, StatusCode> {
-    let user_id = Uuid::parse_str(&claims.sub)
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<RefreshRequest>,
+) -> Result<Json<ApiResponse<LoginResponse>>, StatusCode> {
+    let audit_entry = |outcome: &str| AuditEntry::new(AuditEvent::TokenRefresh, outcome)
+        .with_origin(Some(remote.ip().to_string()), audit::user_agent(&headers));
+
+    let token_hash = AuthService::hash_token(&request.refresh_token);
+    let stored = database.get_refresh_token(&token_hash).await
+        .map_err(database_error)?;
+    let Some(stored) = stored else {
+        audit::record(&database, audit_entry("failure")).await;
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let user = database.get_user_by_id(stored.user_id).await
+        .map_err(database_error)?
+        .filter(|user| user.is_active);
+    let Some(user) = user else {
+        audit::record(&database, audit_entry("denied").with_user(Some(stored.user_id), "")).await;
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let (token, claims) = auth_service.issue_token(&user, stored.device_id.clone())
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let expires_at = chrono::DateTime::from_timestamp(claims.exp, 0)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let folder_path = if request.path.ends_with('/') {
-        format!("{}{}", request.path, request.name)
+    let (refresh_token, refresh_expires_at) = if request.rotate {
+        let (new_token, mut row) = new_refresh_token(&auth_service, user.id, stored.device_id.clone());
+        row.session_id = stored.session_id;
+
+        // Lost a race with another refresh of the same token
+        if !database.rotate_refresh_token(stored.id, &row).await
+            .map_err(database_error)? {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        (new_token, row.expires_at)
     } else {
-        format!("{}/{}", request.path, request.name)
+        (request.refresh_token, stored.expires_at)
     };
 
-    let mut metadata = filesystem.create_directory(&folder_path).await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Tokens from before sessions existed have none to follow
+    if let Some(session_id) = stored.session_id {
+        database.refresh_session(session_id, &claims.jti, expires_at, refresh_expires_at, Utc::now()).await
+            .map_err(database_error)?;
+    }
+    let must_change_password = database.must_change_password(user.id).await
+        .map_err(database_error)?;
 
-    metadata.owner_id = user_id;
+    audit::record(&database, audit_entry("success").with_user(Some(user.id), &user.username)).await;
 
-    // Save metadata to database
-    database.create_file_metadata(&metadata).await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let response = LoginResponse {
+        token,
+        user,
+        expires_at,
+        refresh_token,
+        refresh_expires_at,
+        must_change_password,
+    };
 
-    Ok(Json(ApiResponse::success(metadata)))
+    Ok(Json(ApiResponse::success(response)))
 }
 
-pub async fn delete_file(
-    State(filesystem): State<FileSystemService>,
+/// Revoke the caller's refresh tokens for one device, e.g. a lost phone
+pub async fn revoke_refresh_tokens(
     State(database): State<Database>,
     Extension(claims): Extension<Claims>,
-    Path(file_path): Path<String>,
-) -> Result<Json<ApiResponse<()>>, StatusCode> {
-    let user_id = Uuid::parse_str(&claims.sub)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Path(device_id): Path<String>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
 
-    let file_path = urlencoding::decode(&file_path)
-        .map_err(|_| StatusCode::BAD_REQUEST)?
-        .into_owned();
+    let revoked = database.delete_device_refresh_tokens(user_id, &device_id).await
+        .map_err(database_error)?;
 
-    // TODO: Check permissions before deleting
+    Ok(Json(ApiResponse::success(json!({ "revoked": revoked }))))
+}
 
-    filesystem.delete_file(&file_path).await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
+/// Revoke the access token used for this request, ending its session, along
+/// with the refresh tokens of the device it was issued to
+pub async fn logout(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    // Tokens from before jtis existed can't be revoked one by one
+    if !claims.jti.is_empty() {
+        let expires_at = chrono::DateTime::from_timestamp(claims.exp, 0)
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        database.revoke_token(&claims.jti, user_id, expires_at).await
+            .map_err(database_error)?;
+        auth_service.revoke_token(&claims.jti, claims.exp);
+        database.delete_session_by_jti(user_id, &claims.jti).await
+            .map_err(database_error)?;
+    }
+
+    if let Some(device_id) = &claims.device_id {
+        database.delete_device_refresh_tokens(user_id, device_id).await
+            .map_err(database_error)?;
+    }
 
     Ok(Json(ApiResponse::success(())))
 }
 
-pub async fn sync_files(
-    State(filesystem): State<FileSystemService>,
+/// The caller's account. When an admin is impersonating them, says who.
+pub async fn get_user_profile(
     State(database): State<Database>,
     Extension(claims): Extension<Claims>,
-    Json(request): Json<SyncRequest>,
-) -> Result<Json<ApiResponse<SyncResponse>>, StatusCode> {
-    let user_id = Uuid::parse_str(&claims.sub)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<Json<ApiResponse<UserProfile>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let account = database.get_user_account(user_id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
 
-    let since = request.last_sync.unwrap_or_else(|| {
-        Utc::now() - chrono::Duration::hours(24)
-    });
-
-    let changes = database.get_files_changed_since(user_id, since).await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(ApiResponse::success(UserProfile {
+        account,
+        impersonated_by: claims.impersonator,
+    })))
+}
 
-    let sync_token = Uuid::new_v4().to_string();
+/// Change the caller's password. Every token they hold is revoked, this
+/// one included, so all their devices have to log in again.
+pub async fn change_password(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<ChangePasswordRequest>,
+) -> Result<Json<ApiResponse<()>>, ApiError> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let user = database.get_user_by_id(user_id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
 
-    let response = SyncResponse {
-        changes,
-        sync_token,
-    };
+    if !auth_service.verify_password(&request.current_password, &user.password_hash)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "Current password is incorrect"));
+    }
 
-    Ok(Json(ApiResponse::success(response)))
+    set_password(&auth_service, &database, user_id, &request.new_password, false).await?;
+    Ok(Json(ApiResponse::success(())))
 }
 
-pub async fn create_share_link(
+/// Set a user's password without knowing the old one. They are asked to
+/// pick their own at the next login.
+pub async fn reset_user_password(
+    State(auth_service): State<AuthService>,
     State(database): State<Database>,
     Extension(claims): Extension<Claims>,
-    Path(file_id): Path<String>,
-    Query(params): Query<HashMap<String, String>>,
-) -> Result<Json<ApiResponse<ShareLink>>, StatusCode> {
-    let user_id = Uuid::parse_str(&claims.sub)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Path(target_user_id): Path<String>,
+    Json(request): Json<ResetPasswordRequest>,
+) -> Result<Json<ApiResponse<()>>, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
 
-    let file_id = Uuid::parse_str(&file_id)
+    let target_user_id = Uuid::parse_str(&target_user_id)
         .map_err(|_| StatusCode::BAD_REQUEST)?;
 
-    // Check if file exists and user owns it
-    let file_metadata = database.get_file_metadata(file_id).await
+    set_password(&auth_service, &database, target_user_id, &request.new_password, true).await?;
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Hash and store a new password, then revoke every token issued before it
+async fn set_password(
+    auth_service: &AuthService,
+    database: &Database,
+    user_id: Uuid,
+    password: &str,
+    must_change_password: bool,
+) -> Result<(), ApiError> {
+    auth_service.validate_password(password).map_err(password_rejected)?;
+
+    let password_hash = auth_service.hash_password(password)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let file_metadata = match file_metadata {
-        Some(metadata) if metadata.owner_id == user_id => metadata,
-        Some(_) => return Ok(Json(ApiResponse::error("Access denied".to_string()))),
-        None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+    let now = Utc::now();
+    let updated = database.update_password_hash(user_id, &password_hash, must_change_password, now).await
+        .map_err(database_error)?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    auth_service.revoke_user_tokens(user_id, now);
+
+    Ok(())
+}
+
+/// Generate a TOTP secret for the caller. It takes effect once confirmed
+/// with a code through `verify_two_factor`.
+pub async fn setup_two_factor(
+    State(two_factor_service): State<TwoFactorService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<TwoFactorSetupResponse>>, ApiError> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let two_factor = database.get_two_factor(user_id).await
+        .map_err(database_error)?;
+    if two_factor.enabled {
+        return Err(ApiError::new(StatusCode::CONFLICT, "Two-factor authentication is already enabled"));
+    }
+
+    let secret = two_factor_service.generate_secret(&claims.username)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    database.set_pending_totp_secret(user_id, &secret.encrypted).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(TwoFactorSetupResponse {
+        secret: secret.base32,
+        otpauth_uri: secret.otpauth_uri,
+    })))
+}
+
+/// Confirm the secret from setup with a code and start requiring codes at
+/// login. Returns the recovery codes, which are not shown again.
+pub async fn verify_two_factor(
+    State(two_factor_service): State<TwoFactorService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<TwoFactorVerifyRequest>,
+) -> Result<Json<ApiResponse<TwoFactorVerifyResponse>>, ApiError> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let two_factor = database.get_two_factor(user_id).await
+        .map_err(database_error)?;
+    if two_factor.enabled {
+        return Err(ApiError::new(StatusCode::CONFLICT, "Two-factor authentication is already enabled"));
+    }
+    let secret = two_factor.secret
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Set up two-factor authentication first"))?;
+
+    if !two_factor_service.verify_code(&secret, &claims.username, &request.code)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Invalid two-factor code"));
+    }
+
+    let recovery_codes = two_factor::generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes.iter()
+        .map(|code| two_factor::hash_recovery_code(code))
+        .collect();
+    database.enable_two_factor(user_id, &hashes).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(TwoFactorVerifyResponse { recovery_codes })))
+}
+
+/// Turn off two-factor authentication for a user who lost their
+/// authenticator and recovery codes
+pub async fn disable_user_two_factor(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(target_user_id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let target_user_id = Uuid::parse_str(&target_user_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let updated = database.disable_two_factor(target_user_id).await
+        .map_err(database_error)?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Devices the caller has logged in from, most recently seen first
+pub async fn list_devices(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<Device>>>, StatusCode> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let devices = database.list_devices(user_id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(devices)))
+}
+
+/// Cut a device off: its tokens stop working and its sync session ends.
+/// Logging in from it again brings it back.
+pub async fn revoke_device(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(device_id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let now = Utc::now();
+    let revoked = database.revoke_device(user_id, &device_id, now).await
+        .map_err(database_error)?;
+    if !revoked {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    auth_service.revoke_device_tokens(user_id, &device_id, now);
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Where and when the caller is logged in, most recently active first
+pub async fn list_sessions(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<Session>>>, StatusCode> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let mut sessions = database.list_sessions(user_id).await
+        .map_err(database_error)?;
+    for session in &mut sessions {
+        session.current = session.jti == claims.jti;
+    }
+
+    Ok(Json(ApiResponse::success(sessions)))
+}
+
+/// Log one of the caller's sessions out: its access token is revoked and it
+/// can't be refreshed any more
+pub async fn end_session(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(session_id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let session_id = Uuid::parse_str(&session_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let session = database.end_session(user_id, session_id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    auth_service.revoke_token(&session.jti, session.token_expires_at.timestamp());
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Create a personal access token for scripts. The secret is returned once.
+pub async fn create_api_token(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<CreateApiTokenRequest>,
+) -> Result<Json<ApiResponse<CreateApiTokenResponse>>, ApiError> {
+    let response = new_api_token(
+        &auth_service,
+        &database,
+        &claims,
+        &request.name,
+        request.scope,
+        Vec::new(),
+        request.expires_in_days,
+    )
+    .await?;
+
+    Ok(Json(ApiResponse::success(response)))
+}
+
+/// A personal access token limited to some folders, e.g. a kiosk that may
+/// only download from `/public`
+pub async fn create_scoped_token(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Json(mut request): Json<CreateScopedTokenRequest>,
+) -> Result<Json<ApiResponse<CreateApiTokenResponse>>, ApiError> {
+    if request.scopes.is_empty() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "A scoped token needs at least one scope"));
+    }
+    for scope in &mut request.scopes {
+        if !scope.path_prefix.starts_with('/') || path_components(&scope.path_prefix).is_none() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid path prefix '{}'", scope.path_prefix),
+            ));
+        }
+        scope.path_prefix = normalize_path(&scope.path_prefix);
+    }
+
+    // Only reading needs no more than a read-only token
+    let token_scope = if request.scopes.iter().all(|scope| scope.action == Action::Read) {
+        TokenScope::ReadOnly
+    } else {
+        TokenScope::ReadWrite
     };
 
-    let expires_in_hours = params.get("expires_in_hours")
-        .and_then(|s| s.parse::<i64>().ok())
-        .unwrap_or(24);
+    let response = new_api_token(
+        &auth_service,
+        &database,
+        &claims,
+        &request.name,
+        token_scope,
+        request.scopes,
+        request.expires_in_days,
+    )
+    .await?;
 
-    let max_downloads = params.get("max_downloads")
-        .and_then(|s| s.parse::<u32>().ok());
+    Ok(Json(ApiResponse::success(response)))
+}
+
+/// Longest lifetime a token may be given, in days
+const MAX_EXPIRY_DAYS: i64 = 3650;
+
+/// When something given `expires_in_days` from `now` expires, refusing
+/// lifetimes that aren't positive or exceed `MAX_EXPIRY_DAYS`
+fn expiry_after_days(now: chrono::DateTime<Utc>, expires_in_days: Option<i64>) -> Result<Option<chrono::DateTime<Utc>>, ApiError> {
+    let Some(days) = expires_in_days else {
+        return Ok(None);
+    };
+    if !(1..=MAX_EXPIRY_DAYS).contains(&days) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("expires_in_days must be between 1 and {}", MAX_EXPIRY_DAYS),
+        ));
+    }
+    chrono::Duration::try_days(days)
+        .and_then(|lifetime| now.checked_add_signed(lifetime))
+        .map(Some)
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "expires_in_days is out of range"))
+}
 
-    let share_link = ShareLink {
+async fn new_api_token(
+    auth_service: &AuthService,
+    database: &Database,
+    claims: &Claims,
+    name: &str,
+    scope: TokenScope,
+    path_scopes: Vec<PathScope>,
+    expires_in_days: Option<i64>,
+) -> Result<CreateApiTokenResponse, ApiError> {
+    require_login(claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Token name must not be empty"));
+    }
+    let now = Utc::now();
+    let expires_at = expiry_after_days(now, expires_in_days)?;
+
+    let (secret, token_hash) = auth_service.generate_api_token();
+    let token = ApiToken {
         id: Uuid::new_v4(),
-        file_id,
-        created_by: user_id,
-        share_token: Uuid::new_v4().to_string(),
-        expires_at: Some(Utc::now() + chrono::Duration::hours(expires_in_hours)),
-        password_protected: false,
-        download_count: 0,
-        max_downloads,
-        created_at: Utc::now(),
+        user_id,
+        name: name.to_string(),
+        token_hash,
+        scope,
+        path_scopes,
+        created_at: now,
+        expires_at,
+        last_used_at: None,
+    };
+
+    database.create_api_token(&token).await
+        .map_err(database_error)?;
+
+    Ok(CreateApiTokenResponse { token, secret })
+}
+
+pub async fn list_api_tokens(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<ApiToken>>>, StatusCode> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let tokens = database.list_api_tokens(user_id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(tokens)))
+}
+
+pub async fn delete_api_token(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(token_id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let token_id = Uuid::parse_str(&token_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let deleted = database.delete_api_token(user_id, token_id).await
+        .map_err(database_error)?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Create an app password for a client that only speaks HTTP Basic auth.
+/// The password is returned once.
+pub async fn create_app_password(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<CreateAppPasswordRequest>,
+) -> Result<Json<ApiResponse<CreateAppPasswordResponse>>, ApiError> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let label = request.label.trim();
+    if label.is_empty() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "App password label must not be empty"));
+    }
+    let now = Utc::now();
+    let expires_at = expiry_after_days(now, request.expires_in_days)?;
+    let path_prefix = match &request.path_prefix {
+        Some(prefix) if !prefix.starts_with('/') || path_components(prefix).is_none() => {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid path prefix '{}'", prefix)));
+        }
+        Some(prefix) => Some(normalize_path(prefix)),
+        None => None,
     };
 
-    database.create_share_link(&share_link).await
+    let id = Uuid::new_v4();
+    let (password, password_hash) = auth_service.generate_app_password(id)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let app_password = AppPassword {
+        id,
+        user_id,
+        label: label.to_string(),
+        password_hash,
+        path_prefix,
+        created_at: now,
+        expires_at,
+        last_used_at: None,
+    };
 
-    Ok(Json(ApiResponse::success(share_link)))
+    database.create_app_password(&app_password).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(CreateAppPasswordResponse { app_password, password })))
 }
 
-pub async fn get_server_info() -> Json<ApiResponse<serde_json::Value>> {
-    let info = json!({
-        "name": "Synker Server",
-        "version": "0.1.0",
-        "description": "Self-hosted cloud storage server for MyCloud OS5",
-        "api_version": "v1",
-        "features": [
-            "file_upload",
-            "file_download",
-            "file_sync",
-            "folder_creation",
-            "file_sharing",
-            "user_authentication"
-        ]
-    });
+pub async fn list_app_passwords(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<AppPassword>>>, StatusCode> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
 
-    Json(ApiResponse::success(info))
+    let app_passwords = database.list_app_passwords(user_id, true).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(app_passwords)))
+}
+
+pub async fn delete_app_password(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    require_login(&claims)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let id = Uuid::parse_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let deleted = database.delete_app_password(user_id, id).await
+        .map_err(database_error)?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Header carrying the SHA-256 the client expects an upload to have
+const CHECKSUM_HEADER: &str = "x-synker-checksum";
+
+/// Header carrying the SHA-256 of the version the client changed
+const BASE_CHECKSUM_HEADER: &str = "x-synker-base-checksum";
+
+/// The version of a file a client last saw before changing it
+#[derive(Default)]
+struct BaseVersion {
+    checksum: Option<String>,
+    modified_at: Option<chrono::DateTime<Utc>>,
+}
+
+impl BaseVersion {
+    fn is_set(&self) -> bool {
+        self.checksum.is_some() || self.modified_at.is_some()
+    }
+
+    /// Whether `current` is not what the client saw
+    fn conflicts_with(&self, current: &FileMetadata) -> bool {
+        self.checksum.as_deref().is_some_and(|checksum| !checksum.eq_ignore_ascii_case(&current.checksum))
+            || self.modified_at.is_some_and(|modified_at| modified_at != current.modified_at)
+    }
+}
+
+/// How conflicting uploads and pushes are settled, from `[sync]`
+#[derive(Clone)]
+pub struct ConflictSettings {
+    policy: ConflictPolicy,
+    tolerance: chrono::Duration,
+    /// Per-folder policies, longest pattern first
+    folders: Arc<Vec<(Gitignore, ConflictPolicy)>>,
+}
+
+impl ConflictSettings {
+    pub fn new(settings: &SyncSettings) -> Result<Self> {
+        let mut patterns: Vec<_> = settings.folder_policies.iter().collect();
+        patterns.sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.len()));
+
+        let mut folders = Vec::with_capacity(patterns.len());
+        for (pattern, policy) in patterns {
+            let mut builder = GitignoreBuilder::new("/");
+            builder.add_line(None, pattern)?;
+            folders.push((builder.build()?, *policy));
+        }
+
+        Ok(Self {
+            policy: settings.conflict_policy,
+            tolerance: chrono::Duration::seconds(settings.newest_wins_tolerance_seconds as i64),
+            folders: Arc::new(folders),
+        })
+    }
+
+    /// The policy for conflicts at `path`
+    fn policy_for(&self, path: &str) -> ConflictPolicy {
+        self.folders.iter()
+            .find(|(pattern, _)| pattern.matched_path_or_any_parents(path, false).is_ignore())
+            .map_or(self.policy, |(_, policy)| *policy)
+    }
+
+    /// What `policy` comes down to for a client's version, last modified at
+    /// `modified_at`, against the `current` one. Never `NewestWins`.
+    fn settle(&self, policy: ConflictPolicy, modified_at: Option<chrono::DateTime<Utc>>, current: &FileMetadata) -> ConflictPolicy {
+        match (policy, modified_at) {
+            (ConflictPolicy::NewestWins, Some(modified_at)) => {
+                let ahead = modified_at - current.modified_at;
+                if ahead > self.tolerance {
+                    ConflictPolicy::ClientWins
+                } else if ahead < -self.tolerance {
+                    ConflictPolicy::ServerWins
+                } else {
+                    ConflictPolicy::KeepBoth
+                }
+            }
+            // Without the client's time there's nothing to go by, so nothing is dropped
+            (ConflictPolicy::NewestWins, None) => ConflictPolicy::KeepBoth,
+            (policy, _) => policy,
+        }
+    }
+}
+
+/// Where a write based on some version of a file goes
+enum Settlement {
+    /// Nothing is at the path yet
+    New,
+    /// The file is still what the client saw, so the write replaces it
+    Unchanged,
+    /// The file changed meanwhile; the write goes ahead at `path`, over
+    /// the file unless `policy` kept both
+    Conflict { path: String, policy: ConflictPolicy },
+    /// The file changed meanwhile and stays as it is
+    ServerWins(FileMetadata),
+}
+
+/// Check a write to `path` based on `base` against the file there now,
+/// letting `decide` pick a policy when they conflict. `Fail` is 409 Conflict.
+async fn settle_conflict(
+    storage: &dyn StorageBackend,
+    database: &Database,
+    claims: &Claims,
+    user_id: Uuid,
+    path: &str,
+    base: &BaseVersion,
+    decide: impl FnOnce(&FileMetadata) -> ConflictPolicy,
+) -> Result<Settlement, ApiError> {
+    let current = match database.get_file_metadata_by_path(user_id, path).await {
+        Ok(Some(current)) => Some(current),
+        Ok(None) => storage.metadata(path).await.ok(),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+    };
+    let current = match current {
+        Some(current) if base.conflicts_with(&current) => current,
+        Some(_) => return Ok(Settlement::Unchanged),
+        None => return Ok(Settlement::New),
+    };
+
+    match decide(&current) {
+        ConflictPolicy::Fail => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("{} changed since the version this upload is based on", path),
+        ).with_data(current)),
+        ConflictPolicy::ServerWins => Ok(Settlement::ServerWins(current)),
+        ConflictPolicy::ClientWins => Ok(Settlement::Conflict { path: path.to_string(), policy: ConflictPolicy::ClientWins }),
+        ConflictPolicy::KeepBoth | ConflictPolicy::NewestWins => {
+            let device = caller_device_name(database, claims).await;
+            let path = conflicted_copy_path(storage, path, &device, Utc::now()).await;
+            Ok(Settlement::Conflict { path, policy: ConflictPolicy::KeepBoth })
+        }
+    }
+}
+
+pub async fn upload_file(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    State(conflicts): State<ConflictSettings>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Json<ApiResponse<UploadResponse>>, ApiError> {
+    let path = params.get("path").unwrap_or(&"/".to_string()).clone();
+    let mut overwrite = params.get("overwrite")
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+    // Overrides the configured policy for this upload
+    let on_conflict = params.get("on_conflict")
+        .map(|value| ConflictPolicy::parse(value).ok_or_else(|| {
+            ApiError::new(StatusCode::BAD_REQUEST, format!("Unknown on_conflict '{}'", value))
+        }))
+        .transpose()?;
+
+    let user_id = shared_target_user(&database, &claims, &params, Action::Write, &path).await?;
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+    let storage = home_storage(storage.as_ref(), user_id)?;
+
+    let filesystem = visible_filesystem(filesystem, &database, &claims, &params).await?;
+
+    // Sent either as a header or as a `checksum` field ahead of the file
+    let mut expected_checksum = headers.get(CHECKSUM_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string());
+    // Likewise the version the client changed, as `base_checksum` and
+    // `base_modified_at` fields
+    let mut base = BaseVersion {
+        checksum: headers.get(BASE_CHECKSUM_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string()),
+        modified_at: None,
+    };
+    // When the client last modified the file, as a `modified_at` field
+    let mut modified_at = None;
+
+    while let Some(mut field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+        if field.file_name().is_none() {
+            match field.name() {
+                Some("checksum") => {
+                    let value = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+                    expected_checksum = Some(value.trim().to_string());
+                    continue;
+                }
+                Some("base_checksum") => {
+                    let value = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+                    base.checksum = Some(value.trim().to_string());
+                    continue;
+                }
+                Some("base_modified_at") => {
+                    let value = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+                    let modified_at = chrono::DateTime::parse_from_rfc3339(value.trim())
+                        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "base_modified_at must be an RFC 3339 time"))?;
+                    base.modified_at = Some(modified_at.with_timezone(&Utc));
+                    continue;
+                }
+                Some("modified_at") => {
+                    let value = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+                    let value = chrono::DateTime::parse_from_rfc3339(value.trim())
+                        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "modified_at must be an RFC 3339 time"))?;
+                    modified_at = Some(value.with_timezone(&Utc));
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        let filename = field.file_name().unwrap_or("unnamed").to_string();
+
+        let mut file_path = normalize_path(&if path.ends_with('/') {
+            format!("{}{}", path, filename)
+        } else {
+            format!("{}/{}", path, filename)
+        });
+        check_scope(&claims, Action::Write, &file_path)?;
+        check_upload_path(&filesystem, &file_path)?;
+
+        check_file_lock(&database, &claims, lock_home(&filesystem), &file_path).await?;
+
+        // The client already has this exact file here, so there is nothing to transfer
+        if let Some(expected) = expected_checksum.as_deref() {
+            if let Some(existing) = find_identical_file(&filesystem, &database, user_id, &file_path, expected).await? {
+                let response = UploadResponse {
+                    file_id: existing.id,
+                    path: existing.path,
+                    size: existing.size,
+                    checksum: existing.checksum,
+                    deduplicated: true,
+                    conflict: false,
+                    conflict_policy: None,
+                };
+                return Ok(Json(ApiResponse::success(response)));
+            }
+        }
+
+        // Someone else may have changed the file since the client last saw it
+        let policy = on_conflict.unwrap_or_else(|| conflicts.policy_for(&file_path));
+        let mut conflict_policy = None;
+        let mut unchanged = false;
+        if base.is_set() {
+            let settlement = settle_conflict(storage.as_ref(), &database, &claims, user_id, &file_path, &base, |current| {
+                conflicts.settle(policy, modified_at, current)
+            }).await?;
+            match settlement {
+                Settlement::New => {}
+                // Replacing exactly what the client saw
+                Settlement::Unchanged => {
+                    overwrite = true;
+                    unchanged = true;
+                }
+                Settlement::Conflict { path, policy } => {
+                    overwrite = policy == ConflictPolicy::ClientWins;
+                    file_path = path;
+                    conflict_policy = Some(policy);
+                }
+                Settlement::ServerWins(current) => {
+                    let response = UploadResponse {
+                        file_id: current.id,
+                        path: current.path,
+                        size: current.size,
+                        checksum: current.checksum,
+                        deduplicated: false,
+                        conflict: true,
+                        conflict_policy: Some(ConflictPolicy::ServerWins),
+                    };
+                    return Ok(Json(ApiResponse::success(response)));
+                }
+            }
+        }
+
+        // Check if file exists and overwrite is not allowed
+        if !overwrite {
+            if let Ok(_) = storage.metadata(&file_path).await {
+                return Ok(Json(ApiResponse::error("File already exists".to_string())));
+            }
+        }
+
+        // The size isn't known up front, so the quota is enforced while streaming
+        let quota = get_quota_usage(&filesystem, &database, user_id).await?;
+        let freed = replaced_file_size(&database, user_id, &file_path, overwrite).await?;
+        let allowance = quota.remaining(freed);
+
+        // Stream the field to a temp file so large uploads never sit in memory
+        let mut upload = filesystem.begin_upload().await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        loop {
+            let chunk = match field.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(_) => {
+                    upload.abort().await;
+                    return Err(StatusCode::BAD_REQUEST.into());
+                }
+            };
+
+            if let Err(e) = upload.write_chunk(&chunk).await {
+                upload.abort().await;
+                return match e.downcast_ref::<FileSystemError>() {
+                    Some(FileSystemError::FileTooLarge) => Err(StatusCode::PAYLOAD_TOO_LARGE.into()),
+                    _ => Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+                };
+            }
+
+            if allowance.map_or(false, |allowance| upload.size() > allowance) {
+                let requested = upload.size();
+                upload.abort().await;
+                return Err(quota_exceeded(&quota, requested));
+            }
+        }
+
+        if let Some(expected) = expected_checksum.as_deref() {
+            let actual = upload.checksum();
+            if !expected.eq_ignore_ascii_case(&actual) {
+                upload.abort().await;
+                return Err(checksum_mismatch(expected, &actual));
+            }
+        }
+
+        if overwrite {
+            preserve_previous_version(&filesystem, &database, user_id, &file_path).await?;
+        }
+
+        let staged = upload.finish().await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let (metadata, conflict_policy) = if unchanged {
+            store_over_base(&filesystem, storage.as_ref(), &database, &claims, user_id, &file_path, staged, &base, |current| {
+                conflicts.settle(policy, modified_at, current)
+            }).await?
+        } else {
+            (store_staged_file(storage.as_ref(), &database, user_id, &file_path, staged).await?, conflict_policy)
+        };
+
+        let response = UploadResponse {
+            file_id: metadata.id,
+            path: metadata.path,
+            size: metadata.size,
+            checksum: metadata.checksum,
+            deduplicated: false,
+            conflict: conflict_policy.is_some(),
+            conflict_policy,
+        };
+
+        return Ok(Json(ApiResponse::success(response)));
+    }
+
+    Ok(Json(ApiResponse::error("No file uploaded".to_string())))
+}
+
+/// Turn away names, ignored paths and extensions files may not be stored under
+fn check_upload_path(filesystem: &FileSystemService, path: &str) -> Result<(), ApiError> {
+    if let Err(e) = filesystem.check_file_name(path) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+
+    if let Err(e) = filesystem.check_ignored(path) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+
+    if let Err(e) = filesystem.check_extension(path) {
+        return Err(ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Put staged content at `path` and record it for `user_id`, keeping the id
+/// of the file it replaces
+async fn store_staged_file(
+    storage: &dyn StorageBackend,
+    database: &Database,
+    user_id: Uuid,
+    path: &str,
+    staged: StagedUpload,
+) -> Result<FileMetadata, StatusCode> {
+    let mut metadata = storage.put(path, staged).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    metadata.owner_id = user_id;
+    metadata.parent_id = database.ensure_parent_directories(user_id, &metadata.path).await
+        .map_err(database_error)?;
+
+    save_uploaded_metadata(database, &mut metadata).await?;
+
+    Ok(metadata)
+}
+
+/// Put staged content over the file at `path` while it is still the
+/// version `base` describes, checking and recording it in one transaction.
+/// The content is handed back when the file changed in the meantime.
+async fn replace_staged_file(
+    storage: &dyn StorageBackend,
+    database: &Database,
+    user_id: Uuid,
+    path: &str,
+    staged: StagedUpload,
+    base: &BaseVersion,
+) -> Result<Result<FileMetadata, StagedUpload>, ApiError> {
+    let mut tx = begin(database).await?;
+    let claimed = tx.claim_file_version(
+        user_id,
+        path,
+        base.checksum.as_deref(),
+        base.modified_at,
+        &staged.checksum,
+        staged.size,
+    ).await;
+    let existing = match claimed {
+        Ok(Some(existing)) => existing,
+        Ok(None) => return Ok(Err(staged)),
+        Err(e) => {
+            tracing::error!("Failed to check {} against its base version: {}", path, e);
+            staged.discard().await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        }
+    };
+
+    // Dropping the transaction on failure gives the file back its checksum
+    let mut metadata = storage.put(path, staged).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    metadata.owner_id = user_id;
+    metadata.parent_id = existing.parent_id;
+    let recorded = match tx.upsert_file_metadata(&mut metadata).await {
+        Ok(()) => tx.commit().await,
+        Err(e) => Err(e),
+    };
+    recorded.map_err(|e| {
+        tracing::error!("Failed to record the upload to {}: {}", path, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Ok(metadata))
+}
+
+/// Store a write that was settled as based on the current file. Should the
+/// file have changed since, the write is settled again with `decide`.
+async fn store_over_base(
+    filesystem: &FileSystemService,
+    storage: &dyn StorageBackend,
+    database: &Database,
+    claims: &Claims,
+    user_id: Uuid,
+    path: &str,
+    staged: StagedUpload,
+    base: &BaseVersion,
+    decide: impl FnOnce(&FileMetadata) -> ConflictPolicy,
+) -> Result<(FileMetadata, Option<ConflictPolicy>), ApiError> {
+    let staged = match replace_staged_file(storage, database, user_id, path, staged, base).await? {
+        Ok(metadata) => return Ok((metadata, None)),
+        Err(staged) => staged,
+    };
+
+    let settlement = match settle_conflict(storage, database, claims, user_id, path, base, decide).await {
+        Ok(settlement) => settlement,
+        Err(e) => {
+            staged.discard().await;
+            return Err(e);
+        }
+    };
+    match settlement {
+        Settlement::ServerWins(current) => {
+            staged.discard().await;
+            Ok((current, Some(ConflictPolicy::ServerWins)))
+        }
+        Settlement::Conflict { path, policy } => {
+            if policy == ConflictPolicy::ClientWins {
+                preserve_previous_version(filesystem, database, user_id, &path).await?;
+            }
+            let metadata = store_staged_file(storage, database, user_id, &path, staged).await?;
+            Ok((metadata, Some(policy)))
+        }
+        // Gone meanwhile, or a file on disk with no row to swap
+        Settlement::New | Settlement::Unchanged => {
+            let metadata = store_staged_file(storage, database, user_id, path, staged).await?;
+            Ok((metadata, None))
+        }
+    }
+}
+
+/// Where to keep an upload that conflicts with `path`:
+/// `name (conflicted copy from <device> <date>).ext` beside it, numbered
+/// when that is taken as well
+async fn conflicted_copy_path(
+    storage: &dyn StorageBackend,
+    path: &str,
+    device: &str,
+    at: chrono::DateTime<Utc>,
+) -> String {
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    let label = format!("conflicted copy from {} {}", device.replace(['/', '\\'], "-"), at.format("%Y-%m-%d"));
+
+    let mut attempt = 1;
+    loop {
+        let copy = match attempt {
+            1 => format!("{} ({})", stem, label),
+            _ => format!("{} ({} {})", stem, label, attempt),
+        };
+        let copy_path = match extension {
+            Some(extension) => format!("{}/{}.{}", parent, copy, extension),
+            None => format!("{}/{}", parent, copy),
+        };
+        if storage.metadata(&copy_path).await.is_err() {
+            return copy_path;
+        }
+        attempt += 1;
+    }
+}
+
+/// How the caller's device calls itself: its name, else its id, else the
+/// caller's username for tokens not tied to a device
+async fn caller_device_name(database: &Database, claims: &Claims) -> String {
+    let Some(device_id) = &claims.device_id else {
+        return claims.username.clone();
+    };
+    let Ok(user_id) = Uuid::parse_str(&claims.sub) else {
+        return device_id.clone();
+    };
+
+    database.list_devices(user_id).await.ok()
+        .and_then(|devices| devices.into_iter().find(|device| &device.device_id == device_id))
+        .and_then(|device| device.name)
+        .unwrap_or_else(|| device_id.clone())
+}
+
+/// Before `path` is overwritten, keep its current content as a version of the
+/// existing file.
+async fn preserve_previous_version(
+    filesystem: &FileSystemService,
+    database: &Database,
+    user_id: Uuid,
+    path: &str,
+) -> Result<(), StatusCode> {
+    let existing = match database.get_file_metadata_by_path(user_id, path).await {
+        Ok(Some(existing)) if !existing.is_directory => existing,
+        Ok(_) => return Ok(()),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    // Versioning is disabled
+    if filesystem.max_versions_per_file() == 0 {
+        return Ok(());
+    }
+
+    let (checksum, size) = match filesystem.store_version(path).await {
+        Ok(stored) => stored,
+        // Nothing left on disk to preserve
+        Err(_) => return Ok(()),
+    };
+
+    let latest = database.get_latest_version_number(existing.id).await
+        .map_err(database_error)?;
+
+    let version = FileVersion {
+        id: Uuid::new_v4(),
+        file_id: existing.id,
+        version: latest + 1,
+        size,
+        checksum,
+        modified_at: existing.modified_at,
+        author_id: existing.owner_id,
+        created_at: Utc::now(),
+    };
+
+    database.create_file_version(&version).await
+        .map_err(database_error)?;
+
+    if let Err(e) = prune_file_versions(filesystem, database, existing.id).await {
+        tracing::warn!("Failed to prune versions of {}: {}", existing.id, e);
+    }
+
+    Ok(())
+}
+
+/// Remove the stored versions that deleting metadata rows left unused
+pub(crate) async fn remove_unused_versions(filesystem: &FileSystemService, deleted: &DeletedFiles) {
+    for checksum in &deleted.unused_versions {
+        if let Err(e) = filesystem.delete_version(checksum).await {
+            tracing::warn!("Failed to remove unused version {}: {}", checksum, e);
+        }
+    }
+}
+
+async fn remove_file_version(
+    filesystem: &FileSystemService,
+    database: &Database,
+    version: &FileVersion,
+) -> anyhow::Result<()> {
+    if database.delete_file_version(version.id, &version.checksum).await? {
+        filesystem.delete_version(&version.checksum).await?;
+    }
+    Ok(())
+}
+
+/// Enforce the per-file version count and the total version storage limit
+async fn prune_file_versions(
+    filesystem: &FileSystemService,
+    database: &Database,
+    file_id: Uuid,
+) -> anyhow::Result<()> {
+    let versions = database.list_file_versions(file_id).await?;
+    for version in versions.iter().skip(filesystem.max_versions_per_file() as usize) {
+        remove_file_version(filesystem, database, version).await?;
+    }
+
+    let storage_limit = filesystem.max_version_storage();
+    if storage_limit > 0 {
+        while database.get_total_version_size().await? > storage_limit {
+            let oldest = database.get_oldest_file_versions(16).await?;
+            if oldest.is_empty() {
+                break;
+            }
+            for version in &oldest {
+                remove_file_version(filesystem, database, version).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Persist metadata for freshly written content, reusing the row of the file
+/// it replaced so the file keeps its id across overwrites.
+async fn save_uploaded_metadata(database: &Database, metadata: &mut FileMetadata) -> Result<(), StatusCode> {
+    database.upsert_file_metadata(metadata).await
+        .map_err(database_error)
+}
+
+/// Bytes used by `user_id` and the quota that applies to them
+async fn get_quota_usage(
+    filesystem: &FileSystemService,
+    database: &Database,
+    user_id: Uuid,
+) -> Result<QuotaUsage, StatusCode> {
+    let used = database.get_user_storage_usage(user_id).await
+        .map_err(database_error)?;
+    let limit = database.get_user_quota(user_id).await
+        .map_err(database_error)?
+        .or(filesystem.default_quota());
+
+    Ok(QuotaUsage { used, limit })
+}
+
+/// Size of the file an overwriting write to `path` releases from the quota
+async fn replaced_file_size(
+    database: &Database,
+    user_id: Uuid,
+    path: &str,
+    overwrite: bool,
+) -> Result<u64, StatusCode> {
+    if !overwrite {
+        return Ok(0);
+    }
+
+    let existing = database.get_file_metadata_by_path(user_id, path).await
+        .map_err(database_error)?;
+
+    Ok(existing.filter(|m| !m.is_directory).map_or(0, |m| m.size))
+}
+
+fn quota_exceeded(quota: &QuotaUsage, requested: u64) -> ApiError {
+    let limit = quota.limit.unwrap_or(u64::MAX);
+    ApiError::new(
+        StatusCode::INSUFFICIENT_STORAGE,
+        format!("Storage quota exceeded: {} of {} bytes used", quota.used, limit),
+    )
+    .with_data(QuotaExceeded {
+        used: quota.used,
+        limit,
+        requested,
+    })
+}
+
+fn checksum_mismatch(expected: &str, actual: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        format!("Checksum mismatch: expected {}, got {}", expected, actual),
+    )
+    .with_data(ChecksumMismatch {
+        expected: expected.to_string(),
+        actual: actual.to_string(),
+    })
+}
+
+/// The stored file at `path` if it already has the expected checksum. The size
+/// on disk must still match the record, so a file changed behind our back
+/// isn't mistaken for the one the client has.
+async fn find_identical_file(
+    filesystem: &FileSystemService,
+    database: &Database,
+    user_id: Uuid,
+    path: &str,
+    checksum: &str,
+) -> Result<Option<FileMetadata>, StatusCode> {
+    let existing = match database.get_file_metadata_by_path(user_id, path).await {
+        Ok(Some(existing)) if !existing.is_directory => existing,
+        Ok(_) => return Ok(None),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    if !existing.checksum.eq_ignore_ascii_case(checksum) {
+        return Ok(None);
+    }
+
+    match filesystem.stat(path).await {
+        Ok(stat) if stat.len() == existing.size => Ok(Some(existing)),
+        _ => Ok(None),
+    }
+}
+
+fn upload_session_status(session: &UploadSession, received_chunks: Vec<u32>) -> UploadSessionStatus {
+    let total_chunks = session.total_chunks();
+
+    UploadSessionStatus {
+        session_id: session.id,
+        target_path: session.target_path.clone(),
+        total_size: session.total_size,
+        chunk_size: session.chunk_size,
+        total_chunks,
+        complete: received_chunks.len() as u32 == total_chunks,
+        received_chunks,
+    }
+}
+
+async fn get_owned_upload_session(
+    database: &Database,
+    session_id: &str,
+    user_id: Uuid,
+) -> Result<UploadSession, StatusCode> {
+    let session_id = Uuid::parse_str(session_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    match database.get_upload_session(session_id).await {
+        Ok(Some(session)) if session.user_id == user_id => Ok(session),
+        Ok(Some(_)) => Err(StatusCode::FORBIDDEN),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Chunk sizes and counts upload sessions may use
+#[derive(Clone, Copy)]
+pub struct UploadLimits {
+    pub min_chunk_size: u64,
+    pub max_chunk_size: u64,
+    pub max_chunks: u32,
+}
+
+pub async fn create_upload_session(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    State(limits): State<UploadLimits>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(mut request): Json<CreateUploadSessionRequest>,
+) -> Result<Json<ApiResponse<UploadSessionStatus>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    request.path = normalize_path(&request.path);
+    check_scope(&claims, Action::Write, &request.path)?;
+
+    // Sessions always upload into the caller's own home
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+    let storage = home_storage(storage.as_ref(), user_id)?;
+
+    if request.path.is_empty() || request.path.ends_with('/') {
+        return Ok(Json(ApiResponse::error("Path must name a file".to_string())));
+    }
+
+    if !(limits.min_chunk_size..=limits.max_chunk_size).contains(&request.chunk_size) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, format!(
+            "Chunk size must be between {} and {} bytes",
+            limits.min_chunk_size, limits.max_chunk_size
+        )));
+    }
+
+    match UploadSession::chunk_count(request.total_size, request.chunk_size) {
+        Some(chunks) if chunks <= limits.max_chunks => {}
+        _ => return Err(ApiError::new(StatusCode::BAD_REQUEST, format!(
+            "An upload may have at most {} chunks; use larger chunks",
+            limits.max_chunks
+        ))),
+    }
+
+    if request.total_size > filesystem.max_file_size() {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+    }
+
+    if let Err(e) = filesystem.check_file_name(&request.path) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+
+    let visible = visible_filesystem(filesystem.clone(), &database, &claims, &params).await?;
+    if let Err(e) = visible.check_ignored(&request.path) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+
+    if let Err(e) = filesystem.check_extension(&request.path) {
+        return Err(ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()));
+    }
+
+    check_file_lock(&database, &claims, lock_home(&filesystem), &request.path).await?;
+
+    let overwrite = request.overwrite.unwrap_or(false);
+    if !overwrite {
+        if let Ok(_) = storage.metadata(&request.path).await {
+            return Ok(Json(ApiResponse::error("File already exists".to_string())));
+        }
+    }
+
+    let quota = get_quota_usage(&filesystem, &database, user_id).await?;
+    let freed = replaced_file_size(&database, user_id, &request.path, overwrite).await?;
+    if !quota.allows(request.total_size, freed) {
+        return Err(quota_exceeded(&quota, request.total_size));
+    }
+
+    let now = Utc::now();
+    let session = UploadSession {
+        id: Uuid::new_v4(),
+        user_id,
+        target_path: request.path,
+        total_size: request.total_size,
+        chunk_size: request.chunk_size,
+        checksum: request.checksum,
+        overwrite,
+        created_at: now,
+        updated_at: now,
+        hash_state: None,
+    };
+
+    database.create_upload_session(&session).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(upload_session_status(&session, Vec::new()))))
+}
+
+pub async fn upload_session_chunk(
+    State(filesystem): State<FileSystemService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path((session_id, chunk_index)): Path<(String, u32)>,
+    body: axum::body::Bytes,
+) -> Result<Json<ApiResponse<UploadSessionStatus>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let session = get_owned_upload_session(&database, &session_id, user_id).await?;
+    check_scope(&claims, Action::Write, &session.target_path)?;
+
+    if chunk_index >= session.total_chunks() {
+        return Ok(Json(ApiResponse::error(format!(
+            "Chunk index {} out of range (session has {} chunks)",
+            chunk_index,
+            session.total_chunks()
+        ))));
+    }
+
+    let expected_length = session.chunk_length(chunk_index);
+    if body.len() as u64 != expected_length {
+        return Ok(Json(ApiResponse::error(format!(
+            "Chunk {} must be {} bytes, got {}",
+            chunk_index,
+            expected_length,
+            body.len()
+        ))));
+    }
+
+    let offset = chunk_index as u64 * session.chunk_size;
+    filesystem.write_session_chunk(session.id, offset, &body).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    database.mark_upload_chunk_received(session.id, chunk_index).await
+        .map_err(database_error)?;
+
+    let received = database.get_received_chunks(session.id).await
+        .map_err(database_error)?;
+
+    // Hash the chunk now, so completing the session doesn't read the file back
+    let mut hash = session.hash_state
+        .as_deref()
+        .and_then(ResumableSha256::from_bytes)
+        .unwrap_or_default();
+    let hashed = hash.len();
+    filesystem
+        .extend_session_hash(session.id, &mut hash, offset, &body, session.contiguous_bytes(&received))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if hash.len() != hashed {
+        database.update_upload_session_hash(session.id, &hash.to_bytes()).await
+            .map_err(database_error)?;
+    }
+
+    Ok(Json(ApiResponse::success(upload_session_status(&session, received))))
+}
+
+pub async fn get_upload_session_status(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(session_id): Path<String>,
+) -> Result<Json<ApiResponse<UploadSessionStatus>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let session = get_owned_upload_session(&database, &session_id, user_id).await?;
+
+    let received = database.get_received_chunks(session.id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(upload_session_status(&session, received))))
+}
+
+pub async fn complete_upload_session(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(session_id): Path<String>,
+    request: Option<Json<CompleteUploadRequest>>,
+) -> Result<Json<ApiResponse<UploadResponse>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let session = get_owned_upload_session(&database, &session_id, user_id).await?;
+    check_scope(&claims, Action::Write, &session.target_path)?;
+    let filesystem = home_filesystem(&filesystem, session.user_id)?;
+    let storage = home_storage(storage.as_ref(), session.user_id)?;
+
+    if let Some(missing) = missing_chunks(&database, &session).await? {
+        return Ok(Json(ApiResponse::error(missing)));
+    }
+
+    let expected_checksum = request.and_then(|Json(request)| request.checksum);
+    let metadata = commit_upload_session(&filesystem, storage.as_ref(), &database, &claims, &session, expected_checksum).await?;
+
+    let response = UploadResponse {
+        file_id: metadata.id,
+        path: metadata.path,
+        size: metadata.size,
+        checksum: metadata.checksum,
+        deduplicated: false,
+        conflict: false,
+        conflict_policy: None,
+    };
+
+    Ok(Json(ApiResponse::success(response)))
+}
+
+/// What is still missing from a session, or None once every chunk is in
+async fn missing_chunks(database: &Database, session: &UploadSession) -> Result<Option<String>, StatusCode> {
+    let received = database.get_received_chunks(session.id).await
+        .map_err(database_error)?;
+
+    let status = upload_session_status(session, received);
+    Ok((!status.complete).then(|| format!(
+        "Upload incomplete: {} of {} chunks received",
+        status.received_chunks.len(),
+        status.total_chunks
+    )))
+}
+
+/// Assemble a session whose chunks are all in at its target path, record
+/// the file and end the session
+async fn commit_upload_session(
+    filesystem: &FileSystemService,
+    storage: &dyn StorageBackend,
+    database: &Database,
+    claims: &Claims,
+    session: &UploadSession,
+    expected_checksum: Option<String>,
+) -> Result<FileMetadata, ApiError> {
+    let user_id = session.user_id;
+
+    // Someone may have locked the file while the chunks were arriving
+    check_file_lock(database, claims, lock_home(filesystem), &session.target_path).await?;
+
+    // Other uploads may have used up the quota since the session was created
+    let quota = get_quota_usage(filesystem, database, user_id).await?;
+    let freed = replaced_file_size(database, user_id, &session.target_path, session.overwrite).await?;
+    if !quota.allows(session.total_size, freed) {
+        return Err(quota_exceeded(&quota, session.total_size));
+    }
+
+    if session.overwrite {
+        preserve_previous_version(filesystem, database, user_id, &session.target_path).await?;
+    }
+
+    let staged = stage_session_upload(filesystem, database, session, expected_checksum).await?;
+    let metadata = store_staged_file(storage, database, user_id, &session.target_path, staged).await?;
+
+    database.delete_upload_session(session.id).await
+        .map_err(database_error)?;
+
+    Ok(metadata)
+}
+
+/// Assemble a session's chunks, checked against `expected_checksum` or
+/// else the session's own. Corrupt data ends the session.
+async fn stage_session_upload(
+    filesystem: &FileSystemService,
+    database: &Database,
+    session: &UploadSession,
+    expected_checksum: Option<String>,
+) -> Result<StagedUpload, ApiError> {
+    let expected_checksum = expected_checksum.or(session.checksum.clone());
+    let hash = session.hash_state.as_deref().and_then(ResumableSha256::from_bytes);
+    let result = filesystem
+        .stage_upload_session(session.id, hash, expected_checksum.as_deref())
+        .await;
+
+    match result {
+        Ok(staged) => Ok(staged),
+        Err(e) => {
+            if let Some(FileSystemError::ChecksumMismatch { expected, actual }) = e.downcast_ref::<FileSystemError>() {
+                // The assembled data is corrupt, so the client has to start over
+                let _ = filesystem.discard_upload_session(session.id).await;
+                let _ = database.delete_upload_session(session.id).await;
+                return Err(checksum_mismatch(expected, actual));
+            }
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+        }
+    }
+}
+
+/// Block size the signature was taken with, on `/files/signature` responses
+const BLOCK_SIZE_HEADER: &str = "x-synker-block-size";
+
+/// Block size of `/files/signature` and how large files must be before
+/// deltas are worth it
+#[derive(Clone, Copy)]
+pub struct DeltaSettings {
+    pub block_size: u32,
+    pub min_file_size: u64,
+}
+
+fn too_small_for_delta(settings: &DeltaSettings) -> ApiError {
+    ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        format!("Files under {} bytes are uploaded in full", settings.min_file_size),
+    )
+}
+
+/// Block signature of the server's copy of a file, in librsync's format, to
+/// compute a delta against. The copy's SHA-256 comes along in
+/// `x-synker-base-checksum`.
+pub async fn get_file_signature(
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    State(settings): State<DeltaSettings>,
+    Extension(claims): Extension<Claims>,
+    Path(file_path): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let file_path = normalize_path(&urlencoding::decode(&file_path)
+        .map_err(|_| StatusCode::BAD_REQUEST)?);
+    check_scope(&claims, Action::Read, &file_path)?;
+
+    let user_id = shared_target_user(&database, &claims, &params, Action::Read, &file_path).await?;
+    let storage = home_storage(storage.as_ref(), user_id)?;
+
+    let metadata = storage.metadata(&file_path).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    if metadata.is_directory {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    if metadata.size < settings.min_file_size {
+        return Err(too_small_for_delta(&settings));
+    }
+
+    // Signed as it streams past, so large files never sit in memory
+    let mut stream = storage.get_stream(&file_path, None).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let mut builder = SignatureBuilder::new(settings.block_size);
+    while let Some(chunk) = stream.try_next().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        builder.update(&chunk);
+    }
+    let (signature, checksum) = builder.finish();
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(BLOCK_SIZE_HEADER, settings.block_size)
+        .header(BASE_CHECKSUM_HEADER, checksum)
+        .body(axum::body::Body::from(signature))
+        .unwrap())
+}
+
+/// Rebuild a file from the server's copy and a delta against its signature.
+/// The result must hash to the `x-synker-checksum` sent along, or nothing
+/// changes. The old content is kept as a version, as with any overwrite.
+pub async fn apply_file_delta(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    State(settings): State<DeltaSettings>,
+    Extension(claims): Extension<Claims>,
+    Path(file_path): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    delta: axum::body::Bytes,
+) -> Result<Json<ApiResponse<UploadResponse>>, ApiError> {
+    let file_path = normalize_path(&urlencoding::decode(&file_path)
+        .map_err(|_| StatusCode::BAD_REQUEST)?);
+    check_scope(&claims, Action::Write, &file_path)?;
+
+    let user_id = shared_target_user(&database, &claims, &params, Action::Write, &file_path).await?;
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+    let storage = home_storage(storage.as_ref(), user_id)?;
+
+    let expected_checksum = headers.get(CHECKSUM_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, format!("A delta needs the {} header", CHECKSUM_HEADER)))?;
+
+    check_upload_path(&filesystem, &file_path)?;
+    check_file_lock(&database, &claims, lock_home(&filesystem), &file_path).await?;
+
+    let base = storage.metadata(&file_path).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    if base.is_directory {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    if base.size < settings.min_file_size {
+        return Err(too_small_for_delta(&settings));
+    }
+    // Nothing is saved over sending the file itself
+    if delta.len() as u64 >= base.size {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "The delta is as large as the file; upload it in full instead",
+        ));
+    }
+
+    // A delta only fits the version its signature was taken from
+    if let Some(base_checksum) = headers.get(BASE_CHECKSUM_HEADER).and_then(|value| value.to_str().ok()) {
+        let current = match database.get_file_metadata_by_path(user_id, &file_path).await {
+            Ok(Some(current)) => current,
+            Ok(None) => base.clone(),
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+        };
+        if !current.checksum.eq_ignore_ascii_case(base_checksum.trim()) {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                format!("{} changed since its signature was taken", file_path),
+            ).with_data(current));
+        }
+    }
+
+    let commands = parse_delta(&delta, base.size)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let quota = get_quota_usage(&filesystem, &database, user_id).await?;
+    let freed = replaced_file_size(&database, user_id, &file_path, true).await?;
+    let allowance = quota.remaining(freed);
+
+    // Written to a temp file and moved into place, like any upload
+    let mut upload = filesystem.begin_upload().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let within_quota = |upload: &UploadWriter| allowance.map_or(true, |allowance| upload.size() <= allowance);
+    let written = async {
+        for command in commands {
+            match command {
+                DeltaCommand::Literal(range) => upload.write_chunk(&delta[range]).await?,
+                DeltaCommand::Copy { offset, length } => {
+                    let mut stream = storage.get_stream(&file_path, Some(offset..offset + length)).await?;
+                    while let Some(chunk) = stream.try_next().await? {
+                        upload.write_chunk(&chunk).await?;
+                        if !within_quota(&upload) {
+                            return Ok(false);
+                        }
+                    }
+                }
+            }
+            if !within_quota(&upload) {
+                return Ok(false);
+            }
+        }
+        Ok::<_, anyhow::Error>(true)
+    }
+    .await;
+
+    match written {
+        Ok(true) => {}
+        Ok(false) => {
+            let requested = upload.size();
+            upload.abort().await;
+            return Err(quota_exceeded(&quota, requested));
+        }
+        Err(e) => {
+            upload.abort().await;
+            return match e.downcast_ref::<FileSystemError>() {
+                Some(FileSystemError::FileTooLarge) => Err(StatusCode::PAYLOAD_TOO_LARGE.into()),
+                _ => Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+            };
+        }
+    }
+
+    let actual = upload.checksum();
+    if !expected_checksum.eq_ignore_ascii_case(&actual) {
+        upload.abort().await;
+        return Err(checksum_mismatch(&expected_checksum, &actual));
+    }
+
+    preserve_previous_version(&filesystem, &database, user_id, &file_path).await?;
+
+    let staged = upload.finish().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let metadata = store_staged_file(storage.as_ref(), &database, user_id, &file_path, staged).await?;
+
+    let response = UploadResponse {
+        file_id: metadata.id,
+        path: metadata.path,
+        size: metadata.size,
+        checksum: metadata.checksum,
+        deduplicated: false,
+        conflict: false,
+        conflict_policy: None,
+    };
+
+    Ok(Json(ApiResponse::success(response)))
+}
+
+/// The byte range asked for by a `Range: bytes=...` header, end exclusive.
+/// `Err` means the range can't be satisfied; multiple ranges are not supported
+/// and get the whole file.
+fn requested_range(headers: &HeaderMap, size: u64) -> Result<Option<Range<u64>>, ()> {
+    let Some(spec) = headers.get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+    else {
+        return Ok(None);
+    };
+
+    if spec.contains(',') {
+        return Ok(None);
+    }
+
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let range = match (start.trim(), end.trim()) {
+        // The last `suffix` bytes
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            size.saturating_sub(suffix)..size
+        }
+        (start, "") => start.parse().map_err(|_| ())?..size,
+        (start, end) => {
+            let end: u64 = end.parse().map_err(|_| ())?;
+            start.parse().map_err(|_| ())?..(end + 1).min(size)
+        }
+    };
+
+    if range.start >= range.end {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+pub async fn download_file(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    anonymous: Option<Extension<AnonymousAccess>>,
+    Path(file_path): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    request_headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    // Decode the file path (it might be URL encoded)
+    let file_path = normalize_path(&urlencoding::decode(&file_path)
+        .map_err(|_| StatusCode::BAD_REQUEST)?);
+    check_scope(&claims, Action::Read, &file_path)?;
+
+    let storage = match anonymous {
+        Some(Extension(access)) => {
+            check_anonymous_path(&access, &file_path)?;
+            storage
+        }
+        None => {
+            let user_id = shared_target_user(&database, &claims, &params, Action::Read, &file_path).await?;
+            owned_file(&database, &filesystem, user_id, &file_path).await?;
+            home_storage(storage.as_ref(), user_id)?
+        }
+    };
+
+    let file_metadata = storage.metadata(&file_path).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    if file_metadata.is_directory {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // Copies and moves keep modification times, so only the content tells
+    // whether the client's copy is current
+    let last_modified = http_date(file_metadata.modified_at);
+    let etag = (!file_metadata.checksum.is_empty()).then(|| format!("\"{}\"", file_metadata.checksum));
+    if let Some(etag) = etag.as_deref().filter(|etag| etag_matches(&request_headers, etag)) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag)
+            .header(header::LAST_MODIFIED, last_modified)
+            .body(axum::body::Body::empty())
+            .unwrap());
+    }
+
+    let range = match requested_range(&request_headers, file_metadata.size) {
+        Ok(range) => range,
+        Err(()) => {
+            return Ok(Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", file_metadata.size))
+                .body(axum::body::Body::empty())
+                .unwrap());
+        }
+    };
+    let length = range.as_ref().map_or(file_metadata.size, |range| range.end - range.start);
+
+    let stream = storage.get_stream(&file_path, range.clone()).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    // Large files are streamed so they never sit in memory whole
+    let body = if filesystem.should_stream(length) {
+        axum::body::Body::from_stream(stream)
+    } else {
+        let file_data = stream
+            .try_fold(Vec::new(), |mut data, chunk| async move {
+                data.extend_from_slice(&chunk);
+                Ok(data)
+            })
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        axum::body::Body::from(file_data)
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        file_metadata.mime_type.parse().unwrap(),
+    );
+    headers.insert(
+        header::CONTENT_LENGTH,
+        length.into(),
+    );
+    headers.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
+    headers.insert(header::LAST_MODIFIED, last_modified.parse().unwrap());
+    if let Some(etag) = etag.and_then(|etag| etag.parse().ok()) {
+        headers.insert(header::ETAG, etag);
+    }
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", file_metadata.name).parse().unwrap(),
+    );
+
+    let status = match &range {
+        Some(range) => {
+            headers.insert(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", range.start, range.end - 1, file_metadata.size).parse().unwrap(),
+            );
+            StatusCode::PARTIAL_CONTENT
+        }
+        None => StatusCode::OK,
+    };
+
+    Ok(Response::builder()
+        .status(status)
+        .headers(headers)
+        .body(body)
+        .unwrap())
+}
+
+pub async fn get_thumbnail(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(thumbnails): State<ThumbnailService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(file_path): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let file_path = normalize_path(&urlencoding::decode(&file_path)
+        .map_err(|_| StatusCode::BAD_REQUEST)?);
+    check_scope(&claims, Action::Read, &file_path)?;
+    require_local_storage(storage.as_ref(), "Thumbnails")?;
+
+    let user_id = shared_target_user(&database, &claims, &params, Action::Read, &file_path).await?;
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+
+    let size = params.get("size")
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or(256)
+        .clamp(16, 1024);
+
+    let file_metadata = filesystem.get_file_metadata(&file_path).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    if file_metadata.is_directory || !ThumbnailService::is_supported(&file_metadata.mime_type) {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
+    }
+
+    let source = filesystem.resolve_path(&file_path)
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let data = thumbnails.get_or_create(&source, &file_metadata.checksum, size).await
+        .map_err(|e| match e.downcast_ref::<ThumbnailError>() {
+            Some(ThumbnailError::SourceTooLarge { .. }) => StatusCode::UNPROCESSABLE_ENTITY,
+            None => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, "image/jpeg".parse().unwrap());
+    headers.insert(header::CONTENT_LENGTH, data.len().into());
+    headers.insert(header::CACHE_CONTROL, "private, max-age=86400".parse().unwrap());
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .headers(headers)
+        .body(axum::body::Body::from(data))
+        .unwrap())
+}
+
+/// Owners of the nearest tracked directory above `path`, or none when no
+/// directory above it has a row
+pub(crate) async fn nearest_directory_owners(database: &Database, path: &str) -> anyhow::Result<Vec<Uuid>> {
+    let mut ancestor = parent_path(path);
+    while let Some(directory) = ancestor {
+        let owners: Vec<Uuid> = database.get_file_metadata_for_path(directory).await?
+            .into_iter()
+            .filter(|row| row.is_directory)
+            .map(|row| row.owner_id)
+            .collect();
+        if !owners.is_empty() {
+            return Ok(owners);
+        }
+        ancestor = parent_path(directory);
+    }
+
+    Ok(Vec::new())
+}
+
+/// A response that a client polling with `If-None-Match` or
+/// `If-Modified-Since` may already have
+pub enum Conditional<T> {
+    /// The body, tagged when later requests can be answered with a 304
+    Modified(T, Option<String>),
+    /// 304 with an empty body: the client's copy is current
+    NotModified(String),
+}
+
+impl<T: IntoResponse> IntoResponse for Conditional<T> {
+    fn into_response(self) -> Response {
+        let (mut response, etag) = match self {
+            Conditional::Modified(body, etag) => (body.into_response(), etag),
+            Conditional::NotModified(etag) => (StatusCode::NOT_MODIFIED.into_response(), Some(etag)),
+        };
+        if let Some(etag) = etag.and_then(|etag| etag.parse().ok()) {
+            response.headers_mut().insert(header::ETAG, etag);
+        }
+        response
+    }
+}
+
+/// A strong ETag over `parts`, which should be metadata and never content
+fn etag_of(parts: impl IntoIterator<Item = String>) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    let digest = format!("{:x}", hasher.finalize());
+    format!("\"{}\"", &digest[..32])
+}
+
+/// Whether `If-None-Match` names `etag`, compared weakly as for GET
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers.get_all(header::IF_NONE_MATCH).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// `Last-Modified` for `modified_at`
+fn http_date(modified_at: chrono::DateTime<Utc>) -> String {
+    modified_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Page sizes of listings: what `limit` defaults to, and the most it may be
+#[derive(Clone, Copy)]
+pub struct PageLimits {
+    pub default_size: u32,
+    pub max_size: u32,
+}
+
+/// Which page of a listing a request wants, from `limit`, `offset` and
+/// `page_token`. Tokens carry the sort key of the last entry handed out, so
+/// following them costs the same on every page; `offset` skips entries
+/// from there.
+struct PageRequest {
+    limit: u32,
+    offset: u32,
+    after: Option<String>,
+}
+
+impl PageLimits {
+    /// 400 for a `page_token` this server didn't hand out
+    fn request(&self, params: &HashMap<String, String>) -> Result<PageRequest, ApiError> {
+        let limit = params.get("limit")
+            .and_then(|limit| limit.parse::<u32>().ok())
+            .unwrap_or(self.default_size)
+            .clamp(1, self.max_size);
+        let offset = params.get("offset")
+            .and_then(|offset| offset.parse::<u32>().ok())
+            .unwrap_or(0);
+        let after = params.get("page_token")
+            .map(|token| {
+                URL_SAFE_NO_PAD.decode(token).ok()
+                    .and_then(|key| String::from_utf8(key).ok())
+                    .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Invalid page_token"))
+            })
+            .transpose()?;
+        Ok(PageRequest { limit, offset, after })
+    }
+}
+
+impl PageRequest {
+    /// Entries to fetch: one more than the page holds, to tell whether
+    /// another page follows
+    fn fetch(&self) -> i64 {
+        self.limit as i64 + 1
+    }
+
+    /// The sort key of a time-ordered listing's token: a time and an id
+    fn after_time(&self) -> Result<Option<(chrono::DateTime<Utc>, Uuid)>, ApiError> {
+        self.after.as_deref()
+            .map(|key| parse_time_key(key)
+                .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Invalid page_token")))
+            .transpose()
+    }
+
+    /// A page from `items` as fetched, up to `fetch()` of them, keyed for
+    /// the next token by `key`
+    fn page<T>(&self, mut items: Vec<T>, total: u64, key: impl Fn(&T) -> String) -> Paginated<T> {
+        let next_token = (items.len() > self.limit as usize).then(|| {
+            items.truncate(self.limit as usize);
+            items.last().map(|last| URL_SAFE_NO_PAD.encode(key(last)))
+        }).flatten();
+        Paginated { items, total, next_token }
+    }
+}
+
+impl PageRequest {
+    /// Where a listing without a key of its own to sort by, like ranked
+    /// search results, starts. Its tokens carry the position of the page
+    /// after.
+    fn start(&self) -> Result<u32, ApiError> {
+        let after = self.after.as_deref()
+            .map(str::parse::<u32>)
+            .transpose()
+            .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "Invalid page_token"))?;
+        Ok(after.unwrap_or(0).saturating_add(self.offset))
+    }
+
+    /// A page of such a listing, fetched from `start()`
+    fn page_from_start<T>(&self, items: Vec<T>, total: u64) -> Result<Paginated<T>, ApiError> {
+        let next = self.start()?.saturating_add(self.limit);
+        Ok(self.page(items, total, |_| next.to_string()))
+    }
+}
+
+/// Page token key of an entry in a time-ordered listing
+fn time_key(time: chrono::DateTime<Utc>, id: Uuid) -> String {
+    format!("{} {}", time.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true), id)
+}
+
+/// The time and id of a key made by `time_key`
+fn parse_time_key(key: &str) -> Option<(chrono::DateTime<Utc>, Uuid)> {
+    let (time, id) = key.split_once(' ')?;
+    Some((
+        chrono::DateTime::parse_from_rfc3339(time).ok()?.with_timezone(&Utc),
+        Uuid::parse_str(id).ok()?,
+    ))
+}
+
+pub async fn list_files(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    State(pages): State<PageLimits>,
+    Extension(claims): Extension<Claims>,
+    anonymous: Option<Extension<AnonymousAccess>>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Conditional<Json<ApiResponse<Paginated<ListedFile>>>>, ApiError> {
+    let path = normalize_path(params.get("path").map_or("/", String::as_str));
+    check_scope(&claims, Action::Read, &path)?;
+    let page = pages.request(&params)?;
+    let recursive = params.get("recursive")
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+
+    // Anonymous visitors browse the shared tree, which no user owns
+    let mut recorded = false;
+    let (filesystem, storage, user_id) = match anonymous {
+        Some(Extension(access)) => {
+            check_anonymous_path(&access, &path)?;
+            if recursive {
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, "Anonymous listings can't be recursive"));
+            }
+            (filesystem, storage, None)
+        }
+        None => {
+            let user_id = shared_target_user(&database, &claims, &params, Action::Read, &path).await?;
+            // Folders nobody recorded yet still list; someone else's don't
+            let folder = path.trim_end_matches('/');
+            recorded = folder.is_empty();
+            if !folder.is_empty() {
+                match owned_file(&database, &filesystem, user_id, folder).await {
+                    Ok(folder) => recorded = folder.is_directory,
+                    Err(StatusCode::NOT_FOUND) => {}
+                    Err(status) => return Err(status.into()),
+                }
+            }
+            let home = home_filesystem(&filesystem, user_id)?;
+            (home, home_storage(storage.as_ref(), user_id)?, Some(user_id))
+        }
+    };
+
+    let include_ignored = params.get("include_ignored")
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+    let filesystem = visible_filesystem(filesystem, &database, &claims, &params).await?;
+
+    // Names are unique within a folder, so they order pages on their own;
+    // recursive listings go by path
+    let key = |file: &FileMetadata| if recursive { file.path.clone() } else { file.name.clone() };
+    // Recursive listings and recorded folders page from the records, so the
+    // tree isn't walked. Ignored entries aren't recorded, so listings that
+    // want them, like folders nobody recorded yet, read storage and record
+    // what they find.
+    let from_records = recursive || (recorded && !include_ignored);
+    let mut files = match user_id {
+        Some(user_id) if from_records => {
+            let (after, offset) = (page.after.as_deref(), page.offset as i64);
+            let (files, total) = if recursive {
+                (
+                    database.get_descendants_page(user_id, &path, after, page.fetch(), offset).await,
+                    database.count_descendants(user_id, &path).await,
+                )
+            } else {
+                (
+                    database.get_children_page(user_id, &path, after, page.fetch(), offset).await,
+                    database.count_children(user_id, &path).await,
+                )
+            };
+            let mut files = page.page(files.map_err(database_error)?, total.map_err(database_error)?, key);
+            filesystem.filter_ignored(&mut files.items);
+            files
+        }
+        _ => {
+            // Listing never hashes file contents; checksums come from the cache when valid
+            let mut files = storage.list(&path).await
+                .map_err(|_| StatusCode::NOT_FOUND)?;
+            filesystem.filter_ignored(&mut files);
+
+            let total = files.len() as u64;
+            files.sort_by_key(key);
+            let files: Vec<FileMetadata> = files.into_iter()
+                .filter(|file| page.after.as_ref().map_or(true, |after| key(file) > *after))
+                .skip(page.offset as usize)
+                .take(page.fetch() as usize)
+                .collect();
+            page.page(files, total, key)
+        }
+    };
+
+    // Stars and tags are the caller's own, even in someone else's files
+    let with_tags = params.get("include")
+        .is_some_and(|include| include.split(',').any(|part| part.trim() == "tags"));
+    let mut favorites = HashSet::new();
+    let mut tags = HashMap::new();
+    if let Some(user_id) = user_id {
+        if !from_records {
+            database.assign_stable_ids(user_id, &mut files.items).await
+                .map_err(database_error)?;
+        }
+        let caller = Uuid::parse_str(&claims.sub)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let ids: Vec<Uuid> = files.items.iter().map(|file| file.id).collect();
+        favorites = database.get_favorite_ids(caller, &ids).await
+            .map_err(database_error)?;
+        if with_tags {
+            tags = database.get_file_tags(caller, &ids).await
+                .map_err(database_error)?;
+        }
+    }
+    let files = Paginated {
+        items: files.items.into_iter()
+            .map(|file| ListedFile {
+                is_favorite: favorites.contains(&file.id),
+                tags: with_tags.then(|| tags.remove(&file.id).unwrap_or_default()),
+                file,
+            })
+            .collect(),
+        total: files.total,
+        next_token: files.next_token,
+    };
+
+    // Anonymous listings carry throwaway ids, so only stable ones count
+    let etag = etag_of(std::iter::once(files.total.to_string()).chain(files.items.iter().map(|listed| {
+        let file = &listed.file;
+        let id = if user_id.is_some() { file.id.to_string() } else { String::new() };
+        format!(
+            "{}|{}|{}|{}|{}|{}|{}",
+            id, file.path, file.size, file.modified_at.timestamp_micros(), file.is_directory, listed.is_favorite,
+            listed.tags.as_deref().map(|tags| tags.join(",")).unwrap_or_default(),
+        )
+    })));
+    if etag_matches(&headers, &etag) {
+        return Ok(Conditional::NotModified(etag));
+    }
+
+    Ok(Conditional::Modified(Json(ApiResponse::success(files)), Some(etag)))
+}
+
+/// The caller's files found by name and details, a page at a time. `q`
+/// matches names with words beginning with each of its words; `mime` (a
+/// type or a family like `image/*`), `min_size`, `max_size`,
+/// `modified_after` (RFC 3339), `path_prefix` and `tag` narrow the results.
+/// At least one of them is needed.
+pub async fn search_files(
+    State(filesystem): State<FileSystemService>,
+    State(database): State<Database>,
+    State(pages): State<PageLimits>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<Paginated<FileMetadata>>>, ApiError> {
+    let user_id = target_user(&database, &claims, &params).await?;
+    let page = pages.request(&params)?;
+
+    let size = |name: &str| {
+        params.get(name)
+            .map(|size| size.parse::<u64>().map_err(|_| {
+                ApiError::new(StatusCode::BAD_REQUEST, format!("{} must be a number of bytes", name))
+            }))
+            .transpose()
+    };
+    let search = FileSearch {
+        query: params.get("q").filter(|q| !q.trim().is_empty()).cloned(),
+        mime_type: params.get("mime").filter(|mime| !mime.is_empty()).cloned(),
+        min_size: size("min_size")?,
+        max_size: size("max_size")?,
+        modified_after: params.get("modified_after")
+            .map(|after| chrono::DateTime::parse_from_rfc3339(after).map(|after| after.with_timezone(&Utc)))
+            .transpose()
+            .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "modified_after must be an RFC 3339 time"))?,
+        path_prefix: params.get("path_prefix")
+            .map(|prefix| path_components(prefix).map(|components| format!("/{}", components.join("/"))))
+            .map(|prefix| prefix.ok_or(StatusCode::BAD_REQUEST))
+            .transpose()?,
+        tag: params.get("tag").map(|tag| tag_name(tag)).transpose()?,
+    };
+    if search.query.is_none() && search.mime_type.is_none() && search.min_size.is_none()
+        && search.max_size.is_none() && search.modified_after.is_none() && search.path_prefix.is_none()
+        && search.tag.is_none()
+    {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Give q or a filter to search by"));
+    }
+
+    let (files, total) = database.search_files(user_id, &search, page.fetch(), page.start()? as i64).await
+        .map_err(|e| {
+            tracing::error!("Search for {} failed: {}", user_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let mut files = page.page_from_start(files, total)?;
+
+    // Like listings, these can leave a page short
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+    let filesystem = visible_filesystem(filesystem, &database, &claims, &params).await?;
+    filesystem.filter_ignored(&mut files.items);
+    files.items.retain(|file| check_scope(&claims, Action::Read, &file.path).is_ok());
+
+    Ok(Json(ApiResponse::success(files)))
+}
+
+pub async fn list_children(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(file_id): Path<String>,
+) -> Result<Json<ApiResponse<Vec<FileMetadata>>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let directory = get_owned_file(&database, &file_id, user_id).await?;
+    check_scope(&claims, Action::Read, &directory.path)?;
+    if !directory.is_directory {
+        return Ok(Json(ApiResponse::error("Not a directory".to_string())));
+    }
+
+    let children = database.get_children(directory.id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(children)))
+}
+
+pub async fn create_folder(
+    State(filesystem): State<FileSystemService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(request): Json<CreateFolderRequest>,
+) -> Result<Json<ApiResponse<FileMetadata>>, ApiError> {
+    let folder_path = normalize_path(&if request.path.ends_with('/') {
+        format!("{}{}", request.path, request.name)
+    } else {
+        format!("{}/{}", request.path, request.name)
+    });
+    check_scope(&claims, Action::Write, &folder_path)?;
+
+    let user_id = shared_target_user(&database, &claims, &params, Action::Write, &folder_path).await?;
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+
+    let metadata = make_directory(&filesystem, &database, user_id, &folder_path).await?;
+
+    Ok(Json(ApiResponse::success(metadata)))
+}
+
+/// Create a folder and record it for `user_id`
+async fn make_directory(
+    filesystem: &FileSystemService,
+    database: &Database,
+    user_id: Uuid,
+    folder_path: &str,
+) -> Result<FileMetadata, ApiError> {
+    if let Err(e) = filesystem.check_file_name(folder_path) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+
+    let mut metadata = filesystem.create_directory(folder_path).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    metadata.owner_id = user_id;
+    metadata.parent_id = database.ensure_parent_directories(user_id, &metadata.path).await
+        .map_err(database_error)?;
+
+    // Reuses the existing row if this folder was already known
+    save_uploaded_metadata(database, &mut metadata).await?;
+
+    Ok(metadata)
+}
+
+/// Whether the token carries a permission the user still holds. Used
+/// where a route is open to everyone but some of what it does isn't.
+async fn user_has_permission(
+    database: &Database,
+    claims: &Claims,
+    permission: Permission,
+) -> Result<bool, StatusCode> {
+    let user = database.get_user_by_username(&claims.username).await
+        .map_err(database_error)?;
+
+    Ok(claims.has(permission) && user.is_some_and(|user| user.has_permission(permission)))
+}
+
+/// Turn away personal access tokens and impersonating admins from account
+/// management
+fn require_login(claims: &Claims) -> Result<(), StatusCode> {
+    if claims.is_api_token() || claims.is_impersonation() {
+        Err(StatusCode::FORBIDDEN)
+    } else {
+        Ok(())
+    }
+}
+
+pub async fn delete_file(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(file_path): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<()>>, ApiError> {
+    let file_path = normalize_path(&urlencoding::decode(&file_path)
+        .map_err(|_| StatusCode::BAD_REQUEST)?);
+    check_scope(&claims, Action::Delete, &file_path)?;
+
+    let user_id = shared_target_user(&database, &claims, &params, Action::Delete, &file_path).await?;
+    owned_file(&database, &filesystem, user_id, &file_path).await?;
+    let home_id = lock_home(&home_filesystem(&filesystem, user_id)?);
+    let storage = home_storage(storage.as_ref(), user_id)?;
+
+    let permanent = params.get("permanent")
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+
+    // Hard deletes skip the trash and are reserved for admins
+    if permanent {
+        if !user_has_permission(&database, &claims, Permission::Admin).await? {
+            return Err(StatusCode::FORBIDDEN.into());
+        }
+
+        // Retrying after the records failed finds the content already gone
+        if storage.metadata(&file_path).await.is_ok() {
+            storage.delete(&file_path).await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+
+        let deleted = database.delete_metadata_under_path(user_id, &file_path).await
+            .map_err(|e| {
+                tracing::error!("Deleted {} but not its records: {}", file_path, e);
+                ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("{} was deleted but is still on record; delete it again to finish", file_path),
+                )
+            })?;
+        remove_unused_versions(&filesystem, &deleted).await;
+    } else {
+        move_path_to_trash(storage.as_ref(), &database, user_id, &file_path).await?;
+    }
+
+    // Locks on what is gone would only hold up whatever is stored there next
+    if let Err(e) = database.delete_file_locks_under(home_id, &file_path).await {
+        tracing::warn!("Failed to release locks under {}: {}", file_path, e);
+    }
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+pub(crate) fn file_name(path: &str) -> String {
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or("")
+        .to_string()
+}
+
+/// Validation failures are reported in the body with a 200, like the rest of the API
+fn rejected(message: &str) -> ApiError {
+    ApiError::new(StatusCode::OK, message)
+}
+
+/// Take the write turn for a change spanning several rows
+async fn begin(database: &Database) -> Result<DatabaseTransaction<'_>, ApiError> {
+    database.begin().await.map_err(|e| {
+        tracing::error!("Failed to begin a transaction: {}", e);
+        database_error(e).into()
+    })
+}
+
+/// A move, copy or delete done on disk, with the metadata writes that
+/// record it and how to take it back if they can't be
+struct DiskChange {
+    /// What a moved or copied entry became
+    metadata: Option<FileMetadata>,
+    writes: Vec<MetadataWrite>,
+    undo: Undo,
+}
+
+enum Undo {
+    Trashed(TrashEntry),
+    Moved { from: String, to: String, replaced: Option<TrashEntry> },
+    Copied { to: String, replaced: Option<TrashEntry> },
+}
+
+impl Undo {
+    /// Where the entry went, whose parent folders are recorded with it
+    fn destination(&self) -> Option<&str> {
+        match self {
+            Undo::Trashed(_) => None,
+            Undo::Moved { to, .. } | Undo::Copied { to, .. } => Some(to),
+        }
+    }
+
+    async fn run(&self, filesystem: &FileSystemService, storage: &dyn StorageBackend) {
+        let replaced = match self {
+            Undo::Trashed(entry) => Some(entry),
+            Undo::Moved { from, to, replaced } => {
+                if let Err(e) = storage.rename(to, from).await {
+                    tracing::error!("Failed to move {} back to {}: {}", to, from, e);
+                }
+                replaced.as_ref()
+            }
+            Undo::Copied { to, replaced } => {
+                if let Err(e) = filesystem.delete_file(to).await {
+                    tracing::error!("Failed to remove the copy at {}: {}", to, e);
+                }
+                replaced.as_ref()
+            }
+        };
+        if let Some(entry) = replaced {
+            restore_replaced(storage, entry).await;
+        }
+    }
+}
+
+/// Record `change` in a transaction of its own, taken only once the disk
+/// work is done, so other writes never wait on a rename or a copy. If it
+/// can't be recorded, the disk work is undone.
+async fn record_change(
+    filesystem: &FileSystemService,
+    storage: &dyn StorageBackend,
+    database: &Database,
+    user_id: Uuid,
+    change: &mut DiskChange,
+) -> Result<(), ApiError> {
+    let Err(e) = store_change(database, user_id, change).await else {
+        return Ok(());
+    };
+    tracing::error!("Failed to record metadata changes: {}", e);
+    change.undo.run(filesystem, storage).await;
+
+    Err(match database_error(e) {
+        StatusCode::SERVICE_UNAVAILABLE => StatusCode::SERVICE_UNAVAILABLE.into(),
+        _ => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to record the change"),
+    })
+}
+
+async fn store_change(database: &Database, user_id: Uuid, change: &mut DiskChange) -> anyhow::Result<()> {
+    let mut tx = database.begin().await?;
+    if let Some(to) = change.undo.destination() {
+        let parent_id = tx.ensure_parent_rows(user_id, to).await?;
+        for write in change.writes.iter_mut() {
+            if let MetadataWrite::Insert(metadata) | MetadataWrite::Move { metadata, .. } = write {
+                if metadata.path == to {
+                    metadata.parent_id = parent_id;
+                }
+            }
+        }
+        if let Some(metadata) = change.metadata.as_mut() {
+            metadata.parent_id = parent_id;
+        }
+    }
+    tx.apply_metadata_writes(&change.writes).await?;
+    tx.commit().await
+}
+
+/// Move `path` into the user's trash and record the entry so it can be restored
+async fn move_path_to_trash(
+    storage: &dyn StorageBackend,
+    database: &Database,
+    user_id: Uuid,
+    path: &str,
+) -> Result<(), ApiError> {
+    let entry = trash_path(storage, database, user_id, path).await?;
+
+    let recorded = async {
+        let mut tx = database.begin().await?;
+        tx.create_trash_entry(&entry).await?;
+        tx.commit().await
+    }.await;
+    if let Err(e) = recorded {
+        tracing::error!("Failed to record {} in the trash: {}", path, e);
+
+        // Put the content back, so deleting it again starts over
+        let location = FileSystemService::trash_location(user_id, entry.id);
+        if storage.rename(&location, path).await.is_err() {
+            return Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("{} was moved to the trash but is not listed there", path),
+            ));
+        }
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    }
+
+    Ok(())
+}
+
+/// Move `path` into the user's trash, leaving the entry for the caller to record
+async fn trash_path(
+    storage: &dyn StorageBackend,
+    database: &Database,
+    user_id: Uuid,
+    path: &str,
+) -> Result<TrashEntry, StatusCode> {
+    let file_id = database.get_file_metadata_by_path(user_id, path).await
+        .map_err(database_error)?
+        .map(|metadata| metadata.id);
+
+    let source = storage.metadata(path).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let is_directory = source.is_directory;
+    let size = if is_directory {
+        stored_size(storage, path).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    } else {
+        source.size
+    };
+
+    // The trash is a reserved directory in the same storage
+    let entry_id = Uuid::new_v4();
+    storage.rename(path, &FileSystemService::trash_location(user_id, entry_id)).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    Ok(TrashEntry {
+        id: entry_id,
+        user_id,
+        file_id,
+        original_path: path.to_string(),
+        name: file_name(path),
+        is_directory,
+        size,
+        deleted_at: Utc::now(),
+    })
+}
+
+/// Put an entry trashed by `trash_path` back where it was, for when the
+/// operation that replaced it fails
+async fn restore_replaced(storage: &dyn StorageBackend, entry: &TrashEntry) {
+    let location = FileSystemService::trash_location(entry.user_id, entry.id);
+    if let Err(e) = storage.rename(&location, &entry.original_path).await {
+        tracing::error!("Failed to restore {} from the trash: {}", entry.original_path, e);
+    }
+}
+
+/// Trash an owned entry, leaving it for `record_change` to record
+async fn perform_delete(
+    storage: &dyn StorageBackend,
+    database: &Database,
+    user_id: Uuid,
+    path: &str,
+) -> Result<DiskChange, ApiError> {
+    let path = normalize_path(path.trim_end_matches('/'));
+    let path = path.as_str();
+
+    if path.is_empty() {
+        return Err(rejected("Cannot delete the root directory"));
+    }
+
+    // Only entries recorded under this user can be deleted
+    database.get_file_metadata_by_path(user_id, path).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let entry = trash_path(storage, database, user_id, path).await?;
+    Ok(DiskChange {
+        metadata: None,
+        writes: vec![MetadataWrite::Trash(entry.clone())],
+        undo: Undo::Trashed(entry),
+    })
+}
+
+/// Move an owned entry on disk, leaving it for `record_change` to record
+async fn perform_move(
+    filesystem: &FileSystemService,
+    storage: &dyn StorageBackend,
+    database: &Database,
+    user_id: Uuid,
+    from: &str,
+    to: &str,
+    overwrite: bool,
+) -> Result<DiskChange, ApiError> {
+    let (from, to) = (normalize_path(from), normalize_path(to));
+    let from = from.trim_end_matches('/');
+    let to = to.trim_end_matches('/');
+
+    if from.is_empty() || to.is_empty() {
+        return Err(rejected("Cannot move the root directory"));
+    }
+
+    if from == to {
+        return Err(rejected("Source and destination are the same"));
+    }
+
+    if to.starts_with(&format!("{}/", from)) {
+        return Err(rejected("Cannot move a directory into itself"));
+    }
+
+    if filesystem.is_reserved_path(to) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    if let Err(e) = filesystem.check_file_name(to) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+
+    // Only entries recorded under this user can be moved
+    let mut metadata = database.get_file_metadata_by_path(user_id, from).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if !metadata.is_directory {
+        if let Err(e) = filesystem.check_extension(to) {
+            return Err(ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()));
+        }
+    }
+
+    storage.metadata(from).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let mut writes = Vec::new();
+    let mut replaced = None;
+
+    if storage.metadata(to).await.is_ok() {
+        if !overwrite {
+            return Err(rejected("Destination already exists"));
+        }
+
+        // The replaced entry stays recoverable from the trash
+        replaced = Some(trash_path(storage, database, user_id, to).await?);
+    }
+
+    if storage.rename(from, to).await.is_err() {
+        if let Some(entry) = &replaced {
+            restore_replaced(storage, entry).await;
+        }
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    writes.extend(replaced.clone().map(MetadataWrite::Trash));
+
+    metadata.path = to.to_string();
+    metadata.name = file_name(to);
+    metadata.modified_at = Utc::now();
+
+    writes.push(MetadataWrite::Move {
+        metadata: metadata.clone(),
+        old_path: from.to_string(),
+    });
+
+    Ok(DiskChange {
+        metadata: Some(metadata),
+        writes,
+        undo: Undo::Moved { from: from.to_string(), to: to.to_string(), replaced },
+    })
+}
+
+/// Copy an owned entry on disk, leaving it for `record_change` to record
+async fn perform_copy(
+    filesystem: &FileSystemService,
+    storage: &dyn StorageBackend,
+    database: &Database,
+    user_id: Uuid,
+    from: &str,
+    to: &str,
+    overwrite: bool,
+) -> Result<DiskChange, ApiError> {
+    let (from, to) = (normalize_path(from), normalize_path(to));
+    let from = from.trim_end_matches('/');
+    let to = to.trim_end_matches('/');
+
+    if from.is_empty() || to.is_empty() {
+        return Err(rejected("Cannot copy the root directory"));
+    }
+
+    require_local_storage(storage, "Copies")?;
+
+    if from == to || to.starts_with(&format!("{}/", from)) {
+        return Err(rejected("Cannot copy a directory into itself"));
+    }
+
+    if filesystem.is_reserved_path(to) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    if let Err(e) = filesystem.check_file_name(to) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, e.to_string()));
+    }
+
+    // Only entries recorded under this user can be copied
+    let source = database.get_file_metadata_by_path(user_id, from).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if !source.is_directory {
+        if let Err(e) = filesystem.check_extension(to) {
+            return Err(ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()));
+        }
+    }
+
+    // Everything that can turn the copy down is checked before the
+    // destination is touched
+    let destination_exists = storage.metadata(to).await.is_ok();
+    if destination_exists && !overwrite {
+        return Err(rejected("Destination already exists"));
+    }
+
+    let copy_size = if source.is_directory {
+        filesystem.get_directory_size(from).await
+            .map_err(|_| StatusCode::NOT_FOUND)?
+    } else {
+        storage.metadata(from).await
+            .map_err(|_| StatusCode::NOT_FOUND)?
+            .size
+    };
+    if copy_size > filesystem.max_copy_size() {
+        let err = FileSystemError::CopyTooLarge { limit: filesystem.max_copy_size() };
+        return Err(ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, err.to_string()));
+    }
+    let quota = get_quota_usage(filesystem, database, user_id).await?;
+    if !quota.allows(copy_size, 0) {
+        return Err(quota_exceeded(&quota, copy_size));
+    }
+
+    let mut writes = Vec::new();
+    let mut replaced = None;
+
+    if destination_exists {
+        // The replaced entry stays recoverable from the trash
+        replaced = Some(trash_path(storage, database, user_id, to).await?);
+    }
+
+    let mut copied = match filesystem.copy_tree(from, to).await {
+        Ok(copied) => copied,
+        Err(e) => {
+            if let Some(entry) = &replaced {
+                restore_replaced(storage, entry).await;
+            }
+            return match e.downcast_ref::<FileSystemError>() {
+                Some(err @ FileSystemError::CopyTooLarge { .. }) => {
+                    Err(ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, err.to_string()))
+                }
+                _ => Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+            };
+        }
+    };
+
+    // Entries come back parents first, so each parent id is known before its
+    // children; the copy's own parent is found when it is recorded
+    let mut directory_ids: HashMap<String, Uuid> = HashMap::new();
+
+    for entry in copied.iter_mut() {
+        entry.owner_id = user_id;
+        entry.parent_id = match parent_path(&entry.path) {
+            Some(parent) if entry.path != to => directory_ids.get(parent).copied(),
+            _ => None,
+        };
+        if entry.is_directory {
+            directory_ids.insert(entry.path.clone(), entry.id);
+        }
+    }
+
+    let root = copied.first().cloned().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    writes.extend(replaced.clone().map(MetadataWrite::Trash));
+    writes.extend(copied.into_iter().map(MetadataWrite::Insert));
+
+    Ok(DiskChange {
+        metadata: Some(root),
+        writes,
+        undo: Undo::Copied { to: to.to_string(), replaced },
+    })
+}
+
+pub async fn move_file(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(request): Json<MoveRequest>,
+) -> Result<Json<ApiResponse<FileMetadata>>, ApiError> {
+    check_transfer_scope(&claims, &request.from, &request.to, true)?;
+
+    let user_id = target_user(&database, &claims, &params).await?;
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+    let storage = home_storage(storage.as_ref(), user_id)?;
+
+    let overwrite = request.overwrite.unwrap_or(false);
+    let mut change =
+        perform_move(&filesystem, storage.as_ref(), &database, user_id, &request.from, &request.to, overwrite).await?;
+    record_change(&filesystem, storage.as_ref(), &database, user_id, &mut change).await?;
+
+    let metadata = change.metadata.ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(ApiResponse::success(metadata)))
+}
+
+pub async fn copy_file(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(request): Json<CopyRequest>,
+) -> Result<Json<ApiResponse<FileMetadata>>, ApiError> {
+    check_transfer_scope(&claims, &request.from, &request.to, false)?;
+
+    let user_id = target_user(&database, &claims, &params).await?;
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+    let storage = home_storage(storage.as_ref(), user_id)?;
+
+    let overwrite = request.overwrite.unwrap_or(false);
+    let mut change =
+        perform_copy(&filesystem, storage.as_ref(), &database, user_id, &request.from, &request.to, overwrite).await?;
+    record_change(&filesystem, storage.as_ref(), &database, user_id, &mut change).await?;
+
+    let root = change.metadata.ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(ApiResponse::success(root)))
+}
+
+/// Largest number of operations accepted in one batch request
+const MAX_BATCH_OPERATIONS: usize = 500;
+
+pub async fn batch_operations(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(request): Json<BatchRequest>,
+) -> Result<Json<ApiResponse<Vec<BatchItemResult>>>, ApiError> {
+    // The route needs write; deletes in the batch need delete as well
+    if request.operations.iter().any(|operation| matches!(operation.op, BatchOp::Delete))
+        && !claims.has(Permission::Delete)
+    {
+        return Err(missing_permission(Permission::Delete));
+    }
+
+    let user_id = target_user(&database, &claims, &params).await?;
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+    let storage = home_storage(storage.as_ref(), user_id)?;
+
+    if request.operations.is_empty() {
+        return Err(rejected("No operations given"));
+    }
+
+    if request.operations.len() > MAX_BATCH_OPERATIONS {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("At most {} operations are allowed per batch", MAX_BATCH_OPERATIONS),
+        ));
+    }
+
+    let mut results: Vec<BatchItemResult> = Vec::with_capacity(request.operations.len());
+    // Each operation is recorded as soon as it is done on disk, so later
+    // ones read what earlier ones wrote. One that can't be recorded is
+    // undone, and the rest aren't attempted.
+    let mut aborted = false;
+
+    for (index, operation) in request.operations.into_iter().enumerate() {
+        let from = normalize_path(operation.from.trim_end_matches('/'));
+        let to = operation.to.as_deref().map(|to| normalize_path(to.trim_end_matches('/')));
+
+        let mut result = BatchItemResult {
+            index,
+            op: operation.op,
+            from: from.clone(),
+            to: to.clone(),
+            success: false,
+            error: None,
+            metadata: None,
+        };
+
+        if aborted {
+            result.error = Some("Not attempted after the batch failed".to_string());
+            results.push(result);
+            continue;
+        }
+
+        let overwrite = operation.overwrite.unwrap_or(false);
+        let allowed = match (operation.op, to.as_deref()) {
+            (BatchOp::Delete, _) => check_scope(&claims, Action::Delete, &from),
+            (BatchOp::Move, Some(to)) => check_transfer_scope(&claims, &from, to, true),
+            (BatchOp::Copy, Some(to)) => check_transfer_scope(&claims, &from, to, false),
+            (_, None) => Ok(()),
+        };
+        let outcome = match (operation.op, to.as_deref()) {
+            _ if allowed.is_err() => Err(ApiError::from(StatusCode::FORBIDDEN)),
+            (BatchOp::Delete, _) => perform_delete(storage.as_ref(), &database, user_id, &from).await,
+            (BatchOp::Move, Some(to)) => perform_move(&filesystem, storage.as_ref(), &database, user_id, &from, to, overwrite).await,
+            (BatchOp::Copy, Some(to)) => perform_copy(&filesystem, storage.as_ref(), &database, user_id, &from, to, overwrite).await,
+            (_, None) => Err(rejected("Destination is required")),
+        };
+
+        match outcome {
+            Ok(mut change) => match record_change(&filesystem, storage.as_ref(), &database, user_id, &mut change).await {
+                Ok(()) => {
+                    result.success = true;
+                    result.metadata = change.metadata;
+                }
+                Err(e) => {
+                    result.error = Some(e.message());
+                    aborted = true;
+                }
+            },
+            Err(e) => result.error = Some(e.message()),
+        }
+
+        results.push(result);
+    }
+
+    Ok(Json(ApiResponse::success(results)))
+}
+
+/// Locks last this long unless the client asks for another TTL
+const DEFAULT_LOCK_TTL_SECONDS: u64 = 300;
+/// Longest TTL granted per request; longer edits keep refreshing the lock
+const MAX_LOCK_TTL_SECONDS: u64 = 3600;
+
+/// Locks are keyed by the path with exactly one leading slash
+fn lock_path(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+/// A lock belongs to the user and device that took it
+fn holds_lock(lock: &FileLock, user_id: Uuid, claims: &Claims) -> bool {
+    lock.owner_id == user_id && lock.device_id == claims.device_id
+}
+
+fn locked(lock: &FileLock) -> ApiError {
+    ApiError::new(
+        StatusCode::LOCKED,
+        format!("{} is locked by {}", lock.path, lock.owner_name),
+    )
+    .with_data(LockConflict {
+        path: lock.path.clone(),
+        holder: lock.owner_name.clone(),
+        device_id: lock.device_id.clone(),
+        expires_at: lock.expires_at,
+    })
+}
+
+/// The storage as the caller sees it. Admins passing `include_ignored=true`
+/// also get entries hidden by ignore rules.
+async fn visible_filesystem(
+    filesystem: FileSystemService,
+    database: &Database,
+    claims: &Claims,
+    params: &HashMap<String, String>,
+) -> Result<FileSystemService, StatusCode> {
+    let include_ignored = params.get("include_ignored")
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+
+    if !include_ignored {
+        return Ok(filesystem);
+    }
+
+    if !user_has_permission(database, claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(filesystem.including_ignored())
+}
+
+/// Fail with `403 Forbidden` unless `path` lies in the anonymous root.
+/// Compared by components, so neither `..` nor a sibling such as
+/// `/public-old` for `/public` gets out.
+fn check_anonymous_path(access: &AnonymousAccess, path: &str) -> Result<(), StatusCode> {
+    match (path_components(path), path_components(&access.root)) {
+        (Some(path), Some(root)) if path.starts_with(&root) => Ok(()),
+        _ => Err(StatusCode::FORBIDDEN),
+    }
+}
+
+/// Fail with `403 Forbidden` when the token is limited to some folders and
+/// none of them allows `action` on `path`. Tokens without scopes pass.
+fn check_scope(claims: &Claims, action: Action, path: &str) -> Result<(), StatusCode> {
+    if claims.scopes.is_empty() || claims.scopes.iter().any(|scope| scope.covers(action, path)) {
+        Ok(())
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+/// Scopes a move or copy needs: a move takes the source away, so it needs
+/// write at both ends, while a copy only reads the source
+fn check_transfer_scope(claims: &Claims, from: &str, to: &str, moving: bool) -> Result<(), StatusCode> {
+    check_scope(claims, if moving { Action::Write } else { Action::Read }, from)?;
+    check_scope(claims, Action::Write, to)
+}
+
+/// The user whose files a request works on: the caller, or for admins the
+/// user named by `user=`. Other users' files are only reachable through shares.
+async fn target_user(
+    database: &Database,
+    claims: &Claims,
+    params: &HashMap<String, String>,
+) -> Result<Uuid, StatusCode> {
+    let Some(username) = params.get("user") else {
+        return Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    if !user_has_permission(database, claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    database.get_user_by_username(username).await
+        .map_err(database_error)?
+        .map(|user| user.id)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// The same, except that `user=` may also name someone who shared a file or
+/// folder covering `path` with the caller, allowing `action` on it
+async fn shared_target_user(
+    database: &Database,
+    claims: &Claims,
+    params: &HashMap<String, String>,
+    action: Action,
+    path: &str,
+) -> Result<Uuid, StatusCode> {
+    let Some(owner) = params.get("user") else {
+        return target_user(database, claims, params).await;
+    };
+    if user_has_permission(database, claims, Permission::Admin).await? {
+        return target_user(database, claims, params).await;
+    }
+
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let shares = database.get_incoming_shares(user_id).await
+        .map_err(database_error)?;
+    shares.iter()
+        .find(|share| &share.owner == owner && share.covers(action, path))
+        .map(|share| share.share.grantor)
+        .ok_or(StatusCode::FORBIDDEN)
+}
+
+/// What is on record at `path` in `user_id`'s files, looked up before the
+/// file is read or removed: 404 when nothing is. In a flat tree, where all
+/// users' paths are the same files, a path on record for someone else is
+/// theirs and gets 403.
+async fn owned_file(
+    database: &Database,
+    filesystem: &FileSystemService,
+    user_id: Uuid,
+    path: &str,
+) -> Result<FileMetadata, StatusCode> {
+    if let Some(metadata) = database.get_file_metadata_by_path(user_id, path).await
+        .map_err(database_error)? {
+        return Ok(metadata);
+    }
+
+    let flat = filesystem.for_user(user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .home()
+        .is_none();
+    if flat && database.is_path_owned_by_other(user_id, path).await
+        .map_err(database_error)? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Err(StatusCode::NOT_FOUND)
+}
+
+/// The filesystem rooted at `user_id`'s home when user homes are on
+fn home_filesystem(filesystem: &FileSystemService, user_id: Uuid) -> Result<FileSystemService, StatusCode> {
+    filesystem.for_user(user_id).map_err(|e| {
+        tracing::error!("Failed to open the home of {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+fn home_storage(storage: &dyn StorageBackend, user_id: Uuid) -> Result<Arc<dyn StorageBackend>, StatusCode> {
+    storage.for_user(user_id).map_err(|e| {
+        tracing::error!("Failed to open the home of {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Fail with `501 Not Implemented` for features that work on files on disk
+/// when contents are kept elsewhere, such as in a bucket
+fn require_local_storage(storage: &dyn StorageBackend, feature: &str) -> Result<(), ApiError> {
+    if storage.is_local() {
+        return Ok(());
+    }
+    Err(ApiError::new(
+        StatusCode::NOT_IMPLEMENTED,
+        format!("{} are not available with the s3 storage backend", feature),
+    ))
+}
+
+/// Home that locks on `filesystem` are keyed by; nil for the shared tree
+fn lock_home(filesystem: &FileSystemService) -> Uuid {
+    filesystem.home().unwrap_or_default()
+}
+
+/// Fail with `423 Locked` when another user or device holds a lock on `path`
+async fn check_file_lock(
+    database: &Database,
+    claims: &Claims,
+    home_id: Uuid,
+    path: &str,
+) -> Result<(), ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    match database.get_file_lock(home_id, &lock_path(path)).await {
+        Ok(Some(lock)) if !holds_lock(&lock, user_id, claims) => Err(locked(&lock)),
+        Ok(_) => Ok(()),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+    }
+}
+
+pub async fn lock_file(
+    State(filesystem): State<FileSystemService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(file_path): Path<String>,
+    request: Option<Json<LockRequest>>,
+) -> Result<Json<ApiResponse<FileLock>>, ApiError> {
+    let file_path = normalize_path(&urlencoding::decode(&file_path)
+        .map_err(|_| StatusCode::BAD_REQUEST)?);
+    check_scope(&claims, Action::Write, &file_path)?;
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let home_id = lock_home(&home_filesystem(&filesystem, user_id)?);
+    let path = lock_path(&file_path);
+
+    if path == "/" {
+        return Err(rejected("Cannot lock the root directory"));
+    }
+
+    if filesystem.is_reserved_path(&path) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let (ttl_seconds, token) = match request {
+        Some(Json(request)) => (request.ttl_seconds, request.token),
+        None => (None, None),
+    };
+    let ttl_seconds = ttl_seconds
+        .unwrap_or(DEFAULT_LOCK_TTL_SECONDS)
+        .clamp(1, MAX_LOCK_TTL_SECONDS);
+
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::seconds(ttl_seconds as i64);
+
+    let existing = database.get_file_lock(home_id, &path).await
+        .map_err(database_error)?;
+
+    if let Some(mut lock) = existing {
+        // The token lets the holder refresh from a new session on the same device
+        let presented_token = token.as_deref() == Some(lock.token.as_str());
+        if !holds_lock(&lock, user_id, &claims) && !presented_token {
+            return Err(locked(&lock));
+        }
+
+        database.refresh_file_lock(lock.id, expires_at).await
+            .map_err(database_error)?;
+        lock.expires_at = expires_at;
+
+        return Ok(Json(ApiResponse::success(lock)));
+    }
+
+    let lock = FileLock {
+        id: Uuid::new_v4(),
+        home_id,
+        path: path.clone(),
+        owner_id: user_id,
+        owner_name: claims.username.clone(),
+        device_id: claims.device_id.clone(),
+        token: Uuid::new_v4().to_string(),
+        acquired_at: now,
+        expires_at,
+    };
+
+    let acquired = database.acquire_file_lock(&lock).await
+        .map_err(database_error)?;
+
+    if !acquired {
+        // Another client took the lock since we looked
+        return match database.get_file_lock(home_id, &path).await {
+            Ok(Some(lock)) => Err(locked(&lock)),
+            _ => Err(StatusCode::CONFLICT.into()),
+        };
+    }
+
+    Ok(Json(ApiResponse::success(lock)))
+}
+
+pub async fn unlock_file(
+    State(filesystem): State<FileSystemService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(file_path): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<()>>, ApiError> {
+    let file_path = normalize_path(&urlencoding::decode(&file_path)
+        .map_err(|_| StatusCode::BAD_REQUEST)?);
+    check_scope(&claims, Action::Write, &file_path)?;
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let home_id = lock_home(&home_filesystem(&filesystem, user_id)?);
+    let path = lock_path(&file_path);
+
+    let force = params.get("force")
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false);
+
+    let lock = database.get_file_lock(home_id, &path).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let presented_token = params.get("token") == Some(&lock.token);
+    if !holds_lock(&lock, user_id, &claims) && !presented_token {
+        // Admins can break locks left behind by clients that went away
+        if !force || !user_has_permission(&database, &claims, Permission::Admin).await? {
+            return Err(locked(&lock));
+        }
+
+        tracing::info!("{} broke the lock on {} held by {}", claims.username, lock.path, lock.owner_name);
+    }
+
+    database.delete_file_lock(lock.id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+async fn get_owned_trash_entry(
+    database: &Database,
+    entry_id: &str,
+    user_id: Uuid,
+) -> Result<TrashEntry, StatusCode> {
+    let entry_id = Uuid::parse_str(entry_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    match database.get_trash_entry(entry_id).await {
+        Ok(Some(entry)) if entry.user_id == user_id => Ok(entry),
+        Ok(_) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// The caller's trash, newest first, a page at a time. Tokens limited to
+/// some folders only see entries from those, so their pages can come up
+/// short.
+pub async fn list_trash(
+    State(database): State<Database>,
+    State(pages): State<PageLimits>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<Paginated<TrashEntry>>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let page = pages.request(&params)?;
+
+    let entries = database.list_trash_page(user_id, page.after_time()?, page.fetch(), page.offset as i64).await
+        .map_err(database_error)?;
+    let total = database.count_trash_entries(user_id).await
+        .map_err(database_error)?;
+    let mut entries = page.page(entries, total, |entry| time_key(entry.deleted_at, entry.id));
+    entries.items.retain(|entry| check_scope(&claims, Action::Read, &entry.original_path).is_ok());
+
+    Ok(Json(ApiResponse::success(entries)))
+}
+
+pub async fn restore_trash_entry(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(entry_id): Path<String>,
+) -> Result<Json<ApiResponse<FileMetadata>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let entry = get_owned_trash_entry(&database, &entry_id, user_id).await?;
+    check_scope(&claims, Action::Write, &entry.original_path)?;
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+    let storage = home_storage(storage.as_ref(), user_id)?;
+
+    if storage.metadata(&entry.original_path).await.is_ok() {
+        return Ok(Json(ApiResponse::error(format!("A file already exists at {}", entry.original_path))));
+    }
+
+    let location = FileSystemService::trash_location(user_id, entry.id);
+    if storage.rename(&location, &entry.original_path).await.is_err() {
+        return Ok(Json(ApiResponse::error("Trash entry not found".to_string())));
+    }
+
+    database.restore_trash_entry(&entry).await
+        .map_err(database_error)?;
+
+    let metadata = database.resolve_file_metadata(&filesystem, user_id, &entry.original_path).await
+        .map_err(database_error)?;
+    database.record_file_restored(metadata.id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(metadata)))
+}
+
+pub async fn purge_trash_entry(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(entry_id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let entry = get_owned_trash_entry(&database, &entry_id, user_id).await?;
+    check_scope(&claims, Action::Delete, &entry.original_path)?;
+    let storage = home_storage(storage.as_ref(), user_id)?;
+
+    let location = FileSystemService::trash_location(user_id, entry.id);
+    if storage.metadata(&location).await.is_ok() {
+        storage.delete(&location).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    // The rows and the entry listing them go together
+    let mut tx = database.begin().await
+        .map_err(database_error)?;
+    let deleted = match entry.file_id {
+        Some(file_id) => Some(tx.delete_file_metadata(file_id).await
+            .map_err(database_error)?),
+        None => None,
+    };
+    tx.delete_trash_entry(entry.id).await
+        .map_err(database_error)?;
+    tx.commit().await
+        .map_err(database_error)?;
+
+    if let Some(deleted) = deleted {
+        remove_unused_versions(&filesystem, &deleted).await;
+    }
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+async fn get_owned_file(
+    database: &Database,
+    file_id: &str,
+    user_id: Uuid,
+) -> Result<FileMetadata, StatusCode> {
+    let file_id = Uuid::parse_str(file_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    match database.get_file_metadata(file_id).await {
+        Ok(Some(metadata)) if metadata.owner_id == user_id => Ok(metadata),
+        Ok(Some(_)) => Err(StatusCode::FORBIDDEN),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub async fn list_file_versions(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(file_id): Path<String>,
+) -> Result<Json<ApiResponse<Vec<FileVersion>>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let file = get_owned_file(&database, &file_id, user_id).await?;
+    check_scope(&claims, Action::Read, &file.path)?;
+
+    let versions = database.list_file_versions(file.id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(versions)))
+}
+
+pub async fn download_file_version(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path((file_id, version)): Path<(String, u32)>,
+) -> Result<Response, ApiError> {
+    require_local_storage(storage.as_ref(), "File versions")?;
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let file = get_owned_file(&database, &file_id, user_id).await?;
+    check_scope(&claims, Action::Read, &file.path)?;
+
+    let version = database.get_file_version(file.id, version).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let stream = filesystem.open_version_stream(&version.checksum).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        file.mime_type.parse().unwrap(),
+    );
+    headers.insert(
+        header::CONTENT_LENGTH,
+        version.size.into(),
+    );
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", file.name).parse().unwrap(),
+    );
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .headers(headers)
+        .body(axum::body::Body::from_stream(stream))
+        .unwrap())
+}
+
+pub async fn restore_file_version(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path((file_id, version)): Path<(String, u32)>,
+) -> Result<Json<ApiResponse<FileMetadata>>, ApiError> {
+    require_local_storage(storage.as_ref(), "File versions")?;
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let file = get_owned_file(&database, &file_id, user_id).await?;
+    check_scope(&claims, Action::Write, &file.path)?;
+
+    let version = database.get_file_version(file.id, version).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let filesystem = home_filesystem(&filesystem, file.owner_id)?;
+
+    // The content being replaced becomes a version too, so a restore can be undone
+    preserve_previous_version(&filesystem, &database, user_id, &file.path).await?;
+
+    let mut metadata = filesystem.restore_version(&version.checksum, &file.path).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    metadata.owner_id = user_id;
+    metadata.parent_id = file.parent_id;
+
+    save_uploaded_metadata(&database, &mut metadata).await?;
+
+    Ok(Json(ApiResponse::success(metadata)))
+}
+
+pub async fn sync_files(
+    State(filesystem): State<FileSystemService>,
+    State(database): State<Database>,
+    State(SyncPageLimit(max_page)): State<SyncPageLimit>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    Json(request): Json<SyncRequest>,
+) -> Result<Conditional<Json<ApiResponse<SyncResponse>>>, ApiError> {
+    let user_id = target_user(&database, &claims, &params).await?;
+    let folders = sync_folders(&request.folders)?;
+    let limit = page_size(request.limit, max_page)?;
+    let now = Utc::now();
+
+    // Read before the changes, so none recorded meanwhile fall between tokens
+    let latest = database.get_latest_change_seq().await
+        .map_err(database_error)?;
+
+    let after = match (&request.sync_token, request.last_sync) {
+        (Some(token), _) => sync_cursor(&database, token, latest).await?,
+        // Changes from before the horizon are gone, so the client can't catch up
+        (None, Some(last_sync)) => {
+            let horizon = database.get_change_log_horizon().await
+                .map_err(database_error)?;
+            if horizon.map_or(true, |horizon| last_sync >= horizon.pruned_before) {
+                Some(cursor_at(&database, last_sync, latest).await?)
+            } else {
+                None
+            }
+        }
+        (None, None) => Some(cursor_at(&database, now - chrono::Duration::hours(24), latest).await?),
+    };
+
+    let session = if params.contains_key("user") {
+        None
+    } else {
+        record_sync(&database, &claims, user_id, now).await?
+    };
+    let exclusions = Exclusions::of(session.as_ref())?;
+
+    let Some(after) = after else {
+        return Ok(Conditional::Modified(Json(ApiResponse::success(SyncResponse {
+            changes: Vec::new(),
+            sync_token: latest.to_string(),
+            full_resync_required: true,
+            has_more: false,
+        })), None));
+    };
+
+    // A client holding a complete answer up to the feed's latest change has
+    // nothing to catch up on, whichever token it sends
+    let owners = feed_owners(&database, &params, user_id).await?;
+    let seen = database.get_latest_change_seq_for(&owners, latest).await
+        .map_err(database_error)?;
+    let excluded = session.map(|session| session.exclude_patterns).unwrap_or_default();
+    let etag = etag_of(std::iter::once(seen.to_string()).chain(folders.iter().cloned()).chain(excluded));
+    if etag_matches(&headers, &etag) {
+        return Ok(Conditional::NotModified(etag));
+    }
+
+    let window = page_window(&database, &owners, after, latest, limit).await?;
+    let changes = sync_changes(&filesystem, &database, &claims, &params, user_id, &folders, &exclusions, window).await?;
+
+    let response = SyncResponse {
+        changes,
+        sync_token: window.through.to_string(),
+        full_resync_required: false,
+        has_more: window.through < latest,
+    };
+
+    // Only a page that reaches the end can stand in for later polls
+    let etag = (!response.has_more).then_some(etag);
+    Ok(Conditional::Modified(Json(ApiResponse::success(response)), etag))
+}
+
+/// Entries read from the database at a time while a snapshot streams
+const SNAPSHOT_PAGE_SIZE: u32 = 500;
+
+/// Everything in the caller's `folders` (comma-separated, all of them when
+/// left out) as it stands now, for a new device with no cursor to sync from.
+/// Streams NDJSON: a header line with the cursor to sync from afterwards and
+/// the owner's quota, then a line per entry in path order. Entries come
+/// straight from the database, with their stored checksums.
+pub async fn get_sync_snapshot(
+    State(filesystem): State<FileSystemService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let user_id = target_user(&database, &claims, &params).await?;
+    let requested: Vec<String> = params.get("folders")
+        .map(|folders| folders.split(',').filter(|folder| !folder.trim().is_empty()).map(str::to_string).collect())
+        .unwrap_or_default();
+    let folders = sync_folders(&requested)?;
+
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+    let filesystem = visible_filesystem(filesystem, &database, &claims, &params).await?;
+    let quota = get_quota_usage(&filesystem, &database, user_id).await?;
+
+    // Read before the entries, so anything that changes while they stream
+    // comes up again in the first sync after
+    let latest = database.get_latest_change_seq().await
+        .map_err(database_error)?;
+
+    let session = if params.contains_key("user") {
+        None
+    } else {
+        record_sync(&database, &claims, user_id, Utc::now()).await?
+    };
+    let exclusions = Arc::new(Exclusions::of(session.as_ref())?);
+
+    let roots = outermost_folders(&folders);
+    let head = SnapshotHeader { sync_token: latest.to_string(), folders, quota };
+    let head = futures_util::stream::once(async move { Ok(ndjson_line(&head)) });
+
+    let entries = futures_util::stream::try_unfold((0, None), move |(root, after): (usize, Option<String>)| {
+        let (database, filesystem, claims, exclusions) = (database.clone(), filesystem.clone(), claims.clone(), exclusions.clone());
+        let folder = roots.get(root).cloned();
+        async move {
+            let Some(folder) = folder else {
+                return Ok(None);
+            };
+            let mut page = database.get_folder_entries_page(user_id, &folder, after.as_deref(), SNAPSHOT_PAGE_SIZE).await
+                .map_err(|e| {
+                    tracing::error!("Failed to read the snapshot of {} for {}: {}", folder, user_id, e);
+                    std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+                })?;
+
+            let next = match page.last() {
+                Some(last) if page.len() == SNAPSHOT_PAGE_SIZE as usize => (root, Some(last.path.clone())),
+                _ => (root + 1, None),
+            };
+            filesystem.filter_ignored(&mut page);
+            let lines: Vec<u8> = page.into_iter()
+                .filter(|metadata| check_scope(&claims, Action::Read, &metadata.path).is_ok()
+                    && !exclusions.excludes(&metadata.path, metadata.is_directory))
+                .flat_map(|metadata| ndjson_line(&SnapshotEntry::from(metadata)))
+                .collect();
+            Ok::<_, std::io::Error>(Some((lines, next)))
+        }
+    });
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(axum::body::Body::from_stream(head.chain(entries)))
+        .unwrap())
+}
+
+fn ndjson_line(value: &impl serde::Serialize) -> Vec<u8> {
+    let mut line = serde_json::to_vec(value).expect("snapshot lines serialize");
+    line.push(b'\n');
+    line
+}
+
+/// The folders to read entries from, `/` for none; folders inside others
+/// would list their entries twice
+fn outermost_folders(folders: &[String]) -> Vec<String> {
+    let mut roots: Vec<String> = if folders.is_empty() { vec!["/".to_string()] } else { folders.to_vec() };
+    roots.sort();
+    roots.dedup();
+    roots.iter()
+        .filter(|root| !roots.iter().any(|other| other != *root && is_within(root, other)))
+        .cloned()
+        .collect()
+}
+
+/// Most files one `/sync/plan` manifest may list
+#[derive(Clone, Copy)]
+pub struct SyncPlanLimit(pub u32);
+
+/// What a sync would download, upload and delete, without doing any of it.
+/// The body is the client's manifest as NDJSON, a `ManifestEntry` per line,
+/// read as it streams in. It's compared with the caller's files under
+/// `folders` (comma-separated, all of them when left out). The client's
+/// `sync_token` tells which server files changed since it last synced;
+/// without one, nothing is planned for deletion on the server.
+pub async fn plan_sync(
+    State(filesystem): State<FileSystemService>,
+    State(database): State<Database>,
+    State(SyncPlanLimit(max_entries)): State<SyncPlanLimit>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+    body: axum::body::Body,
+) -> Result<Json<ApiResponse<SyncPlanResponse>>, ApiError> {
+    let user_id = target_user(&database, &claims, &params).await?;
+    let requested: Vec<String> = params.get("folders")
+        .map(|folders| folders.split(',').filter(|folder| !folder.trim().is_empty()).map(str::to_string).collect())
+        .unwrap_or_default();
+    let folders = sync_folders(&requested)?;
+
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+    let filesystem = visible_filesystem(filesystem, &database, &claims, &params).await?;
+
+    // Looked up only: previewing a sync doesn't count as one
+    let session = if params.contains_key("user") {
+        None
+    } else {
+        registered_session(&database, &claims, user_id).await?
+    };
+    let exclusions = Exclusions::of(session.as_ref())?;
+    let covered = |path: &str, is_dir: bool| {
+        (folders.is_empty() || folders.iter().any(|folder| is_within(path, folder)))
+            && !filesystem.is_ignored(path, is_dir)
+            && check_scope(&claims, Action::Read, path).is_ok()
+            && !exclusions.excludes(path, is_dir)
+    };
+
+    let mut local = read_manifest(body, max_entries).await?;
+    local.retain(|entry| covered(&entry.path, false));
+
+    let latest = database.get_latest_change_seq().await
+        .map_err(database_error)?;
+    let after = match params.get("sync_token") {
+        Some(token) => sync_cursor(&database, token, latest).await?,
+        None => None,
+    };
+
+    let mut remote = Vec::new();
+    for folder in outermost_folders(&folders) {
+        let mut after_path = None;
+        loop {
+            let page = database.get_folder_entries_page(user_id, &folder, after_path.as_deref(), SNAPSHOT_PAGE_SIZE).await
+                .map_err(|e| {
+                    tracing::error!("Failed to read the entries of {} for {}: {}", folder, user_id, e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            let done = page.len() < SNAPSHOT_PAGE_SIZE as usize;
+            after_path = page.last().map(|last| last.path.clone());
+            remote.extend(page.into_iter()
+                .filter(|metadata| covered(&metadata.path, metadata.is_directory))
+                .map(SnapshotEntry::from));
+            if done {
+                break;
+            }
+        }
+    }
+
+    let changes = match after {
+        Some(after) => {
+            let changes = database.get_changes_after(user_id, after, latest).await
+                .map_err(database_error)?;
+            Some(server_changes(changes, &remote))
+        }
+        None => None,
+    };
+
+    Ok(Json(ApiResponse::success(SyncPlanResponse {
+        plan: planner::plan(local, remote, changes.as_ref()),
+        sync_token: latest.to_string(),
+    })))
+}
+
+/// Read a `/sync/plan` manifest line by line as it arrives. 400 naming the
+/// line for one that doesn't parse or repeats a path, 413 past `max_entries`.
+async fn read_manifest(body: axum::body::Body, max_entries: u32) -> Result<Vec<ManifestEntry>, ApiError> {
+    let mut entries = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    let mut pending: Vec<u8> = Vec::new();
+    let mut line_number = 0;
+    let mut stream = body.into_data_stream();
+
+    let mut parse = |line: &[u8], line_number: usize| -> Result<(), ApiError> {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+        let invalid = |reason: &str| ApiError::new(StatusCode::BAD_REQUEST, format!("Manifest line {}: {}", line_number, reason));
+        let mut entry: ManifestEntry = serde_json::from_slice(line).map_err(|e| invalid(&e.to_string()))?;
+        entry.path = path_components(&entry.path)
+            .map(|components| format!("/{}", components.join("/")))
+            .ok_or_else(|| invalid("invalid path"))?;
+        if !seen.insert(entry.path.clone()) {
+            return Err(invalid(&format!("{} is listed twice", entry.path)));
+        }
+        if entries.len() >= max_entries as usize {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("At most {} manifest entries are allowed per plan", max_entries),
+            ));
+        }
+        entries.push(entry);
+        Ok(())
+    };
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        pending.extend_from_slice(&chunk);
+        while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+            line_number += 1;
+            parse(&pending[..end], line_number)?;
+            pending.drain(..=end);
+        }
+    }
+    parse(&pending, line_number + 1)?;
+
+    Ok(entries)
+}
+
+/// How each of `remote` changed on the server in `changes`. Entries under a
+/// folder created or moved there are new to the client too, though only the
+/// folder itself is logged.
+fn server_changes(changes: Vec<FileChange>, remote: &[SnapshotEntry]) -> HashMap<String, ServerChange> {
+    let mut changed = HashMap::new();
+    let mut added_folders = Vec::new();
+    for change in changes {
+        let server_change = match change.change_type {
+            ChangeType::Created | ChangeType::Moved => ServerChange::Added,
+            ChangeType::Modified => ServerChange::Modified,
+            ChangeType::Deleted => continue,
+        };
+        if server_change == ServerChange::Added
+            && change.metadata.as_ref().is_some_and(|metadata| metadata.is_directory)
+        {
+            added_folders.push(change.path.clone());
+        }
+        changed.insert(change.path, server_change);
+    }
+
+    for entry in remote {
+        if added_folders.iter().any(|folder| is_within(&entry.path, folder)) {
+            changed.insert(entry.path.clone(), ServerChange::Added);
+        }
+    }
+    changed
+}
+
+/// Most changes one sync response carries, unless the request asks for fewer
+#[derive(Clone, Copy)]
+pub struct SyncPageLimit(pub u32);
+
+/// Page size when the client doesn't ask for one
+const DEFAULT_SYNC_PAGE_SIZE: u32 = 1000;
+
+/// The page size a sync asked for, capped at `max`; 400 for 0
+fn page_size(limit: Option<u32>, max: u32) -> Result<u32, StatusCode> {
+    match limit {
+        Some(0) => Err(StatusCode::BAD_REQUEST),
+        Some(limit) => Ok(limit.min(max)),
+        None => Ok(DEFAULT_SYNC_PAGE_SIZE.min(max)),
+    }
+}
+
+/// The cursor a sync from a time starts after: just before the first change
+/// recorded after it
+async fn cursor_at(database: &Database, since: chrono::DateTime<Utc>, latest: i64) -> Result<i64, StatusCode> {
+    let first = database.get_first_change_after(since).await
+        .map_err(database_error)?;
+    Ok(first.map_or(latest, |first| first - 1))
+}
+
+/// Whose changes make up the caller's feed: their own and, unless an admin
+/// is looking at someone else's, those of everyone sharing with them
+async fn feed_owners(
+    database: &Database,
+    params: &HashMap<String, String>,
+    user_id: Uuid,
+) -> Result<Vec<Uuid>, StatusCode> {
+    let mut owners = vec![user_id];
+    if !params.contains_key("user") {
+        let shares = database.get_incoming_shares(user_id).await
+            .map_err(database_error)?;
+        owners.extend(shares.iter().map(|share| share.share.grantor));
+        owners.sort();
+        owners.dedup();
+    }
+    Ok(owners)
+}
+
+/// The next page of the feed after `after`: at most `limit` logged changes
+/// to `owners`' files. Changes to the same file within the page come out as
+/// one.
+async fn page_window(
+    database: &Database,
+    owners: &[Uuid],
+    after: i64,
+    latest: i64,
+    limit: u32,
+) -> Result<ChangeWindow, StatusCode> {
+    let through = database.get_change_page_end(owners, after, latest, limit).await
+        .map_err(database_error)?;
+    Ok(ChangeWindow { after, through })
+}
+
+/// Longest `/sync/wait` holds a request open: the server's request timeout
+#[derive(Clone, Copy)]
+pub struct LongPollLimit(pub std::time::Duration);
+
+/// Hold the request until there are changes after `cursor` for the caller,
+/// or `timeout` seconds pass, for clients that can't keep a WebSocket open.
+/// Answers like `sync_files`; without a cursor it waits for the next change.
+pub async fn wait_for_changes(
+    State(filesystem): State<FileSystemService>,
+    State(database): State<Database>,
+    State(LongPollLimit(limit)): State<LongPollLimit>,
+    State(SyncPageLimit(max_page)): State<SyncPageLimit>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<SyncResponse>>, ApiError> {
+    let user_id = target_user(&database, &claims, &params).await?;
+    let page = params.get("limit")
+        .map(|page| page.parse::<u32>().map_err(|_| StatusCode::BAD_REQUEST))
+        .transpose()?;
+    let page = page_size(page, max_page)?;
+
+    let timeout = params.get("timeout")
+        .map(|timeout| timeout.parse::<u64>().map_err(|_| StatusCode::BAD_REQUEST))
+        .transpose()?
+        .map_or(limit, |seconds| std::time::Duration::from_secs(seconds).min(limit));
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    // The device waits on the folders it last synced, less what it excludes
+    let session = if params.contains_key("user") {
+        None
+    } else {
+        registered_session(&database, &claims, user_id).await?
+    };
+    let exclusions = Exclusions::of(session.as_ref())?;
+    let folders = session.map(|session| session.sync_folders).unwrap_or_default();
+
+    // Subscribed before looking, so a change landing in between still wakes us
+    let mut changed = database.subscribe_changes();
+
+    let latest = database.get_latest_change_seq().await
+        .map_err(database_error)?;
+    let mut after = match params.get("cursor") {
+        Some(cursor) => match sync_cursor(&database, cursor, latest).await? {
+            Some(after) => after,
+            None => {
+                return Ok(Json(ApiResponse::success(SyncResponse {
+                    changes: Vec::new(),
+                    sync_token: latest.to_string(),
+                    full_resync_required: true,
+                    has_more: false,
+                })));
+            }
+        },
+        None => latest,
+    };
+
+    // Everything here lives in the request's future, so a client hanging up
+    // drops the wait with it
+    loop {
+        let latest = database.get_latest_change_seq().await
+            .map_err(database_error)?;
+        let changes = if latest > after {
+            let owners = feed_owners(&database, &params, user_id).await?;
+            let window = page_window(&database, &owners, after, latest, page).await?;
+            after = window.through;
+            sync_changes(&filesystem, &database, &claims, &params, user_id, &folders, &exclusions, window).await?
+        } else {
+            Vec::new()
+        };
+        // Changes for others, or hidden from the caller, just move the cursor on
+        let has_more = after < latest;
+        if changes.is_empty() && has_more {
+            continue;
+        }
+
+        let woken = changes.is_empty()
+            && matches!(tokio::time::timeout_at(deadline, changed.changed()).await, Ok(Ok(())));
+        if !woken {
+            return Ok(Json(ApiResponse::success(SyncResponse {
+                changes,
+                sync_token: after.to_string(),
+                full_resync_required: false,
+                has_more,
+            })));
+        }
+    }
+}
+
+/// What one `/sync/push` batch may carry
+#[derive(Clone, Copy)]
+pub struct PushLimits {
+    pub max_operations: usize,
+    /// Inline content of the whole batch, decoded
+    pub max_payload_bytes: u64,
+    /// Inline content of a single file, decoded
+    pub max_inline_bytes: u64,
+}
+
+/// Apply a client's local changes in one request. Each operation commits on
+/// its own and gets its own result; a failed one doesn't stop the rest.
+/// The sync token returned lets the client skip its own changes on the
+/// next sync.
+pub async fn push_changes(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    State(limits): State<PushLimits>,
+    State(conflicts): State<ConflictSettings>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+    Json(request): Json<PushRequest>,
+) -> Result<Json<ApiResponse<PushResponse>>, ApiError> {
+    // The route needs write; deletes in the batch need delete as well
+    if request.operations.iter().any(|operation| matches!(operation.op, PushOp::Delete))
+        && !claims.has(Permission::Delete)
+    {
+        return Err(missing_permission(Permission::Delete));
+    }
+
+    let user_id = target_user(&database, &claims, &params).await?;
+    let filesystem = home_filesystem(&filesystem, user_id)?;
+    let storage = home_storage(storage.as_ref(), user_id)?;
+
+    if request.operations.is_empty() {
+        return Err(rejected("No operations given"));
+    }
+
+    if request.operations.len() > limits.max_operations {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("At most {} operations are allowed per push", limits.max_operations),
+        ));
+    }
+
+    // Decoded up front, so an oversized batch is turned away before anything changes
+    let mut contents = Vec::with_capacity(request.operations.len());
+    let mut payload: u64 = 0;
+    for operation in &request.operations {
+        let content = match &operation.content {
+            Some(content) => Some(STANDARD.decode(content).map_err(|_| {
+                ApiError::new(StatusCode::BAD_REQUEST, format!("Content of {} is not valid base64", operation.path))
+            })?),
+            None => None,
+        };
+        let size = content.as_ref().map_or(0, |content| content.len() as u64);
+        if size > limits.max_inline_bytes {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("{} is too large to push inline; upload it in a session instead", operation.path),
+            ));
+        }
+        payload += size;
+        contents.push(content);
+    }
+    if payload > limits.max_payload_bytes {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("At most {} bytes of content are allowed per push", limits.max_payload_bytes),
+        ));
+    }
+
+    // A device's exclusions hold for its writes too, in case its client
+    // doesn't apply them itself
+    let session = match &claims.device_id {
+        Some(device_id) if !params.contains_key("user") => database.get_sync_session(user_id, device_id).await
+            .map_err(database_error)?,
+        _ => None,
+    };
+    let exclusions = Exclusions::of(session.as_ref())?;
+
+    let before = database.get_latest_change_seq().await
+        .map_err(database_error)?;
+
+    let home = PushHome {
+        filesystem: &filesystem,
+        storage: storage.as_ref(),
+        database: &database,
+        claims: &claims,
+        user_id,
+        conflicts: &conflicts,
+    };
+    let mut results: Vec<PushItemResult> = Vec::with_capacity(request.operations.len());
+    // Rows the push changed and the paths it wrote, to tell its changes apart afterwards
+    let mut pushed_ids: Vec<Uuid> = Vec::new();
+    let mut pushed_paths: Vec<String> = Vec::new();
+
+    for (index, (operation, content)) in request.operations.into_iter().zip(contents).enumerate() {
+        let path = normalize_path(operation.path.trim_end_matches('/'));
+        let to = operation.to.as_deref().map(|to| normalize_path(to.trim_end_matches('/')));
+        let overwrite = operation.overwrite.unwrap_or(false);
+        let mut conflict_policy = None;
+
+        let allowed = match (operation.op, to.as_deref()) {
+            (PushOp::Delete, _) => check_scope(&claims, Action::Delete, &path),
+            (PushOp::Move, Some(to)) => check_transfer_scope(&claims, &path, to, true),
+            (PushOp::CreateDir | PushOp::Put, _) => check_scope(&claims, Action::Write, &path),
+            (PushOp::Move, None) => Ok(()),
+        };
+        let excluded = [Some(path.as_str()), to.as_deref()].into_iter().flatten()
+            .find(|path| exclusions.excludes(path, matches!(operation.op, PushOp::CreateDir)));
+        let outcome = match (operation.op, to.as_deref()) {
+            _ if allowed.is_err() => Err(ApiError::from(StatusCode::FORBIDDEN)),
+            _ if excluded.is_some() => Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                format!("{} is excluded from this device's sync", excluded.unwrap_or_default()),
+            )),
+            (PushOp::CreateDir, _) => make_directory(&filesystem, &database, user_id, &path).await
+                .map(|metadata| (Some(metadata), Vec::new())),
+            // Each operation's writes commit on their own
+            (PushOp::Delete, _) => async {
+                let mut change = perform_delete(storage.as_ref(), &database, user_id, &path).await?;
+                record_change(&filesystem, storage.as_ref(), &database, user_id, &mut change).await?;
+                Ok::<_, ApiError>((None, change.writes))
+            }.await,
+            (PushOp::Move, Some(to)) => async {
+                let mut change =
+                    perform_move(&filesystem, storage.as_ref(), &database, user_id, &path, to, overwrite).await?;
+                record_change(&filesystem, storage.as_ref(), &database, user_id, &mut change).await?;
+                Ok::<_, ApiError>((change.metadata, change.writes))
+            }.await,
+            (PushOp::Move, None) => Err(rejected("Destination is required")),
+            (PushOp::Put, _) => {
+                let base = BaseVersion { checksum: operation.base_checksum, modified_at: operation.base_modified_at };
+                match (content, operation.upload_session) {
+                    (Some(content), None) => {
+                        push_file(&home, &path, &content, operation.checksum, overwrite, base, operation.modified_at).await
+                    }
+                    (None, Some(session_id)) => {
+                        push_upload_session(&home, &path, session_id, operation.checksum, base, operation.modified_at).await
+                    }
+                    _ => Err(rejected("A put needs either content or an upload session")),
+                }
+                .map(|(metadata, policy)| {
+                    conflict_policy = policy;
+                    (Some(metadata), Vec::new())
+                })
+            }
+        };
+
+        let mut result = PushItemResult {
+            index,
+            op: operation.op,
+            path: path.clone(),
+            to: to.clone(),
+            success: false,
+            error: None,
+            metadata: None,
+            conflict_policy,
+        };
+
+        match outcome {
+            Ok((metadata, writes)) => {
+                pushed_ids.extend(metadata.as_ref().map(|metadata| metadata.id));
+                pushed_ids.extend(writes.iter().filter_map(|write| match write {
+                    MetadataWrite::Insert(metadata) | MetadataWrite::Move { metadata, .. } => Some(metadata.id),
+                    MetadataWrite::Trash(entry) => entry.file_id,
+                }));
+                pushed_paths.push(path);
+                pushed_paths.extend(to);
+                result.success = true;
+                result.metadata = metadata;
+            }
+            Err(e) => result.error = Some(e.message()),
+        }
+
+        results.push(result);
+    }
+
+    // Skip past the push only if nothing else the client would see happened
+    // meanwhile; otherwise it syncs from before and gets its own changes again
+    let after = database.get_latest_change_seq().await
+        .map_err(database_error)?;
+    let sync_token = if after > before {
+        let window = ChangeWindow { after: before, through: after };
+        let changes = sync_changes(&filesystem, &database, &claims, &params, user_id, &[], &exclusions, window).await?;
+        let only_pushed = changes.iter().all(|change| {
+            pushed_ids.contains(&change.file_id) || (change.shared_by.is_none() && is_pushed_parent(change, &pushed_paths))
+        });
+        if only_pushed { after } else { before }
+    } else {
+        after
+    };
+
+    Ok(Json(ApiResponse::success(PushResponse {
+        results,
+        sync_token: sync_token.to_string(),
+    })))
+}
+
+/// A folder the push created on the way to one of its paths
+fn is_pushed_parent(change: &FileChange, pushed_paths: &[String]) -> bool {
+    matches!(change.change_type, ChangeType::Created)
+        && change.metadata.as_ref().map_or(false, |metadata| metadata.is_directory)
+        && pushed_paths.iter().any(|path| is_within(path, &change.path))
+}
+
+/// Where a push writes files to, and for whom
+struct PushHome<'a> {
+    filesystem: &'a FileSystemService,
+    storage: &'a dyn StorageBackend,
+    database: &'a Database,
+    claims: &'a Claims,
+    user_id: Uuid,
+    conflicts: &'a ConflictSettings,
+}
+
+/// Store a small file sent inline with a push, along with how a conflict
+/// with the version it's based on was settled
+async fn push_file(
+    home: &PushHome<'_>,
+    path: &str,
+    content: &[u8],
+    expected_checksum: Option<String>,
+    mut overwrite: bool,
+    base: BaseVersion,
+    modified_at: Option<chrono::DateTime<Utc>>,
+) -> Result<(FileMetadata, Option<ConflictPolicy>), ApiError> {
+    let PushHome { filesystem, storage, database, claims, user_id, conflicts } = *home;
+
+    check_upload_path(filesystem, path)?;
+    check_file_lock(database, claims, lock_home(filesystem), path).await?;
+
+    let mut path = path.to_string();
+    let policy = conflicts.policy_for(&path);
+    let mut conflict_policy = None;
+    let mut unchanged = false;
+    if base.is_set() {
+        let settlement = settle_conflict(storage, database, claims, user_id, &path, &base, |current| {
+            conflicts.settle(policy, modified_at, current)
+        }).await?;
+        match settlement {
+            Settlement::New => {}
+            Settlement::Unchanged => {
+                overwrite = true;
+                unchanged = true;
+            }
+            Settlement::Conflict { path: settled, policy } => {
+                overwrite = policy == ConflictPolicy::ClientWins;
+                path = settled;
+                conflict_policy = Some(policy);
+            }
+            Settlement::ServerWins(current) => return Ok((current, Some(ConflictPolicy::ServerWins))),
+        }
+    }
+    let path = path.as_str();
+
+    if !overwrite && storage.metadata(path).await.is_ok() {
+        return Err(rejected("File already exists"));
+    }
+
+    let size = content.len() as u64;
+    let quota = get_quota_usage(filesystem, database, user_id).await?;
+    let freed = replaced_file_size(database, user_id, path, overwrite).await?;
+    if !quota.allows(size, freed) {
+        return Err(quota_exceeded(&quota, size));
+    }
+
+    let mut upload = filesystem.begin_upload().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Err(e) = upload.write_chunk(content).await {
+        upload.abort().await;
+        return match e.downcast_ref::<FileSystemError>() {
+            Some(FileSystemError::FileTooLarge) => Err(StatusCode::PAYLOAD_TOO_LARGE.into()),
+            _ => Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+        };
+    }
+
+    if let Some(expected) = expected_checksum.as_deref() {
+        let actual = upload.checksum();
+        if !expected.eq_ignore_ascii_case(&actual) {
+            upload.abort().await;
+            return Err(checksum_mismatch(expected, &actual));
+        }
+    }
+
+    if overwrite {
+        preserve_previous_version(filesystem, database, user_id, path).await?;
+    }
+
+    let staged = upload.finish().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if unchanged {
+        return store_over_base(filesystem, storage, database, claims, user_id, path, staged, &base, |current| {
+            conflicts.settle(policy, modified_at, current)
+        }).await;
+    }
+    let metadata = store_staged_file(storage, database, user_id, path, staged).await?;
+    Ok((metadata, conflict_policy))
+}
+
+/// Finish a large file of a push from the upload session that carried it,
+/// settling a conflict with the version it's based on as `push_file` does
+async fn push_upload_session(
+    home: &PushHome<'_>,
+    path: &str,
+    session_id: Uuid,
+    expected_checksum: Option<String>,
+    base: BaseVersion,
+    modified_at: Option<chrono::DateTime<Utc>>,
+) -> Result<(FileMetadata, Option<ConflictPolicy>), ApiError> {
+    let PushHome { filesystem, storage, database, claims, user_id, conflicts } = *home;
+
+    let session = get_owned_upload_session(database, &session_id.to_string(), user_id).await?;
+    if normalize_path(&session.target_path) != path {
+        return Err(rejected("The upload session is for another path"));
+    }
+
+    if let Some(missing) = missing_chunks(database, &session).await? {
+        return Err(rejected(&missing));
+    }
+
+    if !base.is_set() {
+        let metadata = commit_upload_session(filesystem, storage, database, claims, &session, expected_checksum).await?;
+        return Ok((metadata, None));
+    }
+
+    let mut path = path.to_string();
+    let mut overwrite = session.overwrite;
+    let policy = conflicts.policy_for(&path);
+    let mut conflict_policy = None;
+    let mut unchanged = false;
+    let settlement = settle_conflict(storage, database, claims, user_id, &path, &base, |current| {
+        conflicts.settle(policy, modified_at, current)
+    }).await?;
+    match settlement {
+        Settlement::New => {}
+        Settlement::Unchanged => {
+            overwrite = true;
+            unchanged = true;
+        }
+        Settlement::Conflict { path: settled, policy } => {
+            overwrite = policy == ConflictPolicy::ClientWins;
+            path = settled;
+            conflict_policy = Some(policy);
+        }
+        Settlement::ServerWins(current) => {
+            // The content isn't kept, so neither is the session
+            let _ = filesystem.discard_upload_session(session.id).await;
+            let _ = database.delete_upload_session(session.id).await;
+            return Ok((current, Some(ConflictPolicy::ServerWins)));
+        }
+    }
+    let path = path.as_str();
+
+    check_file_lock(database, claims, lock_home(filesystem), path).await?;
+    let quota = get_quota_usage(filesystem, database, user_id).await?;
+    let freed = replaced_file_size(database, user_id, path, overwrite).await?;
+    if !quota.allows(session.total_size, freed) {
+        return Err(quota_exceeded(&quota, session.total_size));
+    }
+
+    if overwrite {
+        preserve_previous_version(filesystem, database, user_id, path).await?;
+    }
+
+    let staged = stage_session_upload(filesystem, database, &session, expected_checksum).await?;
+    let (metadata, conflict_policy) = if unchanged {
+        store_over_base(filesystem, storage, database, claims, user_id, path, staged, &base, |current| {
+            conflicts.settle(policy, modified_at, current)
+        }).await?
+    } else {
+        (store_staged_file(storage, database, user_id, path, staged).await?, conflict_policy)
+    };
+
+    database.delete_upload_session(session.id).await
+        .map_err(database_error)?;
+
+    Ok((metadata, conflict_policy))
+}
+
+/// The folders a device syncs, normalized; 400 for paths that climb out
+fn sync_folders(folders: &[String]) -> Result<Vec<String>, StatusCode> {
+    folders.iter()
+        .map(|folder| {
+            path_components(folder)
+                .map(|components| format!("/{}", components.join("/")))
+                .ok_or(StatusCode::BAD_REQUEST)
+        })
+        .collect()
+}
+
+/// What a device leaves out of its sync, from its session's exclude
+/// patterns. Same syntax and matching as the server-wide `ignore_patterns`.
+struct Exclusions(Gitignore);
+
+impl Exclusions {
+    /// 400 for patterns that don't parse
+    fn new(patterns: &[String]) -> Result<Self, StatusCode> {
+        let mut builder = GitignoreBuilder::new("/");
+        for pattern in patterns {
+            builder.add_line(None, pattern).map_err(|_| StatusCode::BAD_REQUEST)?;
+        }
+        Ok(Self(builder.build().map_err(|_| StatusCode::BAD_REQUEST)?))
+    }
+
+    fn none() -> Self {
+        Self(Gitignore::empty())
+    }
+
+    fn of(session: Option<&SyncSession>) -> Result<Self, StatusCode> {
+        match session {
+            // Checked when they were saved
+            Some(session) => Self::new(&session.exclude_patterns).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR),
+            None => Ok(Self::none()),
+        }
+    }
+
+    fn excludes(&self, path: &str, is_dir: bool) -> bool {
+        self.0.matched_path_or_any_parents(path, is_dir).is_ignore()
+    }
+
+    /// A change as the device sees it: moves across the edge of what it
+    /// excludes show up as the entry appearing or disappearing
+    fn clip(&self, change: FileChange) -> Option<FileChange> {
+        let is_dir = change.metadata.as_ref().map_or(false, |metadata| metadata.is_directory);
+        clip_change(change, |path| !self.excludes(path, is_dir))
+    }
+}
+
+/// A change as seen by a device syncing only `folders`, if it sees it
+fn folder_change(change: FileChange, folders: &[String]) -> Option<FileChange> {
+    // A synced folder moving, or one above it, is followed rather than lost
+    let moves_folder = change.old_path.as_deref()
+        .is_some_and(|old_path| folders.iter().any(|folder| is_within(folder, old_path)));
+    if moves_folder {
+        return Some(change);
+    }
+
+    clip_change(change, |path| folders.iter().any(|folder| is_within(path, folder)))
+}
+
+/// Fit a change to the part of the tree `covered` accepts: moves across its
+/// edge show up as the entry appearing or disappearing
+fn clip_change(mut change: FileChange, covered: impl Fn(&str) -> bool) -> Option<FileChange> {
+    let moved_from = change.old_path.take().filter(|old_path| covered(old_path));
+    if covered(&change.path) {
+        if let (ChangeType::Moved, None) = (&change.change_type, &moved_from) {
+            change.change_type = ChangeType::Created;
+        }
+        change.old_path = moved_from;
+    } else if let Some(old_path) = moved_from {
+        change.change_type = ChangeType::Deleted;
+        change.path = old_path;
+        change.metadata = None;
+    } else {
+        return None;
+    }
+
+    Some(change)
+}
+
+/// A change as `visible` lets a device see it. A folder moved into view
+/// from outside it shows up as created, followed by everything in it now,
+/// since the device never had any of it.
+async fn with_moved_in_contents(
+    database: &Database,
+    owner_id: Uuid,
+    change: FileChange,
+    visible: impl Fn(FileChange) -> Option<FileChange>,
+) -> Result<Vec<FileChange>, StatusCode> {
+    let moved_folder = matches!(change.change_type, ChangeType::Moved)
+        && change.metadata.as_ref().is_some_and(|metadata| metadata.is_directory);
+    let Some(change) = visible(change) else {
+        return Ok(Vec::new());
+    };
+    if !moved_folder || !matches!(change.change_type, ChangeType::Created) {
+        return Ok(vec![change]);
+    }
+
+    let contents = database.get_descendants(owner_id, &change.path).await
+        .map_err(database_error)?;
+    let timestamp = change.timestamp;
+    let mut changes = vec![change];
+    changes.extend(contents.into_iter().filter_map(|metadata| visible(FileChange {
+        file_id: metadata.id,
+        change_type: ChangeType::Created,
+        path: metadata.path.clone(),
+        old_path: None,
+        metadata: Some(metadata),
+        timestamp,
+        shared_by: None,
+    })));
+    Ok(changes)
+}
+
+/// Where a sync token picks up from, or None for tokens the feed can't
+/// continue: from before the horizon, from the future or from elsewhere
+async fn sync_cursor(database: &Database, token: &str, latest: i64) -> Result<Option<i64>, StatusCode> {
+    let Some(after) = token.parse::<i64>().ok().filter(|after| (0..=latest).contains(after)) else {
+        return Ok(None);
+    };
+
+    let horizon = database.get_change_log_horizon().await
+        .map_err(database_error)?;
+    Ok(horizon.map_or(true, |horizon| after >= horizon.pruned_through)
+        .then_some(after))
+}
+
+/// The changes in `window` the caller gets to see under `folders`
+/// (everywhere when empty), their shares included
+async fn sync_changes(
+    filesystem: &FileSystemService,
+    database: &Database,
+    claims: &Claims,
+    params: &HashMap<String, String>,
+    user_id: Uuid,
+    folders: &[String],
+    exclusions: &Exclusions,
+    window: ChangeWindow,
+) -> Result<Vec<FileChange>, StatusCode> {
+    let filesystem = home_filesystem(filesystem, user_id)?;
+    let filesystem = visible_filesystem(filesystem, database, claims, params).await?;
+    let visible = |change: FileChange| {
+        let is_dir = change.metadata.as_ref().map_or(false, |metadata| metadata.is_directory);
+        if filesystem.is_ignored(&change.path, is_dir) || check_scope(claims, Action::Read, &change.path).is_err() {
+            return None;
+        }
+        let change = exclusions.clip(change)?;
+        if folders.is_empty() {
+            return Some(change);
+        }
+        folder_change(change, folders)
+    };
+
+    let mut changes = Vec::new();
+    for change in window.changes(database, user_id).await? {
+        changes.extend(with_moved_in_contents(database, user_id, change, &visible).await?);
+    }
+
+    // The caller's own feed also carries what others shared with them
+    if !params.contains_key("user") {
+        changes.extend(shared_changes(&filesystem, database, claims, user_id, window).await?);
+        changes.sort_by_key(|change| change.timestamp);
+    }
+
+    Ok(changes)
+}
+
+/// The changes a sync covers: those numbered after `after` up to `through`
+#[derive(Clone, Copy)]
+struct ChangeWindow {
+    after: i64,
+    through: i64,
+}
+
+impl ChangeWindow {
+    async fn changes(self, database: &Database, owner_id: Uuid) -> Result<Vec<FileChange>, StatusCode> {
+        database.get_changes_after(owner_id, self.after, self.through).await
+            .map_err(database_error)
+    }
+}
+
+/// The caller's sync session, which has to be registered and active for
+/// its device to sync. Logins without a device have none.
+async fn registered_session(
+    database: &Database,
+    claims: &Claims,
+    user_id: Uuid,
+) -> Result<Option<SyncSession>, ApiError> {
+    let Some(device_id) = &claims.device_id else {
+        return Ok(None);
+    };
+
+    match database.get_sync_session(user_id, device_id).await {
+        Ok(Some(session)) if session.is_active => Ok(Some(session)),
+        Ok(_) => Err(unregistered_device(device_id)),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+    }
+}
+
+/// 409 for a device that has to register with `POST /sync/sessions` first
+fn unregistered_device(device_id: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::CONFLICT,
+        format!("Device '{}' is not registered for sync; register it again", device_id),
+    )
+    .with_data(json!({ "register_required": true }))
+}
+
+/// Note when the caller's device last synced, and return its session. The
+/// folders of one sync don't change the registered ones; registering and
+/// updating the session do. Failing to record it doesn't fail the sync, but
+/// an unregistered device can't sync.
+async fn record_sync(
+    database: &Database,
+    claims: &Claims,
+    user_id: Uuid,
+    synced_at: chrono::DateTime<Utc>,
+) -> Result<Option<SyncSession>, ApiError> {
+    let Some(mut session) = registered_session(database, claims, user_id).await? else {
+        return Ok(None);
+    };
+
+    session.last_sync = synced_at;
+    if let Err(e) = database.record_sync_session_sync(session.id, synced_at).await {
+        tracing::warn!("Failed to record sync session for device {}: {}", session.device_id, e);
+    }
+    Ok(Some(session))
+}
+
+/// Register the calling device for sync with the folders it wants, or bring
+/// back one that went inactive
+pub async fn register_sync_session(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<RegisterSyncSessionRequest>,
+) -> Result<Json<ApiResponse<SyncSession>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let device_id = claims.device_id.clone()
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Only a device login can register for sync"))?;
+    let folders = sync_folders(&request.folders)?;
+    Exclusions::new(&request.exclude_patterns)?;
+    let device_name = match request.device_name.map(|name| name.trim().to_string()) {
+        Some(name) if !name.is_empty() => name,
+        _ => caller_device_name(&database, &claims).await,
+    };
+
+    let existing = database.get_sync_session(user_id, &device_id).await
+        .map_err(database_error)?;
+    let session = SyncSession {
+        id: existing.as_ref().map_or_else(Uuid::new_v4, |session| session.id),
+        user_id,
+        device_id,
+        device_name,
+        last_sync: Utc::now(),
+        sync_folders: folders,
+        exclude_patterns: request.exclude_patterns,
+        is_active: true,
+    };
+    let result = match existing {
+        Some(_) => database.update_sync_session(&session).await,
+        None => database.create_sync_session(&session).await,
+    };
+    result.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(ApiResponse::success(session)))
+}
+
+/// The caller's devices registered for sync, most recently synced first
+pub async fn list_sync_sessions(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<SyncSession>>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let sessions = database.list_sync_sessions(user_id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(sessions)))
+}
+
+/// Rename one of the caller's devices, change what it syncs, or mark it
+/// inactive so it has to register again
+pub async fn update_sync_session(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(device_id): Path<String>,
+    Json(request): Json<UpdateSyncSessionRequest>,
+) -> Result<Json<ApiResponse<SyncSession>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut session = database.get_sync_session(user_id, &device_id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if let Some(name) = request.device_name {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        session.device_name = name.to_string();
+    }
+    if let Some(folders) = request.folders {
+        session.sync_folders = sync_folders(&folders)?;
+    }
+    if let Some(patterns) = request.exclude_patterns {
+        Exclusions::new(&patterns)?;
+        session.exclude_patterns = patterns;
+    }
+    if let Some(is_active) = request.is_active {
+        session.is_active = is_active;
+    }
+
+    database.update_sync_session(&session).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(session)))
+}
+
+/// Changes under the files and folders shared with `user_id`, tagged with
+/// their owners. Moves across the edge of a share show up as the file
+/// appearing or disappearing.
+async fn shared_changes(
+    filesystem: &FileSystemService,
+    database: &Database,
+    claims: &Claims,
+    user_id: Uuid,
+    window: ChangeWindow,
+) -> Result<Vec<FileChange>, StatusCode> {
+    let shares = database.get_incoming_shares(user_id).await
+        .map_err(database_error)?;
+
+    let mut by_owner: HashMap<Uuid, Vec<IncomingShare>> = HashMap::new();
+    for share in shares {
+        by_owner.entry(share.share.grantor).or_default().push(share);
+    }
+
+    let mut changes = Vec::new();
+    for (owner_id, shares) in by_owner {
+        let home = home_filesystem(filesystem, owner_id)?;
+        let covered = |path: &str| {
+            shares.iter().any(|share| share.covers(Action::Read, path))
+                && check_scope(claims, Action::Read, path).is_ok()
+        };
+
+        let visible = |change: FileChange| {
+            let is_dir = change.metadata.as_ref().map_or(false, |metadata| metadata.is_directory);
+            if home.is_ignored(&change.path, is_dir) {
+                return None;
+            }
+            clip_change(change, &covered)
+        };
+
+        for change in window.changes(database, owner_id).await? {
+            for mut change in with_moved_in_contents(database, owner_id, change, &visible).await? {
+                change.shared_by = Some(shares[0].owner.clone());
+                changes.push(change);
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Fresh short codes tried before giving up on a share link
+const SHARE_CODE_ATTEMPTS: usize = 5;
+
+pub async fn create_share_link(
+    State(database): State<Database>,
+    State(share_tokens): State<ShareTokens>,
+    State(mailer): State<Option<Mailer>>,
+    State(webhooks): State<Webhooks>,
+    Extension(claims): Extension<Claims>,
+    Path(file_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    request: Option<Json<CreateShareRequest>>,
+) -> Result<Json<ApiResponse<ShareLink>>, ApiError> {
+    let Json(request) = request.unwrap_or_default();
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let file_id = Uuid::parse_str(&file_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    // Check if file exists and user owns it
+    let file_metadata = database.get_file_metadata(file_id).await
+        .map_err(database_error)?;
+
+    let file_metadata = match file_metadata {
+        Some(metadata) if metadata.owner_id == user_id => metadata,
+        Some(_) => return Ok(Json(ApiResponse::error("Access denied".to_string()))),
+        None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+    };
+    check_scope(&claims, Action::Read, &file_metadata.path)?;
+
+    let expires_in_hours = params.get("expires_in_hours")
+        .and_then(|s| s.parse::<i64>().ok())
+        .unwrap_or(24);
+
+    let max_downloads = params.get("max_downloads")
+        .and_then(|s| s.parse::<u32>().ok());
+
+    let recipients = share_recipients(mailer.as_ref(), &request.notify)?;
+
+    let alias = params.get("alias").cloned();
+    if let Some(alias) = &alias {
+        share_tokens::check_alias(alias).map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+        if share_code_taken(&database, alias).await? {
+            return Err(alias_taken(alias));
+        }
+    }
+
+    let id = Uuid::new_v4();
+    let expires_at = Some(Utc::now() + chrono::Duration::hours(expires_in_hours));
+    let mut share_link = ShareLink {
+        id,
+        file_id,
+        created_by: user_id,
+        share_token: share_tokens.sign(id, expires_at),
+        expires_at,
+        password_protected: false,
+        download_count: 0,
+        max_downloads,
+        created_at: Utc::now(),
+        revoked_at: None,
+        password_hash: None,
+        short_code: None,
+        alias,
+    };
+
+    // Codes are checked up front; the unique indexes catch the rare race
+    for _ in 0..SHARE_CODE_ATTEMPTS {
+        let code = share_tokens.new_code();
+        if share_code_taken(&database, &code).await? {
+            continue;
+        }
+        share_link.short_code = Some(code);
+
+        match database.create_share_link(&share_link).await {
+            Ok(()) => {
+                if let (Some(mailer), false) = (&mailer, recipients.is_empty()) {
+                    mailer.notify_share(&share_link, &claims.username, &file_metadata.name, recipients);
+                }
+                webhooks.notify_share(&share_link, &file_metadata);
+                return Ok(Json(ApiResponse::success(share_link)));
+            }
+            Err(e) if crate::database::is_unique_violation(&e) => {
+                if let Some(alias) = &share_link.alias {
+                    if share_code_taken(&database, alias).await? {
+                        return Err(alias_taken(alias));
+                    }
+                }
+            }
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+        }
+    }
+
+    tracing::error!("No free share code after {} attempts", SHARE_CODE_ATTEMPTS);
+    Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+}
+
+/// Parse the addresses a new link is to be emailed to. Asking for emails
+/// where email isn't set up is an error rather than silently dropped.
+fn share_recipients(mailer: Option<&Mailer>, notify: &[String]) -> Result<Vec<Mailbox>, ApiError> {
+    if notify.is_empty() {
+        return Ok(Vec::new());
+    }
+    if mailer.is_none() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Email notifications aren't set up on this server"));
+    }
+    if notify.len() > notifications::MAX_RECIPIENTS {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Share links can be emailed to at most {} addresses", notifications::MAX_RECIPIENTS),
+        ));
+    }
+
+    notify.iter()
+        .map(|address| address.parse::<Mailbox>().map_err(|_| {
+            ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid email address '{}'", address))
+        }))
+        .collect()
+}
+
+/// Whether a short code or alias already points at a share
+async fn share_code_taken(database: &Database, code: &str) -> Result<bool, StatusCode> {
+    database.get_share_link_by_code(code).await
+        .map(|share_link| share_link.is_some())
+        .map_err(database_error)
+}
+
+fn alias_taken(alias: &str) -> ApiError {
+    ApiError::new(StatusCode::CONFLICT, format!("The alias '{}' is taken", alias))
+}
+
+/// The caller's live share links, with the files they point to
+pub async fn list_shares(
+    State(database): State<Database>,
+    State(pages): State<PageLimits>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<Paginated<ShareSummary>>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let page = pages.request(&params)?;
+    let now = Utc::now();
+
+    let shares = database.get_share_links_by_user(user_id, now, page.after_time()?, page.fetch(), page.offset as i64).await
+        .map_err(database_error)?;
+    let total = database.count_share_links_by_user(user_id, now).await
+        .map_err(database_error)?;
+    let mut shares = page.page(shares, total, |share| time_key(share.share.created_at, share.share.id));
+    shares.items.retain(|share| check_scope(&claims, Action::Read, &share.file_path).is_ok());
+
+    Ok(Json(ApiResponse::success(shares)))
+}
+
+/// One of the caller's live share links, if its file is within their scopes
+async fn owned_share_link(database: &Database, claims: &Claims, share_id: &str) -> Result<ShareLink, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let share_id = Uuid::parse_str(share_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let share_link = database.get_share_link(share_id).await
+        .map_err(database_error)?
+        .filter(|share_link| share_link.created_by == user_id && share_link.revoked_at.is_none())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if let Some(file) = database.get_file_metadata(share_link.file_id).await
+        .map_err(database_error)? {
+        check_scope(claims, Action::Read, &file.path)?;
+    }
+
+    Ok(share_link)
+}
+
+/// Turn a share link off. Its public URL answers 410 from then on.
+pub async fn revoke_share(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(share_id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let share_link = owned_share_link(&database, &claims, &share_id).await?;
+
+    let revoked = database.delete_share_link(share_link.id, share_link.created_by, Utc::now()).await
+        .map_err(database_error)?;
+    if !revoked {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Change a share link's expiry, download limit or password. A new expiry
+/// is signed into a new token; URLs handed out before keep working until
+/// the earlier of their own expiry and the new one.
+pub async fn update_share(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    State(share_tokens): State<ShareTokens>,
+    Extension(claims): Extension<Claims>,
+    Path(share_id): Path<String>,
+    Json(request): Json<UpdateShareRequest>,
+) -> Result<Json<ApiResponse<ShareLink>>, ApiError> {
+    let mut share_link = owned_share_link(&database, &claims, &share_id).await?;
+
+    if let Some(expires_at) = request.expires_at {
+        if expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "expires_at must be in the future"));
+        }
+        share_link.expires_at = expires_at;
+        // Links from before signing carry no expiry to replace
+        if !share_tokens::is_legacy_token(&share_link.share_token) {
+            share_link.share_token = share_tokens.sign(share_link.id, expires_at);
+        }
+    }
+    if let Some(max_downloads) = request.max_downloads {
+        share_link.max_downloads = max_downloads;
+    }
+    if let Some(password) = request.password {
+        share_link.password_hash = match password {
+            Some(password) if password.is_empty() => {
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, "Share password must not be empty"));
+            }
+            Some(password) => Some(auth_service.hash_password(&password)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?),
+            None => None,
+        };
+        share_link.password_protected = share_link.password_hash.is_some();
+    }
+
+    let updated = database.update_share_link(&share_link).await
+        .map_err(database_error)?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    Ok(Json(ApiResponse::success(share_link)))
+}
+
+/// Visits to one of the caller's share links, newest first. Revoked links
+/// keep their activity until it ages out with the audit log.
+pub async fn get_share_activity(
+    State(database): State<Database>,
+    State(pages): State<PageLimits>,
+    Extension(claims): Extension<Claims>,
+    Path(share_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<Paginated<ShareAccess>>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let share_id = Uuid::parse_str(&share_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    database.get_share_link(share_id).await
+        .map_err(database_error)?
+        .filter(|share_link| share_link.created_by == user_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let page = pages.request(&params)?;
+
+    let activity = database.list_share_access(share_id, page.after_time()?, page.fetch(), page.offset as i64).await
+        .map_err(database_error)?;
+    let total = database.count_share_access(share_id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(page.page(activity, total, |access| time_key(access.accessed_at, access.id)))))
+}
+
+/// Share one of the caller's files or folders with another local user, or
+/// change what an existing share allows them
+pub async fn share_with_user(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(file_id): Path<String>,
+    Json(request): Json<ShareWithRequest>,
+) -> Result<Json<ApiResponse<UserShare>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let file = get_owned_file(&database, &file_id, user_id).await?;
+    check_scope(&claims, Action::Read, &file.path)?;
+    if request.permissions == SharePermissions::ReadWrite {
+        check_scope(&claims, Action::Write, &file.path)?;
+    }
+
+    let grantee = database.get_user_by_username(&request.username).await
+        .map_err(database_error)?
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "User not found"))?;
+    if grantee.id == user_id {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Files can't be shared with their owner"));
+    }
+
+    let share = UserShare {
+        id: Uuid::new_v4(),
+        file_id: file.id,
+        grantor: user_id,
+        grantee: grantee.id,
+        permissions: request.permissions,
+        created_at: Utc::now(),
+    };
+    database.create_user_share(&share).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(share)))
+}
+
+/// Stop sharing one of the caller's files or folders with a user
+pub async fn unshare_with_user(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path((file_id, username)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let file = get_owned_file(&database, &file_id, user_id).await?;
+    check_scope(&claims, Action::Read, &file.path)?;
+
+    let grantee = database.get_user_by_username(&username).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let removed = database.delete_user_share(file.id, grantee.id).await
+        .map_err(database_error)?;
+    if !removed {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Files and folders other users shared with the caller
+pub async fn list_shared_with_me(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<IncomingShare>>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut shares = database.get_incoming_shares(user_id).await
+        .map_err(database_error)?;
+    shares.retain(|share| check_scope(&claims, Action::Read, &share.path).is_ok());
+
+    Ok(Json(ApiResponse::success(shares)))
+}
+
+/// Whether `user_id` may read `file`: it's theirs, or a share with them
+/// covers it
+fn can_read_file(user_id: Uuid, shares: &[IncomingShare], file: &FileMetadata) -> bool {
+    file.owner_id == user_id
+        || shares.iter().any(|share| share.share.grantor == file.owner_id && share.covers(Action::Read, &file.path))
+}
+
+/// Star one of the caller's files or folders, or one shared with them
+pub async fn add_favorite(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(file_id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let file_id = Uuid::parse_str(&file_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let file = database.get_file_metadata(file_id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let shares = database.get_incoming_shares(user_id).await
+        .map_err(database_error)?;
+    if !can_read_file(user_id, &shares, &file) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    check_scope(&claims, Action::Read, &file.path)?;
+
+    database.add_favorite(user_id, file.id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Take the caller's star off a file or folder. Nothing needs to be
+/// readable for that, so stars outlive the shares they were made through.
+pub async fn remove_favorite(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(file_id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let file_id = Uuid::parse_str(&file_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    database.remove_favorite(user_id, file_id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// What the caller starred that they can still read
+pub async fn list_favorites(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<FileMetadata>>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut favorites = database.get_favorites(user_id).await
+        .map_err(database_error)?;
+    let shares = database.get_incoming_shares(user_id).await
+        .map_err(database_error)?;
+    favorites.retain(|file| can_read_file(user_id, &shares, file) && check_scope(&claims, Action::Read, &file.path).is_ok());
+
+    Ok(Json(ApiResponse::success(favorites)))
+}
+
+/// Longest a tag may be, in characters
+const MAX_TAG_LENGTH: usize = 64;
+
+/// A tag name as it is stored: trimmed and lowercase, so `Taxes 2024 ` and
+/// `taxes 2024` are one tag. 400 for an empty or overlong one.
+fn tag_name(name: &str) -> Result<String, ApiError> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() || name.chars().count() > MAX_TAG_LENGTH {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Tags must be 1 to {} characters long", MAX_TAG_LENGTH),
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Tags can't contain control characters"));
+    }
+    Ok(name)
+}
+
+/// Tag one of the caller's files or folders, or one shared with them.
+/// Returns the caller's tags on it.
+pub async fn add_file_tag(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(file_id): Path<String>,
+    Json(request): Json<TagRequest>,
+) -> Result<Json<ApiResponse<Vec<String>>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let file_id = Uuid::parse_str(&file_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let name = tag_name(&request.name)?;
+
+    let file = database.get_file_metadata(file_id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let shares = database.get_incoming_shares(user_id).await
+        .map_err(database_error)?;
+    if !can_read_file(user_id, &shares, &file) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    check_scope(&claims, Action::Read, &file.path)?;
+
+    database.add_file_tag(user_id, file.id, &name).await
+        .map_err(database_error)?;
+    let mut tags = database.get_file_tags(user_id, &[file.id]).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(tags.remove(&file.id).unwrap_or_default())))
+}
+
+/// Take one of the caller's tags off a file. Like unstarring, this needs
+/// no access to the file.
+pub async fn remove_file_tag(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path((file_id, tag)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<()>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let file_id = Uuid::parse_str(&file_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let name = tag_name(&tag)?;
+
+    database.remove_file_tag(user_id, file_id, &name).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// The caller's tags with how many files carry each
+pub async fn list_tags(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<TagUsage>>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let tags = database.get_tags(user_id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(tags)))
+}
+
+/// Rename one of the caller's tags on every file it is on. Renaming it to
+/// a tag they already have merges the two.
+pub async fn rename_tag(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(tag): Path<String>,
+    Json(request): Json<TagRequest>,
+) -> Result<Json<ApiResponse<()>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let from = tag_name(&tag)?;
+    let to = tag_name(&request.name)?;
+
+    let renamed = database.rename_tag(user_id, &from, &to).await
+        .map_err(database_error)?;
+    if !renamed {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "Tag not found"));
+    }
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Longest a comment may be, in characters
+const MAX_COMMENT_LENGTH: usize = 10_000;
+
+/// A comment body as it is stored, trimmed. 400 for an empty or overlong one.
+fn comment_body(body: &str) -> Result<String, ApiError> {
+    let body = body.trim();
+    if body.is_empty() || body.chars().count() > MAX_COMMENT_LENGTH {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Comments must be 1 to {} characters long", MAX_COMMENT_LENGTH),
+        ));
+    }
+    Ok(body.to_string())
+}
+
+/// The file `file_id` once the caller is known to be able to read it, and
+/// whether they can write to it too
+async fn commented_file(
+    database: &Database,
+    claims: &Claims,
+    user_id: Uuid,
+    file_id: &str,
+) -> Result<(FileMetadata, bool), ApiError> {
+    let file_id = Uuid::parse_str(file_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let file = database.get_file_metadata(file_id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let shares = database.get_incoming_shares(user_id).await
+        .map_err(database_error)?;
+    if !can_read_file(user_id, &shares, &file) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    check_scope(claims, Action::Read, &file.path)?;
+
+    let writable = (file.owner_id == user_id
+        || shares.iter().any(|share| share.share.grantor == file.owner_id && share.covers(Action::Write, &file.path)))
+        && check_scope(claims, Action::Write, &file.path).is_ok();
+    Ok((file, writable))
+}
+
+/// The comment `comment_id` on `file`
+async fn file_comment(database: &Database, file: &FileMetadata, comment_id: &str) -> Result<Comment, ApiError> {
+    let comment_id = Uuid::parse_str(comment_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    database.get_comment(comment_id).await
+        .map_err(database_error)?
+        .filter(|comment| comment.file_id == file.id)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Comment not found"))
+}
+
+/// The comments on a file the caller can read, oldest first
+pub async fn list_comments(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(file_id): Path<String>,
+) -> Result<Json<ApiResponse<Vec<Comment>>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (file, _) = commented_file(&database, &claims, user_id, &file_id).await?;
+
+    let comments = database.get_comments(file.id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(comments)))
+}
+
+/// Comment on a file the caller can write to
+pub async fn add_comment(
+    State(database): State<Database>,
+    State(webhooks): State<Webhooks>,
+    Extension(claims): Extension<Claims>,
+    Path(file_id): Path<String>,
+    Json(request): Json<CommentRequest>,
+) -> Result<Json<ApiResponse<Comment>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let body = comment_body(&request.body)?;
+    let (file, writable) = commented_file(&database, &claims, user_id, &file_id).await?;
+    if !writable {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let comment = Comment {
+        id: Uuid::new_v4(),
+        file_id: file.id,
+        author_id: Some(user_id),
+        author: Some(claims.username.clone()),
+        body,
+        created_at: Utc::now(),
+        edited_at: None,
+    };
+    database.create_comment(&comment).await
+        .map_err(database_error)?;
+    webhooks.notify_comment(&comment, &file);
+
+    Ok(Json(ApiResponse::success(comment)))
+}
+
+/// Change the body of one of the caller's own comments
+pub async fn edit_comment(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path((file_id, comment_id)): Path<(String, String)>,
+    Json(request): Json<CommentRequest>,
+) -> Result<Json<ApiResponse<Comment>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let body = comment_body(&request.body)?;
+    let (file, _) = commented_file(&database, &claims, user_id, &file_id).await?;
+    let mut comment = file_comment(&database, &file, &comment_id).await?;
+
+    // Others' words stay theirs, even for those who can write to the file
+    if comment.author_id != Some(user_id) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let edited_at = Utc::now();
+    let updated = database.update_comment(comment.id, &body, edited_at).await
+        .map_err(database_error)?;
+    if !updated {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "Comment not found"));
+    }
+
+    comment.body = body;
+    comment.edited_at = Some(edited_at);
+    Ok(Json(ApiResponse::success(comment)))
+}
+
+/// Delete a comment: the caller's own, or any on a file they can write to
+pub async fn delete_comment(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path((file_id, comment_id)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<()>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (file, writable) = commented_file(&database, &claims, user_id, &file_id).await?;
+    let comment = file_comment(&database, &file, &comment_id).await?;
+
+    if comment.author_id != Some(user_id) && !writable {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    database.delete_comment(comment.id).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Password sent for a protected share link, in the `X-Share-Password`
+/// header or, for plain browser links, the `password` query parameter
+fn share_password<'a>(headers: &'a HeaderMap, params: &'a HashMap<String, String>) -> Option<&'a str> {
+    headers.get("x-share-password")
+        .and_then(|password| password.to_str().ok())
+        .or_else(|| params.get("password").map(String::as_str))
+}
+
+/// The share link a public request is for and the file or folder it shares,
+/// once the link is known to be live and any password matches. Signed
+/// tokens that are forged or expired are turned away before the database is
+/// asked. The row is read on every request, so a revoked link stops working
+/// at once.
+async fn redeem_share_link(
+    auth_service: &AuthService,
+    database: &Database,
+    share_tokens: &ShareTokens,
+    token: &str,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+    visit: &ShareVisit,
+) -> Result<(ShareLink, FileMetadata), StatusCode> {
+    let share_link = if share_tokens::is_legacy_token(token) {
+        if !share_tokens.accepts_legacy() {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        tracing::warn!("Share link used with a legacy unsigned token");
+        database.get_share_link_by_token(token).await
+    } else if share_tokens::is_short_token(token) {
+        database.get_share_link_by_code(token).await
+    } else {
+        let signed = share_tokens.verify(token, Utc::now())
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        database.get_share_link(signed.share_id).await
+    }
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if share_link.revoked_at.is_some() {
+        visit.record(share_link.id, ShareAccessOutcome::Revoked, 0);
+        return Err(StatusCode::GONE);
+    }
+    if share_link.is_expired(Utc::now()) {
+        visit.record(share_link.id, ShareAccessOutcome::Expired, 0);
+        return Err(StatusCode::GONE);
+    }
+    if let Some(password_hash) = &share_link.password_hash {
+        let matches = match share_password(headers, params) {
+            Some(password) => auth_service.verify_password(password, password_hash)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+            None => false,
+        };
+        if !matches {
+            visit.record(share_link.id, ShareAccessOutcome::WrongPassword, 0);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    let shared = database.get_file_metadata(share_link.file_id).await
+        .map_err(database_error)?;
+    let Some(shared) = shared else {
+        visit.record(share_link.id, ShareAccessOutcome::NotFound, 0);
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    Ok((share_link, shared))
+}
+
+/// Path of `relative_path` inside a shared folder. Compared by components,
+/// so `..` and absolute paths can't get out of it.
+fn shared_path(folder: &FileMetadata, relative_path: &str) -> Result<String, StatusCode> {
+    let relative = path_components(relative_path).ok_or(StatusCode::BAD_REQUEST)?;
+    let root = path_components(&folder.path).ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(format!("/{}", root.into_iter().chain(relative).collect::<Vec<_>>().join("/")))
+}
+
+/// Stream one shared file, counting it against the link's downloads
+async fn serve_shared_file(
+    storage: &dyn StorageBackend,
+    database: &Database,
+    share_link: &ShareLink,
+    path: &str,
+    visit: &ShareVisit,
+) -> Result<Response, StatusCode> {
+    let file_metadata = match storage.metadata(path).await {
+        Ok(file_metadata) if !file_metadata.is_directory => file_metadata,
+        _ => {
+            visit.record(share_link.id, ShareAccessOutcome::NotFound, 0);
+            return Err(StatusCode::NOT_FOUND);
+        }
+    };
+
+    // Counted only once the file is known to be there
+    if !database.record_share_download(share_link.id).await.map_err(database_error)? {
+        visit.record(share_link.id, ShareAccessOutcome::DownloadsUsedUp, 0);
+        return Err(StatusCode::GONE);
+    }
+
+    let stream = storage.get_stream(path, None).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    visit.record(share_link.id, ShareAccessOutcome::Success, file_metadata.size);
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, file_metadata.mime_type)
+        .header(header::CONTENT_LENGTH, file_metadata.size)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_metadata.name),
+        )
+        .body(axum::body::Body::from_stream(stream))
+        .unwrap())
+}
+
+/// Download through a share link, or for a shared folder list it. `path=`
+/// lists one of its subfolders. Listings show names, sizes and types only,
+/// and don't count against the link's downloads.
+pub async fn download_shared_file(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    State(share_tokens): State<ShareTokens>,
+    State(share_access): State<ShareAccessLog>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let visit = share_access.visit(client, &headers);
+    let (share_link, shared) = redeem_share_link(
+        &auth_service, &database, &share_tokens, &token, &headers, &params, &visit,
+    ).await?;
+    let storage = home_storage(storage.as_ref(), shared.owner_id)?;
+
+    if !shared.is_directory {
+        return serve_shared_file(storage.as_ref(), &database, &share_link, &shared.path, &visit).await;
+    }
+
+    let relative = params.get("path").map_or("", String::as_str);
+    let path = shared_path(&shared, relative)?;
+    let filesystem = home_filesystem(&filesystem, shared.owner_id)?;
+    let listed = if path != shared.path && filesystem.is_ignored(&path, true) {
+        None
+    } else {
+        storage.list(&path).await.ok()
+    };
+    let Some(mut entries) = listed else {
+        visit.record(share_link.id, ShareAccessOutcome::NotFound, 0);
+        return Err(StatusCode::NOT_FOUND);
+    };
+    filesystem.filter_ignored(&mut entries);
+    visit.record(share_link.id, ShareAccessOutcome::Success, 0);
+
+    let prefix = format!("{}/", shared.path.trim_end_matches('/'));
+    let entries = entries.into_iter()
+        .map(|entry| SharedEntry {
+            path: entry.path.strip_prefix(&prefix).unwrap_or(&entry.name).to_string(),
+            name: entry.name,
+            size: entry.size,
+            mime_type: entry.mime_type,
+            is_directory: entry.is_directory,
+            modified_at: entry.modified_at,
+        })
+        .collect();
+
+    let listing = SharedFolderListing {
+        name: shared.name,
+        path: path.strip_prefix(&prefix).unwrap_or("").to_string(),
+        entries,
+    };
+    Ok(Json(ApiResponse::success(listing)).into_response())
+}
+
+/// Download one file from a shared folder. Each file counts against the
+/// link's downloads.
+pub async fn download_shared_folder_file(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    State(share_tokens): State<ShareTokens>,
+    State(share_access): State<ShareAccessLog>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path((token, relative_path)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let visit = share_access.visit(client, &headers);
+    let (share_link, shared) = redeem_share_link(
+        &auth_service, &database, &share_tokens, &token, &headers, &params, &visit,
+    ).await?;
+
+    let relative_path = urlencoding::decode(&relative_path)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let path = shared_path(&shared, &relative_path)?;
+    if !shared.is_directory
+        || path == shared.path
+        || home_filesystem(&filesystem, shared.owner_id)?.is_ignored(&path, false)
+    {
+        visit.record(share_link.id, ShareAccessOutcome::NotFound, 0);
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let storage = home_storage(storage.as_ref(), shared.owner_id)?;
+    serve_shared_file(storage.as_ref(), &database, &share_link, &path, &visit).await
+}
+
+/// The requesting user's quota from the database. Admins also get the
+/// server's disks and usage, the latter from the admin statistics cache.
+pub async fn get_storage_info(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(database): State<Database>,
+    State(downloads): State<DownloadCounter>,
+    State(stats): State<StatsCache>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<StorageInfo>>, StatusCode> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let quota = get_quota_usage(&filesystem, &database, user_id).await?;
+
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Ok(Json(ApiResponse::success(StorageInfo { disk: None, mounts: None, usage: None, quota })));
+    }
+
+    let disk = storage.free_space().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let stats = stats.get_or_gather(|| gather_admin_stats(&filesystem, &database, &downloads)).await
+        .map_err(|e| {
+            tracing::error!("Failed to gather statistics: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(ApiResponse::success(StorageInfo {
+        disk: Some(disk),
+        mounts: Some(stats.mounts),
+        usage: Some(stats.usage),
+        quota,
+    })))
+}
+
+/// Events read from the database at a time while building a page of the
+/// activity feed
+const ACTIVITY_BATCH: i64 = 500;
+/// Alike events this close together are shown as one entry of the feed
+const ACTIVITY_BUCKET_MINUTES: i64 = 60;
+
+/// The user's recent activity, newest first: changes to their files, and
+/// downloads through their share links, with runs of alike events grouped
+/// into one entry. `include=comments` adds comments on their files.
+/// `before` takes the `next_before` of the previous page.
+pub async fn get_activity(
+    State(database): State<Database>,
+    State(pages): State<PageLimits>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<ActivityPage>>, ApiError> {
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let limit = pages.request(&params)?.limit as usize;
+    let mut before = params.get("before")
+        .map(|before| {
+            URL_SAFE_NO_PAD.decode(before).ok()
+                .and_then(|key| String::from_utf8(key).ok())
+                .and_then(|key| parse_time_key(&key))
+                .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Invalid before"))
+        })
+        .transpose()?;
+
+    let with_comments = params.get("include")
+        .is_some_and(|include| include.split(',').any(|part| part.trim() == "comments"));
+
+    // Scoped tokens only see activity in their folders, which the database
+    // picks out so it isn't read through to find them
+    let within: Vec<String> = claims.scopes.iter()
+        .filter(|scope| scope.action == Action::Read)
+        .filter_map(|scope| path_components(&scope.path_prefix))
+        .map(|folder| format!("/{}", folder.join("/")))
+        .collect();
+    if !claims.scopes.is_empty() && within.is_empty() {
+        return Ok(Json(ApiResponse::success(ActivityPage { items: Vec::new(), next_before: None })));
+    }
+
+    // Read on until the groups of the page are complete, which takes an
+    // event past them
+    let mut grouper = ActivityGrouper::new(chrono::Duration::minutes(ACTIVITY_BUCKET_MINUTES));
+    loop {
+        let events = database.get_activity_before(user_id, before, ACTIVITY_BATCH, with_comments, &within).await
+            .map_err(database_error)?;
+        let exhausted = (events.len() as i64) < ACTIVITY_BATCH;
+        before = events.last().map(|last| (last.occurred_at, last.id));
+        for event in events {
+            if check_scope(&claims, Action::Read, &event.path).is_ok() {
+                grouper.push(event);
+            }
+        }
+        if exhausted || grouper.has_more_than(limit) {
+            break;
+        }
+    }
+
+    let (items, next_before) = grouper.finish(limit);
+    let next_before = next_before.map(|(time, id)| URL_SAFE_NO_PAD.encode(time_key(time, id)));
+    Ok(Json(ApiResponse::success(ActivityPage { items, next_before })))
+}
+
+pub async fn set_user_quota(
+    State(filesystem): State<FileSystemService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(target_user_id): Path<String>,
+    Json(request): Json<SetQuotaRequest>,
+) -> Result<Json<ApiResponse<QuotaUsage>>, StatusCode> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let target_user_id = Uuid::parse_str(&target_user_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let updated = database.set_user_quota(target_user_id, request.quota_bytes).await
+        .map_err(database_error)?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let quota = get_quota_usage(&filesystem, &database, target_user_id).await?;
+    Ok(Json(ApiResponse::success(quota)))
+}
+
+/// Invalidate every token the user holds, e.g. when a device is stolen.
+/// They have to log in again.
+pub async fn revoke_user_tokens(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(target_user_id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let target_user_id = Uuid::parse_str(&target_user_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let now = Utc::now();
+    let updated = database.set_tokens_valid_after(target_user_id, now).await
+        .map_err(database_error)?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    auth_service.revoke_user_tokens(target_user_id, now);
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// A short-lived token to act as another user, for seeing what their account
+/// sees. Everything done with it is audited as the admin's.
+pub async fn impersonate_user(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(target_user_id): Path<String>,
+) -> Result<Json<ApiResponse<ImpersonationResponse>>, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let admin_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let target_user_id = Uuid::parse_str(&target_user_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    if target_user_id == admin_id {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "You can't impersonate yourself"));
+    }
+
+    let user = database.get_user_by_id(target_user_id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !user.is_active {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!("User '{}' is deactivated", user.username)));
+    }
+    let account = database.get_user_account(user.id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let impersonator = Impersonator { id: admin_id, username: claims.username.clone() };
+    let (token, impersonation) = auth_service.issue_impersonation_token(&user, impersonator)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let expires_at = chrono::DateTime::from_timestamp(impersonation.exp, 0)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(ApiResponse::success(ImpersonationResponse { token, expires_at, user: account })))
+}
+
+/// Recent login attempts, newest first, for spotting password guessing
+pub async fn list_login_attempts(
+    State(database): State<Database>,
+    State(pages): State<PageLimits>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<Paginated<LoginAttempt>>>, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    let page = pages.request(&params)?;
+    let username = params.get("username").map(String::as_str);
+
+    let attempts = database.list_login_attempts(username, page.after_time()?, page.fetch(), page.offset as i64).await
+        .map_err(database_error)?;
+    let total = database.count_login_attempts(username).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(page.page(attempts, total, |attempt| time_key(attempt.attempted_at, attempt.id)))))
+}
+
+/// The audit log, newest first, a page at a time. `since` (RFC 3339),
+/// `user` (a username) and `event` narrow it down.
+pub async fn list_audit_events(
+    State(database): State<Database>,
+    State(pages): State<PageLimits>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<Paginated<AuditEntry>>>, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let since = params.get("since")
+        .map(|since| chrono::DateTime::parse_from_rfc3339(since).map(|since| since.with_timezone(&Utc)))
+        .transpose()
+        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "since must be an RFC 3339 time"))?;
+    let event = params.get("event")
+        .map(|event| AuditEvent::parse(event).ok_or_else(|| {
+            ApiError::new(StatusCode::BAD_REQUEST, format!("Unknown audit event '{}'", event))
+        }))
+        .transpose()?;
+
+    let page = pages.request(&params)?;
+    let username = params.get("user").map(String::as_str);
+
+    let entries = database.list_audit_events(since, username, event, page.after_time()?, page.fetch(), page.offset as i64).await
+        .map_err(database_error)?;
+    let total = database.count_audit_events(since, username, event).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(page.page(entries, total, |entry| time_key(entry.occurred_at, entry.id)))))
+}
+
+/// Everyone with an account, by name, a page at a time
+pub async fn list_users(
+    State(database): State<Database>,
+    State(pages): State<PageLimits>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<Paginated<UserAccount>>>, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    let page = pages.request(&params)?;
+
+    let users = database.list_users(page.after.as_deref(), page.fetch(), page.offset as i64).await
+        .map_err(database_error)?;
+    let total = database.count_users().await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(page.page(users, total, |user| user.username.clone()))))
+}
+
+pub async fn create_user(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<CreateUserRequest>,
+) -> Result<Json<ApiResponse<UserAccount>>, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let username = request.username.trim();
+    if username.is_empty() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Username is required"));
+    }
+    auth_service.validate_password(&request.password).map_err(password_rejected)?;
+
+    let existing = database.get_user_by_username(username).await
+        .map_err(database_error)?;
+    if existing.is_some() {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!("User '{}' already exists", username)));
+    }
+
+    let password_hash = auth_service.hash_password(&request.password)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = User {
+        id: Uuid::new_v4(),
+        username: username.to_string(),
+        email: request.email.filter(|email| !email.trim().is_empty()),
+        password_hash,
+        created_at: Utc::now(),
+        last_login: None,
+        is_active: true,
+        auth_generation: 0,
+        permissions: request.permissions.iter().map(|permission| permission.as_str().to_string()).collect(),
+    };
+    database.create_user(&user).await
+        .map_err(database_error)?;
+
+    if request.quota_bytes.is_some() {
+        database.set_user_quota(user.id, request.quota_bytes).await
+            .map_err(database_error)?;
+    }
+
+    let account = database.get_user_account(user.id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(ApiResponse::success(account)))
+}
+
+/// Activate or deactivate a user, or change their permissions or quota.
+/// Deactivating logs them out everywhere.
+pub async fn update_user(
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(target_user_id): Path<String>,
+    Json(request): Json<UpdateUserRequest>,
+) -> Result<Json<ApiResponse<UserAccount>>, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let target_user_id = Uuid::parse_str(&target_user_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    // Admins can't lock themselves out
+    if target_user_id.to_string() == claims.sub {
+        if request.is_active == Some(false) {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "You can't deactivate your own account"));
+        }
+        if request.permissions.as_ref().is_some_and(|permissions| !permissions.contains(&Permission::Admin)) {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "You can't take away your own admin permission"));
+        }
+    }
+
+    let updated = database.update_user(target_user_id, &request).await
+        .map_err(database_error)?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    // Tokens issued before stay revoked if the user is activated again
+    if request.is_active == Some(false) {
+        let now = Utc::now();
+        database.set_tokens_valid_after(target_user_id, now).await
+            .map_err(database_error)?;
+        auth_service.revoke_user_tokens(target_user_id, now);
+    }
+
+    let account = database.get_user_account(target_user_id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(ApiResponse::success(account)))
+}
+
+/// Remove a user for good. `files=delete` removes what they stored, leaving
+/// directories that hold other users' files; `files=reassign` hands it all to
+/// the admin doing the deleting, in a folder named after the user when
+/// everyone has their own home. Their trash is emptied either way.
+pub async fn delete_user(
+    State(filesystem): State<FileSystemService>,
+    State(storage): State<Arc<dyn StorageBackend>>,
+    State(auth_service): State<AuthService>,
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+    Path(target_user_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<()>>, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let admin_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let target_user_id = Uuid::parse_str(&target_user_id)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let policy = match params.get("files").and_then(|files| OrphanedFiles::parse(files)) {
+        Some(policy) => policy,
+        None => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "Choose what happens to the user's files with files=delete or files=reassign",
+            ));
+        }
+    };
+    if target_user_id == admin_id {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "You can't delete your own account"));
+    }
+
+    let user = database.get_user_by_id(target_user_id).await
+        .map_err(database_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    // With homes, reassigned files move into a folder named after the user
+    let path_prefix = match policy {
+        OrphanedFiles::Reassign if filesystem.user_homes() => {
+            let folder = format!("/{}", user.username);
+            let admin_home = home_storage(storage.as_ref(), admin_id)?;
+            let recorded = database.get_file_metadata_by_path(admin_id, &folder).await
+                .map_err(database_error)?;
+            if recorded.is_some() || admin_home.metadata(&folder).await.is_ok() {
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    format!("{} already exists in your home; move it out of the way first", folder),
+                ));
+            }
+            Some(folder)
+        }
+        _ => None,
+    };
+
+    // Whatever they still hold stops working now
+    auth_service.revoke_user_tokens(target_user_id, Utc::now());
+
+    let home = home_storage(storage.as_ref(), target_user_id)?;
+    let trash = database.list_trash_entries(target_user_id).await
+        .map_err(database_error)?;
+    let sessions = database.get_user_upload_sessions(target_user_id).await
+        .map_err(database_error)?;
+
+    if policy == OrphanedFiles::Delete {
+        remove_owned_files(&filesystem, home.as_ref(), &database, target_user_id).await?;
+    }
+
+    // The records settle first, so a failure leaves the files where their
+    // rows say they are
+    let deleted = database.delete_user(target_user_id, admin_id, path_prefix.as_deref()).await
+        .map_err(|e| {
+            tracing::error!("Failed to delete user {}: {}", target_user_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    remove_unused_versions(&filesystem, &deleted).await;
+
+    for entry in trash {
+        let location = FileSystemService::trash_location(target_user_id, entry.id);
+        if home.metadata(&location).await.is_ok() {
+            if let Err(e) = home.delete(&location).await {
+                tracing::warn!("Failed to remove {} from the trash of a deleted user: {}", entry.original_path, e);
+            }
+        }
+    }
+
+    for session_id in sessions {
+        if let Err(e) = filesystem.discard_upload_session(session_id).await {
+            tracing::warn!("Failed to discard upload session {}: {}", session_id, e);
+        }
+    }
+
+    if let Some(folder) = &path_prefix {
+        let from = FileSystemService::home_location(target_user_id);
+        if storage.metadata(&from).await.is_ok() {
+            let to = format!("{}{}", FileSystemService::home_location(admin_id), folder);
+            if let Err(e) = storage.rename(&from, &to).await {
+                tracing::error!("Reassigned the files of {} but failed to move {} to {}: {}", user.username, from, to, e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+            }
+        }
+    }
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+/// Delete the files on record as the user's, then their directories once
+/// nothing else is left inside. Rows still flagged as trashed after their
+/// trash was emptied go too.
+async fn remove_owned_files(
+    filesystem: &FileSystemService,
+    storage: &dyn StorageBackend,
+    database: &Database,
+    user_id: Uuid,
+) -> Result<(), StatusCode> {
+    let owned = database.get_files_owned_by_including_deleted(user_id).await
+        .map_err(database_error)?;
+    let (directories, files): (Vec<_>, Vec<_>) = owned.into_iter().partition(|metadata| metadata.is_directory);
+
+    for file in &files {
+        if storage.metadata(&file.path).await.is_ok() {
+            storage.delete(&file.path).await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+        let deleted = database.delete_file_metadata(file.id).await
+            .map_err(database_error)?;
+        remove_unused_versions(filesystem, &deleted).await;
+    }
+
+    // Children sort after their parents, so in reverse they come first
+    for directory in directories.iter().rev() {
+        let empty = match storage.list(&directory.path).await {
+            Ok(entries) => entries.is_empty(),
+            Err(_) => true,
+        };
+        if !empty {
+            continue;
+        }
+
+        if storage.metadata(&directory.path).await.is_ok() {
+            storage.delete(&directory.path).await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+        database.delete_file_metadata(directory.id).await
+            .map_err(database_error)?;
+    }
+
+    Ok(())
+}
+
+pub async fn list_integrity_issues(
+    State(database): State<Database>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<IntegrityIssue>>>, StatusCode> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let issues = database.list_integrity_issues().await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(issues)))
+}
+
+/// Start verifying every file under a path. The scan runs in the background and
+/// its findings show up in `list_integrity_issues`.
+pub async fn start_integrity_scan(
+    State(database): State<Database>,
+    State(integrity): State<IntegrityScanner>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<IntegrityScanRequest>,
+) -> Result<(StatusCode, Json<ApiResponse<serde_json::Value>>), StatusCode> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let path = normalize_path(&request.path);
+    let files = database.get_files_under_path(&path).await
+        .map_err(database_error)?;
+    let queued = files.len();
+
+    tokio::spawn(async move {
+        match integrity.verify_files(&files).await {
+            Ok(report) => tracing::info!("Integrity scan of {} finished: {:?}", path, report),
+            Err(e) => tracing::error!("Integrity scan of {} failed: {}", path, e),
+        }
+    });
+
+    let body = json!({
+        "path": request.path,
+        "queued_files": queued,
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(ApiResponse::success(body))))
+}
+
+/// The configured webhooks and a page of their latest deliveries.
+/// `webhook` narrows the deliveries down to one hook.
+pub async fn list_webhooks(
+    State(database): State<Database>,
+    State(webhooks): State<Webhooks>,
+    State(pages): State<PageLimits>,
+    Extension(claims): Extension<Claims>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<ApiResponse<WebhookOverview>>, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    let page = pages.request(&params)?;
+    let webhook = params.get("webhook").map(String::as_str);
+
+    let deliveries = database.list_webhook_deliveries(webhook, page.after_time()?, page.fetch(), page.offset as i64).await
+        .map_err(database_error)?;
+    let total = database.count_webhook_deliveries(webhook).await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(WebhookOverview {
+        webhooks: webhooks.list(),
+        deliveries: page.page(deliveries, total, |delivery| time_key(delivery.created_at, delivery.id)),
+    })))
+}
+
+/// Send a test event to a webhook once and report how it went
+pub async fn test_webhook(
+    State(database): State<Database>,
+    State(webhooks): State<Webhooks>,
+    Extension(claims): Extension<Claims>,
+    Path(name): Path<String>,
+) -> Result<Json<ApiResponse<WebhookDelivery>>, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    match webhooks.send_test(&name).await {
+        Ok(Some(delivery)) => Ok(Json(ApiResponse::success(delivery))),
+        Ok(None) => Err(ApiError::new(StatusCode::NOT_FOUND, format!("No webhook named '{}'", name))),
+        Err(e) => {
+            tracing::error!("Failed to record a test delivery to webhook {}: {}", name, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+        }
+    }
+}
+
+/// The transfer rate limits in force
+pub async fn get_bandwidth_limits(
+    State(database): State<Database>,
+    State(bandwidth): State<Bandwidth>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<BandwidthLimits>>, StatusCode> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(Json(ApiResponse::success(bandwidth.limits())))
+}
+
+/// Change the transfer rate limits without a restart. Transfers under way
+/// slow down or speed up right away; a restart goes back to the configured ones.
+pub async fn set_bandwidth_limits(
+    State(database): State<Database>,
+    State(bandwidth): State<Bandwidth>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<UpdateBandwidthRequest>,
+) -> Result<Json<ApiResponse<BandwidthLimits>>, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let limits = bandwidth.set_limits(request.max_mbps, &request.user_mbps)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(Json(ApiResponse::success(limits)))
+}
+
+/// Users seen within this many days count as active
+const ACTIVE_USER_DAYS: i64 = 7;
+
+/// Figures for a status page. They are counted at most once per
+/// `stats_cache_seconds`; in between, the last ones are returned with
+/// `from_cache` set.
+pub async fn get_admin_stats(
+    State(filesystem): State<FileSystemService>,
+    State(database): State<Database>,
+    State(downloads): State<DownloadCounter>,
+    State(stats): State<StatsCache>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<AdminStats>>, StatusCode> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let stats = stats.get_or_gather(|| gather_admin_stats(&filesystem, &database, &downloads)).await
+        .map_err(|e| {
+            tracing::error!("Failed to gather statistics: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(ApiResponse::success(stats)))
+}
+
+/// Count the admin statistics afresh. Storage usage walks every file, which
+/// is why these are cached.
+async fn gather_admin_stats(
+    filesystem: &FileSystemService,
+    database: &Database,
+    downloads: &DownloadCounter,
+) -> anyhow::Result<AdminStats> {
+    let now = Utc::now();
+    let totals = database.get_global_stats(
+        now - chrono::Duration::days(ACTIVE_USER_DAYS),
+        now - chrono::Duration::days(1),
+    ).await?;
+
+    Ok(AdminStats {
+        totals,
+        disk: filesystem.get_disk_space_async().await?,
+        mounts: filesystem.get_mount_disk_space().await?,
+        usage: filesystem.get_storage_usage().await?,
+        // Counted in memory since the server started
+        downloads_last_day: downloads.last_day(),
+        generated_at: now,
+        from_cache: false,
+        approximate: vec!["downloads_last_day".to_string()],
+    })
+}
+
+/// Take a consistent snapshot of the database. It is kept in the backup
+/// directory, where the oldest go past `keep`, or with `download` sent back
+/// with its checksum in a header.
+pub async fn create_backup(
+    State(database): State<Database>,
+    State(backups): State<Backups>,
+    Extension(claims): Extension<Claims>,
+    request: Option<Json<BackupRequest>>,
+) -> Result<Response, ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let compress = request.compress.unwrap_or(backups.compresses());
+
+    if !request.download {
+        if !backups.has_directory() {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "No backup directory is configured; ask for a download instead",
+            ));
+        }
+        let info = backups.save(compress).await.map_err(|e| {
+            tracing::error!("Backup failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        return Ok(Json(ApiResponse::success(info)).into_response());
+    }
+
+    let (snapshot, info) = backups.snapshot(compress).await.map_err(|e| {
+        tracing::error!("Backup failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let file = tokio::fs::File::open(snapshot.path()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // The snapshot goes with the stream, whether it is read to the end or
+    // the download is cut off
+    let stream = tokio_util::io::ReaderStream::new(file).map(move |chunk| {
+        let _ = &snapshot;
+        chunk
+    });
+
+    let content_type = if info.compressed { "application/gzip" } else { "application/vnd.sqlite3" };
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, info.size)
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", info.file_name))
+        .header(CHECKSUM_HEADER, &info.checksum)
+        .body(axum::body::Body::from_stream(stream))
+        .unwrap())
+}
+
+/// The background maintenance jobs and how their last runs went
+pub async fn list_jobs(
+    State(database): State<Database>,
+    State(scheduler): State<Scheduler>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<JobStatus>>>, StatusCode> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(Json(ApiResponse::success(scheduler.status())))
+}
+
+/// Start a job now rather than at its next turn. The run happens in
+/// background; the job's status shows how it went.
+pub async fn run_job(
+    State(database): State<Database>,
+    State(scheduler): State<Scheduler>,
+    Extension(claims): Extension<Claims>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<ApiResponse<JobStatus>>), ApiError> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    match scheduler.trigger(&name) {
+        Some(status) => {
+            tracing::info!("Job {} triggered by {}", name, claims.username);
+            Ok((StatusCode::ACCEPTED, Json(ApiResponse::success(status))))
+        }
+        None => Err(ApiError::new(StatusCode::NOT_FOUND, format!("No job named '{}'", name))),
+    }
+}
+
+/// Compare storage with the database and adopt or prune what doesn't match.
+/// Runs in small batches alongside normal traffic and returns the summary.
+pub async fn reconcile_storage(
+    State(database): State<Database>,
+    State(reconciler): State<Reconciler>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<ReconcileRequest>,
+) -> Result<Json<ApiResponse<ReconcileReport>>, StatusCode> {
+    if !user_has_permission(&database, &claims, Permission::Admin).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    if !reconciler.is_enabled() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let owner_id = reconciler.resolve_owner(request.owner.as_deref()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if request.mode == ReconcileMode::Adopt && owner_id.is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let report = reconciler.run(request.mode, owner_id).await.map_err(|e| {
+        tracing::error!("Reconciliation failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(ApiResponse::success(report)))
+}
+
+pub async fn get_server_info(
+    State(filesystem): State<FileSystemService>,
+    State(anonymous): State<Option<AnonymousAccess>>,
+) -> Json<ApiResponse<serde_json::Value>> {
+    let info = json!({
+        "name": "Synker Server",
+        "version": "0.1.0",
+        "description": "Self-hosted cloud storage server for MyCloud OS5",
+        "api_version": "v1",
+        "features": [
+            "file_upload",
+            "file_download",
+            "file_sync",
+            "folder_creation",
+            "file_sharing",
+            "user_authentication"
+        ],
+        "upload": {
+            "max_file_size": filesystem.max_file_size(),
+            "allowed_extensions": filesystem.allowed_extensions(),
+            "allow_no_extension": filesystem.allows_no_extension(),
+        },
+        "anonymous_read": {
+            "enabled": anonymous.is_some(),
+            "root": anonymous.map(|access| access.root),
+        }
+    });
+
+    Json(ApiResponse::success(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PasswordScheme, WebhookSettings};
+    use crate::database::ChangeActor;
+    use crate::mock_mycloud::MockMyCloud;
+    use tempfile::tempdir;
+
+    async fn multipart_upload(filename: &str, data: &str, checksum: Option<&str>) -> Multipart {
+        use axum::extract::FromRequest;
+
+        let mut body = String::new();
+        if let Some(checksum) = checksum {
+            body.push_str(&format!(
+                "--XBOUNDARY\r\nContent-Disposition: form-data; name=\"checksum\"\r\n\r\n{}\r\n",
+                checksum
+            ));
+        }
+        body.push_str(&format!(
+            "--XBOUNDARY\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n{}\r\n--XBOUNDARY--\r\n",
+            filename, data
+        ));
+
+        let request = axum::http::Request::builder()
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=XBOUNDARY")
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        Multipart::from_request(request, &()).await.unwrap()
+    }
+
+    fn local_storage(filesystem: &FileSystemService) -> State<Arc<dyn StorageBackend>> {
+        State(Arc::new(filesystem.clone()))
+    }
+
+    fn test_limiter() -> LoginLimiter {
+        let mut settings = crate::config::ServerConfig::default().auth;
+        settings.login_attempts_per_minute_per_user = 100;
+        LoginLimiter::new(&settings)
+    }
+
+    fn test_pages() -> State<PageLimits> {
+        State(PageLimits { default_size: 100, max_size: 1000 })
+    }
+
+    fn test_client() -> ConnectInfo<SocketAddr> {
+        ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000)))
+    }
+
+    /// A MyCloud without accounts, which no test should reach unless it
+    /// turns the fallback on
+    fn test_mycloud(auth_fallback: bool) -> Arc<dyn MyCloudApi> {
+        Arc::new(MockMyCloud::new(auth_fallback))
+    }
+
+    /// What the users these tests create hold: "admin" only the admin
+    /// permission, everyone else read and write
+    fn fixture_permissions(username: &str) -> Vec<Permission> {
+        if username == "admin" {
+            vec![Permission::Admin]
+        } else {
+            vec![Permission::Read, Permission::Write]
+        }
+    }
+
+    /// The body of a conditional response, which mustn't be a 304
+    fn fresh<T>(response: Conditional<T>) -> T {
+        match response {
+            Conditional::Modified(body, _) => body,
+            Conditional::NotModified(_) => panic!("Expected a body, got 304 Not Modified"),
+        }
+    }
+
+    async fn register_device(database: &Database, claims: &Claims) {
+        register_sync_session(
+            State(database.clone()),
+            Extension(claims.clone()),
+            Json(RegisterSyncSessionRequest { device_name: None, folders: Vec::new(), exclude_patterns: Vec::new() }),
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn test_database(dir: &std::path::Path) -> (Database, Uuid) {
+        let url = format!("sqlite://{}?mode=rwc", dir.join("test.db").display());
+        let database = Database::new(&url).await.unwrap();
+
+        let user = User {
+            id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string(), "write".to_string()],
+        };
+        database.create_user(&user).await.unwrap();
+
+        (database, user.id)
+    }
+
+    /// Claims of a plain login as `user_id`, from no device in particular
+    fn test_claims(user_id: Uuid, username: &str, permissions: Vec<Permission>) -> Claims {
+        Claims {
+            sub: user_id.to_string(),
+            username: username.to_string(),
+            exp: 0,
+            iat: 0,
+            device_id: None,
+            jti: String::new(),
+            scope: None,
+            permissions,
+            scopes: Vec::new(),
+            auth_generation: 0,
+            impersonator: None,
+        }
+    }
+
+    /// Small enough that tests can send a few bytes per chunk
+    const TEST_UPLOAD_LIMITS: UploadLimits = UploadLimits { min_chunk_size: 4, max_chunk_size: 1024, max_chunks: 10 };
+
+    #[tokio::test]
+    async fn test_listing_ids_are_stable() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        filesystem.save_file("/a.txt", b"a").await.unwrap();
+        filesystem.save_file("/b.txt", b"b").await.unwrap();
+        filesystem.create_directory("/docs").await.unwrap();
+
+        let mut first = filesystem.list_directory_fast("/").await.unwrap();
+        database.assign_stable_ids(user_id, &mut first).await.unwrap();
+
+        let mut second = filesystem.list_directory_fast("/").await.unwrap();
+        database.assign_stable_ids(user_id, &mut second).await.unwrap();
+
+        let first_ids: Vec<(String, Uuid)> = first.iter().map(|f| (f.path.clone(), f.id)).collect();
+        let second_ids: Vec<(String, Uuid)> = second.iter().map(|f| (f.path.clone(), f.id)).collect();
+        assert_eq!(first_ids.len(), 3);
+        assert_eq!(first_ids, second_ids);
+
+        let single = database.resolve_file_metadata(&filesystem, user_id, "/a.txt").await.unwrap();
+        assert_eq!(Some(&(single.path.clone(), single.id)), first_ids.iter().find(|(p, _)| p == "/a.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_share_links_use_signed_tokens() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+        let share_tokens = ShareTokens::new("share-secret-share-secret-share-secret");
+
+        filesystem.save_file("/shared.txt", b"shared").await.unwrap();
+        let file = database.resolve_file_metadata(&filesystem, user_id, "/shared.txt").await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Share]);
+        let share = |params: HashMap<String, String>| {
+            create_share_link(
+                State(database.clone()),
+                State(share_tokens.clone()),
+                State(None),
+                State(Webhooks::new(&WebhookSettings::default(), database.clone())),
+                Extension(claims.clone()),
+                Path(file.id.to_string()),
+                Query(params),
+                None,
+            )
+        };
+        let download = |share_tokens: ShareTokens, token: String| {
+            download_shared_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(AuthService::new("test_secret")),
+                State(database.clone()),
+                State(share_tokens),
+                State(ShareAccessLog::new(database.clone())),
+                test_client(),
+                Path(token),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+            )
+        };
+
+        let Json(response) = share(HashMap::new()).await.unwrap();
+        let link = response.data.unwrap();
+        assert!(!share_tokens::is_legacy_token(&link.share_token));
+        assert_eq!(share_tokens.verify(&link.share_token, Utc::now()).unwrap().share_id, link.id);
+
+        let response = download(share_tokens.clone(), link.share_token.clone()).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"shared");
+        assert_eq!(database.get_share_link(link.id).await.unwrap().unwrap().download_count, 1);
+
+        // Forged tokens and those signed with another secret go nowhere
+        let forged = share_tokens.sign(Uuid::new_v4(), None);
+        assert_eq!(download(share_tokens.clone(), forged).await.unwrap_err(), StatusCode::NOT_FOUND);
+        let other = ShareTokens::new("another-secret-another-secret-another");
+        assert_eq!(download(other, link.share_token.clone()).await.unwrap_err(), StatusCode::NOT_FOUND);
+
+        // Links run out after their downloads
+        let params = HashMap::from([("max_downloads".to_string(), "1".to_string())]);
+        let Json(response) = share(params).await.unwrap();
+        let limited = response.data.unwrap();
+        assert!(download(share_tokens.clone(), limited.share_token.clone()).await.is_ok());
+        assert_eq!(download(share_tokens.clone(), limited.share_token).await.unwrap_err(), StatusCode::GONE);
+
+        // Short codes and aliases lead to the same share
+        let code = link.short_code.clone().unwrap();
+        assert_eq!(code.len(), share_tokens::DEFAULT_CODE_LENGTH);
+        assert!(download(share_tokens.clone(), code).await.is_ok());
+
+        let params = HashMap::from([("alias".to_string(), "family-photos-2024".to_string())]);
+        let Json(response) = share(params.clone()).await.unwrap();
+        let aliased = response.data.unwrap();
+        assert_eq!(aliased.alias.as_deref(), Some("family-photos-2024"));
+        assert!(aliased.short_code.is_some());
+        assert!(download(share_tokens.clone(), "family-photos-2024".to_string()).await.is_ok());
+        assert_eq!(share(params).await.unwrap_err().status, StatusCode::CONFLICT);
+        let params = HashMap::from([("alias".to_string(), "Family Photos".to_string())]);
+        assert_eq!(share(params).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+        assert_eq!(download(share_tokens.clone(), "no-such-alias".to_string()).await.unwrap_err(), StatusCode::NOT_FOUND);
+
+        // Emails need SMTP set up
+        let err = create_share_link(
+            State(database.clone()),
+            State(share_tokens.clone()),
+            State(None),
+            State(Webhooks::new(&WebhookSettings::default(), database.clone())),
+            Extension(claims.clone()),
+            Path(file.id.to_string()),
+            Query(HashMap::new()),
+            Some(Json(CreateShareRequest { notify: vec!["friend@example.com".to_string()] })),
+        ).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        // Links from before signing work only while legacy tokens are accepted
+        let legacy = ShareLink {
+            id: Uuid::new_v4(),
+            share_token: Uuid::new_v4().to_string(),
+            max_downloads: None,
+            download_count: 0,
+            short_code: None,
+            alias: None,
+            ..link
+        };
+        database.create_share_link(&legacy).await.unwrap();
+        assert!(download(share_tokens.clone().with_legacy_tokens(true), legacy.share_token.clone()).await.is_ok());
+        assert_eq!(download(share_tokens.clone(), legacy.share_token).await.unwrap_err(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_folder_share_links_list_and_serve_their_files() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+        let share_tokens = ShareTokens::new("share-secret-share-secret-share-secret");
+
+        filesystem.save_file("/trip/beach.jpg", b"sand").await.unwrap();
+        filesystem.save_file("/trip/day2/hike.jpg", b"hills").await.unwrap();
+        filesystem.save_file("/trip/private/diary.txt", b"dear diary").await.unwrap();
+        filesystem.save_file("/trip/.synkerignore", b"private/\n").await.unwrap();
+        filesystem.save_file("/taxes.pdf", b"numbers").await.unwrap();
+        let folder = database.resolve_file_metadata(&filesystem, user_id, "/trip").await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Share]);
+        let Json(response) = create_share_link(
+            State(database.clone()),
+            State(share_tokens.clone()),
+            State(None),
+            State(Webhooks::new(&WebhookSettings::default(), database.clone())),
+            Extension(claims),
+            Path(folder.id.to_string()),
+            Query(HashMap::from([("max_downloads".to_string(), "2".to_string())])),
+            None,
+        ).await.unwrap();
+        let token = response.data.unwrap().share_token;
+
+        let list = |path: Option<&str>| {
+            let params = path.map(|path| HashMap::from([("path".to_string(), path.to_string())])).unwrap_or_default();
+            download_shared_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(AuthService::new("test_secret")),
+                State(database.clone()),
+                State(share_tokens.clone()),
+                State(ShareAccessLog::new(database.clone())),
+                test_client(),
+                Path(token.clone()),
+                Query(params),
+                HeaderMap::new(),
+            )
+        };
+        let fetch = |relative_path: &str| {
+            download_shared_folder_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(AuthService::new("test_secret")),
+                State(database.clone()),
+                State(share_tokens.clone()),
+                State(ShareAccessLog::new(database.clone())),
+                test_client(),
+                Path((token.clone(), relative_path.to_string())),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+            )
+        };
+        let listing = |response: Response| async move {
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["data"].clone()
+        };
+
+        // Names, sizes and types only, with ignored entries left out
+        let root = listing(list(None).await.unwrap()).await;
+        assert_eq!(root["name"], "trip");
+        assert_eq!(root["path"], "");
+        let mut names: Vec<_> = root["entries"].as_array().unwrap().iter()
+            .map(|entry| entry["path"].as_str().unwrap().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec![".synkerignore", "beach.jpg", "day2"]);
+        let beach = root["entries"].as_array().unwrap().iter().find(|entry| entry["name"] == "beach.jpg").unwrap();
+        assert_eq!(beach["size"], 4);
+        assert!(beach.get("id").is_none() && beach.get("owner_id").is_none());
+
+        let day2 = listing(list(Some("day2")).await.unwrap()).await;
+        assert_eq!(day2["path"], "day2");
+        assert_eq!(day2["entries"][0]["path"], "day2/hike.jpg");
+
+        // Nothing outside the shared folder, or hidden in it, can be reached
+        for path in ["../taxes.pdf", "day2/../../taxes.pdf", "/../taxes.pdf", "private/diary.txt", "day2", ""] {
+            assert!(fetch(path).await.is_err(), "{}", path);
+        }
+        assert_eq!(list(Some("..")).await.unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(list(Some("private")).await.unwrap_err(), StatusCode::NOT_FOUND);
+
+        // Each file fetched counts, listings don't
+        let response = fetch("day2/hike.jpg").await.unwrap();
+        assert_eq!(&hyper::body::to_bytes(response.into_body()).await.unwrap()[..], b"hills");
+        assert!(fetch("beach.jpg").await.is_ok());
+        assert_eq!(fetch("beach.jpg").await.unwrap_err(), StatusCode::GONE);
+        assert!(list(None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_share_links_are_listed_edited_and_revoked() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+        let auth_service = AuthService::new("test_secret").with_bcrypt_cost(4);
+        let share_tokens = ShareTokens::new("share-secret-share-secret-share-secret");
+
+        filesystem.save_file("/photos/beach.jpg", b"sand").await.unwrap();
+        let file = database.resolve_file_metadata(&filesystem, user_id, "/photos/beach.jpg").await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Share]);
+        let Json(response) = create_share_link(
+            State(database.clone()),
+            State(share_tokens.clone()),
+            State(None),
+            State(Webhooks::new(&WebhookSettings::default(), database.clone())),
+            Extension(claims.clone()),
+            Path(file.id.to_string()),
+            Query(HashMap::new()),
+            None,
+        ).await.unwrap();
+        let link = response.data.unwrap();
+
+        let list = || list_shares(State(database.clone()), test_pages(), Extension(claims.clone()), Query(HashMap::new()));
+        let update = |share_id: Uuid, body: serde_json::Value| {
+            update_share(
+                State(auth_service.clone()),
+                State(database.clone()),
+                State(share_tokens.clone()),
+                Extension(claims.clone()),
+                Path(share_id.to_string()),
+                Json(serde_json::from_value(body).unwrap()),
+            )
+        };
+        let download = |token: String, password: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(password) = password {
+                headers.insert("x-share-password", password.parse().unwrap());
+            }
+            download_shared_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(auth_service.clone()),
+                State(database.clone()),
+                State(share_tokens.clone()),
+                State(ShareAccessLog::new(database.clone())),
+                test_client(),
+                Path(token),
+                Query(HashMap::new()),
+                headers,
+            )
+        };
+
+        // Listed with the file it points to
+        let Json(response) = list().await.unwrap();
+        let shares = response.data.unwrap().items;
+        assert_eq!(shares.len(), 1);
+        assert_eq!(shares[0].share.id, link.id);
+        assert_eq!(shares[0].file_name, "beach.jpg");
+        assert_eq!(shares[0].file_path, "/photos/beach.jpg");
+
+        // A password, a download limit and a later expiry, which needs a new token
+        let expires_at = Utc::now() + chrono::Duration::days(7);
+        let Json(response) = update(link.id, json!({
+            "expires_at": expires_at,
+            "max_downloads": 5,
+            "password": "sandcastle",
+        })).await.unwrap();
+        let edited = response.data.unwrap();
+        assert!(edited.password_protected);
+        assert_eq!(edited.max_downloads, Some(5));
+        assert_ne!(edited.share_token, link.share_token);
+        assert_eq!(share_tokens.verify(&edited.share_token, Utc::now()).unwrap().expires_at.unwrap().timestamp(), expires_at.timestamp());
+
+        assert_eq!(download(edited.share_token.clone(), None).await.unwrap_err(), StatusCode::UNAUTHORIZED);
+        assert_eq!(download(edited.share_token.clone(), Some("wrong")).await.unwrap_err(), StatusCode::UNAUTHORIZED);
+        assert!(download(edited.share_token.clone(), Some("sandcastle")).await.is_ok());
+
+        // Fields left out stay; null clears
+        let Json(response) = update(link.id, json!({ "password": null })).await.unwrap();
+        let cleared = response.data.unwrap();
+        assert!(!cleared.password_protected);
+        assert_eq!(cleared.max_downloads, Some(5));
+        assert!(download(cleared.share_token.clone(), None).await.is_ok());
+
+        let err = update(link.id, json!({ "expires_at": Utc::now() - chrono::Duration::hours(1) })).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        // Revoked links answer 410 at once and leave the listing, but keep their row
+        revoke_share(State(database.clone()), Extension(claims.clone()), Path(link.id.to_string())).await.unwrap();
+        assert_eq!(download(cleared.share_token.clone(), None).await.unwrap_err(), StatusCode::GONE);
+        assert_eq!(download(link.share_token.clone(), None).await.unwrap_err(), StatusCode::GONE);
+        assert!(list().await.unwrap().0.data.unwrap().items.is_empty());
+        assert!(database.get_share_link(link.id).await.unwrap().unwrap().revoked_at.is_some());
+
+        // Every visit is in the link's activity, written in the background
+        let activity = |claims: Claims| get_share_activity(
+            State(database.clone()),
+            test_pages(),
+            Extension(claims),
+            Path(link.id.to_string()),
+            Query(HashMap::new()),
+        );
+        let mut visits = Vec::new();
+        for _ in 0..50 {
+            visits = activity(claims.clone()).await.unwrap().0.data.unwrap().items;
+            if visits.len() >= 6 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let mut outcomes: Vec<_> = visits.iter().map(|visit| (visit.outcome.as_str(), visit.bytes_served)).collect();
+        outcomes.sort();
+        assert_eq!(outcomes, vec![
+            ("revoked", 0), ("revoked", 0), ("success", 4), ("success", 4), ("wrong_password", 0), ("wrong_password", 0),
+        ]);
+        assert!(visits.iter().all(|visit| visit.ip_address.as_deref() == Some("127.0.0.0")));
+
+        // Revoked links can't be edited or revoked again, nor can other users' links be touched
+        assert_eq!(update(link.id, json!({ "max_downloads": 1 })).await.unwrap_err().status, StatusCode::NOT_FOUND);
+        let err = revoke_share(State(database.clone()), Extension(claims.clone()), Path(link.id.to_string())).await.unwrap_err();
+        assert_eq!(err, StatusCode::NOT_FOUND);
+        let stranger = Claims { sub: Uuid::new_v4().to_string(), ..claims.clone() };
+        let Json(response) = create_share_link(
+            State(database.clone()),
+            State(share_tokens.clone()),
+            State(None),
+            State(Webhooks::new(&WebhookSettings::default(), database.clone())),
+            Extension(claims.clone()),
+            Path(file.id.to_string()),
+            Query(HashMap::new()),
+            None,
+        ).await.unwrap();
+        let other = response.data.unwrap();
+        let err = revoke_share(State(database.clone()), Extension(stranger.clone()), Path(other.id.to_string())).await.unwrap_err();
+        assert_eq!(err, StatusCode::NOT_FOUND);
+        assert_eq!(activity(stranger).await.unwrap_err().status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_dead_share_links_are_purged() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        filesystem.save_file("/a.txt", b"a").await.unwrap();
+        let file = database.resolve_file_metadata(&filesystem, user_id, "/a.txt").await.unwrap();
+
+        let now = Utc::now();
+        let link = |expires_at: Option<chrono::DateTime<Utc>>, download_count: u32, max_downloads: Option<u32>| ShareLink {
+            id: Uuid::new_v4(),
+            file_id: file.id,
+            created_by: user_id,
+            share_token: Uuid::new_v4().to_string(),
+            expires_at,
+            password_protected: false,
+            download_count,
+            max_downloads,
+            created_at: now,
+            revoked_at: None,
+            password_hash: None,
+            short_code: None,
+            alias: None,
+        };
+        let expired = link(Some(now - chrono::Duration::minutes(1)), 0, None);
+        let used_up = link(None, 3, Some(3));
+        let live = [
+            link(Some(now + chrono::Duration::hours(1)), 0, None),
+            link(None, 2, Some(3)),
+            link(None, 50, None),
+        ];
+        for share_link in live.iter().chain([&expired, &used_up]) {
+            database.create_share_link(share_link).await.unwrap();
+        }
+
+        // The listing and the purge agree on what has expired
+        assert!(expired.is_expired(now) && !live[0].is_expired(now) && !live[1].is_expired(now));
+        let listed = database.get_share_links_by_user(user_id, now, None, 100, 0).await.unwrap();
+        assert!(!listed.iter().any(|summary| summary.share.id == expired.id));
+
+        assert_eq!(database.purge_expired_share_links(now).await.unwrap(), 2);
+        assert!(database.get_share_link(expired.id).await.unwrap().is_none());
+        assert!(database.get_share_link(used_up.id).await.unwrap().is_none());
+        for share_link in &live {
+            assert!(database.get_share_link(share_link.id).await.unwrap().is_some());
+        }
+        assert_eq!(database.purge_expired_share_links(now).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_removed_files_take_what_refers_to_them_along() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+        let share_tokens = ShareTokens::new("share-secret-share-secret-share-secret");
+
+        for path in ["/docs/a.txt", "/other/b.txt"] {
+            filesystem.save_file(path, b"data").await.unwrap();
+        }
+        let file = database.resolve_file_metadata(&filesystem, user_id, "/docs/a.txt").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/other/b.txt").await.unwrap();
+        let (checksum, size) = filesystem.store_version("/docs/a.txt").await.unwrap();
+        database.create_file_version(&FileVersion {
+            id: Uuid::new_v4(),
+            file_id: file.id,
+            version: 1,
+            size,
+            checksum,
+            modified_at: file.modified_at,
+            author_id: user_id,
+            created_at: Utc::now(),
+        }).await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete, Permission::Share]);
+        let Json(response) = create_share_link(
+            State(database.clone()),
+            State(share_tokens.clone()),
+            State(None),
+            State(Webhooks::new(&WebhookSettings::default(), database.clone())),
+            Extension(claims.clone()),
+            Path(file.id.to_string()),
+            Query(HashMap::new()),
+            None,
+        ).await.unwrap();
+        let link = response.data.unwrap();
+        let download = || download_shared_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(AuthService::new("test_secret")),
+            State(database.clone()),
+            State(share_tokens.clone()),
+            State(ShareAccessLog::new(database.clone())),
+            test_client(),
+            Path(link.share_token.clone()),
+            Query(HashMap::new()),
+            HeaderMap::new(),
+        );
+
+        // In the trash the link finds nothing, and purging takes it along
+        delete_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path("docs".to_string()),
+            Query(HashMap::new()),
+        ).await.unwrap();
+        assert_eq!(download().await.unwrap_err(), StatusCode::NOT_FOUND);
+
+        let entry = database.list_trash_entries(user_id).await.unwrap().remove(0);
+        purge_trash_entry(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path(entry.id.to_string()),
+        ).await.unwrap();
+        assert!(database.get_file_metadata(file.id).await.unwrap().is_none());
+        assert!(database.get_share_link(link.id).await.unwrap().is_none());
+        assert!(database.list_file_versions(file.id).await.unwrap().is_empty());
+        assert_eq!(database.get_total_version_size().await.unwrap(), 0);
+
+        // Removing by path records the deletion once, for the folder
+        let cursor = database.get_latest_change_seq().await.unwrap();
+        let deleted = database.delete_metadata_under_path(user_id, "/other").await.unwrap();
+        assert_eq!(deleted.rows, 2);
+        assert!(database.get_file_metadata_by_path(user_id, "/other/b.txt").await.unwrap().is_none());
+        let latest = database.get_latest_change_seq().await.unwrap();
+        let changes = database.get_changes_after(user_id, cursor, latest).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(changes[0].change_type, ChangeType::Deleted) && changes[0].path == "/other");
+    }
+
+    #[tokio::test]
+    async fn test_trash_lists_restores_and_purges_deleted_files() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        for (path, content) in [("/a.txt", "first"), ("/b.txt", "second")] {
+            filesystem.save_file(path, content.as_bytes()).await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete]);
+        let delete = |path: &str| delete_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path(path.to_string()),
+            Query(HashMap::new()),
+        );
+        let trash = || {
+            let listed = list_trash(State(database.clone()), test_pages(), Extension(claims.clone()), Query(HashMap::new()));
+            async move { listed.await.unwrap().0.data.unwrap().items }
+        };
+        let restore = |entry: &TrashEntry| restore_trash_entry(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path(entry.id.to_string()),
+        );
+
+        delete("a.txt").await.unwrap();
+        delete("b.txt").await.unwrap();
+        let entries = trash().await;
+        let mut paths: Vec<_> = entries.iter().map(|entry| entry.original_path.as_str()).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["/a.txt", "/b.txt"]);
+        assert!(!filesystem.get_absolute_path("/a.txt").exists());
+        let entry = |path: &str| entries.iter().find(|entry| entry.original_path == path).unwrap().clone();
+        let (a, b) = (entry("/a.txt"), entry("/b.txt"));
+
+        // Something new at the original path is left alone, and the entry kept
+        filesystem.save_file("/a.txt", b"newer").await.unwrap();
+        let Json(response) = restore(&a).await.unwrap();
+        assert!(!response.success);
+        assert_eq!(filesystem.read_file("/a.txt").await.unwrap(), b"newer");
+        assert_eq!(trash().await.len(), 2);
+
+        std::fs::remove_file(filesystem.get_absolute_path("/a.txt")).unwrap();
+        let Json(response) = restore(&a).await.unwrap();
+        let restored = response.data.unwrap();
+        assert_eq!(restored.path, "/a.txt");
+        assert_eq!(filesystem.read_file("/a.txt").await.unwrap(), b"first");
+        assert_eq!(trash().await.len(), 1);
+
+        // Purging removes the entry, its content and its rows for good
+        let location = FileSystemService::trash_location(user_id, b.id);
+        assert!(filesystem.get_absolute_path(&location).exists());
+        purge_trash_entry(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path(b.id.to_string()),
+        ).await.unwrap();
+        assert!(trash().await.is_empty());
+        assert!(!filesystem.get_absolute_path(&location).exists());
+        assert!(database.get_files_owned_by_including_deleted(user_id).await.unwrap().iter().all(|row| row.path != "/b.txt"));
+        assert!(restore(&b).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_trashed_rows_are_hidden_until_restored() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        filesystem.save_file("/photos/beach.txt", b"sand").await.unwrap();
+        let file = database.resolve_file_metadata(&filesystem, user_id, "/photos/beach.txt").await.unwrap();
+        let folder = database.get_file_metadata_by_path(user_id, "/photos").await.unwrap().unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete]);
+        let search = FileSearch { query: Some("beach".to_string()), ..FileSearch::default() };
+
+        let cursor = database.get_latest_change_seq().await.unwrap();
+        delete_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path("photos".to_string()),
+            Query(HashMap::new()),
+        ).await.unwrap();
+
+        // The rows stay, out of sight of everything but the trash
+        assert!(database.get_file_metadata(file.id).await.unwrap().is_none());
+        assert!(database.get_children(folder.id).await.unwrap().is_empty());
+        assert!(database.get_files_owned_by(user_id).await.unwrap().is_empty());
+        assert_eq!(database.search_files(user_id, &search, 10, 0).await.unwrap().1, 0);
+        let kept = database.get_files_owned_by_including_deleted(user_id).await.unwrap();
+        assert_eq!(kept.iter().map(|row| row.id).collect::<Vec<_>>(), vec![folder.id, file.id]);
+
+        // The folder's deletion covers what was inside
+        let latest = database.get_latest_change_seq().await.unwrap();
+        let changes = database.get_changes_after(user_id, cursor, latest).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(changes[0].change_type, ChangeType::Deleted) && changes[0].path == "/photos");
+
+        let entry = database.list_trash_entries(user_id).await.unwrap().remove(0);
+        restore_trash_entry(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims),
+            Path(entry.id.to_string()),
+        ).await.unwrap();
+        let restored = database.get_file_metadata(file.id).await.unwrap().unwrap();
+        assert_eq!(restored.path, "/photos/beach.txt");
+        assert_eq!(database.get_children(folder.id).await.unwrap().len(), 1);
+        assert_eq!(database.search_files(user_id, &search, 10, 0).await.unwrap().1, 1);
+    }
+
+    #[tokio::test]
+    async fn test_activity_groups_runs_of_changes_and_share_downloads() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let claims = Claims {
+            device_id: Some("scanner".to_string()),
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write])
+        };
+        let upload = |folder: &str, name: &str| {
+            let params = HashMap::from([("path".to_string(), folder.to_string())]);
+            let (filesystem, database, claims) = (filesystem.clone(), database.clone(), claims.clone());
+            let name = name.to_string();
+            // Attributed as the auth layer does for requests
+            let actor = ChangeActor { user_id, device_id: claims.device_id.clone() };
+            actor.scope(async move {
+                upload_file(
+                    State(filesystem.clone()),
+                    local_storage(&filesystem),
+                    State(database),
+                    State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+                    Extension(claims),
+                    Query(params),
+                    HeaderMap::new(),
+                    multipart_upload(&name, "page", None).await,
+                )
+                .await
+                .unwrap();
+            })
+        };
+        upload("/", "notes.txt").await;
+        for page in 0..3 {
+            upload("/scans", &format!("{}.pdf", page)).await;
+        }
+
+        // Someone fetches a scan through a share link
+        let scan = database.get_file_metadata_by_path(user_id, "/scans/0.pdf").await.unwrap().unwrap();
+        let now = Utc::now();
+        let link = ShareLink {
+            id: Uuid::new_v4(),
+            file_id: scan.id,
+            created_by: user_id,
+            share_token: Uuid::new_v4().to_string(),
+            expires_at: None,
+            password_protected: false,
+            download_count: 1,
+            max_downloads: None,
+            created_at: now,
+            revoked_at: None,
+            password_hash: None,
+            short_code: None,
+            alias: None,
+        };
+        database.create_share_link(&link).await.unwrap();
+        database.record_share_access(&ShareAccess {
+            id: Uuid::new_v4(),
+            share_id: link.id,
+            accessed_at: now + chrono::Duration::seconds(1),
+            ip_address: None,
+            user_agent: None,
+            bytes_served: 4,
+            outcome: ShareAccessOutcome::Success,
+            detail: None,
+        }).await.unwrap();
+
+        let activity = |params: HashMap<String, String>| get_activity(
+            State(database.clone()),
+            test_pages(),
+            Extension(claims.clone()),
+            Query(params),
+        );
+
+        let Json(response) = activity(HashMap::new()).await.unwrap();
+        let page = response.data.unwrap();
+        assert!(page.next_before.is_none());
+        assert_eq!(page.items[0].kind, ActivityKind::SharedDownload);
+        assert_eq!(page.items[0].file_ids, vec![scan.id]);
+        let scans = &page.items[1];
+        assert_eq!((scans.kind, scans.directory.as_str(), scans.events), (ActivityKind::Created, "/scans", 3));
+        assert_eq!(scans.device_id.as_deref(), Some("scanner"));
+        assert_eq!(scans.actor_id, Some(user_id));
+        assert!(page.items.iter().any(|group| group.directory == "/" && group.kind == ActivityKind::Created));
+
+        // One group at a time, each page going on where the last stopped
+        let Json(response) = activity(HashMap::from([("limit".to_string(), "1".to_string())])).await.unwrap();
+        let first = response.data.unwrap();
+        assert_eq!(first.items.len(), 1);
+        assert_eq!(first.items[0].kind, ActivityKind::SharedDownload);
+        let params = HashMap::from([
+            ("limit".to_string(), "1".to_string()),
+            ("before".to_string(), first.next_before.unwrap()),
+        ]);
+        let Json(response) = activity(params).await.unwrap();
+        let second = response.data.unwrap();
+        assert_eq!(second.items[0].file_ids, scans.file_ids);
+        assert!(second.next_before.is_some());
+
+        let params = HashMap::from([("before".to_string(), "not-a-key".to_string())]);
+        assert_eq!(activity(params).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+
+        // Tokens scoped to a folder only see what happened in it
+        let scoped = |scopes: Vec<PathScope>| get_activity(
+            State(database.clone()),
+            test_pages(),
+            Extension(Claims { scopes, ..claims.clone() }),
+            Query(HashMap::new()),
+        );
+        let Json(response) = scoped(vec![PathScope { action: Action::Read, path_prefix: "/scans".to_string() }]).await.unwrap();
+        let page = response.data.unwrap();
+        let notes = database.get_file_metadata_by_path(user_id, "/notes.txt").await.unwrap().unwrap();
+        assert!(page.items.iter().any(|group| group.file_ids == scans.file_ids));
+        assert!(page.items.iter().all(|group| !group.file_ids.contains(&notes.id)));
+        let Json(response) = scoped(vec![PathScope { action: Action::Write, path_prefix: "/scans".to_string() }]).await.unwrap();
+        assert!(response.data.unwrap().items.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_nested_paths_get_parent_rows() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+
+        let parent_id = database.ensure_parent_directories(user_id, "/a/b/c/file.txt").await.unwrap();
+
+        let c = database.get_file_metadata_by_path(user_id, "/a/b/c").await.unwrap().unwrap();
+        let b = database.get_file_metadata_by_path(user_id, "/a/b").await.unwrap().unwrap();
+        let a = database.get_file_metadata_by_path(user_id, "/a").await.unwrap().unwrap();
+        assert_eq!(parent_id, Some(c.id));
+        assert_eq!(c.parent_id, Some(b.id));
+        assert_eq!(b.parent_id, Some(a.id));
+        assert_eq!(a.parent_id, None);
+
+        // Resolving again reuses the same rows
+        let again = database.ensure_parent_directories(user_id, "/a/b/c/other.txt").await.unwrap();
+        assert_eq!(again, Some(c.id));
+
+        let children = database.get_children(a.id).await.unwrap();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].id, b.id);
+
+        database.delete_file_metadata(a.id).await.unwrap();
+        assert!(database.get_file_metadata_by_path(user_id, "/a/b/c").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_move_directory_rewrites_descendants() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        filesystem.save_file("/old/sub/file.txt", b"data").await.unwrap();
+        let file = database.resolve_file_metadata(&filesystem, user_id, "/old/sub/file.txt").await.unwrap();
+        let cursor = database.get_latest_change_seq().await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let request = MoveRequest {
+            from: "/old".to_string(),
+            to: "/new".to_string(),
+            overwrite: None,
+        };
+
+        let Json(response) = move_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims),
+            Query(HashMap::new()),
+            Json(request),
+        )
+        .await
+        .unwrap();
+        let moved = response.data.unwrap();
+        assert_eq!(moved.path, "/new");
+        assert_eq!(moved.name, "new");
+
+        assert!(filesystem.get_absolute_path("/new/sub/file.txt").exists());
+        let file_after = database.get_file_metadata_by_path(user_id, "/new/sub/file.txt").await.unwrap().unwrap();
+        assert_eq!(file_after.id, file.id);
+        assert!(database.get_file_metadata_by_path(user_id, "/old/sub").await.unwrap().is_none());
+
+        // One move for the folder, none for what it holds
+        let latest = database.get_latest_change_seq().await.unwrap();
+        let changes = database.get_changes_after(user_id, cursor, latest).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(changes[0].change_type, ChangeType::Moved));
+        assert_eq!((changes[0].old_path.as_deref(), changes[0].path.as_str()), (Some("/old"), "/new"));
+    }
+
+    #[tokio::test]
+    async fn test_folder_moves_reach_the_sync_feed_as_one_change() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let paths = ["/photos/a.jpg", "/photos/2024/b.jpg", "/photos/2024/summer/c.jpg", "/photos-raw/d.raw"];
+        for path in paths {
+            filesystem.save_file(path, path.as_bytes()).await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+        let before = database.get_file_metadata_by_path(user_id, "/photos/2024/summer/c.jpg").await.unwrap().unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let sync = |sync_token: String| {
+            let sync = sync_files(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(SyncPageLimit(1000)),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+                Json(SyncRequest { folders: Vec::new(), last_sync: None, sync_token: Some(sync_token), limit: None }),
+            );
+            async move { sync.await.map(fresh).unwrap().0.data.unwrap() }
+        };
+        let move_to = |from: &str, to: &str| {
+            move_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                Json(MoveRequest { from: from.to_string(), to: to.to_string(), overwrite: None }),
+            )
+        };
+
+        let token = database.get_latest_change_seq().await.unwrap().to_string();
+        move_to("/photos", "/pictures").await.unwrap();
+
+        // The folder's rename alone, with nothing created or deleted below it
+        let response = sync(token).await;
+        assert_eq!(response.changes.len(), 1, "{:?}", response.changes);
+        let change = &response.changes[0];
+        assert!(matches!(change.change_type, ChangeType::Moved));
+        assert_eq!((change.old_path.as_deref(), change.path.as_str()), (Some("/photos"), "/pictures"));
+        assert!(change.metadata.as_ref().unwrap().is_directory);
+
+        // What the folder held followed it, keeping ids and content
+        let after = database.get_file_metadata_by_path(user_id, "/pictures/2024/summer/c.jpg").await.unwrap().unwrap();
+        assert_eq!((after.id, &after.checksum), (before.id, &before.checksum));
+        for path in ["/pictures/a.jpg", "/pictures/2024/b.jpg", "/photos-raw/d.raw"] {
+            assert!(database.get_file_metadata_by_path(user_id, path).await.unwrap().is_some(), "{}", path);
+        }
+        assert!(database.get_file_metadata_by_path(user_id, "/photos/a.jpg").await.unwrap().is_none());
+
+        // A moved file brings its unchanged checksum, so it needn't be fetched again
+        let token = response.sync_token;
+        move_to("/pictures/2024/summer/c.jpg", "/c.jpg").await.unwrap();
+        let changes = sync(token).await.changes;
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(changes[0].change_type, ChangeType::Moved));
+        assert_eq!(changes[0].old_path.as_deref(), Some("/pictures/2024/summer/c.jpg"));
+        assert_eq!(changes[0].metadata.as_ref().unwrap().checksum, before.checksum);
+    }
+
+    #[tokio::test]
+    async fn test_failed_metadata_writes_leave_no_rows_behind() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        filesystem.save_file("/old/sub/file.txt", b"data").await.unwrap();
+        let file = database.resolve_file_metadata(&filesystem, user_id, "/old/sub/file.txt").await.unwrap();
+        let folder = database.get_file_metadata_by_path(user_id, "/old").await.unwrap().unwrap();
+
+        let rows = |database: Database| async move {
+            database.get_files_owned_by_including_deleted(user_id).await.unwrap()
+                .into_iter()
+                .map(|row| (row.id, row.path, row.parent_id))
+                .collect::<Vec<_>>()
+        };
+        let before = rows(database.clone()).await;
+        let cursor = database.get_latest_change_seq().await.unwrap();
+
+        let rename = || MetadataWrite::Move {
+            metadata: FileMetadata { name: "new".to_string(), path: "/new".to_string(), ..folder.clone() },
+            old_path: "/old".to_string(),
+        };
+
+        // The folder and what it holds are rewritten first, then inserting a
+        // row that already exists fails and takes the rewrite with it
+        let result = database.apply_metadata_writes(&[rename(), MetadataWrite::Insert(file.clone())]).await;
+        assert!(result.is_err());
+        assert_eq!(rows(database.clone()).await, before);
+        assert_eq!(database.get_latest_change_seq().await.unwrap(), cursor);
+
+        // Abandoned halfway, a transaction records nothing either
+        let mut tx = database.begin().await.unwrap();
+        tx.apply_metadata_writes(&[rename()]).await.unwrap();
+        assert!(tx.get_file_metadata_by_path(user_id, "/new/sub/file.txt").await.unwrap().is_some());
+        drop(tx);
+        assert_eq!(rows(database.clone()).await, before);
+        assert_eq!(database.get_latest_change_seq().await.unwrap(), cursor);
+
+        // and lets the next writer in
+        database.apply_metadata_writes(&[rename()]).await.unwrap();
+        let moved = database.get_file_metadata_by_path(user_id, "/new/sub/file.txt").await.unwrap().unwrap();
+        assert_eq!(moved.id, file.id);
+
+        // A move done on disk that can't be recorded is taken back there
+        filesystem.save_file("/notes.txt", b"notes").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/notes.txt").await.unwrap();
+        let State(storage) = local_storage(&filesystem);
+        let mut change = perform_move(&filesystem, storage.as_ref(), &database, user_id, "/notes.txt", "/moved.txt", false)
+            .await
+            .unwrap();
+        assert!(filesystem.get_absolute_path("/moved.txt").exists());
+        change.writes.push(MetadataWrite::Insert(file.clone()));
+        assert!(record_change(&filesystem, storage.as_ref(), &database, user_id, &mut change).await.is_err());
+        assert!(filesystem.get_absolute_path("/notes.txt").exists());
+        assert!(!filesystem.get_absolute_path("/moved.txt").exists());
+        assert!(database.get_file_metadata_by_path(user_id, "/notes.txt").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_renaming_a_large_folder_moves_every_row_at_once() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        filesystem.create_directory("/photos").await.unwrap();
+        let photos = database.resolve_file_metadata(&filesystem, user_id, "/photos").await.unwrap();
+        filesystem.save_file("/photos-raw/keep.jpg", b"raw").await.unwrap();
+        let raw = database.resolve_file_metadata(&filesystem, user_id, "/photos-raw/keep.jpg").await.unwrap();
+
+        let children: Vec<FileMetadata> = (0..1000)
+            .map(|i| FileMetadata {
+                id: Uuid::new_v4(),
+                name: format!("{}.jpg", i),
+                path: format!("/photos/{}.jpg", i),
+                is_directory: false,
+                parent_id: Some(photos.id),
+                ..photos.clone()
+            })
+            .collect();
+        database.create_file_metadata_batch(&children).await.unwrap();
+        let cursor = database.get_latest_change_seq().await.unwrap();
+
+        let renamed = FileMetadata { name: "pictures".to_string(), path: "/pictures".to_string(), ..photos.clone() };
+        database.move_file_metadata(&renamed, "/photos").await.unwrap();
+
+        assert!(database.get_files_under_path("/photos").await.unwrap().is_empty());
+        let moved = database.get_files_under_path("/pictures").await.unwrap();
+        assert_eq!(moved.len(), 1000);
+        for child in children.iter().step_by(97) {
+            let path = child.path.replacen("/photos/", "/pictures/", 1);
+            assert_eq!(database.get_file_metadata_by_path(user_id, &path).await.unwrap().unwrap().id, child.id);
+        }
+        let folder = database.get_file_metadata(photos.id).await.unwrap().unwrap();
+        assert_eq!((folder.name.as_str(), folder.path.as_str()), ("pictures", "/pictures"));
+        // A sibling sharing the name's start is not below it
+        assert_eq!(database.get_file_metadata(raw.id).await.unwrap().unwrap().path, "/photos-raw/keep.jpg");
+
+        // Committed together, as the one move clients replay
+        let latest = database.get_latest_change_seq().await.unwrap();
+        assert_eq!(latest, cursor + 1);
+        let changes = database.get_changes_after(user_id, cursor, latest).await.unwrap();
+        assert_eq!((changes[0].old_path.as_deref(), changes[0].path.as_str()), (Some("/photos"), "/pictures"));
+    }
+
+    #[tokio::test]
+    async fn test_copy_directory_creates_new_rows() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        filesystem.save_file("/photos/2024/a.jpg", b"jpeg").await.unwrap();
+        let original = database.resolve_file_metadata(&filesystem, user_id, "/photos/2024/a.jpg").await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let request = CopyRequest {
+            from: "/photos".to_string(),
+            to: "/backup/photos".to_string(),
+            overwrite: None,
+        };
+
+        let Json(response) = copy_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims),
+            Query(HashMap::new()),
+            Json(request),
+        )
+        .await
+        .unwrap();
+        let root = response.data.unwrap();
+        assert_eq!(root.path, "/backup/photos");
+
+        let backup = database.get_file_metadata_by_path(user_id, "/backup").await.unwrap().unwrap();
+        assert_eq!(root.parent_id, Some(backup.id));
+
+        let year = database.get_file_metadata_by_path(user_id, "/backup/photos/2024").await.unwrap().unwrap();
+        assert_eq!(year.parent_id, Some(root.id));
+
+        let copy = database.get_file_metadata_by_path(user_id, "/backup/photos/2024/a.jpg").await.unwrap().unwrap();
+        assert_ne!(copy.id, original.id);
+        assert_eq!(copy.parent_id, Some(year.id));
+        assert_eq!(copy.checksum, original.checksum);
+        assert_eq!(copy.owner_id, user_id);
+    }
+
+    #[tokio::test]
+    async fn test_copy_needs_local_storage() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+        let bucket: Arc<dyn StorageBackend> = Arc::new(crate::storage::memory::MemoryBackend::default());
+
+        filesystem.save_file("/a.txt", b"hello").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/a.txt").await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let err = copy_file(
+            State(filesystem.clone()),
+            State(bucket),
+            State(database.clone()),
+            Extension(claims),
+            Query(HashMap::new()),
+            Json(CopyRequest { from: "/a.txt".to_string(), to: "/b.txt".to_string(), overwrite: None }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_IMPLEMENTED);
+        assert!(!filesystem.resolve_path("/b.txt").unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn test_refused_overwrite_leaves_the_destination() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        filesystem.save_file("/a.txt", b"0123456789").await.unwrap();
+        filesystem.save_file("/b.txt", b"abcdefghij").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/a.txt").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/b.txt").await.unwrap();
+        database.set_user_quota(user_id, Some(25)).await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+
+        let err = copy_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Query(HashMap::new()),
+            Json(CopyRequest { from: "/a.txt".to_string(), to: "/b.txt".to_string(), overwrite: Some(true) }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status, StatusCode::INSUFFICIENT_STORAGE);
+
+        // A move whose source is gone from disk is refused before anything is trashed
+        filesystem.delete_file("/a.txt").await.unwrap();
+        let err = move_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims),
+            Query(HashMap::new()),
+            Json(MoveRequest { from: "/a.txt".to_string(), to: "/b.txt".to_string(), overwrite: Some(true) }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+
+        assert_eq!(filesystem.read_file("/b.txt").await.unwrap(), b"abcdefghij");
+        assert!(database.get_file_metadata_by_path(user_id, "/b.txt").await.unwrap().is_some());
+        assert!(database.list_trash_entries(user_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upload_session_respects_quota() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        filesystem.save_file("/existing.txt", b"0123456789").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/existing.txt").await.unwrap();
+        assert_eq!(database.get_user_storage_usage(user_id).await.unwrap(), 10);
+
+        database.set_user_quota(user_id, Some(25)).await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let request = |path: &str, total_size: u64, overwrite: bool| CreateUploadSessionRequest {
+            path: path.to_string(),
+            total_size,
+            chunk_size: 8,
+            checksum: None,
+            overwrite: Some(overwrite),
+        };
+
+        let err = create_upload_session(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(TEST_UPLOAD_LIMITS),
+            Extension(claims.clone()),
+            Query(HashMap::new()),
+            Json(request("/new.txt", 20, false)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status, StatusCode::INSUFFICIENT_STORAGE);
+
+        // Overwriting releases the old file's bytes
+        let Json(response) = create_upload_session(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(TEST_UPLOAD_LIMITS),
+            Extension(claims),
+            Query(HashMap::new()),
+            Json(request("/existing.txt", 20, true)),
+        )
+        .await
+        .unwrap();
+        assert!(response.success);
+    }
+
+    #[tokio::test]
+    async fn test_upload_sessions_reject_chunk_sizes_out_of_range() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+
+        // Too small, too large, overflowing, and too many chunks for the file
+        for (total_size, chunk_size) in [(100, 0), (100, 3), (100, 2048), (100, u64::MAX), (1000, 8)] {
+            let err = create_upload_session(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                State(TEST_UPLOAD_LIMITS),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                Json(CreateUploadSessionRequest {
+                    path: "/big.txt".to_string(),
+                    total_size,
+                    chunk_size,
+                    checksum: None,
+                    overwrite: None,
+                }),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST, "chunk_size {}", chunk_size);
+        }
+
+        assert_eq!(UploadSession::chunk_count(u64::MAX, 1), None);
+        assert_eq!(UploadSession::chunk_count(20, 8), Some(3));
+        assert_eq!(UploadSession::chunk_count(0, 8), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_upload_session_chunks_complete_into_the_file() {
+        use sha2::Digest;
+
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let content = b"0123456789abcdefghij";
+        let checksum = format!("{:x}", sha2::Sha256::digest(content));
+
+        let Json(response) = create_upload_session(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(TEST_UPLOAD_LIMITS),
+            Extension(claims.clone()),
+            Query(HashMap::new()),
+            Json(CreateUploadSessionRequest {
+                path: "/big.txt".to_string(),
+                total_size: content.len() as u64,
+                chunk_size: 8,
+                checksum: Some(checksum.clone()),
+                overwrite: None,
+            }),
+        )
+        .await
+        .unwrap();
+        let status = response.data.unwrap();
+        assert_eq!(status.total_chunks, 3);
+        let session_id = status.session_id.to_string();
+
+        let chunk = |index: u32| {
+            let start = index as usize * 8;
+            axum::body::Bytes::copy_from_slice(&content[start..content.len().min(start + 8)])
+        };
+        let send = |index: u32, body: axum::body::Bytes| upload_session_chunk(
+            State(filesystem.clone()),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path((session_id.clone(), index)),
+            body,
+        );
+
+        // Out of range and wrongly sized chunks are turned away
+        assert!(!send(3, chunk(0)).await.unwrap().0.success);
+        assert!(!send(2, chunk(0)).await.unwrap().0.success);
+
+        // Chunks may arrive out of order
+        for index in [2, 0] {
+            let Json(response) = send(index, chunk(index)).await.unwrap();
+            assert!(!response.data.unwrap().complete);
+        }
+        let Json(response) = send(1, chunk(1)).await.unwrap();
+        assert_eq!(response.data.unwrap().received_chunks, vec![0, 1, 2]);
+
+        let Json(response) = complete_upload_session(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims),
+            Path(session_id.clone()),
+            None,
+        )
+        .await
+        .unwrap();
+        let uploaded = response.data.unwrap();
+        assert_eq!(uploaded.size, 20);
+        assert_eq!(uploaded.checksum, checksum);
+        assert_eq!(filesystem.read_file("/big.txt").await.unwrap(), content);
+        let stored = database.get_file_metadata_by_path(user_id, "/big.txt").await.unwrap().unwrap();
+        assert_eq!(stored.checksum, checksum);
+        assert!(database.get_upload_session(status.session_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_batch_reports_each_operation() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        for path in ["/a.txt", "/b.txt", "/c.txt"] {
+            filesystem.save_file(path, b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+        let before = Utc::now();
+        let cursor = database.get_latest_change_seq().await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete]);
+        let operation = |op, from: &str, to: Option<&str>| BatchOperation {
+            op,
+            from: from.to_string(),
+            to: to.map(str::to_string),
+            overwrite: None,
+        };
+        let request = BatchRequest {
+            operations: vec![
+                operation(BatchOp::Move, "/a.txt", Some("/docs/a.txt")),
+                // Depends on the move above being visible
+                operation(BatchOp::Copy, "/docs/a.txt", Some("/docs/a-copy.txt")),
+                operation(BatchOp::Delete, "/b.txt", None),
+                operation(BatchOp::Delete, "/missing.txt", None),
+                operation(BatchOp::Copy, "/c.txt", None),
+            ],
+        };
+
+        let Json(response) = batch_operations(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Query(HashMap::new()),
+            Json(request),
+        )
+        .await
+        .unwrap();
+        let results = response.data.unwrap();
+
+        let outcomes: Vec<bool> = results.iter().map(|r| r.success).collect();
+        assert_eq!(outcomes, vec![true, true, true, false, false]);
+        assert!(results[3].error.is_some());
+        assert_eq!(results[4].error.as_deref(), Some("Destination is required"));
+
+        assert!(database.get_file_metadata_by_path(user_id, "/docs/a.txt").await.unwrap().is_some());
+        assert!(database.get_file_metadata_by_path(user_id, "/docs/a-copy.txt").await.unwrap().is_some());
+        assert!(filesystem.get_absolute_path("/c.txt").exists());
+
+        let latest = database.get_latest_change_seq().await.unwrap();
+        let changes = database.get_changes_after(user_id, cursor, latest).await.unwrap();
+        assert!(changes.iter().any(|c| matches!(c.change_type, ChangeType::Moved)
+            && c.old_path.as_deref() == Some("/a.txt")));
+        assert!(changes.iter().any(|c| matches!(c.change_type, ChangeType::Deleted) && c.path == "/b.txt"));
+        // The deleted file's lingering row must not bring it back
+        assert!(!changes.iter().any(|c| matches!(c.change_type, ChangeType::Modified) && c.path == "/b.txt"));
+
+        // Once the deletions are pruned, clients from before can't catch up
+        database.purge_change_log_before(Utc::now()).await.unwrap();
+        let sync = |last_sync| {
+            let sync = sync_files(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(SyncPageLimit(1000)),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+                Json(SyncRequest { folders: Vec::new(), last_sync: Some(last_sync), sync_token: None, limit: None }),
+            );
+            async move { sync.await.map(fresh) }
+        };
+        let Json(response) = sync(before).await.unwrap();
+        let response = response.data.unwrap();
+        assert!(response.full_resync_required);
+        assert!(response.changes.is_empty());
+        let Json(response) = sync(Utc::now()).await.unwrap();
+        assert!(!response.data.unwrap().full_resync_required);
+    }
+
+    #[tokio::test]
+    async fn test_sync_tokens_pick_up_where_they_left_off() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let claims = Claims {
+            device_id: Some("laptop".to_string()),
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write])
+        };
+        register_device(&database, &claims).await;
+        let sync = |sync_token: Option<&str>| {
+            let sync = sync_files(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(SyncPageLimit(1000)),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+                Json(SyncRequest {
+                    folders: vec!["/".to_string()],
+                    last_sync: None,
+                    sync_token: sync_token.map(str::to_string),
+                    limit: None,
+                }),
+            );
+            async move { sync.await.map(fresh) }
+        };
+
+        let Json(response) = sync(None).await.unwrap();
+        let start = response.data.unwrap().sync_token;
+
+        for path in ["/a.txt", "/b.txt"] {
+            filesystem.save_file(path, b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+
+        let Json(response) = sync(Some(&start)).await.unwrap();
+        let response = response.data.unwrap();
+        assert!(!response.full_resync_required);
+        let created: Vec<&str> = response.changes.iter()
+            .filter(|change| matches!(change.change_type, ChangeType::Created))
+            .map(|change| change.path.as_str())
+            .collect();
+        assert_eq!(created, vec!["/a.txt", "/b.txt"]);
+        let caught_up = response.sync_token;
+
+        // Nothing new, nothing sent again
+        let Json(response) = sync(Some(&caught_up)).await.unwrap();
+        let response = response.data.unwrap();
+        assert!(response.changes.is_empty());
+        assert_eq!(response.sync_token, caught_up);
+
+        database.delete_metadata_under_path(user_id, "/b.txt").await.unwrap();
+
+        let Json(response) = sync(Some(&caught_up)).await.unwrap();
+        let changes = response.data.unwrap().changes;
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(changes[0].change_type, ChangeType::Deleted));
+        assert_eq!(changes[0].path, "/b.txt");
+
+        // Tokens this server never gave out can't be trusted
+        for token in ["not-a-token", "999999", "-1"] {
+            let Json(response) = sync(Some(token)).await.unwrap();
+            assert!(response.data.unwrap().full_resync_required, "{}", token);
+        }
+
+        // The device's session notes the sync, keeping its registered folders
+        let session = database.get_sync_session(user_id, "laptop").await.unwrap().unwrap();
+        assert!(session.sync_folders.is_empty());
+        assert!(Utc::now() - session.last_sync < chrono::Duration::minutes(1));
+
+        // Tokens from before a prune are too old to catch up from
+        database.purge_change_log_before(Utc::now()).await.unwrap();
+        let Json(response) = sync(Some(&caught_up)).await.unwrap();
+        let response = response.data.unwrap();
+        assert!(response.full_resync_required);
+        let Json(response) = sync(Some(&response.sync_token)).await.unwrap();
+        assert!(!response.data.unwrap().full_resync_required);
+    }
+
+    #[tokio::test]
+    async fn test_deleted_files_reach_the_feed_until_it_is_pruned() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        filesystem.save_file("/gone.txt", b"data").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/gone.txt").await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete]);
+        let sync = |sync_token: Option<String>, last_sync: Option<chrono::DateTime<Utc>>| {
+            let sync = sync_files(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(SyncPageLimit(1000)),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+                Json(SyncRequest { folders: Vec::new(), last_sync, sync_token, limit: None }),
+            );
+            async move { sync.await.map(fresh).unwrap().0.data.unwrap() }
+        };
+
+        let before = Utc::now();
+        let token = sync(None, None).await.sync_token;
+
+        delete_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path("gone.txt".to_string()),
+            Query(HashMap::new()),
+        )
+        .await
+        .unwrap();
+
+        // Another device hears about the deletion, even with the row in the trash
+        let response = sync(Some(token.clone()), None).await;
+        assert!(!response.full_resync_required);
+        assert_eq!(response.changes.len(), 1);
+        assert!(matches!(response.changes[0].change_type, ChangeType::Deleted));
+        assert_eq!(response.changes[0].path, "/gone.txt");
+        let caught_up = response.sync_token;
+
+        // Past the retention window the deletion is gone, and so is any way
+        // to catch up from before it
+        database.purge_change_log_before(Utc::now()).await.unwrap();
+        let horizon = database.get_change_log_horizon().await.unwrap().unwrap();
+        assert!(horizon.pruned_before > before);
+
+        for response in [sync(Some(token), None).await, sync(None, Some(before)).await] {
+            assert!(response.full_resync_required);
+            assert!(response.changes.is_empty());
+        }
+
+        // Cursors from after the horizon carry on as before
+        let response = sync(Some(caught_up), None).await;
+        assert!(!response.full_resync_required);
+        assert!(response.changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_compacting_the_change_log_keeps_what_syncs_see() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        // Edited, moved and edited again
+        filesystem.save_file("/a.txt", b"a").await.unwrap();
+        let a = database.resolve_file_metadata(&filesystem, user_id, "/a.txt").await.unwrap();
+        for _ in 0..3 {
+            database.update_file_metadata(&a).await.unwrap();
+        }
+        let moved = FileMetadata { name: "b.txt".to_string(), path: "/b.txt".to_string(), ..a.clone() };
+        database.move_file_metadata(&moved, "/a.txt").await.unwrap();
+        database.update_file_metadata(&moved).await.unwrap();
+
+        // Edited, then deleted
+        filesystem.save_file("/c.txt", b"c").await.unwrap();
+        let c = database.resolve_file_metadata(&filesystem, user_id, "/c.txt").await.unwrap();
+        database.update_file_metadata(&c).await.unwrap();
+        database.update_file_metadata(&c).await.unwrap();
+        database.delete_metadata_under_path(user_id, "/c.txt").await.unwrap();
+
+        let latest = database.get_latest_change_seq().await.unwrap();
+        let feed = |database: Database| async move {
+            let mut feeds = Vec::new();
+            for cursor in 0..=latest {
+                let changes = database.get_changes_after(user_id, cursor, latest).await.unwrap();
+                feeds.push(changes.into_iter()
+                    .map(|c| (c.file_id, format!("{:?}", c.change_type), c.path, c.old_path))
+                    .collect::<Vec<_>>());
+            }
+            feeds
+        };
+        let before = feed(database.clone()).await;
+
+        // Three edits before the move, and all but the deletion of c.txt
+        assert_eq!(database.compact_change_log_before(Utc::now()).await.unwrap(), 6);
+        assert_eq!(feed(database.clone()).await, before);
+        assert_eq!(database.compact_change_log_before(Utc::now()).await.unwrap(), 0);
+        assert!(database.get_change_log_horizon().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_polls_get_304_until_something_changes() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        for path in ["/docs/a.txt", "/docs/b.txt"] {
+            filesystem.save_file(path, b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete]);
+        let if_none_match = |etag: &str| HeaderMap::from_iter([(header::IF_NONE_MATCH, etag.parse().unwrap())]);
+        let list = |headers: HeaderMap| {
+            list_files(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                test_pages(),
+                Extension(claims.clone()),
+                None,
+                Query(HashMap::from([("path".to_string(), "/docs".to_string())])),
+                headers,
+            )
+        };
+        let sync = |headers: HeaderMap| {
+            sync_files(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(SyncPageLimit(1000)),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                headers,
+                Json(SyncRequest { folders: Vec::new(), last_sync: None, sync_token: None, limit: None }),
+            )
+        };
+
+        let Conditional::Modified(_, Some(listed)) = list(HeaderMap::new()).await.unwrap() else {
+            panic!("Listings carry an ETag");
+        };
+        let response = list(if_none_match(&listed)).await.unwrap().into_response();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], listed.as_str());
+        assert!(hyper::body::to_bytes(response.into_body()).await.unwrap().is_empty());
+
+        let Conditional::Modified(_, Some(synced)) = sync(HeaderMap::new()).await.unwrap() else {
+            panic!("Complete syncs carry an ETag");
+        };
+        assert!(matches!(sync(if_none_match(&synced)).await.unwrap(), Conditional::NotModified(_)));
+
+        // One file changing is enough for both
+        filesystem.save_file("/docs/a.txt", b"changed").await.unwrap();
+        let a = database.resolve_file_metadata(&filesystem, user_id, "/docs/a.txt").await.unwrap();
+        database.update_file_metadata(&a).await.unwrap();
+
+        let Conditional::Modified(_, Some(relisted)) = list(if_none_match(&listed)).await.unwrap() else {
+            panic!("The listing changed");
+        };
+        assert_ne!(relisted, listed);
+        let Conditional::Modified(Json(response), Some(resynced)) = sync(if_none_match(&synced)).await.unwrap() else {
+            panic!("The feed changed");
+        };
+        assert_ne!(resynced, synced);
+        assert!(response.data.unwrap().changes.iter().any(|change| change.path == "/docs/a.txt"));
+
+        // Downloads go by the file's content
+        let download = |headers: HeaderMap| {
+            download_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                Extension(claims.clone()),
+                None,
+                Path("docs/a.txt".to_string()),
+                Query(HashMap::new()),
+                headers,
+            )
+        };
+        let response = download(HeaderMap::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let downloaded = response.headers()[header::ETAG].to_str().unwrap().to_string();
+        let last_modified = response.headers()[header::LAST_MODIFIED].clone();
+        let response = download(if_none_match(&downloaded)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], downloaded.as_str());
+        let since = HeaderMap::from_iter([(header::IF_MODIFIED_SINCE, last_modified)]);
+        assert_eq!(download(since).await.unwrap().status(), StatusCode::OK);
+
+        // A move over the file keeps the other file's modification time,
+        // which is no newer than what the client has
+        filesystem.save_file("/docs/b.txt", b"replaced").await.unwrap();
+        let a_path = filesystem.get_absolute_path("/docs/a.txt");
+        let earlier = std::fs::metadata(&a_path).unwrap().modified().unwrap() - std::time::Duration::from_secs(60);
+        std::fs::File::options().write(true).open(filesystem.get_absolute_path("/docs/b.txt")).unwrap()
+            .set_modified(earlier).unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/docs/b.txt").await.unwrap();
+        move_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Query(HashMap::new()),
+            Json(MoveRequest { from: "/docs/b.txt".to_string(), to: "/docs/a.txt".to_string(), overwrite: Some(true) }),
+        ).await.unwrap();
+        let response = download(if_none_match(&downloaded)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[header::ETAG], downloaded.as_str());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"replaced");
+    }
+
+    #[tokio::test]
+    async fn test_sync_pages_through_large_change_sets() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        // The server allows at most 3 changes per page
+        let sync = |sync_token: &str, limit: Option<u32>| {
+            let sync = sync_files(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(SyncPageLimit(3)),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+                Json(SyncRequest {
+                    folders: Vec::new(),
+                    last_sync: None,
+                    sync_token: Some(sync_token.to_string()),
+                    limit,
+                }),
+            );
+            async move { sync.await.map(fresh) }
+        };
+        let paths = |changes: &[FileChange]| changes.iter().map(|change| change.path.clone()).collect::<Vec<_>>();
+
+        let start = database.get_latest_change_seq().await.unwrap().to_string();
+        filesystem.save_file("/a.txt", b"data").await.unwrap();
+        let mut a = database.resolve_file_metadata(&filesystem, user_id, "/a.txt").await.unwrap();
+        a.modified_at = Utc::now();
+        database.update_file_metadata(&a).await.unwrap();
+        for path in ["/b.txt", "/c.txt"] {
+            filesystem.save_file(path, b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+
+        // Asking for more than the server allows gets its maximum; the two
+        // changes to a.txt in the page come out as one
+        let Json(response) = sync(&start, Some(100)).await.unwrap();
+        let page = response.data.unwrap();
+        assert_eq!(paths(&page.changes), vec!["/a.txt", "/b.txt"]);
+        assert!(matches!(page.changes[0].change_type, ChangeType::Created));
+        assert!(page.has_more);
+
+        let Json(response) = sync(&page.sync_token, None).await.unwrap();
+        let page = response.data.unwrap();
+        assert_eq!(paths(&page.changes), vec!["/c.txt"]);
+        assert!(!page.has_more);
+
+        let Json(response) = sync(&page.sync_token, None).await.unwrap();
+        let last = response.data.unwrap();
+        assert!(last.changes.is_empty());
+        assert!(!last.has_more);
+        assert_eq!(last.sync_token, page.sync_token);
+
+        let Json(response) = sync(&start, Some(1)).await.unwrap();
+        let page = response.data.unwrap();
+        assert_eq!(paths(&page.changes), vec!["/a.txt"]);
+        assert!(page.has_more);
+
+        assert_eq!(sync(&start, Some(0)).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_long_polls_wake_on_changes() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let wait = |limit: std::time::Duration, cursor: Option<&str>, timeout: &str| {
+            let mut params = HashMap::from([("timeout".to_string(), timeout.to_string())]);
+            if let Some(cursor) = cursor {
+                params.insert("cursor".to_string(), cursor.to_string());
+            }
+            wait_for_changes(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(LongPollLimit(limit)),
+                State(SyncPageLimit(1000)),
+                Extension(claims.clone()),
+                Query(params),
+            )
+        };
+        let limit = std::time::Duration::from_secs(5);
+
+        let Json(response) = wait(limit, None, "0").await.unwrap();
+        let response = response.data.unwrap();
+        assert!(response.changes.is_empty());
+        let cursor = response.sync_token;
+
+        // One upload wakes every waiter
+        let upload = async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            filesystem.save_file("/a.txt", b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, "/a.txt").await.unwrap();
+        };
+        let (first, second, _) = tokio::join!(
+            wait(limit, Some(&cursor), "30"),
+            wait(limit, Some(&cursor), "30"),
+            upload,
+        );
+        for response in [first, second] {
+            let Json(response) = response.unwrap();
+            let response = response.data.unwrap();
+            assert_eq!(response.changes.len(), 1);
+            assert_eq!(response.changes[0].path, "/a.txt");
+            assert_ne!(response.sync_token, cursor);
+        }
+
+        // Clients can't ask to be held longer than the server allows
+        let started = std::time::Instant::now();
+        let Json(response) = wait(std::time::Duration::from_millis(100), None, "3600").await.unwrap();
+        assert!(response.data.unwrap().changes.is_empty());
+        assert!(started.elapsed() < limit);
+
+        let Json(response) = wait(limit, Some("999999"), "30").await.unwrap();
+        assert!(response.data.unwrap().full_resync_required);
+        assert_eq!(wait(limit, None, "soon").await.unwrap_err().status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_sync_only_reports_the_requested_folders() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+        let start = database.get_latest_change_seq().await.unwrap().to_string();
+
+        for path in ["/documents/a.txt", "/documents/work/b.txt", "/documents-old/c.txt", "/photos/d.jpg"] {
+            filesystem.save_file(path, b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+
+        let claims = Claims {
+            device_id: Some("laptop".to_string()),
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write])
+        };
+        register_device(&database, &claims).await;
+        let sync = |folders: &[&str], sync_token: &str| {
+            let sync = sync_files(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(SyncPageLimit(1000)),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+                Json(SyncRequest {
+                    folders: folders.iter().map(|folder| folder.to_string()).collect(),
+                    last_sync: None,
+                    sync_token: Some(sync_token.to_string()),
+                    limit: None,
+                }),
+            );
+            async move { sync.await.map(fresh) }
+        };
+        let move_to = |from: &str, to: &str| {
+            move_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                Json(MoveRequest { from: from.to_string(), to: to.to_string(), overwrite: None }),
+            )
+        };
+
+        // Overlapping folders report each file once, and only theirs
+        let Json(response) = sync(&["/documents", "/documents/work/"], &start).await.unwrap();
+        let response = response.data.unwrap();
+        let mut paths: Vec<String> = response.changes.into_iter()
+            .filter(|change| !change.metadata.as_ref().is_some_and(|metadata| metadata.is_directory))
+            .map(|change| change.path)
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec!["/documents/a.txt", "/documents/work/b.txt"]);
+        let caught_up = response.sync_token;
+
+        let session = database.get_sync_session(user_id, "laptop").await.unwrap().unwrap();
+        assert!(session.sync_folders.is_empty());
+
+        // A file leaving the folder is gone for the device; the folder's
+        // own rename is passed on so the device can follow it
+        move_to("/documents/a.txt", "/photos/a.txt").await.unwrap();
+        move_to("/documents", "/papers").await.unwrap();
+
+        let Json(response) = sync(&["/documents"], &caught_up).await.unwrap();
+        let changes = response.data.unwrap().changes;
+        assert_eq!(changes.len(), 2, "{:?}", changes);
+        assert!(changes.iter().any(|change| matches!(change.change_type, ChangeType::Deleted)
+            && change.path == "/documents/a.txt"));
+        assert!(changes.iter().any(|change| matches!(change.change_type, ChangeType::Moved)
+            && change.old_path.as_deref() == Some("/documents") && change.path == "/papers"));
+
+        // No folders means everything
+        let Json(response) = sync(&[], &caught_up).await.unwrap();
+        let response = response.data.unwrap();
+        let changes = response.changes;
+        assert!(changes.iter().any(|change| change.path == "/photos/a.txt"));
+
+        assert_eq!(sync(&["/documents/../photos"], &caught_up).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+
+        // A folder moved in from outside brings what is in it along
+        let caught_up = response.sync_token;
+        move_to("/documents-old", "/photos/old").await.unwrap();
+        let Json(response) = sync(&["/photos"], &caught_up).await.unwrap();
+        let changes = response.data.unwrap().changes;
+        assert_eq!(changes.len(), 2, "{:?}", changes);
+        assert!(changes.iter().all(|change| matches!(change.change_type, ChangeType::Created) && change.old_path.is_none()));
+        assert_eq!(changes[0].path, "/photos/old");
+        assert_eq!(changes[1].path, "/photos/old/c.txt");
+    }
+
+    #[tokio::test]
+    async fn test_snapshots_stream_the_requested_folders() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let mut paths = vec![
+            "/documents/a.txt".to_string(),
+            "/documents/work/b.txt".to_string(),
+            "/photos/old/c.jpg".to_string(),
+            "/photos/d.jpg".to_string(),
+            "/music/e.mp3".to_string(),
+        ];
+        // More than a page's worth
+        paths.extend((0..SNAPSHOT_PAGE_SIZE + 20).map(|i| format!("/photos/raw/{:04}.cr2", i)));
+        for path in &paths {
+            filesystem.save_file(path, b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+        let stored = database.get_file_metadata_by_path(user_id, "/documents/a.txt").await.unwrap().unwrap();
+
+        let claims = Claims {
+            device_id: Some("new-laptop".to_string()),
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete])
+        };
+        register_device(&database, &claims).await;
+
+        delete_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path("photos/old".to_string()),
+            Query(HashMap::new()),
+        ).await.unwrap();
+        // Changed on disk behind the server's back; the stored checksum is trusted
+        filesystem.save_file("/documents/a.txt", b"changed").await.unwrap();
+        let latest = database.get_latest_change_seq().await.unwrap();
+
+        let snapshot = |folders: Option<&str>| {
+            let params = folders.map(|folders| HashMap::from([("folders".to_string(), folders.to_string())]));
+            let response = get_sync_snapshot(
+                State(filesystem.clone()),
+                State(database.clone()),
+                Extension(claims.clone()),
+                Query(params.unwrap_or_default()),
+            );
+            async move {
+                let response = response.await?;
+                assert_eq!(response.headers()[header::CONTENT_TYPE], "application/x-ndjson");
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let mut lines = body.split(|byte| *byte == b'\n').filter(|line| !line.is_empty());
+                let head: SnapshotHeader = serde_json::from_slice(lines.next().unwrap()).unwrap();
+                let entries: Vec<SnapshotEntry> = lines.map(|line| serde_json::from_slice(line).unwrap()).collect();
+                Ok::<_, ApiError>((head, entries))
+            }
+        };
+        let files = |entries: &[SnapshotEntry]| -> Vec<String> {
+            entries.iter().filter(|entry| !entry.is_directory).map(|entry| entry.path.clone()).collect()
+        };
+
+        let (head, entries) = snapshot(Some("/documents,/documents/work,/photos")).await.unwrap();
+        assert_eq!(head.sync_token, latest.to_string());
+        assert_eq!(head.folders, vec!["/documents", "/documents/work", "/photos"]);
+        assert_eq!(head.quota.used, database.get_user_storage_usage(user_id).await.unwrap());
+
+        // In path order, each once, without the trash
+        let mut expected: Vec<String> = paths.iter()
+            .filter(|path| !path.starts_with("/music") && !path.starts_with("/photos/old"))
+            .cloned()
+            .collect();
+        expected.sort();
+        assert_eq!(files(&entries), expected);
+        assert!(!entries.iter().any(|entry| entry.path == "/photos/old"));
+
+        let a = entries.iter().find(|entry| entry.path == "/documents/a.txt").unwrap();
+        assert_eq!((a.size, a.checksum.as_str()), (4, stored.checksum.as_str()));
+
+        let session = database.get_sync_session(user_id, "new-laptop").await.unwrap().unwrap();
+        assert!(session.sync_folders.is_empty());
+
+        // No folders means everything
+        let (head, entries) = snapshot(None).await.unwrap();
+        assert!(head.folders.is_empty());
+        assert_eq!(files(&entries).len(), paths.len() - 1);
+
+        assert_eq!(snapshot(Some("/documents/../music")).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_devices_register_before_they_sync() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let claims = |device_id: Option<&str>| Claims {
+            device_id: device_id.map(str::to_string),
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write])
+        };
+        let laptop = claims(Some("laptop"));
+        let sync = |claims: Claims| {
+            let sync = sync_files(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(SyncPageLimit(1000)),
+                Extension(claims),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+                Json(SyncRequest { folders: Vec::new(), last_sync: None, sync_token: None, limit: None }),
+            );
+            async move { sync.await.map(fresh) }
+        };
+        let update = |device_id: &str, is_active: Option<bool>, folders: Option<Vec<String>>| {
+            update_sync_session(
+                State(database.clone()),
+                Extension(laptop.clone()),
+                Path(device_id.to_string()),
+                Json(UpdateSyncSessionRequest { device_name: None, folders, exclude_patterns: None, is_active }),
+            )
+        };
+
+        assert_eq!(sync(laptop.clone()).await.unwrap_err().status, StatusCode::CONFLICT);
+        // Logins without a device have nothing to register
+        sync(claims(None)).await.unwrap();
+        let err = register_sync_session(
+            State(database.clone()),
+            Extension(claims(None)),
+            Json(RegisterSyncSessionRequest { device_name: None, folders: Vec::new(), exclude_patterns: Vec::new() }),
+        ).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        let Json(response) = register_sync_session(
+            State(database.clone()),
+            Extension(laptop.clone()),
+            Json(RegisterSyncSessionRequest {
+                device_name: Some("Work laptop".to_string()),
+                folders: vec!["/docs/".to_string()],
+                exclude_patterns: Vec::new(),
+            }),
+        ).await.unwrap();
+        let session = response.data.unwrap();
+        assert_eq!(session.device_name, "Work laptop");
+        assert_eq!(session.sync_folders, vec!["/docs"]);
+        sync(laptop.clone()).await.unwrap();
+
+        let Json(response) = list_sync_sessions(State(database.clone()), Extension(laptop.clone())).await.unwrap();
+        let sessions = response.data.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0].is_active);
+
+        // Marked inactive, the device has to register again
+        let Json(response) = update("laptop", Some(false), Some(vec!["/photos".to_string()])).await.unwrap();
+        let session = response.data.unwrap();
+        assert!(!session.is_active);
+        assert_eq!(session.sync_folders, vec!["/photos"]);
+        assert_eq!(sync(laptop.clone()).await.unwrap_err().status, StatusCode::CONFLICT);
+        assert_eq!(update("phone", Some(false), None).await.unwrap_err(), StatusCode::NOT_FOUND);
+
+        register_device(&database, &laptop).await;
+        sync(laptop.clone()).await.unwrap();
+
+        // So does one that stopped syncing
+        let idle = database.deactivate_idle_sync_sessions(Utc::now() + chrono::Duration::minutes(1)).await.unwrap();
+        assert_eq!(idle, 1);
+        assert_eq!(sync(laptop.clone()).await.unwrap_err().status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_listings_come_in_pages() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        for name in ["e.txt", "a.txt", "d.txt", "b.txt", "c.txt", "sub/f.txt"] {
+            let path = format!("/docs/{}", name);
+            filesystem.save_file(&path, b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, &path).await.unwrap();
+        }
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete]);
+        let params = |page_token: Option<String>| {
+            let mut params = HashMap::from([
+                ("path".to_string(), "/docs".to_string()),
+                ("limit".to_string(), "2".to_string()),
+            ]);
+            params.extend(page_token.map(|token| ("page_token".to_string(), token)));
+            params
+        };
+        let list = |page_token: Option<String>| {
+            let list = list_files(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                test_pages(),
+                Extension(claims.clone()),
+                None,
+                Query(params(page_token)),
+                HeaderMap::new(),
+            );
+            async move { list.await.map(fresh) }
+        };
+
+        // Following the tokens walks the folder by name, each entry once,
+        // leaving out what lies deeper
+        let mut names = Vec::new();
+        let mut page_token = None;
+        loop {
+            let Json(response) = list(page_token).await.unwrap();
+            let page = response.data.unwrap();
+            assert_eq!(page.total, 6);
+            assert!(page.items.len() <= 2);
+            names.extend(page.items.into_iter().map(|listed| listed.file.name));
+            page_token = page.next_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        assert_eq!(names, vec!["a.txt", "b.txt", "c.txt", "d.txt", "e.txt", "sub"]);
+
+        // Recursive listings come from the records, by path
+        let Json(response) = list_files(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            test_pages(),
+            Extension(claims.clone()),
+            None,
+            Query(HashMap::from([
+                ("path".to_string(), "/".to_string()),
+                ("recursive".to_string(), "true".to_string()),
+                ("limit".to_string(), "4".to_string()),
+            ])),
+            HeaderMap::new(),
+        ).await.map(fresh).unwrap();
+        let page = response.data.unwrap();
+        assert_eq!(page.total, 8);
+        let paths: Vec<String> = page.items.into_iter().map(|listed| listed.file.path).collect();
+        assert_eq!(paths, vec!["/docs", "/docs/a.txt", "/docs/b.txt", "/docs/c.txt"]);
+        assert!(page.next_token.is_some());
+
+        // The trash pages newest first the same way
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            delete_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                Extension(claims.clone()),
+                Path(format!("docs/{}", name)),
+                Query(HashMap::new()),
+            ).await.unwrap();
+        }
+        let trash = |page_token: Option<String>| {
+            list_trash(State(database.clone()), test_pages(), Extension(claims.clone()), Query(params(page_token)))
+        };
+        let Json(response) = trash(None).await.unwrap();
+        let first = response.data.unwrap();
+        assert_eq!(first.total, 3);
+        let Json(response) = trash(first.next_token).await.unwrap();
+        let second = response.data.unwrap();
+        assert!(second.next_token.is_none());
+        let names: Vec<String> = first.items.iter().chain(&second.items).map(|entry| entry.name.clone()).collect();
+        assert_eq!(names, vec!["c.txt", "b.txt", "a.txt"]);
+
+        assert_eq!(trash(Some("bm90IGEga2V5".to_string())).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_search_follows_creates_renames_and_deletes() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        for path in ["/docs/Quarterly-Report.pdf", "/docs/report-draft.txt", "/photos/beach.jpg", "/photos/report.jpg"] {
+            filesystem.save_file(path, b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete]);
+        let search = |params: &[(&str, &str)]| {
+            let params = params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+            search_files(
+                State(filesystem.clone()),
+                State(database.clone()),
+                test_pages(),
+                Extension(claims.clone()),
+                Query(params),
+            )
+        };
+        let paths = |params: &'static [(&'static str, &'static str)]| {
+            let search = search(params);
+            async move {
+                let Json(response) = search.await.unwrap();
+                let mut paths: Vec<String> = response.data.unwrap().items.into_iter().map(|file| file.path).collect();
+                paths.sort();
+                paths
+            }
+        };
+
+        // Words match by prefix, whatever their case
+        assert_eq!(paths(&[("q", "rep")]).await, vec!["/docs/Quarterly-Report.pdf", "/docs/report-draft.txt", "/photos/report.jpg"]);
+        assert_eq!(paths(&[("q", "report"), ("mime", "image/*")]).await, vec!["/photos/report.jpg"]);
+        assert_eq!(paths(&[("mime", "image/*"), ("path_prefix", "/photos")]).await, vec!["/photos/beach.jpg", "/photos/report.jpg"]);
+        assert_eq!(paths(&[("q", "report"), ("path_prefix", "/docs")]).await.len(), 2);
+
+        // Renamed files are found by their new name only
+        let mut renamed = database.get_file_metadata_by_path(user_id, "/docs/Quarterly-Report.pdf").await.unwrap().unwrap();
+        renamed.name = "summary.pdf".to_string();
+        renamed.path = "/docs/summary.pdf".to_string();
+        database.move_file_metadata(&renamed, "/docs/Quarterly-Report.pdf").await.unwrap();
+        assert!(paths(&[("q", "quarterly")]).await.is_empty());
+        assert_eq!(paths(&[("q", "summary")]).await, vec!["/docs/summary.pdf"]);
+
+        // Files in the trash are not found
+        delete_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path("photos/report.jpg".to_string()),
+            Query(HashMap::new()),
+        ).await.unwrap();
+        assert_eq!(paths(&[("q", "report")]).await, vec!["/docs/report-draft.txt"]);
+
+        assert_eq!(search(&[]).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+        assert_eq!(search(&[("min_size", "big")]).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_plans_preview_a_sync_without_changing_anything() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        for path in ["/docs/kept.txt", "/docs/edited.txt", "/docs/dropped.txt", "/other/elsewhere.txt"] {
+            filesystem.save_file(path, b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+        let last_sync = database.get_latest_change_seq().await.unwrap().to_string();
+        filesystem.save_file("/docs/added.txt", b"new").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/docs/added.txt").await.unwrap();
+        let latest = database.get_latest_change_seq().await.unwrap();
+        let stored = database.get_file_metadata_by_path(user_id, "/docs/kept.txt").await.unwrap().unwrap().checksum;
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read]);
+        let plan = |max_entries: u32, sync_token: Option<&str>, manifest: String| {
+            let mut params = HashMap::from([("folders".to_string(), "/docs".to_string())]);
+            if let Some(sync_token) = sync_token {
+                params.insert("sync_token".to_string(), sync_token.to_string());
+            }
+            plan_sync(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(SyncPlanLimit(max_entries)),
+                Extension(claims.clone()),
+                Query(params),
+                axum::body::Body::from(manifest),
+            )
+        };
+        let line = |path: &str, checksum: &str, base_checksum: Option<&str>| {
+            json!({ "path": path, "size": 4, "checksum": checksum, "modified_at": Utc::now(), "base_checksum": base_checksum }).to_string()
+        };
+        let manifest = [
+            line("/docs/kept.txt", &stored, Some(&stored)),
+            line("docs/edited.txt", "edited-here", Some(&stored)),
+            line("/docs/local.txt", "local", None),
+        ].join("\n");
+        let paths = |transfers: &[PlannedTransfer]| -> Vec<String> {
+            transfers.iter().map(|transfer| transfer.path.clone()).collect()
+        };
+
+        let Json(response) = plan(10, Some(&last_sync), manifest.clone()).await.unwrap();
+        let response = response.data.unwrap();
+        assert_eq!(response.sync_token, latest.to_string());
+        assert_eq!(response.plan.unchanged, 1);
+        assert_eq!(paths(&response.plan.upload), vec!["/docs/edited.txt", "/docs/local.txt"]);
+        assert_eq!(paths(&response.plan.download), vec!["/docs/added.txt"]);
+        assert_eq!(response.plan.download_bytes, 3);
+        assert_eq!(response.plan.delete_remote, vec!["/docs/dropped.txt"]);
+        assert!(response.plan.delete_local.is_empty() && response.plan.conflicts.is_empty());
+
+        // Without a token, nothing is known to be deleted on the device
+        let Json(response) = plan(10, None, manifest.clone()).await.unwrap();
+        let response = response.data.unwrap();
+        assert_eq!(paths(&response.plan.download), vec!["/docs/added.txt", "/docs/dropped.txt"]);
+        assert!(response.plan.delete_remote.is_empty());
+
+        assert_eq!(plan(2, None, manifest.clone()).await.unwrap_err().status, StatusCode::PAYLOAD_TOO_LARGE);
+        let broken = format!("{}\nnot json\n", line("/docs/kept.txt", &stored, None));
+        assert_eq!(plan(10, None, broken).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+        let twice = format!("{}\n{}", line("/docs/kept.txt", &stored, None), line("/docs//kept.txt", &stored, None));
+        assert_eq!(plan(10, None, twice).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+
+        // Only a preview
+        assert_eq!(database.get_latest_change_seq().await.unwrap(), latest);
+        assert!(filesystem.get_file_metadata("/docs/dropped.txt").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_devices_leave_out_what_they_exclude() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+        let start = database.get_latest_change_seq().await.unwrap().to_string();
+
+        for path in ["/work/report.txt", "/personal/diary.txt", "/work/personal.txt", "/work/build/out.o"] {
+            filesystem.save_file(path, b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+
+        let claims = Claims {
+            device_id: Some("work-laptop".to_string()),
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete])
+        };
+        let register = |exclude_patterns: &[&str]| {
+            register_sync_session(
+                State(database.clone()),
+                Extension(claims.clone()),
+                Json(RegisterSyncSessionRequest {
+                    device_name: None,
+                    folders: Vec::new(),
+                    exclude_patterns: exclude_patterns.iter().map(|pattern| pattern.to_string()).collect(),
+                }),
+            )
+        };
+        assert_eq!(register(&["/personal/["]).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+        let Json(response) = register(&["/personal", "build/"]).await.unwrap();
+        assert_eq!(response.data.unwrap().exclude_patterns, vec!["/personal", "build/"]);
+
+        let sync = |sync_token: &str| {
+            let sync = sync_files(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(SyncPageLimit(1000)),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+                Json(SyncRequest { folders: Vec::new(), last_sync: None, sync_token: Some(sync_token.to_string()), limit: None }),
+            );
+            async move { sync.await.map(fresh) }
+        };
+        let files = |changes: &[FileChange]| -> Vec<String> {
+            let mut paths: Vec<String> = changes.iter()
+                .filter(|change| !change.metadata.as_ref().is_some_and(|metadata| metadata.is_directory))
+                .map(|change| change.path.clone())
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        // Anchored patterns only match at the top, `build/` matches anywhere
+        let Json(response) = sync(&start).await.unwrap();
+        let response = response.data.unwrap();
+        assert_eq!(files(&response.changes), vec!["/work/personal.txt", "/work/report.txt"]);
+        let caught_up = response.sync_token;
+
+        let response = get_sync_snapshot(
+            State(filesystem.clone()),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Query(HashMap::new()),
+        ).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let snapshot = String::from_utf8(body.to_vec()).unwrap();
+        assert!(snapshot.contains("/work/report.txt"));
+        assert!(!snapshot.contains("/personal/") && !snapshot.contains("/work/build"));
+
+        // The device can't write there either, even if its client tries
+        let operation = |op, path: &str| PushOperation {
+            op,
+            path: path.to_string(),
+            to: None,
+            content: Some(STANDARD.encode(b"hello")),
+            upload_session: None,
+            checksum: None,
+            overwrite: None,
+            base_checksum: None,
+            base_modified_at: None,
+            modified_at: None,
+        };
+        let Json(response) = push_changes(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(PushLimits { max_operations: 5, max_payload_bytes: 64, max_inline_bytes: 32 }),
+            State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+            Extension(claims.clone()),
+            Query(HashMap::new()),
+            Json(PushRequest {
+                operations: vec![
+                    operation(PushOp::Put, "/personal/plans.txt"),
+                    operation(PushOp::Put, "/work/notes.txt"),
+                    PushOperation { to: Some("/personal/report.txt".to_string()), content: None, ..operation(PushOp::Move, "/work/report.txt") },
+                    PushOperation { content: None, ..operation(PushOp::Delete, "/personal/diary.txt") },
+                ],
+            }),
+        ).await.unwrap();
+        let results = response.data.unwrap().results;
+        assert_eq!(results.iter().map(|result| result.success).collect::<Vec<_>>(), vec![false, true, false, false]);
+        assert!(results[0].error.as_deref().unwrap().contains("excluded"));
+        assert!(filesystem.get_absolute_path("/personal/diary.txt").exists());
+        assert!(!filesystem.get_absolute_path("/personal/plans.txt").exists());
+
+        // Other devices, and the same one once the pattern is gone, see it all
+        let Json(response) = update_sync_session(
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path("work-laptop".to_string()),
+            Json(UpdateSyncSessionRequest { device_name: None, folders: None, exclude_patterns: Some(Vec::new()), is_active: None }),
+        ).await.unwrap();
+        assert!(response.data.unwrap().exclude_patterns.is_empty());
+        let Json(response) = sync(&start).await.unwrap();
+        assert!(files(&response.data.unwrap().changes).contains(&"/personal/diary.txt".to_string()));
+        let Json(response) = sync(&caught_up).await.unwrap();
+        assert_eq!(files(&response.data.unwrap().changes), vec!["/work/notes.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_push_applies_each_operation_and_skips_its_own_changes() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        for path in ["/b.txt", "/c.txt"] {
+            filesystem.save_file(path, b"data").await.unwrap();
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+
+        let claims = Claims {
+            device_id: Some("laptop".to_string()),
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete])
+        };
+        register_device(&database, &claims).await;
+        let limits = PushLimits { max_operations: 5, max_payload_bytes: 64, max_inline_bytes: 32 };
+        let operation = |op, path: &str| PushOperation {
+            op,
+            path: path.to_string(),
+            to: None,
+            content: None,
+            upload_session: None,
+            checksum: None,
+            overwrite: None,
+            base_checksum: None,
+            base_modified_at: None,
+            modified_at: None,
+        };
+        let push = |operations: Vec<PushOperation>| {
+            push_changes(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                State(limits),
+                State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+                Extension(claims.clone()),
+                Query(HashMap::new()),
+                Json(PushRequest { operations }),
+            )
+        };
+
+        let Json(response) = fresh(sync_files(
+            State(filesystem.clone()),
+            State(database.clone()),
+            State(SyncPageLimit(1000)),
+            Extension(claims.clone()),
+            Query(HashMap::new()),
+            HeaderMap::new(),
+            Json(SyncRequest { folders: Vec::new(), last_sync: None, sync_token: None, limit: None }),
+        )
+        .await
+        .unwrap());
+        let start = response.data.unwrap().sync_token;
+
+        let Json(response) = push(vec![
+            operation(PushOp::CreateDir, "/docs"),
+            PushOperation {
+                content: Some(STANDARD.encode(b"hello")),
+                ..operation(PushOp::Put, "/docs/notes/a.txt")
+            },
+            PushOperation { to: Some("/docs/b.txt".to_string()), ..operation(PushOp::Move, "/b.txt") },
+            operation(PushOp::Delete, "/c.txt"),
+            operation(PushOp::Put, "/docs/empty.txt"),
+        ])
+        .await
+        .unwrap();
+        let response = response.data.unwrap();
+
+        let outcomes: Vec<bool> = response.results.iter().map(|r| r.success).collect();
+        assert_eq!(outcomes, vec![true, true, true, true, false]);
+        assert_eq!(response.results[4].error.as_deref(), Some("A put needs either content or an upload session"));
+        assert_eq!(tokio::fs::read(filesystem.get_absolute_path("/docs/notes/a.txt")).await.unwrap(), b"hello");
+        assert!(database.get_file_metadata_by_path(user_id, "/docs/b.txt").await.unwrap().is_some());
+        assert!(database.get_file_metadata_by_path(user_id, "/c.txt").await.unwrap().is_none());
+
+        // The token is past the push, so the device doesn't download its own changes
+        assert_ne!(response.sync_token, start);
+        let Json(synced) = fresh(sync_files(
+            State(filesystem.clone()),
+            State(database.clone()),
+            State(SyncPageLimit(1000)),
+            Extension(claims.clone()),
+            Query(HashMap::new()),
+            HeaderMap::new(),
+            Json(SyncRequest { folders: Vec::new(), last_sync: None, sync_token: Some(response.sync_token), limit: None }),
+        )
+        .await
+        .unwrap());
+        assert!(synced.data.unwrap().changes.is_empty());
+
+        // Batches over the limits are turned away whole
+        let too_many = (0..6).map(|i| operation(PushOp::CreateDir, &format!("/dir{}", i))).collect();
+        assert_eq!(push(too_many).await.unwrap_err().status, StatusCode::PAYLOAD_TOO_LARGE);
+        let too_big = PushOperation {
+            content: Some(STANDARD.encode([0u8; 33])),
+            ..operation(PushOp::Put, "/big.bin")
+        };
+        assert_eq!(push(vec![too_big]).await.unwrap_err().status, StatusCode::PAYLOAD_TOO_LARGE);
+        let payload = (0..3)
+            .map(|i| PushOperation {
+                content: Some(STANDARD.encode([0u8; 30])),
+                ..operation(PushOp::Put, &format!("/part{}.bin", i))
+            })
+            .collect();
+        assert_eq!(push(payload).await.unwrap_err().status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(database.get_file_metadata_by_path(user_id, "/part0.bin").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_upload_checksum_verification() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let params = HashMap::from([("path".to_string(), "/docs".to_string())]);
+        let hello_sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        // A wrong checksum is rejected and nothing is stored
+        let err = upload_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+            Extension(claims.clone()),
+            Query(params.clone()),
+            HeaderMap::new(),
+            multipart_upload("a.txt", "hello", Some(&"0".repeat(64))).await,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.data.unwrap()["actual"], hello_sha256);
+        assert!(!filesystem.get_absolute_path("/docs/a.txt").exists());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CHECKSUM_HEADER, hello_sha256.parse().unwrap());
+
+        let Json(response) = upload_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+            Extension(claims.clone()),
+            Query(params.clone()),
+            headers.clone(),
+            multipart_upload("a.txt", "hello", None).await,
+        )
+        .await
+        .unwrap();
+        let stored = response.data.unwrap();
+        assert!(!stored.deduplicated);
+
+        // The same content at the same path is not written again
+        let Json(response) = upload_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+            Extension(claims),
+            Query(params),
+            headers,
+            multipart_upload("a.txt", "hello", None).await,
+        )
+        .await
+        .unwrap();
+        let skipped = response.data.unwrap();
+        assert!(skipped.deduplicated);
+        assert_eq!(skipped.file_id, stored.file_id);
+        assert_eq!(skipped.size, 5);
+    }
+
+    #[tokio::test]
+    async fn test_large_downloads_stream_whole() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap()
+            .with_stream_threshold(1024);
+
+        // Several read chunks, the last one short
+        let content: Vec<u8> = (0..3 * crate::filesystem::STREAM_CHUNK_SIZE + 17).map(|i| (i % 251) as u8).collect();
+        filesystem.save_file("/big.bin", &content).await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/big.bin").await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read]);
+        let download = |headers: HeaderMap| {
+            download_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                Extension(claims.clone()),
+                None,
+                Path("big.bin".to_string()),
+                Query(HashMap::new()),
+                headers,
+            )
+        };
+
+        let response = download(HeaderMap::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], content.len().to_string().as_str());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, content);
+
+        // A range across a chunk boundary streams just those bytes
+        let start = crate::filesystem::STREAM_CHUNK_SIZE - 10;
+        let end = 2 * crate::filesystem::STREAM_CHUNK_SIZE + 10;
+        let range = HeaderMap::from_iter([(header::RANGE, format!("bytes={}-{}", start, end - 1).parse().unwrap())]);
+        let response = download(range).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], (end - start).to_string().as_str());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, &content[start..end]);
+    }
+
+    #[tokio::test]
+    async fn test_overwrites_keep_versions_to_list_download_and_restore() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        // Deduplicated, so on unix a version shares its inode with the live file and the object
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap()
+            .with_version_retention(2, 0)
+            .with_deduplication(true);
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let params = HashMap::from([
+            ("path".to_string(), "/".to_string()),
+            ("overwrite".to_string(), "true".to_string()),
+        ]);
+        let upload = |content: &'static str| {
+            let (filesystem, database, claims, params) = (filesystem.clone(), database.clone(), claims.clone(), params.clone());
+            async move {
+                upload_file(
+                    State(filesystem.clone()),
+                    local_storage(&filesystem),
+                    State(database),
+                    State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+                    Extension(claims),
+                    Query(params),
+                    HeaderMap::new(),
+                    multipart_upload("notes.txt", content, None).await,
+                )
+                .await
+                .unwrap()
+            }
+        };
+        let download = |file_id: Uuid, version: u32| download_file_version(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path((file_id.to_string(), version)),
+        );
+        let read_version = |file_id: Uuid, version: u32| {
+            let download = download(file_id, version);
+            async move {
+                let response = download.await.unwrap();
+                assert_eq!(response.headers()[header::CONTENT_LENGTH], "2");
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            }
+        };
+        let versions = |file_id: Uuid| {
+            let listed = list_file_versions(State(database.clone()), Extension(claims.clone()), Path(file_id.to_string()));
+            async move {
+                listed.await.unwrap().0.data.unwrap().iter().map(|version| version.version).collect::<Vec<_>>()
+            }
+        };
+
+        let file_id = upload("v1").await.0.data.unwrap().file_id;
+        for content in ["v2", "v3", "v4"] {
+            assert_eq!(upload(content).await.0.data.unwrap().file_id, file_id);
+        }
+
+        // Three overwrites kept three versions, of which the newest two remain
+        assert_eq!(versions(file_id).await, vec![3, 2]);
+        assert_eq!(read_version(file_id, 3).await.as_ref(), b"v3");
+        assert_eq!(read_version(file_id, 2).await.as_ref(), b"v2");
+        assert_eq!(download(file_id, 1).await.unwrap_err().status, StatusCode::NOT_FOUND);
+
+        // Restoring keeps what it replaces as a version too
+        let Json(response) = restore_file_version(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path((file_id.to_string(), 2)),
+        )
+        .await
+        .unwrap();
+        let restored = response.data.unwrap();
+        assert_eq!(restored.id, file_id);
+        assert_eq!(filesystem.read_file("/notes.txt").await.unwrap(), b"v2");
+        assert_eq!(versions(file_id).await, vec![4, 3]);
+        assert_eq!(read_version(file_id, 4).await.as_ref(), b"v4");
+
+        // Overwriting the live file leaves the stored bytes alone
+        upload("v5").await;
+        assert_eq!(read_version(file_id, 4).await.as_ref(), b"v4");
+        assert_eq!(read_version(file_id, 5).await.as_ref(), b"v2");
+        assert_eq!(filesystem.read_file("/notes.txt").await.unwrap(), b"v5");
+    }
+
+    #[tokio::test]
+    async fn test_conflicting_uploads_keep_both_versions() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let upload = |device: &str, base: Option<&str>, on_conflict: Option<&str>, data: &'static str| {
+            let claims = Claims {
+                device_id: Some(device.to_string()),
+                ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write])
+            };
+            let mut params = HashMap::from([("path".to_string(), "/docs".to_string())]);
+            if let Some(on_conflict) = on_conflict {
+                params.insert("on_conflict".to_string(), on_conflict.to_string());
+            }
+            let mut headers = HeaderMap::new();
+            if let Some(base) = base {
+                headers.insert(BASE_CHECKSUM_HEADER, base.parse().unwrap());
+            }
+            let (filesystem, database) = (filesystem.clone(), database.clone());
+            async move {
+                upload_file(
+                    State(filesystem.clone()),
+                    local_storage(&filesystem),
+                    State(database),
+                    State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+                    Extension(claims),
+                    Query(params),
+                    headers,
+                    multipart_upload("a.txt", data, None).await,
+                )
+                .await
+            }
+        };
+        let read = |path: &str| std::fs::read_to_string(filesystem.get_absolute_path(path)).unwrap();
+        let hello_sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        upload("laptop", None, None, "hello").await.unwrap();
+        let cursor = database.get_latest_change_seq().await.unwrap();
+
+        // Based on what is there, so it simply replaces it
+        let Json(response) = upload("laptop", Some(hello_sha256), None, "hello from the laptop").await.unwrap();
+        assert!(!response.data.unwrap().conflict);
+        assert_eq!(read("/docs/a.txt"), "hello from the laptop");
+
+        // The phone still had the first version
+        let Json(response) = upload("phone", Some(hello_sha256), None, "hello from the phone").await.unwrap();
+        let copy = response.data.unwrap();
+        assert!(copy.conflict);
+        let date = Utc::now().format("%Y-%m-%d");
+        assert_eq!(copy.path, format!("/docs/a (conflicted copy from phone {}).txt", date));
+        assert_eq!(read(&copy.path), "hello from the phone");
+        assert_eq!(read("/docs/a.txt"), "hello from the laptop");
+
+        // Other devices pick the copy up
+        let latest = database.get_latest_change_seq().await.unwrap();
+        let changes = database.get_changes_after(user_id, cursor, latest).await.unwrap();
+        assert!(changes.iter().any(|change| matches!(change.change_type, ChangeType::Created)
+            && change.path == copy.path && change.file_id == copy.file_id));
+
+        let Json(response) = upload("phone", Some(hello_sha256), Some("keep-both"), "hello again").await.unwrap();
+        assert_eq!(response.data.unwrap().path, format!("/docs/a (conflicted copy from phone {} 2).txt", date));
+
+        let err = upload("phone", Some(hello_sha256), Some("fail"), "hello once more").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+        assert_eq!(read("/docs/a.txt"), "hello from the laptop");
+        let err = upload("phone", Some(hello_sha256), Some("overwrite"), "hello").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        // Found unchanged, but replaced before the write landed
+        let mut staged = filesystem.begin_upload().await.unwrap();
+        staged.write_chunk(b"hello, late").await.unwrap();
+        let staged = staged.finish().await.unwrap();
+        let stale = BaseVersion { checksum: Some(hello_sha256.to_string()), modified_at: None };
+        let claims = Claims {
+            device_id: Some("tablet".to_string()),
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write])
+        };
+        let storage = local_storage(&filesystem).0;
+        let (late, policy) = store_over_base(
+            &filesystem,
+            storage.as_ref(),
+            &database,
+            &claims,
+            user_id,
+            "/docs/a.txt",
+            staged,
+            &stale,
+            |_| ConflictPolicy::KeepBoth,
+        ).await.unwrap();
+        assert_eq!(policy, Some(ConflictPolicy::KeepBoth));
+        assert_eq!(late.path, format!("/docs/a (conflicted copy from tablet {}).txt", date));
+        assert_eq!(read("/docs/a.txt"), "hello from the laptop");
+        let current = database.get_file_metadata_by_path(user_id, "/docs/a.txt").await.unwrap().unwrap();
+        assert_ne!(current.checksum, hello_sha256);
+    }
+
+    #[tokio::test]
+    async fn test_each_path_has_a_single_row() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete]);
+        let upload = |overwrite: bool, data: &'static str| {
+            let params = HashMap::from([
+                ("path".to_string(), "/docs".to_string()),
+                ("overwrite".to_string(), overwrite.to_string()),
+            ]);
+            let (filesystem, database, claims) = (filesystem.clone(), database.clone(), claims.clone());
+            async move {
+                let Json(response) = upload_file(
+                    State(filesystem.clone()),
+                    local_storage(&filesystem),
+                    State(database),
+                    State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+                    Extension(claims),
+                    Query(params),
+                    HeaderMap::new(),
+                    multipart_upload("a.txt", data, None).await,
+                )
+                .await
+                .unwrap();
+                response.data.unwrap().file_id
+            }
+        };
+        let delete = || delete_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path("docs/a.txt".to_string()),
+            Query(HashMap::new()),
+        );
+        let rows = || async {
+            let owned = database.get_files_owned_by(user_id).await.unwrap();
+            owned.into_iter().filter(|row| row.path == "/docs/a.txt").map(|row| row.id).collect::<Vec<_>>()
+        };
+
+        // Overwriting updates the row and shows up as an edit
+        let first = upload(false, "one").await;
+        let cursor = database.get_latest_change_seq().await.unwrap();
+        assert_eq!(upload(true, "two").await, first);
+        assert_eq!(rows().await, vec![first]);
+        let latest = database.get_latest_change_seq().await.unwrap();
+        let changes = database.get_changes_after(user_id, cursor, latest).await.unwrap();
+        assert!(!changes.is_empty());
+        assert!(changes.iter().all(|change| matches!(change.change_type, ChangeType::Modified) && change.file_id == first));
+
+        // A new file where a trashed one was is a file of its own
+        delete().await.unwrap();
+        let second = upload(false, "three").await;
+        assert_ne!(second, first);
+        assert_eq!(rows().await, vec![second]);
+
+        // Each trash entry still has its own row to restore or purge
+        delete().await.unwrap();
+        assert!(rows().await.is_empty());
+        let Json(response) = list_trash(State(database.clone()), test_pages(), Extension(claims.clone()), Query(HashMap::new())).await.unwrap();
+        let trash = response.data.unwrap().items;
+        let entry = |file_id: Uuid| trash.iter().find(|entry| entry.file_id == Some(file_id)).unwrap().id.to_string();
+
+        let Json(response) = restore_trash_entry(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path(entry(first)),
+        ).await.unwrap();
+        assert_eq!(response.data.unwrap().id, first);
+        purge_trash_entry(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path(entry(second)),
+        ).await.unwrap();
+        assert_eq!(rows().await, vec![first]);
+        assert!(database.get_file_metadata(second).await.unwrap().is_none());
+        assert_eq!(std::fs::read_to_string(filesystem.get_absolute_path("/docs/a.txt")).unwrap(), "two");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_uploads_all_land() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+
+        // Far more writers than pooled connections, each reading before it writes
+        let uploads = (0..48).map(|i| {
+            let (filesystem, database, claims) = (filesystem.clone(), database.clone(), claims.clone());
+            tokio::spawn(async move {
+                let params = HashMap::from([("path".to_string(), format!("/load/{}", i % 4))]);
+                upload_file(
+                    State(filesystem.clone()),
+                    local_storage(&filesystem),
+                    State(database),
+                    State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+                    Extension(claims),
+                    Query(params),
+                    HeaderMap::new(),
+                    multipart_upload(&format!("file-{}.txt", i), "payload", None).await,
+                )
+                .await
+                .map(|_| ())
+            })
+        });
+        for upload in uploads.collect::<Vec<_>>() {
+            upload.await.unwrap().unwrap();
+        }
+
+        let owned = database.get_files_owned_by(user_id).await.unwrap();
+        assert_eq!(owned.iter().filter(|row| !row.is_directory).count(), 48);
+        let latest = database.get_latest_change_seq().await.unwrap();
+        let changes = database.get_changes_after(user_id, 0, latest).await.unwrap();
+        assert_eq!(changes.iter().filter(|change| change.path.ends_with(".txt")).count(), 48);
+        database.health().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pushes_settle_conflicts_by_the_configured_policy() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let mut stored = HashMap::new();
+        for path in ["/notes.txt", "/todo.txt", "/memo.txt", "/draft.txt", "/photos/beach.txt", "/photos/dunes.txt"] {
+            filesystem.save_file(path, b"old").await.unwrap();
+            let metadata = database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+            stored.insert(path, metadata.modified_at);
+        }
+
+        let claims = Claims {
+            device_id: Some("laptop".to_string()),
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write])
+        };
+        register_device(&database, &claims).await;
+        let settings = SyncSettings {
+            conflict_policy: ConflictPolicy::NewestWins,
+            newest_wins_tolerance_seconds: 2,
+            folder_policies: HashMap::from([
+                ("/photos".to_string(), ConflictPolicy::KeepBoth),
+                ("/draft.txt".to_string(), ConflictPolicy::ServerWins),
+            ]),
+        };
+        let limits = PushLimits { max_operations: 10, max_payload_bytes: 1024, max_inline_bytes: 1024 };
+        // Each based on a version the server no longer has, last modified `ahead` of the server's
+        let put = |path: &str, ahead: chrono::Duration| PushOperation {
+            op: PushOp::Put,
+            path: path.to_string(),
+            to: None,
+            content: Some(STANDARD.encode(b"new")),
+            upload_session: None,
+            checksum: None,
+            overwrite: None,
+            base_checksum: Some("0".repeat(64)),
+            base_modified_at: None,
+            modified_at: Some(stored[path] + ahead),
+        };
+        let hour = chrono::Duration::hours(1);
+
+        // Larger files come through an upload session, and are settled alike
+        let Json(response) = create_upload_session(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(TEST_UPLOAD_LIMITS),
+            Extension(claims.clone()),
+            Query(HashMap::new()),
+            Json(CreateUploadSessionRequest {
+                path: "/photos/dunes.txt".to_string(),
+                total_size: 3,
+                chunk_size: 8,
+                checksum: None,
+                overwrite: Some(true),
+            }),
+        ).await.unwrap();
+        let session_id = response.data.unwrap().session_id;
+        upload_session_chunk(
+            State(filesystem.clone()),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path((session_id.to_string(), 0)),
+            axum::body::Bytes::from_static(b"new"),
+        ).await.unwrap();
+
+        let Json(response) = push_changes(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(limits),
+            State(ConflictSettings::new(&settings).unwrap()),
+            Extension(claims),
+            Query(HashMap::new()),
+            Json(PushRequest {
+                operations: vec![
+                    put("/notes.txt", hour),
+                    put("/todo.txt", -hour),
+                    put("/memo.txt", chrono::Duration::seconds(1)),
+                    put("/draft.txt", hour),
+                    put("/photos/beach.txt", hour),
+                    PushOperation { content: None, upload_session: Some(session_id), ..put("/photos/dunes.txt", hour) },
+                ],
+            }),
+        )
+        .await
+        .unwrap();
+        let results = response.data.unwrap().results;
+        assert!(results.iter().all(|result| result.success));
+        let read = |path: &str| std::fs::read_to_string(filesystem.get_absolute_path(path)).unwrap();
+
+        // Newest wins, either way
+        assert_eq!(results[0].conflict_policy, Some(ConflictPolicy::ClientWins));
+        assert_eq!(read("/notes.txt"), "new");
+        assert_eq!(results[1].conflict_policy, Some(ConflictPolicy::ServerWins));
+        assert_eq!(read("/todo.txt"), "old");
+        // Too close to call
+        assert_eq!(results[2].conflict_policy, Some(ConflictPolicy::KeepBoth));
+        assert_eq!(read("/memo.txt"), "old");
+
+        // Folder policies win over the default
+        assert_eq!(results[3].conflict_policy, Some(ConflictPolicy::ServerWins));
+        assert_eq!(results[3].metadata.as_ref().unwrap().path, "/draft.txt");
+        assert_eq!(read("/draft.txt"), "old");
+        assert_eq!(results[4].conflict_policy, Some(ConflictPolicy::KeepBoth));
+        let copy = &results[4].metadata.as_ref().unwrap().path;
+        assert!(copy.starts_with("/photos/beach (conflicted copy from "));
+        assert_eq!(read(copy), "new");
+        assert_eq!(read("/photos/beach.txt"), "old");
+        assert_eq!(results[5].conflict_policy, Some(ConflictPolicy::KeepBoth));
+        let copy = &results[5].metadata.as_ref().unwrap().path;
+        assert!(copy.starts_with("/photos/dunes (conflicted copy from "));
+        assert_eq!(read(copy), "new");
+        assert_eq!(read("/photos/dunes.txt"), "old");
+        assert!(database.get_upload_session(session_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delta_uploads_patch_the_server_copy() {
+        use sha2::Digest;
+
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap()
+            .with_version_retention(10, 0);
+
+        let base: Vec<u8> = (0..20_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        filesystem.save_file("/vault.hc", &base).await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/vault.hc").await.unwrap();
+        filesystem.save_file("/small.txt", b"small").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/small.txt").await.unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let settings = DeltaSettings { block_size: 512, min_file_size: 1024 };
+        let signature = |path: &str| {
+            get_file_signature(
+                local_storage(&filesystem),
+                State(database.clone()),
+                State(settings),
+                Extension(claims.clone()),
+                Path(path.to_string()),
+                Query(HashMap::new()),
+            )
+        };
+        let apply = |path: &str, delta: Vec<u8>, checksum: &str, base_checksum: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CHECKSUM_HEADER, checksum.parse().unwrap());
+            headers.insert(BASE_CHECKSUM_HEADER, base_checksum.parse().unwrap());
+            apply_file_delta(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                State(settings),
+                Extension(claims.clone()),
+                Path(path.to_string()),
+                Query(HashMap::new()),
+                headers,
+                delta.into(),
+            )
+        };
+
+        let response = signature("vault.hc").await.unwrap();
+        assert_eq!(response.headers()[BLOCK_SIZE_HEADER], "512");
+        let base_checksum = response.headers()[BASE_CHECKSUM_HEADER].to_str().unwrap().to_string();
+        assert_eq!(base_checksum, format!("{:x}", sha2::Sha256::digest(&base)));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        // A few bytes changed in the middle
+        let mut new = base.clone();
+        new[40_000..40_016].copy_from_slice(b"changed content!");
+        let new_checksum = format!("{:x}", sha2::Sha256::digest(&new));
+        let mut delta = Vec::new();
+        let indexed = fast_rsync::Signature::deserialize(body.to_vec()).unwrap();
+        fast_rsync::diff(&indexed.index(), &new, &mut delta).unwrap();
+        assert!(delta.len() < new.len() / 10);
+
+        // A wrong final checksum leaves the file alone
+        let err = apply("vault.hc", delta.clone(), &base_checksum, &base_checksum).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(std::fs::read(filesystem.get_absolute_path("/vault.hc")).unwrap(), base);
+
+        let Json(response) = apply("vault.hc", delta.clone(), &new_checksum, &base_checksum).await.unwrap();
+        let patched = response.data.unwrap();
+        assert_eq!(patched.checksum, new_checksum);
+        assert_eq!(std::fs::read(filesystem.get_absolute_path("/vault.hc")).unwrap(), new);
+
+        // The content the patch replaced is kept as a version, byte for byte
+        let versions = database.list_file_versions(patched.file_id).await.unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].checksum, base_checksum);
+        let kept = download_file_version(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path((patched.file_id.to_string(), versions[0].version)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(hyper::body::to_bytes(kept.into_body()).await.unwrap(), base);
+
+        // The same delta no longer fits once the file moved on
+        let err = apply("vault.hc", delta, &new_checksum, &base_checksum).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+
+        // Small files and deltas as large as the file go up in full
+        assert_eq!(signature("small.txt").await.unwrap_err().status, StatusCode::UNPROCESSABLE_ENTITY);
+        let err = apply("vault.hc", vec![0; new.len()], &new_checksum, &new_checksum).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_locked_path_rejects_other_devices() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let device = |device_id: &str| Claims {
+            device_id: Some(device_id.to_string()),
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write])
+        };
+        let session_request = || CreateUploadSessionRequest {
+            path: "/report.docx".to_string(),
+            total_size: 10,
+            chunk_size: 10,
+            checksum: None,
+            overwrite: None,
+        };
+
+        let Json(response) = lock_file(
+            State(filesystem.clone()),
+            State(database.clone()),
+            Extension(device("laptop")),
+            Path("report.docx".to_string()),
+            None,
+        )
+        .await
+        .unwrap();
+        let lock = response.data.unwrap();
+        assert_eq!(lock.path, "/report.docx");
+
+        let err = create_upload_session(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(TEST_UPLOAD_LIMITS),
+            Extension(device("phone")),
+            Query(HashMap::new()),
+            Json(session_request()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status, StatusCode::LOCKED);
+        assert_eq!(err.data.unwrap()["holder"], "testuser");
+
+        // Only admins can force a lock they don't hold
+        let params = HashMap::from([("force".to_string(), "true".to_string())]);
+        let err = unlock_file(
+            State(filesystem.clone()),
+            State(database.clone()),
+            Extension(device("phone")),
+            Path("report.docx".to_string()),
+            Query(params),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status, StatusCode::LOCKED);
+
+        // The holder's own uploads go through
+        create_upload_session(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(TEST_UPLOAD_LIMITS),
+            Extension(device("laptop")),
+            Query(HashMap::new()),
+            Json(session_request()),
+        )
+        .await
+        .unwrap();
+
+        unlock_file(
+            State(filesystem.clone()),
+            State(database.clone()),
+            Extension(device("laptop")),
+            Path("report.docx".to_string()),
+            Query(HashMap::new()),
+        )
+        .await
+        .unwrap();
+
+        create_upload_session(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database),
+            Extension(device("phone")),
+            Query(HashMap::new()),
+            Json(session_request()),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_user_homes_keep_files_apart() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024)
+            .unwrap()
+            .with_user_homes(true);
+
+        let admin = User {
+            id: Uuid::new_v4(),
+            username: "admin".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["admin".to_string()],
+        };
+        database.create_user(&admin).await.unwrap();
+
+        let claims = |id: Uuid, username: &str| test_claims(id, username, fixture_permissions(username));
+        let list = |claims: Claims, params: HashMap<String, String>| {
+            let list = list_files(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                test_pages(),
+                Extension(claims),
+                None,
+                Query(params),
+                HeaderMap::new(),
+            );
+            async move { list.await.map(fresh) }
+        };
+
+        create_folder(
+            State(filesystem.clone()),
+            State(database.clone()),
+            Extension(claims(user_id, "testuser")),
+            Query(HashMap::new()),
+            Json(CreateFolderRequest { path: "/".to_string(), name: "private".to_string() }),
+        )
+        .await
+        .unwrap();
+        let home = filesystem.for_user(user_id).unwrap();
+        assert!(home.get_absolute_path("/private").is_dir());
+        assert!(!filesystem.get_absolute_path("/private").exists());
+
+        // The admin's own home is empty
+        let Json(response) = list(claims(admin.id, "admin"), HashMap::new()).await.unwrap();
+        assert!(response.data.unwrap().items.is_empty());
+
+        // Only admins can look into another home
+        let as_admin = HashMap::from([("user".to_string(), "admin".to_string())]);
+        let err = list(claims(user_id, "testuser"), as_admin).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        let as_user = HashMap::from([("user".to_string(), "testuser".to_string())]);
+        let Json(response) = list(claims(admin.id, "admin"), as_user).await.unwrap();
+        let files = response.data.unwrap().items;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].file.path, "/private");
+        assert_eq!(files[0].file.owner_id, user_id);
+    }
+
+    #[tokio::test]
+    async fn test_users_share_folders_with_each_other() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, owner_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024)
+            .unwrap()
+            .with_user_homes(true);
+
+        let friend = User {
+            id: Uuid::new_v4(),
+            username: "friend".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string(), "write".to_string()],
+        };
+        database.create_user(&friend).await.unwrap();
+
+        let claims = |id: Uuid, username: &str| test_claims(id, username, fixture_permissions(username));
+        let owner = claims(owner_id, "testuser");
+        let guest = claims(friend.id, "friend");
+        let at = |path: &str| HashMap::from([
+            ("user".to_string(), "testuser".to_string()),
+            ("path".to_string(), path.to_string()),
+        ]);
+        let list = |claims: Claims, params: HashMap<String, String>| {
+            let list = list_files(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                test_pages(),
+                Extension(claims),
+                None,
+                Query(params),
+                HeaderMap::new(),
+            );
+            async move { list.await.map(fresh) }
+        };
+        let upload = |claims: Claims, params: HashMap<String, String>, name: &'static str| {
+            let filesystem = filesystem.clone();
+            let database = database.clone();
+            async move {
+                upload_file(
+                    State(filesystem.clone()),
+                    local_storage(&filesystem),
+                    State(database),
+                    State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+                    Extension(claims),
+                    Query(params),
+                    HeaderMap::new(),
+                    multipart_upload(name, "data", None).await,
+                )
+                .await
+            }
+        };
+        let share = |permissions: SharePermissions, file_id: Uuid| {
+            share_with_user(
+                State(database.clone()),
+                Extension(owner.clone()),
+                Path(file_id.to_string()),
+                Json(ShareWithRequest { username: "friend".to_string(), permissions }),
+            )
+        };
+
+        let mut folders = HashMap::new();
+        for name in ["shared", "private"] {
+            let Json(response) = create_folder(
+                State(filesystem.clone()),
+                State(database.clone()),
+                Extension(owner.clone()),
+                Query(HashMap::new()),
+                Json(CreateFolderRequest { path: "/".to_string(), name: name.to_string() }),
+            )
+            .await
+            .unwrap();
+            folders.insert(name, response.data.unwrap().id);
+        }
+        let own_folder = HashMap::from([("path".to_string(), "/shared".to_string())]);
+        upload(owner.clone(), own_folder, "a.txt").await.unwrap();
+
+        // Nothing is reachable before it is shared
+        assert_eq!(list(guest.clone(), at("/shared")).await.unwrap_err().status, StatusCode::FORBIDDEN);
+
+        // Owners can't share with themselves
+        let err = share_with_user(
+            State(database.clone()),
+            Extension(owner.clone()),
+            Path(folders["shared"].to_string()),
+            Json(ShareWithRequest { username: "testuser".to_string(), permissions: SharePermissions::Read }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        share(SharePermissions::Read, folders["shared"]).await.unwrap();
+
+        let Json(response) = list_shared_with_me(State(database.clone()), Extension(guest.clone())).await.unwrap();
+        let incoming = response.data.unwrap();
+        assert_eq!(incoming.len(), 1);
+        assert_eq!((incoming[0].owner.as_str(), incoming[0].path.as_str()), ("testuser", "/shared"));
+
+        // The folder's contents can be read, nothing beside it
+        let Json(response) = list(guest.clone(), at("/shared")).await.unwrap();
+        let files = response.data.unwrap().items;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].file.path, "/shared/a.txt");
+        assert_eq!(list(guest.clone(), at("/private")).await.unwrap_err().status, StatusCode::FORBIDDEN);
+        assert_eq!(list(guest.clone(), at("/")).await.unwrap_err().status, StatusCode::FORBIDDEN);
+
+        // Read-only shares take no uploads
+        let err = upload(guest.clone(), at("/shared"), "b.txt").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        share(SharePermissions::ReadWrite, folders["shared"]).await.unwrap();
+        upload(guest.clone(), at("/shared"), "b.txt").await.unwrap();
+        let home = filesystem.for_user(owner_id).unwrap();
+        assert!(home.get_absolute_path("/shared/b.txt").is_file());
+        let err = upload(guest.clone(), at("/private"), "c.txt").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        // Whatever is inside can be deleted, the shared folder itself can't
+        let delete = |path: &str| {
+            delete_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                Extension(guest.clone()),
+                Path(path.to_string()),
+                Query(HashMap::from([("user".to_string(), "testuser".to_string())])),
+            )
+        };
+        assert_eq!(delete("shared").await.unwrap_err(), StatusCode::FORBIDDEN);
+        delete("shared/b.txt").await.unwrap();
+        assert!(!home.get_absolute_path("/shared/b.txt").exists());
+
+        // The grantee's sync feed carries the shared folder's changes
+        let Json(response) = fresh(sync_files(
+            State(filesystem.clone()),
+            State(database.clone()),
+            State(SyncPageLimit(1000)),
+            Extension(guest.clone()),
+            Query(HashMap::new()),
+            HeaderMap::new(),
+            Json(SyncRequest {
+                folders: Vec::new(),
+                last_sync: Some(Utc::now() - chrono::Duration::hours(1)),
+                sync_token: None,
+                limit: None,
+            }),
+        ).await.unwrap());
+        let changes = response.data.unwrap().changes;
+        assert!(changes.iter().any(|change| change.path == "/shared/a.txt"
+            && change.shared_by.as_deref() == Some("testuser")));
+        assert!(!changes.iter().any(|change| change.path.starts_with("/private")));
+
+        // Revoking takes the access away
+        unshare_with_user(
+            State(database.clone()),
+            Extension(owner.clone()),
+            Path((folders["shared"].to_string(), "friend".to_string())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(list(guest.clone(), at("/shared")).await.unwrap_err().status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_favorites_are_starred_per_user() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, owner_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024)
+            .unwrap()
+            .with_user_homes(true);
+
+        let friend = User {
+            id: Uuid::new_v4(),
+            username: "friend".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string(), "write".to_string()],
+        };
+        database.create_user(&friend).await.unwrap();
+
+        let claims = |id: Uuid, username: &str| test_claims(id, username, fixture_permissions(username));
+        let owner = claims(owner_id, "testuser");
+        let guest = claims(friend.id, "friend");
+        let star = |claims: Claims, file_id: Uuid| {
+            add_favorite(State(database.clone()), Extension(claims), Path(file_id.to_string()))
+        };
+        let starred = |claims: Claims| {
+            let database = database.clone();
+            async move {
+                let Json(response) = list_favorites(State(database), Extension(claims)).await.unwrap();
+                response.data.unwrap().into_iter().map(|file| file.name).collect::<Vec<_>>()
+            }
+        };
+        let listed = |claims: Claims, params: HashMap<String, String>| {
+            let list = list_files(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                test_pages(),
+                Extension(claims),
+                None,
+                Query(params),
+                HeaderMap::new(),
+            );
+            async move {
+                let Json(response) = fresh(list.await.unwrap());
+                response.data.unwrap().items.into_iter()
+                    .map(|listed| (listed.file.name, listed.is_favorite))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let Json(response) = create_folder(
+            State(filesystem.clone()),
+            State(database.clone()),
+            Extension(owner.clone()),
+            Query(HashMap::new()),
+            Json(CreateFolderRequest { path: "/".to_string(), name: "shared".to_string() }),
+        )
+        .await
+        .unwrap();
+        let folder_id = response.data.unwrap().id;
+        let mut ids = HashMap::new();
+        for name in ["a.txt", "b.txt"] {
+            let Json(response) = upload_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+                Extension(owner.clone()),
+                Query(HashMap::from([("path".to_string(), "/shared".to_string())])),
+                HeaderMap::new(),
+                multipart_upload(name, "data", None).await,
+            )
+            .await
+            .unwrap();
+            ids.insert(name, response.data.unwrap().file_id);
+        }
+
+        // Only files the caller can read can be starred
+        assert_eq!(star(guest.clone(), ids["a.txt"]).await.unwrap_err(), StatusCode::FORBIDDEN);
+        share_with_user(
+            State(database.clone()),
+            Extension(owner.clone()),
+            Path(folder_id.to_string()),
+            Json(ShareWithRequest { username: "friend".to_string(), permissions: SharePermissions::Read }),
+        )
+        .await
+        .unwrap();
+
+        star(owner.clone(), ids["a.txt"]).await.unwrap();
+        star(owner.clone(), ids["a.txt"]).await.unwrap();
+        star(guest.clone(), ids["b.txt"]).await.unwrap();
+
+        // Each sees their own stars, the friend's on the owner's files too
+        let own = HashMap::from([("path".to_string(), "/shared".to_string())]);
+        assert_eq!(listed(owner.clone(), own).await, vec![("a.txt".to_string(), true), ("b.txt".to_string(), false)]);
+        let theirs = HashMap::from([
+            ("user".to_string(), "testuser".to_string()),
+            ("path".to_string(), "/shared".to_string()),
+        ]);
+        assert_eq!(listed(guest.clone(), theirs).await, vec![("a.txt".to_string(), false), ("b.txt".to_string(), true)]);
+        assert_eq!(starred(owner.clone()).await, vec!["a.txt"]);
+        assert_eq!(starred(guest.clone()).await, vec!["b.txt"]);
+
+        // Trashed files and files no longer shared drop out
+        delete_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(owner.clone()),
+            Path("shared/a.txt".to_string()),
+            Query(HashMap::new()),
+        ).await.unwrap();
+        assert!(starred(owner.clone()).await.is_empty());
+        unshare_with_user(
+            State(database.clone()),
+            Extension(owner.clone()),
+            Path((folder_id.to_string(), "friend".to_string())),
+        ).await.unwrap();
+        assert!(starred(guest.clone()).await.is_empty());
+
+        // Unstarring needs no access
+        remove_favorite(State(database.clone()), Extension(guest), Path(ids["b.txt"].to_string())).await.unwrap();
+        assert!(database.get_favorite_ids(friend.id, &[ids["b.txt"]]).await.unwrap().is_empty());
+        assert_eq!(database.get_favorite_ids(owner_id, &[ids["a.txt"]]).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_comments_follow_their_file_until_it_is_purged() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, owner_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024)
+            .unwrap()
+            .with_user_homes(true);
+
+        let mut users = HashMap::new();
+        for username in ["reader", "editor"] {
+            let user = User {
+                id: Uuid::new_v4(),
+                username: username.to_string(),
+                email: None,
+                password_hash: "hash".to_string(),
+                created_at: Utc::now(),
+                last_login: None,
+                is_active: true,
+                auth_generation: 0,
+                permissions: vec!["read".to_string(), "write".to_string()],
+            };
+            database.create_user(&user).await.unwrap();
+            users.insert(username, user.id);
+        }
+
+        let claims = |id: Uuid, username: &str| test_claims(id, username, fixture_permissions(username));
+        let owner = claims(owner_id, "testuser");
+        let reader = claims(users["reader"], "reader");
+        let editor = claims(users["editor"], "editor");
+
+        let Json(response) = create_folder(
+            State(filesystem.clone()),
+            State(database.clone()),
+            Extension(owner.clone()),
+            Query(HashMap::new()),
+            Json(CreateFolderRequest { path: "/".to_string(), name: "shared".to_string() }),
+        )
+        .await
+        .unwrap();
+        let folder_id = response.data.unwrap().id;
+        let Json(response) = upload_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            State(ConflictSettings::new(&SyncSettings::default()).unwrap()),
+            Extension(owner.clone()),
+            Query(HashMap::from([("path".to_string(), "/shared".to_string())])),
+            HeaderMap::new(),
+            multipart_upload("plan.txt", "v2", None).await,
+        )
+        .await
+        .unwrap();
+        let file_id = response.data.unwrap().file_id.to_string();
+
+        let comments = |claims: Claims| {
+            let list = list_comments(State(database.clone()), Extension(claims), Path(file_id.clone()));
+            async move {
+                list.await.map(|Json(response)| {
+                    response.data.unwrap().into_iter().map(|comment| (comment.author.unwrap_or_default(), comment.body)).collect::<Vec<_>>()
+                })
+            }
+        };
+        let comment = |claims: Claims, body: &str| add_comment(
+            State(database.clone()),
+            State(Webhooks::new(&WebhookSettings::default(), database.clone())),
+            Extension(claims),
+            Path(file_id.clone()),
+            Json(CommentRequest { body: body.to_string() }),
+        );
+        let edit = |claims: Claims, comment_id: Uuid, body: &str| edit_comment(
+            State(database.clone()),
+            Extension(claims),
+            Path((file_id.clone(), comment_id.to_string())),
+            Json(CommentRequest { body: body.to_string() }),
+        );
+        let remove = |claims: Claims, comment_id: Uuid| delete_comment(
+            State(database.clone()),
+            Extension(claims),
+            Path((file_id.clone(), comment_id.to_string())),
+        );
+
+        // Reading needs the file shared, commenting write access to it
+        assert_eq!(comments(reader.clone()).await.unwrap_err().status, StatusCode::FORBIDDEN);
+        for (username, permissions) in [("reader", SharePermissions::Read), ("editor", SharePermissions::ReadWrite)] {
+            share_with_user(
+                State(database.clone()),
+                Extension(owner.clone()),
+                Path(folder_id.to_string()),
+                Json(ShareWithRequest { username: username.to_string(), permissions }),
+            )
+            .await
+            .unwrap();
+        }
+        assert!(comments(reader.clone()).await.unwrap().is_empty());
+        assert_eq!(comment(reader.clone(), "looks fine").await.unwrap_err().status, StatusCode::FORBIDDEN);
+        assert_eq!(comment(owner.clone(), "   ").await.unwrap_err().status, StatusCode::BAD_REQUEST);
+
+        let Json(response) = comment(owner.clone(), " please review v2 ").await.unwrap();
+        let request = response.data.unwrap();
+        let Json(response) = comment(editor.clone(), "on it").await.unwrap();
+        let reply = response.data.unwrap();
+        assert_eq!(
+            comments(reader.clone()).await.unwrap(),
+            vec![("testuser".to_string(), "please review v2".to_string()), ("editor".to_string(), "on it".to_string())]
+        );
+
+        // Only authors edit; authors and writers delete
+        assert_eq!(edit(editor.clone(), request.id, "reviewed").await.unwrap_err().status, StatusCode::FORBIDDEN);
+        let Json(response) = edit(editor.clone(), reply.id, "done, see v3").await.unwrap();
+        assert!(response.data.unwrap().edited_at.is_some());
+        assert_eq!(remove(reader.clone(), reply.id).await.unwrap_err().status, StatusCode::FORBIDDEN);
+        remove(owner.clone(), reply.id).await.unwrap();
+        assert_eq!(remove(owner.clone(), reply.id).await.unwrap_err().status, StatusCode::NOT_FOUND);
+
+        // Renaming keeps them, and the owner's feed shows them when asked
+        move_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(owner.clone()),
+            Query(HashMap::new()),
+            Json(MoveRequest { from: "/shared/plan.txt".to_string(), to: "/shared/plan-v2.txt".to_string(), overwrite: None }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(comments(reader.clone()).await.unwrap().len(), 1);
+        let activity = |include: Option<&str>| {
+            let params = include.map(|include| HashMap::from([("include".to_string(), include.to_string())])).unwrap_or_default();
+            let activity = get_activity(State(database.clone()), test_pages(), Extension(owner.clone()), Query(params));
+            async move {
+                let Json(response) = activity.await.unwrap();
+                response.data.unwrap().items.into_iter().map(|group| group.kind).collect::<Vec<_>>()
+            }
+        };
+        assert!(!activity(None).await.contains(&ActivityKind::Commented));
+        assert!(activity(Some("comments")).await.contains(&ActivityKind::Commented));
+
+        // Trashing keeps them for a restore, purging removes them
+        delete_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(owner.clone()),
+            Path("shared/plan-v2.txt".to_string()),
+            Query(HashMap::new()),
+        ).await.unwrap();
+        let file_uuid = Uuid::parse_str(&file_id).unwrap();
+        assert_eq!(database.get_comments(file_uuid).await.unwrap().len(), 1);
+        let entry = database.list_trash_entries(owner_id).await.unwrap().remove(0);
+        purge_trash_entry(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(owner.clone()),
+            Path(entry.id.to_string()),
+        ).await.unwrap();
+        assert!(database.get_comments(file_uuid).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tags_filter_search_and_rename_everywhere() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write, Permission::Delete]);
+        let mut ids = HashMap::new();
+        for path in ["/scans/a.pdf", "/scans/b.pdf", "/notes.txt"] {
+            filesystem.save_file(path, b"scan").await.unwrap();
+            ids.insert(path, database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap().id);
+        }
+        let tag = |path: &str, name: &str| {
+            add_file_tag(
+                State(database.clone()),
+                Extension(claims.clone()),
+                Path(ids[path].to_string()),
+                Json(TagRequest { name: name.to_string() }),
+            )
+        };
+        let tags = || {
+            let database = database.clone();
+            let claims = claims.clone();
+            async move {
+                let Json(response) = list_tags(State(database), Extension(claims)).await.unwrap();
+                response.data.unwrap().into_iter().map(|tag| (tag.name, tag.files)).collect::<Vec<_>>()
+            }
+        };
+        let search = |params: &[(&str, &str)]| {
+            let params = params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+            let search = search_files(
+                State(filesystem.clone()),
+                State(database.clone()),
+                test_pages(),
+                Extension(claims.clone()),
+                Query(params),
+            );
+            async move {
+                let Json(response) = search.await.unwrap();
+                let mut names: Vec<String> = response.data.unwrap().items.into_iter().map(|file| file.name).collect();
+                names.sort();
+                names
+            }
+        };
+
+        // Names are trimmed and lowercased
+        let Json(response) = tag("/scans/a.pdf", "  Taxes-2024 ").await.unwrap();
+        assert_eq!(response.data.unwrap(), vec!["taxes-2024"]);
+        tag("/scans/a.pdf", "warranty").await.unwrap();
+        tag("/scans/b.pdf", "Warranty").await.unwrap();
+        tag("/notes.txt", "taxes-2024").await.unwrap();
+        for bad in ["   ", &"x".repeat(MAX_TAG_LENGTH + 1)] {
+            assert_eq!(tag("/notes.txt", bad).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(tags().await, vec![("taxes-2024".to_string(), 2), ("warranty".to_string(), 2)]);
+
+        // The tag filter goes with the others
+        assert_eq!(search(&[("tag", "warranty")]).await, vec!["a.pdf", "b.pdf"]);
+        assert_eq!(search(&[("tag", "taxes-2024"), ("path_prefix", "/scans")]).await, vec!["a.pdf"]);
+        assert_eq!(search(&[("tag", "TAXES-2024"), ("q", "notes")]).await, vec!["notes.txt"]);
+        assert!(search(&[("tag", "unknown")]).await.is_empty());
+
+        // Listings carry tags only when asked
+        let list = |include: Option<&str>| {
+            let mut params = HashMap::from([("path".to_string(), "/scans".to_string())]);
+            if let Some(include) = include {
+                params.insert("include".to_string(), include.to_string());
+            }
+            let list = list_files(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                test_pages(),
+                Extension(claims.clone()),
+                None,
+                Query(params),
+                HeaderMap::new(),
+            );
+            async move {
+                let Json(response) = fresh(list.await.unwrap());
+                response.data.unwrap().items.into_iter().map(|listed| listed.tags).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(list(None).await, vec![None, None]);
+        assert_eq!(list(Some("tags")).await, vec![
+            Some(vec!["taxes-2024".to_string(), "warranty".to_string()]),
+            Some(vec!["warranty".to_string()]),
+        ]);
+
+        // A rename reaches every tagged file, and onto an existing tag merges
+        let rename = |from: &str, to: &str| {
+            rename_tag(
+                State(database.clone()),
+                Extension(claims.clone()),
+                Path(from.to_string()),
+                Json(TagRequest { name: to.to_string() }),
+            )
+        };
+        rename("warranty", "Receipts").await.unwrap();
+        assert_eq!(tags().await, vec![("receipts".to_string(), 2), ("taxes-2024".to_string(), 2)]);
+        rename("taxes-2024", "receipts").await.unwrap();
+        assert_eq!(tags().await, vec![("receipts".to_string(), 3)]);
+        assert_eq!(rename("taxes-2024", "other").await.unwrap_err().status, StatusCode::NOT_FOUND);
+
+        remove_file_tag(
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path((ids["/notes.txt"].to_string(), "Receipts".to_string())),
+        ).await.unwrap();
+        assert_eq!(tags().await, vec![("receipts".to_string(), 2)]);
+
+        // Trashed files stop counting, and purged ones lose their tags
+        delete_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path("scans/a.pdf".to_string()),
+            Query(HashMap::new()),
+        ).await.unwrap();
+        assert_eq!(tags().await, vec![("receipts".to_string(), 1)]);
+        let entry = database.list_trash_entries(user_id).await.unwrap().remove(0);
+        purge_trash_entry(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path(entry.id.to_string()),
+        ).await.unwrap();
+        assert!(database.get_file_tags(user_id, &[ids["/scans/a.pdf"]]).await.unwrap().is_empty());
+        assert_eq!(tags().await, vec![("receipts".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_tokens_rotate_and_die_with_password() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let auth_service = AuthService::new("test_secret");
+        let password_hash = auth_service.hash_password("old-password").unwrap();
+        database.update_password_hash(user_id, &password_hash, false, Utc::now()).await.unwrap();
+
+        let login_request = |password: &str| LoginRequest {
+            username: "testuser".to_string(),
+            password: password.to_string(),
+            device_id: Some("laptop".to_string()),
+            device_name: None,
+            totp_code: None,
+            client_version: None,
+        };
+        let refresh_request = |token: &str, rotate: bool| RefreshRequest {
+            refresh_token: token.to_string(),
+            rotate,
+        };
+
+        let Json(response) = login(
+            State(auth_service.clone()),
+            State(TwoFactorService::new("test_secret")),
+            State(test_limiter()),
+            State(database.clone()),
+            State(test_mycloud(false)),
+            test_client(),
+            HeaderMap::new(),
+            Json(login_request("old-password")),
+        ).await.unwrap();
+        let first = response.data.unwrap().refresh_token;
+
+        // Without rotation the same refresh token keeps working
+        let Json(response) = refresh_access_token(
+            State(auth_service.clone()),
+            State(database.clone()),
+            test_client(),
+            HeaderMap::new(),
+            Json(refresh_request(&first, false)),
+        ).await.unwrap();
+        let refreshed = response.data.unwrap();
+        assert_eq!(refreshed.refresh_token, first);
+        let claims = auth_service.verify_token(&refreshed.token).unwrap();
+        assert_eq!(claims.device_id.as_deref(), Some("laptop"));
+
+        // Rotation retires the old token
+        let Json(response) = refresh_access_token(
+            State(auth_service.clone()),
+            State(database.clone()),
+            test_client(),
+            HeaderMap::new(),
+            Json(refresh_request(&first, true)),
+        ).await.unwrap();
+        let second = response.data.unwrap().refresh_token;
+        assert_ne!(second, first);
+
+        let err = refresh_access_token(
+            State(auth_service.clone()),
+            State(database.clone()),
+            test_client(),
+            HeaderMap::new(),
+            Json(refresh_request(&first, false)),
+        ).await.unwrap_err();
+        assert_eq!(err, StatusCode::UNAUTHORIZED);
+
+        // Changing the password revokes every refresh token
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let err = change_password(
+            State(auth_service.clone()),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Json(ChangePasswordRequest {
+                current_password: "wrong".to_string(),
+                new_password: "new-password".to_string(),
+            }),
+        ).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        change_password(
+            State(auth_service.clone()),
+            State(database.clone()),
+            Extension(claims),
+            Json(ChangePasswordRequest {
+                current_password: "old-password".to_string(),
+                new_password: "new-password".to_string(),
+            }),
+        ).await.unwrap();
+
+        let err = refresh_access_token(
+            State(auth_service.clone()),
+            State(database.clone()),
+            test_client(),
+            HeaderMap::new(),
+            Json(refresh_request(&second, false)),
+        ).await.unwrap_err();
+        assert_eq!(err, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_password_reset_forces_a_change() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let auth_service = AuthService::new("test_secret");
+
+        let admin = User {
+            id: Uuid::new_v4(),
+            username: "admin".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["admin".to_string()],
+        };
+        database.create_user(&admin).await.unwrap();
+
+        let claims = |id: Uuid, username: &str| test_claims(id, username, fixture_permissions(username));
+        let reset = |claims: Claims, password: &str| {
+            reset_user_password(
+                State(auth_service.clone()),
+                State(database.clone()),
+                Extension(claims),
+                Path(user_id.to_string()),
+                Json(ResetPasswordRequest { new_password: password.to_string() }),
+            )
+        };
+        let login_as_user = |password: &str| {
+            login(
+                State(auth_service.clone()),
+                State(TwoFactorService::new("test_secret")),
+                State(test_limiter()),
+                State(database.clone()),
+                State(test_mycloud(false)),
+                test_client(),
+                HeaderMap::new(),
+                Json(LoginRequest {
+                    username: "testuser".to_string(),
+                    password: password.to_string(),
+                    device_id: None,
+                    device_name: None,
+                    totp_code: None,
+                    client_version: None,
+                }),
+            )
+        };
+
+        // Only admins may reset, and the new password must be long enough
+        let err = reset(claims(user_id, "testuser"), "temporary-password").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        let err = reset(claims(admin.id, "admin"), "short").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        reset(claims(admin.id, "admin"), "temporary-password").await.unwrap();
+        let Json(response) = login_as_user("temporary-password").await.unwrap();
+        assert!(response.data.unwrap().must_change_password);
+
+        // A password the policy rejects comes back with every rule it breaks
+        let err = change_password(
+            State(auth_service.clone()),
+            State(database.clone()),
+            Extension(claims(user_id, "testuser")),
+            Json(ChangePasswordRequest {
+                current_password: "temporary-password".to_string(),
+                new_password: "PASSWORD".to_string(),
+            }),
+        ).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert_eq!(err.data.unwrap()["violations"], serde_json::json!([{ "rule": "common" }]));
+
+        change_password(
+            State(auth_service.clone()),
+            State(database.clone()),
+            Extension(claims(user_id, "testuser")),
+            Json(ChangePasswordRequest {
+                current_password: "temporary-password".to_string(),
+                new_password: "my-own-password".to_string(),
+            }),
+        ).await.unwrap();
+
+        let Json(response) = login_as_user("my-own-password").await.unwrap();
+        assert!(!response.data.unwrap().must_change_password);
+    }
+
+    #[tokio::test]
+    async fn test_two_factor_login() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let auth_service = AuthService::new("test_secret");
+        let two_factor_service = TwoFactorService::new("test_secret");
+        let password_hash = auth_service.hash_password("password").unwrap();
+        database.update_password_hash(user_id, &password_hash, false, Utc::now()).await.unwrap();
+
+        let admin = User {
+            id: Uuid::new_v4(),
+            username: "admin".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["admin".to_string()],
+        };
+        database.create_user(&admin).await.unwrap();
+
+        let claims = |id: Uuid, username: &str| test_claims(id, username, fixture_permissions(username));
+        let login_with = |totp_code: Option<String>| {
+            login(
+                State(auth_service.clone()),
+                State(two_factor_service.clone()),
+                State(test_limiter()),
+                State(database.clone()),
+                State(test_mycloud(false)),
+                test_client(),
+                HeaderMap::new(),
+                Json(LoginRequest {
+                    username: "testuser".to_string(),
+                    password: "password".to_string(),
+                    device_id: None,
+                    device_name: None,
+                    totp_code,
+                    client_version: None,
+                }),
+            )
+        };
+
+        let Json(response) = setup_two_factor(
+            State(two_factor_service.clone()),
+            State(database.clone()),
+            Extension(claims(user_id, "testuser")),
+        ).await.unwrap();
+        assert!(response.data.unwrap().otpauth_uri.starts_with("otpauth://totp/"));
+
+        // Not enforced until a code confirms the setup
+        login_with(None).await.unwrap();
+
+        let secret = database.get_two_factor(user_id).await.unwrap().secret.unwrap();
+        let code = two_factor::current_code(&two_factor_service, &secret, "testuser");
+        let Json(response) = verify_two_factor(
+            State(two_factor_service.clone()),
+            State(database.clone()),
+            Extension(claims(user_id, "testuser")),
+            Json(TwoFactorVerifyRequest { code: code.clone() }),
+        ).await.unwrap();
+        let recovery_codes = response.data.unwrap().recovery_codes;
+
+        let err = login_with(None).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(err.data, Some(json!({ "requires_2fa": true })));
+        let err = login_with(Some("000000".to_string())).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+        login_with(Some(code)).await.unwrap();
+
+        // Recovery codes work once each
+        login_with(Some(recovery_codes[0].clone())).await.unwrap();
+        let err = login_with(Some(recovery_codes[0].clone())).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+
+        // An admin can switch it off for a locked-out user
+        let err = disable_user_two_factor(
+            State(database.clone()),
+            Extension(claims(user_id, "testuser")),
+            Path(user_id.to_string()),
+        ).await.unwrap_err();
+        assert_eq!(err, StatusCode::FORBIDDEN);
+        disable_user_two_factor(
+            State(database.clone()),
+            Extension(claims(admin.id, "admin")),
+            Path(user_id.to_string()),
+        ).await.unwrap();
+        login_with(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_api_tokens_cannot_manage_the_account() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+
+        let claims = |scope: Option<TokenScope>| Claims {
+            scope,
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write])
+        };
+
+        // What tokens may do with files is checked per route, see the server tests
+        let err = list_api_tokens(
+            State(database.clone()),
+            Extension(claims(Some(TokenScope::ReadWrite))),
+        ).await.unwrap_err();
+        assert_eq!(err, StatusCode::FORBIDDEN);
+        list_api_tokens(State(database.clone()), Extension(claims(None))).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_app_passwords_are_never_accepted_by_login() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let auth_service = AuthService::new("test_secret").with_bcrypt_cost(4);
+        let password_hash = auth_service.hash_password("password").unwrap();
+        database.update_password_hash(user_id, &password_hash, false, Utc::now()).await.unwrap();
+
+        let claims = |scope: Option<TokenScope>| Claims {
+            scope,
+            ..test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write])
+        };
+        let create = |request: CreateAppPasswordRequest| {
+            create_app_password(State(auth_service.clone()), State(database.clone()), Extension(claims(None)), Json(request))
+        };
+
+        let Json(response) = create(CreateAppPasswordRequest {
+            label: " DAV on my phone ".to_string(),
+            path_prefix: Some("/photos".to_string()),
+            expires_in_days: Some(30),
+        }).await.unwrap();
+        let created = response.data.unwrap();
+        assert_eq!(created.app_password.label, "DAV on my phone");
+        assert_eq!(created.app_password.path_prefix.as_deref(), Some("/photos"));
+        assert!(created.password.len() >= 20);
+
+        for (label, path_prefix, expires_in_days) in [("", None, None), ("dav", Some("photos"), None), ("dav", None, Some(0)), ("dav", None, Some(i64::MAX))] {
+            let err = create(CreateAppPasswordRequest {
+                label: label.to_string(),
+                path_prefix: path_prefix.map(str::to_string),
+                expires_in_days,
+            }).await.unwrap_err();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        }
+
+        // Listed without the password or its hash
+        let Json(response) = list_app_passwords(State(database.clone()), Extension(claims(None))).await.unwrap();
+        let listed = serde_json::to_value(response.data.unwrap()).unwrap();
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        assert!(listed[0].get("password").is_none() && listed[0].get("password_hash").is_none());
+
+        // Logging in takes the account password only
+        let login_with = |password: String| {
+            login(
+                State(auth_service.clone()),
+                State(TwoFactorService::new("test_secret")),
+                State(test_limiter()),
+                State(database.clone()),
+                State(test_mycloud(false)),
+                test_client(),
+                HeaderMap::new(),
+                Json(LoginRequest {
+                    username: "testuser".to_string(),
+                    password,
+                    device_id: None,
+                    device_name: None,
+                    totp_code: None,
+                    client_version: None,
+                }),
+            )
+        };
+        let Json(response) = login_with(created.password.clone()).await.unwrap();
+        assert!(!response.success);
+        let Json(response) = login_with("password".to_string()).await.unwrap();
+        assert!(response.success);
+
+        // Managed only with a login, like access tokens
+        let err = list_app_passwords(State(database.clone()), Extension(claims(Some(TokenScope::ReadWrite)))).await.unwrap_err();
+        assert_eq!(err, StatusCode::FORBIDDEN);
+
+        let id = created.app_password.id.to_string();
+        delete_app_password(State(database.clone()), Extension(claims(None)), Path(id.clone())).await.unwrap();
+        let err = delete_app_password(State(database.clone()), Extension(claims(None)), Path(id)).await.unwrap_err();
+        assert_eq!(err, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_scoped_tokens_stay_in_their_folders() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+        let auth_service = AuthService::new("test_secret");
+
+        filesystem.save_file("/public/a.txt", b"a").await.unwrap();
+        filesystem.save_file("/private/b.txt", b"b").await.unwrap();
+        for path in ["/public/a.txt", "/private/b.txt"] {
+            database.resolve_file_metadata(&filesystem, user_id, path).await.unwrap();
+        }
+
+        let login = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+        let mint = |scopes: Vec<PathScope>| {
+            create_scoped_token(
+                State(auth_service.clone()),
+                State(database.clone()),
+                Extension(login.clone()),
+                Json(CreateScopedTokenRequest { name: "kiosk".to_string(), scopes, expires_in_days: None }),
+            )
+        };
+        let scope = |action: Action, path_prefix: &str| PathScope { action, path_prefix: path_prefix.to_string() };
+
+        // Prefixes have to be absolute and stay put
+        assert_eq!(mint(Vec::new()).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+        let err = mint(vec![scope(Action::Read, "/public/../private")]).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        let err = mint(vec![scope(Action::Read, "public")]).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        // Lifetimes are capped rather than overflowing the clock
+        for expires_in_days in [Some(0), Some(MAX_EXPIRY_DAYS + 1), Some(i64::MAX)] {
+            let err = create_scoped_token(
+                State(auth_service.clone()),
+                State(database.clone()),
+                Extension(login.clone()),
+                Json(CreateScopedTokenRequest {
+                    name: "kiosk".to_string(),
+                    scopes: vec![scope(Action::Read, "/public")],
+                    expires_in_days,
+                }),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        }
+
+        let Json(response) = mint(vec![scope(Action::Read, "/public")]).await.unwrap();
+        let minted = response.data.unwrap();
+        assert_eq!(minted.token.scope, TokenScope::ReadOnly);
+        let claims = auth_service.verify_api_token(&database, &minted.secret).await.unwrap();
+        assert_eq!(claims.scopes, vec![scope(Action::Read, "/public")]);
+
+        let list = |path: &str| {
+            let list = list_files(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                test_pages(),
+                Extension(claims.clone()),
+                None,
+                Query(HashMap::from([("path".to_string(), path.to_string())])),
+                HeaderMap::new(),
+            );
+            async move { list.await.map(fresh) }
+        };
+        let download = |path: &str| {
+            download_file(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(database.clone()),
+                Extension(claims.clone()),
+                None,
+                Path(path.to_string()),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+            )
+        };
+
+        assert!(list("/public").await.is_ok());
+        assert!(download("public/a.txt").await.is_ok());
+        for path in ["/", "/private", "/public/../private", "/public-old"] {
+            assert_eq!(list(path).await.unwrap_err().status, StatusCode::FORBIDDEN, "{}", path);
+        }
+        assert_eq!(download("private/b.txt").await.unwrap_err(), StatusCode::FORBIDDEN);
+        assert_eq!(download("public/..%2Fprivate/b.txt").await.unwrap_err(), StatusCode::FORBIDDEN);
+
+        // Reading doesn't extend to deleting
+        let err = delete_file(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path("public/a.txt".to_string()),
+            Query(HashMap::new()),
+        ).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        assert!(filesystem.get_absolute_path("/public/a.txt").exists());
+
+        // Sync only reports what lies in the scopes
+        let Json(response) = fresh(sync_files(
+            State(filesystem.clone()),
+            State(database.clone()),
+            State(SyncPageLimit(1000)),
+            Extension(claims.clone()),
+            Query(HashMap::new()),
+            HeaderMap::new(),
+            Json(SyncRequest {
+                folders: Vec::new(),
+                last_sync: Some(Utc::now() - chrono::Duration::hours(1)),
+                sync_token: None,
+                limit: None,
+            }),
+        ).await.unwrap());
+        let paths: Vec<String> = response.data.unwrap().changes.into_iter().map(|change| change.path).collect();
+        assert!(paths.contains(&"/public/a.txt".to_string()));
+        assert!(paths.iter().all(|path| path.starts_with("/public")), "{:?}", paths);
+
+        // Tokens without scopes reach everything as before
+        let Json(response) = fresh(list_files(
+            State(filesystem.clone()),
+            local_storage(&filesystem),
+            State(database.clone()),
+            test_pages(),
+            Extension(login.clone()),
+            None,
+            Query(HashMap::from([("path".to_string(), "/private".to_string())])),
+            HeaderMap::new(),
+        ).await.unwrap());
+        assert_eq!(response.data.unwrap().items.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_logins_lock_the_username() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let auth_service = AuthService::new("test_secret").with_bcrypt_cost(4);
+        let password_hash = auth_service.hash_password("password").unwrap();
+        database.update_password_hash(user_id, &password_hash, false, Utc::now()).await.unwrap();
+
+        let login_with = |limiter: LoginLimiter, password: &str| {
+            login(
+                State(auth_service.clone()),
+                State(TwoFactorService::new("test_secret")),
+                State(limiter),
+                State(database.clone()),
+                State(test_mycloud(false)),
+                test_client(),
+                HeaderMap::new(),
+                Json(LoginRequest {
+                    username: "testuser".to_string(),
+                    password: password.to_string(),
+                    device_id: None,
+                    device_name: None,
+                    totp_code: None,
+                    client_version: None,
+                }),
+            )
+        };
+
+        // Too many attempts in a minute are turned away before the password is looked at
+        let mut settings = crate::config::ServerConfig::default().auth;
+        settings.login_attempts_per_minute_per_user = 2;
+        let strict = LoginLimiter::new(&settings);
+        login_with(strict.clone(), "password").await.unwrap();
+        login_with(strict.clone(), "password").await.unwrap();
+        let err = login_with(strict, "password").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(err.retry_after.is_some_and(|seconds| seconds <= 30));
+
+        // A run of failures locks the username, even for the right password
+        let limiter = test_limiter();
+        for _ in 0..5 {
+            let Json(response) = login_with(limiter.clone(), "wrong").await.unwrap();
+            assert!(!response.success);
+        }
+        let err = login_with(limiter.clone(), "password").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(err.retry_after.is_some_and(|seconds| seconds <= 30));
+
+        let attempts = database.list_login_attempts(Some("testuser"), None, 10, 0).await.unwrap();
+        assert_eq!(attempts[0].outcome, "locked");
+        assert_eq!(attempts[1].outcome, "failure");
+
+        // The lock lifts on its own, and a success clears the count
+        let (failures, _) = database.get_consecutive_login_failures("testuser", limiter.failures_since()).await.unwrap();
+        assert_eq!(failures, 5);
+        let long_ago = Utc::now() - chrono::Duration::minutes(10);
+        assert!(limiter.locked_until(failures, Some(long_ago)).is_none());
+
+        // Failures from before the window are forgotten
+        let (failures, _) = database.get_consecutive_login_failures("testuser", Utc::now()).await.unwrap();
+        assert_eq!(failures, 0);
+    }
+
+    #[tokio::test]
+    async fn test_logins_and_refreshes_are_audited() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let auth_service = AuthService::new("test_secret").with_bcrypt_cost(4);
+        let password_hash = auth_service.hash_password("password").unwrap();
+        database.update_password_hash(user_id, &password_hash, false, Utc::now()).await.unwrap();
+
+        let admin = User {
+            id: Uuid::new_v4(),
+            username: "admin".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["admin".to_string()],
+        };
+        database.create_user(&admin).await.unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, "synker-desktop/2.1".parse().unwrap());
+        let login_with = |password: &str| {
+            login(
+                State(auth_service.clone()),
+                State(TwoFactorService::new("test_secret")),
+                State(test_limiter()),
+                State(database.clone()),
+                State(test_mycloud(false)),
+                test_client(),
+                headers.clone(),
+                Json(LoginRequest {
+                    username: "testuser".to_string(),
+                    password: password.to_string(),
+                    device_id: None,
+                    device_name: None,
+                    totp_code: None,
+                    client_version: None,
+                }),
+            )
+        };
+        let refresh = |refresh_token: String| {
+            refresh_access_token(
+                State(auth_service.clone()),
+                State(database.clone()),
+                test_client(),
+                headers.clone(),
+                Json(RefreshRequest { refresh_token, rotate: false }),
+            )
+        };
+
+        let Json(response) = login_with("wrong").await.unwrap();
+        assert!(!response.success);
+        let Json(response) = login_with("password").await.unwrap();
+        let refresh_token = response.data.unwrap().refresh_token;
+        refresh(refresh_token).await.unwrap();
+        assert_eq!(refresh("unknown".to_string()).await.unwrap_err(), StatusCode::UNAUTHORIZED);
+
+        let admin_claims = test_claims(admin.id, "admin", vec![Permission::Admin]);
+        let audit = |params: &[(&str, &str)]| {
+            let params = params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+            list_audit_events(State(database.clone()), test_pages(), Extension(admin_claims.clone()), Query(params))
+        };
+
+        // Newest first, with where each came from
+        let Json(response) = audit(&[]).await.unwrap();
+        let page = response.data.unwrap();
+        assert_eq!((page.total, page.next_token), (4, None));
+        let entries = page.items;
+        let outcomes: Vec<_> = entries.iter().map(|entry| (entry.event, entry.outcome.as_str())).collect();
+        assert_eq!(outcomes, vec![
+            (AuditEvent::TokenRefresh, "failure"),
+            (AuditEvent::TokenRefresh, "success"),
+            (AuditEvent::Login, "success"),
+            (AuditEvent::Login, "failure"),
+        ]);
+        assert_eq!(entries[1].user_id, Some(user_id));
+        assert_eq!(entries[3].username.as_deref(), Some("testuser"));
+        assert_eq!(entries[3].ip_address.as_deref(), Some("127.0.0.1"));
+        assert_eq!(entries[3].user_agent.as_deref(), Some("synker-desktop/2.1"));
+
+        // Filtered and a page at a time
+        let Json(response) = audit(&[("event", "login"), ("user", "testuser"), ("limit", "1")]).await.unwrap();
+        let page = response.data.unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.items[0].outcome, "success");
+        let token = page.next_token.unwrap();
+        let Json(response) = audit(&[("event", "login"), ("user", "testuser"), ("limit", "1"), ("page_token", token.as_str())]).await.unwrap();
+        let page = response.data.unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].outcome, "failure");
+        assert!(page.next_token.is_none());
+        let Json(response) = audit(&[("event", "login"), ("user", "testuser"), ("limit", "1"), ("offset", "1")]).await.unwrap();
+        assert_eq!(response.data.unwrap().items[0].outcome, "failure");
+
+        let later = (Utc::now() + chrono::Duration::minutes(1)).to_rfc3339();
+        let Json(response) = audit(&[("since", later.as_str())]).await.unwrap();
+        let page = response.data.unwrap();
+        assert!(page.items.is_empty());
+        assert_eq!(page.total, 0);
+
+        let err = audit(&[("page_token", "not-a-token")]).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        let err = audit(&[("event", "coffee_break")]).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        let err = audit(&[("since", "yesterday")]).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        // Only admins read it
+        let user_claims = Claims { sub: user_id.to_string(), username: "testuser".to_string(), ..admin_claims.clone() };
+        let err = list_audit_events(State(database.clone()), test_pages(), Extension(user_claims), Query(HashMap::new())).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        // Old entries are pruned
+        assert_eq!(database.purge_audit_events_before(Utc::now() + chrono::Duration::seconds(1)).await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_revoked_devices_lose_their_tokens() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let auth_service = AuthService::new("test_secret").with_bcrypt_cost(4);
+        let password_hash = auth_service.hash_password("password").unwrap();
+        database.update_password_hash(user_id, &password_hash, false, Utc::now()).await.unwrap();
+
+        let login_from = |device_id: &str| {
+            login(
+                State(auth_service.clone()),
+                State(TwoFactorService::new("test_secret")),
+                State(test_limiter()),
+                State(database.clone()),
+                State(test_mycloud(false)),
+                test_client(),
+                HeaderMap::new(),
+                Json(LoginRequest {
+                    username: "testuser".to_string(),
+                    password: "password".to_string(),
+                    device_id: Some(device_id.to_string()),
+                    device_name: Some(format!("{} name", device_id)),
+                    totp_code: None,
+                    client_version: Some("1.2.0".to_string()),
+                }),
+            )
+        };
+        let claims = test_claims(user_id, "testuser", vec![Permission::Read, Permission::Write]);
+
+        let Json(laptop) = login_from("laptop").await.unwrap();
+        let Json(phone) = login_from("phone").await.unwrap();
+        let laptop = laptop.data.unwrap();
+        let phone = phone.data.unwrap();
+        database.create_sync_session(&SyncSession {
+            id: Uuid::new_v4(),
+            user_id,
+            device_id: "phone".to_string(),
+            device_name: "phone name".to_string(),
+            last_sync: Utc::now(),
+            sync_folders: vec!["/".to_string()],
+            exclude_patterns: Vec::new(),
+            is_active: true,
+        }).await.unwrap();
+
+        let Json(response) = list_devices(State(database.clone()), Extension(claims.clone())).await.unwrap();
+        let devices = response.data.unwrap();
+        assert_eq!(devices.len(), 2);
+        assert!(devices.iter().all(|device| device.client_version.as_deref() == Some("1.2.0")));
+
+        revoke_device(
+            State(auth_service.clone()),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path("phone".to_string()),
+        ).await.unwrap();
+
+        assert!(auth_service.verify_token(&phone.token).is_err());
+        assert!(auth_service.verify_token(&laptop.token).is_ok());
+        let session = database.get_sync_session(user_id, "phone").await.unwrap().unwrap();
+        assert!(!session.is_active);
+        let err = refresh_access_token(
+            State(auth_service.clone()),
+            State(database.clone()),
+            test_client(),
+            HeaderMap::new(),
+            Json(RefreshRequest { refresh_token: phone.refresh_token, rotate: false }),
+        ).await.unwrap_err();
+        assert_eq!(err, StatusCode::UNAUTHORIZED);
+
+        let Json(response) = list_devices(State(database.clone()), Extension(claims.clone())).await.unwrap();
+        let phone_device = response.data.unwrap().into_iter().find(|device| device.device_id == "phone").unwrap();
+        assert!(phone_device.revoked_at.is_some());
+
+        let err = revoke_device(
+            State(auth_service.clone()),
+            State(database.clone()),
+            Extension(claims),
+            Path("tablet".to_string()),
+        ).await.unwrap_err();
+        assert_eq!(err, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admins_manage_users() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+        let auth_service = AuthService::new("test_secret").with_bcrypt_cost(4);
+
+        let admin = User {
+            id: Uuid::new_v4(),
+            username: "admin".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["admin".to_string()],
+        };
+        database.create_user(&admin).await.unwrap();
+
+        filesystem.save_file("/notes.txt", b"data").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/notes.txt").await.unwrap();
+
+        let claims = |id: Uuid, username: &str| test_claims(id, username, fixture_permissions(username));
+        let create = |claims: Claims, username: &str, password: &str| {
+            create_user(
+                State(auth_service.clone()),
+                State(database.clone()),
+                Extension(claims),
+                Json(CreateUserRequest {
+                    username: username.to_string(),
+                    email: None,
+                    password: password.to_string(),
+                    permissions: vec![Permission::Read, Permission::Write],
+                    quota_bytes: Some(1024),
+                }),
+            )
+        };
+        let update = |target: Uuid, request: UpdateUserRequest| {
+            update_user(
+                State(auth_service.clone()),
+                State(database.clone()),
+                Extension(claims(admin.id, "admin")),
+                Path(target.to_string()),
+                Json(request),
+            )
+        };
+        let remove = |target: Uuid, files: Option<&str>| {
+            delete_user(
+                State(filesystem.clone()),
+                local_storage(&filesystem),
+                State(auth_service.clone()),
+                State(database.clone()),
+                Extension(claims(admin.id, "admin")),
+                Path(target.to_string()),
+                Query(files.map(|files| HashMap::from([("files".to_string(), files.to_string())])).unwrap_or_default()),
+            )
+        };
+
+        // Only admins add users, with a fresh name and a long enough password
+        let err = create(claims(user_id, "testuser"), "alice", "long-enough").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        let err = create(claims(admin.id, "admin"), "testuser", "long-enough").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+        let err = create(claims(admin.id, "admin"), "alice", "short").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        let Json(response) = create(claims(admin.id, "admin"), "alice", "long-enough").await.unwrap();
+        let alice = response.data.unwrap();
+        assert_eq!(alice.permissions, vec!["read", "write"]);
+        assert_eq!(alice.quota_bytes, Some(1024));
+
+        let list = |params: HashMap<String, String>| {
+            list_users(State(database.clone()), test_pages(), Extension(claims(admin.id, "admin")), Query(params))
+        };
+        let Json(response) = list(HashMap::from([("limit".to_string(), "2".to_string())])).await.unwrap();
+        let page = response.data.unwrap();
+        assert_eq!(page.total, 3);
+        let names: Vec<&str> = page.items.iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(names, vec!["admin", "alice"]);
+
+        // The token picks up after the last name handed out
+        let Json(response) = list(HashMap::from([
+            ("limit".to_string(), "2".to_string()),
+            ("page_token".to_string(), page.next_token.unwrap()),
+        ])).await.unwrap();
+        let page = response.data.unwrap();
+        assert_eq!(page.items.iter().map(|user| user.username.as_str()).collect::<Vec<_>>(), vec!["testuser"]);
+        assert!(page.next_token.is_none());
+        let params = HashMap::from([("page_token".to_string(), "not a token".to_string())]);
+        assert_eq!(list(params).await.unwrap_err().status, StatusCode::BAD_REQUEST);
+
+        // Deactivating kills the tokens the user holds and stops logins
+        let user = database.get_user_by_id(user_id).await.unwrap().unwrap();
+        let (token, _) = auth_service.generate_token(&user, None).unwrap();
+        let Json(response) = update(user_id, UpdateUserRequest {
+            is_active: Some(false),
+            quota_bytes: Some(None),
+            ..Default::default()
+        }).await.unwrap();
+        let account = response.data.unwrap();
+        assert!(!account.is_active);
+        assert_eq!(account.permissions, vec!["read", "write"]);
+        assert!(auth_service.verify_token(&token).is_err());
+
+        let err = update(admin.id, UpdateUserRequest { is_active: Some(false), ..Default::default() }).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        // Deleting needs a choice for the files; reassigned ones go to the admin
+        let err = remove(user_id, None).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        let err = remove(admin.id, Some("delete")).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        // Entries the admin has on record too keep the admin's rows
+        filesystem.save_file("/docs/a.txt", b"data").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/docs/a.txt").await.unwrap();
+        let docs = database.resolve_file_metadata(&filesystem, admin.id, "/docs").await.unwrap();
+
+        remove(user_id, Some("reassign")).await.unwrap();
+        assert!(database.get_user_by_id(user_id).await.unwrap().is_none());
+        let notes = database.get_file_metadata_by_path(admin.id, "/notes.txt").await.unwrap().unwrap();
+        assert_eq!(notes.owner_id, admin.id);
+        assert_eq!(database.get_file_metadata_by_path(admin.id, "/docs").await.unwrap().unwrap().id, docs.id);
+        let moved = database.get_file_metadata_by_path(admin.id, "/docs/a.txt").await.unwrap().unwrap();
+        assert_eq!(moved.parent_id, Some(docs.id));
+
+        // Deleted files are gone from storage too
+        filesystem.save_file("/alice.txt", b"data").await.unwrap();
+        database.resolve_file_metadata(&filesystem, alice.id, "/alice.txt").await.unwrap();
+        remove(alice.id, Some("delete")).await.unwrap();
+        assert!(!filesystem.get_absolute_path("/alice.txt").exists());
+        assert!(database.get_file_metadata_by_path(admin.id, "/alice.txt").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_admin_stats_are_counted_then_cached() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let admin = User {
+            id: Uuid::new_v4(),
+            username: "admin".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: Some(Utc::now()),
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["admin".to_string()],
+        };
+        database.create_user(&admin).await.unwrap();
+
+        filesystem.save_file("/docs/notes.txt", b"data").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/docs/notes.txt").await.unwrap();
+
+        let downloads = DownloadCounter::default();
+        downloads.record();
+        downloads.record();
+
+        let claims = |id: Uuid, username: &str| test_claims(id, username, fixture_permissions(username));
+        let stats = |cache: &StatsCache, claims: Claims| {
+            get_admin_stats(
+                State(filesystem.clone()),
+                State(database.clone()),
+                State(downloads.clone()),
+                State(cache.clone()),
+                Extension(claims),
+            )
+        };
+        let cache = StatsCache::new(std::time::Duration::from_secs(60));
+
+        let err = stats(&cache, claims(user_id, "testuser")).await.unwrap_err();
+        assert_eq!(err, StatusCode::FORBIDDEN);
+
+        let Json(response) = stats(&cache, claims(admin.id, "admin")).await.unwrap();
+        let first = response.data.unwrap();
+        assert!(!first.from_cache);
+        assert_eq!((first.totals.users, first.totals.active_users), (2, 1));
+        // The folder is not a file
+        assert_eq!((first.totals.files, first.totals.logical_bytes), (1, 4));
+        assert_eq!(first.totals.uploads_last_day, 1);
+        assert_eq!(first.downloads_last_day, 2);
+        assert!(first.disk.total > 0);
+        assert_eq!(first.approximate, vec!["downloads_last_day"]);
+
+        // Within the cache's lifetime the same figures come back, marked as kept
+        filesystem.save_file("/more.txt", b"more data").await.unwrap();
+        database.resolve_file_metadata(&filesystem, user_id, "/more.txt").await.unwrap();
+        let Json(response) = stats(&cache, claims(admin.id, "admin")).await.unwrap();
+        let cached = response.data.unwrap();
+        assert!(cached.from_cache);
+        assert_eq!(cached.totals.files, 1);
+        assert_eq!(cached.generated_at, first.generated_at);
+
+        let uncached = StatsCache::new(std::time::Duration::ZERO);
+        let Json(response) = stats(&uncached, claims(admin.id, "admin")).await.unwrap();
+        let recounted = response.data.unwrap();
+        assert!(!recounted.from_cache);
+        assert_eq!((recounted.totals.files, recounted.totals.logical_bytes), (2, 13));
+
+        // Storage info gives users their quota only, and admins the kept usage
+        let storage_info = |claims: Claims| {
+            get_storage_info(
+                State(filesystem.clone()),
+                State(Arc::new(filesystem.clone()) as Arc<dyn StorageBackend>),
+                State(database.clone()),
+                State(downloads.clone()),
+                State(cache.clone()),
+                Extension(claims),
+            )
+        };
+        let Json(response) = storage_info(claims(user_id, "testuser")).await.unwrap();
+        let info = response.data.unwrap();
+        assert!(info.disk.is_none() && info.mounts.is_none() && info.usage.is_none());
+        assert_eq!(info.quota.used, 13);
+        let Json(response) = storage_info(claims(admin.id, "admin")).await.unwrap();
+        let info = response.data.unwrap();
+        assert!(info.disk.is_some());
+        assert_eq!(info.usage.unwrap().logical, first.usage.logical);
+    }
+
+    #[tokio::test]
+    async fn test_admins_back_up_the_database() {
+        use sha2::Digest;
+
+        let db_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+
+        let admin = User {
+            id: Uuid::new_v4(),
+            username: "admin".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["admin".to_string()],
+        };
+        database.create_user(&admin).await.unwrap();
+
+        let claims = |id: Uuid, username: &str| test_claims(id, username, fixture_permissions(username));
+        let backup = |backups: &Backups, claims: Claims, request: BackupRequest| {
+            create_backup(State(database.clone()), State(backups.clone()), Extension(claims), Some(Json(request)))
+        };
+        let settings = crate::config::BackupSettings::default();
+        let downloads_only = Backups::new(database.clone(), &settings).with_temp_directory(backup_dir.path().join("temp"));
+
+        let err = backup(&downloads_only, claims(user_id, "testuser"), BackupRequest::default()).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        let err = backup(&downloads_only, claims(admin.id, "admin"), BackupRequest::default()).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+
+        // A download carries its checksum and leaves nothing behind
+        let request = BackupRequest { compress: Some(false), download: true };
+        let response = backup(&downloads_only, claims(admin.id, "admin"), request).await.unwrap();
+        let checksum = response.headers()[CHECKSUM_HEADER].to_str().unwrap().to_string();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.starts_with(b"SQLite format 3\0"));
+        assert_eq!(checksum, format!("{:x}", sha2::Sha256::digest(&body)));
+        assert_eq!(std::fs::read_dir(backup_dir.path().join("temp")).unwrap().count(), 0);
+
+        // Nor does one cut off before the end
+        let request = BackupRequest { compress: Some(false), download: true };
+        drop(backup(&downloads_only, claims(admin.id, "admin"), request).await.unwrap());
+        assert_eq!(std::fs::read_dir(backup_dir.path().join("temp")).unwrap().count(), 0);
+
+        // With a directory, backups are kept there
+        let settings = crate::config::BackupSettings {
+            directory: Some(backup_dir.path().join("kept")),
+            ..settings
+        };
+        let kept = Backups::new(database.clone(), &settings);
+        let response = backup(&kept, claims(admin.id, "admin"), BackupRequest::default()).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let file_name = response["data"]["file_name"].as_str().unwrap();
+        assert!(file_name.ends_with(".db.gz"));
+        assert!(backup_dir.path().join("kept").join(file_name).exists());
+    }
+
+    #[tokio::test]
+    async fn test_admins_run_jobs_on_demand() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+
+        let admin = User {
+            id: Uuid::new_v4(),
+            username: "admin".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["admin".to_string()],
+        };
+        database.create_user(&admin).await.unwrap();
+
+        let claims = |id: Uuid, username: &str| test_claims(id, username, fixture_permissions(username));
+
+        let scheduler = Scheduler::new();
+        let ran = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let job_ran = ran.clone();
+        scheduler.register_deferred("nightly-report", std::time::Duration::from_secs(86400), move || {
+            let ran = job_ran.clone();
+            async move {
+                ran.store(true, std::sync::atomic::Ordering::SeqCst);
+                Ok::<_, anyhow::Error>(())
+            }
+        });
+        let list = |claims: Claims| list_jobs(State(database.clone()), State(scheduler.clone()), Extension(claims));
+        let run = |claims: Claims, name: &str| {
+            run_job(State(database.clone()), State(scheduler.clone()), Extension(claims), Path(name.to_string()))
+        };
+
+        assert_eq!(list(claims(user_id, "testuser")).await.unwrap_err(), StatusCode::FORBIDDEN);
+        let err = run(claims(user_id, "testuser"), "nightly-report").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        let jobs = list(claims(admin.id, "admin")).await.unwrap().0.data.unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].name, "nightly-report");
+        assert_eq!(jobs[0].interval_seconds, 86400);
+        assert_eq!(jobs[0].runs, 0);
+
+        let err = run(claims(admin.id, "admin"), "weekly-report").await.unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+
+        let (status, _) = run(claims(admin.id, "admin"), "nightly-report").await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        for _ in 0..50 {
+            if list(claims(admin.id, "admin")).await.unwrap().0.data.unwrap()[0].runs == 1 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(ran.load(std::sync::atomic::Ordering::SeqCst));
+        let jobs = list(claims(admin.id, "admin")).await.unwrap().0.data.unwrap();
+        assert_eq!(jobs[0].runs, 1);
+        assert!(jobs[0].last_error.is_none());
+
+        scheduler.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_listed_and_can_be_ended() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let auth_service = AuthService::new("test_secret").with_bcrypt_cost(4);
+        let password_hash = auth_service.hash_password("password").unwrap();
+        database.update_password_hash(user_id, &password_hash, false, Utc::now()).await.unwrap();
+
+        let login_with = |user_agent: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::USER_AGENT, user_agent.parse().unwrap());
+            login(
+                State(auth_service.clone()),
+                State(TwoFactorService::new("test_secret")),
+                State(test_limiter()),
+                State(database.clone()),
+                State(test_mycloud(false)),
+                test_client(),
+                headers,
+                Json(LoginRequest {
+                    username: "testuser".to_string(),
+                    password: "password".to_string(),
+                    device_id: None,
+                    device_name: None,
+                    totp_code: None,
+                    client_version: None,
+                }),
+            )
+        };
+
+        let Json(browser) = login_with("Mozilla/5.0 Firefox/131.0").await.unwrap();
+        let Json(script) = login_with("synker-cli/0.3").await.unwrap();
+        let browser = browser.data.unwrap();
+        let script = script.data.unwrap();
+        let claims = auth_service.verify_token(&browser.token).unwrap();
+
+        let Json(response) = list_sessions(State(database.clone()), Extension(claims.clone())).await.unwrap();
+        let sessions = response.data.unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().all(|session| session.ip_address.as_deref() == Some("127.0.0.1")));
+        let current: Vec<_> = sessions.iter().filter(|session| session.current).collect();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].user_agent.as_deref(), Some("Mozilla/5.0 Firefox/131.0"));
+        let script_session = sessions.iter().find(|session| !session.current).unwrap().id;
+
+        // The session follows its tokens through a refresh
+        let Json(refreshed) = refresh_access_token(
+            State(auth_service.clone()),
+            State(database.clone()),
+            test_client(),
+            HeaderMap::new(),
+            Json(RefreshRequest { refresh_token: script.refresh_token, rotate: true }),
+        ).await.unwrap();
+        let refreshed = refreshed.data.unwrap();
+
+        end_session(
+            State(auth_service.clone()),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path(script_session.to_string()),
+        ).await.unwrap();
+
+        assert!(auth_service.verify_token(&refreshed.token).is_err());
+        assert!(auth_service.verify_token(&browser.token).is_ok());
+        let err = refresh_access_token(
+            State(auth_service.clone()),
+            State(database.clone()),
+            test_client(),
+            HeaderMap::new(),
+            Json(RefreshRequest { refresh_token: refreshed.refresh_token, rotate: false }),
+        ).await.unwrap_err();
+        assert_eq!(err, StatusCode::UNAUTHORIZED);
+        // Recorded, so a restart doesn't bring the token back
+        let revoked = database.get_revoked_tokens().await.unwrap();
+        assert_eq!(revoked.len(), 1);
+
+        let err = end_session(
+            State(auth_service.clone()),
+            State(database.clone()),
+            Extension(claims.clone()),
+            Path(script_session.to_string()),
+        ).await.unwrap_err();
+        assert_eq!(err, StatusCode::NOT_FOUND);
+
+        // Once it can't be refreshed, a session is cleaned up
+        let later = Utc::now() + chrono::Duration::days(31);
+        assert_eq!(database.purge_expired_sessions(later).await.unwrap(), 1);
+        let Json(response) = list_sessions(State(database.clone()), Extension(claims)).await.unwrap();
+        assert!(response.data.unwrap().is_empty());
+    }
+
+    /// A MyCloud that knows "nasuser" with the password "naspass"
+    fn nas_with_user(auth_fallback: bool) -> Arc<MockMyCloud> {
+        let nas = Arc::new(MockMyCloud::new(auth_fallback));
+        nas.add_user(crate::mycloud::MyCloudUser {
+            username: "nasuser".to_string(),
+            email: Some("nas@example.com".to_string()),
+            full_name: None,
+            groups: vec!["users".to_string()],
+            is_admin: false,
+            is_active: true,
+            last_login: None,
+        }, "naspass");
+        nas
+    }
+
+    #[tokio::test]
+    async fn test_mycloud_users_are_created_on_first_login() {
+        let db_dir = tempdir().unwrap();
+        let (database, _) = test_database(db_dir.path()).await;
+        let auth_service = AuthService::new("test_secret").with_bcrypt_cost(4);
+        let nas = nas_with_user(true);
+
+        let login_with = |mycloud: Arc<dyn MyCloudApi>, password: &str| {
+            login(
+                State(auth_service.clone()),
+                State(TwoFactorService::new("test_secret")),
+                State(test_limiter()),
+                State(database.clone()),
+                State(mycloud),
+                test_client(),
+                HeaderMap::new(),
+                Json(LoginRequest {
+                    username: "nasuser".to_string(),
+                    password: password.to_string(),
+                    device_id: None,
+                    device_name: None,
+                    totp_code: None,
+                    client_version: None,
+                }),
+            )
+        };
+
+        // Without the fallback MyCloud isn't asked
+        let Json(response) = login_with(nas_with_user(false), "naspass").await.unwrap();
+        assert!(!response.success);
+        assert!(database.get_user_by_username("nasuser").await.unwrap().is_none());
+
+        // MyCloud says no
+        let Json(response) = login_with(nas.clone(), "wrong").await.unwrap();
+        assert!(!response.success);
+        assert!(database.get_user_by_username("nasuser").await.unwrap().is_none());
+
+        let Json(response) = login_with(nas.clone(), "naspass").await.unwrap();
+        assert!(response.success);
+        let user = database.get_user_by_username("nasuser").await.unwrap().unwrap();
+        assert_eq!(user.email.as_deref(), Some("nas@example.com"));
+        assert!(user.has_permission(Permission::Write));
+        assert!(!user.has_permission(Permission::Admin));
+        assert!(database.is_mycloud_user(user.id).await.unwrap());
+        let claims = auth_service.verify_token(&response.data.unwrap().token).unwrap();
+        assert_eq!(claims.sub, user.id.to_string());
+
+        // The password was stored, so it works with MyCloud out of reach
+        nas.set_reachable(false);
+        let Json(response) = login_with(nas.clone(), "naspass").await.unwrap();
+        assert!(response.success);
+        let Json(response) = login_with(nas.clone(), "wrong").await.unwrap();
+        assert!(!response.success);
+    }
+
+    #[tokio::test]
+    async fn test_oidc_logins_map_to_local_users() {
+        let db_dir = tempdir().unwrap();
+        let (database, _) = test_database(db_dir.path()).await;
+        let user_id = Uuid::new_v4();
+        database.create_user(&User {
+            id: user_id,
+            username: "carol".to_string(),
+            email: Some("carol@example.com".to_string()),
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string(), "write".to_string()],
+        }).await.unwrap();
+        let auth_service = AuthService::new("test_secret").with_bcrypt_cost(4);
+
+        let settings = |auto_provision: bool, link_by_email: bool| crate::config::OidcSettings {
+            issuer_url: "https://auth.example.com".to_string(),
+            client_id: "synker".to_string(),
+            client_secret: "client-secret".to_string(),
+            redirect_url: "https://synker.example.com/api/v1/auth/oidc/callback".to_string(),
+            auto_provision,
+            link_by_email,
+            groups_claim: "groups".to_string(),
+            default_permissions: vec![Permission::Read],
+            group_permissions: HashMap::from([("synker-admin".to_string(), vec![Permission::Admin])]),
+            post_login_redirect: None,
+        };
+        let identity = |subject: &str, email: &str, email_verified: bool, groups: &[&str]| OidcIdentity {
+            issuer: "https://auth.example.com".to_string(),
+            subject: subject.to_string(),
+            email: Some(email.to_string()),
+            email_verified,
+            preferred_username: Some(subject.to_string()),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        };
+        let oidc = OidcService::new(settings(true, true));
+
+        // Emails only link when that is turned on
+        let unlinked = OidcService::new(settings(false, false));
+        let err = oidc_user(&unlinked, &auth_service, &database, &identity("kc-0", "carol@example.com", true, &[])).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        // A verified email finds the existing user, who keeps their permissions
+        let user = oidc_user(&oidc, &auth_service, &database, &identity("kc-1", "CAROL@example.com", true, &["synker-admin"])).await.unwrap();
+        assert_eq!(user.id, user_id);
+        assert!(!user.has_permission(Permission::Admin));
+        // From then on the link holds, whatever the email
+        let user = oidc_user(&oidc, &auth_service, &database, &identity("kc-1", "new@example.com", false, &[])).await.unwrap();
+        assert_eq!(user.id, user_id);
+
+        // Someone new is created, with permissions from their groups
+        let alice = oidc_user(&oidc, &auth_service, &database, &identity("alice", "alice@example.com", false, &["synker-admin"])).await.unwrap();
+        assert_ne!(alice.id, user_id);
+        assert_eq!(alice.permissions, vec!["read", "admin"]);
+        assert!(!auth_service.verify_password("", &alice.password_hash).unwrap());
+        // which follow the groups at the next login
+        let alice = oidc_user(&oidc, &auth_service, &database, &identity("alice", "alice@example.com", false, &[])).await.unwrap();
+        assert_eq!(alice.permissions, vec!["read"]);
+        let stored = database.get_user_by_id(alice.id).await.unwrap().unwrap();
+        assert_eq!(stored.permissions, vec!["read"]);
+
+        // A name that is taken isn't handed to someone else
+        let err = oidc_user(&oidc, &auth_service, &database, &identity("testuser", "other@example.com", false, &[])).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+
+        // Admins are never linked by email
+        database.create_user(&User {
+            id: Uuid::new_v4(),
+            username: "dave".to_string(),
+            email: Some("dave@example.com".to_string()),
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["admin".to_string()],
+        }).await.unwrap();
+        let err = oidc_user(&oidc, &auth_service, &database, &identity("kc-2", "dave@example.com", true, &[])).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        assert!(database.get_oidc_identity("https://auth.example.com", "kc-2").await.unwrap().is_none());
+
+        // Without provisioning, strangers are turned away
+        let strict = OidcService::new(settings(false, true));
+        let err = oidc_user(&strict, &auth_service, &database, &identity("bob", "bob@example.com", false, &[])).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        assert!(database.get_user_by_username("bob").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_login_upgrades_old_password_hashes() {
+        let db_dir = tempdir().unwrap();
+        let (database, user_id) = test_database(db_dir.path()).await;
+        let bcrypt_hash = AuthService::new("test_secret")
+            .with_bcrypt_cost(4)
+            .hash_password("password")
+            .unwrap();
+        database.update_password_hash(user_id, &bcrypt_hash, false, Utc::now()).await.unwrap();
+
+        let auth_service = AuthService::new("test_secret")
+            .with_password_scheme(PasswordScheme::Argon2id)
+            .with_argon2_params(argon2::Params::new(1024, 1, 1, None).unwrap());
+        let login_with = |password: &str| {
+            login(
+                State(auth_service.clone()),
+                State(TwoFactorService::new("test_secret")),
+                State(test_limiter()),
+                State(database.clone()),
+                State(test_mycloud(false)),
+                test_client(),
+                HeaderMap::new(),
+                Json(LoginRequest {
+                    username: "testuser".to_string(),
+                    password: password.to_string(),
+                    device_id: None,
+                    device_name: None,
+                    totp_code: None,
+                    client_version: None,
+                }),
+            )
+        };
+
+        // A wrong password leaves the old hash alone
+        let Json(response) = login_with("wrong").await.unwrap();
+        assert!(!response.success);
+        let user = database.get_user_by_id(user_id).await.unwrap().unwrap();
+        assert_eq!(user.password_hash, bcrypt_hash);
+
+        let Json(response) = login_with("password").await.unwrap();
+        assert!(response.success);
+        let user = database.get_user_by_id(user_id).await.unwrap().unwrap();
+        assert!(user.password_hash.starts_with("$argon2id$"));
+        assert!(!auth_service.needs_rehash(&user.password_hash));
+
+        let Json(response) = login_with("password").await.unwrap();
+        assert!(response.success);
+    }
 }
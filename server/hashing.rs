@@ -0,0 +1,149 @@
+use sha2::compress256;
+use sha2::digest::generic_array::GenericArray;
+
+const BLOCK_SIZE: usize = 64;
+
+// SHA-256 initial hash values
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+    0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+// State words, then the byte count, then up to one block of pending input
+const STATE_BYTES: usize = 8 * 4 + 8;
+
+/// SHA-256 whose progress can be saved and picked up later, so a resumable
+/// upload can hash its chunks as they arrive, across requests and restarts.
+/// `sha2::Sha256` gives no access to its internal state, hence this wrapper
+/// around its compression function.
+#[derive(Debug, Clone)]
+pub struct ResumableSha256 {
+    state: [u32; 8],
+    length: u64,
+    pending: Vec<u8>,
+}
+
+impl Default for ResumableSha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResumableSha256 {
+    pub fn new() -> Self {
+        Self {
+            state: INITIAL_STATE,
+            length: 0,
+            pending: Vec::with_capacity(BLOCK_SIZE),
+        }
+    }
+
+    /// Bytes hashed so far
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+
+        if !self.pending.is_empty() {
+            let take = (BLOCK_SIZE - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            if self.pending.len() < BLOCK_SIZE {
+                return;
+            }
+            compress256(&mut self.state, &[GenericArray::clone_from_slice(&self.pending)]);
+            self.pending.clear();
+        }
+
+        let whole = data.len() - data.len() % BLOCK_SIZE;
+        let blocks: Vec<_> = data[..whole]
+            .chunks_exact(BLOCK_SIZE)
+            .map(GenericArray::clone_from_slice)
+            .collect();
+        compress256(&mut self.state, &blocks);
+        self.pending.extend_from_slice(&data[whole..]);
+    }
+
+    /// Hex digest, matching `format!("{:x}", Sha256::digest(data))`
+    pub fn finalize(mut self) -> String {
+        let bit_length = self.length * 8;
+
+        let mut padding = vec![0x80];
+        let used = (self.pending.len() + 1) % BLOCK_SIZE;
+        let zeros = if used <= BLOCK_SIZE - 8 { BLOCK_SIZE - 8 - used } else { 2 * BLOCK_SIZE - 8 - used };
+        padding.resize(1 + zeros, 0);
+        padding.extend_from_slice(&bit_length.to_be_bytes());
+
+        // Padding must not count towards the message length
+        let length = self.length;
+        self.update(&padding);
+        self.length = length;
+        debug_assert!(self.pending.is_empty());
+
+        self.state.iter().map(|word| format!("{:08x}", word)).collect()
+    }
+
+    /// Serialized state for storing alongside an upload session
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(STATE_BYTES + self.pending.len());
+        for word in self.state {
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.length.to_be_bytes());
+        bytes.extend_from_slice(&self.pending);
+        bytes
+    }
+
+    /// Restore a state saved by `to_bytes`, or None if it is malformed
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < STATE_BYTES || bytes.len() >= STATE_BYTES + BLOCK_SIZE {
+            return None;
+        }
+
+        let mut state = [0u32; 8];
+        for (word, chunk) in state.iter_mut().zip(bytes[..32].chunks_exact(4)) {
+            *word = u32::from_be_bytes(chunk.try_into().ok()?);
+        }
+        let length = u64::from_be_bytes(bytes[32..STATE_BYTES].try_into().ok()?);
+        let pending = bytes[STATE_BYTES..].to_vec();
+
+        if length % BLOCK_SIZE as u64 != pending.len() as u64 {
+            return None;
+        }
+
+        Some(Self { state, length, pending })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_matches_sha256_across_splits_and_restores() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 251) as u8).collect();
+
+        for length in [0, 1, 55, 56, 63, 64, 65, 119, 120, 128, 1000] {
+            let expected = format!("{:x}", Sha256::digest(&data[..length]));
+
+            for split in [0, 1, 7, 64, length / 2, length] {
+                let split = split.min(length);
+                let mut hash = ResumableSha256::new();
+                hash.update(&data[..split]);
+
+                // Pick up where the first request left off
+                let mut hash = ResumableSha256::from_bytes(&hash.to_bytes()).unwrap();
+                hash.update(&data[split..length]);
+
+                assert_eq!(hash.len(), length as u64);
+                assert_eq!(hash.finalize(), expected, "length {} split {}", length, split);
+            }
+        }
+
+        assert!(ResumableSha256::from_bytes(&[0; 10]).is_none());
+    }
+}
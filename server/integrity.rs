@@ -0,0 +1,187 @@
+use chrono::{DateTime, Utc};
+use anyhow::Result;
+use uuid::Uuid;
+
+use crate::database::Database;
+use crate::filesystem::FileSystemService;
+use crate::types::{FileMetadata, IntegrityIssue, IntegrityScanReport};
+
+// Some filesystems only keep whole seconds, so small mtime differences are ignored
+const MTIME_TOLERANCE_SECONDS: i64 = 1;
+
+/// Re-reads stored files and compares them with the checksum recorded in
+/// `file_metadata`, so silent corruption on disk gets noticed.
+#[derive(Clone)]
+pub struct IntegrityScanner {
+    database: Database,
+    filesystem: FileSystemService,
+    files_per_cycle: u32,
+    max_bytes_per_sec: u64,
+}
+
+impl IntegrityScanner {
+    pub fn new(
+        database: Database,
+        filesystem: FileSystemService,
+        files_per_cycle: u32,
+        max_bytes_per_sec: u64,
+    ) -> Self {
+        Self {
+            database,
+            filesystem,
+            files_per_cycle,
+            max_bytes_per_sec,
+        }
+    }
+
+    /// Verify the files that have gone longest without a check
+    pub async fn run_cycle(&self) -> Result<IntegrityScanReport> {
+        let files = self.database
+            .get_files_due_for_verification(self.files_per_cycle)
+            .await?;
+        self.verify_files(&files).await
+    }
+
+    pub async fn verify_files(&self, files: &[FileMetadata]) -> Result<IntegrityScanReport> {
+        let mut report = IntegrityScanReport::default();
+
+        for file in files {
+            let now = Utc::now();
+            // Paths are relative to the owner's home when user homes are on
+            let filesystem = self.filesystem.for_user(file.owner_id)?;
+
+            let stat = match filesystem.stat(&file.path).await {
+                Ok(stat) if stat.is_file() => stat,
+                // Rows of trashed or externally removed files; checked again later
+                _ => {
+                    report.missing += 1;
+                    self.database.mark_file_verified(file.id, now).await?;
+                    continue;
+                }
+            };
+
+            let actual = match filesystem
+                .calculate_checksum_throttled(&file.path, self.max_bytes_per_sec)
+                .await
+            {
+                Ok(checksum) => checksum,
+                // Read errors are what a failing disk looks like, so report them
+                Err(e) => {
+                    tracing::warn!("Integrity scan could not read {}: {}", file.path, e);
+                    self.flag(file, "unreadable".to_string(), now).await?;
+                    report.mismatched += 1;
+                    continue;
+                }
+            };
+
+            let modified_at = stat
+                .modified()
+                .map(DateTime::<Utc>::from)
+                .unwrap_or(now);
+            let changed_on_disk =
+                modified_at > file.modified_at + chrono::Duration::seconds(MTIME_TOLERANCE_SECONDS);
+
+            if changed_on_disk || file.checksum.is_empty() {
+                // Legitimately rewritten, or never hashed: the current content becomes the reference
+                self.database
+                    .restamp_file(file.id, &actual, stat.len(), modified_at, now)
+                    .await?;
+                self.database.clear_integrity_issue(file.id).await?;
+                report.restamped += 1;
+            } else if actual != file.checksum {
+                tracing::error!(
+                    "Checksum mismatch for {}: expected {}, got {}",
+                    file.path,
+                    file.checksum,
+                    actual
+                );
+                self.flag(file, actual, now).await?;
+                report.mismatched += 1;
+            } else {
+                self.database.mark_file_verified(file.id, now).await?;
+                self.database.clear_integrity_issue(file.id).await?;
+                report.verified += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn flag(&self, file: &FileMetadata, actual: String, detected_at: DateTime<Utc>) -> Result<()> {
+        let issue = IntegrityIssue {
+            id: Uuid::new_v4(),
+            file_id: file.id,
+            path: file.path.clone(),
+            expected_checksum: file.checksum.clone(),
+            actual_checksum: actual,
+            detected_at,
+        };
+
+        self.database.record_integrity_issue(&issue).await?;
+        self.database.mark_file_verified(file.id, detected_at).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{FilePermissions, User};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_scan_flags_corruption_and_restamps_edits() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let url = format!("sqlite://{}?mode=rwc", db_dir.path().join("test.db").display());
+        let database = Database::new(&url).await.unwrap();
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let user = User {
+            id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string()],
+        };
+        database.create_user(&user).await.unwrap();
+
+        let mut rows = Vec::new();
+        for path in ["/rotten.txt", "/edited.txt", "/fine.txt"] {
+            let mut metadata = filesystem.save_file(path, b"original").await.unwrap();
+            metadata.owner_id = user.id;
+            metadata.permissions = FilePermissions { read: true, write: true, delete: true, share: true };
+            database.create_file_metadata(&metadata).await.unwrap();
+            rows.push(metadata);
+        }
+
+        // Bit rot keeps the mtime; a real edit moves it forward
+        let rotten = filesystem.get_absolute_path("/rotten.txt");
+        let mtime = std::fs::metadata(&rotten).unwrap().modified().unwrap();
+        std::fs::write(&rotten, b"0riginal").unwrap();
+        std::fs::File::options().write(true).open(&rotten).unwrap().set_modified(mtime).unwrap();
+
+        let edited = filesystem.get_absolute_path("/edited.txt");
+        std::fs::write(&edited, b"changed by hand").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&edited)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+
+        let scanner = IntegrityScanner::new(database.clone(), filesystem, 10, 0);
+        let report = scanner.run_cycle().await.unwrap();
+        assert_eq!(report.verified, 1);
+        assert_eq!(report.restamped, 1);
+        assert_eq!(report.mismatched, 1);
+
+        let issues = database.list_integrity_issues().await.unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].path, "/rotten.txt");
+        assert_eq!(issues[0].expected_checksum, rows[0].checksum);
+    }
+}
@@ -0,0 +1,224 @@
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::config::MyCloudSettings;
+use crate::mycloud::{MyCloudApi, MyCloudShare, MyCloudUser};
+
+/// A MyCloud NAS kept in memory, for tests and for running the server
+/// without one (`--mock-mycloud`, debug builds only). Calls can be made to fail on cue.
+pub struct MockMyCloud {
+    auth_fallback: bool,
+    state: Mutex<MockState>,
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Accounts by username, with their passwords
+    accounts: BTreeMap<String, (MyCloudUser, String)>,
+    shares: Vec<MyCloudShare>,
+    unreachable: bool,
+    /// How many more calls of each method fail
+    failures: HashMap<String, u32>,
+}
+
+impl MockMyCloud {
+    /// A NAS with no accounts or shares
+    pub fn new(auth_fallback: bool) -> Self {
+        Self {
+            auth_fallback,
+            state: Mutex::new(MockState::default()),
+        }
+    }
+
+    /// A NAS for development: the configured admin, a "demo" user with the
+    /// password "demo", and a Public share both can write to
+    pub fn from_settings(config: &MyCloudSettings) -> Self {
+        let mycloud = Self::new(config.auth_fallback);
+        mycloud.add_user(account(&config.admin_username, "administrators", true), &config.admin_password);
+        mycloud.add_user(account("demo", "users", false), "demo");
+        mycloud.add_share(MyCloudShare {
+            name: "Public".to_string(),
+            path: format!("{}/Public", config.share_root.trim_end_matches('/')),
+            permissions: vec!["read".to_string(), "write".to_string()],
+            accessible_by: vec![config.admin_username.clone(), "demo".to_string()],
+        });
+        mycloud
+    }
+
+    /// Add an account that logs in with `password`, or replace the one of
+    /// the same name
+    pub fn add_user(&self, user: MyCloudUser, password: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.accounts.insert(user.username.clone(), (user, password.to_string()));
+    }
+
+    /// Add a share, or replace the one of the same name
+    pub fn add_share(&self, share: MyCloudShare) {
+        let mut state = self.state.lock().unwrap();
+        state.shares.retain(|existing| existing.name != share.name);
+        state.shares.push(share);
+    }
+
+    /// The state for a call of `method`, unless it was set to fail
+    fn answer(&self, method: &str) -> Result<MutexGuard<'_, MockState>> {
+        let mut state = self.state.lock().unwrap();
+        if state.unreachable {
+            return Err(anyhow!("MyCloud is unreachable"));
+        }
+        if let Some(remaining) = state.failures.get_mut(method).filter(|remaining| **remaining > 0) {
+            *remaining -= 1;
+            return Err(anyhow!("MyCloud failed {}", method));
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+impl MockMyCloud {
+    pub fn remove_user(&self, username: &str) {
+        self.state.lock().unwrap().accounts.remove(username);
+    }
+
+    pub fn remove_share(&self, name: &str) {
+        self.state.lock().unwrap().shares.retain(|share| share.name != name);
+    }
+
+    /// Fail every call until reachable again
+    pub fn set_reachable(&self, reachable: bool) {
+        self.state.lock().unwrap().unreachable = !reachable;
+    }
+
+    /// Fail the next `times` calls of `method`, such as "list_users"
+    pub fn fail_next(&self, method: &str, times: u32) {
+        self.state.lock().unwrap().failures.insert(method.to_string(), times);
+    }
+}
+
+fn account(username: &str, group: &str, is_admin: bool) -> MyCloudUser {
+    MyCloudUser {
+        username: username.to_string(),
+        email: None,
+        full_name: None,
+        groups: vec![group.to_string()],
+        is_admin,
+        is_active: true,
+        last_login: None,
+    }
+}
+
+#[async_trait]
+impl MyCloudApi for MockMyCloud {
+    fn auth_fallback(&self) -> bool {
+        self.auth_fallback
+    }
+
+    async fn verify_user_credentials(&self, username: &str, password: &str) -> Result<Option<MyCloudUser>> {
+        let state = self.answer("verify_user_credentials")?;
+        Ok(state.accounts.get(username)
+            .filter(|(user, expected)| user.is_active && expected == password)
+            .map(|(user, _)| user.clone()))
+    }
+
+    async fn get_user_info(&self, username: &str) -> Result<Option<MyCloudUser>> {
+        let state = self.answer("get_user_info")?;
+        Ok(state.accounts.get(username).map(|(user, _)| user.clone()))
+    }
+
+    async fn list_users(&self) -> Result<Vec<MyCloudUser>> {
+        let state = self.answer("list_users")?;
+        Ok(state.accounts.values().map(|(user, _)| user.clone()).collect())
+    }
+
+    async fn get_user_shares(&self, username: &str) -> Result<Vec<MyCloudShare>> {
+        let state = self.answer("get_user_shares")?;
+        Ok(state.shares.iter()
+            .filter(|share| share.accessible_by.iter().any(|name| name == username))
+            .cloned()
+            .collect())
+    }
+
+    /// Admins may do anything. Others may read the shares open to them, and
+    /// do whatever else those shares' permissions name.
+    async fn check_user_permissions(&self, username: &str, resource: &str, action: &str) -> Result<bool> {
+        let state = self.answer("check_user_permissions")?;
+        let Some((user, _)) = state.accounts.get(username).filter(|(user, _)| user.is_active) else {
+            return Ok(false);
+        };
+        if user.is_admin {
+            return Ok(true);
+        }
+        Ok(state.shares.iter().any(|share| {
+            share.name == resource
+                && share.accessible_by.iter().any(|name| name == username)
+                && (action == "read" || share.permissions.iter().any(|permission| permission == action))
+        }))
+    }
+
+    async fn get_system_info(&self) -> Result<serde_json::Value> {
+        let state = self.answer("get_system_info")?;
+        Ok(serde_json::json!({
+            "model": "MockMyCloud",
+            "firmware": env!("CARGO_PKG_VERSION"),
+            "users": state.accounts.len(),
+            "shares": state.shares.len(),
+        }))
+    }
+
+    async fn monitor_shares(&self) -> Result<Vec<MyCloudShare>> {
+        let state = self.answer("monitor_shares")?;
+        Ok(state.shares.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scripted_failures_run_out() {
+        let mycloud = MockMyCloud::new(true);
+        mycloud.add_user(account("alice", "users", false), "secret");
+
+        mycloud.fail_next("list_users", 2);
+        assert!(mycloud.list_users().await.is_err());
+        // Other calls are unaffected
+        assert!(mycloud.verify_user_credentials("alice", "secret").await.unwrap().is_some());
+        assert!(mycloud.list_users().await.is_err());
+        assert_eq!(mycloud.list_users().await.unwrap().len(), 1);
+
+        mycloud.set_reachable(false);
+        assert!(mycloud.get_user_info("alice").await.is_err());
+        mycloud.set_reachable(true);
+        assert!(mycloud.get_user_info("alice").await.unwrap().is_some());
+        assert!(mycloud.verify_user_credentials("alice", "wrong").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shares_decide_permissions() {
+        let config = crate::config::ServerConfig::default().mycloud;
+        let mycloud = MockMyCloud::from_settings(&config);
+        mycloud.add_user(account("bob", "guests", false), "bob");
+        mycloud.add_share(MyCloudShare {
+            name: "Archive".to_string(),
+            path: "/mnt/HD/HD_a2/Archive".to_string(),
+            permissions: vec!["read".to_string()],
+            accessible_by: vec!["bob".to_string()],
+        });
+
+        assert_eq!(mycloud.monitor_shares().await.unwrap()[0].path, "/mnt/HD/HD_a2/Public");
+        assert!(mycloud.check_user_permissions("demo", "Public", "write").await.unwrap());
+        assert!(!mycloud.check_user_permissions("demo", "Archive", "read").await.unwrap());
+        assert!(mycloud.check_user_permissions("bob", "Archive", "read").await.unwrap());
+        assert!(!mycloud.check_user_permissions("bob", "Archive", "write").await.unwrap());
+        assert!(mycloud.check_user_permissions(&config.admin_username, "Archive", "delete").await.unwrap());
+
+        let shares = mycloud.get_user_shares("bob").await.unwrap();
+        assert_eq!(shares.len(), 1);
+        mycloud.remove_share("Archive");
+        assert!(mycloud.get_user_shares("bob").await.unwrap().is_empty());
+        mycloud.remove_user("bob");
+        assert!(!mycloud.check_user_permissions("bob", "Public", "read").await.unwrap());
+    }
+}
@@ -1,12 +1,23 @@
 use serde::{Deserialize, Serialize};
-use reqwest::{Client, header::HeaderMap};
+use reqwest::{Client, Response, StatusCode, header::HeaderMap};
 use anyhow::{Result, anyhow};
+use async_trait::async_trait;
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
-use crate::types::User;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::auth::NO_PASSWORD;
 use crate::config::MyCloudSettings;
+use crate::database::Database;
+use crate::filesystem::{FileSystemService, path_components};
+use crate::types::{SharePermissions, User};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The admin session is renewed this long before MyCloud would end it
+const RENEW_BEFORE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MyCloudUser {
     pub username: String,
     pub email: Option<String>,
@@ -25,7 +36,7 @@ pub struct MyCloudAuthResponse {
     pub error: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MyCloudShare {
     pub name: String,
     pub path: String,
@@ -33,10 +44,50 @@ pub struct MyCloudShare {
     pub accessible_by: Vec<String>,
 }
 
+/// What Synker asks of a MyCloud NAS. `MyCloudIntegration` asks a real one
+/// over HTTP; `MockMyCloud` answers from memory.
+#[async_trait]
+pub trait MyCloudApi: Send + Sync {
+    /// Whether logins the local database turns down are checked here
+    fn auth_fallback(&self) -> bool;
+
+    /// The NAS account, when `username` and `password` log in to it
+    async fn verify_user_credentials(&self, username: &str, password: &str) -> Result<Option<MyCloudUser>>;
+
+    async fn get_user_info(&self, username: &str) -> Result<Option<MyCloudUser>>;
+
+    /// Every account on the NAS
+    async fn list_users(&self) -> Result<Vec<MyCloudUser>>;
+
+    /// The shares `username` can open
+    async fn get_user_shares(&self, username: &str) -> Result<Vec<MyCloudShare>>;
+
+    async fn check_user_permissions(&self, username: &str, resource: &str, action: &str) -> Result<bool>;
+
+    async fn get_system_info(&self) -> Result<serde_json::Value>;
+
+    /// Every share on the NAS
+    async fn monitor_shares(&self) -> Result<Vec<MyCloudShare>>;
+}
+
+/// MyCloud OS5's REST API
 pub struct MyCloudIntegration {
     client: Client,
     config: MyCloudSettings,
-    session_token: Option<String>,
+    /// Shared by every request, and renewed by whichever finds it stale
+    session: RwLock<Option<Session>>,
+}
+
+/// An admin session and when it is due for renewal
+struct Session {
+    token: String,
+    renew_at: Option<Instant>,
+}
+
+impl Session {
+    fn is_fresh(&self) -> bool {
+        self.renew_at.map_or(true, |renew_at| Instant::now() < renew_at)
+    }
 }
 
 impl MyCloudIntegration {
@@ -53,11 +104,12 @@ impl MyCloudIntegration {
         Self {
             client,
             config,
-            session_token: None,
+            session: RwLock::new(None),
         }
     }
 
-    pub async fn authenticate_admin(&mut self) -> Result<()> {
+    /// Log in as the admin for a new session
+    async fn login_admin(&self) -> Result<Session> {
         let auth_url = format!("{}/api/2.1/rest/login", self.config.api_endpoint);
         
         let auth_request = serde_json::json!({
@@ -81,11 +133,70 @@ impl MyCloudIntegration {
             return Err(anyhow!("MyCloud authentication failed: {:?}", auth_response.error));
         }
 
-        self.session_token = auth_response.session_token;
-        Ok(())
+        let token = auth_response.session_token
+            .ok_or_else(|| anyhow!("MyCloud sent no session token"))?;
+        let ttl = Duration::from_secs(self.config.session_ttl_seconds);
+        Ok(Session {
+            token,
+            renew_at: (!ttl.is_zero()).then(|| Instant::now() + ttl.saturating_sub(RENEW_BEFORE)),
+        })
+    }
+
+    /// The admin session's token, logging in first when there is no session
+    /// or it is due for renewal
+    async fn session_token(&self) -> Result<String> {
+        if let Some(session) = self.session.read().await.as_ref().filter(|session| session.is_fresh()) {
+            return Ok(session.token.clone());
+        }
+
+        let mut session = self.session.write().await;
+        // Another request may have logged in while this one waited
+        if let Some(current) = session.as_ref().filter(|current| current.is_fresh()) {
+            return Ok(current.token.clone());
+        }
+        let renewed = self.login_admin().await?;
+        let token = renewed.token.clone();
+        *session = Some(renewed);
+        Ok(token)
     }
 
-    pub async fn verify_user_credentials(&self, username: &str, password: &str) -> Result<Option<MyCloudUser>> {
+    /// GET `url` with the admin session. When MyCloud turns the session
+    /// down, log in again and try once more.
+    async fn get_authorized(&self, url: &str) -> Result<Response> {
+        let token = self.session_token().await?;
+        let response = self.client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        tracing::info!("MyCloud ended the admin session, logging in again");
+        {
+            // Unless another request already replaced it
+            let mut session = self.session.write().await;
+            if session.as_ref().is_some_and(|session| session.token == token) {
+                *session = None;
+            }
+        }
+        let token = self.session_token().await?;
+        Ok(self.client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl MyCloudApi for MyCloudIntegration {
+    fn auth_fallback(&self) -> bool {
+        self.config.auth_fallback
+    }
+
+    async fn verify_user_credentials(&self, username: &str, password: &str) -> Result<Option<MyCloudUser>> {
         let auth_url = format!("{}/api/2.1/rest/login", self.config.api_endpoint);
         
         let auth_request = serde_json::json!({
@@ -112,16 +223,10 @@ impl MyCloudIntegration {
         }
     }
 
-    pub async fn get_user_info(&self, username: &str) -> Result<Option<MyCloudUser>> {
-        self.ensure_authenticated().await?;
-        
+    async fn get_user_info(&self, username: &str) -> Result<Option<MyCloudUser>> {
         let user_url = format!("{}/api/2.1/rest/users/{}", self.config.api_endpoint, username);
-        
-        let response = self.client
-            .get(&user_url)
-            .header("Authorization", format!("Bearer {}", self.session_token.as_ref().unwrap()))
-            .send()
-            .await?;
+
+        let response = self.get_authorized(&user_url).await?;
 
         if response.status().is_success() {
             let user: MyCloudUser = response.json().await?;
@@ -131,16 +236,23 @@ impl MyCloudIntegration {
         }
     }
 
-    pub async fn get_user_shares(&self, username: &str) -> Result<Vec<MyCloudShare>> {
-        self.ensure_authenticated().await?;
-        
+    async fn list_users(&self) -> Result<Vec<MyCloudUser>> {
+        let users_url = format!("{}/api/2.1/rest/users", self.config.api_endpoint);
+
+        let response = self.get_authorized(&users_url).await?;
+
+        if response.status().is_success() {
+            let users: Vec<MyCloudUser> = response.json().await?;
+            Ok(users)
+        } else {
+            Err(anyhow!("Failed to get users: {}", response.status()))
+        }
+    }
+
+    async fn get_user_shares(&self, username: &str) -> Result<Vec<MyCloudShare>> {
         let shares_url = format!("{}/api/2.1/rest/users/{}/shares", self.config.api_endpoint, username);
-        
-        let response = self.client
-            .get(&shares_url)
-            .header("Authorization", format!("Bearer {}", self.session_token.as_ref().unwrap()))
-            .send()
-            .await?;
+
+        let response = self.get_authorized(&shares_url).await?;
 
         if response.status().is_success() {
             let shares: Vec<MyCloudShare> = response.json().await?;
@@ -150,34 +262,13 @@ impl MyCloudIntegration {
         }
     }
 
-    pub async fn sync_user_to_local(&self, mycloud_user: &MyCloudUser, password_hash: &str) -> Result<User> {
-        let user = User {
-            id: Uuid::new_v4(),
-            username: mycloud_user.username.clone(),
-            email: mycloud_user.email.clone(),
-            password_hash: password_hash.to_string(),
-            created_at: Utc::now(),
-            last_login: mycloud_user.last_login,
-            is_active: mycloud_user.is_active,
-            permissions: self.map_mycloud_permissions(&mycloud_user.groups),
-        };
-
-        Ok(user)
-    }
-
-    pub async fn check_user_permissions(&self, username: &str, resource: &str, action: &str) -> Result<bool> {
-        self.ensure_authenticated().await?;
-        
+    async fn check_user_permissions(&self, username: &str, resource: &str, action: &str) -> Result<bool> {
         let permissions_url = format!(
             "{}/api/2.1/rest/users/{}/permissions?resource={}&action={}",
             self.config.api_endpoint, username, resource, action
         );
-        
-        let response = self.client
-            .get(&permissions_url)
-            .header("Authorization", format!("Bearer {}", self.session_token.as_ref().unwrap()))
-            .send()
-            .await?;
+
+        let response = self.get_authorized(&permissions_url).await?;
 
         if response.status().is_success() {
             let result: serde_json::Value = response.json().await?;
@@ -187,59 +278,10 @@ impl MyCloudIntegration {
         }
     }
 
-    async fn ensure_authenticated(&self) -> Result<()> {
-        if self.session_token.is_none() {
-            return Err(anyhow!("Not authenticated with MyCloud"));
-        }
-        Ok(())
-    }
-
-    fn map_mycloud_permissions(&self, groups: &[String]) -> Vec<String> {
-        let mut permissions = Vec::new();
-        
-        for group in groups {
-            match group.as_str() {
-                "administrators" => {
-                    permissions.extend_from_slice(&[
-                        "read".to_string(),
-                        "write".to_string(),
-                        "delete".to_string(),
-                        "share".to_string(),
-                        "admin".to_string(),
-                    ]);
-                }
-                "users" => {
-                    permissions.extend_from_slice(&[
-                        "read".to_string(),
-                        "write".to_string(),
-                        "share".to_string(),
-                    ]);
-                }
-                "guests" => {
-                    permissions.push("read".to_string());
-                }
-                _ => {
-                    // Custom group permissions can be added here
-                    permissions.push("read".to_string());
-                }
-            }
-        }
-
-        permissions.sort();
-        permissions.dedup();
-        permissions
-    }
-
-    pub async fn get_system_info(&self) -> Result<serde_json::Value> {
-        self.ensure_authenticated().await?;
-        
+    async fn get_system_info(&self) -> Result<serde_json::Value> {
         let info_url = format!("{}/api/2.1/rest/system/info", self.config.api_endpoint);
-        
-        let response = self.client
-            .get(&info_url)
-            .header("Authorization", format!("Bearer {}", self.session_token.as_ref().unwrap()))
-            .send()
-            .await?;
+
+        let response = self.get_authorized(&info_url).await?;
 
         if response.status().is_success() {
             let info: serde_json::Value = response.json().await?;
@@ -249,16 +291,10 @@ impl MyCloudIntegration {
         }
     }
 
-    pub async fn monitor_shares(&self) -> Result<Vec<MyCloudShare>> {
-        self.ensure_authenticated().await?;
-        
+    async fn monitor_shares(&self) -> Result<Vec<MyCloudShare>> {
         let shares_url = format!("{}/api/2.1/rest/shares", self.config.api_endpoint);
-        
-        let response = self.client
-            .get(&shares_url)
-            .header("Authorization", format!("Bearer {}", self.session_token.as_ref().unwrap()))
-            .send()
-            .await?;
+
+        let response = self.get_authorized(&shares_url).await?;
 
         if response.status().is_success() {
             let shares: Vec<MyCloudShare> = response.json().await?;
@@ -269,72 +305,537 @@ impl MyCloudIntegration {
     }
 }
 
-// Background service to periodically sync with MyCloud
+/// A local user for a NAS account, with permissions from its groups
+pub fn user_from_mycloud(mycloud_user: &MyCloudUser, password_hash: &str) -> User {
+    User {
+        id: Uuid::new_v4(),
+        username: mycloud_user.username.clone(),
+        email: mycloud_user.email.clone(),
+        password_hash: password_hash.to_string(),
+        created_at: Utc::now(),
+        last_login: mycloud_user.last_login,
+        is_active: mycloud_user.is_active,
+        auth_generation: 0,
+        permissions: map_mycloud_permissions(&mycloud_user.groups),
+    }
+}
+
+fn map_mycloud_permissions(groups: &[String]) -> Vec<String> {
+    let mut permissions = Vec::new();
+    
+    for group in groups {
+        match group.as_str() {
+            "administrators" => {
+                permissions.extend_from_slice(&[
+                    "read".to_string(),
+                    "write".to_string(),
+                    "delete".to_string(),
+                    "share".to_string(),
+                    "admin".to_string(),
+                ]);
+            }
+            "users" => {
+                permissions.extend_from_slice(&[
+                    "read".to_string(),
+                    "write".to_string(),
+                    "share".to_string(),
+                ]);
+            }
+            "guests" => {
+                permissions.push("read".to_string());
+            }
+            _ => {
+                // Custom group permissions can be added here
+                permissions.push("read".to_string());
+            }
+        }
+    }
+
+    permissions.sort();
+    permissions.dedup();
+    permissions
+}
+
+/// What became of one MyCloud share on a sync
+#[derive(Debug, PartialEq)]
+pub enum ShareImport {
+    /// Its folder at `path` is shared with `grantees` users
+    Imported { name: String, path: String, grantees: usize },
+    Skipped { name: String, reason: String },
+}
+
+/// What one sync of MyCloud's users changed here
+#[derive(Debug, Default, PartialEq)]
+pub struct UserSyncSummary {
+    pub created: usize,
+    pub updated: usize,
+    pub deactivated: usize,
+}
+
+// Background service to periodically sync with MyCloud, run by the scheduler
 pub struct MyCloudSyncService {
-    integration: MyCloudIntegration,
+    mycloud: Arc<dyn MyCloudApi>,
+    database: Database,
+    filesystem: FileSystemService,
     sync_interval: std::time::Duration,
+    sync_users: bool,
+    import_shares: bool,
+    admin_username: String,
+    share_root: String,
+    share_folder: String,
 }
 
 impl MyCloudSyncService {
-    pub fn new(config: MyCloudSettings) -> Self {
-        let sync_interval = std::time::Duration::from_secs(config.sync_interval_seconds);
-        let integration = MyCloudIntegration::new(config);
-
+    pub fn new(
+        config: &MyCloudSettings,
+        mycloud: Arc<dyn MyCloudApi>,
+        database: Database,
+        filesystem: FileSystemService,
+    ) -> Self {
         Self {
-            integration,
-            sync_interval,
+            mycloud,
+            database,
+            filesystem,
+            sync_interval: std::time::Duration::from_secs(config.sync_interval_seconds),
+            sync_users: config.sync_users,
+            import_shares: config.import_shares,
+            admin_username: config.admin_username.clone(),
+            share_root: config.share_root.clone(),
+            share_folder: config.share_folder.clone(),
         }
     }
 
-    pub async fn start(&mut self) -> Result<()> {
-        // Authenticate with MyCloud
-        self.integration.authenticate_admin().await?;
-        
-        // Start background sync loop
-        loop {
-            if let Err(e) = self.sync_cycle().await {
-                eprintln!("MyCloud sync error: {}", e);
+    /// How often `sync_cycle` should run
+    pub fn interval(&self) -> std::time::Duration {
+        self.sync_interval
+    }
+
+    pub async fn sync_cycle(&self) -> Result<()> {
+        // Sync shares
+        if self.import_shares {
+            for import in self.import_shares().await? {
+                match import {
+                    ShareImport::Imported { name, path, grantees } => {
+                        tracing::info!("Imported MyCloud share {} as {} for {} users", name, path, grantees);
+                    }
+                    ShareImport::Skipped { name, reason } => {
+                        tracing::warn!("Skipped MyCloud share {}: {}", name, reason);
+                    }
+                }
+            }
+        } else {
+            let shares = self.mycloud.monitor_shares().await?;
+            tracing::debug!("Synced {} shares from MyCloud", shares.len());
+        }
+
+        if self.sync_users {
+            let summary = self.sync_users().await?;
+            tracing::info!(
+                "Synced users from MyCloud: {} created, {} updated, {} deactivated",
+                summary.created,
+                summary.updated,
+                summary.deactivated
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Create users here for NAS accounts new to Synker and bring the ones
+    /// created from MyCloud in line with their accounts, deactivating those
+    /// gone from the NAS. Users created here are never touched, even when a
+    /// NAS account has the same name.
+    pub async fn sync_users(&self) -> Result<UserSyncSummary> {
+        let accounts = self.mycloud.list_users().await?;
+        // The NAS always has its admin, so no accounts at all is a fault
+        // rather than a reason to deactivate everyone
+        if accounts.is_empty() {
+            return Err(anyhow!("MyCloud listed no users"));
+        }
+
+        let mut provisioned: HashMap<String, User> = self.database.get_mycloud_users().await?
+            .into_iter()
+            .map(|user| (user.username.clone(), user))
+            .collect();
+        let mut summary = UserSyncSummary::default();
+
+        for account in &accounts {
+            // They set a password here by logging in through the MyCloud fallback
+            let mut synced = user_from_mycloud(account, NO_PASSWORD);
+
+            match provisioned.remove(&account.username) {
+                Some(user) => {
+                    let mut permissions = user.permissions.clone();
+                    permissions.sort();
+                    if user.email == synced.email && user.is_active == synced.is_active && permissions == synced.permissions {
+                        continue;
+                    }
+                    self.database.update_mycloud_user(user.id, synced.email.as_deref(), synced.is_active, &synced.permissions).await?;
+                    if user.is_active && !synced.is_active {
+                        summary.deactivated += 1;
+                    } else {
+                        summary.updated += 1;
+                    }
+                }
+                None => {
+                    if self.database.get_user_by_username(&account.username).await?.is_some() {
+                        tracing::debug!("Not syncing MyCloud user {}: a local user has the name", account.username);
+                        continue;
+                    }
+                    // Only the last login on the NAS, not one here
+                    synced.last_login = None;
+                    self.database.create_mycloud_user(&synced).await?;
+                    summary.created += 1;
+                }
             }
-            
-            tokio::time::sleep(self.sync_interval).await;
         }
+
+        // Left over are users whose NAS account is gone
+        for user in provisioned.into_values().filter(|user| user.is_active) {
+            self.database.update_mycloud_user(user.id, user.email.as_deref(), false, &user.permissions).await?;
+            summary.deactivated += 1;
+        }
+
+        Ok(summary)
     }
 
-    async fn sync_cycle(&mut self) -> Result<()> {
-        // Re-authenticate if needed
-        if self.integration.session_token.is_none() {
-            self.integration.authenticate_admin().await?;
+    /// Share the folder of each MyCloud share, owned by the admin user here,
+    /// with the users who can open the share on the NAS. Grants made for
+    /// shares gone from the NAS are revoked; their folders stay.
+    pub async fn import_shares(&self) -> Result<Vec<ShareImport>> {
+        let shares = self.mycloud.monitor_shares().await?;
+        let owner = self.database.get_user_by_username(&self.admin_username).await?
+            .ok_or_else(|| anyhow!("No user {} here to own the MyCloud shares", self.admin_username))?;
+        let filesystem = self.filesystem.for_user(owner.id)?;
+
+        let mut imports = Vec::new();
+        let mut imported = Vec::new();
+        for share in &shares {
+            let skip = |reason: String| ShareImport::Skipped { name: share.name.clone(), reason };
+
+            let Some(path) = self.local_share_path(&share.path) else {
+                imports.push(skip(format!("{} is outside {}", share.path, self.share_root)));
+                continue;
+            };
+            // Creates or refreshes the folder's row
+            let folder = match self.database.resolve_file_metadata(&filesystem, owner.id, &path).await {
+                Ok(folder) if folder.is_directory => folder,
+                Ok(_) => {
+                    imports.push(skip(format!("{} is not a folder", path)));
+                    continue;
+                }
+                Err(_) => {
+                    imports.push(skip(format!("{} does not exist", path)));
+                    continue;
+                }
+            };
+
+            let permissions = if share.permissions.iter().any(|p| p == "write" || p == "read_write") {
+                SharePermissions::ReadWrite
+            } else {
+                SharePermissions::Read
+            };
+            let mut grants = Vec::new();
+            for username in &share.accessible_by {
+                match self.database.get_user_by_username(username).await? {
+                    Some(user) if user.id != owner.id => grants.push((user.id, permissions)),
+                    Some(_) => {}
+                    None => tracing::debug!("MyCloud share {} is open to {}, who has no user here", share.name, username),
+                }
+            }
+
+            self.database.set_mycloud_share_grants(&share.name, folder.id, owner.id, &grants).await?;
+            imported.push(share.name.clone());
+            imports.push(ShareImport::Imported { name: share.name.clone(), path, grantees: grants.len() });
         }
 
-        // Sync shares
-        let shares = self.integration.monitor_shares().await?;
-        println!("Synced {} shares from MyCloud", shares.len());
+        let revoked = self.database.revoke_mycloud_share_grants(&imported).await?;
+        if revoked > 0 {
+            tracing::info!("Revoked {} grants of MyCloud shares no longer imported", revoked);
+        }
 
-        // Additional sync operations can be added here
-        // - User synchronization
-        // - Permission updates
-        // - System status checks
+        Ok(imports)
+    }
 
-        Ok(())
+    /// Where the share at `nas_path` is in Synker: under `share_folder` as it
+    /// is under `share_root` on the NAS. None for paths outside the root.
+    fn local_share_path(&self, nas_path: &str) -> Option<String> {
+        let root = path_components(&self.share_root)?;
+        let path = path_components(nas_path)?;
+        let rest = path.strip_prefix(root.as_slice()).filter(|rest| !rest.is_empty())?;
+        let mut local = path_components(&self.share_folder)?;
+        local.extend_from_slice(rest);
+        Some(format!("/{}", local.join("/")))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    #[tokio::test]
-    async fn test_mycloud_user_mapping() {
-        let config = MyCloudSettings {
-            api_endpoint: "http://localhost".to_string(),
+    use axum::http::HeaderMap as RequestHeaders;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use axum::response::IntoResponse;
+    use tempfile::tempdir;
+    use crate::mock_mycloud::MockMyCloud;
+    use crate::types::UserShare;
+
+    fn settings(api_endpoint: &str, session_ttl_seconds: u64) -> MyCloudSettings {
+        MyCloudSettings {
+            api_endpoint: api_endpoint.to_string(),
             admin_username: "admin".to_string(),
             admin_password: "password".to_string(),
             verify_ssl: false,
             sync_interval_seconds: 300,
+            auth_fallback: false,
+            session_ttl_seconds,
+            sync_users: false,
+            import_shares: false,
+            share_root: String::new(),
+            share_folder: String::new(),
+        }
+    }
+
+    /// Serves logins and the share list. Each login hands out the next
+    /// token, and shares are only listed for tokens from `first_valid` on.
+    /// Returns its endpoint and the number of logins so far.
+    async fn http_mycloud(first_valid: u64) -> (String, Arc<AtomicU64>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let logins = Arc::new(AtomicU64::new(0));
+
+        let issued = logins.clone();
+        let app = axum::Router::new()
+            .route("/api/2.1/rest/login", axum::routing::post(move || {
+                let token = issued.fetch_add(1, Ordering::SeqCst) + 1;
+                async move { axum::Json(json!({ "success": true, "session_token": format!("token-{}", token) })) }
+            }))
+            .route("/api/2.1/rest/shares", axum::routing::get(move |headers: RequestHeaders| async move {
+                let token = headers.get("Authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer token-"))
+                    .and_then(|number| number.parse::<u64>().ok());
+                match token {
+                    Some(token) if token >= first_valid => axum::Json(json!([
+                        { "name": "Public", "path": "/shares/Public", "permissions": ["read"], "accessible_by": [] }
+                    ])).into_response(),
+                    _ => axum::http::StatusCode::UNAUTHORIZED.into_response(),
+                }
+            }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        (endpoint, logins)
+    }
+
+    #[tokio::test]
+    async fn test_ended_sessions_are_renewed_and_retried_once() {
+        // MyCloud has forgotten the first session by the time it is used
+        let (endpoint, logins) = http_mycloud(2).await;
+        let integration = MyCloudIntegration::new(settings(&endpoint, 3600));
+
+        let shares = integration.monitor_shares().await.unwrap();
+        assert_eq!(shares[0].name, "Public");
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+
+        // The new session is kept while it is good
+        integration.monitor_shares().await.unwrap();
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+
+        // A session turned down again after logging in gives up rather than loops
+        let (endpoint, logins) = http_mycloud(u64::MAX).await;
+        let integration = MyCloudIntegration::new(settings(&endpoint, 3600));
+        assert!(integration.monitor_shares().await.is_err());
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_renewed_before_they_run_out() {
+        // Shorter than the renewal margin, so every request finds it due
+        let (endpoint, logins) = http_mycloud(1).await;
+        let integration = MyCloudIntegration::new(settings(&endpoint, 30));
+        integration.monitor_shares().await.unwrap();
+        integration.monitor_shares().await.unwrap();
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+
+        // Without a TTL only MyCloud turning it down ends a session
+        let (endpoint, logins) = http_mycloud(1).await;
+        let integration = MyCloudIntegration::new(settings(&endpoint, 0));
+        integration.monitor_shares().await.unwrap();
+        integration.monitor_shares().await.unwrap();
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+    }
+
+    fn account(username: &str, groups: &[&str], is_active: bool) -> MyCloudUser {
+        MyCloudUser {
+            username: username.to_string(),
+            email: Some(format!("{}@nas.local", username)),
+            full_name: None,
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+            is_admin: false,
+            is_active,
+            last_login: None,
+        }
+    }
+
+    fn share(name: &str, path: &str, permissions: &[&str], accessible_by: &[&str]) -> MyCloudShare {
+        MyCloudShare {
+            name: name.to_string(),
+            path: path.to_string(),
+            permissions: permissions.iter().map(|permission| permission.to_string()).collect(),
+            accessible_by: accessible_by.iter().map(|username| username.to_string()).collect(),
+        }
+    }
+
+    fn local_user(username: &str) -> User {
+        User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string()],
+        }
+    }
+
+    async fn test_database(dir: &std::path::Path) -> Database {
+        let url = format!("sqlite://{}?mode=rwc", dir.join("test.db").display());
+        Database::new(&url).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_nas_accounts_are_provisioned_and_kept_in_line() {
+        let dir = tempdir().unwrap();
+        let database = test_database(dir.path()).await;
+
+        // A local user sharing a NAS account's name is never taken over
+        database.create_user(&local_user("carol")).await.unwrap();
+
+        let nas = Arc::new(MockMyCloud::new(false));
+        for (username, group) in [("admin", "administrators"), ("alice", "users"), ("bob", "guests"), ("carol", "administrators")] {
+            nas.add_user(account(username, &[group], true), "password");
+        }
+        let filesystem = FileSystemService::new(dir.path().join("files"), 1024 * 1024).unwrap();
+        let config = MyCloudSettings { sync_users: true, ..settings("http://127.0.0.1:9", 3600) };
+        let sync = MyCloudSyncService::new(&config, nas.clone(), database.clone(), filesystem);
+
+        let summary = sync.sync_users().await.unwrap();
+        assert_eq!(summary, UserSyncSummary { created: 3, updated: 0, deactivated: 0 });
+        let alice = database.get_user_by_username("alice").await.unwrap().unwrap();
+        assert!(database.is_mycloud_user(alice.id).await.unwrap());
+        assert_eq!(alice.password_hash, NO_PASSWORD);
+        assert_eq!(alice.email.as_deref(), Some("alice@nas.local"));
+        assert_eq!(database.get_user_by_username("carol").await.unwrap().unwrap().permissions, vec!["read"]);
+
+        // Nothing changed on the NAS, nothing changes here
+        assert_eq!(sync.sync_users().await.unwrap(), UserSyncSummary::default());
+
+        // Bob moves group, alice is disabled and admin is removed
+        nas.add_user(account("alice", &["users"], false), "password");
+        nas.add_user(account("bob", &["users"], true), "password");
+        nas.remove_user("admin");
+        let summary = sync.sync_users().await.unwrap();
+        assert_eq!(summary, UserSyncSummary { created: 0, updated: 1, deactivated: 2 });
+        let bob = database.get_user_by_username("bob").await.unwrap().unwrap();
+        assert!(bob.permissions.contains(&"write".to_string()));
+        let alice = database.get_user_by_username("alice").await.unwrap().unwrap();
+        assert!(!alice.is_active);
+        assert_eq!(alice.auth_generation, 1);
+        assert!(!database.get_user_by_username("admin").await.unwrap().unwrap().is_active);
+        assert!(database.get_user_by_username("carol").await.unwrap().unwrap().is_active);
+
+        // Failing to list users leaves everyone as they are
+        nas.fail_next("list_users", 1);
+        assert!(sync.sync_users().await.is_err());
+
+        // An empty list is taken for a fault, not the end of every account
+        for username in ["alice", "bob", "carol"] {
+            nas.remove_user(username);
+        }
+        assert!(sync.sync_users().await.is_err());
+        assert!(database.get_user_by_username("bob").await.unwrap().unwrap().is_active);
+    }
+
+    #[tokio::test]
+    async fn test_nas_shares_are_shared_with_their_users() {
+        let dir = tempdir().unwrap();
+        let database = test_database(dir.path()).await;
+        let files = dir.path().join("files");
+        std::fs::create_dir_all(files.join("nas/Photos")).unwrap();
+        std::fs::create_dir_all(files.join("nas/Family")).unwrap();
+        std::fs::write(files.join("nas/notes.txt"), b"not a folder").unwrap();
+        let filesystem = FileSystemService::new(&files, 1024 * 1024).unwrap();
+
+        let (admin, alice, bob) = (local_user("admin"), local_user("alice"), local_user("bob"));
+        for user in [&admin, &alice, &bob] {
+            database.create_user(user).await.unwrap();
+        }
+
+        let nas = Arc::new(MockMyCloud::new(false));
+        nas.add_share(share("Photos", "/mnt/HD/HD_a2/Photos", &["read", "write"], &["admin", "alice", "bob", "dave"]));
+        nas.add_share(share("Family", "/mnt/HD/HD_a2/Family", &["read"], &["alice"]));
+        nas.add_share(share("Notes", "/mnt/HD/HD_a2/notes.txt", &["read"], &["bob"]));
+        nas.add_share(share("Backups", "/mnt/HD/HD_a2/Backups", &["read"], &["bob"]));
+        nas.add_share(share("Stick", "/mnt/USB/Stick", &["read"], &["bob"]));
+        let config = MyCloudSettings {
+            import_shares: true,
+            share_root: "/mnt/HD/HD_a2".to_string(),
+            share_folder: "/nas".to_string(),
+            ..settings("http://127.0.0.1:9", 3600)
         };
+        let sync = MyCloudSyncService::new(&config, nas.clone(), database.clone(), filesystem.clone());
+
+        let imports = sync.import_shares().await.unwrap();
+        assert_eq!(imports[0], ShareImport::Imported { name: "Photos".to_string(), path: "/nas/Photos".to_string(), grantees: 2 });
+        assert_eq!(imports[1], ShareImport::Imported { name: "Family".to_string(), path: "/nas/Family".to_string(), grantees: 1 });
+        // Missing folders, files and paths outside the root are left alone
+        for skipped in &imports[2..] {
+            assert!(matches!(skipped, ShareImport::Skipped { .. }));
+        }
 
-        let integration = MyCloudIntegration::new(config);
-        
+        let incoming = database.get_incoming_shares(alice.id).await.unwrap();
+        let paths: Vec<(&str, SharePermissions)> = incoming.iter().map(|s| (s.path.as_str(), s.share.permissions)).collect();
+        assert_eq!(paths, vec![("/nas/Family", SharePermissions::Read), ("/nas/Photos", SharePermissions::ReadWrite)]);
+        assert_eq!(incoming[0].owner, "admin");
+        assert_eq!(database.get_incoming_shares(bob.id).await.unwrap().len(), 1);
+
+        // Syncing again changes nothing
+        sync.import_shares().await.unwrap();
+        assert_eq!(database.get_incoming_shares(alice.id).await.unwrap().len(), 2);
+
+        // Bob is given Family by hand, then Family goes from the NAS and bob
+        // loses his place on Photos
+        let family = database.get_file_metadata_by_path(admin.id, "/nas/Family").await.unwrap().unwrap();
+        database.create_user_share(&UserShare {
+            id: Uuid::new_v4(),
+            file_id: family.id,
+            grantor: admin.id,
+            grantee: bob.id,
+            permissions: SharePermissions::Read,
+            created_at: Utc::now(),
+        }).await.unwrap();
+        nas.remove_share("Family");
+        nas.add_share(share("Photos", "/mnt/HD/HD_a2/Photos", &["read"], &["alice"]));
+        sync.import_shares().await.unwrap();
+
+        let incoming = database.get_incoming_shares(alice.id).await.unwrap();
+        assert_eq!(incoming.len(), 1);
+        assert_eq!((incoming[0].path.as_str(), incoming[0].share.permissions), ("/nas/Photos", SharePermissions::Read));
+        let incoming = database.get_incoming_shares(bob.id).await.unwrap();
+        assert_eq!(incoming.len(), 1);
+        assert_eq!(incoming[0].path, "/nas/Family");
+        assert!(files.join("nas/Family").is_dir());
+
+        // Without its owner there is nothing to import into
+        let config = MyCloudSettings { admin_username: "root".to_string(), ..config };
+        let orphaned = MyCloudSyncService::new(&config, nas.clone(), database.clone(), filesystem);
+        assert!(orphaned.import_shares().await.is_err());
+    }
+
+    #[test]
+    fn test_mycloud_user_mapping() {
         let mycloud_user = MyCloudUser {
             username: "testuser".to_string(),
             email: Some("test@example.com".to_string()),
@@ -345,7 +846,7 @@ mod tests {
             last_login: None,
         };
 
-        let user = integration.sync_user_to_local(&mycloud_user, "password_hash").await.unwrap();
+        let user = user_from_mycloud(&mycloud_user, "password_hash");
         
         assert_eq!(user.username, "testuser");
         assert_eq!(user.email, Some("test@example.com".to_string()));
@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use uuid::Uuid;
+
+use crate::config::NotificationSettings;
+use crate::database::Database;
+use crate::types::{ShareAccess, ShareAccessOutcome, ShareLink};
+
+// Each email is tried this often before it is given up on, waiting
+// RETRY_DELAY and then twice as long each time in between
+const SEND_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Most addresses one share link can be emailed to at once
+pub const MAX_RECIPIENTS: usize = 20;
+
+/// Emails share links over SMTP. Sending happens in the background; how it
+/// went ends up in the link's activity log.
+#[derive(Clone)]
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    public_url: String,
+    database: Database,
+}
+
+impl Mailer {
+    /// None unless `[notifications.smtp]` is set
+    pub fn new(settings: &NotificationSettings, database: Database) -> Result<Option<Self>> {
+        let Some(smtp) = &settings.smtp else {
+            return Ok(None);
+        };
+        let public_url = settings.public_url.clone()
+            .ok_or_else(|| anyhow!("Emails need notifications.public_url"))?;
+
+        let mut transport = if smtp.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host)
+        }
+        .port(smtp.port);
+        if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Some(Self {
+            transport: transport.build(),
+            from: smtp.from.parse().map_err(|e| anyhow!("Invalid SMTP from address: {}", e))?,
+            public_url: public_url.trim_end_matches('/').to_string(),
+            database,
+        }))
+    }
+
+    /// The public URL of a share link, by its shortest name
+    pub fn share_url(&self, share_link: &ShareLink) -> String {
+        let token = share_link.alias.as_ref()
+            .or(share_link.short_code.as_ref())
+            .unwrap_or(&share_link.share_token);
+        format!("{}/api/v1/share/{}", self.public_url, token)
+    }
+
+    /// Email a share link to each recipient without waiting for it
+    pub fn notify_share(&self, share_link: &ShareLink, sender: &str, file_name: &str, recipients: Vec<Mailbox>) {
+        let (subject, body) = share_email(sender, file_name, &self.share_url(share_link), share_link);
+
+        for recipient in recipients {
+            let mailer = self.clone();
+            let share_id = share_link.id;
+            let (subject, body) = (subject.clone(), body.clone());
+            tokio::spawn(async move {
+                let result = mailer.send(&recipient, &subject, &body).await;
+                let (outcome, detail) = match result {
+                    Ok(()) => (ShareAccessOutcome::EmailSent, recipient.email.to_string()),
+                    Err(e) => {
+                        tracing::warn!("Failed to email share {} to {}: {}", share_id, recipient.email, e);
+                        (ShareAccessOutcome::EmailFailed, format!("{}: {}", recipient.email, e))
+                    }
+                };
+                mailer.record(share_id, outcome, detail).await;
+            });
+        }
+    }
+
+    async fn send(&self, recipient: &Mailbox, subject: &str, body: &str) -> Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(recipient.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body.to_string())?;
+
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            match self.transport.send(message.clone()).await {
+                Ok(_) => return Ok(()),
+                // Permanent rejections, such as an unknown mailbox, won't get better
+                Err(e) if e.is_permanent() || attempt == SEND_ATTEMPTS => return Err(e.into()),
+                Err(e) => {
+                    tracing::debug!("Email to {} failed on attempt {}: {}", recipient.email, attempt, e);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn record(&self, share_id: Uuid, outcome: ShareAccessOutcome, detail: String) {
+        let access = ShareAccess {
+            id: Uuid::new_v4(),
+            share_id,
+            accessed_at: Utc::now(),
+            ip_address: None,
+            user_agent: None,
+            bytes_served: 0,
+            outcome,
+            detail: Some(detail),
+        };
+        if let Err(e) = self.database.record_share_access(&access).await {
+            tracing::warn!("Failed to record an email for share {}: {}", share_id, e);
+        }
+    }
+}
+
+/// Subject and body of the email announcing a share link. It says whether
+/// a password is needed but never what it is.
+pub fn share_email(sender: &str, file_name: &str, url: &str, share_link: &ShareLink) -> (String, String) {
+    let subject = format!("{} shared \"{}\" with you", sender, file_name);
+
+    let mut body = format!("{} shared \"{}\" with you:\n\n{}\n\n", sender, file_name, url);
+    match share_link.expires_at {
+        Some(expires_at) => body.push_str(&format!(
+            "The link works until {} UTC.\n",
+            expires_at.format("%Y-%m-%d %H:%M")
+        )),
+        None => body.push_str("The link doesn't expire.\n"),
+    }
+    if share_link.password_protected {
+        body.push_str(&format!("It needs a password, which {} will give you separately.\n", sender));
+    }
+
+    (subject, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_share_emails_never_carry_the_password() {
+        let share_link = ShareLink {
+            id: Uuid::new_v4(),
+            file_id: Uuid::new_v4(),
+            created_by: Uuid::new_v4(),
+            share_token: "token".to_string(),
+            expires_at: Some(Utc.with_ymd_and_hms(2026, 5, 1, 12, 30, 0).unwrap()),
+            password_protected: true,
+            download_count: 0,
+            max_downloads: None,
+            created_at: Utc::now(),
+            revoked_at: None,
+            password_hash: Some("sandcastle".to_string()),
+            short_code: Some("7HqX2mPd9K".to_string()),
+            alias: None,
+        };
+
+        let (subject, body) = share_email("alice", "beach.jpg", "https://nas.example.com/api/v1/share/7HqX2mPd9K", &share_link);
+        assert_eq!(subject, "alice shared \"beach.jpg\" with you");
+        assert!(body.contains("https://nas.example.com/api/v1/share/7HqX2mPd9K"));
+        assert!(body.contains("until 2026-05-01 12:30 UTC"));
+        assert!(body.contains("needs a password"));
+        assert!(!body.contains("sandcastle"));
+
+        let open = ShareLink { expires_at: None, password_protected: false, password_hash: None, ..share_link };
+        let (_, body) = share_email("alice", "beach.jpg", "https://nas.example.com/api/v1/share/x", &open);
+        assert!(body.contains("doesn't expire"));
+        assert!(!body.contains("password"));
+    }
+}
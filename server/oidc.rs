@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use openidconnect::core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata};
+use openidconnect::reqwest::async_http_client;
+use openidconnect::{
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, Scope, TokenResponse,
+};
+use tokio::sync::RwLock;
+
+use crate::config::OidcSettings;
+use crate::types::Permission;
+
+// A login has this long to come back from the provider
+const PENDING_LOGIN_TTL: Duration = Duration::from_secs(600);
+// Logins are started without logging in, so past this many in progress the
+// oldest are dropped to bound memory
+const MAX_PENDING_LOGINS: usize = 10_000;
+// Metadata and signing keys are fetched again after this, to follow key
+// rotation at the provider
+const METADATA_TTL: Duration = Duration::from_secs(3600);
+
+/// What a login sent to the provider needs when it comes back
+struct PendingLogin {
+    pkce_verifier: String,
+    nonce: String,
+    started: Instant,
+}
+
+/// Who the provider says logged in
+#[derive(Debug, Clone)]
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+    pub groups: Vec<String>,
+}
+
+/// Authorization code flow with PKCE against one OpenID Connect provider.
+/// Logins in progress are kept in memory, keyed by their `state`, so a
+/// restart in the middle of one means starting it over.
+#[derive(Clone)]
+pub struct OidcService {
+    settings: Arc<OidcSettings>,
+    client: Arc<RwLock<Option<(CoreClient, Instant)>>>,
+    pending: Arc<Mutex<HashMap<String, PendingLogin>>>,
+}
+
+impl OidcService {
+    pub fn new(settings: OidcSettings) -> Self {
+        Self {
+            settings: Arc::new(settings),
+            client: Arc::new(RwLock::new(None)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn settings(&self) -> &OidcSettings {
+        &self.settings
+    }
+
+    /// Where to send the browser to log in at the provider
+    pub async fn authorize_url(&self) -> Result<String> {
+        let client = self.client().await?;
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+        let (url, state, nonce) = client
+            .authorize_url(CoreAuthenticationFlow::AuthorizationCode, CsrfToken::new_random, Nonce::new_random)
+            .add_scope(Scope::new("email".to_string()))
+            .add_scope(Scope::new("profile".to_string()))
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, login| now.duration_since(login.started) < PENDING_LOGIN_TTL);
+        while pending.len() >= MAX_PENDING_LOGINS {
+            let oldest = pending.iter()
+                .min_by_key(|(_, login)| login.started)
+                .map(|(state, _)| state.clone());
+            match oldest {
+                Some(state) => pending.remove(&state),
+                None => break,
+            };
+        }
+        pending.insert(state.secret().clone(), PendingLogin {
+            pkce_verifier: pkce_verifier.secret().clone(),
+            nonce: nonce.secret().clone(),
+            started: now,
+        });
+
+        Ok(url.to_string())
+    }
+
+    /// Trade the code the provider sent back for an ID token and check it
+    /// against the provider's keys, the client ID and the login's nonce
+    pub async fn complete_login(&self, state: &str, code: &str) -> Result<OidcIdentity> {
+        // Taken out whatever happens next, so a state works only once
+        let pending = self.pending.lock().unwrap()
+            .remove(state)
+            .filter(|login| login.started.elapsed() < PENDING_LOGIN_TTL)
+            .ok_or_else(|| anyhow!("Unknown or expired login state"))?;
+
+        let client = self.client().await?;
+        let token_response = client
+            .exchange_code(AuthorizationCode::new(code.to_string()))
+            .set_pkce_verifier(PkceCodeVerifier::new(pending.pkce_verifier))
+            .request_async(async_http_client)
+            .await
+            .map_err(|e| anyhow!("Failed to exchange authorization code: {}", e))?;
+
+        let id_token = token_response.id_token()
+            .ok_or_else(|| anyhow!("Provider sent no ID token"))?;
+        let claims = id_token.claims(&client.id_token_verifier(), &Nonce::new(pending.nonce))
+            .map_err(|e| anyhow!("Invalid ID token: {}", e))?;
+
+        Ok(OidcIdentity {
+            issuer: claims.issuer().as_str().to_string(),
+            subject: claims.subject().as_str().to_string(),
+            email: claims.email().map(|email| email.as_str().to_string()),
+            email_verified: claims.email_verified().unwrap_or(false),
+            preferred_username: claims.preferred_username().map(|name| name.as_str().to_string()),
+            // Verified above, so reading the payload directly is safe
+            groups: string_claim(&id_token.to_string(), &self.settings.groups_claim),
+        })
+    }
+
+    /// The default permissions plus what each of the groups adds
+    pub fn permissions_for(&self, groups: &[String]) -> Vec<Permission> {
+        let mut permissions = self.settings.default_permissions.clone();
+
+        for group in groups {
+            for permission in self.settings.group_permissions.get(group).into_iter().flatten() {
+                if !permissions.contains(permission) {
+                    permissions.push(*permission);
+                }
+            }
+        }
+
+        permissions
+    }
+
+    async fn client(&self) -> Result<CoreClient> {
+        if let Some((client, fetched)) = &*self.client.read().await {
+            if fetched.elapsed() < METADATA_TTL {
+                return Ok(client.clone());
+            }
+        }
+
+        let issuer = IssuerUrl::new(self.settings.issuer_url.clone())?;
+        let metadata = CoreProviderMetadata::discover_async(issuer, async_http_client)
+            .await
+            .map_err(|e| anyhow!("OIDC discovery failed: {}", e))?;
+        let client = CoreClient::from_provider_metadata(
+            metadata,
+            ClientId::new(self.settings.client_id.clone()),
+            Some(ClientSecret::new(self.settings.client_secret.clone())),
+        )
+        .set_redirect_uri(RedirectUrl::new(self.settings.redirect_url.clone())?);
+
+        *self.client.write().await = Some((client.clone(), Instant::now()));
+        Ok(client)
+    }
+}
+
+/// A claim from a JWT's payload that holds one string or a list of them,
+/// which is how providers send groups. Missing or malformed gives none.
+fn string_claim(jwt: &str, claim: &str) -> Vec<String> {
+    let payload = jwt.split('.')
+        .nth(1)
+        .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+        .and_then(|payload| serde_json::from_slice::<serde_json::Value>(&payload).ok());
+
+    match payload.as_ref().and_then(|payload| payload.get(claim)) {
+        Some(serde_json::Value::String(value)) => vec![value.clone()],
+        Some(serde_json::Value::Array(values)) => values.iter()
+            .filter_map(|value| value.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn settings(issuer_url: &str) -> OidcSettings {
+        OidcSettings {
+            issuer_url: issuer_url.to_string(),
+            client_id: "synker".to_string(),
+            client_secret: "client-secret".to_string(),
+            redirect_url: "https://synker.example.com/api/v1/auth/oidc/callback".to_string(),
+            auto_provision: true,
+            link_by_email: false,
+            groups_claim: "groups".to_string(),
+            default_permissions: vec![Permission::Read],
+            group_permissions: HashMap::from([
+                ("synker-admin".to_string(), vec![Permission::Admin]),
+                ("editors".to_string(), vec![Permission::Write, Permission::Delete]),
+            ]),
+            post_login_redirect: None,
+        }
+    }
+
+    /// Serves just enough of a provider for discovery. Returns its issuer URL.
+    async fn mock_provider() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+        let metadata = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"]
+        });
+        let app = axum::Router::new()
+            .route("/.well-known/openid-configuration", axum::routing::get(move || async move { axum::Json(metadata) }))
+            .route("/jwks", axum::routing::get(|| async { axum::Json(json!({ "keys": [] })) }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        issuer
+    }
+
+    #[test]
+    fn test_groups_add_to_default_permissions() {
+        let service = OidcService::new(settings("https://auth.example.com"));
+
+        assert_eq!(service.permissions_for(&[]), vec![Permission::Read]);
+        assert_eq!(
+            service.permissions_for(&["editors".to_string(), "unknown".to_string(), "editors".to_string()]),
+            vec![Permission::Read, Permission::Write, Permission::Delete]
+        );
+        assert_eq!(
+            service.permissions_for(&["synker-admin".to_string()]),
+            vec![Permission::Read, Permission::Admin]
+        );
+    }
+
+    #[test]
+    fn test_groups_are_read_from_the_token_payload() {
+        let jwt = |payload: serde_json::Value| {
+            format!("header.{}.signature", URL_SAFE_NO_PAD.encode(payload.to_string()))
+        };
+
+        assert_eq!(string_claim(&jwt(json!({ "groups": ["a", "b"] })), "groups"), vec!["a", "b"]);
+        assert_eq!(string_claim(&jwt(json!({ "role": "admin" })), "role"), vec!["admin"]);
+        assert!(string_claim(&jwt(json!({ "sub": "x" })), "groups").is_empty());
+        assert!(string_claim("not a jwt", "groups").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_logins_carry_pkce_and_a_single_use_state() {
+        let service = OidcService::new(settings(&mock_provider().await));
+
+        let url = reqwest::Url::parse(&service.authorize_url().await.unwrap()).unwrap();
+        let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert!(url.path().ends_with("/authorize"));
+        assert_eq!(query["client_id"], "synker");
+        assert_eq!(query["code_challenge_method"], "S256");
+        assert!(query.contains_key("nonce"));
+
+        // A state the service never handed out is turned away before the provider is asked
+        assert!(service.complete_login("forged", "code").await.is_err());
+        assert!(service.pending.lock().unwrap().contains_key(&query["state"]));
+    }
+
+    #[tokio::test]
+    async fn test_pending_logins_are_capped() {
+        let service = OidcService::new(settings(&mock_provider().await));
+        let started = Instant::now();
+        {
+            let mut pending = service.pending.lock().unwrap();
+            for i in 0..MAX_PENDING_LOGINS {
+                pending.insert(format!("state-{}", i), PendingLogin {
+                    pkce_verifier: String::new(),
+                    nonce: String::new(),
+                    started,
+                });
+            }
+        }
+
+        let url = reqwest::Url::parse(&service.authorize_url().await.unwrap()).unwrap();
+        let state = url.query_pairs().find(|(key, _)| key == "state").unwrap().1.into_owned();
+        let pending = service.pending.lock().unwrap();
+        assert_eq!(pending.len(), MAX_PENDING_LOGINS);
+        assert!(pending.contains_key(&state));
+    }
+}
@@ -0,0 +1,194 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::config::AuthSettings;
+
+// Past this many tracked keys, full buckets are dropped to bound memory
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Slows down password guessing. Token buckets per source IP and per
+/// username cap the attempt rate, and a run of recent failures for one
+/// username locks it for a while, doubling with each further failure.
+#[derive(Clone)]
+pub struct LoginLimiter {
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    per_ip_per_minute: u32,
+    per_user_per_minute: u32,
+    lockout_threshold: u32,
+    lockout_base: Duration,
+    lockout_max: Duration,
+    lockout_window: Duration,
+}
+
+impl LoginLimiter {
+    pub fn new(settings: &AuthSettings) -> Self {
+        Self {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            per_ip_per_minute: settings.login_attempts_per_minute_per_ip,
+            per_user_per_minute: settings.login_attempts_per_minute_per_user,
+            lockout_threshold: settings.lockout_threshold,
+            lockout_base: Duration::seconds(settings.lockout_base_seconds as i64),
+            lockout_max: Duration::seconds(settings.lockout_max_seconds as i64),
+            lockout_window: Duration::seconds(settings.lockout_window_seconds as i64),
+        }
+    }
+
+    /// Take one attempt from the address's bucket and, when given, the
+    /// username's. On refusal, returns how many seconds until the emptier
+    /// bucket allows another.
+    pub fn check(&self, ip: &str, username: Option<&str>) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_TRACKED_KEYS {
+            let (per_ip, per_user) = (self.per_ip_per_minute, self.per_user_per_minute);
+            buckets.retain(|key, bucket| {
+                let limit = if key.starts_with("ip:") { per_ip } else { per_user };
+                refill(bucket, limit, now) < limit as f64
+            });
+        }
+
+        let mut keys = vec![(format!("ip:{}", ip), self.per_ip_per_minute)];
+        if let Some(username) = username {
+            keys.push((format!("user:{}", username.to_lowercase()), self.per_user_per_minute));
+        }
+
+        // Check both before taking from either, so one full bucket doesn't
+        // drain the other
+        let mut wait = 0.0_f64;
+        for (key, limit) in &keys {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket { tokens: *limit as f64, updated: now });
+            let tokens = refill(bucket, *limit, now);
+            if tokens < 1.0 {
+                wait = wait.max((1.0 - tokens) * 60.0 / *limit as f64);
+            }
+        }
+        if wait > 0.0 {
+            return Err(wait.ceil() as u64);
+        }
+
+        for (key, _) in &keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Failures before this no longer count towards a lockout
+    pub fn failures_since(&self) -> DateTime<Utc> {
+        Utc::now() - self.lockout_window
+    }
+
+    /// When a username with `failures` consecutive failures, the last at
+    /// `last_failure`, may try again; None if it isn't locked
+    pub fn locked_until(&self, failures: u32, last_failure: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+        if failures < self.lockout_threshold {
+            return None;
+        }
+        let last_failure = last_failure?;
+
+        // Capped doubling: an attacker trickling in bad guesses can keep an
+        // account slow, never shut for good
+        let doublings = (failures - self.lockout_threshold).min(16);
+        let cooldown = (self.lockout_base * 2_i32.pow(doublings)).min(self.lockout_max);
+
+        let until = last_failure + cooldown;
+        (until > Utc::now()).then_some(until)
+    }
+}
+
+/// Top a bucket up for the time since it was last touched
+fn refill(bucket: &mut Bucket, limit: u32, now: Instant) -> f64 {
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * limit as f64 / 60.0).min(limit as f64);
+    bucket.updated = now;
+    bucket.tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PasswordPolicy, PasswordScheme};
+
+    fn settings() -> AuthSettings {
+        AuthSettings {
+            jwt_secret: "test_secret".to_string(),
+            jwt_previous_secrets: Vec::new(),
+            jwt_issuer: "synker".to_string(),
+            jwt_audience: "synker".to_string(),
+            jwt_leeway_seconds: 60,
+            share_secret: "share_secret".to_string(),
+            share_previous_secrets: Vec::new(),
+            accept_legacy_share_tokens: true,
+            share_code_length: 10,
+            share_cleanup_interval_minutes: 60,
+            token_expiry_hours: 1,
+            refresh_token_expiry_days: 30,
+            password_scheme: PasswordScheme::Argon2id,
+            bcrypt_cost: 8,
+            argon2_memory_kib: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+            password_policy: PasswordPolicy::default(),
+            login_attempts_per_minute_per_ip: 10,
+            login_attempts_per_minute_per_user: 3,
+            lockout_threshold: 5,
+            lockout_base_seconds: 30,
+            lockout_max_seconds: 300,
+            lockout_window_seconds: 3600,
+            audit_retention_days: 90,
+            share_access_log_ips: true,
+            allow_anonymous_read: false,
+            anonymous_root: None,
+            oidc: None,
+        }
+    }
+
+    #[test]
+    fn test_buckets_limit_per_user_and_per_ip() {
+        let limiter = LoginLimiter::new(&settings());
+
+        for _ in 0..3 {
+            limiter.check("10.0.0.1", Some("alice")).unwrap();
+        }
+        let wait = limiter.check("10.0.0.1", Some("Alice")).unwrap_err();
+        assert!(wait > 0 && wait <= 20);
+
+        // Other users from the same address still get through, up to its limit
+        for name in ["bob", "carol", "dave", "erin", "frank", "grace", "heidi"] {
+            limiter.check("10.0.0.1", Some(name)).unwrap();
+        }
+        assert!(limiter.check("10.0.0.1", Some("ivan")).is_err());
+        limiter.check("10.0.0.2", Some("ivan")).unwrap();
+
+        // Leaving the username out only takes from the address
+        for _ in 0..10 {
+            limiter.check("10.0.0.3", None).unwrap();
+        }
+        assert!(limiter.check("10.0.0.3", None).is_err());
+        assert!(limiter.check("10.0.0.4", Some("alice")).is_err());
+    }
+
+    #[test]
+    fn test_lockout_doubles_up_to_the_cap() {
+        let limiter = LoginLimiter::new(&settings());
+        let now = Utc::now();
+
+        assert!(limiter.locked_until(4, Some(now)).is_none());
+        assert_eq!(limiter.locked_until(5, Some(now)), Some(now + Duration::seconds(30)));
+        assert_eq!(limiter.locked_until(7, Some(now)), Some(now + Duration::seconds(120)));
+        assert_eq!(limiter.locked_until(50, Some(now)), Some(now + Duration::seconds(300)));
+
+        // Once the cooldown has passed the next attempt is allowed
+        assert!(limiter.locked_until(5, Some(now - Duration::seconds(31))).is_none());
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::ErrorKind;
+use std::time::Duration;
+use chrono::Utc;
+use anyhow::{Result, anyhow};
+use uuid::Uuid;
+
+use crate::database::Database;
+use crate::filesystem::{FileSystemService, parent_path};
+use crate::handlers::{nearest_directory_owners, remove_unused_versions};
+use crate::types::{FileMetadata, ReconcileMode, ReconcileReport};
+
+// Entries this fresh may belong to an upload or move still in flight
+const GRACE_PERIOD_SECONDS: i64 = 60;
+
+// Pause between batches, so requests being served get the database in between
+const BATCH_PAUSE: Duration = Duration::from_millis(50);
+
+/// Compares what is on disk under `base_path` with `file_metadata`, e.g. after
+/// a crash, and adopts untracked entries or prunes rows whose files are gone.
+/// Works in small batches so it can run while the server is serving traffic.
+#[derive(Clone)]
+pub struct Reconciler {
+    database: Database,
+    filesystem: FileSystemService,
+    /// Username adopted entries outside any tracked folder go to by default
+    default_owner: String,
+    batch_size: u32,
+    grace_period: chrono::Duration,
+    enabled: bool,
+}
+
+impl Reconciler {
+    pub fn new(database: Database, filesystem: FileSystemService, default_owner: String, batch_size: u32) -> Self {
+        Self {
+            database,
+            filesystem,
+            default_owner,
+            batch_size: batch_size.max(1),
+            grace_period: chrono::Duration::seconds(GRACE_PERIOD_SECONDS),
+            enabled: true,
+        }
+    }
+
+    /// Refuse to run, for storage backends that keep file contents elsewhere
+    pub fn disabled(mut self) -> Self {
+        self.enabled = false;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Id of `username`, or of the configured owner when none is given
+    pub async fn resolve_owner(&self, username: Option<&str>) -> Result<Option<Uuid>> {
+        let username = username.unwrap_or(&self.default_owner);
+        Ok(self.database.get_user_by_username(username).await?.map(|user| user.id))
+    }
+
+    /// Check disk against the database. Adopted entries go to the owner of the
+    /// nearest tracked folder above them, or to `owner_id` at the top level.
+    pub async fn run(&self, mode: ReconcileMode, owner_id: Option<Uuid>) -> Result<ReconcileReport> {
+        if !self.enabled {
+            return Err(anyhow!("Reconciliation needs the local storage backend"));
+        }
+        if !self.filesystem.storage_root_exists("/") {
+            return Err(anyhow!("Storage directory is missing"));
+        }
+        if mode == ReconcileMode::Adopt && owner_id.is_none() {
+            return Err(anyhow!("Adopting untracked files needs an owner"));
+        }
+
+        tracing::info!("Reconciliation started in {:?} mode", mode);
+        let mut report = ReconcileReport::new(mode);
+        self.scan_disk(mode, owner_id, &mut report).await?;
+        self.scan_rows(mode, &mut report).await?;
+        tracing::info!("Reconciliation finished: {:?}", report);
+
+        Ok(report)
+    }
+
+    /// Walk the tree breadth first, so folders are adopted before their contents
+    async fn scan_disk(&self, mode: ReconcileMode, owner_id: Option<Uuid>, report: &mut ReconcileReport) -> Result<()> {
+        let mut directories = VecDeque::from(["/".to_string()]);
+        let mut batch = Vec::new();
+
+        while let Some(directory) = directories.pop_front() {
+            let entries = match self.filesystem.list_directory_fast(&directory).await {
+                Ok(entries) => entries,
+                // Removed since it was queued
+                Err(_) => continue,
+            };
+
+            for entry in entries {
+                if entry.is_directory {
+                    directories.push_back(entry.path.clone());
+                }
+                batch.push(entry);
+
+                if batch.len() >= self.batch_size as usize {
+                    self.check_entries(mode, owner_id, &mut batch, report).await?;
+                }
+            }
+        }
+
+        if !batch.is_empty() {
+            self.check_entries(mode, owner_id, &mut batch, report).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn check_entries(
+        &self,
+        mode: ReconcileMode,
+        owner_id: Option<Uuid>,
+        batch: &mut Vec<FileMetadata>,
+        report: &mut ReconcileReport,
+    ) -> Result<()> {
+        let paths: Vec<String> = batch.iter().map(|entry| entry.path.clone()).collect();
+        let tracked = self.database.get_tracked_kinds(&paths).await?;
+        let recent = Utc::now() - self.grace_period;
+        // Adopted rows are recorded together, but a folder's row has to be in
+        // before anything in it is adopted, for its owner and parent id
+        let mut adopted: Vec<FileMetadata> = Vec::new();
+        let mut adopted_folders: HashSet<String> = HashSet::new();
+
+        for entry in batch.drain(..) {
+            report.scanned_entries += 1;
+
+            match tracked.get(&entry.path) {
+                Some(&is_directory) if is_directory != entry.is_directory => {
+                    tracing::warn!(
+                        "{} is a {} on disk but a {} in the database",
+                        entry.path,
+                        kind(entry.is_directory),
+                        kind(is_directory)
+                    );
+                    report.conflicts.push(entry.path);
+                }
+                Some(_) => {}
+                None if entry.modified_at > recent => report.skipped += 1,
+                None => {
+                    tracing::info!("Untracked on disk: {}", entry.path);
+                    report.untracked += 1;
+
+                    if let (ReconcileMode::Adopt, Some(owner_id)) = (mode, owner_id) {
+                        if parent_path(&entry.path).is_some_and(|parent| adopted_folders.contains(parent)) {
+                            self.database.create_file_metadata_batch(&adopted).await?;
+                            adopted.clear();
+                            adopted_folders.clear();
+                        }
+                        if self.adopt(&entry.path, owner_id, &mut adopted).await? {
+                            report.adopted += 1;
+                            if entry.is_directory {
+                                adopted_folders.insert(entry.path);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if !adopted.is_empty() {
+            self.database.create_file_metadata_batch(&adopted).await?;
+        }
+
+        tracing::info!(
+            "Reconciliation checked {} entries on disk, {} untracked",
+            report.scanned_entries,
+            report.untracked
+        );
+        tokio::time::sleep(BATCH_PAUSE).await;
+
+        Ok(())
+    }
+
+    /// Rows for an untracked entry the way the change watcher records it,
+    /// added to `adopted` for the caller to insert
+    async fn adopt(&self, path: &str, fallback_owner: Uuid, adopted: &mut Vec<FileMetadata>) -> Result<bool> {
+        // Something may have recorded it since the batch was checked
+        if !self.database.get_file_metadata_for_path(path).await?.is_empty() {
+            return Ok(false);
+        }
+
+        let fresh = match self.filesystem.get_file_metadata(path).await {
+            Ok(fresh) => fresh,
+            Err(_) => return Ok(false),
+        };
+
+        let mut owners = nearest_directory_owners(&self.database, path).await?;
+        if owners.is_empty() {
+            owners.push(fallback_owner);
+        }
+
+        for owner_id in owners {
+            let mut metadata = fresh.clone();
+            metadata.id = Uuid::new_v4();
+            metadata.owner_id = owner_id;
+            metadata.modified_at = Utc::now();
+            metadata.parent_id = self.database.ensure_parent_directories(owner_id, path).await?;
+            adopted.push(metadata);
+        }
+
+        Ok(true)
+    }
+
+    async fn scan_rows(&self, mode: ReconcileMode, report: &mut ReconcileReport) -> Result<()> {
+        let mut after = None;
+
+        loop {
+            let rows = self.database.get_file_metadata_page(after, self.batch_size).await?;
+            let Some(last) = rows.last() else {
+                break;
+            };
+            after = Some(last.id);
+            let recent = Utc::now() - self.grace_period;
+
+            for row in rows {
+                report.scanned_rows += 1;
+
+                // Rows of trashed entries sit under the trash until the entry is purged
+                if self.filesystem.is_reserved_path(&row.path) || !self.is_missing(&row.path).await {
+                    continue;
+                }
+                if row.modified_at > recent {
+                    report.skipped += 1;
+                    continue;
+                }
+
+                tracing::info!("Missing from disk: {}", row.path);
+                report.dangling += 1;
+
+                if mode == ReconcileMode::Prune {
+                    // Rows below a pruned folder go with it
+                    let deleted = self.database.delete_metadata_under_path(row.owner_id, &row.path).await?;
+                    remove_unused_versions(&self.filesystem, &deleted).await;
+                    report.pruned += deleted.rows;
+                }
+            }
+
+            tracing::info!(
+                "Reconciliation checked {} rows, {} missing from disk",
+                report.scanned_rows,
+                report.dangling
+            );
+            tokio::time::sleep(BATCH_PAUSE).await;
+        }
+
+        Ok(())
+    }
+
+    /// Only a definite "not found" counts. Other errors, like a denied
+    /// symlink, or a mount whose drive is unplugged, leave the row alone.
+    async fn is_missing(&self, path: &str) -> bool {
+        if !self.filesystem.storage_root_exists(path) {
+            return false;
+        }
+
+        match self.filesystem.stat(path).await {
+            Ok(_) => false,
+            Err(e) => e
+                .downcast_ref::<std::io::Error>()
+                .map_or(false, |e| e.kind() == ErrorKind::NotFound),
+        }
+    }
+}
+
+fn kind(is_directory: bool) -> &'static str {
+    if is_directory { "folder" } else { "file" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{FilePermissions, User};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_adopts_untracked_files_and_prunes_dangling_rows() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let url = format!("sqlite://{}?mode=rwc", db_dir.path().join("test.db").display());
+        let database = Database::new(&url).await.unwrap();
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let user = User {
+            id: Uuid::new_v4(),
+            username: "admin".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["admin".to_string()],
+        };
+        database.create_user(&user).await.unwrap();
+
+        let mut folder = filesystem.create_directory("/docs").await.unwrap();
+        folder.owner_id = user.id;
+        folder.permissions = FilePermissions { read: true, write: true, delete: true, share: true };
+        database.create_file_metadata(&folder).await.unwrap();
+
+        // A row left behind by a crash, and files written without one
+        let mut gone = filesystem.save_file("/docs/gone.txt", b"lost").await.unwrap();
+        gone.owner_id = user.id;
+        gone.parent_id = Some(folder.id);
+        gone.permissions = folder.permissions.clone();
+        database.create_file_metadata(&gone).await.unwrap();
+        std::fs::remove_file(filesystem.get_absolute_path("/docs/gone.txt")).unwrap();
+
+        filesystem.save_file("/docs/orphan.txt", b"found").await.unwrap();
+        filesystem.save_file("/loose.txt", b"found").await.unwrap();
+
+        let mut reconciler = Reconciler::new(database.clone(), filesystem, "admin".to_string(), 1);
+        reconciler.grace_period = chrono::Duration::zero();
+        let owner_id = reconciler.resolve_owner(None).await.unwrap();
+
+        let report = reconciler.run(ReconcileMode::Report, owner_id).await.unwrap();
+        assert_eq!(report.untracked, 2);
+        assert_eq!(report.dangling, 1);
+        assert!(database.get_file_metadata(gone.id).await.unwrap().is_some());
+
+        let report = reconciler.run(ReconcileMode::Adopt, owner_id).await.unwrap();
+        assert_eq!(report.adopted, 2);
+        let orphan = database.get_file_metadata_by_path(user.id, "/docs/orphan.txt").await.unwrap().unwrap();
+        assert_eq!(orphan.parent_id, Some(folder.id));
+        assert!(database.get_file_metadata_by_path(user.id, "/loose.txt").await.unwrap().is_some());
+
+        let report = reconciler.run(ReconcileMode::Prune, owner_id).await.unwrap();
+        assert_eq!(report.untracked, 0);
+        assert_eq!(report.pruned, 1);
+        assert!(database.get_file_metadata(gone.id).await.unwrap().is_none());
+        assert!(report.conflicts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_adopted_folders_are_recorded_before_their_contents() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let url = format!("sqlite://{}?mode=rwc", db_dir.path().join("test.db").display());
+        let database = Database::new(&url).await.unwrap();
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let user = User {
+            id: Uuid::new_v4(),
+            username: "admin".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["admin".to_string()],
+        };
+        database.create_user(&user).await.unwrap();
+
+        // A whole library copied in behind the server's back, checked in one batch
+        for i in 0..20 {
+            filesystem.save_file(&format!("/photos/2023/{}.jpg", i), b"jpeg").await.unwrap();
+        }
+        filesystem.save_file("/photos/cover.jpg", b"jpeg").await.unwrap();
+
+        let mut reconciler = Reconciler::new(database.clone(), filesystem, "admin".to_string(), 100);
+        reconciler.grace_period = chrono::Duration::zero();
+        let report = reconciler.run(ReconcileMode::Adopt, Some(user.id)).await.unwrap();
+        assert_eq!(report.adopted, 23);
+
+        let photos = database.get_file_metadata_by_path(user.id, "/photos").await.unwrap().unwrap();
+        let year = database.get_file_metadata_by_path(user.id, "/photos/2023").await.unwrap().unwrap();
+        assert_eq!(year.parent_id, Some(photos.id));
+        let below = database.get_descendants(user.id, "/photos/2023").await.unwrap();
+        assert_eq!(below.len(), 20);
+        assert!(below.iter().all(|file| file.parent_id == Some(year.id) && file.owner_id == user.id));
+    }
+}
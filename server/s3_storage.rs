@@ -0,0 +1,293 @@
+use std::ops::Range;
+use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use mime_guess::from_path;
+use s3::{Bucket, Region, creds::Credentials};
+use tokio::fs as async_fs;
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::config::S3Settings;
+use crate::storage::{ByteStream, StagedUpload, StorageBackend};
+use crate::types::{DiskSpace, FileMetadata, FilePermissions};
+
+// Object metadata key holding the SHA-256 recorded at upload
+const CHECKSUM_METADATA: &str = "sha256";
+
+// Buffer between a download task and the response body
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Keeps file contents in an S3-compatible bucket such as MinIO. Directories
+/// are implied by object keys, so empty folders don't show up in listings.
+#[derive(Clone)]
+pub struct S3Backend {
+    bucket: Bucket,
+    /// Prepended to every key; empty or ending in '/'
+    prefix: String,
+    /// The configured prefix, before scoping to a user's home
+    root_prefix: String,
+    user_homes: bool,
+}
+
+impl S3Backend {
+    pub fn new(settings: &S3Settings) -> Result<Self> {
+        let region = Region::Custom {
+            region: settings.region.clone(),
+            endpoint: settings.endpoint.clone(),
+        };
+        let credentials = Credentials::new(
+            Some(&settings.access_key),
+            Some(&settings.secret_key),
+            None,
+            None,
+            None,
+        )?;
+
+        let mut bucket = Bucket::new(&settings.bucket, region, credentials)?;
+        if settings.path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        let prefix = match settings.path_prefix.trim_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        };
+
+        Ok(Self {
+            bucket,
+            root_prefix: prefix.clone(),
+            prefix,
+            user_homes: false,
+        })
+    }
+
+    /// Keep every user's files under `users/<user_id>/` below the prefix
+    pub fn with_user_homes(mut self, enabled: bool) -> Self {
+        self.user_homes = enabled;
+        self
+    }
+
+    fn key(&self, path: &str) -> String {
+        format!("{}{}", self.prefix, path.trim_matches('/'))
+    }
+
+    /// Key prefix shared by everything inside the directory at `path`
+    fn directory_prefix(&self, path: &str) -> String {
+        match path.trim_matches('/') {
+            "" => self.prefix.clone(),
+            path => format!("{}{}/", self.prefix, path),
+        }
+    }
+
+    fn path_of(&self, key: &str) -> String {
+        format!("/{}", key.strip_prefix(&self.prefix).unwrap_or(key).trim_end_matches('/'))
+    }
+
+    /// Every object key under the directory at `path`
+    async fn keys_under(&self, path: &str) -> Result<Vec<String>> {
+        let results = self.bucket.list(self.directory_prefix(path), None).await?;
+        Ok(results
+            .into_iter()
+            .flat_map(|result| result.contents)
+            .map(|object| object.key)
+            .collect())
+    }
+}
+
+fn parse_time(value: Option<&str>) -> DateTime<Utc> {
+    value
+        .and_then(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .or_else(|_| DateTime::parse_from_rfc2822(value))
+                .ok()
+        })
+        .map(|time| time.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now)
+}
+
+fn object_metadata(path: String, size: u64, checksum: String, modified_at: DateTime<Utc>, is_directory: bool) -> FileMetadata {
+    let mime_type = if is_directory {
+        "inode/directory".to_string()
+    } else {
+        from_path(&path).first_or_octet_stream().to_string()
+    };
+
+    FileMetadata {
+        id: Uuid::new_v4(),
+        name: path.rsplit('/').next().unwrap_or("").to_string(),
+        path,
+        size,
+        mime_type,
+        checksum,
+        created_at: modified_at,
+        modified_at,
+        owner_id: Uuid::nil(),
+        is_directory,
+        is_symlink: false,
+        parent_id: None,
+        permissions: FilePermissions {
+            read: true,
+            write: true,
+            delete: true,
+            share: true,
+        },
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    async fn put(&self, path: &str, upload: StagedUpload) -> Result<FileMetadata> {
+        // The checksum rides along as object metadata so it never has to be recomputed
+        let mut bucket = self.bucket.clone();
+        bucket.add_header(&format!("x-amz-meta-{}", CHECKSUM_METADATA), &upload.checksum);
+
+        let result = async {
+            let mut file = async_fs::File::open(&upload.temp_path).await?;
+            bucket.put_object_stream(&mut file, self.key(path)).await?;
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+
+        let (size, checksum) = (upload.size, upload.checksum.clone());
+        upload.discard().await;
+        result?;
+
+        Ok(object_metadata(path.to_string(), size, checksum, Utc::now(), false))
+    }
+
+    async fn get_stream(&self, path: &str, range: Option<Range<u64>>) -> Result<ByteStream> {
+        let key = self.key(path);
+        // Fails early for missing objects rather than midway through the body
+        self.bucket.head_object(&key).await.map_err(|_| anyhow!("File not found"))?;
+
+        // The bucket writes into one end of a pipe while the body reads the other
+        let (reader, mut writer) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+        let bucket = self.bucket.clone();
+        tokio::spawn(async move {
+            let result = match range {
+                Some(range) if range.start >= range.end => Ok(200),
+                Some(range) => {
+                    bucket.get_object_range_to_writer(&key, range.start, Some(range.end - 1), &mut writer).await
+                }
+                None => bucket.get_object_to_writer(&key, &mut writer).await,
+            };
+            if let Err(e) = result {
+                tracing::warn!("Failed to stream {} from the bucket: {}", key, e);
+            }
+        });
+
+        Ok(ReaderStream::with_capacity(reader, STREAM_BUFFER_SIZE).boxed())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let key = self.key(path);
+        if self.bucket.head_object(&key).await.is_ok() {
+            self.bucket.delete_object(&key).await?;
+            return Ok(());
+        }
+
+        let keys = self.keys_under(path).await?;
+        if keys.is_empty() {
+            return Err(anyhow!("File not found"));
+        }
+
+        for key in keys {
+            self.bucket.delete_object(&key).await?;
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        // S3 has no rename, so each object is copied and the original removed
+        let from_key = self.key(from);
+        if self.bucket.head_object(&from_key).await.is_ok() {
+            self.bucket.copy_object_internal(&from_key, self.key(to)).await?;
+            self.bucket.delete_object(&from_key).await?;
+            return Ok(());
+        }
+
+        let keys = self.keys_under(from).await?;
+        if keys.is_empty() {
+            return Err(anyhow!("Source file not found"));
+        }
+
+        let (from_prefix, to_prefix) = (self.directory_prefix(from), self.directory_prefix(to));
+        for key in keys {
+            let target = format!("{}{}", to_prefix, &key[from_prefix.len()..]);
+            self.bucket.copy_object_internal(&key, &target).await?;
+            self.bucket.delete_object(&key).await?;
+        }
+        Ok(())
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        let prefix = self.directory_prefix(path);
+        let results = self.bucket.list(prefix.clone(), Some("/".to_string())).await?;
+
+        let mut entries = Vec::new();
+        for result in results {
+            for common_prefix in result.common_prefixes.unwrap_or_default() {
+                entries.push(object_metadata(self.path_of(&common_prefix.prefix), 0, String::new(), Utc::now(), true));
+            }
+
+            // Checksums need a request per object, so listings leave them empty
+            for object in result.contents {
+                if object.key == prefix {
+                    continue;
+                }
+                let modified_at = parse_time(Some(&object.last_modified));
+                entries.push(object_metadata(self.path_of(&object.key), object.size, String::new(), modified_at, false));
+            }
+        }
+
+        entries.sort_by(|a, b| b.is_directory.cmp(&a.is_directory).then_with(|| a.name.cmp(&b.name)));
+        Ok(entries)
+    }
+
+    async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+        if let Ok((head, _)) = self.bucket.head_object(self.key(path)).await {
+            let checksum = head.metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get(CHECKSUM_METADATA))
+                .cloned()
+                .unwrap_or_default();
+            let size = head.content_length.unwrap_or(0).max(0) as u64;
+            let modified_at = parse_time(head.last_modified.as_deref());
+            return Ok(object_metadata(path.to_string(), size, checksum, modified_at, false));
+        }
+
+        let results = self.bucket.list(self.directory_prefix(path), Some("/".to_string())).await?;
+        let exists = results.iter().any(|result| {
+            !result.contents.is_empty() || result.common_prefixes.as_ref().map_or(false, |p| !p.is_empty())
+        });
+        if !exists {
+            return Err(anyhow!("File not found"));
+        }
+
+        Ok(object_metadata(path.to_string(), 0, String::new(), Utc::now(), true))
+    }
+
+    async fn free_space(&self) -> Result<DiskSpace> {
+        // Buckets have no fixed capacity to report
+        Ok(DiskSpace {
+            total: u64::MAX,
+            available: u64::MAX,
+            used: 0,
+        })
+    }
+
+    fn for_user(&self, user_id: Uuid) -> Result<Arc<dyn StorageBackend>> {
+        let mut backend = self.clone();
+        if self.user_homes {
+            backend.prefix = format!("{}users/{}/", self.root_prefix, user_id);
+        }
+        Ok(Arc::new(backend))
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+}
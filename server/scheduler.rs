@@ -0,0 +1,288 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::any::Any;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::types::JobStatus;
+
+/// Longest random delay added to a job's wait, so jobs registered together
+/// don't all hit the database at once. Jobs get a tenth of their interval
+/// when that is less.
+const MAX_JITTER: Duration = Duration::from_secs(60);
+
+type JobFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Runs named maintenance jobs in background at their intervals. Each job
+/// has a task of its own, so one failing or panicking doesn't stop the rest.
+#[derive(Clone)]
+pub struct Scheduler {
+    jobs: Arc<Mutex<Vec<Arc<Job>>>>,
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+struct Job {
+    name: String,
+    interval: Duration,
+    run: Box<dyn Fn() -> JobFuture + Send + Sync>,
+    trigger: Notify,
+    state: Mutex<JobState>,
+}
+
+#[derive(Default)]
+struct JobState {
+    running: bool,
+    runs: u64,
+    failures: u64,
+    last_started_at: Option<DateTime<Utc>>,
+    last_finished_at: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    next_run_at: Option<DateTime<Utc>>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        let (shutdown, _) = watch::channel(false);
+        Self {
+            jobs: Arc::new(Mutex::new(Vec::new())),
+            tasks: Arc::new(Mutex::new(Vec::new())),
+            shutdown: Arc::new(shutdown),
+        }
+    }
+
+    /// Run `job` every `interval`, the first time shortly after registering
+    pub fn register<F, Fut>(&self, name: &str, interval: Duration, job: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.add(name, interval, Duration::ZERO, job);
+    }
+
+    /// Run `job` every `interval`, the first time one interval after
+    /// registering
+    pub fn register_deferred<F, Fut>(&self, name: &str, interval: Duration, job: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.add(name, interval, interval, job);
+    }
+
+    fn add<F, Fut>(&self, name: &str, interval: Duration, first_delay: Duration, job: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let job = Arc::new(Job {
+            name: name.to_string(),
+            interval,
+            run: Box::new(move || Box::pin(job())),
+            trigger: Notify::new(),
+            state: Mutex::new(JobState::default()),
+        });
+        self.jobs.lock().unwrap().push(job.clone());
+
+        let task = tokio::spawn(schedule(job, first_delay, self.shutdown.subscribe()));
+        self.tasks.lock().unwrap().push(task);
+    }
+
+    /// Every job in the order registered
+    pub fn status(&self) -> Vec<JobStatus> {
+        self.jobs.lock().unwrap().iter().map(|job| job.status()).collect()
+    }
+
+    /// Run a job now rather than waiting for its turn. A job already running
+    /// goes again once it is done. None when there is no job by that name.
+    pub fn trigger(&self, name: &str) -> Option<JobStatus> {
+        let job = self.jobs.lock().unwrap().iter().find(|job| job.name == name).cloned()?;
+        job.trigger.notify_one();
+        Some(job.status())
+    }
+
+    /// Stop scheduling runs and wait for the ones under way to finish
+    pub async fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+        let tasks: Vec<JoinHandle<()>> = self.tasks.lock().unwrap().drain(..).collect();
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+/// Wait out the job's turns, or a trigger, and run it until shutdown
+async fn schedule(job: Arc<Job>, first_delay: Duration, mut shutdown: watch::Receiver<bool>) {
+    let mut delay = first_delay;
+    loop {
+        let wait = delay + jitter(job.interval);
+        delay = job.interval;
+        job.state.lock().unwrap().next_run_at = chrono::Duration::from_std(wait).ok().map(|wait| Utc::now() + wait);
+
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = job.trigger.notified() => {}
+            _ = shutdown.changed() => return,
+        }
+        job.run_once().await;
+    }
+}
+
+impl Job {
+    async fn run_once(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.running = true;
+            state.last_started_at = Some(Utc::now());
+            state.next_run_at = None;
+        }
+
+        // A run of its own, so a panic ends the run rather than the job
+        let outcome = match tokio::spawn((self.run)()).await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(e) if e.is_panic() => Err(format!("panicked: {}", panic_message(e.into_panic()))),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = &outcome {
+            tracing::error!("Job {} failed: {}", self.name, e);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        state.runs += 1;
+        state.last_finished_at = Some(Utc::now());
+        if outcome.is_err() {
+            state.failures += 1;
+        }
+        state.last_error = outcome.err();
+    }
+
+    fn status(&self) -> JobStatus {
+        let state = self.state.lock().unwrap();
+        JobStatus {
+            name: self.name.clone(),
+            interval_seconds: self.interval.as_secs(),
+            running: state.running,
+            runs: state.runs,
+            failures: state.failures,
+            last_started_at: state.last_started_at,
+            last_finished_at: state.last_finished_at,
+            last_error: state.last_error.clone(),
+            next_run_at: state.next_run_at,
+        }
+    }
+}
+
+/// A random wait of up to a tenth of `interval`, at most `MAX_JITTER`
+fn jitter(interval: Duration) -> Duration {
+    let max = (interval / 10).min(MAX_JITTER).as_millis() as u64;
+    if max == 0 {
+        return Duration::ZERO;
+    }
+    // Random bits are at hand in v4 UUIDs
+    Duration::from_millis(Uuid::new_v4().as_u64_pair().0 % max)
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn find(scheduler: &Scheduler, name: &str) -> JobStatus {
+        scheduler.status().into_iter().find(|job| job.name == name).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_failing_jobs_dont_stop_the_others() {
+        let scheduler = Scheduler::new();
+        let counted = Arc::new(AtomicU64::new(0));
+        let count = counted.clone();
+        let interval = Duration::from_millis(20);
+
+        scheduler.register("counts", interval, move || {
+            let count = count.clone();
+            async move {
+                count.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, anyhow::Error>(())
+            }
+        });
+        scheduler.register("fails", interval, || async { Err::<(), _>(anyhow::anyhow!("disk on fire")) });
+        scheduler.register("panics", interval, || async {
+            let state: Option<()> = None;
+            state.expect("bad state");
+            Ok::<_, anyhow::Error>(())
+        });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        scheduler.shutdown().await;
+
+        let counts = find(&scheduler, "counts");
+        assert!(counts.runs >= 2);
+        assert_eq!(counts.runs, counted.load(Ordering::SeqCst));
+        assert_eq!(counts.failures, 0);
+        assert!(counts.last_error.is_none());
+
+        let fails = find(&scheduler, "fails");
+        assert!(fails.runs >= 2);
+        assert_eq!(fails.failures, fails.runs);
+        assert_eq!(fails.last_error.as_deref(), Some("disk on fire"));
+
+        // Panicking doesn't end the job either
+        let panics = find(&scheduler, "panics");
+        assert!(panics.runs >= 2);
+        assert_eq!(panics.last_error.as_deref(), Some("panicked: bad state"));
+        assert!(!panics.running);
+    }
+
+    #[tokio::test]
+    async fn test_triggered_jobs_run_before_their_turn() {
+        let scheduler = Scheduler::new();
+        let counted = Arc::new(AtomicU64::new(0));
+        let count = counted.clone();
+        scheduler.register_deferred("hourly", Duration::from_secs(3600), move || {
+            let count = count.clone();
+            async move {
+                count.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, anyhow::Error>(())
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let waiting = find(&scheduler, "hourly");
+        assert_eq!(waiting.runs, 0);
+        assert!(waiting.next_run_at.unwrap() > Utc::now() + chrono::Duration::minutes(59));
+
+        assert!(scheduler.trigger("nightly").is_none());
+        assert!(scheduler.trigger("hourly").is_some());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(counted.load(Ordering::SeqCst), 1);
+
+        let ran = find(&scheduler, "hourly");
+        assert_eq!(ran.runs, 1);
+        assert!(ran.last_finished_at.is_some());
+
+        // Shutting down doesn't wait out the next turn
+        tokio::time::timeout(Duration::from_secs(1), scheduler.shutdown()).await.unwrap();
+    }
+}
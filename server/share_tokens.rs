@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Share ID, expiry as Unix seconds (0 for never), then the MAC
+const PAYLOAD_LEN: usize = 16 + 8;
+const MAC_LEN: usize = 32;
+
+const CODE_ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+pub const DEFAULT_CODE_LENGTH: usize = 10;
+/// Longest short code or alias. Signed tokens are always longer, so the
+/// two can't be mistaken for each other.
+pub const MAX_CODE_LENGTH: usize = 48;
+const MIN_ALIAS_LENGTH: usize = 3;
+
+/// What a valid share token says about its share
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedShare {
+    pub share_id: Uuid,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Signs share tokens, so forged or expired ones are turned away before the
+/// database is asked. The share row is still what a token is checked against
+/// for revocation and download counts.
+#[derive(Clone)]
+pub struct ShareTokens {
+    key: Vec<u8>,
+    /// Previous secrets, for links handed out before a rotation
+    previous_keys: Vec<Vec<u8>>,
+    accept_legacy: bool,
+    code_length: usize,
+}
+
+impl ShareTokens {
+    pub fn new(share_secret: &str) -> Self {
+        Self {
+            key: share_secret.as_bytes().to_vec(),
+            previous_keys: Vec::new(),
+            accept_legacy: false,
+            code_length: DEFAULT_CODE_LENGTH,
+        }
+    }
+
+    /// Still accept tokens signed with these. New ones use the current secret.
+    pub fn with_previous_secrets(mut self, share_secrets: &[String]) -> Self {
+        self.previous_keys = share_secrets.iter().map(|secret| secret.as_bytes().to_vec()).collect();
+        self
+    }
+
+    /// Also accept the plain UUID tokens of links made before tokens were signed
+    pub fn with_legacy_tokens(mut self, accept: bool) -> Self {
+        self.accept_legacy = accept;
+        self
+    }
+
+    /// Characters in new short codes, each worth almost six bits
+    pub fn with_code_length(mut self, code_length: usize) -> Self {
+        self.code_length = code_length;
+        self
+    }
+
+    pub fn accepts_legacy(&self) -> bool {
+        self.accept_legacy
+    }
+
+    pub fn sign(&self, share_id: Uuid, expires_at: Option<DateTime<Utc>>) -> String {
+        let mut token = share_id.as_bytes().to_vec();
+        token.extend_from_slice(&expires_at.map_or(0, |at| at.timestamp()).to_be_bytes());
+
+        let mac = mac(&self.key, &token).finalize().into_bytes();
+        token.extend_from_slice(&mac);
+        URL_SAFE_NO_PAD.encode(token)
+    }
+
+    /// A random base62 code for a short share URL. Unlike signed tokens it
+    /// carries nothing, so it is only as good as the share row it finds.
+    pub fn new_code(&self) -> String {
+        let mut code = String::with_capacity(self.code_length);
+        let mut bytes = [0u8; 32];
+        while code.len() < self.code_length {
+            OsRng.fill_bytes(&mut bytes);
+            // Bytes past the last whole multiple of 62 would favour some characters
+            for byte in bytes.iter().filter(|&&byte| (byte as usize) < CODE_ALPHABET.len() * 4) {
+                if code.len() == self.code_length {
+                    break;
+                }
+                code.push(CODE_ALPHABET[*byte as usize % CODE_ALPHABET.len()] as char);
+            }
+        }
+        code
+    }
+
+    /// The share a token was signed for, if the signature holds under the
+    /// current or a previous secret and it hasn't expired by `now`
+    pub fn verify(&self, token: &str, now: DateTime<Utc>) -> Result<SignedShare> {
+        let token = URL_SAFE_NO_PAD.decode(token)
+            .map_err(|_| anyhow!("Malformed share token"))?;
+        if token.len() != PAYLOAD_LEN + MAC_LEN {
+            return Err(anyhow!("Malformed share token"));
+        }
+
+        let (payload, signature) = token.split_at(PAYLOAD_LEN);
+        let signed = std::iter::once(&self.key)
+            .chain(&self.previous_keys)
+            .any(|key| mac(key, payload).verify_slice(signature).is_ok());
+        if !signed {
+            return Err(anyhow!("Invalid share token signature"));
+        }
+
+        let (share_id, expires_at) = payload.split_at(16);
+        let share_id = Uuid::from_slice(share_id)?;
+        let expires_at = match i64::from_be_bytes(expires_at.try_into()?) {
+            0 => None,
+            timestamp => Some(Utc.timestamp_opt(timestamp, 0).single()
+                .ok_or_else(|| anyhow!("Invalid share token expiry"))?),
+        };
+
+        if expires_at.is_some_and(|at| at <= now) {
+            return Err(anyhow!("Share token has expired"));
+        }
+
+        Ok(SignedShare { share_id, expires_at })
+    }
+}
+
+/// Plain UUID tokens of links made before tokens were signed
+pub fn is_legacy_token(token: &str) -> bool {
+    Uuid::parse_str(token).is_ok()
+}
+
+/// Short codes and aliases, as opposed to signed tokens
+pub fn is_short_token(token: &str) -> bool {
+    token.len() <= MAX_CODE_LENGTH
+        && !token.is_empty()
+        && token.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+}
+
+/// Aliases are lowercase letters, digits and inner hyphens, so they survive
+/// being read out or typed, and never look like a legacy token
+pub fn check_alias(alias: &str) -> Result<(), String> {
+    if alias.len() < MIN_ALIAS_LENGTH || alias.len() > MAX_CODE_LENGTH {
+        return Err(format!("Aliases must be {} to {} characters long", MIN_ALIAS_LENGTH, MAX_CODE_LENGTH));
+    }
+    if !alias.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-') {
+        return Err("Aliases may only contain lowercase letters, digits and hyphens".to_string());
+    }
+    if alias.starts_with('-') || alias.ends_with('-') || is_legacy_token(alias) {
+        return Err("Aliases can't start or end with a hyphen or look like a UUID".to_string());
+    }
+    Ok(())
+}
+
+fn mac(key: &[u8], payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(payload);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    const SECRET: &str = "share-secret-share-secret-share-secret";
+
+    #[test]
+    fn test_tokens_carry_the_share_and_its_expiry() {
+        let tokens = ShareTokens::new(SECRET);
+        let share_id = Uuid::new_v4();
+        let expires_at = Utc.timestamp_opt(Utc::now().timestamp() + 3600, 0).unwrap();
+
+        let token = tokens.sign(share_id, Some(expires_at));
+        assert!(!is_legacy_token(&token));
+        assert_eq!(
+            tokens.verify(&token, Utc::now()).unwrap(),
+            SignedShare { share_id, expires_at: Some(expires_at) }
+        );
+
+        // Expired tokens are rejected on their own
+        assert!(tokens.verify(&token, expires_at).is_err());
+
+        // Tokens without an expiry never run out
+        let token = tokens.sign(share_id, None);
+        assert_eq!(tokens.verify(&token, Utc::now() + Duration::days(3650)).unwrap().expires_at, None);
+    }
+
+    #[test]
+    fn test_forged_tokens_are_rejected() {
+        let tokens = ShareTokens::new(SECRET);
+        let token = tokens.sign(Uuid::new_v4(), None);
+
+        // Another share ID or a later expiry breaks the signature
+        let mut forged = URL_SAFE_NO_PAD.decode(&token).unwrap();
+        forged[0] ^= 1;
+        assert!(tokens.verify(&URL_SAFE_NO_PAD.encode(&forged), Utc::now()).is_err());
+
+        let other = ShareTokens::new("another-secret-another-secret-another");
+        assert!(other.verify(&token, Utc::now()).is_err());
+
+        assert!(tokens.verify("not a token", Utc::now()).is_err());
+        assert!(tokens.verify(&token[..token.len() - 4], Utc::now()).is_err());
+        assert!(is_legacy_token(&Uuid::new_v4().to_string()));
+    }
+
+    #[test]
+    fn test_short_codes_and_aliases() {
+        let tokens = ShareTokens::new(SECRET).with_code_length(12);
+        let code = tokens.new_code();
+        assert_eq!(code.len(), 12);
+        assert!(code.bytes().all(|byte| byte.is_ascii_alphanumeric()));
+        assert_ne!(code, tokens.new_code());
+        assert!(is_short_token(&code));
+
+        // Signed tokens never pass for short ones
+        assert!(!is_short_token(&tokens.sign(Uuid::new_v4(), None)));
+
+        assert!(check_alias("family-photos-2024").is_ok());
+        for alias in ["ab", "Family", "photos/2024", "-photos", "photos-", &"a".repeat(49)] {
+            assert!(check_alias(alias).is_err(), "{} was accepted", alias);
+        }
+        assert!(check_alias(&Uuid::new_v4().to_string()).is_err());
+    }
+
+    #[test]
+    fn test_links_survive_a_share_secret_rotation() {
+        let before = ShareTokens::new(SECRET);
+        let share_id = Uuid::new_v4();
+        let token = before.sign(share_id, None);
+
+        let after = ShareTokens::new("rotated-secret-rotated-secret-rotated")
+            .with_previous_secrets(&[SECRET.to_string()]);
+        assert_eq!(after.verify(&token, Utc::now()).unwrap().share_id, share_id);
+
+        // New tokens use the new secret
+        assert!(before.verify(&after.sign(share_id, None), Utc::now()).is_err());
+    }
+}
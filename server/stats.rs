@@ -0,0 +1,126 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::types::AdminStats;
+
+const SECONDS_PER_HOUR: i64 = 3600;
+/// Hours of downloads kept
+const DOWNLOAD_WINDOW_HOURS: i64 = 24;
+
+/// Downloads served in each of the last 24 hours. Kept in memory only, so
+/// the count starts over when the server restarts.
+#[derive(Clone, Default)]
+pub struct DownloadCounter {
+    hours: Arc<Mutex<VecDeque<(i64, u64)>>>,
+}
+
+impl DownloadCounter {
+    pub fn record(&self) {
+        self.record_at(Utc::now());
+    }
+
+    pub fn last_day(&self) -> u64 {
+        self.last_day_at(Utc::now())
+    }
+
+    fn record_at(&self, now: DateTime<Utc>) {
+        let hour = now.timestamp().div_euclid(SECONDS_PER_HOUR);
+        let mut hours = self.hours.lock().unwrap();
+        match hours.back_mut() {
+            Some((last, count)) if *last == hour => *count += 1,
+            _ => hours.push_back((hour, 1)),
+        }
+        while hours.front().is_some_and(|(first, _)| *first <= hour - DOWNLOAD_WINDOW_HOURS) {
+            hours.pop_front();
+        }
+    }
+
+    fn last_day_at(&self, now: DateTime<Utc>) -> u64 {
+        let hour = now.timestamp().div_euclid(SECONDS_PER_HOUR);
+        self.hours.lock().unwrap().iter()
+            .filter(|(at, _)| *at > hour - DOWNLOAD_WINDOW_HOURS)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// Counts the successful responses of the download routes it is layered on
+pub async fn count_downloads(
+    State(downloads): State<DownloadCounter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    if response.status().is_success() {
+        downloads.record();
+    }
+    response
+}
+
+/// Keeps the last admin statistics for a while, so a dashboard refreshing
+/// every few seconds doesn't recount the database each time
+#[derive(Clone)]
+pub struct StatsCache {
+    max_age: Duration,
+    latest: Arc<tokio::sync::Mutex<Option<(Instant, AdminStats)>>>,
+}
+
+impl StatsCache {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            latest: Arc::new(tokio::sync::Mutex::new(None)),
+        }
+    }
+
+    /// The kept figures while they are fresh enough, otherwise new ones from
+    /// `gather`. Requests arriving while it runs wait for its figures rather
+    /// than counting again.
+    pub async fn get_or_gather<F, Fut>(&self, gather: F) -> anyhow::Result<AdminStats>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<AdminStats>>,
+    {
+        let mut latest = self.latest.lock().await;
+        if let Some((at, stats)) = latest.as_ref() {
+            if at.elapsed() < self.max_age {
+                return Ok(AdminStats { from_cache: true, ..stats.clone() });
+            }
+        }
+
+        let stats = gather().await?;
+        *latest = Some((Instant::now(), stats.clone()));
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_downloads_older_than_a_day_drop_out() {
+        let counter = DownloadCounter::default();
+        let start = Utc.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap();
+
+        counter.record_at(start);
+        counter.record_at(start + chrono::Duration::minutes(30));
+        counter.record_at(start + chrono::Duration::hours(5));
+        assert_eq!(counter.last_day_at(start + chrono::Duration::hours(5)), 3);
+
+        // The first hour has left the window
+        assert_eq!(counter.last_day_at(start + chrono::Duration::hours(24)), 1);
+        counter.record_at(start + chrono::Duration::hours(30));
+        assert_eq!(counter.last_day_at(start + chrono::Duration::hours(30)), 1);
+        assert_eq!(counter.hours.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,379 @@
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use tokio::fs as async_fs;
+use uuid::Uuid;
+
+use crate::filesystem::FileSystemService;
+use crate::types::{DiskSpace, FileMetadata};
+
+/// File contents as they are read from a backend
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// An upload fully received into the temp directory, with its size and
+/// SHA-256 already known. Backends take ownership of the temp file.
+pub struct StagedUpload {
+    pub(crate) temp_path: PathBuf,
+    pub size: u64,
+    pub checksum: String,
+}
+
+impl StagedUpload {
+    /// Remove the temp file without storing it
+    pub async fn discard(self) {
+        if let Err(e) = async_fs::remove_file(&self.temp_path).await {
+            tracing::warn!("Failed to remove temp file {:?}: {}", self.temp_path, e);
+        }
+    }
+}
+
+/// Where file contents live. Paths are the API's paths ("/docs/a.txt"),
+/// already normalized and validated by the caller.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Store a staged upload at `path`, replacing whatever is there
+    async fn put(&self, path: &str, upload: StagedUpload) -> Result<FileMetadata>;
+
+    /// Stream a file's contents, or only the bytes in `range`
+    async fn get_stream(&self, path: &str, range: Option<Range<u64>>) -> Result<ByteStream>;
+
+    /// Remove a file, or a directory and everything in it
+    async fn delete(&self, path: &str) -> Result<()>;
+
+    /// Move a file or directory, creating the destination's parents
+    async fn rename(&self, from: &str, to: &str) -> Result<()>;
+
+    /// Entries directly inside a directory. Ignore rules are left to the caller.
+    async fn list(&self, path: &str) -> Result<Vec<FileMetadata>>;
+
+    async fn metadata(&self, path: &str) -> Result<FileMetadata>;
+
+    async fn free_space(&self) -> Result<DiskSpace>;
+
+    /// The same backend rooted at `user_id`'s home, when user homes are on
+    fn for_user(&self, user_id: Uuid) -> Result<Arc<dyn StorageBackend>>;
+
+    /// Whether contents are files on disk that the `FileSystemService` can
+    /// reach. Copies, thumbnails and versions still need that.
+    fn is_local(&self) -> bool;
+}
+
+/// The default backend: files under `base_path` and the configured mounts
+#[async_trait]
+impl StorageBackend for FileSystemService {
+    async fn put(&self, path: &str, upload: StagedUpload) -> Result<FileMetadata> {
+        self.commit_upload(path, upload).await
+    }
+
+    async fn get_stream(&self, path: &str, range: Option<Range<u64>>) -> Result<ByteStream> {
+        Ok(self.open_file_stream(path, range).await?.boxed())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        self.delete_file(path).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.move_file(from, to).await
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<FileMetadata>> {
+        self.including_ignored().list_directory_fast(path).await
+    }
+
+    async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+        self.get_file_metadata(path).await
+    }
+
+    async fn free_space(&self) -> Result<DiskSpace> {
+        self.get_disk_space_async().await
+    }
+
+    fn for_user(&self, user_id: Uuid) -> Result<Arc<dyn StorageBackend>> {
+        Ok(Arc::new(FileSystemService::for_user(self, user_id)?))
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// Total size of the files under `path`, walking the backend's listings
+pub async fn stored_size(storage: &dyn StorageBackend, path: &str) -> Result<u64> {
+    let mut total = 0;
+    let mut pending = vec![path.to_string()];
+
+    while let Some(directory) = pending.pop() {
+        for entry in storage.list(&directory).await? {
+            if entry.is_directory {
+                pending.push(entry.path);
+            } else {
+                total += entry.size;
+            }
+        }
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+pub mod memory {
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+    use anyhow::anyhow;
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+    use crate::types::FilePermissions;
+
+    struct StoredFile {
+        data: Bytes,
+        checksum: String,
+        modified_at: DateTime<Utc>,
+    }
+
+    /// Keeps files in memory, with directories implied by file paths the way
+    /// object stores do
+    #[derive(Default, Clone)]
+    pub struct MemoryBackend {
+        files: Arc<Mutex<BTreeMap<String, StoredFile>>>,
+    }
+
+    fn entry(path: &str, size: u64, checksum: &str, modified_at: DateTime<Utc>, is_directory: bool) -> FileMetadata {
+        FileMetadata {
+            id: Uuid::new_v4(),
+            name: path.rsplit('/').next().unwrap_or("").to_string(),
+            path: path.to_string(),
+            size,
+            mime_type: if is_directory {
+                "inode/directory".to_string()
+            } else {
+                mime_guess::from_path(path).first_or_octet_stream().to_string()
+            },
+            checksum: checksum.to_string(),
+            created_at: modified_at,
+            modified_at,
+            owner_id: Uuid::nil(),
+            is_directory,
+            is_symlink: false,
+            parent_id: None,
+            permissions: FilePermissions { read: true, write: true, delete: true, share: true },
+        }
+    }
+
+    fn prefix(path: &str) -> String {
+        format!("{}/", path.trim_end_matches('/'))
+    }
+
+    #[async_trait]
+    impl StorageBackend for MemoryBackend {
+        async fn put(&self, path: &str, upload: StagedUpload) -> Result<FileMetadata> {
+            let data = async_fs::read(&upload.temp_path).await?;
+            let checksum = upload.checksum.clone();
+            upload.discard().await;
+
+            let modified_at = Utc::now();
+            let size = data.len() as u64;
+            self.files.lock().unwrap().insert(
+                path.to_string(),
+                StoredFile { data: data.into(), checksum: checksum.clone(), modified_at },
+            );
+            Ok(entry(path, size, &checksum, modified_at, false))
+        }
+
+        async fn get_stream(&self, path: &str, range: Option<Range<u64>>) -> Result<ByteStream> {
+            let files = self.files.lock().unwrap();
+            let file = files.get(path).ok_or_else(|| anyhow!("File not found"))?;
+            let data = match range {
+                Some(range) => {
+                    let end = (range.end as usize).min(file.data.len());
+                    file.data.slice((range.start as usize).min(end)..end)
+                }
+                None => file.data.clone(),
+            };
+            Ok(futures_util::stream::once(async move { Ok(data) }).boxed())
+        }
+
+        async fn delete(&self, path: &str) -> Result<()> {
+            let mut files = self.files.lock().unwrap();
+            let before = files.len();
+            let prefix = prefix(path);
+            files.retain(|key, _| key != path && !key.starts_with(&prefix));
+            if files.len() == before {
+                return Err(anyhow!("File not found"));
+            }
+            Ok(())
+        }
+
+        async fn rename(&self, from: &str, to: &str) -> Result<()> {
+            let mut files = self.files.lock().unwrap();
+            let prefix = prefix(from);
+            let keys: Vec<String> = files.keys()
+                .filter(|key| *key == from || key.starts_with(&prefix))
+                .cloned()
+                .collect();
+            if keys.is_empty() {
+                return Err(anyhow!("Source file not found"));
+            }
+
+            for key in keys {
+                let file = files.remove(&key).unwrap();
+                files.insert(format!("{}{}", to, &key[from.len()..]), file);
+            }
+            Ok(())
+        }
+
+        async fn list(&self, path: &str) -> Result<Vec<FileMetadata>> {
+            let files = self.files.lock().unwrap();
+            let prefix = if path == "/" { "/".to_string() } else { prefix(path) };
+            let mut entries: Vec<FileMetadata> = Vec::new();
+
+            for (key, file) in files.range(prefix.clone()..) {
+                let Some(rest) = key.strip_prefix(&prefix) else { break };
+                match rest.split_once('/') {
+                    Some((directory, _)) => {
+                        let directory_path = format!("{}{}", prefix, directory);
+                        if entries.last().map_or(true, |last| last.path != directory_path) {
+                            entries.push(entry(&directory_path, 0, "", file.modified_at, true));
+                        }
+                    }
+                    None => entries.push(entry(key, file.data.len() as u64, &file.checksum, file.modified_at, false)),
+                }
+            }
+
+            Ok(entries)
+        }
+
+        async fn metadata(&self, path: &str) -> Result<FileMetadata> {
+            let files = self.files.lock().unwrap();
+            if let Some(file) = files.get(path) {
+                return Ok(entry(path, file.data.len() as u64, &file.checksum, file.modified_at, false));
+            }
+
+            let prefix = prefix(path);
+            files.iter()
+                .find(|(key, _)| key.starts_with(&prefix))
+                .map(|(_, file)| entry(path, 0, "", file.modified_at, true))
+                .ok_or_else(|| anyhow!("File not found"))
+        }
+
+        async fn free_space(&self) -> Result<DiskSpace> {
+            Ok(DiskSpace { total: u64::MAX, available: u64::MAX, used: 0 })
+        }
+
+        // Everyone shares one tree
+        fn for_user(&self, _user_id: Uuid) -> Result<Arc<dyn StorageBackend>> {
+            Ok(Arc::new(self.clone()))
+        }
+
+        fn is_local(&self) -> bool {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::TryStreamExt;
+    use sha2::Digest;
+    use tempfile::tempdir;
+    use crate::s3_storage::S3Backend;
+
+    async fn stage(filesystem: &FileSystemService, data: &[u8]) -> StagedUpload {
+        let mut upload = filesystem.begin_upload().await.unwrap();
+        upload.write_chunk(data).await.unwrap();
+        upload.finish().await.unwrap()
+    }
+
+    async fn read(storage: &dyn StorageBackend, path: &str, range: Option<Range<u64>>) -> Vec<u8> {
+        let chunks: Vec<Bytes> = storage.get_stream(path, range).await.unwrap().try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    /// Behaviour every backend has to share. `staging` provides temp files.
+    async fn check_backend(storage: &dyn StorageBackend, staging: &FileSystemService) {
+        let stored = storage.put("/docs/report.txt", stage(staging, b"quarterly report").await).await.unwrap();
+        assert_eq!(stored.size, 16);
+        assert_eq!(stored.checksum, format!("{:x}", sha2::Sha256::digest(b"quarterly report")));
+
+        let metadata = storage.metadata("/docs/report.txt").await.unwrap();
+        assert_eq!(metadata.size, 16);
+        assert_eq!(metadata.checksum, stored.checksum);
+        assert!(storage.metadata("/docs").await.unwrap().is_directory);
+        assert!(storage.metadata("/missing.txt").await.is_err());
+
+        assert_eq!(read(storage, "/docs/report.txt", None).await, b"quarterly report");
+        assert_eq!(read(storage, "/docs/report.txt", Some(10..16)).await, b"report");
+
+        storage.put("/docs/old/notes.txt", stage(staging, b"notes").await).await.unwrap();
+        let listed: Vec<(String, bool)> = storage.list("/docs").await.unwrap()
+            .into_iter()
+            .map(|entry| (entry.path, entry.is_directory))
+            .collect();
+        assert!(listed.contains(&("/docs/report.txt".to_string(), false)));
+        assert!(listed.contains(&("/docs/old".to_string(), true)));
+        assert_eq!(stored_size(storage, "/docs").await.unwrap(), 21);
+
+        storage.rename("/docs/old", "/archive/old").await.unwrap();
+        assert_eq!(read(storage, "/archive/old/notes.txt", None).await, b"notes");
+        assert!(storage.metadata("/docs/old/notes.txt").await.is_err());
+
+        storage.delete("/archive").await.unwrap();
+        assert!(storage.metadata("/archive/old/notes.txt").await.is_err());
+        storage.delete("/docs/report.txt").await.unwrap();
+        assert!(storage.metadata("/docs/report.txt").await.is_err());
+
+        assert!(storage.free_space().await.unwrap().available > 0);
+    }
+
+    #[tokio::test]
+    async fn test_local_backend() {
+        let storage_dir = tempdir().unwrap();
+        let temp_dir = tempdir().unwrap();
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024)
+            .unwrap()
+            .with_temp_directory(temp_dir.path());
+
+        check_backend(&filesystem, &filesystem).await;
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend() {
+        let storage_dir = tempdir().unwrap();
+        let staging = FileSystemService::new(storage_dir.path(), 1024).unwrap();
+
+        check_backend(&memory::MemoryBackend::default(), &staging).await;
+    }
+
+    /// Runs against a real bucket when SYNKER_TEST_S3_ENDPOINT is set, e.g.
+    /// a local MinIO container
+    #[tokio::test]
+    async fn test_s3_backend() {
+        let Ok(endpoint) = std::env::var("SYNKER_TEST_S3_ENDPOINT") else {
+            return;
+        };
+        let env = |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.to_string());
+
+        let storage = S3Backend::new(&crate::config::S3Settings {
+            endpoint,
+            region: env("SYNKER_TEST_S3_REGION", "us-east-1"),
+            bucket: env("SYNKER_TEST_S3_BUCKET", "synker-test"),
+            access_key: env("SYNKER_TEST_S3_ACCESS_KEY", "minioadmin"),
+            secret_key: env("SYNKER_TEST_S3_SECRET_KEY", "minioadmin"),
+            path_prefix: format!("test-{}", uuid::Uuid::new_v4()),
+            path_style: true,
+        })
+        .unwrap();
+
+        let storage_dir = tempdir().unwrap();
+        let staging = FileSystemService::new(storage_dir.path(), 1024).unwrap();
+        check_backend(&storage, &staging).await;
+    }
+}
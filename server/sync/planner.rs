@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::types::{ConflictReason, ManifestEntry, PlannedConflict, PlannedTransfer, SnapshotEntry, SyncPlan};
+
+/// How a path changed on the server since the client last synced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerChange {
+    /// Created there, or moved there
+    Added,
+    Modified,
+}
+
+/// Work out what the client with the files in `local` and the server with
+/// those in `server` have to send each other, without touching either.
+///
+/// A client tells which of its files it changed with their
+/// `base_checksum`. What changed on the server comes from `changes`, every
+/// path changed since the client's last sync, or none when it has no
+/// cursor; files on only one side are then taken to be new, and files that
+/// differ without a base are conflicts.
+pub fn plan(
+    mut local: Vec<ManifestEntry>,
+    server: Vec<SnapshotEntry>,
+    changes: Option<&HashMap<String, ServerChange>>,
+) -> SyncPlan {
+    let mut server: BTreeMap<String, SnapshotEntry> = server.into_iter()
+        .filter(|entry| !entry.is_directory)
+        .map(|entry| (entry.path.clone(), entry))
+        .collect();
+    // Some(None) for paths known not to have changed on the server
+    let server_change = |path: &str| changes.map(|changes| changes.get(path).copied());
+
+    let mut plan = SyncPlan::default();
+    local.sort_by(|a, b| a.path.cmp(&b.path));
+
+    for entry in local {
+        let base = entry.base_checksum.as_deref();
+        match server.remove(&entry.path) {
+            Some(remote) if remote.checksum == entry.checksum => plan.unchanged += 1,
+            Some(remote) => {
+                let changed_locally = base.map(|base| base != entry.checksum);
+                let changed_on_server = match base {
+                    Some(base) => Some(base != remote.checksum),
+                    None => server_change(&entry.path).map(|change| change.is_some()),
+                };
+                match (changed_locally, changed_on_server) {
+                    (_, Some(false)) => upload(&mut plan, entry),
+                    (Some(false), Some(true)) => download(&mut plan, remote),
+                    _ => plan.conflicts.push(PlannedConflict {
+                        path: entry.path,
+                        reason: ConflictReason::BothChanged,
+                        local_checksum: Some(entry.checksum),
+                        server_checksum: Some(remote.checksum),
+                    }),
+                }
+            }
+            None => match base {
+                None => upload(&mut plan, entry),
+                Some(base) if base == entry.checksum => plan.delete_local.push(entry.path),
+                Some(_) => plan.conflicts.push(PlannedConflict {
+                    path: entry.path,
+                    reason: ConflictReason::DeletedOnServer,
+                    local_checksum: Some(entry.checksum),
+                    server_checksum: None,
+                }),
+            },
+        }
+    }
+
+    // Whatever is left, the client doesn't have
+    for (path, remote) in server {
+        match server_change(&path) {
+            None | Some(Some(ServerChange::Added)) => download(&mut plan, remote),
+            // It had the file when it last synced, so it deleted it since
+            Some(None) => plan.delete_remote.push(path),
+            Some(Some(ServerChange::Modified)) => plan.conflicts.push(PlannedConflict {
+                path,
+                reason: ConflictReason::DeletedLocally,
+                local_checksum: None,
+                server_checksum: Some(remote.checksum),
+            }),
+        }
+    }
+
+    plan.download.sort_by(|a, b| a.path.cmp(&b.path));
+    plan.conflicts.sort_by(|a, b| a.path.cmp(&b.path));
+    plan
+}
+
+fn upload(plan: &mut SyncPlan, entry: ManifestEntry) {
+    plan.upload_bytes += entry.size;
+    plan.upload.push(PlannedTransfer { path: entry.path, size: entry.size, checksum: entry.checksum });
+}
+
+fn download(plan: &mut SyncPlan, entry: SnapshotEntry) {
+    plan.download_bytes += entry.size;
+    plan.download.push(PlannedTransfer { path: entry.path, size: entry.size, checksum: entry.checksum });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn local(path: &str, checksum: &str, base: Option<&str>) -> ManifestEntry {
+        ManifestEntry {
+            path: path.to_string(),
+            size: 10,
+            checksum: checksum.to_string(),
+            modified_at: Utc::now(),
+            base_checksum: base.map(str::to_string),
+        }
+    }
+
+    fn server(path: &str, checksum: &str) -> SnapshotEntry {
+        SnapshotEntry {
+            path: path.to_string(),
+            size: 20,
+            checksum: checksum.to_string(),
+            modified_at: Utc::now(),
+            is_directory: false,
+        }
+    }
+
+    fn paths(transfers: &[PlannedTransfer]) -> Vec<&str> {
+        transfers.iter().map(|transfer| transfer.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_bases_tell_which_side_changed() {
+        let plan = plan(
+            vec![
+                local("/same.txt", "a", Some("a")),
+                local("/edited-here.txt", "b2", Some("b1")),
+                local("/edited-there.txt", "c1", Some("c1")),
+                local("/edited-both.txt", "d2", Some("d1")),
+                local("/new-here.txt", "e", None),
+                local("/gone-there.txt", "f", Some("f")),
+                local("/edited-gone-there.txt", "g2", Some("g1")),
+            ],
+            vec![
+                server("/same.txt", "a"),
+                server("/edited-here.txt", "b1"),
+                server("/edited-there.txt", "c2"),
+                server("/edited-both.txt", "d3"),
+                server("/new-there.txt", "h"),
+            ],
+            None,
+        );
+
+        assert_eq!(plan.unchanged, 1);
+        assert_eq!(paths(&plan.upload), vec!["/edited-here.txt", "/new-here.txt"]);
+        assert_eq!(paths(&plan.download), vec!["/edited-there.txt", "/new-there.txt"]);
+        assert_eq!(plan.delete_local, vec!["/gone-there.txt"]);
+        assert!(plan.delete_remote.is_empty());
+        assert_eq!((plan.upload_bytes, plan.download_bytes), (20, 40));
+
+        let conflicts: Vec<_> = plan.conflicts.iter().map(|conflict| (conflict.path.as_str(), conflict.reason)).collect();
+        assert_eq!(conflicts, vec![
+            ("/edited-both.txt", ConflictReason::BothChanged),
+            ("/edited-gone-there.txt", ConflictReason::DeletedOnServer),
+        ]);
+    }
+
+    #[test]
+    fn test_server_changes_stand_in_for_missing_bases() {
+        let changes = HashMap::from([
+            ("/changed-there.txt".to_string(), ServerChange::Modified),
+            ("/added-there.txt".to_string(), ServerChange::Added),
+            ("/changed-gone-here.txt".to_string(), ServerChange::Modified),
+        ]);
+        let local_files = vec![
+            local("/changed-here.txt", "a2", None),
+            local("/changed-there.txt", "b2", None),
+        ];
+        let server_files = vec![
+            server("/changed-here.txt", "a1"),
+            server("/changed-there.txt", "b1"),
+            server("/added-there.txt", "c"),
+            server("/gone-here.txt", "d"),
+            server("/changed-gone-here.txt", "e"),
+        ];
+
+        let with_cursor = plan(local_files.clone(), server_files.clone(), Some(&changes));
+        assert_eq!(paths(&with_cursor.upload), vec!["/changed-here.txt"]);
+        assert_eq!(paths(&with_cursor.download), vec!["/added-there.txt"]);
+        assert_eq!(with_cursor.delete_remote, vec!["/gone-here.txt"]);
+        let conflicts: Vec<_> = with_cursor.conflicts.iter().map(|conflict| (conflict.path.as_str(), conflict.reason)).collect();
+        assert_eq!(conflicts, vec![
+            ("/changed-gone-here.txt", ConflictReason::DeletedLocally),
+            ("/changed-there.txt", ConflictReason::BothChanged),
+        ]);
+
+        // Without a cursor nothing is known to be deleted, and differences are conflicts
+        let without = plan(local_files, server_files, None);
+        assert!(without.upload.is_empty() && without.delete_remote.is_empty());
+        assert_eq!(paths(&without.download), vec!["/added-there.txt", "/changed-gone-here.txt", "/gone-here.txt"]);
+        assert_eq!(without.conflicts.len(), 2);
+    }
+}
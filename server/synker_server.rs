@@ -5,15 +5,38 @@ mod types;
 mod database;
 mod auth;
 mod filesystem;
+mod hashing;
+mod delta;
 mod handlers;
 mod config;
 mod mycloud;
+#[cfg(any(test, debug_assertions))]
+mod mock_mycloud;
+mod thumbnails;
+mod integrity;
+mod reconcile;
+mod watcher;
+mod storage;
+mod s3_storage;
+mod two_factor;
+mod rate_limit;
+mod oidc;
+mod share_tokens;
+mod audit;
+mod notifications;
+mod webhooks;
+mod bandwidth;
+mod sync;
+mod activity;
+mod stats;
+mod backup;
+mod scheduler;
 
 use axum::{
-    extract::DefaultBodyLimit,
+    extract::{DefaultBodyLimit, State},
     http::{StatusCode, Method},
     middleware,
-    routing::{get, post, delete, put},
+    routing::{get, post, delete, patch, put},
     Router,
 };
 use tower::ServiceBuilder;
@@ -28,14 +51,35 @@ use anyhow::Result;
 use std::sync::Arc;
 
 use crate::{
-    auth::{AuthService, auth_middleware},
-    database::Database,
-    filesystem::FileSystemService,
-    config::ServerConfig,
-    mycloud::{MyCloudIntegration, MyCloudSyncService},
+    auth::{ActivityThrottle, AnonymousAccess, AuthService, AuthState, auth_middleware, reject_anonymous, require_permission},
+    two_factor::TwoFactorService,
+    share_tokens::ShareTokens,
+    rate_limit::LoginLimiter,
+    database::{Database, is_pool_timeout},
+    filesystem::{FileSystemService, normalize_path},
+    thumbnails::ThumbnailService,
+    integrity::IntegrityScanner,
+    reconcile::Reconciler,
+    watcher::ChangeWatcher,
+    storage::StorageBackend,
+    s3_storage::S3Backend,
+    config::{ServerConfig, StorageBackendKind},
+    types::{AuditEvent, Permission, ReconcileMode},
+    mycloud::{MyCloudApi, MyCloudIntegration, MyCloudSyncService},
+    oidc::OidcService,
+    audit::{audit_changes, AuditRoutes, ShareAccessLog},
+    notifications::Mailer,
+    webhooks::Webhooks,
+    bandwidth::{throttle_transfers, Bandwidth},
+    stats::{count_downloads, DownloadCounter, StatsCache},
+    backup::{write_backup, Backups},
+    scheduler::Scheduler,
     handlers::*,
 };
 
+/// How long login attempts are kept for auditing
+const LOGIN_ATTEMPT_RETENTION_DAYS: i64 = 30;
+
 #[derive(Parser, Debug)]
 #[command(name = "synker-server")]
 #[command(about = "Self-hosted cloud storage server for MyCloud OS5")]
@@ -55,14 +99,65 @@ struct Args {
     /// Create initial admin user
     #[arg(long)]
     create_admin: bool,
+
+    /// Compare storage with the database (report, adopt or prune) and exit
+    #[arg(long, value_name = "MODE")]
+    reconcile: Option<ReconcileMode>,
+
+    /// Move the files of a flat deployment into the admin user's home and exit
+    #[arg(long)]
+    migrate_user_homes: bool,
+
+    /// Index the names of all files for search again and exit
+    #[arg(long)]
+    rebuild_search_index: bool,
+
+    /// Write a consistent copy of the database to PATH and exit; gzipped
+    /// when PATH ends in .gz
+    #[arg(long, value_name = "PATH")]
+    backup: Option<std::path::PathBuf>,
+
+    /// Answer MyCloud calls from memory instead of the NAS, for development.
+    /// Debug builds only.
+    #[cfg(debug_assertions)]
+    #[arg(long)]
+    mock_mycloud: bool,
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub database: Database,
     pub filesystem: FileSystemService,
+    pub storage: Arc<dyn StorageBackend>,
+    pub thumbnails: ThumbnailService,
+    pub integrity: IntegrityScanner,
+    pub reconciler: Reconciler,
     pub auth_service: AuthService,
-    pub mycloud: Arc<MyCloudIntegration>,
+    pub two_factor: TwoFactorService,
+    pub share_tokens: ShareTokens,
+    pub share_access: ShareAccessLog,
+    pub login_limiter: LoginLimiter,
+    pub mycloud: Arc<dyn MyCloudApi>,
+    /// Set when `[auth.oidc]` is configured
+    pub oidc: Option<OidcService>,
+    /// Set when `auth.allow_anonymous_read` is on
+    pub anonymous: Option<AnonymousAccess>,
+    /// Set when `[notifications.smtp]` is configured
+    pub mailer: Option<Mailer>,
+    pub webhooks: Webhooks,
+    pub long_poll: LongPollLimit,
+    pub pages: PageLimits,
+    pub sync_page: SyncPageLimit,
+    pub sync_plan: SyncPlanLimit,
+    pub delta: DeltaSettings,
+    pub push_limits: PushLimits,
+    pub upload_limits: UploadLimits,
+    pub conflicts: ConflictSettings,
+    pub bandwidth: Bandwidth,
+    pub downloads: DownloadCounter,
+    pub stats: StatsCache,
+    pub backups: Backups,
+    pub scheduler: Scheduler,
 }
 
 #[tokio::main]
@@ -87,27 +182,80 @@ async fn main() -> Result<()> {
     tracing::info!("Configuration loaded from: {}", args.config);
 
     // Initialize database
-    let database = Database::new(&config.database.url).await?;
+    let database = Database::connect(&config.database).await?;
     tracing::info!("Database connected: {}", config.database.url);
 
+    report_unnormalized_paths(&database).await?;
+
     if args.init_db {
         tracing::info!("Database initialized successfully");
         return Ok(());
     }
 
+    if args.rebuild_search_index {
+        database.rebuild_search_index().await?;
+        tracing::info!("Search index rebuilt");
+        return Ok(());
+    }
+
+    if let Some(path) = &args.backup {
+        let compress = path.extension().is_some_and(|extension| extension == "gz");
+        let info = write_backup(&database, path, compress).await?;
+        tracing::info!("Backup written to {:?} ({} bytes, sha256 {})", path, info.size, info.checksum);
+        return Ok(());
+    }
+
     // Initialize filesystem service
     let filesystem = FileSystemService::new(
         &config.filesystem.base_path,
         config.filesystem.max_file_size_mb * 1024 * 1024, // Convert MB to bytes
-    )?;
+    )?
+    .with_temp_directory(&config.filesystem.temp_directory)
+    .with_stream_threshold(config.filesystem.stream_threshold_bytes)
+    .with_allowed_extensions(
+        config.filesystem.allowed_extensions.clone(),
+        config.filesystem.allow_no_extension,
+    )
+    .with_version_retention(
+        config.filesystem.max_versions_per_file,
+        config.filesystem.max_version_storage_mb * 1024 * 1024,
+    )
+    .with_max_copy_size(config.filesystem.max_copy_size_mb * 1024 * 1024)
+    .with_deduplication(config.filesystem.deduplicate_files)
+    .with_symlink_policy(config.filesystem.symlink_policy)
+    .with_default_quota(match config.filesystem.default_quota_mb {
+        0 => None,
+        mb => Some(mb * 1024 * 1024),
+    })
+    .with_mounts(
+        config.filesystem.mounts
+            .iter()
+            .map(|mount| (mount.name.clone(), mount.path.clone()))
+            .collect(),
+    )?
+    .with_ignore_patterns(config.filesystem.ignore_patterns.clone())?
+    .with_user_homes(config.filesystem.user_homes);
     tracing::info!("Filesystem service initialized: {:?}", config.filesystem.base_path);
 
+    let storage: Arc<dyn StorageBackend> = match (config.storage.backend, &config.storage.s3) {
+        (StorageBackendKind::S3, Some(s3)) => {
+            tracing::info!("Storing file contents in bucket {} at {}", s3.bucket, s3.endpoint);
+            Arc::new(S3Backend::new(s3)?.with_user_homes(config.filesystem.user_homes))
+        }
+        _ => Arc::new(filesystem.clone()),
+    };
+
+    let thumbnails = ThumbnailService::new(
+        config.filesystem.temp_directory.join("thumbnails"),
+        config.filesystem.thumbnail_max_source_dimension,
+    );
+
     // Initialize auth service
-    let auth_service = AuthService::new(&config.auth.jwt_secret);
+    let auth_service = AuthService::from_settings(&config.auth);
     tracing::info!("Authentication service initialized");
 
     // Initialize MyCloud integration
-    let mycloud = Arc::new(MyCloudIntegration::new(config.mycloud.clone()));
+    let mycloud = mycloud_api(&args, &config);
     tracing::info!("MyCloud integration initialized");
 
     // Create admin user if requested
@@ -116,23 +264,320 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    let integrity = IntegrityScanner::new(
+        database.clone(),
+        filesystem.clone(),
+        config.filesystem.integrity_scan_files_per_cycle,
+        config.filesystem.integrity_scan_rate_mb_per_sec * 1024 * 1024,
+    );
+
+    let mut reconciler = Reconciler::new(
+        database.clone(),
+        filesystem.clone(),
+        config.filesystem.reconcile_owner.clone(),
+        config.filesystem.reconcile_batch_size,
+    );
+    // Both compare paths under base_path with rows without knowing about homes
+    if config.storage.backend != StorageBackendKind::Local || config.filesystem.user_homes {
+        reconciler = reconciler.disabled();
+    }
+
+    if args.migrate_user_homes {
+        migrate_user_homes(&database, &filesystem, &config).await?;
+        return Ok(());
+    }
+
+    if config.filesystem.user_homes
+        && config.storage.backend == StorageBackendKind::Local
+        && filesystem.has_flat_content()?
+    {
+        tracing::warn!(
+            "Files directly under {:?} are not in any user's home; move them with --migrate-user-homes",
+            config.filesystem.base_path
+        );
+    }
+
+    if let Some(mode) = args.reconcile {
+        run_reconciliation(&reconciler, mode, &config).await?;
+        return Ok(());
+    }
+
     // Create app state
+    let mailer = Mailer::new(&config.notifications, database.clone())?;
+    if mailer.is_some() {
+        tracing::info!("Share links can be emailed");
+    }
+
+    let webhooks = Webhooks::new(&config.webhooks, database.clone());
+
     let app_state = AppState {
+        mailer,
+        webhooks,
+        share_access: ShareAccessLog::new(database.clone())
+            .with_ip_capture(config.auth.share_access_log_ips),
+        backups: Backups::new(database.clone(), &config.backup)
+            .with_temp_directory(&config.filesystem.temp_directory),
         database,
         filesystem,
+        storage,
+        thumbnails,
+        integrity,
+        reconciler,
         auth_service: auth_service.clone(),
+        two_factor: TwoFactorService::new(&config.auth.jwt_secret)
+            .with_previous_secrets(&config.auth.jwt_previous_secrets),
+        share_tokens: ShareTokens::new(&config.auth.share_secret)
+            .with_previous_secrets(&config.auth.share_previous_secrets)
+            .with_legacy_tokens(config.auth.accept_legacy_share_tokens)
+            .with_code_length(config.auth.share_code_length),
+        login_limiter: LoginLimiter::new(&config.auth),
         mycloud,
+        oidc: config.auth.oidc.clone().map(OidcService::new),
+        anonymous: config.auth.allow_anonymous_read
+            .then(|| config.auth.anonymous_root.clone())
+            .flatten()
+            .map(|root| AnonymousAccess { root }),
+        long_poll: LongPollLimit(std::time::Duration::from_secs(config.server.request_timeout_seconds)),
+        pages: PageLimits {
+            default_size: config.server.default_page_size,
+            max_size: config.server.max_page_size,
+        },
+        sync_page: SyncPageLimit(config.filesystem.sync_max_page_size),
+        sync_plan: SyncPlanLimit(config.filesystem.sync_plan_max_entries),
+        delta: DeltaSettings {
+            block_size: config.filesystem.delta_block_size,
+            min_file_size: config.filesystem.delta_min_file_size_kb * 1024,
+        },
+        push_limits: PushLimits {
+            max_operations: config.filesystem.push_max_operations,
+            max_payload_bytes: config.filesystem.push_max_payload_mb * 1024 * 1024,
+            max_inline_bytes: config.filesystem.push_max_inline_kb * 1024,
+        },
+        upload_limits: UploadLimits {
+            min_chunk_size: config.filesystem.upload_min_chunk_kb * 1024,
+            max_chunk_size: config.filesystem.upload_max_chunk_mb * 1024 * 1024,
+            max_chunks: config.filesystem.upload_max_chunks,
+        },
+        conflicts: ConflictSettings::new(&config.sync)?,
+        bandwidth: Bandwidth::new(&config.server)?,
+        downloads: DownloadCounter::default(),
+        stats: StatsCache::new(std::time::Duration::from_secs(config.server.stats_cache_seconds)),
+        scheduler: Scheduler::new(),
     };
 
-    // Start MyCloud sync service in background
-    let mycloud_sync_config = config.mycloud.clone();
-    tokio::spawn(async move {
-        let mut sync_service = MyCloudSyncService::new(mycloud_sync_config);
-        if let Err(e) = sync_service.start().await {
-            tracing::error!("MyCloud sync service error: {}", e);
+    // Tokens revoked before a restart must stay revoked
+    refresh_revocations(&app_state.database, &app_state.auth_service).await?;
+
+    // Maintenance runs on the scheduler, which admins can inspect and prod
+    // through /api/v1/admin/jobs
+    let scheduler = app_state.scheduler.clone();
+    let hourly = std::time::Duration::from_secs(3600);
+
+    // Keep the in-memory revocation list in step with the database
+    let revocation_database = app_state.database.clone();
+    let revocation_auth = app_state.auth_service.clone();
+    scheduler.register("token-revocations", std::time::Duration::from_secs(60), move || {
+        let (database, auth_service) = (revocation_database.clone(), revocation_auth.clone());
+        async move { refresh_revocations(&database, &auth_service).await }
+    });
+
+    // Sync with MyCloud
+    let mycloud_sync = MyCloudSyncService::new(
+        &config.mycloud,
+        app_state.mycloud.clone(),
+        app_state.database.clone(),
+        app_state.filesystem.clone(),
+    );
+    let mycloud_interval = mycloud_sync.interval();
+    let mycloud_sync = Arc::new(mycloud_sync);
+    scheduler.register("mycloud-sync", mycloud_interval, move || {
+        let sync_service = mycloud_sync.clone();
+        async move { sync_service.sync_cycle().await }
+    });
+
+    // Garbage collect abandoned upload sessions
+    let gc_database = app_state.database.clone();
+    let gc_filesystem = app_state.filesystem.clone();
+    let upload_session_ttl = chrono::Duration::hours(config.filesystem.upload_session_ttl_hours as i64);
+    scheduler.register("upload-session-cleanup", hourly, move || {
+        let (database, filesystem) = (gc_database.clone(), gc_filesystem.clone());
+        async move { purge_stale_upload_sessions(&database, &filesystem, upload_session_ttl).await }
+    });
+
+    // Purge expired trash entries
+    let trash_database = app_state.database.clone();
+    let trash_filesystem = app_state.filesystem.clone();
+    let trash_storage = app_state.storage.clone();
+    let trash_retention = chrono::Duration::days(config.filesystem.trash_retention_days as i64);
+    scheduler.register("trash-purge", hourly, move || {
+        let (database, filesystem, storage) = (trash_database.clone(), trash_filesystem.clone(), trash_storage.clone());
+        async move { purge_expired_trash(&database, &filesystem, storage.as_ref(), trash_retention).await }
+    });
+
+    // Forget old login attempts
+    let attempts_database = app_state.database.clone();
+    scheduler.register("login-attempt-cleanup", hourly, move || {
+        let database = attempts_database.clone();
+        async move {
+            let cutoff = chrono::Utc::now() - chrono::Duration::days(LOGIN_ATTEMPT_RETENTION_DAYS);
+            database.purge_login_attempts_before(cutoff).await?;
+            Ok(())
+        }
+    });
+
+    // Forget audit log entries, share link visits and webhook deliveries past
+    // their retention
+    if config.auth.audit_retention_days > 0 {
+        let audit_retention = chrono::Duration::days(config.auth.audit_retention_days as i64);
+        let audit_database = app_state.database.clone();
+        scheduler.register("audit-log-cleanup", hourly, move || {
+            let database = audit_database.clone();
+            async move {
+                database.purge_audit_events_before(chrono::Utc::now() - audit_retention).await?;
+                Ok(())
+            }
+        });
+        let share_access_database = app_state.database.clone();
+        scheduler.register("share-activity-cleanup", hourly, move || {
+            let database = share_access_database.clone();
+            async move {
+                database.purge_share_access_before(chrono::Utc::now() - audit_retention).await?;
+                Ok(())
+            }
+        });
+        let deliveries_database = app_state.database.clone();
+        scheduler.register("webhook-delivery-cleanup", hourly, move || {
+            let database = deliveries_database.clone();
+            async move {
+                database.purge_webhook_deliveries_before(chrono::Utc::now() - audit_retention).await?;
+                Ok(())
+            }
+        });
+    }
+
+    // Forget sessions that can no longer be refreshed
+    let sessions_database = app_state.database.clone();
+    scheduler.register("session-cleanup", hourly, move || {
+        let database = sessions_database.clone();
+        async move {
+            database.purge_expired_sessions(chrono::Utc::now()).await?;
+            Ok(())
         }
     });
 
+    // Back the database up into the backup directory, the first time one
+    // interval after starting
+    if config.backup.interval_hours > 0 {
+        let backups = app_state.backups.clone();
+        let backup_interval = std::time::Duration::from_secs(config.backup.interval_hours * 3600);
+        scheduler.register_deferred("backup", backup_interval, move || {
+            let backups = backups.clone();
+            async move {
+                backups.save(backups.compresses()).await?;
+                Ok(())
+            }
+        });
+    }
+
+    // Delete share links that can no longer be redeemed
+    if config.auth.share_cleanup_interval_minutes > 0 {
+        let shares_database = app_state.database.clone();
+        let cleanup_interval = std::time::Duration::from_secs(config.auth.share_cleanup_interval_minutes * 60);
+        scheduler.register("share-link-cleanup", cleanup_interval, move || {
+            let database = shares_database.clone();
+            async move {
+                let purged = database.purge_expired_share_links(chrono::Utc::now()).await?;
+                if purged > 0 {
+                    tracing::info!(purged, "Purged {} expired share links", purged);
+                }
+                Ok(())
+            }
+        });
+    }
+
+    // Mark devices that stopped syncing inactive
+    if config.filesystem.sync_session_idle_days > 0 {
+        let sync_database = app_state.database.clone();
+        let idle = chrono::Duration::days(config.filesystem.sync_session_idle_days as i64);
+        scheduler.register("sync-session-cleanup", hourly, move || {
+            let database = sync_database.clone();
+            async move {
+                let deactivated = database.deactivate_idle_sync_sessions(chrono::Utc::now() - idle).await?;
+                if deactivated > 0 {
+                    tracing::info!("Marked {} idle sync sessions inactive", deactivated);
+                }
+                Ok(())
+            }
+        });
+    }
+
+    // Compact the sync feed and forget changes past their retention
+    if config.filesystem.change_log_compact_after_days > 0 {
+        let compact_database = app_state.database.clone();
+        let compact_after = chrono::Duration::days(config.filesystem.change_log_compact_after_days as i64);
+        scheduler.register("change-log-compaction", hourly, move || {
+            let database = compact_database.clone();
+            async move {
+                database.compact_change_log_before(chrono::Utc::now() - compact_after).await?;
+                Ok(())
+            }
+        });
+    }
+    if config.filesystem.change_log_retention_days > 0 {
+        let changes_database = app_state.database.clone();
+        let retention = chrono::Duration::days(config.filesystem.change_log_retention_days as i64);
+        scheduler.register("change-log-cleanup", hourly, move || {
+            let database = changes_database.clone();
+            async move {
+                database.purge_change_log_before(chrono::Utc::now() - retention).await?;
+                Ok(())
+            }
+        });
+    }
+
+    // Verify stored checksums
+    if config.filesystem.integrity_scan_interval_hours > 0 {
+        let scanner = app_state.integrity.clone();
+        let scan_interval = std::time::Duration::from_secs(config.filesystem.integrity_scan_interval_hours * 3600);
+        scheduler.register("integrity-scan", scan_interval, move || {
+            let scanner = scanner.clone();
+            async move {
+                let report = scanner.run_cycle().await?;
+                if report.mismatched > 0 {
+                    tracing::warn!("Integrity scan found {} damaged files", report.mismatched);
+                }
+                Ok(())
+            }
+        });
+    }
+
+    // Mirror changes made directly on disk into the sync feed
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let watcher_task = if config.filesystem.watch_external_changes {
+        let watcher = ChangeWatcher::new(app_state.database.clone(), app_state.filesystem.clone());
+        let shutdown = shutdown_rx.clone();
+        Some(tokio::spawn(async move {
+            if let Err(e) = watcher.run(shutdown).await {
+                tracing::error!("Filesystem watcher error: {}", e);
+            }
+        }))
+    } else {
+        None
+    };
+
+    // Tell webhooks about changes as they are recorded
+    let webhook_task = if app_state.webhooks.is_empty() {
+        None
+    } else {
+        tracing::info!("Sending events to {} webhooks", config.webhooks.endpoints.len());
+        let webhooks = app_state.webhooks.clone();
+        Some(tokio::spawn(async move {
+            if let Err(e) = webhooks.run(shutdown_rx).await {
+                tracing::error!("Webhook dispatcher error: {}", e);
+            }
+        }))
+    };
+
     // Build application router
     let app = create_router(app_state, &config);
 
@@ -141,32 +586,210 @@ async fn main() -> Result<()> {
     tracing::info!("Server starting on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    // Login rate limiting keys on the client address
+    axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    tracing::info!("Shutting down");
+    let _ = shutdown_tx.send(true);
+    scheduler.shutdown().await;
+    if let Some(watcher_task) = watcher_task {
+        let _ = watcher_task.await;
+    }
+    if let Some(webhook_task) = webhook_task {
+        let _ = webhook_task.await;
+    }
 
     Ok(())
 }
 
+/// The NAS, or in debug builds started with `--mock-mycloud` one kept in memory
+#[cfg(debug_assertions)]
+fn mycloud_api(args: &Args, config: &ServerConfig) -> Arc<dyn MyCloudApi> {
+    if args.mock_mycloud {
+        tracing::warn!("Using an in-memory MyCloud with the accounts {} and demo", config.mycloud.admin_username);
+        return Arc::new(crate::mock_mycloud::MockMyCloud::from_settings(&config.mycloud));
+    }
+    Arc::new(MyCloudIntegration::new(config.mycloud.clone()))
+}
+
+#[cfg(not(debug_assertions))]
+fn mycloud_api(_args: &Args, config: &ServerConfig) -> Arc<dyn MyCloudApi> {
+    Arc::new(MyCloudIntegration::new(config.mycloud.clone()))
+}
+
+async fn shutdown_signal() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!("Failed to listen for shutdown signal: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
 fn create_router(state: AppState, config: &ServerConfig) -> Router {
+    // File contents moving in either direction count towards the bandwidth limits
+    let throttled = middleware::from_fn_with_state(state.bandwidth.clone(), throttle_transfers);
+    // and downloads towards the admin statistics
+    let downloaded = middleware::from_fn_with_state(state.downloads.clone(), count_downloads);
+
     // Public routes (no authentication required)
     let public_routes = Router::new()
         .route("/", get(get_server_info))
         .route("/health", get(health_check))
         .route("/api/v1/auth/login", post(login))
-        .route("/api/v1/share/:token", get(download_shared_file));
+        .route("/api/v1/auth/refresh", post(refresh_access_token))
+        .route("/api/v1/auth/oidc/login", get(oidc_login))
+        .route("/api/v1/auth/oidc/callback", get(oidc_callback))
+        .route("/api/v1/share/:token", get(download_shared_file).route_layer(throttled.clone()).route_layer(downloaded.clone()))
+        .route(
+            "/api/v1/share/:token/file/*path",
+            get(download_shared_folder_file).route_layer(throttled.clone()).route_layer(downloaded.clone()),
+        );
 
-    // Protected routes (authentication required)
-    let protected_routes = Router::new()
-        .route("/api/v1/files/upload", post(upload_file))
-        .route("/api/v1/files/download/*path", get(download_file))
+    // Protected routes (authentication required), grouped by the permission
+    // each needs. Account routes only need a login. Browsing also lets
+    // anonymous visitors in when that is on, limited to the anonymous root.
+    let browse_routes = Router::new()
+        .route("/api/v1/files/download/*path", get(download_file).route_layer(throttled.clone()).route_layer(downloaded.clone()))
         .route("/api/v1/files/list", get(list_files))
-        .route("/api/v1/files/delete/*path", delete(delete_file))
-        .route("/api/v1/folders/create", post(create_folder))
+        .route_layer(middleware::from_fn_with_state(Permission::Read, require_permission));
+
+    let read_routes = Router::new()
+        .route("/api/v1/files/upload/session/:id/status", get(get_upload_session_status))
+        .route("/api/v1/files/thumbnail/*path", get(get_thumbnail))
+        .route("/api/v1/files/signature/*path", get(get_file_signature))
+        .route("/api/v1/files/search", get(search_files))
+        .route("/api/v1/files/:id/children", get(list_children))
+        .route("/api/v1/files/:id/versions", get(list_file_versions))
+        .route(
+            "/api/v1/files/:id/versions/:version",
+            get(download_file_version).route_layer(throttled.clone()).route_layer(downloaded),
+        )
+        .route("/api/v1/files/:id/favorite", put(add_favorite).delete(remove_favorite))
+        .route("/api/v1/files/:id/tags", post(add_file_tag))
+        .route("/api/v1/files/:id/tags/:tag", delete(remove_file_tag))
+        .route("/api/v1/files/:id/comments", get(list_comments).post(add_comment))
+        .route("/api/v1/files/:id/comments/:comment_id", patch(edit_comment).delete(delete_comment))
+        .route("/api/v1/trash", get(list_trash))
+        .route("/api/v1/shared-with-me", get(list_shared_with_me))
         .route("/api/v1/sync", post(sync_files))
+        .route("/api/v1/sync/wait", get(wait_for_changes))
+        .route("/api/v1/sync/snapshot", get(get_sync_snapshot))
+        .route("/api/v1/sync/plan", post(plan_sync))
+        .route("/api/v1/sync/sessions", post(register_sync_session).get(list_sync_sessions))
+        .route("/api/v1/sync/sessions/:device_id", put(update_sync_session))
+        .route("/api/v1/user/storage", get(get_storage_info))
+        .route("/api/v1/user/activity", get(get_activity))
+        .route("/api/v1/user/favorites", get(list_favorites))
+        .route("/api/v1/tags", get(list_tags))
+        .route("/api/v1/tags/:tag", patch(rename_tag))
+        .route_layer(middleware::from_fn_with_state(Permission::Read, require_permission));
+
+    let write_routes = Router::new()
+        .route("/api/v1/files/upload", post(upload_file).route_layer(throttled.clone()))
+        .route("/api/v1/files/upload/session", post(create_upload_session))
+        .route("/api/v1/files/upload/session/:id/chunk/:index", put(upload_session_chunk).route_layer(throttled.clone()))
+        .route("/api/v1/files/upload/session/:id/complete", post(complete_upload_session))
+        .route("/api/v1/files/delta/*path", post(apply_file_delta).route_layer(throttled.clone()))
+        .route("/api/v1/files/move", post(move_file))
+        .route("/api/v1/files/copy", post(copy_file))
+        .route("/api/v1/files/batch", post(batch_operations))
+        .route("/api/v1/sync/push", post(push_changes).route_layer(throttled))
+        .route("/api/v1/files/lock/*path", post(lock_file).delete(unlock_file))
+        .route("/api/v1/files/:id/versions/:version/restore", post(restore_file_version))
+        .route("/api/v1/folders/create", post(create_folder))
+        .route("/api/v1/trash/:id/restore", post(restore_trash_entry))
+        .route_layer(middleware::from_fn_with_state(Permission::Write, require_permission));
+
+    let delete_routes = Router::new()
+        .route("/api/v1/files/delete/*path", delete(delete_file))
+        .route("/api/v1/trash/:id", delete(purge_trash_entry))
+        .route_layer(middleware::from_fn_with_state(Permission::Delete, require_permission));
+
+    let share_routes = Router::new()
         .route("/api/v1/share/:file_id", post(create_share_link))
+        .route("/api/v1/files/:id/share-with", post(share_with_user))
+        .route_layer(middleware::from_fn_with_state(
+            AuditRoutes { database: state.database.clone(), event: AuditEvent::ShareCreated },
+            audit_changes,
+        ))
+        .route_layer(middleware::from_fn_with_state(Permission::Share, require_permission));
+
+    let share_management_routes = Router::new()
+        .route("/api/v1/shares", get(list_shares))
+        .route("/api/v1/shares/:id", patch(update_share).delete(revoke_share))
+        .route("/api/v1/shares/:id/activity", get(get_share_activity))
+        .route("/api/v1/files/:id/share-with/:username", delete(unshare_with_user))
+        .route_layer(middleware::from_fn_with_state(
+            AuditRoutes { database: state.database.clone(), event: AuditEvent::ShareUpdated },
+            audit_changes,
+        ))
+        .route_layer(middleware::from_fn_with_state(Permission::Share, require_permission));
+
+    let admin_routes = Router::new()
+        .route("/api/v1/admin/users", get(list_users).post(create_user))
+        .route("/api/v1/admin/users/:id", put(update_user).delete(delete_user))
+        .route("/api/v1/admin/users/:id/quota", put(set_user_quota))
+        .route("/api/v1/admin/users/:id/revoke-tokens", post(revoke_user_tokens))
+        .route("/api/v1/admin/users/:id/password", post(reset_user_password))
+        .route("/api/v1/admin/users/:id/2fa", delete(disable_user_two_factor))
+        .route("/api/v1/admin/impersonate/:id", post(impersonate_user))
+        .route("/api/v1/admin/login-attempts", get(list_login_attempts))
+        .route("/api/v1/admin/audit", get(list_audit_events))
+        .route("/api/v1/admin/integrity", get(list_integrity_issues))
+        .route("/api/v1/admin/integrity/scan", post(start_integrity_scan))
+        .route("/api/v1/admin/reconcile", post(reconcile_storage))
+        .route("/api/v1/admin/webhooks", get(list_webhooks))
+        .route("/api/v1/admin/webhooks/:name/test", post(test_webhook))
+        .route("/api/v1/admin/bandwidth", get(get_bandwidth_limits).put(set_bandwidth_limits))
+        .route("/api/v1/admin/stats", get(get_admin_stats))
+        .route("/api/v1/admin/backup", post(create_backup))
+        .route("/api/v1/admin/jobs", get(list_jobs))
+        .route("/api/v1/admin/jobs/:name/run", post(run_job))
+        .route_layer(middleware::from_fn_with_state(
+            AuditRoutes { database: state.database.clone(), event: AuditEvent::AdminAction },
+            audit_changes,
+        ))
+        .route_layer(middleware::from_fn_with_state(Permission::Admin, require_permission));
+
+    let account_routes = Router::new()
+        .route("/api/v1/auth/logout", post(logout))
+        .route("/api/v1/auth/refresh-tokens/:device_id", delete(revoke_refresh_tokens))
         .route("/api/v1/user/profile", get(get_user_profile))
-        .route("/api/v1/user/storage", get(get_storage_info))
+        .route("/api/v1/user/password", post(change_password))
+        .route("/api/v1/user/2fa/setup", post(setup_two_factor))
+        .route("/api/v1/user/2fa/verify", post(verify_two_factor))
+        .route("/api/v1/user/tokens", post(create_api_token).get(list_api_tokens))
+        .route("/api/v1/user/tokens/scoped", post(create_scoped_token))
+        .route("/api/v1/user/tokens/:id", delete(delete_api_token))
+        .route("/api/v1/user/app-passwords", post(create_app_password).get(list_app_passwords))
+        .route("/api/v1/user/app-passwords/:id", delete(delete_app_password))
+        .route("/api/v1/user/devices", get(list_devices))
+        .route("/api/v1/user/devices/:device_id", delete(revoke_device))
+        .route("/api/v1/user/sessions", get(list_sessions))
+        .route("/api/v1/user/sessions/:id", delete(end_session));
+
+    let login_routes = Router::new()
+        .merge(read_routes)
+        .merge(write_routes)
+        .merge(delete_routes)
+        .merge(share_routes)
+        .merge(share_management_routes)
+        .merge(admin_routes)
+        .merge(account_routes)
+        .route_layer(middleware::from_fn(reject_anonymous));
+
+    let protected_routes = Router::new()
+        .merge(browse_routes)
+        .merge(login_routes)
         .layer(middleware::from_fn_with_state(
-            state.auth_service.clone(),
+            AuthState {
+                auth_service: state.auth_service.clone(),
+                database: state.database.clone(),
+                activity: ActivityThrottle::default(),
+                login_limiter: state.login_limiter.clone(),
+                anonymous: state.anonymous.clone(),
+            },
             auth_middleware,
         ));
 
@@ -180,7 +803,7 @@ fn create_router(state: AppState, config: &ServerConfig) -> Router {
                 .layer(
                     CorsLayer::new()
                         .allow_origin(Any)
-                        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+                        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
                         .allow_headers(Any),
                 )
                 .layer(DefaultBodyLimit::max(config.server.max_request_size))
@@ -191,23 +814,148 @@ fn create_router(state: AppState, config: &ServerConfig) -> Router {
     app
 }
 
-async fn health_check() -> &'static str {
-    "OK"
+/// 503 when the database can't be reached, so monitoring notices
+async fn health_check(State(database): State<Database>) -> Result<&'static str, ApiError> {
+    match database.health().await {
+        Ok(()) => Ok("OK"),
+        Err(e) if is_pool_timeout(&e) => Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Every database connection is busy",
+        )),
+        Err(e) => {
+            tracing::error!("Health check could not query the database: {}", e);
+            Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "The database is unavailable"))
+        }
+    }
+}
+
+async fn refresh_revocations(database: &Database, auth_service: &AuthService) -> Result<()> {
+    let jtis = database.get_revoked_tokens().await?;
+    let valid_after = database.get_tokens_valid_after().await?;
+    let device_valid_after = database.get_device_revocations().await?;
+    auth_service.load_revocations(jtis, valid_after, device_valid_after);
+    Ok(())
+}
+
+async fn purge_stale_upload_sessions(
+    database: &Database,
+    filesystem: &FileSystemService,
+    ttl: chrono::Duration,
+) -> Result<()> {
+    let stale = database.get_stale_upload_sessions(chrono::Utc::now() - ttl).await?;
+
+    for session_id in &stale {
+        filesystem.discard_upload_session(*session_id).await?;
+        database.delete_upload_session(*session_id).await?;
+    }
+
+    if !stale.is_empty() {
+        tracing::info!("Removed {} stale upload sessions", stale.len());
+    }
+
+    Ok(())
+}
+
+async fn purge_expired_trash(
+    database: &Database,
+    filesystem: &FileSystemService,
+    storage: &dyn StorageBackend,
+    retention: chrono::Duration,
+) -> Result<()> {
+    let expired = database.get_expired_trash_entries(chrono::Utc::now() - retention).await?;
+
+    for entry in &expired {
+        let storage = storage.for_user(entry.user_id)?;
+        let location = FileSystemService::trash_location(entry.user_id, entry.id);
+        if storage.metadata(&location).await.is_ok() {
+            storage.delete(&location).await?;
+        }
+        if let Some(file_id) = entry.file_id {
+            let deleted = database.delete_file_metadata(file_id).await?;
+            remove_unused_versions(filesystem, &deleted).await;
+        }
+        database.delete_trash_entry(entry.id).await?;
+    }
+
+    if !expired.is_empty() {
+        tracing::info!("Purged {} expired trash entries", expired.len());
+    }
+
+    Ok(())
 }
 
-async fn download_shared_file() -> Result<String, StatusCode> {
-    // TODO: Implement shared file download
-    Err(StatusCode::NOT_IMPLEMENTED)
+/// Rows recorded before paths were normalized to NFC. Rows that normalize to
+/// the same path are duplicates of one file and have to be merged by hand.
+async fn report_unnormalized_paths(database: &Database) -> Result<()> {
+    use std::collections::HashMap;
+
+    let mut by_normalized: HashMap<(uuid::Uuid, String), Vec<String>> = HashMap::new();
+    for (owner_id, path) in database.list_file_paths().await? {
+        by_normalized.entry((owner_id, normalize_path(&path))).or_default().push(path);
+    }
+
+    let mut unnormalized = 0;
+    for ((owner_id, normalized), paths) in &by_normalized {
+        if paths.len() > 1 {
+            tracing::warn!(
+                "Paths of user {} collide after Unicode normalization as {}: {:?}",
+                owner_id, normalized, paths
+            );
+        }
+        unnormalized += paths.iter().filter(|path| *path != normalized).count();
+    }
+
+    if unnormalized > 0 {
+        tracing::warn!(
+            "{} file paths are not in Unicode NFC and can't be reached until renamed",
+            unnormalized
+        );
+    }
+
+    Ok(())
 }
 
-async fn get_user_profile() -> Result<String, StatusCode> {
-    // TODO: Implement user profile endpoint
-    Err(StatusCode::NOT_IMPLEMENTED)
+async fn run_reconciliation(reconciler: &Reconciler, mode: ReconcileMode, config: &ServerConfig) -> Result<()> {
+    let owner_id = reconciler.resolve_owner(None).await?;
+    if mode == ReconcileMode::Adopt && owner_id.is_none() {
+        return Err(anyhow::anyhow!("Reconcile owner '{}' does not exist", config.filesystem.reconcile_owner));
+    }
+
+    let report = reconciler.run(mode, owner_id).await?;
+    println!("Reconciliation ({:?}) complete:", mode);
+    println!("  Scanned: {} entries on disk, {} rows", report.scanned_entries, report.scanned_rows);
+    println!("  Untracked: {} ({} adopted)", report.untracked, report.adopted);
+    println!("  Missing from disk: {} ({} rows pruned)", report.dangling, report.pruned);
+    println!("  Skipped as recently changed: {}", report.skipped);
+    println!("  Conflicts: {}", report.conflicts.len());
+    for path in &report.conflicts {
+        println!("    {}", path);
+    }
+
+    Ok(())
 }
 
-async fn get_storage_info() -> Result<String, StatusCode> {
-    // TODO: Implement storage info endpoint
-    Err(StatusCode::NOT_IMPLEMENTED)
+/// Turn a flat deployment into one with user homes: the files become the
+/// admin user's, and each user's trash moves into their own home
+async fn migrate_user_homes(database: &Database, filesystem: &FileSystemService, config: &ServerConfig) -> Result<()> {
+    if !config.filesystem.user_homes {
+        return Err(anyhow::anyhow!("Set user_homes = true before migrating"));
+    }
+    if config.storage.backend != StorageBackendKind::Local {
+        return Err(anyhow::anyhow!("Only the local storage backend can be migrated; move the objects under users/<id>/ by hand"));
+    }
+
+    let username = &config.mycloud.admin_username;
+    let admin = database.get_user_by_username(username).await?
+        .ok_or_else(|| anyhow::anyhow!("Admin user '{}' does not exist; run --create-admin first", username))?;
+
+    let moved = filesystem.migrate_to_user_homes(admin.id).await?;
+    let reassigned = database.assign_files_to_owner(admin.id).await?;
+
+    println!("Moved {} top-level entries into the home of {}", moved, username);
+    println!("Gave {} file records of other users to {}", reassigned, username);
+
+    Ok(())
 }
 
 async fn create_initial_admin(
@@ -228,6 +976,11 @@ async fn create_initial_admin(
         return Ok(());
     }
 
+    if let Err(violations) = auth_service.validate_password(password) {
+        let problems: Vec<String> = violations.iter().map(|violation| violation.message()).collect();
+        return Err(anyhow::anyhow!("Admin password rejected by the password policy: {}", problems.join("; ")));
+    }
+
     let password_hash = auth_service.hash_password(password)?;
 
     let admin_user = User {
@@ -238,6 +991,7 @@ async fn create_initial_admin(
         created_at: Utc::now(),
         last_login: None,
         is_active: true,
+        auth_generation: 0,
         permissions: vec![
             "read".to_string(),
             "write".to_string(),
@@ -253,3 +1007,584 @@ async fn create_initial_admin(
     Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use chrono::Utc;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+    use crate::mock_mycloud::MockMyCloud;
+    use crate::types::User;
+
+    async fn test_state(db_dir: &std::path::Path, storage_dir: &std::path::Path, config: &ServerConfig) -> AppState {
+        let url = format!("sqlite://{}?mode=rwc", db_dir.join("test.db").display());
+        let database = Database::new(&url).await.unwrap();
+        let filesystem = FileSystemService::new(storage_dir, 1024 * 1024).unwrap();
+        AppState {
+            database: database.clone(),
+            filesystem: filesystem.clone(),
+            storage: Arc::new(filesystem.clone()),
+            thumbnails: ThumbnailService::new(storage_dir.join("thumbnails"), 4096),
+            integrity: IntegrityScanner::new(database.clone(), filesystem.clone(), 100, 1024 * 1024),
+            reconciler: Reconciler::new(database.clone(), filesystem.clone(), "admin".to_string(), 100),
+            auth_service: AuthService::new(&config.auth.jwt_secret),
+            two_factor: TwoFactorService::new(&config.auth.jwt_secret),
+            share_tokens: ShareTokens::new(&config.auth.share_secret),
+            share_access: ShareAccessLog::new(database.clone()),
+            login_limiter: LoginLimiter::new(&config.auth),
+            mycloud: Arc::new(MockMyCloud::new(false)),
+            oidc: None,
+            anonymous: None,
+            mailer: None,
+            webhooks: Webhooks::new(&config.webhooks, database.clone()),
+            long_poll: LongPollLimit(std::time::Duration::from_secs(config.server.request_timeout_seconds)),
+            pages: PageLimits {
+                default_size: config.server.default_page_size,
+                max_size: config.server.max_page_size,
+            },
+            sync_page: SyncPageLimit(config.filesystem.sync_max_page_size),
+            sync_plan: SyncPlanLimit(config.filesystem.sync_plan_max_entries),
+            delta: DeltaSettings {
+                block_size: config.filesystem.delta_block_size,
+                min_file_size: config.filesystem.delta_min_file_size_kb * 1024,
+            },
+            push_limits: PushLimits {
+                max_operations: config.filesystem.push_max_operations,
+                max_payload_bytes: config.filesystem.push_max_payload_mb * 1024 * 1024,
+                max_inline_bytes: config.filesystem.push_max_inline_kb * 1024,
+            },
+            upload_limits: UploadLimits {
+                min_chunk_size: config.filesystem.upload_min_chunk_kb * 1024,
+                max_chunk_size: config.filesystem.upload_max_chunk_mb * 1024 * 1024,
+                max_chunks: config.filesystem.upload_max_chunks,
+            },
+            conflicts: ConflictSettings::new(&config.sync).unwrap(),
+            bandwidth: Bandwidth::new(&config.server).unwrap(),
+            downloads: DownloadCounter::default(),
+            stats: StatsCache::new(std::time::Duration::from_secs(config.server.stats_cache_seconds)),
+            backups: Backups::new(database.clone(), &config.backup).with_temp_directory(storage_dir.join("temp")),
+            scheduler: Scheduler::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_only_users_are_turned_away_from_protected_routes() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let storage_dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig::default();
+
+        let state = test_state(db_dir.path(), storage_dir.path(), &config).await;
+        let database = state.database.clone();
+        let auth_service = state.auth_service.clone();
+        let app = create_router(state, &config);
+
+        let reader = User {
+            id: Uuid::new_v4(),
+            username: "reader".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string()],
+        };
+        database.create_user(&reader).await.unwrap();
+        let (token, _) = auth_service.generate_token(&reader, None).unwrap();
+
+        let call = |method: &'static str, uri: String| {
+            let app = app.clone();
+            let token = token.clone();
+            async move {
+                let request = Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header("Authorization", format!("Bearer {}", token))
+                    .header("Content-Type", "application/json")
+                    .body(Body::from("{}"))
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).ok())
+            }
+        };
+
+        let id = Uuid::new_v4();
+        let protected = [
+            ("POST", "/api/v1/files/upload".to_string(), "write"),
+            ("POST", "/api/v1/files/upload/session".to_string(), "write"),
+            ("PUT", format!("/api/v1/files/upload/session/{}/chunk/0", id), "write"),
+            ("POST", format!("/api/v1/files/upload/session/{}/complete", id), "write"),
+            ("POST", "/api/v1/files/delta/docs/a.txt".to_string(), "write"),
+            ("POST", "/api/v1/files/move".to_string(), "write"),
+            ("POST", "/api/v1/files/copy".to_string(), "write"),
+            ("POST", "/api/v1/files/batch".to_string(), "write"),
+            ("POST", "/api/v1/sync/push".to_string(), "write"),
+            ("POST", "/api/v1/files/lock/docs/a.txt".to_string(), "write"),
+            ("DELETE", "/api/v1/files/lock/docs/a.txt".to_string(), "write"),
+            ("POST", format!("/api/v1/files/{}/versions/1/restore", id), "write"),
+            ("POST", "/api/v1/folders/create".to_string(), "write"),
+            ("POST", format!("/api/v1/trash/{}/restore", id), "write"),
+            ("DELETE", "/api/v1/files/delete/docs/a.txt".to_string(), "delete"),
+            ("DELETE", format!("/api/v1/trash/{}", id), "delete"),
+            ("POST", format!("/api/v1/share/{}", id), "share"),
+            ("GET", "/api/v1/admin/users".to_string(), "admin"),
+            ("POST", "/api/v1/admin/users".to_string(), "admin"),
+            ("PUT", format!("/api/v1/admin/users/{}", id), "admin"),
+            ("DELETE", format!("/api/v1/admin/users/{}?files=delete", id), "admin"),
+            ("PUT", format!("/api/v1/admin/users/{}/quota", id), "admin"),
+            ("POST", format!("/api/v1/admin/users/{}/revoke-tokens", id), "admin"),
+            ("POST", format!("/api/v1/admin/users/{}/password", id), "admin"),
+            ("DELETE", format!("/api/v1/admin/users/{}/2fa", id), "admin"),
+            ("GET", "/api/v1/admin/login-attempts".to_string(), "admin"),
+            ("GET", "/api/v1/admin/integrity".to_string(), "admin"),
+            ("POST", "/api/v1/admin/integrity/scan".to_string(), "admin"),
+            ("POST", "/api/v1/admin/reconcile".to_string(), "admin"),
+            ("GET", "/api/v1/admin/webhooks".to_string(), "admin"),
+            ("POST", "/api/v1/admin/webhooks/home-assistant/test".to_string(), "admin"),
+            ("GET", "/api/v1/admin/bandwidth".to_string(), "admin"),
+            ("PUT", "/api/v1/admin/bandwidth".to_string(), "admin"),
+            ("GET", "/api/v1/admin/stats".to_string(), "admin"),
+            ("POST", "/api/v1/admin/backup".to_string(), "admin"),
+            ("GET", "/api/v1/admin/jobs".to_string(), "admin"),
+            ("POST", "/api/v1/admin/jobs/trash-purge/run".to_string(), "admin"),
+        ];
+
+        for (method, uri, permission) in protected {
+            let (status, body) = call(method, uri.clone()).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{} {}", method, uri);
+            let body = body.unwrap();
+            assert_eq!(body["data"]["missing_permission"], permission, "{} {}", method, uri);
+        }
+
+        // Reading still works
+        let (status, _) = call("GET", "/api/v1/trash".to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_visitors_only_read_the_anonymous_root() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let storage_dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig::default();
+
+        std::fs::create_dir_all(storage_dir.path().join("public/docs")).unwrap();
+        std::fs::create_dir_all(storage_dir.path().join("public-old")).unwrap();
+        std::fs::create_dir_all(storage_dir.path().join("private")).unwrap();
+        std::fs::write(storage_dir.path().join("public/readme.txt"), "hello").unwrap();
+        std::fs::write(storage_dir.path().join("public-old/readme.txt"), "old").unwrap();
+        std::fs::write(storage_dir.path().join("private/secret.txt"), "secret").unwrap();
+
+        let mut state = test_state(db_dir.path(), storage_dir.path(), &config).await;
+        state.anonymous = Some(AnonymousAccess { root: "/public".to_string() });
+        let app = create_router(state, &config);
+
+        let call = |method: &'static str, uri: &str| {
+            let app = app.clone();
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .body(Body::from("{}"))
+                .unwrap();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, body)
+            }
+        };
+
+        // Inside the root listing and downloading work
+        let (status, body) = call("GET", "/api/v1/files/list?path=/public").await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let mut names: Vec<_> = body["data"]["items"].as_array().unwrap().iter()
+            .map(|file| file["name"].as_str().unwrap().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["docs", "readme.txt"]);
+
+        let (status, body) = call("GET", "/api/v1/files/list?path=/public/docs").await;
+        assert_eq!(status, StatusCode::OK, "{:?}", body);
+
+        let (status, body) = call("GET", "/api/v1/files/download/public/readme.txt").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"hello");
+
+        // Nothing outside it can be reached
+        for uri in [
+            "/api/v1/files/list",
+            "/api/v1/files/list?path=/private",
+            "/api/v1/files/list?path=/public-old",
+            "/api/v1/files/list?path=/public/../private",
+            "/api/v1/files/download/private/secret.txt",
+            "/api/v1/files/download/public-old/readme.txt",
+            "/api/v1/files/download/public/..%2Fprivate/secret.txt",
+            "/api/v1/files/list?path=/public&include_ignored=true",
+        ] {
+            assert_eq!(call("GET", uri).await.0, StatusCode::FORBIDDEN, "{}", uri);
+        }
+
+        // Every other route still wants a login, reads included
+        for (method, uri) in [
+            ("GET", "/api/v1/trash"),
+            ("GET", "/api/v1/user/storage"),
+            ("GET", "/api/v1/files/thumbnail/public/readme.txt"),
+            ("GET", "/api/v1/user/profile"),
+            ("GET", "/api/v1/user/sessions"),
+            ("GET", "/api/v1/admin/users"),
+            ("POST", "/api/v1/files/upload"),
+            ("POST", "/api/v1/folders/create"),
+            ("DELETE", "/api/v1/files/delete/public/readme.txt"),
+        ] {
+            assert_eq!(call(method, uri).await.0, StatusCode::UNAUTHORIZED, "{} {}", method, uri);
+        }
+
+        // A bad token is rejected rather than treated as anonymous
+        let request = Request::builder()
+            .uri("/api/v1/files/list?path=/public")
+            .header("Authorization", "Bearer invalid")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        // The server advertises it
+        let (status, body) = call("GET", "/").await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["data"]["anonymous_read"]["enabled"], true);
+        assert_eq!(body["data"]["anonymous_read"]["root"], "/public");
+    }
+
+    #[tokio::test]
+    async fn test_deactivated_users_are_logged_out_everywhere() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let storage_dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig::default();
+
+        let state = test_state(db_dir.path(), storage_dir.path(), &config).await;
+        let database = state.database.clone();
+        let auth_service = state.auth_service.clone();
+        let app = create_router(state, &config);
+
+        // A second server on the same database, which only hears of the
+        // deactivation through it
+        let ttl = std::time::Duration::from_secs(1);
+        let mut other_state = test_state(db_dir.path(), storage_dir.path(), &config).await;
+        other_state.auth_service = AuthService::new(&config.auth.jwt_secret).with_user_status_ttl(ttl);
+        let other_app = create_router(other_state, &config);
+
+        let user = |username: &str, permissions: &[&str]| User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: permissions.iter().map(|permission| permission.to_string()).collect(),
+        };
+        let admin = user("admin", &["read", "admin"]);
+        let member = user("member", &["read"]);
+        database.create_user(&admin).await.unwrap();
+        database.create_user(&member).await.unwrap();
+        let (admin_token, _) = auth_service.generate_token(&admin, None).unwrap();
+        let (member_token, _) = auth_service.generate_token(&member, None).unwrap();
+
+        let call = |app: &Router, method: &'static str, uri: String, token: &str, body: &'static str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        for app in [&app, &other_app] {
+            let status = call(app, "GET", "/api/v1/trash".to_string(), &member_token, "").await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let uri = format!("/api/v1/admin/users/{}", member.id);
+        let status = call(&app, "PUT", uri, &admin_token, r#"{"is_active": false}"#).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // The server the admin used refuses the token at once, the other
+        // one as soon as its cached status runs out
+        let status = call(&app, "GET", "/api/v1/trash".to_string(), &member_token, "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        tokio::time::sleep(ttl).await;
+        let status = call(&other_app, "GET", "/api/v1/trash".to_string(), &member_token, "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_users_cannot_download_or_delete_each_others_files() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let storage_dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig::default();
+
+        let state = test_state(db_dir.path(), storage_dir.path(), &config).await;
+        let database = state.database.clone();
+        let filesystem = state.filesystem.clone();
+        let auth_service = state.auth_service.clone();
+        let app = create_router(state, &config);
+
+        let user = |username: &str| User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string(), "write".to_string(), "delete".to_string()],
+        };
+        let alice = user("alice");
+        let bob = user("bob");
+        database.create_user(&alice).await.unwrap();
+        database.create_user(&bob).await.unwrap();
+        let (alice_token, _) = auth_service.generate_token(&alice, None).unwrap();
+        let (bob_token, _) = auth_service.generate_token(&bob, None).unwrap();
+
+        // Without user homes both see the same tree, so only the record
+        // tells whose the file is
+        filesystem.save_file("/report.txt", b"alice's").await.unwrap();
+        let mut report = filesystem.get_file_metadata("/report.txt").await.unwrap();
+        report.owner_id = alice.id;
+        database.create_file_metadata(&report).await.unwrap();
+        filesystem.save_file("/untracked.txt", b"nobody's").await.unwrap();
+
+        let call = |method: &'static str, uri: &'static str, token: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        assert_eq!(call("GET", "/api/v1/files/download/report.txt", &bob_token).await, StatusCode::FORBIDDEN);
+        assert_eq!(call("DELETE", "/api/v1/files/delete/report.txt", &bob_token).await, StatusCode::FORBIDDEN);
+        assert!(filesystem.get_absolute_path("/report.txt").exists());
+
+        // Nothing on record is nobody's to read or remove
+        assert_eq!(call("GET", "/api/v1/files/download/untracked.txt", &bob_token).await, StatusCode::NOT_FOUND);
+        assert_eq!(call("DELETE", "/api/v1/files/delete/untracked.txt", &bob_token).await, StatusCode::NOT_FOUND);
+
+        assert_eq!(call("GET", "/api/v1/files/download/report.txt", &alice_token).await, StatusCode::OK);
+        assert_eq!(call("DELETE", "/api/v1/files/delete/report.txt", &alice_token).await, StatusCode::OK);
+        assert!(!filesystem.get_absolute_path("/report.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_security_events_reach_the_audit_log() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let storage_dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig::default();
+
+        let state = test_state(db_dir.path(), storage_dir.path(), &config).await;
+        let database = state.database.clone();
+        let filesystem = state.filesystem.clone();
+        let auth_service = state.auth_service.clone();
+        let app = create_router(state, &config);
+
+        let user = |username: &str, permissions: &[&str]| User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: permissions.iter().map(|permission| permission.to_string()).collect(),
+        };
+        let admin = user("admin", &["read", "admin"]);
+        let member = user("member", &["read"]);
+        database.create_user(&admin).await.unwrap();
+        database.create_user(&member).await.unwrap();
+        let (admin_token, _) = auth_service.generate_token(&admin, None).unwrap();
+        let (member_token, _) = auth_service.generate_token(&member, None).unwrap();
+
+        filesystem.save_file("/notes.txt", b"notes").await.unwrap();
+        let mut notes = filesystem.get_file_metadata("/notes.txt").await.unwrap();
+        notes.owner_id = member.id;
+        database.create_file_metadata(&notes).await.unwrap();
+
+        let call = |method: &'static str, uri: String, token: &str, body: &'static str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .header("User-Agent", "audit-test")
+                .header("Content-Type", "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        let uri = format!("/api/v1/admin/users/{}", member.id);
+        assert_eq!(call("PUT", uri.clone(), &member_token, r#"{"is_active": false}"#).await, StatusCode::FORBIDDEN);
+        assert_eq!(call("PUT", uri.clone(), &admin_token, r#"{"permissions": ["read", "share"]}"#).await, StatusCode::OK);
+        let member = database.get_user_by_id(member.id).await.unwrap().unwrap();
+        let (member_token, _) = auth_service.generate_token(&member, None).unwrap();
+        assert_eq!(call("POST", format!("/api/v1/share/{}", notes.id), &member_token, "").await, StatusCode::OK);
+        // Reads aren't admin actions
+        assert_eq!(call("GET", "/api/v1/admin/users".to_string(), &admin_token, "").await, StatusCode::OK);
+        // Failed authentication is sampled per address
+        for _ in 0..3 {
+            assert_eq!(call("GET", "/api/v1/trash".to_string(), "forged", "").await, StatusCode::UNAUTHORIZED);
+        }
+
+        // Written in the background
+        let mut entries = Vec::new();
+        for _ in 0..50 {
+            entries = database.list_audit_events(None, None, None, None, 100, 0).await.unwrap();
+            if entries.len() >= 4 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let mut events: Vec<_> = entries.iter()
+            .map(|entry| (entry.event, entry.username.clone(), entry.outcome.clone()))
+            .collect();
+        events.sort_by_key(|(event, _, _)| event.as_str());
+        assert_eq!(events, vec![
+            (AuditEvent::AdminAction, Some("admin".to_string()), "success".to_string()),
+            (AuditEvent::AuthFailure, None, "failure".to_string()),
+            (AuditEvent::PermissionDenied, Some("member".to_string()), "denied".to_string()),
+            (AuditEvent::ShareCreated, Some("member".to_string()), "success".to_string()),
+        ]);
+
+        let denied = entries.iter().find(|entry| entry.event == AuditEvent::PermissionDenied).unwrap();
+        assert_eq!(denied.target.as_deref(), Some(format!("PUT {}", uri).as_str()));
+        assert_eq!(denied.user_id, Some(member.id));
+        assert_eq!(denied.user_agent.as_deref(), Some("audit-test"));
+
+        // Admins read it back through the API
+        let request = Request::builder()
+            .uri("/api/v1/admin/audit?event=admin_action&user=admin")
+            .header("Authorization", format!("Bearer {}", admin_token))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["data"]["items"].as_array().unwrap().len(), 1);
+        assert_eq!(body["data"]["items"][0]["event"], "admin_action");
+        assert_eq!(body["data"]["total"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_admins_can_impersonate_users() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let storage_dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig::default();
+
+        let state = test_state(db_dir.path(), storage_dir.path(), &config).await;
+        let database = state.database.clone();
+        let auth_service = state.auth_service.clone();
+        let app = create_router(state, &config);
+
+        let user = |username: &str, permissions: &[&str]| User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: permissions.iter().map(|permission| permission.to_string()).collect(),
+        };
+        let admin = user("admin", &["read", "admin"]);
+        let member = user("member", &["read", "write"]);
+        database.create_user(&admin).await.unwrap();
+        database.create_user(&member).await.unwrap();
+        let (admin_token, _) = auth_service.generate_token(&admin, None).unwrap();
+        let (member_token, _) = auth_service.generate_token(&member, None).unwrap();
+
+        let call = |method: &'static str, uri: String, token: String, body: &'static str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let uri = format!("/api/v1/admin/impersonate/{}", member.id);
+        assert_eq!(call("POST", uri.clone(), member_token, "").await.0, StatusCode::FORBIDDEN);
+        let (status, body) = call("POST", uri, admin_token.clone(), "").await;
+        assert_eq!(status, StatusCode::OK, "{:?}", body);
+        let token = body["data"]["token"].as_str().unwrap().to_string();
+        assert_eq!(body["data"]["user"]["username"], "member");
+
+        // Capped at 15 minutes, whatever access tokens otherwise get
+        let claims = auth_service.verify_token(&token).unwrap();
+        assert!(claims.exp - Utc::now().timestamp() <= 15 * 60);
+
+        // The profile tells who is really looking
+        let (status, body) = call("GET", "/api/v1/user/profile".to_string(), token.clone(), "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["username"], "member");
+        assert_eq!(body["data"]["impersonated_by"]["username"], "admin");
+        let (_, body) = call("GET", "/api/v1/user/profile".to_string(), admin_token.clone(), "").await;
+        assert!(body["data"]["impersonated_by"].is_null());
+
+        // The member's files can be seen, but their account can't be managed
+        assert_eq!(call("GET", "/api/v1/files/list".to_string(), token.clone(), "").await.0, StatusCode::OK);
+        let password = r#"{"current_password": "hash", "new_password": "a-new-password"}"#;
+        assert_eq!(call("POST", "/api/v1/user/password".to_string(), token.clone(), password).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(call("GET", "/api/v1/user/sessions".to_string(), token.clone(), "").await.0, StatusCode::FORBIDDEN);
+        assert_eq!(call("GET", "/api/v1/user/app-passwords".to_string(), token.clone(), "").await.0, StatusCode::FORBIDDEN);
+
+        // Everything done with the token is the admin's in the audit log
+        let mut entries = Vec::new();
+        for _ in 0..50 {
+            entries = database.list_audit_events(None, None, None, None, 100, 0).await.unwrap();
+            if entries.len() >= 7 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let impersonated: Vec<_> = entries.iter().filter(|entry| entry.impersonating.is_some()).collect();
+        assert_eq!(impersonated.len(), 5);
+        assert!(impersonated.iter().all(|entry| {
+            entry.user_id == Some(admin.id)
+                && entry.username.as_deref() == Some("admin")
+                && entry.impersonating.as_deref() == Some("member")
+        }));
+        assert_eq!(
+            impersonated.iter().filter(|entry| entry.event == AuditEvent::PermissionDenied).count(),
+            3
+        );
+
+        // Logging out with it ends the impersonation
+        assert_eq!(call("POST", "/api/v1/auth/logout".to_string(), token.clone(), "").await.0, StatusCode::OK);
+        assert_eq!(call("GET", "/api/v1/user/profile".to_string(), token, "").await.0, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use tokio::fs as async_fs;
+use sha2::{Sha256, Digest};
+use anyhow::Result;
+use image::{io::Reader as ImageReader, ImageOutputFormat};
+use uuid::Uuid;
+
+// Mime types the image crate is built to decode
+const SUPPORTED_MIME_TYPES: &[&str] = &[
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+];
+
+const JPEG_QUALITY: u8 = 80;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ThumbnailError {
+    #[error("Source image is {width}x{height}, larger than the {max}px limit")]
+    SourceTooLarge { width: u32, height: u32, max: u32 },
+}
+
+/// Generates JPEG thumbnails and caches them on disk. Cache entries are keyed
+/// by the source checksum, so a changed file never serves a stale thumbnail.
+#[derive(Clone)]
+pub struct ThumbnailService {
+    cache_dir: PathBuf,
+    max_source_dimension: u32,
+}
+
+impl ThumbnailService {
+    pub fn new(cache_dir: impl AsRef<Path>, max_source_dimension: u32) -> Self {
+        Self {
+            cache_dir: cache_dir.as_ref().to_path_buf(),
+            max_source_dimension,
+        }
+    }
+
+    pub fn is_supported(mime_type: &str) -> bool {
+        SUPPORTED_MIME_TYPES.contains(&mime_type)
+    }
+
+    pub async fn get_or_create(&self, source: &Path, checksum: &str, size: u32) -> Result<Vec<u8>> {
+        // All thumbnails of one source file live together so old ones can be
+        // dropped when its content changes
+        let source_dir = self.cache_dir.join(path_key(source));
+        let cache_path = source_dir.join(format!("{}-{}.jpg", checksum, size));
+
+        if cache_path.exists() {
+            return Ok(async_fs::read(&cache_path).await?);
+        }
+
+        let source_path = source.to_path_buf();
+        let max_dimension = self.max_source_dimension;
+        let data = tokio::task::spawn_blocking(move || {
+            render_thumbnail(&source_path, size, max_dimension)
+        })
+        .await??;
+
+        remove_stale_thumbnails(&source_dir, checksum).await?;
+
+        let temp_path = source_dir.join(format!(".{}.tmp", Uuid::new_v4()));
+        async_fs::write(&temp_path, &data).await?;
+        async_fs::rename(&temp_path, &cache_path).await?;
+
+        Ok(data)
+    }
+}
+
+fn path_key(path: &Path) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(path.to_string_lossy().as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Remove cached thumbnails rendered from an older version of the source
+async fn remove_stale_thumbnails(source_dir: &Path, checksum: &str) -> Result<()> {
+    if !source_dir.exists() {
+        async_fs::create_dir_all(source_dir).await?;
+        return Ok(());
+    }
+
+    let mut entries = async_fs::read_dir(source_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        if !name.to_string_lossy().starts_with(checksum) {
+            let _ = async_fs::remove_file(entry.path()).await;
+        }
+    }
+
+    Ok(())
+}
+
+fn render_thumbnail(source: &Path, size: u32, max_dimension: u32) -> Result<Vec<u8>> {
+    // Check dimensions from the header before decoding the whole image
+    let (width, height) = ImageReader::open(source)?
+        .with_guessed_format()?
+        .into_dimensions()?;
+
+    if width > max_dimension || height > max_dimension {
+        return Err(ThumbnailError::SourceTooLarge {
+            width,
+            height,
+            max: max_dimension,
+        }
+        .into());
+    }
+
+    let image = ImageReader::open(source)?.with_guessed_format()?.decode()?;
+    let thumbnail = image.thumbnail(size, size).to_rgb8();
+
+    let mut output = Cursor::new(Vec::new());
+    thumbnail.write_to(&mut output, ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+    Ok(output.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_thumbnail_generation_and_limits() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("photo.png");
+        image::RgbImage::new(800, 600).save(&source).unwrap();
+
+        let service = ThumbnailService::new(temp_dir.path().join("thumbnails"), 4000);
+        let data = service.get_or_create(&source, "abc", 256).await.unwrap();
+
+        let thumbnail = image::load_from_memory(&data).unwrap();
+        assert_eq!(thumbnail.width(), 256);
+        assert!(thumbnail.height() <= 256);
+
+        // A new checksum renders fresh and evicts the old entry
+        service.get_or_create(&source, "def", 256).await.unwrap();
+        let source_dir = temp_dir.path().join("thumbnails").join(path_key(&source));
+        assert!(!source_dir.join("abc-256.jpg").exists());
+        assert!(source_dir.join("def-256.jpg").exists());
+
+        let strict = ThumbnailService::new(temp_dir.path().join("strict"), 500);
+        let err = strict.get_or_create(&source, "abc", 256).await.unwrap_err();
+        assert!(err.downcast_ref::<ThumbnailError>().is_some());
+    }
+}
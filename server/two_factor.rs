@@ -0,0 +1,209 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+const ISSUER: &str = "Synker";
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+// Accept the previous and next code too, for clocks that drift a little
+const SKEW_STEPS: u8 = 1;
+const NONCE_LEN: usize = 12;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+
+/// TOTP secrets and recovery codes for two-factor login. Secrets are kept
+/// encrypted in the database with a key derived from the JWT secret, so a
+/// copy of the database alone can't be used to produce codes.
+#[derive(Clone)]
+pub struct TwoFactorService {
+    cipher: Aes256Gcm,
+    /// Keys of previous JWT secrets, for secrets stored before a rotation
+    previous_ciphers: Vec<Aes256Gcm>,
+}
+
+/// A freshly generated secret, before it is confirmed with a code
+#[derive(Debug)]
+pub struct NewSecret {
+    pub encrypted: String,
+    pub base32: String,
+    pub otpauth_uri: String,
+}
+
+impl TwoFactorService {
+    pub fn new(jwt_secret: &str) -> Self {
+        Self {
+            cipher: cipher(jwt_secret),
+            previous_ciphers: Vec::new(),
+        }
+    }
+
+    /// Still decrypt secrets stored under these. New ones use the current secret.
+    pub fn with_previous_secrets(mut self, jwt_secrets: &[String]) -> Self {
+        self.previous_ciphers = jwt_secrets.iter().map(|secret| cipher(secret)).collect();
+        self
+    }
+
+    pub fn generate_secret(&self, username: &str) -> Result<NewSecret> {
+        let secret = Secret::generate_secret()
+            .to_bytes()
+            .map_err(|e| anyhow!("Failed to generate TOTP secret: {:?}", e))?;
+        let totp = totp(secret.clone(), username)?;
+
+        Ok(NewSecret {
+            encrypted: self.encrypt(&secret)?,
+            base32: totp.get_secret_base32(),
+            otpauth_uri: totp.get_url(),
+        })
+    }
+
+    /// Whether `code` is valid now for the encrypted secret
+    pub fn verify_code(&self, encrypted_secret: &str, username: &str, code: &str) -> Result<bool> {
+        let secret = self.decrypt(encrypted_secret)?;
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+        Ok(totp(secret, username)?.check_current(&code)?)
+    }
+
+    fn encrypt(&self, secret: &[u8]) -> Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher
+            .encrypt(&nonce, secret)
+            .map_err(|_| anyhow!("Failed to encrypt TOTP secret"))?;
+
+        let mut stored = nonce.to_vec();
+        stored.extend_from_slice(&ciphertext);
+        Ok(BASE64.encode(stored))
+    }
+
+    fn decrypt(&self, stored: &str) -> Result<Vec<u8>> {
+        let stored = BASE64.decode(stored)?;
+        if stored.len() <= NONCE_LEN {
+            return Err(anyhow!("Stored TOTP secret is too short"));
+        }
+
+        let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+        // Fails if the JWT secret changed since the secret was stored and
+        // the old one isn't among the previous secrets
+        std::iter::once(&self.cipher)
+            .chain(&self.previous_ciphers)
+            .find_map(|cipher| cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok())
+            .ok_or_else(|| anyhow!("Failed to decrypt TOTP secret"))
+    }
+}
+
+fn cipher(jwt_secret: &str) -> Aes256Gcm {
+    let key = Sha256::new()
+        .chain_update(b"synker-totp-key:")
+        .chain_update(jwt_secret.as_bytes())
+        .finalize();
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+}
+
+fn totp(secret: Vec<u8>, username: &str) -> Result<TOTP> {
+    TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        SKEW_STEPS,
+        STEP_SECONDS,
+        secret,
+        Some(ISSUER.to_string()),
+        username.to_string(),
+    )
+    .map_err(|e| anyhow!("Invalid TOTP parameters: {:?}", e))
+}
+
+/// Single-use codes for when the authenticator is lost, shown to the user once
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let random = Uuid::new_v4().simple().to_string();
+            format!("{}-{}", &random[..5], &random[5..RECOVERY_CODE_LEN])
+        })
+        .collect()
+}
+
+/// What is stored for a recovery code. Dashes, spaces and case don't matter.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
+
+/// Six digits, which authenticator apps may show split by a space
+pub fn is_totp_code(code: &str) -> bool {
+    let digits: Vec<char> = code.chars().filter(|c| !c.is_whitespace()).collect();
+    digits.len() == DIGITS && digits.iter().all(char::is_ascii_digit)
+}
+
+/// Ten letters and digits as handed out by `generate_recovery_codes`,
+/// however they are split by dashes or spaces
+pub fn is_recovery_code(code: &str) -> bool {
+    let characters: Vec<char> = code.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+    characters.len() == RECOVERY_CODE_LEN && characters.iter().all(char::is_ascii_alphanumeric)
+}
+
+#[cfg(test)]
+pub fn current_code(service: &TwoFactorService, encrypted_secret: &str, username: &str) -> String {
+    let secret = service.decrypt(encrypted_secret).unwrap();
+    totp(secret, username).unwrap().generate_current().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_verify_against_the_encrypted_secret() {
+        let service = TwoFactorService::new("test_secret");
+        let secret = service.generate_secret("testuser").unwrap();
+
+        assert!(secret.otpauth_uri.starts_with("otpauth://totp/Synker:testuser?"));
+        assert!(!secret.encrypted.contains(&secret.base32));
+
+        let code = current_code(&service, &secret.encrypted, "testuser");
+        assert!(service.verify_code(&secret.encrypted, "testuser", &code).unwrap());
+        assert!(!service.verify_code(&secret.encrypted, "testuser", "000000x").unwrap());
+
+        // Another JWT secret means another key
+        let other = TwoFactorService::new("other_secret");
+        assert!(other.verify_code(&secret.encrypted, "testuser", &code).is_err());
+    }
+
+    #[test]
+    fn test_secrets_survive_a_jwt_secret_rotation() {
+        let before = TwoFactorService::new("old_secret");
+        let secret = before.generate_secret("testuser").unwrap();
+        let code = current_code(&before, &secret.encrypted, "testuser");
+
+        let after = TwoFactorService::new("new_secret")
+            .with_previous_secrets(&["old_secret".to_string()]);
+        assert!(after.verify_code(&secret.encrypted, "testuser", &code).unwrap());
+
+        // New secrets use the new key
+        let newer = after.generate_secret("testuser").unwrap();
+        assert!(before.decrypt(&newer.encrypted).is_err());
+    }
+
+    #[test]
+    fn test_recovery_codes_hash_loosely() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(codes.iter().all(|code| is_recovery_code(code) && !is_totp_code(code)));
+        assert!(!is_recovery_code("123456"));
+        assert!(is_totp_code("123 456"));
+        assert!(!is_recovery_code("123 456"));
+        assert!(!is_totp_code("12345a") && !is_recovery_code("12345a"));
+
+        let code = &codes[0];
+        assert_eq!(
+            hash_recovery_code(code),
+            hash_recovery_code(&code.to_uppercase().replace('-', " "))
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
+use crate::filesystem::path_components;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
@@ -11,9 +13,59 @@ pub struct User {
     pub created_at: DateTime<Utc>,
     pub last_login: Option<DateTime<Utc>>,
     pub is_active: bool,
+    /// Bumped on deactivation and password changes; tokens issued under an
+    /// older generation are refused
+    #[serde(default)]
+    pub auth_generation: i64,
     pub permissions: Vec<String>,
 }
 
+impl User {
+    /// The permissions this user holds; names the server doesn't know are ignored
+    pub fn granted_permissions(&self) -> Vec<Permission> {
+        self.permissions.iter().filter_map(|name| Permission::parse(name)).collect()
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.iter().any(|name| name == permission.as_str())
+    }
+}
+
+/// Something a user may be allowed to do. Stored by name in `User::permissions`
+/// and carried in access tokens, so routes can check it without a lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+    Share,
+    Admin,
+}
+
+impl Permission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Delete => "delete",
+            Permission::Share => "share",
+            Permission::Admin => "admin",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read" => Some(Permission::Read),
+            "write" => Some(Permission::Write),
+            "delete" => Some(Permission::Delete),
+            "share" => Some(Permission::Share),
+            "admin" => Some(Permission::Admin),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMetadata {
     pub id: Uuid,
@@ -26,10 +78,48 @@ pub struct FileMetadata {
     pub modified_at: DateTime<Utc>,
     pub owner_id: Uuid,
     pub is_directory: bool,
+    /// Set when the entry was reached through a symbolic link
+    #[serde(default)]
+    pub is_symlink: bool,
     pub parent_id: Option<Uuid>,
     pub permissions: FilePermissions,
 }
 
+/// A file or folder as listed to a user, with whether they starred it
+#[derive(Debug, Clone, Serialize)]
+pub struct ListedFile {
+    #[serde(flatten)]
+    pub file: FileMetadata,
+    pub is_favorite: bool,
+    /// The user's tags on it, by name, when asked for with `include=tags`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+}
+
+/// How the filesystem layer treats symbolic links found under the storage directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Any operation touching a symlink fails
+    Deny,
+    /// Symlinks are left out of listings and treated as missing
+    Skip,
+    /// Symlinks are followed as long as the target stays under the base path
+    FollowWithinBase,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub id: Uuid,
+    pub file_id: Uuid,
+    pub version: u32,
+    pub size: u64,
+    pub checksum: String,
+    pub modified_at: DateTime<Utc>,
+    pub author_id: Uuid,
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FilePermissions {
     pub read: bool,
@@ -38,6 +128,81 @@ pub struct FilePermissions {
     pub share: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountDiskSpace {
+    pub name: String,
+    pub disk: DiskSpace,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DiskSpace {
+    pub total: u64,
+    pub available: u64,
+    pub used: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StorageUsage {
+    /// Sum of the sizes of all user-visible files
+    pub logical: u64,
+    /// Bytes actually occupied, counting deduplicated content once
+    pub physical: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    pub used: u64,
+    /// `None` means unlimited
+    pub limit: Option<u64>,
+}
+
+impl QuotaUsage {
+    /// Whether `additional` more bytes fit once `freed` bytes are released,
+    /// e.g. by the file an upload overwrites
+    pub fn allows(&self, additional: u64, freed: u64) -> bool {
+        self.remaining(freed).map_or(true, |remaining| additional <= remaining)
+    }
+
+    /// Bytes still available after `freed` bytes are released; `None` if unlimited
+    pub fn remaining(&self, freed: u64) -> Option<u64> {
+        self.limit
+            .map(|limit| limit.saturating_sub(self.used.saturating_sub(freed)))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChecksumMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuotaExceeded {
+    pub used: u64,
+    pub limit: u64,
+    pub requested: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetQuotaRequest {
+    /// `null` resets the user to the server default
+    pub quota_bytes: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StorageInfo {
+    /// Server-wide figures, only sent to admins
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk: Option<DiskSpace>,
+    /// Space on the drive behind each configured mount
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mounts: Option<Vec<MountDiskSpace>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<StorageUsage>,
+    /// Bytes used by the requesting user and their quota
+    pub quota: QuotaUsage,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncSession {
     pub id: Uuid,
@@ -46,6 +211,9 @@ pub struct SyncSession {
     pub device_name: String,
     pub last_sync: DateTime<Utc>,
     pub sync_folders: Vec<String>,
+    /// Gitignore-style patterns for paths in `sync_folders` the device leaves out
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
     pub is_active: bool,
 }
 
@@ -60,6 +228,254 @@ pub struct ShareLink {
     pub download_count: u32,
     pub max_downloads: Option<u32>,
     pub created_at: DateTime<Utc>,
+    /// Set once the owner revokes the link; the row stays for the audit trail
+    #[serde(default)]
+    pub revoked_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+    /// Short random stand-in for `share_token` in URLs; none on old links
+    #[serde(default)]
+    pub short_code: Option<String>,
+    /// Name the owner picked for the link, resolving like `short_code`
+    #[serde(default)]
+    pub alias: Option<String>,
+}
+
+impl ShareLink {
+    /// Expired once `expires_at` is reached; links without one never expire
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn downloads_used_up(&self) -> bool {
+        self.max_downloads.is_some_and(|max_downloads| self.download_count >= max_downloads)
+    }
+}
+
+/// How a visit to a share link ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareAccessOutcome {
+    /// A file was sent or a folder listed
+    Success,
+    Revoked,
+    Expired,
+    /// No password or the wrong one for a protected link
+    WrongPassword,
+    DownloadsUsedUp,
+    /// The link was valid but the file or path wasn't there
+    NotFound,
+    /// The owner had the link emailed to someone
+    EmailSent,
+    /// Sending that email failed for good
+    EmailFailed,
+}
+
+impl ShareAccessOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ShareAccessOutcome::Success => "success",
+            ShareAccessOutcome::Revoked => "revoked",
+            ShareAccessOutcome::Expired => "expired",
+            ShareAccessOutcome::WrongPassword => "wrong_password",
+            ShareAccessOutcome::DownloadsUsedUp => "downloads_used_up",
+            ShareAccessOutcome::NotFound => "not_found",
+            ShareAccessOutcome::EmailSent => "email_sent",
+            ShareAccessOutcome::EmailFailed => "email_failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "success" => Some(ShareAccessOutcome::Success),
+            "revoked" => Some(ShareAccessOutcome::Revoked),
+            "expired" => Some(ShareAccessOutcome::Expired),
+            "wrong_password" => Some(ShareAccessOutcome::WrongPassword),
+            "downloads_used_up" => Some(ShareAccessOutcome::DownloadsUsedUp),
+            "not_found" => Some(ShareAccessOutcome::NotFound),
+            "email_sent" => Some(ShareAccessOutcome::EmailSent),
+            "email_failed" => Some(ShareAccessOutcome::EmailFailed),
+            _ => None,
+        }
+    }
+}
+
+/// One visit to a share link, as its owner sees it
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareAccess {
+    pub id: Uuid,
+    pub share_id: Uuid,
+    pub accessed_at: DateTime<Utc>,
+    /// The visitor's network, e.g. `203.0.113.0`, never the full address
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    /// Size of the file sent; 0 for listings and failures
+    pub bytes_served: u64,
+    pub outcome: ShareAccessOutcome,
+    /// For emails, who it went to and why it failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateShareRequest {
+    /// Addresses to email the link to, when email is set up
+    #[serde(default)]
+    pub notify: Vec<String>,
+}
+
+/// A share link with the file it points to, as its owner lists it
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareSummary {
+    #[serde(flatten)]
+    pub share: ShareLink,
+    pub file_name: String,
+    pub file_path: String,
+}
+
+/// An entry of a shared folder as visitors see it, without IDs or owners
+#[derive(Debug, Clone, Serialize)]
+pub struct SharedEntry {
+    pub name: String,
+    /// Relative to the shared folder, as `/api/v1/share/{token}/file/{path}` takes it
+    pub path: String,
+    pub size: u64,
+    pub mime_type: String,
+    pub is_directory: bool,
+    pub modified_at: DateTime<Utc>,
+}
+
+/// One folder of a folder share link
+#[derive(Debug, Serialize)]
+pub struct SharedFolderListing {
+    /// Name of the shared folder
+    pub name: String,
+    /// The folder listed, relative to the shared one; empty for the shared one itself
+    pub path: String,
+    pub entries: Vec<SharedEntry>,
+}
+
+/// What another local user may do with a file or folder shared with them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharePermissions {
+    Read,
+    /// Reading, writing and deleting, though never the shared item itself
+    ReadWrite,
+}
+
+impl SharePermissions {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SharePermissions::Read => "read",
+            SharePermissions::ReadWrite => "read_write",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read" => Some(SharePermissions::Read),
+            "read_write" => Some(SharePermissions::ReadWrite),
+            _ => None,
+        }
+    }
+
+    pub fn allows(self, action: Action) -> bool {
+        action == Action::Read || self == SharePermissions::ReadWrite
+    }
+}
+
+/// A file or folder its owner, the grantor, shared with another local user
+#[derive(Debug, Clone, Serialize)]
+pub struct UserShare {
+    pub id: Uuid,
+    pub file_id: Uuid,
+    pub grantor: Uuid,
+    pub grantee: Uuid,
+    pub permissions: SharePermissions,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A share as the user it was shared with sees it. Its files are reached
+/// with `user=` set to the owner, at the owner's paths.
+#[derive(Debug, Clone, Serialize)]
+pub struct IncomingShare {
+    #[serde(flatten)]
+    pub share: UserShare,
+    /// The grantor's username
+    pub owner: String,
+    pub name: String,
+    pub path: String,
+    pub is_directory: bool,
+}
+
+impl IncomingShare {
+    /// Whether the share lets its grantee do `action` on `path`. Deleting
+    /// only reaches what is inside a shared folder.
+    pub fn covers(&self, action: Action, path: &str) -> bool {
+        match (path_components(path), path_components(&self.path)) {
+            (Some(path), Some(shared)) => {
+                path.starts_with(&shared)
+                    && self.share.permissions.allows(action)
+                    && (action != Action::Delete || path.len() > shared.len())
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShareWithRequest {
+    pub username: String,
+    pub permissions: SharePermissions,
+}
+
+/// A tag to put on a file, or the new name of one
+#[derive(Debug, Deserialize)]
+pub struct TagRequest {
+    pub name: String,
+}
+
+/// One of a user's tags and how many of the files they can see carry it
+#[derive(Debug, Clone, Serialize)]
+pub struct TagUsage {
+    pub name: String,
+    pub files: u64,
+}
+
+/// A note left on a file
+#[derive(Debug, Clone, Serialize)]
+pub struct Comment {
+    pub id: Uuid,
+    pub file_id: Uuid,
+    /// None once the author's account is removed
+    pub author_id: Option<Uuid>,
+    /// The author's username
+    pub author: Option<String>,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+    /// Set once the author changes the body
+    pub edited_at: Option<DateTime<Utc>>,
+}
+
+/// The body of a new comment, or the new body of one
+#[derive(Debug, Deserialize)]
+pub struct CommentRequest {
+    pub body: String,
+}
+
+/// Changes to a share link. Fields left out stay as they are.
+#[derive(Debug, Deserialize)]
+pub struct UpdateShareRequest {
+    /// `null` makes the link never expire
+    #[serde(default, deserialize_with = "present")]
+    pub expires_at: Option<Option<DateTime<Utc>>>,
+    /// `null` lifts the limit
+    #[serde(default, deserialize_with = "present")]
+    pub max_downloads: Option<Option<u32>>,
+    /// `null` removes the password
+    #[serde(default, deserialize_with = "present")]
+    pub password: Option<Option<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -90,12 +506,176 @@ impl<T> ApiResponse<T> {
     }
 }
 
+/// One page of a listing. Passing `next_token` back as `page_token` gets the
+/// page after; it's none on the last page.
+#[derive(Debug, Serialize)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    /// Entries in the whole listing
+    pub total: u64,
+    pub next_token: Option<String>,
+}
+
+/// What a `/files/search` looks for; parts left out don't narrow it
+#[derive(Debug, Default)]
+pub struct FileSearch {
+    /// Words that must each begin a word of the name
+    pub query: Option<String>,
+    /// A MIME type, or a family of them like `image/*`
+    pub mime_type: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<DateTime<Utc>>,
+    /// Folder the results lie in, at any depth
+    pub path_prefix: Option<String>,
+    /// A tag, normalized, the owner put on the results
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub file_id: Option<Uuid>,
+    pub original_path: String,
+    pub name: String,
+    pub is_directory: bool,
+    pub size: u64,
+    pub deleted_at: DateTime<Utc>,
+}
+
+/// Advisory lock on a path, held by one user on one device until it expires
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileLock {
+    pub id: Uuid,
+    /// Home the path is in; nil for the shared tree
+    #[serde(skip)]
+    pub home_id: Uuid,
+    pub path: String,
+    pub owner_id: Uuid,
+    pub owner_name: String,
+    pub device_id: Option<String>,
+    /// Lets the holder refresh or release the lock from another session
+    pub token: String,
+    pub acquired_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LockRequest {
+    pub ttl_seconds: Option<u64>,
+    pub token: Option<String>,
+}
+
+/// Returned with `423 Locked`; the token is never disclosed to other clients
+#[derive(Debug, Serialize)]
+pub struct LockConflict {
+    pub path: String,
+    pub holder: String,
+    pub device_id: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntegrityIssue {
+    pub id: Uuid,
+    pub file_id: Uuid,
+    pub path: String,
+    pub expected_checksum: String,
+    pub actual_checksum: String,
+    pub detected_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IntegrityScanRequest {
+    pub path: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct IntegrityScanReport {
+    pub verified: u64,
+    /// Files modified on disk after their row was written, whose checksum was refreshed
+    pub restamped: u64,
+    pub mismatched: u64,
+    pub missing: u64,
+}
+
+/// What a reconciliation run does about disagreements between disk and database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconcileMode {
+    /// Only count and log them
+    Report,
+    /// Record untracked files and folders
+    Adopt,
+    /// Remove rows whose files are gone
+    Prune,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReconcileRequest {
+    pub mode: ReconcileMode,
+    /// Username that adopted entries outside any tracked folder go to;
+    /// defaults to `reconcile_owner`
+    #[serde(default)]
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReconcileReport {
+    pub mode: ReconcileMode,
+    /// Files and folders found on disk
+    pub scanned_entries: u64,
+    pub scanned_rows: u64,
+    /// Entries on disk without a row
+    pub untracked: u64,
+    pub adopted: u64,
+    /// Rows whose file or folder is missing from disk
+    pub dangling: u64,
+    pub pruned: u64,
+    /// Entries written too recently to judge, left for the next run
+    pub skipped: u64,
+    /// Paths that are a file on disk but a folder in the database, or the reverse
+    pub conflicts: Vec<String>,
+}
+
+impl ReconcileReport {
+    pub fn new(mode: ReconcileMode) -> Self {
+        Self {
+            mode,
+            scanned_entries: 0,
+            scanned_rows: 0,
+            untracked: 0,
+            adopted: 0,
+            dangling: 0,
+            pruned: 0,
+            skipped: 0,
+            conflicts: Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
     pub device_id: Option<String>,
     pub device_name: Option<String>,
+    /// Current authenticator code, or a recovery code, once 2FA is enabled
+    #[serde(default)]
+    pub totp_code: Option<String>,
+    /// Shown in the device list
+    #[serde(default)]
+    pub client_version: Option<String>,
+}
+
+/// What the OpenID Connect provider appends when sending the browser back
+#[derive(Debug, Deserialize)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    /// Set instead of `code` when the login failed or was cancelled
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -103,53 +683,1187 @@ pub struct LoginResponse {
     pub token: String,
     pub user: User,
     pub expires_at: DateTime<Utc>,
+    pub refresh_token: String,
+    pub refresh_expires_at: DateTime<Utc>,
+    /// An admin reset the password; clients should ask for a new one
+    pub must_change_password: bool,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct UploadRequest {
-    pub path: String,
-    pub overwrite: Option<bool>,
+pub struct RefreshRequest {
+    pub refresh_token: String,
+    /// Replace the refresh token with a new one; the old one stops working
+    #[serde(default)]
+    pub rotate: bool,
 }
 
-#[derive(Debug, Serialize)]
-pub struct UploadResponse {
-    pub file_id: Uuid,
-    pub path: String,
-    pub size: u64,
-    pub checksum: String,
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct CreateFolderRequest {
-    pub path: String,
-    pub name: String,
+pub struct ResetPasswordRequest {
+    pub new_password: String,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct SyncRequest {
-    pub folders: Vec<String>,
-    pub last_sync: Option<DateTime<Utc>>,
+/// A user as admins see them, without anything secret
+#[derive(Debug, Clone, Serialize)]
+pub struct UserAccount {
+    pub id: Uuid,
+    pub username: String,
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_login: Option<DateTime<Utc>>,
+    pub is_active: bool,
+    pub permissions: Vec<String>,
+    /// `None` means the server default
+    pub quota_bytes: Option<u64>,
+}
+
+/// The admin behind an impersonation token
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Impersonator {
+    pub id: Uuid,
+    pub username: String,
 }
 
+/// A short-lived token for an admin to see what a user sees
 #[derive(Debug, Serialize)]
-pub struct SyncResponse {
-    pub changes: Vec<FileChange>,
-    pub sync_token: String,
+pub struct ImpersonationResponse {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+    pub user: UserAccount,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct FileChange {
-    pub file_id: Uuid,
-    pub change_type: ChangeType,
-    pub path: String,
-    pub metadata: Option<FileMetadata>,
-    pub timestamp: DateTime<Utc>,
+/// The caller's own account, and who is really behind the token when an
+/// admin is impersonating them
+#[derive(Debug, Serialize)]
+pub struct UserProfile {
+    #[serde(flatten)]
+    pub account: UserAccount,
+    pub impersonated_by: Option<Impersonator>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum ChangeType {
-    Created,
-    Modified,
-    Deleted,
-    Moved,
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUserRequest {
+    pub username: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    pub password: String,
+    pub permissions: Vec<Permission>,
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+}
+
+/// Changes to a user; fields left out stay as they are
+#[derive(Debug, Default, Deserialize)]
+pub struct UpdateUserRequest {
+    #[serde(default)]
+    pub is_active: Option<bool>,
+    #[serde(default)]
+    pub permissions: Option<Vec<Permission>>,
+    /// `null` resets the user to the server default
+    #[serde(default, deserialize_with = "present")]
+    pub quota_bytes: Option<Option<u64>>,
+}
+
+/// Tells a field sent as `null` apart from one left out
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// What happens to a deleted user's files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanedFiles {
+    Delete,
+    /// Hand them to the admin deleting the user
+    Reassign,
+}
+
+impl OrphanedFiles {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "delete" => Some(OrphanedFiles::Delete),
+            "reassign" => Some(OrphanedFiles::Reassign),
+            _ => None,
+        }
+    }
+}
+
+/// A user's TOTP secret, still encrypted, and whether logins need a code
+#[derive(Debug, Clone)]
+pub struct TwoFactorState {
+    pub secret: Option<String>,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorSetupResponse {
+    /// For typing into an authenticator by hand
+    pub secret: String,
+    /// For rendering as a QR code
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorVerifyRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorVerifyResponse {
+    /// Shown once; each works a single time in place of a code
+    pub recovery_codes: Vec<String>,
+}
+
+/// A refresh token as stored; the token itself is only ever held by the client
+#[derive(Debug, Clone)]
+pub struct RefreshToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub token_hash: String,
+    pub device_id: Option<String>,
+    /// Set for tokens issued at login and passed on when rotated
+    pub session_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A login, followed through token refreshes until it expires or is ended
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub device_id: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    /// Of the newest access token issued in the session
+    #[serde(skip)]
+    pub jti: String,
+    #[serde(skip)]
+    pub token_expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    /// Updated at most every five minutes while the session makes requests
+    pub last_active_at: DateTime<Utc>,
+    /// When its refresh token runs out
+    pub expires_at: DateTime<Utc>,
+    /// Whether the listing request came from this session
+    pub current: bool,
+}
+
+/// A device a user has logged in from
+#[derive(Debug, Clone, Serialize)]
+pub struct Device {
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub device_id: String,
+    pub name: Option<String>,
+    pub client_version: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    /// Set once revoked, until the device logs in again
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// A login attempt as recorded for lockouts and auditing
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginAttempt {
+    pub id: Uuid,
+    pub username: String,
+    pub ip_address: String,
+    /// success, failure, locked or rate_limited
+    pub outcome: String,
+    pub attempted_at: DateTime<Utc>,
+}
+
+/// A password policy rule a new password breaks, for clients to point out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum PolicyViolation {
+    TooShort { min_length: usize },
+    TooLong { max_length: usize },
+    /// Fewer kinds of character than `require_mixed_classes` asks for
+    MissingCharacterClasses { required: usize, found: usize },
+    /// On the deny list of common passwords
+    Common,
+}
+
+impl PolicyViolation {
+    pub fn message(&self) -> String {
+        match self {
+            PolicyViolation::TooShort { min_length } => {
+                format!("Password must be at least {} characters long", min_length)
+            }
+            PolicyViolation::TooLong { max_length } => {
+                format!("Password must be at most {} bytes long", max_length)
+            }
+            PolicyViolation::MissingCharacterClasses { required, .. } => format!(
+                "Password must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                required
+            ),
+            PolicyViolation::Common => "Password is too common".to_string(),
+        }
+    }
+}
+
+/// Kinds of entry in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// Password or OIDC login, successful or not
+    Login,
+    TokenRefresh,
+    /// A request turned away by `auth_middleware` for a missing, invalid or
+    /// revoked token
+    AuthFailure,
+    PermissionDenied,
+    ShareCreated,
+    /// A share link edited or revoked by its owner
+    ShareUpdated,
+    /// Anything an admin changed through the admin routes
+    AdminAction,
+    /// A request an admin made while impersonating a user
+    Impersonation,
+}
+
+impl AuditEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditEvent::Login => "login",
+            AuditEvent::TokenRefresh => "token_refresh",
+            AuditEvent::AuthFailure => "auth_failure",
+            AuditEvent::PermissionDenied => "permission_denied",
+            AuditEvent::ShareCreated => "share_created",
+            AuditEvent::ShareUpdated => "share_updated",
+            AuditEvent::AdminAction => "admin_action",
+            AuditEvent::Impersonation => "impersonation",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "login" => Some(AuditEvent::Login),
+            "token_refresh" => Some(AuditEvent::TokenRefresh),
+            "auth_failure" => Some(AuditEvent::AuthFailure),
+            "permission_denied" => Some(AuditEvent::PermissionDenied),
+            "share_created" => Some(AuditEvent::ShareCreated),
+            "share_updated" => Some(AuditEvent::ShareUpdated),
+            "admin_action" => Some(AuditEvent::AdminAction),
+            "impersonation" => Some(AuditEvent::Impersonation),
+            _ => None,
+        }
+    }
+}
+
+/// One entry of the audit log
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub id: Uuid,
+    pub occurred_at: DateTime<Utc>,
+    /// Set when the user is known
+    pub user_id: Option<Uuid>,
+    /// The user's name, or the one a failed login was attempted with
+    pub username: Option<String>,
+    /// The user an admin acted as, when the request was made while
+    /// impersonating them. `user_id` and `username` are the admin's.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impersonating: Option<String>,
+    pub event: AuditEvent,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    /// What was acted on, e.g. the request's method and path
+    pub target: Option<String>,
+    /// success, failure or denied
+    pub outcome: String,
+}
+
+impl AuditEntry {
+    pub fn new(event: AuditEvent, outcome: &str) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            occurred_at: Utc::now(),
+            user_id: None,
+            username: None,
+            impersonating: None,
+            event,
+            ip_address: None,
+            user_agent: None,
+            target: None,
+            outcome: outcome.to_string(),
+        }
+    }
+
+    pub fn with_user(mut self, user_id: Option<Uuid>, username: &str) -> Self {
+        self.user_id = user_id;
+        self.username = Some(username.to_string()).filter(|name| !name.is_empty());
+        self
+    }
+
+    pub fn with_impersonating(mut self, username: &str) -> Self {
+        self.impersonating = Some(username.to_string());
+        self
+    }
+
+    pub fn with_origin(mut self, ip_address: Option<String>, user_agent: Option<String>) -> Self {
+        self.ip_address = ip_address;
+        self.user_agent = user_agent;
+        self
+    }
+
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+}
+
+/// What a personal access token may do on its owner's behalf
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    ReadOnly,
+    ReadWrite,
+}
+
+impl TokenScope {
+    /// Whether the scope covers a permission. Admin and account management
+    /// are never covered, whatever the owner may do with a login.
+    pub fn allows(self, permission: Permission) -> bool {
+        match self {
+            TokenScope::ReadOnly => permission == Permission::Read,
+            TokenScope::ReadWrite => permission != Permission::Admin,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::ReadOnly => "read_only",
+            TokenScope::ReadWrite => "read_write",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read_only" => Some(TokenScope::ReadOnly),
+            "read_write" => Some(TokenScope::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+/// What a path scope lets a token do with the files under it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Read,
+    Write,
+    Delete,
+}
+
+/// One action allowed under one folder. A token holding any of these can
+/// only act on paths one of them covers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathScope {
+    pub action: Action,
+    pub path_prefix: String,
+}
+
+impl PathScope {
+    pub fn covers(&self, action: Action, path: &str) -> bool {
+        match (path_components(path), path_components(&self.path_prefix)) {
+            (Some(path), Some(prefix)) => self.action == action && path.starts_with(&prefix),
+            _ => false,
+        }
+    }
+}
+
+/// A personal access token as listed to its owner; the secret is never returned again
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiToken {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub scope: TokenScope,
+    /// Set for scoped tokens, limited to these folders
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub path_scopes: Vec<PathScope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiTokenRequest {
+    pub name: String,
+    pub scope: TokenScope,
+    /// Never expires when left out
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateScopedTokenRequest {
+    pub name: String,
+    pub scopes: Vec<PathScope>,
+    /// Never expires when left out
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateApiTokenResponse {
+    #[serde(flatten)]
+    pub token: ApiToken,
+    /// Shown once; use it as `Authorization: Bearer <secret>`
+    pub secret: String,
+}
+
+/// An app password as listed to its owner; the password is never returned again
+#[derive(Debug, Clone, Serialize)]
+pub struct AppPassword {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub label: String,
+    #[serde(skip)]
+    pub password_hash: String,
+    /// Set when it only reaches the files under this folder
+    pub path_prefix: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl AppPassword {
+    /// The path scopes a folder restriction amounts to: everything a
+    /// file client does, but only under `path_prefix`
+    pub fn path_scopes(&self) -> Vec<PathScope> {
+        match &self.path_prefix {
+            Some(prefix) => [Action::Read, Action::Write, Action::Delete]
+                .into_iter()
+                .map(|action| PathScope { action, path_prefix: prefix.clone() })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateAppPasswordRequest {
+    pub label: String,
+    /// Reaches every file when left out
+    pub path_prefix: Option<String>,
+    /// Never expires when left out
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateAppPasswordResponse {
+    #[serde(flatten)]
+    pub app_password: AppPassword,
+    /// Shown once; use it with the username for HTTP Basic auth
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadRequest {
+    pub path: String,
+    pub overwrite: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadResponse {
+    pub file_id: Uuid,
+    pub path: String,
+    pub size: u64,
+    pub checksum: String,
+    /// Set when an identical file was already stored and the upload was skipped
+    pub deduplicated: bool,
+    /// Set when the file had changed since the version the client based its
+    /// upload on
+    pub conflict: bool,
+    /// How a conflict was settled: `keep_both` stored the upload as a
+    /// conflicted copy at `path`, `client_wins` stored it over the file and
+    /// `server_wins` dropped it, leaving `path` as it was. `newest_wins`
+    /// shows up as whichever of these it came down to.
+    pub conflict_policy: Option<ConflictPolicy>,
+}
+
+/// What an upload or push does when the file changed since the version the
+/// client last saw
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Turn the upload away with 409 Conflict
+    Fail,
+    /// Keep the upload beside the file as a conflicted copy
+    #[default]
+    KeepBoth,
+    /// Drop the upload and keep the file as it is
+    ServerWins,
+    /// Replace the file, keeping it as a previous version
+    ClientWins,
+    /// Whichever of the two was modified last wins; too close to call keeps both
+    NewestWins,
+}
+
+impl ConflictPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "fail" => Some(ConflictPolicy::Fail),
+            "keep-both" => Some(ConflictPolicy::KeepBoth),
+            "server-wins" => Some(ConflictPolicy::ServerWins),
+            "client-wins" => Some(ConflictPolicy::ClientWins),
+            "newest-wins" => Some(ConflictPolicy::NewestWins),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub target_path: String,
+    pub total_size: u64,
+    pub chunk_size: u64,
+    pub checksum: Option<String>,
+    pub overwrite: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Saved `ResumableSha256` covering the start of the file
+    #[serde(skip)]
+    pub hash_state: Option<Vec<u8>>,
+}
+
+impl UploadSession {
+    /// Chunks the file is split into, or None when `chunk_size` is 0 or
+    /// there would be more than fit in a u32
+    pub fn chunk_count(total_size: u64, chunk_size: u64) -> Option<u32> {
+        if chunk_size == 0 {
+            return None;
+        }
+        if total_size == 0 {
+            return Some(1);
+        }
+        u32::try_from(total_size.div_ceil(chunk_size)).ok()
+    }
+
+    /// Sessions are only created with a chunk count that fits
+    pub fn total_chunks(&self) -> u32 {
+        Self::chunk_count(self.total_size, self.chunk_size).unwrap_or(u32::MAX)
+    }
+
+    /// Expected length of the chunk at `index`; only the last chunk may be short
+    pub fn chunk_length(&self, index: u32) -> u64 {
+        let offset = index as u64 * self.chunk_size;
+        self.chunk_size.min(self.total_size.saturating_sub(offset))
+    }
+
+    /// Bytes at the start of the file covered by chunks received without gaps
+    pub fn contiguous_bytes(&self, received: &[u32]) -> u64 {
+        let mut chunks = 0;
+        while received.binary_search(&chunks).is_ok() {
+            chunks += 1;
+        }
+        (chunks as u64 * self.chunk_size).min(self.total_size)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUploadSessionRequest {
+    pub path: String,
+    pub total_size: u64,
+    pub chunk_size: u64,
+    pub checksum: Option<String>,
+    pub overwrite: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadSessionStatus {
+    pub session_id: Uuid,
+    pub target_path: String,
+    pub total_size: u64,
+    pub chunk_size: u64,
+    pub total_chunks: u32,
+    pub received_chunks: Vec<u32>,
+    pub complete: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompleteUploadRequest {
+    pub checksum: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateFolderRequest {
+    pub path: String,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveRequest {
+    pub from: String,
+    pub to: String,
+    pub overwrite: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CopyRequest {
+    pub from: String,
+    pub to: String,
+    pub overwrite: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchOp {
+    Delete,
+    Move,
+    Copy,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchOperation {
+    pub op: BatchOp,
+    pub from: String,
+    /// Destination for `move` and `copy`; ignored for `delete`
+    pub to: Option<String>,
+    pub overwrite: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    pub operations: Vec<BatchOperation>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchItemResult {
+    /// Position of the operation in the request
+    pub index: usize,
+    pub op: BatchOp,
+    pub from: String,
+    pub to: Option<String>,
+    pub success: bool,
+    pub error: Option<String>,
+    pub metadata: Option<FileMetadata>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PushOp {
+    CreateDir,
+    Delete,
+    Move,
+    Put,
+}
+
+/// One change a client pushes
+#[derive(Debug, Deserialize)]
+pub struct PushOperation {
+    pub op: PushOp,
+    pub path: String,
+    /// Destination for `move`
+    pub to: Option<String>,
+    /// Base64 file content for a small `put`
+    pub content: Option<String>,
+    /// Completed upload session holding the content of a large `put`
+    pub upload_session: Option<Uuid>,
+    /// Expected SHA-256 of the content of a `put`
+    pub checksum: Option<String>,
+    pub overwrite: Option<bool>,
+    /// SHA-256 of the version an inline `put` changed, to detect conflicts
+    pub base_checksum: Option<String>,
+    /// Modification time of the version an inline `put` changed
+    pub base_modified_at: Option<DateTime<Utc>>,
+    /// When the client last modified the file, for `newest_wins`
+    pub modified_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PushRequest {
+    pub operations: Vec<PushOperation>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PushItemResult {
+    /// Position of the operation in the request
+    pub index: usize,
+    pub op: PushOp,
+    pub path: String,
+    pub to: Option<String>,
+    pub success: bool,
+    pub error: Option<String>,
+    pub metadata: Option<FileMetadata>,
+    /// How a conflicting `put` was settled, as for uploads
+    pub conflict_policy: Option<ConflictPolicy>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PushResponse {
+    pub results: Vec<PushItemResult>,
+    /// Cursor to sync from next; past the pushed changes unless someone
+    /// else changed something meanwhile
+    pub sync_token: String,
+}
+
+/// Registers the calling device for sync
+#[derive(Debug, Deserialize)]
+pub struct RegisterSyncSessionRequest {
+    /// Defaults to the name the device logged in with
+    pub device_name: Option<String>,
+    /// Folders the device syncs; none means everything
+    #[serde(default)]
+    pub folders: Vec<String>,
+    /// Gitignore-style patterns for paths the device leaves out
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
+}
+
+/// Changes to one of the caller's sync sessions; unset fields stay as they are
+#[derive(Debug, Deserialize)]
+pub struct UpdateSyncSessionRequest {
+    pub device_name: Option<String>,
+    pub folders: Option<Vec<String>>,
+    pub exclude_patterns: Option<Vec<String>>,
+    /// `false` makes the device register again before its next sync
+    pub is_active: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncRequest {
+    pub folders: Vec<String>,
+    pub last_sync: Option<DateTime<Utc>>,
+    /// `sync_token` of the previous response. Takes precedence over
+    /// `last_sync`, and gets exactly the changes made since.
+    pub sync_token: Option<String>,
+    /// Most changes to send at once; the rest come on the next sync
+    pub limit: Option<u32>,
+}
+
+/// First line of a `/sync/snapshot` stream
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    /// Cursor to sync from once the snapshot is applied
+    pub sync_token: String,
+    /// The folders the snapshot covers; empty for everything
+    pub folders: Vec<String>,
+    pub quota: QuotaUsage,
+}
+
+/// One entry of a `/sync/snapshot` stream, a line each after the header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub path: String,
+    pub size: u64,
+    /// As recorded when the file was last written
+    pub checksum: String,
+    pub modified_at: DateTime<Utc>,
+    pub is_directory: bool,
+}
+
+impl From<FileMetadata> for SnapshotEntry {
+    fn from(metadata: FileMetadata) -> Self {
+        Self {
+            path: metadata.path,
+            size: metadata.size,
+            checksum: metadata.checksum,
+            modified_at: metadata.modified_at,
+            is_directory: metadata.is_directory,
+        }
+    }
+}
+
+/// One of the client's files in a `/sync/plan` manifest, a line each
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub checksum: String,
+    pub modified_at: DateTime<Utc>,
+    /// Checksum of the version the client last synced; none for files it
+    /// has never synced
+    #[serde(default)]
+    pub base_checksum: Option<String>,
+}
+
+/// A file one side has to send the other
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedTransfer {
+    pub path: String,
+    pub size: u64,
+    pub checksum: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictReason {
+    /// Both versions changed since the client last synced, or there's no
+    /// telling which one did
+    BothChanged,
+    /// The client changed a file the server deleted
+    DeletedOnServer,
+    /// The server changed a file the client deleted
+    DeletedLocally,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedConflict {
+    pub path: String,
+    pub reason: ConflictReason,
+    pub local_checksum: Option<String>,
+    pub server_checksum: Option<String>,
+}
+
+/// What a client and the server would send each other to agree
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncPlan {
+    pub download: Vec<PlannedTransfer>,
+    pub upload: Vec<PlannedTransfer>,
+    /// Deleted on the server; the client should delete its copies
+    pub delete_local: Vec<String>,
+    /// Deleted by the client; the server would delete its copies
+    pub delete_remote: Vec<String>,
+    pub conflicts: Vec<PlannedConflict>,
+    /// Files already the same on both sides
+    pub unchanged: u64,
+    pub download_bytes: u64,
+    pub upload_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyncPlanResponse {
+    #[serde(flatten)]
+    pub plan: SyncPlan,
+    /// Cursor of the server state the plan was made against
+    pub sync_token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyncResponse {
+    pub changes: Vec<FileChange>,
+    /// Cursor to send back next time
+    pub sync_token: String,
+    /// The feed no longer reaches back to `last_sync` or the token is not
+    /// one this server gave out, so changes may have been missed. `changes`
+    /// is empty; list everything again instead.
+    pub full_resync_required: bool,
+    /// More changes are waiting; sync again with `sync_token` for them
+    pub has_more: bool,
+}
+
+/// How far back the change log reaches after pruning
+#[derive(Debug, Clone)]
+pub struct ChangeLogHorizon {
+    /// Clients that last synced before this may have missed changes
+    pub pruned_before: DateTime<Utc>,
+    /// Likewise clients whose sync token is below this
+    pub pruned_through: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileChange {
+    pub file_id: Uuid,
+    pub change_type: ChangeType,
+    pub path: String,
+    /// Path before the change, set for `ChangeType::Moved`
+    pub old_path: Option<String>,
+    pub metadata: Option<FileMetadata>,
+    pub timestamp: DateTime<Utc>,
+    /// Owner of a file shared with the caller; its paths are the owner's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shared_by: Option<String>,
+}
+
+/// What happened in an entry of the activity feed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityKind {
+    Created,
+    Modified,
+    Moved,
+    Deleted,
+    /// Someone downloaded a file through one of the user's share links
+    SharedDownload,
+    /// Someone commented on one of the user's files
+    Commented,
+}
+
+/// A run of events of one kind, by one actor and device, in one folder
+/// within the same stretch of time, e.g. 14 files created in `/scans`
+#[derive(Debug, Clone, Serialize)]
+pub struct ActivityGroup {
+    pub kind: ActivityKind,
+    /// Folder the files are in; where they went for moves
+    pub directory: String,
+    /// Who made the changes; none for share downloads and changes made on the NAS
+    pub actor_id: Option<Uuid>,
+    pub device_id: Option<String>,
+    /// Each file involved once, most recent first
+    pub file_ids: Vec<Uuid>,
+    /// Events in the run; more than files when a file changed repeatedly
+    pub events: u32,
+    pub first_at: DateTime<Utc>,
+    pub last_at: DateTime<Utc>,
+}
+
+/// One page of the activity feed. Passing `next_before` back as `before`
+/// gets older activity; it's none once there is no more.
+#[derive(Debug, Serialize)]
+pub struct ActivityPage {
+    pub items: Vec<ActivityGroup>,
+    pub next_before: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChangeType {
+    Created,
+    Modified,
+    Deleted,
+    Moved,
+}
+
+/// What a webhook can be told about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Created,
+    Modified,
+    Deleted,
+    Moved,
+    ShareCreated,
+    /// Only sent to hooks that list it
+    CommentCreated,
+    /// Sent on request from the admin API, whatever events the hook takes
+    Test,
+}
+
+impl WebhookEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WebhookEvent::Created => "created",
+            WebhookEvent::Modified => "modified",
+            WebhookEvent::Deleted => "deleted",
+            WebhookEvent::Moved => "moved",
+            WebhookEvent::ShareCreated => "share_created",
+            WebhookEvent::CommentCreated => "comment_created",
+            WebhookEvent::Test => "test",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "created" => Some(WebhookEvent::Created),
+            "modified" => Some(WebhookEvent::Modified),
+            "deleted" => Some(WebhookEvent::Deleted),
+            "moved" => Some(WebhookEvent::Moved),
+            "share_created" => Some(WebhookEvent::ShareCreated),
+            "comment_created" => Some(WebhookEvent::CommentCreated),
+            "test" => Some(WebhookEvent::Test),
+            _ => None,
+        }
+    }
+}
+
+impl From<&ChangeType> for WebhookEvent {
+    fn from(change_type: &ChangeType) -> Self {
+        match change_type {
+            ChangeType::Created => WebhookEvent::Created,
+            ChangeType::Modified => WebhookEvent::Modified,
+            ChangeType::Deleted => WebhookEvent::Deleted,
+            ChangeType::Moved => WebhookEvent::Moved,
+        }
+    }
+}
+
+/// Where sending an event to a webhook has got to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookDeliveryStatus {
+    /// Not sent yet, or waiting to be tried again
+    Pending,
+    Delivered,
+    /// Every attempt failed
+    Failed,
+}
+
+impl WebhookDeliveryStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WebhookDeliveryStatus::Pending => "pending",
+            WebhookDeliveryStatus::Delivered => "delivered",
+            WebhookDeliveryStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(WebhookDeliveryStatus::Pending),
+            "delivered" => Some(WebhookDeliveryStatus::Delivered),
+            "failed" => Some(WebhookDeliveryStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// One event sent, or being sent, to a webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    /// Name of the hook in the config
+    pub webhook: String,
+    pub event: WebhookEvent,
+    pub path: String,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: u32,
+    /// HTTP status of the last attempt that got an answer
+    pub response_status: Option<u16>,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+    /// When a pending delivery is tried again
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// What the server POSTs to a webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookPayload {
+    /// Same on every retry, so receivers can tell repeats apart
+    pub delivery_id: Uuid,
+    pub event: WebhookEvent,
+    /// Path in the owner's files
+    pub path: String,
+    /// Path before a move
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    pub owner_id: Option<Uuid>,
+    /// The file as it is now; none once it's deleted
+    pub metadata: Option<FileMetadata>,
+    /// The new link, for `share_created`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share_id: Option<Uuid>,
+    /// The new comment, for `comment_created`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<Comment>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A configured webhook as the admin API shows it, without its secret
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookInfo {
+    pub name: String,
+    pub url: String,
+    pub path_prefix: Option<String>,
+    /// Empty means every event
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookOverview {
+    pub webhooks: Vec<WebhookInfo>,
+    /// Most recent first
+    pub deliveries: Paginated<WebhookDelivery>,
+}
+
+/// Transfer rate limits in Mbit/s, where 0 means unlimited
+#[derive(Debug, Clone, Serialize)]
+pub struct BandwidthLimits {
+    pub max_mbps: f64,
+    /// Users held to their own limit instead of `max_mbps`
+    pub user_mbps: HashMap<String, f64>,
+    /// Clients on these networks are never throttled
+    pub exempt_networks: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateBandwidthRequest {
+    pub max_mbps: f64,
+    /// Replaces every override; leave it out to clear them
+    #[serde(default)]
+    pub user_mbps: HashMap<String, f64>,
+}
+
+/// Totals across every user, counted in the database
+#[derive(Debug, Clone, Serialize)]
+pub struct GlobalStats {
+    pub users: u64,
+    /// Users who logged in, or whose sessions or devices were seen, in the last week
+    pub active_users: u64,
+    /// Files outside the trash, not counting folders
+    pub files: u64,
+    /// Sum of the sizes of those files
+    pub logical_bytes: u64,
+    /// Files created or overwritten in the last day
+    pub uploads_last_day: u64,
+    /// Share links that are neither revoked, expired nor used up
+    pub active_share_links: u64,
+    /// Files and folders shared with other users
+    pub user_shares: u64,
+    /// Devices that synced in the last day
+    pub recent_sync_sessions: u64,
+}
+
+/// Figures for an admin status page
+#[derive(Debug, Clone, Serialize)]
+pub struct AdminStats {
+    #[serde(flatten)]
+    pub totals: GlobalStats,
+    /// The volume holding the storage root; `used` is what is physically taken up
+    pub disk: DiskSpace,
+    pub mounts: Vec<MountDiskSpace>,
+    /// Logical and physical bytes in storage, across all users
+    pub usage: StorageUsage,
+    pub downloads_last_day: u64,
+    /// When the figures were gathered
+    pub generated_at: DateTime<Utc>,
+    /// Whether they were kept from an earlier request rather than counted for this one
+    pub from_cache: bool,
+    /// Figures that are estimates rather than exact counts
+    pub approximate: Vec<String>,
+}
+
+/// A snapshot of the database written by a backup
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupInfo {
+    pub file_name: String,
+    pub size: u64,
+    /// SHA-256 of the file as written, after compression
+    pub checksum: String,
+    pub compressed: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BackupRequest {
+    /// Gzip the snapshot; `[backup] compress` decides when left out
+    pub compress: Option<bool>,
+    /// Send the snapshot back rather than keeping it in the backup directory
+    #[serde(default)]
+    pub download: bool,
+}
+
+/// A background maintenance job and how its runs went
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub interval_seconds: u64,
+    pub running: bool,
+    pub runs: u64,
+    pub failures: u64,
+    pub last_started_at: Option<DateTime<Utc>>,
+    pub last_finished_at: Option<DateTime<Utc>>,
+    /// Why the last run failed; none when it succeeded
+    pub last_error: Option<String>,
+    /// When it runs next unless triggered; none while it runs
+    pub next_run_at: Option<DateTime<Utc>>,
 }
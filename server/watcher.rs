@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use anyhow::Result;
+use notify::DebouncedEvent;
+use tokio::sync::{mpsc, watch};
+use uuid::Uuid;
+
+use crate::database::Database;
+use crate::filesystem::FileSystemService;
+use crate::handlers::{file_name, nearest_directory_owners, remove_unused_versions};
+use crate::types::FileMetadata;
+
+// Untracked paths wait this long before getting a row, so an upload in flight
+// can record its own row first
+const SETTLE_DELAY: Duration = Duration::from_secs(5);
+
+/// Mirrors changes made directly on disk (over SMB, or locally on the NAS)
+/// into `file_metadata` and the change log, so they reach the sync feed.
+pub struct ChangeWatcher {
+    database: Database,
+    filesystem: FileSystemService,
+    settle_delay: Duration,
+    /// Untracked paths seen on disk, with when they were first seen
+    settling: HashMap<String, Instant>,
+}
+
+impl ChangeWatcher {
+    pub fn new(database: Database, filesystem: FileSystemService) -> Self {
+        Self {
+            database,
+            filesystem,
+            settle_delay: SETTLE_DELAY,
+            settling: HashMap::new(),
+        }
+    }
+
+    /// Watch `base_path` until `shutdown` changes
+    pub async fn run(mut self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+        let directory_watch = self.filesystem.watch_directory("/")?;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        // notify delivers on a std channel, so a blocking thread forwards events
+        // until the receiving end goes away
+        let forwarder = tokio::task::spawn_blocking(move || loop {
+            match directory_watch.recv_timeout(Duration::from_secs(1)) {
+                Ok(event) => {
+                    if tx.send(event).is_err() {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) if !tx.is_closed() => {}
+                Err(_) => break,
+            }
+        });
+
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                event = rx.recv() => match event {
+                    Some(event) => {
+                        if let Err(e) = self.handle_event(event).await {
+                            tracing::warn!("Failed to apply filesystem change: {}", e);
+                        }
+                    }
+                    None => break,
+                },
+                _ = tick.tick() => {
+                    if let Err(e) = self.process_settled().await {
+                        tracing::warn!("Failed to record new files: {}", e);
+                    }
+                }
+            }
+        }
+
+        drop(rx);
+        let _ = forwarder.await;
+        tracing::info!("Filesystem watcher stopped");
+        Ok(())
+    }
+
+    async fn handle_event(&mut self, event: DebouncedEvent) -> Result<()> {
+        match event {
+            DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => {
+                if let Some(path) = self.tracked_path(&path) {
+                    self.sync_path(&path).await?;
+                }
+            }
+            DebouncedEvent::Remove(path) => {
+                if let Some(path) = self.tracked_path(&path) {
+                    self.remove_path(&path).await?;
+                }
+            }
+            DebouncedEvent::Rename(from, to) => {
+                match (self.tracked_path(&from), self.tracked_path(&to)) {
+                    (Some(from), Some(to)) => self.rename_path(&from, &to).await?,
+                    // Moved into the trash or another internal directory
+                    (Some(from), None) => self.remove_path(&from).await?,
+                    // Restored from the trash
+                    (None, Some(to)) => self.sync_path(&to).await?,
+                    (None, None) => {}
+                }
+            }
+            DebouncedEvent::Rescan => {
+                tracing::warn!("Filesystem watcher lost events; changes are picked up on the next listing");
+            }
+            DebouncedEvent::Error(e, path) => {
+                tracing::warn!("Filesystem watcher error at {:?}: {}", path, e);
+            }
+            DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) | DebouncedEvent::Chmod(_) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Relative path for an event, or `None` for internal directories and
+    /// ignored entries
+    fn tracked_path(&self, absolute_path: &Path) -> Option<String> {
+        let path = self.filesystem.get_relative_path(absolute_path).ok()?;
+        if path == "/"
+            || self.filesystem.is_reserved_path(&path)
+            || self.filesystem.is_ignored(&path, absolute_path.is_dir())
+        {
+            return None;
+        }
+        Some(path)
+    }
+
+    /// Bring the rows at `path` up to date with the file on disk
+    async fn sync_path(&mut self, path: &str) -> Result<()> {
+        let stat = match self.filesystem.stat(path).await {
+            Ok(stat) => stat,
+            // Gone again; the removal has its own event
+            Err(_) => return Ok(()),
+        };
+
+        let rows = self.database.get_file_metadata_for_path(path).await?;
+        if rows.is_empty() {
+            self.settling.entry(path.to_string()).or_insert_with(Instant::now);
+            return Ok(());
+        }
+
+        if stat.is_dir() {
+            return Ok(());
+        }
+
+        let modified: DateTime<Utc> = stat.modified()?.into();
+
+        // Writes made through the API record the file's size and mtime, so they stop here
+        let stale: Vec<FileMetadata> = rows
+            .into_iter()
+            .filter(|row| row.size != stat.len() || modified > row.modified_at)
+            .collect();
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        let fresh = self.filesystem.get_file_metadata(path).await?;
+        for mut row in stale {
+            if fresh.size == row.size && fresh.checksum == row.checksum {
+                continue;
+            }
+
+            row.size = fresh.size;
+            row.checksum = fresh.checksum.clone();
+            row.mime_type = fresh.mime_type.clone();
+            row.modified_at = Utc::now();
+            self.database.update_file_metadata(&row).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Record paths that have stayed untracked for the settle delay
+    async fn process_settled(&mut self) -> Result<()> {
+        let settle_delay = self.settle_delay;
+        let mut due: Vec<String> = self.settling.iter()
+            .filter(|(_, seen)| seen.elapsed() >= settle_delay)
+            .map(|(path, _)| path.clone())
+            .collect();
+        // Parents first, so their rows exist before their children's
+        due.sort();
+
+        for path in due {
+            self.settling.remove(&path);
+            self.record_new_path(&path).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Create rows for an untracked path, owned by whoever owns the nearest
+    /// tracked directory above it. Top-level entries have no such owner and
+    /// are recorded the next time someone lists them.
+    async fn record_new_path(&mut self, path: &str) -> Result<()> {
+        if !self.database.get_file_metadata_for_path(path).await?.is_empty() {
+            return self.sync_path(path).await;
+        }
+
+        let owners = nearest_directory_owners(&self.database, path).await?;
+
+        let fresh = match self.filesystem.get_file_metadata(path).await {
+            Ok(fresh) => fresh,
+            Err(_) => return Ok(()),
+        };
+
+        for owner_id in owners {
+            let mut metadata = fresh.clone();
+            metadata.id = Uuid::new_v4();
+            metadata.owner_id = owner_id;
+            metadata.modified_at = Utc::now();
+            metadata.parent_id = self.database.ensure_parent_directories(owner_id, path).await?;
+            self.database.create_file_metadata(&metadata).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn remove_path(&mut self, path: &str) -> Result<()> {
+        self.settling.remove(path);
+
+        for row in self.database.get_file_metadata_for_path(path).await? {
+            let deleted = self.database.delete_metadata_under_path(row.owner_id, path).await?;
+            remove_unused_versions(&self.filesystem, &deleted).await;
+        }
+
+        Ok(())
+    }
+
+    async fn rename_path(&mut self, from: &str, to: &str) -> Result<()> {
+        if let Some(seen) = self.settling.remove(from) {
+            self.settling.insert(to.to_string(), seen);
+        }
+
+        let rows = self.database.get_file_metadata_for_path(from).await?;
+        // Moves made through the API have already updated their rows
+        if rows.is_empty() {
+            return self.sync_path(to).await;
+        }
+
+        // Whatever the rename replaced is gone
+        self.remove_path(to).await?;
+
+        for mut row in rows {
+            row.path = to.to_string();
+            row.name = file_name(to);
+            row.modified_at = Utc::now();
+            row.parent_id = self.database.ensure_parent_directories(row.owner_id, to).await?;
+            self.database.move_file_metadata(&row, from).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ChangeType, FilePermissions, User};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_disk_changes_reach_the_change_feed() {
+        let db_dir = tempdir().unwrap();
+        let storage_dir = tempdir().unwrap();
+        let url = format!("sqlite://{}?mode=rwc", db_dir.path().join("test.db").display());
+        let database = Database::new(&url).await.unwrap();
+        let filesystem = FileSystemService::new(storage_dir.path(), 1024 * 1024).unwrap();
+
+        let user = User {
+            id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: None,
+            password_hash: "hash".to_string(),
+            created_at: Utc::now(),
+            last_login: None,
+            is_active: true,
+            auth_generation: 0,
+            permissions: vec!["read".to_string()],
+        };
+        database.create_user(&user).await.unwrap();
+
+        let mut folder = filesystem.create_directory("/docs").await.unwrap();
+        folder.owner_id = user.id;
+        folder.permissions = FilePermissions { read: true, write: true, delete: true, share: true };
+        database.create_file_metadata(&folder).await.unwrap();
+
+        let mut report = filesystem.save_file("/docs/report.txt", b"draft").await.unwrap();
+        report.owner_id = user.id;
+        report.parent_id = Some(folder.id);
+        report.permissions = folder.permissions.clone();
+        database.create_file_metadata(&report).await.unwrap();
+
+        let mut watcher = ChangeWatcher::new(database.clone(), filesystem.clone());
+        watcher.settle_delay = Duration::ZERO;
+        let absolute = |path: &str| filesystem.get_absolute_path(path);
+
+        // An event for a write the server already recorded changes nothing
+        watcher.handle_event(DebouncedEvent::Write(absolute("/docs/report.txt"))).await.unwrap();
+        let row = database.get_file_metadata(report.id).await.unwrap().unwrap();
+        assert_eq!(row.modified_at, report.modified_at);
+
+        std::fs::write(absolute("/docs/report.txt"), b"final version").unwrap();
+        watcher.handle_event(DebouncedEvent::Write(absolute("/docs/report.txt"))).await.unwrap();
+        let row = database.get_file_metadata(report.id).await.unwrap().unwrap();
+        assert_eq!(row.size, 13);
+        assert_ne!(row.checksum, report.checksum);
+
+        std::fs::write(absolute("/docs/notes.txt"), b"new").unwrap();
+        watcher.handle_event(DebouncedEvent::Create(absolute("/docs/notes.txt"))).await.unwrap();
+        watcher.process_settled().await.unwrap();
+        let notes = database.get_file_metadata_by_path(user.id, "/docs/notes.txt").await.unwrap().unwrap();
+        assert_eq!(notes.parent_id, Some(folder.id));
+
+        let cursor = database.get_latest_change_seq().await.unwrap();
+        std::fs::rename(absolute("/docs/report.txt"), absolute("/docs/final.txt")).unwrap();
+        watcher
+            .handle_event(DebouncedEvent::Rename(absolute("/docs/report.txt"), absolute("/docs/final.txt")))
+            .await
+            .unwrap();
+        std::fs::remove_file(absolute("/docs/notes.txt")).unwrap();
+        watcher.handle_event(DebouncedEvent::Remove(absolute("/docs/notes.txt"))).await.unwrap();
+
+        assert!(database.get_file_metadata(notes.id).await.unwrap().is_none());
+
+        let latest = database.get_latest_change_seq().await.unwrap();
+        let changes = database.get_changes_after(user.id, cursor, latest).await.unwrap();
+        assert!(changes.iter().any(|c| matches!(c.change_type, ChangeType::Moved)
+            && c.file_id == report.id
+            && c.old_path.as_deref() == Some("/docs/report.txt")));
+        assert!(changes.iter().any(|c| matches!(c.change_type, ChangeType::Deleted) && c.file_id == notes.id));
+    }
+}
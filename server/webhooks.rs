@@ -0,0 +1,574 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::sync::{mpsc, watch};
+use uuid::Uuid;
+
+use crate::config::{WebhookEndpoint, WebhookSettings};
+use crate::database::{Database, LoggedChange};
+use crate::filesystem::is_within;
+use crate::types::{
+    ChangeType, Comment, FileMetadata, ShareLink, WebhookDelivery, WebhookDeliveryStatus, WebhookEvent, WebhookInfo, WebhookPayload,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Header carrying `sha256=` and the hex HMAC-SHA256 of the body, keyed
+/// with the hook's secret
+pub const SIGNATURE_HEADER: &str = "x-synker-signature";
+/// Header naming the event, so receivers can route it before parsing
+pub const EVENT_HEADER: &str = "x-synker-event";
+
+// How long a webhook has to answer before the attempt counts as failed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Change log entries read at a time
+const CHANGES_PER_BATCH: i64 = 100;
+// Deliveries waiting for a worker; events past this are dropped
+const QUEUE_CAPACITY: usize = 1000;
+// Deliveries sent at once
+const WORKERS: usize = 4;
+// Longest wait between two attempts, however many failed
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// An event on its way to one hook, with its delivery once it has been tried
+struct QueuedDelivery {
+    endpoint: WebhookEndpoint,
+    payload: WebhookPayload,
+    delivery: Option<WebhookDelivery>,
+}
+
+/// Tells the configured webhooks about file changes, new share links and
+/// new comments. Events wait in a bounded queue for a few workers, which
+/// try each once; failed ones are queued again after a backoff. How it
+/// went ends up in `webhook_deliveries`.
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    endpoints: Arc<Vec<WebhookEndpoint>>,
+    max_attempts: u32,
+    retry_delay: Duration,
+    database: Database,
+    queue: mpsc::Sender<QueuedDelivery>,
+    /// The other end of `queue`, until `run` hands it to the workers
+    queued: Arc<Mutex<Option<mpsc::Receiver<QueuedDelivery>>>>,
+}
+
+impl Webhooks {
+    pub fn new(settings: &WebhookSettings, database: Database) -> Self {
+        let (queue, queued) = mpsc::channel(QUEUE_CAPACITY);
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            endpoints: Arc::new(settings.endpoints.clone()),
+            max_attempts: settings.max_attempts,
+            retry_delay: Duration::from_secs(settings.retry_delay_seconds),
+            database,
+            queue,
+            queued: Arc::new(Mutex::new(Some(queued))),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.is_empty()
+    }
+
+    /// The configured hooks, without their secrets
+    pub fn list(&self) -> Vec<WebhookInfo> {
+        self.endpoints.iter()
+            .map(|endpoint| WebhookInfo {
+                name: endpoint.name.clone(),
+                url: endpoint.url.clone(),
+                path_prefix: endpoint.path_prefix.clone(),
+                events: endpoint.events.clone(),
+            })
+            .collect()
+    }
+
+    /// Follow the change log from now on, sending each change to the hooks
+    /// that want it, until `shutdown` turns true. Changes made while the
+    /// server was down are not sent, and deliveries it cut short are marked
+    /// failed.
+    pub async fn run(self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+        let interrupted = self.database.fail_pending_webhook_deliveries("Interrupted by a restart", Utc::now()).await?;
+        if interrupted > 0 {
+            tracing::warn!("Marked {} webhook deliveries cut short by a restart as failed", interrupted);
+        }
+        self.start_workers(shutdown.clone())?;
+
+        let mut changes = self.database.subscribe_changes();
+        let mut cursor = self.database.get_latest_change_seq().await?;
+
+        loop {
+            loop {
+                let batch = self.database.get_logged_changes_after(cursor, CHANGES_PER_BATCH).await?;
+                let Some(last) = batch.last() else {
+                    break;
+                };
+                cursor = last.seq;
+                for change in batch {
+                    self.notify_change(change).await;
+                }
+            }
+
+            tokio::select! {
+                result = changes.changed() => result?,
+                _ = shutdown.changed() => return Ok(()),
+            }
+        }
+    }
+
+    /// Send what is queued, `WORKERS` deliveries at a time, until `shutdown`
+    /// turns true
+    fn start_workers(&self, shutdown: watch::Receiver<bool>) -> Result<()> {
+        let queued = self.queued.lock().unwrap().take()
+            .ok_or_else(|| anyhow!("Webhook workers are already running"))?;
+        let queued = Arc::new(tokio::sync::Mutex::new(queued));
+
+        for _ in 0..WORKERS {
+            let (webhooks, queued, mut shutdown) = (self.clone(), queued.clone(), shutdown.clone());
+            tokio::spawn(async move {
+                loop {
+                    let next = tokio::select! {
+                        next = async { queued.lock().await.recv().await } => next,
+                        _ = shutdown.changed() => return,
+                    };
+                    let Some(queued) = next else {
+                        return;
+                    };
+                    let name = queued.endpoint.name.clone();
+                    tokio::select! {
+                        result = webhooks.attempt(queued) => {
+                            if let Err(e) = result {
+                                tracing::warn!("Failed to record a delivery to webhook {}: {}", name, e);
+                            }
+                        }
+                        _ = shutdown.changed() => return,
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn notify_change(&self, change: LoggedChange) {
+        let event = WebhookEvent::from(&change.change_type);
+        if !self.endpoints.iter().any(|endpoint| wants(endpoint, event, &change.path, change.old_path.as_deref())) {
+            return;
+        }
+
+        let metadata = match change.change_type {
+            ChangeType::Deleted => None,
+            _ => self.database.get_file_metadata(change.file_id).await.ok().flatten(),
+        };
+        self.notify(WebhookPayload {
+            delivery_id: Uuid::nil(),
+            event,
+            path: change.path,
+            old_path: change.old_path,
+            owner_id: Some(change.owner_id),
+            metadata,
+            share_id: None,
+            comment: None,
+            timestamp: change.changed_at,
+        });
+    }
+
+    /// Tell the hooks that want it about a new share link, without waiting
+    pub fn notify_share(&self, share_link: &ShareLink, file: &FileMetadata) {
+        self.notify(WebhookPayload {
+            delivery_id: Uuid::nil(),
+            event: WebhookEvent::ShareCreated,
+            path: file.path.clone(),
+            old_path: None,
+            owner_id: Some(file.owner_id),
+            metadata: Some(file.clone()),
+            share_id: Some(share_link.id),
+            comment: None,
+            timestamp: share_link.created_at,
+        });
+    }
+
+    /// Tell the hooks that want it about a new comment, without waiting
+    pub fn notify_comment(&self, comment: &Comment, file: &FileMetadata) {
+        self.notify(WebhookPayload {
+            delivery_id: Uuid::nil(),
+            event: WebhookEvent::CommentCreated,
+            path: file.path.clone(),
+            old_path: None,
+            owner_id: Some(file.owner_id),
+            metadata: Some(file.clone()),
+            share_id: None,
+            comment: Some(comment.clone()),
+            timestamp: comment.created_at,
+        });
+    }
+
+    fn notify(&self, payload: WebhookPayload) {
+        for endpoint in self.endpoints.iter() {
+            if !wants(endpoint, payload.event, &payload.path, payload.old_path.as_deref()) {
+                continue;
+            }
+            let queued = QueuedDelivery { endpoint: endpoint.clone(), payload: payload.clone(), delivery: None };
+            if self.queue.try_send(queued).is_err() {
+                tracing::warn!("Webhook queue is full; dropping a {} event for {}", payload.event.as_str(), endpoint.name);
+            }
+        }
+    }
+
+    /// Send a test event to the hook called `name` once and wait for the
+    /// answer. None if there is no such hook.
+    pub async fn send_test(&self, name: &str) -> Result<Option<WebhookDelivery>> {
+        let Some(endpoint) = self.endpoints.iter().find(|endpoint| endpoint.name == name) else {
+            return Ok(None);
+        };
+        let mut payload = WebhookPayload {
+            delivery_id: Uuid::nil(),
+            event: WebhookEvent::Test,
+            path: endpoint.path_prefix.clone().unwrap_or_else(|| "/".to_string()),
+            old_path: None,
+            owner_id: None,
+            metadata: None,
+            share_id: None,
+            comment: None,
+            timestamp: Utc::now(),
+        };
+
+        let mut delivery = self.record_delivery(endpoint, &mut payload).await?;
+        self.send_once(endpoint, &payload, &mut delivery, 1).await?;
+        Ok(Some(delivery))
+    }
+
+    /// Try a queued delivery once. One that fails with attempts left goes
+    /// back in the queue once its backoff has passed, so a hook that is
+    /// down never keeps a worker waiting.
+    async fn attempt(&self, queued: QueuedDelivery) -> Result<()> {
+        let QueuedDelivery { endpoint, mut payload, delivery } = queued;
+        let mut delivery = match delivery {
+            Some(delivery) => delivery,
+            None => self.record_delivery(&endpoint, &mut payload).await?,
+        };
+        self.send_once(&endpoint, &payload, &mut delivery, self.max_attempts).await?;
+
+        match delivery.status {
+            WebhookDeliveryStatus::Pending => {
+                tracing::debug!("Webhook {} failed on attempt {}: {:?}", endpoint.name, delivery.attempts, delivery.error);
+                let delay = self.backoff(delivery.attempts);
+                self.retry_later(QueuedDelivery { endpoint, payload, delivery: Some(delivery) }, delay);
+            }
+            WebhookDeliveryStatus::Failed => {
+                tracing::warn!("Giving up on webhook {} after {} attempts", endpoint.name, delivery.attempts);
+            }
+            WebhookDeliveryStatus::Delivered => {}
+        }
+        Ok(())
+    }
+
+    /// Record a new delivery of `payload` to `endpoint`, and give the
+    /// payload its id
+    async fn record_delivery(&self, endpoint: &WebhookEndpoint, payload: &mut WebhookPayload) -> Result<WebhookDelivery> {
+        let now = Utc::now();
+        let delivery = WebhookDelivery {
+            id: Uuid::new_v4(),
+            webhook: endpoint.name.clone(),
+            event: payload.event,
+            path: payload.path.clone(),
+            status: WebhookDeliveryStatus::Pending,
+            attempts: 0,
+            response_status: None,
+            error: None,
+            next_attempt_at: None,
+            created_at: now,
+            updated_at: now,
+        };
+        self.database.create_webhook_delivery(&delivery).await?;
+        payload.delivery_id = delivery.id;
+        Ok(delivery)
+    }
+
+    /// Send `payload` to `endpoint` once and record how it went. A failure
+    /// leaves the delivery pending, with the time of the next try, until
+    /// `attempts` are used up.
+    async fn send_once(
+        &self,
+        endpoint: &WebhookEndpoint,
+        payload: &WebhookPayload,
+        delivery: &mut WebhookDelivery,
+        attempts: u32,
+    ) -> Result<()> {
+        let body = serde_json::to_vec(payload)?;
+        let result = self.client.post(&endpoint.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, sign(&endpoint.secret, &body))
+            .header(EVENT_HEADER, payload.event.as_str())
+            .body(body)
+            .send()
+            .await;
+
+        delivery.attempts += 1;
+        delivery.updated_at = Utc::now();
+        match result {
+            Ok(response) => {
+                delivery.response_status = Some(response.status().as_u16());
+                delivery.error = (!response.status().is_success())
+                    .then(|| format!("Answered {}", response.status()));
+            }
+            Err(e) => {
+                delivery.response_status = None;
+                delivery.error = Some(e.to_string());
+            }
+        }
+
+        delivery.status = match (&delivery.error, delivery.attempts >= attempts) {
+            (None, _) => WebhookDeliveryStatus::Delivered,
+            (Some(_), true) => WebhookDeliveryStatus::Failed,
+            (Some(_), false) => WebhookDeliveryStatus::Pending,
+        };
+        delivery.next_attempt_at = match delivery.status {
+            WebhookDeliveryStatus::Pending => chrono::Duration::from_std(self.backoff(delivery.attempts)).ok()
+                .map(|wait| delivery.updated_at + wait),
+            _ => None,
+        };
+        self.database.update_webhook_delivery(delivery).await
+    }
+
+    /// How long to wait after the `attempts`th failed try: `retry_delay`,
+    /// doubling each time up to `MAX_RETRY_DELAY`
+    fn backoff(&self, attempts: u32) -> Duration {
+        let doublings = attempts.saturating_sub(1).min(31);
+        self.retry_delay.saturating_mul(1 << doublings).min(MAX_RETRY_DELAY)
+    }
+
+    /// Queue `queued` again once `delay` has passed. It waits for room in
+    /// the queue rather than being dropped, since it is already on record.
+    fn retry_later(&self, queued: QueuedDelivery, delay: Duration) {
+        let queue = self.queue.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = queue.send(queued).await;
+        });
+    }
+}
+
+/// Whether `endpoint` takes `event` at `path`. Moves count on either side
+/// of the path prefix. Comments only go to hooks that ask for them, so
+/// hooks set up for file events don't start getting them.
+fn wants(endpoint: &WebhookEndpoint, event: WebhookEvent, path: &str, old_path: Option<&str>) -> bool {
+    let listed = endpoint.events.contains(&event);
+    if !listed && (!endpoint.events.is_empty() || event == WebhookEvent::CommentCreated) {
+        return false;
+    }
+
+    match &endpoint.path_prefix {
+        Some(prefix) => is_within(path, prefix) || old_path.is_some_and(|old_path| is_within(old_path, prefix)),
+        None => true,
+    }
+}
+
+/// The signature header of `body`
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::HeaderMap, http::StatusCode, routing::post, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use anyhow::anyhow;
+    use tempfile::tempdir;
+
+    /// Check a signature header against `body`, as a receiver would
+    fn verify(secret: &str, body: &[u8], signature: &str) -> Result<()> {
+        let hex = signature.strip_prefix("sha256=").ok_or_else(|| anyhow!("Unknown signature scheme"))?;
+        let expected = (0..hex.len())
+            .step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| anyhow!("Malformed signature"))?;
+
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+        mac.update(body);
+        mac.verify_slice(&expected).map_err(|_| anyhow!("Signature doesn't match"))
+    }
+
+    fn endpoint(url: &str, path_prefix: Option<&str>, events: Vec<WebhookEvent>) -> WebhookEndpoint {
+        WebhookEndpoint {
+            name: "home-assistant".to_string(),
+            url: url.to_string(),
+            secret: "a-long-enough-secret".to_string(),
+            path_prefix: path_prefix.map(str::to_string),
+            events,
+        }
+    }
+
+    fn payload(path: &str) -> WebhookPayload {
+        WebhookPayload {
+            delivery_id: Uuid::nil(),
+            event: WebhookEvent::Created,
+            path: path.to_string(),
+            old_path: None,
+            owner_id: None,
+            metadata: None,
+            share_id: None,
+            comment: None,
+            timestamp: Utc::now(),
+        }
+    }
+
+    /// The latest delivery to `webhook`, once it is no longer waiting for
+    /// its first attempt or an immediate retry
+    async fn settled(database: &Database, webhook: &str) -> WebhookDelivery {
+        loop {
+            let latest = database.list_webhook_deliveries(Some(webhook), None, 1, 0).await.unwrap().pop();
+            if let Some(delivery) = latest.filter(|delivery| {
+                delivery.status != WebhookDeliveryStatus::Pending
+                    || delivery.next_attempt_at.is_some_and(|next| next > Utc::now() + chrono::Duration::minutes(1))
+            }) {
+                return delivery;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn test_hooks_only_take_the_events_and_paths_they_asked_for() {
+        let scans = endpoint("http://hass.local/hook", Some("/scans"), vec![WebhookEvent::Created]);
+        assert!(wants(&scans, WebhookEvent::Created, "/scans/invoice.pdf", None));
+        assert!(!wants(&scans, WebhookEvent::Created, "/scansets/a.pdf", None));
+        assert!(!wants(&scans, WebhookEvent::Deleted, "/scans/invoice.pdf", None));
+
+        let everything = endpoint("http://hass.local/hook", Some("/scans"), Vec::new());
+        assert!(wants(&everything, WebhookEvent::Moved, "/archive/invoice.pdf", Some("/scans/invoice.pdf")));
+        assert!(wants(&everything, WebhookEvent::ShareCreated, "/scans", None));
+        assert!(!wants(&everything, WebhookEvent::CommentCreated, "/scans/invoice.pdf", None));
+        let comments = endpoint("http://hass.local/hook", None, vec![WebhookEvent::CommentCreated]);
+        assert!(wants(&comments, WebhookEvent::CommentCreated, "/scans/invoice.pdf", None));
+
+        let signature = sign("a-long-enough-secret", b"{}");
+        assert!(verify("a-long-enough-secret", b"{}", &signature).is_ok());
+        assert!(verify("another-secret-entirely", b"{}", &signature).is_err());
+        assert!(verify("a-long-enough-secret", b"{ }", &signature).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_are_signed_retried_and_recorded() {
+        let db_dir = tempdir().unwrap();
+        let url = format!("sqlite://{}?mode=rwc", db_dir.path().join("test.db").display());
+        let database = Database::new(&url).await.unwrap();
+
+        // Turns the first attempt away, then checks the signature
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let app = Router::new().route("/hook", post(move |headers: HeaderMap, body: axum::body::Bytes| async move {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+            let signature = headers[SIGNATURE_HEADER].to_str().unwrap();
+            match verify("a-long-enough-secret", &body, signature) {
+                Ok(()) => StatusCode::NO_CONTENT,
+                Err(_) => StatusCode::UNAUTHORIZED,
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hook = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let settings = WebhookSettings {
+            max_attempts: 3,
+            retry_delay_seconds: 0,
+            endpoints: vec![endpoint(&hook, None, Vec::new())],
+        };
+        let webhooks = Webhooks::new(&settings, database.clone());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        webhooks.start_workers(shutdown_rx).unwrap();
+        webhooks.notify(payload("/scans/invoice.pdf"));
+
+        let delivery = settled(&database, "home-assistant").await;
+        assert_eq!(delivery.status, WebhookDeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(delivery.response_status, Some(204));
+        assert_eq!(delivery.next_attempt_at, None);
+        assert_eq!(database.list_webhook_deliveries(Some("home-assistant"), None, 10, 0).await.unwrap().len(), 1);
+        shutdown_tx.send(true).unwrap();
+
+        // Nothing listening gives up after one go when testing
+        let dead = WebhookSettings {
+            max_attempts: 3,
+            retry_delay_seconds: 0,
+            endpoints: vec![endpoint("http://127.0.0.1:9/hook", None, Vec::new())],
+        };
+        let delivery = Webhooks::new(&dead, database.clone()).send_test("home-assistant").await.unwrap().unwrap();
+        assert_eq!(delivery.status, WebhookDeliveryStatus::Failed);
+        assert_eq!(delivery.attempts, 1);
+        assert!(delivery.error.is_some());
+        assert!(Webhooks::new(&dead, database.clone()).send_test("unknown").await.unwrap().is_none());
+
+        // Deliveries a restart cut short don't stay pending
+        let mut stranded = delivery.clone();
+        stranded.id = Uuid::new_v4();
+        stranded.status = WebhookDeliveryStatus::Pending;
+        database.create_webhook_delivery(&stranded).await.unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let running = tokio::spawn(Webhooks::new(&dead, database.clone()).run(shutdown_rx));
+        while database.list_webhook_deliveries(None, None, 10, 0).await.unwrap().iter()
+            .any(|delivery| delivery.status == WebhookDeliveryStatus::Pending)
+        {
+            tokio::task::yield_now().await;
+        }
+        shutdown_tx.send(true).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_a_dead_hook_leaves_the_workers_to_the_others() {
+        let db_dir = tempdir().unwrap();
+        let url = format!("sqlite://{}?mode=rwc", db_dir.path().join("test.db").display());
+        let database = Database::new(&url).await.unwrap();
+
+        let app = Router::new().route("/hook", post(|| async { StatusCode::NO_CONTENT }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hook = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        // More failing deliveries than there are workers, each retried in an hour
+        let settings = WebhookSettings {
+            max_attempts: 5,
+            retry_delay_seconds: 3600,
+            endpoints: vec![
+                WebhookEndpoint { name: "dead".to_string(), ..endpoint("http://127.0.0.1:9/hook", None, Vec::new()) },
+                endpoint(&hook, None, Vec::new()),
+            ],
+        };
+        let webhooks = Webhooks::new(&settings, database.clone());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        webhooks.start_workers(shutdown_rx).unwrap();
+        for i in 0..WORKERS * 2 {
+            webhooks.notify(payload(&format!("/scans/{}.pdf", i)));
+        }
+
+        loop {
+            let delivered = database.list_webhook_deliveries(Some("home-assistant"), None, 100, 0).await.unwrap().iter()
+                .filter(|delivery| delivery.status == WebhookDeliveryStatus::Delivered)
+                .count();
+            if delivered == WORKERS * 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let dead = settled(&database, "dead").await;
+        assert_eq!(dead.status, WebhookDeliveryStatus::Pending);
+        assert_eq!(dead.attempts, 1);
+        assert!(dead.next_attempt_at.unwrap() > dead.updated_at + chrono::Duration::minutes(59));
+        assert_eq!(webhooks.backoff(3), MAX_RETRY_DELAY);
+        shutdown_tx.send(true).unwrap();
+    }
+}